@@ -14,8 +14,68 @@
 
 extern crate gcc;
 
+use std::env;
+
+use std::process::Command;
+
+/// Get output of a given command (None if the command could not be
+/// executed or if it failed).
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = match Command::new(cmd).args(args).output() {
+        Ok(output) => output,
+        Err(_)     => return None
+    };
+    
+    if !output.status.success() {
+        return None;
+    }
+    
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .and_then(|s| if s.is_empty() { None } else { Some(s) })
+}
+
 fn main() {
+    // build info (the environment variables can be used to override the
+    // values, e.g. when building from a source tarball)
+    let git_hash = env::var("ARROW_GIT_HASH").ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or("unknown".to_string());
+    
+    let build_date = env::var("ARROW_BUILD_DATE").ok()
+        .or_else(|| command_output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]))
+        .unwrap_or("unknown".to_string());
+    
+    println!("cargo:rustc-env=ARROW_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=ARROW_BUILD_DATE={}", build_date);
+    
+
     gcc::compile_library("libnet_devices.a",
         &["src/net/raw/devices.c"]);
+    
+    let mut config = gcc::Config::new();
+    
+    config.file("src/utils/x509.c");
+    
+    // use OpenSSL headers from a custom location (e.g. when cross-compiling)
+    if let Ok(include_dir) = env::var("OPENSSL_INCLUDE_DIR") {
+        config.include(include_dir);
+    }
+    
+    config.compile("libx509_utils.a");
+    
+    // the AES-GCM helpers are needed only with the end-to-end encryption
+    if env::var("CARGO_FEATURE_E2E").is_ok() {
+        let mut config = gcc::Config::new();
+        
+        config.file("src/net/arrow/e2e.c");
+        
+        if let Ok(include_dir) = env::var("OPENSSL_INCLUDE_DIR") {
+            config.include(include_dir);
+        }
+        
+        config.compile("libe2e_utils.a");
+    }
 }
 
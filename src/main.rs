@@ -29,40 +29,129 @@ pub mod net;
 
 use std::io;
 use std::env;
+use std::mem;
 use std::process;
 use std::thread;
 
+use std::fs;
+
 use std::fs::File;
 use std::env::Args;
 use std::fmt::Debug;
 use std::error::Error;
 use std::str::FromStr;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use std::thread::JoinHandle;
-use std::io::{BufWriter, Write};
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use utils::logger;
 use utils::logger::LoggerWrapper;
 
 use utils::{Shared, RuntimeError};
 use utils::logger::{Logger, Severity};
-use utils::config::{ArrowConfig, AppContext};
+use utils::config::{ArrowConfig, AppContext, SERVICE_CONNECT_TIMEOUT};
+use utils::config::SCAN_TIME_LIMIT;
+use utils::config::{CLOCK_SKEW_TOLERANCE, DRAIN_TIMEOUT};
+use utils::config::{UPLINK_WRITE_TIMEOUT, ACK_TIMEOUT, SESSION_WRITE_TIMEOUT};
+use utils::identity;
+use utils::instance_lock::InstanceLock;
+use utils::config;
+use utils::config_watch::ConfigWatcher;
+use utils::migration;
+use utils::reload;
+use utils::hooks::{HookEvent, Hooks};
+use utils::status_led::{StatusLed, LedState, LedOutput, LedPatterns, LedPattern};
+use utils::x509;
+use utils::stats_export::{self, StatsExport, ExportFormat};
+use utils::clock;
+use utils::build_info;
+use utils::env_config;
+use utils::events::EventLogger;
+use utils::secret::Secret;
 
 #[cfg(feature = "discovery")]
 use net::discovery;
+#[cfg(feature = "discovery")]
+use net::discovery::ScanBudget;
+
+#[cfg(feature = "control-socket")]
+use net::control_socket;
+
+#[cfg(feature = "control-socket")]
+use net::control_socket::ControlSocket;
+
+#[cfg(feature = "metrics")]
+use net::status_page::StatusPage;
+
+#[cfg(feature = "sntp")]
+use net::sntp::SntpClient;
+
+#[cfg(feature = "discovery")]
+use net::sap::SapListener;
+#[cfg(feature = "discovery")]
+use net::monitor::NetworkMonitor;
+#[cfg(feature = "discovery")]
+use net::oui;
+#[cfg(feature = "discovery")]
+use net::oui::OuiDatabase;
+
+#[cfg(feature = "metrics")]
+use net::traffic::{TrafficMonitor, TRAFFIC_SAMPLE_PERIOD};
+
+#[cfg(feature = "socks")]
+use net::socks::SocksProxy;
+
+#[cfg(feature = "e2e")]
+use net::arrow::e2e::{E2eKey, E2eKeys};
+
+#[cfg(feature = "websocket")]
+use net::arrow::websocket::{Transport, WssEndpoint};
+
+#[cfg(feature = "recording")]
+use net::arrow::tap::{SessionTaps, TAP_DIR};
 
+#[cfg(feature = "sandbox")]
+use net::arrow::sandbox;
+
+use net::dns::DnsCache;
+use net::service_url::{get_fake_mac_address, parse_rtsp_url, parse_mjpeg_url};
 use net::raw::ether::MacAddr;
 use net::raw::devices::EthernetDevice;
 use net::arrow::error::{ArrowError, ErrorKind};
-use net::arrow::{ArrowClient, Sender, Command};
-use net::arrow::protocol::{Service, ServiceTable};
+use net::arrow::{ArrowClientBuilder, Sender, Command};
+use net::arrow::relay::RelayServer;
+use net::arrow::protocol::{Service, ServiceTable, Metadata};
+use net::arrow::protocol::ServiceStatsTable;
+use net::arrow::overload::{OverloadPolicy, OverloadPolicies, ServiceClass};
+use net::arrow::any_tcp::{AddressRule, TcpAllowlist};
+use net::arrow::access::{AccessPolicy, TimeWindow};
+use net::arrow::untrusted::UntrustedInterfaces;
+use net::arrow::flap::{FlapDamping, FLAP_THRESHOLD};
+use net::arrow::latency::SLOW_ACK_THRESHOLD;
+use net::arrow::register_policy::{RegisterAction, RegisterErrorPolicy};
+use net::arrow::register_policy;
+use net::arrow::version::RegressionPolicy;
+use net::arrow::suspend::SuspendDetector;
+use net::arrow::auth::{self, AuthProvider, StaticSecret};
+use net::arrow::authorizer::{self, SessionAuthorizer};
+use net::arrow::occupancy::BUFFER_ALERT_THRESHOLD;
+use net::arrow::keepalive::PingTuner;
+
+#[cfg(feature = "discovery")]
+use net::arrow::protocol::{ScanReport, ScanSummary};
+
+#[cfg(all(feature = "discovery", feature = "dns"))]
+use net::dns;
+
+#[cfg(all(feature = "discovery", feature = "dns"))]
+use std::collections::HashMap;
 
 use openssl::nid::Nid;
 use openssl::ssl::error::SslError;
-use openssl::x509::X509StoreContext;
+use openssl::x509::{X509StoreContext, X509ValidationError};
 use openssl::ssl::{SslContext, SslMethod};
+use openssl::x509::X509FileType;
 use openssl::ssl::{SSL_VERIFY_PEER, SSL_OP_NO_COMPRESSION};
 
 use mio::{EventLoop, Handler, NotifyError};
@@ -72,6 +161,39 @@ use regex::Regex;
 /// Network scan period.
 const NETWORK_SCAN_PERIOD: f64 = 300.0;
 
+/// Service statistics save period (in milliseconds).
+const SVC_STATS_SAVE_PERIOD: u64 = 60000;
+
+/// Period of checking whether all sessions have been drained after the
+/// control socket has been handed over to a new process (in milliseconds).
+const DRAIN_CHECK_PERIOD: u64 = 1000;
+
+/// Period of checking the configuration file for external modifications (in
+/// milliseconds).
+const CONFIG_WATCH_PERIOD: u64 = 2000;
+
+/// Period of checking for configuration reload requests (i.e. SIGHUP) (in
+/// milliseconds).
+const RELOAD_CHECK_PERIOD: u64 = 1000;
+
+/// Period of resolving addresses of static services again (in
+/// milliseconds). Only host names with expired DNS cache entries are
+/// queried.
+const SERVICE_RESOLVE_PERIOD: u64 = 60000;
+
+/// Time limit for acquiring the instance lock after the control socket has
+/// been taken over (in milliseconds). The previous process releases the lock
+/// when it exits, i.e. once its sessions are drained.
+const TAKEOVER_LOCK_TIMEOUT: u64 = (DRAIN_TIMEOUT as u64 + 60) * 1000;
+
+/// Minimum number of active services in the previous service table for
+/// restoring the table automatically after a reset.
+const AUTO_RESTORE_MIN_SERVICES: usize = 4;
+
+/// Maximum forward correction of the system clock (in seconds) that will be
+/// trusted when validating certificates using the SNTP clock.
+const SNTP_CLOCK_TOLERANCE: i64 = 86400;
+
 /// Connectionn retry timeout.
 const RETRY_TIMEOUT:       f64 = 60.0;
 
@@ -85,6 +207,23 @@ static CONFIG_FILE: &'static str = "/etc/arrow/config.json";
 /// Arrow Client connection state file.
 static STATE_FILE: &'static str = "/var/lib/arrow/state";
 
+/// Cache of resolved service host names.
+static DNS_CACHE_FILE: &'static str = "/var/lib/arrow/dns-cache";
+
+/// Service connection statistics file.
+static SVC_STATS_FILE: &'static str = "/var/lib/arrow/svc-stats";
+
+/// Local control socket.
+static CONTROL_SOCKET: &'static str = "/var/run/arrow-client.sock";
+
+/// Arrow Client identity seed file (used only if there is no stable MAC
+/// address).
+static IDENTITY_FILE: &'static str = "/var/lib/arrow/identity";
+
+/// Downloaded OUI vendor database.
+#[cfg(feature = "discovery")]
+static OUI_FILE: &'static str = "/var/lib/arrow/oui";
+
 /// A file containing RTSP paths tested on service discovery (one path per
 /// line).
 static RTSP_PATHS_FILE: &'static str = "/etc/arrow/rtsp-paths";
@@ -102,6 +241,58 @@ fn get_first_mac() -> Result<MacAddr, RuntimeError> {
         .ok_or(RuntimeError::from("there is no configured ethernet device"))
 }
 
+/// Get client info string (client version, platform, enabled optional
+/// features and an optional integrator name).
+fn get_client_info(integrator: Option<&str>) -> String {
+    let features = build_info::features()
+        .join(",");
+
+    let mut res = format!("arrow-client/{} (os: {}; arch: {}; features: {}",
+        build_info::VERSION, env::consts::OS, env::consts::ARCH,
+        features);
+
+    if let Some(integrator) = integrator {
+        res.push_str(&format!("; integrator: {}", integrator));
+    }
+
+    res.push(')');
+
+    res
+}
+
+/// Get MAC address used for client identification if there is no interface
+/// or MAC address given explicitly.
+///
+/// The pseudo-MAC address persisted in the identity file is used if there is
+/// one. Otherwise, MAC address of the first configured ethernet device is used
+/// if it is stable (i.e. universally administered) or if the client has been
+/// already configured (so that existing installations keep their MAC
+/// address). A new pseudo-MAC address is derived only on fresh installations.
+fn get_default_mac(
+    config_file: &str,
+    identity_file: &str) -> Result<MacAddr, RuntimeError> {
+    if let Some(mac) = identity::load_mac(identity_file) {
+        return Ok(mac);
+    }
+
+    let first_mac = get_first_mac();
+
+    match first_mac {
+        Ok(ref mac) if identity::is_stable_mac(mac) => return Ok(*mac),
+        _ => ()
+    }
+
+    let configured = Path::new(config_file).exists()
+        || Path::new(&utils::backup_file(config_file)).exists();
+
+    if configured {
+        first_mac
+    } else {
+        identity::derive_mac(identity_file)
+            .or(first_mac)
+    }
+}
+
 /// Get MAC address of a given network interface.
 fn get_mac(iface: &str) -> Result<MacAddr, RuntimeError> {
     EthernetDevice::list()
@@ -124,115 +315,35 @@ fn result_or_usage<T, E>(res: Result<T, E>) -> T
     }
 }
 
-/// Generate a fake MAC address from a given prefix and socket address.
-///
-/// Note: It is used in case we do not know the device MAC address (e.g. for
-/// services passed as command line arguments).
-fn get_fake_mac_address(prefix: u16, addr: &SocketAddr) -> MacAddr {
-    match addr {
-        &SocketAddr::V4(ref addr) => get_fake_mac_address_v4(prefix, addr),
-        &SocketAddr::V6(ref addr) => get_fake_mac_address_v6(prefix, addr),
-    }
-}
-
-fn get_fake_mac_address_v4(prefix: u16, addr: &SocketAddrV4) -> MacAddr {
-    let a = ((prefix >> 8)  & 0xff) as u8;
-    let b = ( prefix        & 0xff) as u8;
-
-    let addr   = addr.ip();
-    let octets = addr.octets();
-
-    MacAddr::new(a, b,
-        octets[0],
-        octets[1],
-        octets[2],
-        octets[3])
-}
-
-fn get_fake_mac_address_v6(prefix: u16, addr: &SocketAddrV6) -> MacAddr {
-    let addr     = addr.ip();
-    let segments = addr.segments();
-
-    let a = ((prefix      >> 8)  & 0xff) as u8;
-    let b = ( prefix             & 0xff) as u8;
-    let c = ((segments[6] >> 8)  & 0xff) as u8;
-    let d = ( segments[6]        & 0xff) as u8;
-    let e = ((segments[7] >> 8)  & 0xff) as u8;
-    let f = ( segments[7]        & 0xff) as u8;
-
-    MacAddr::new(a, b, c, d, e, f)
-}
-
-/// Parse a given RTSP URL and return Service::RTSP, Service::LockedRTSP or
-/// an error.
-fn parse_rtsp_url(url: &str) -> Result<Service, RuntimeError> {
-    let res = r"^rtsp://([^/]+@)?([^/@:]+|\[[0-9a-fA-F:.]+\])(:(\d+))?(/.*)?$";
-    let re  = Regex::new(res).unwrap();
-
-    if let Some(caps) = re.captures(url) {
-        let host = caps.at(2).unwrap();
-        let path = caps.at(5).unwrap();
-        let port = match caps.at(4) {
-            Some(port_str) => u16::from_str(port_str).unwrap(),
-            _ => 554
-        };
-
-        let socket_addr = try!(net::utils::get_socket_address((host, port))
-            .or(Err(RuntimeError::from(
-                "unable to resolve RTSP service address"))));
-
-        let mac = get_fake_mac_address(0xffff, &socket_addr);
-
-        // note: we do not want to probe the service here as it might not be
-        // available on app startup
-        match caps.at(1) {
-            Some(_) => Ok(Service::LockedRTSP(mac, socket_addr)),
-            None    => Ok(Service::RTSP(mac, socket_addr, path.to_string()))
-        }
-    } else {
-        Err(RuntimeError::from("invalid RTSP URL given"))
-    }
-}
-
-/// Parse a given HTTP URL and return Service::MJPEG, Service::LockedMJPEG or
-/// an error.
-fn parse_mjpeg_url(url: &str) -> Result<Service, RuntimeError> {
-    let res = r"^http://([^/]+@)?([^/@:]+|\[[0-9a-fA-F:.]+\])(:(\d+))?(/.*)?$";
-    let re  = Regex::new(res).unwrap();
-
-    if let Some(caps) = re.captures(url) {
-        let host = caps.at(2).unwrap();
-        let path = caps.at(5).unwrap();
-        let port = match caps.at(4) {
-            Some(port_str) => u16::from_str(port_str).unwrap(),
-            _ => 80
-        };
-
-        let socket_addr = try!(net::utils::get_socket_address((host, port))
-            .or(Err(RuntimeError::from(
-                "unable to resolve HTTP service address"))));
-
-        let mac = get_fake_mac_address(0xffff, &socket_addr);
-
-        // note: we do not want to probe the service here as it might not be
-        // available on app startup
-        match caps.at(1) {
-            Some(_) => Ok(Service::LockedMJPEG(mac, socket_addr)),
-            None    => Ok(Service::MJPEG(mac, socket_addr, path.to_string()))
-        }
-    } else {
-        Err(RuntimeError::from("invalid HTTP URL given"))
-    }
-}
-
 /// Print usage and exit the process with a given exit code.
 fn usage(exit_code: i32) -> ! {
-    println!("USAGE: arrow-client arr-host[:arr-port] [OPTIONS]\n");
+    println!("USAGE: arrow-client arr-host[:arr-port] [OPTIONS]");
+    println!("       arrow-client migrate-config [--config-file=path] [--dry-run]");
+    if cfg!(feature = "control-socket") {
+        println!("       arrow-client ctl [--control-socket=path] command");
+        println!("       arrow-client monitor [--control-socket=path] [--connection]");
+        println!("                    [--sessions] [--scans] [--alerts] [--warnings]");
+    }
+    if cfg!(feature = "discovery") {
+        println!("       arrow-client update-oui url sha256 [--oui-file=path]");
+    }
+    println!("       arrow-client --version [--verbose]");
+    println!("");
+    println!("    If there are no arguments, the configuration is taken from ARROW_*");
+    println!("    environment variables (e.g. ARROW_SERVICE=host:port, ARROW_DISCOVERY=1,");
+    println!("    ARROW_CONFIG_FILE=path, ARROW_LOG_JSON=1).");
+    println!("");
     println!("    arr-host  Angelcam Arrow Service host");
     println!("    arr-port  Angelcam Arrow Service port\n");
     println!("OPTIONS:\n");
     println!("    -i iface  ethernet interface used for client identification (the first");
     println!("              configured network interface is used by default)");
+    println!("    --mac=addr  MAC address used for client identification (useful in case");
+    println!("              there is no ethernet interface available, e.g. in containers)");
+    println!("    --identity-file=path  alternative path to the file with a random seed used");
+    println!("              for deriving a stable client MAC address in case there is no");
+    println!("              stable hardware MAC address (default value:");
+    println!("              /var/lib/arrow/identity)");
     println!("    -c path   path to a CA certificate for Arrow Service identity verification;");
     println!("              in case the path is a directory, it's scanned recursively for");
     println!("              all files with the following extensions:\n");
@@ -249,9 +360,20 @@ fn usage(exit_code: i32) -> ! {
     println!("              format)");
     println!("    -t addr   add a given TCP service (addr must be in the \"host:port\"");
     println!("              format)");
+    if cfg!(feature = "datagram") {
+        println!("    -u addr   add a given UDP service (addr must be in the \"host:port\"");
+        println!("              format); session payload is a sequence of datagrams");
+        println!("              prefixed with their 16-bit length");
+    }
     println!("    -v        enable debug logs\n");
     println!("    --config-file=path  alternative path to the client configuration file");
     println!("                        (default value: /etc/arrow/config.json)");
+    println!("    --client-cert=path  present a given client certificate (PEM, optionally");
+    println!("                        followed by intermediate certificates) to Arrow");
+    println!("                        Service; the certificate is reloaded before every");
+    println!("                        connection attempt");
+    println!("    --client-key=path   private key of the client certificate (PEM, default");
+    println!("                        value: the client certificate file)");
     println!("    --conn-state-file=path  alternative path to the client connection state");
     println!("                        file (default value: /var/lib/arrow/state)");
     println!("    --diagnostic-mode   start the client in diagnostic mode (i.e. the client");
@@ -259,21 +381,296 @@ fn usage(exit_code: i32) -> ! {
     println!("                        will report success as its exit code; note: the");
     println!("                        \"access denied\" response from the server is also");
     println!("                        considered as a success)");
+    println!("    --one-shot-register  save the client identity into the configuration");
+    println!("                        file (generating it if needed), register with a");
+    println!("                        given Arrow Service once and exit (the exit code is");
+    println!("                        the same as in the diagnostic mode)");
+    println!("    --watch-config      watch the configuration file for external");
+    println!("                        modifications (e.g. by a provisioning agent) and");
+    println!("                        reload the service table when it changes");
+    println!("    --clock-grace-mode  accept Arrow Service certificates which are not");
+    println!("                        valid yet because the system clock is behind (the");
+    println!("                        problem is reported to Arrow Service and all");
+    println!("                        service requests are refused until the clock is");
+    println!("                        fixed)");
+    println!("    --clock-skew-tolerance=s  accept Arrow Service certificates which become");
+    println!("                        valid within a given number of seconds (default");
+    println!("                        value: 600, use 0 to disable the tolerance)");
+    println!("    --state-dir=path    directory for all files modified by the client (i.e.");
+    println!("                        the configuration, connection state, identity seed,");
+    println!("                        service statistics and DNS cache); this allows running");
+    println!("                        the client with a read-only configuration file, the");
+    println!("                        configuration file is used only as the initial");
+    println!("                        configuration in such case");
+    println!("    --svc-stats-file=path  alternative path to the service connection");
+    println!("                        statistics file (default value:");
+    println!("                        /var/lib/arrow/svc-stats)");
+    if cfg!(feature = "discovery") {
+        println!("    --oui-file=path     alternative path to the downloaded OUI vendor database");
+        println!("                        (default value: /var/lib/arrow/oui)");
+    }
+    println!("    --report-svc-stats  send a summary of the service connection statistics");
+    println!("                        and the Control Protocol ACK latencies to Arrow");
+    println!("                        Service within the client metadata");
+    println!("    --auth=provider     REGISTER authentication provider; \"static\" (the");
+    println!("                        password from the configuration file; default),");
+    println!("                        \"hmac:path\" (HMAC-SHA256 challenge-response using");
+    println!("                        a secret from a given file) or \"token:path\" (a");
+    println!("                        short-lived token kept in a given file by a local");
+    println!("                        provisioning agent); the files are read on every");
+    println!("                        connection attempt");
+    println!("    --register-error=code:action  set action taken if Arrow Service refuses");
+    println!("                        registration with a given error code (decimal or");
+    println!("                        hexadecimal with the \"0x\" prefix); the action can");
+    println!("                        be \"retry\" (reconnect after the usual timeout),");
+    println!("                        \"wait\" (wait for the client configuration on the");
+    println!("                        service side) or \"halt\" (stop reconnecting for 10");
+    println!("                        hours); this option can be used multiple times");
+    println!("    --version-regression=action  set action taken if the service table");
+    println!("                        version goes back (e.g. after a state reset); the");
+    println!("                        action can be \"update\" (send the whole service");
+    println!("                        table in an UPDATE message; default) or");
+    println!("                        \"reconnect\" (reconnect to Arrow Service)");
+    println!("    --trace-ids         log trace IDs of Control Protocol messages (in the");
+    println!("                        debug mode) and send the connection trace ID to");
+    println!("                        Arrow Service within the client metadata");
+    if cfg!(feature = "discovery") {
+        println!("    --network-alerts    report new MAC addresses and changed open-port");
+        println!("                        fingerprints of known hosts found by the network");
+        println!("                        scanner to Arrow Service (the first scan is used as");
+        println!("                        the baseline)");
+    }
+    if cfg!(all(feature = "discovery", feature = "dns")) {
+        println!("    --host-names        look up host names of discovered devices (reverse");
+        println!("                        DNS, mDNS and NetBIOS) and report them to Arrow");
+        println!("                        Service within the client metadata");
+    }
+    println!("    --scan-time-limit=s stop probing after a given number of seconds and");
+    println!("                        report the partial results of the network scan");
+    println!("                        (default: {}, 0 means no limit)", SCAN_TIME_LIMIT);
+    println!("    --scan-probe-limit=n");
+    println!("                        stop probing after a given number of probes (ARP,");
+    println!("                        ICMP and port scans of a network, RTSP and HTTP");
+    println!("                        requests) within a single network scan");
+    println!("    --adaptive-ping     look for the idle timeout of NATs and firewalls on the");
+    println!("                        way to Arrow Service and send PING messages just");
+    println!("                        below it instead of every minute (the client may");
+    println!("                        reconnect a few times while looking for the timeout)");
+    println!("    --strict-parsing    check messages received from Arrow Service for");
+    println!("                        framing anomalies (e.g. non-zero reserved bits or");
+    println!("                        unexpected message lengths), log them and report");
+    println!("                        them to Arrow Service; the messages are still");
+    println!("                        processed as usual");
+    if cfg!(feature = "metrics") {
+        println!("    --uplink-interface=name  network interface used for reporting the uplink");
+        println!("                        utilization to Arrow Service (default: the");
+        println!("                        interface of the default route)");
+        println!("    --uplink-capacity=kbps  uplink capacity in kbit/s (default: the link");
+    }
+    println!("                        speed of the uplink interface)");
+    println!("    --slow-ack-threshold=ms  log a warning if an ACK takes at least a given");
+    println!("                        number of milliseconds (default: {}, 0 disables", SLOW_ACK_THRESHOLD);
+    println!("                        the warnings)");
+    println!("    --overload-policy=class:policy  set overload policy for a given");
+    println!("                        service class (\"rtsp\", \"mjpeg\", \"http\",");
+    println!("                        \"tcp\" or \"udp\"); the policy can be either \"block\" (stop");
+    println!("                        reading from the service until the buffered data");
+    println!("                        are sent; default) or \"drop\" (drop the oldest");
+    println!("                        media frames; RTSP only)");
+    println!("    --allow-tcp=rule    allow Arrow Service to open sessions to TCP services");
+    println!("                        which are not in the service table; the rule format");
+    println!("                        is \"network/prefix[:port[-port]]\" (e.g.");
+    println!("                        \"192.168.1.0/24:80\"); this option can be used");
+    println!("                        multiple times");
+    if cfg!(feature = "sandbox") {
+        println!("    --sandbox-any-tcp   handle every any-TCP session in a separate");
+        println!("                        resource-limited helper process");
+    }
+    if cfg!(feature = "conntrack") {
+        println!("    --flush-conntrack   remove connection tracking entries of finished");
+        println!("                        sessions (requires CAP_NET_ADMIN)");
+    }
+    println!("    --session-hours=HH:MM-HH:MM  allow service sessions only within a given");
+    println!("                        time window (local time)");
+    println!("    --max-sessions-per-service=n  maximum number of concurrent sessions of");
+    println!("                        a single service");
+    println!("    --deny-service=rule  refuse sessions to services matching a given rule");
+    println!("                        (\"network/prefix[:port[-port]]\" format); this");
+    println!("                        option can be used multiple times");
+    println!("    --untrusted-iface=name  refuse to add or tunnel services asking for");
+    println!("                        plaintext (Basic) credentials within the network");
+    println!("                        of a given interface; this option can be used");
+    println!("                        multiple times");
+    println!("    --session-authorizer=exec:command  ask a given command before opening");
+    println!("                        a service session; the session is refused if the");
+    println!("                        command fails or redirected to the address printed");
+    println!("                        by the command (if any)");
+    if cfg!(feature = "e2e") {
+        println!("    --e2e-key=[mac=]path  encrypt session payloads of a device with a given");
+        println!("                        MAC address (or of all devices if no MAC address is");
+        println!("                        given) end-to-end using a hex-encoded 256-bit key");
+        println!("                        from a given file shared with the final consumer;");
+        println!("                        this option can be used multiple times");
+    }
+    println!("    --flap-threshold=n  hold down services which fail n times within a");
+    println!("                        minute (default: {}, 0 disables the flap", FLAP_THRESHOLD);
+    println!("                        damping)");
+    println!("    --max-bitrate=kbps  report services receiving more than a given number of");
+    println!("                        kbit/s (summed over all their sessions) for 30 seconds");
+    println!("                        (logged and passed to the bitrate-exceeded hook)");
+    if cfg!(feature = "discovery") {
+        println!("    --bitrate-hint=param  ask RTSP services exceeding the bitrate cap for a");
+        println!("                        lower bitrate by sending SET_PARAMETER with a given");
+        println!("                        vendor-specific parameter set to the cap");
+    }
+    println!("    --stats-export=format:path  periodically write connection and session");
+    println!("                        statistics into a given file; the format can be");
+    println!("                        either \"csv\" or \"json\"; previous exports are");
+    println!("                        kept as path.1, path.2, ...");
+    println!("    --stats-export-interval=s  statistics export period in seconds");
+    println!("                        (default: {})", stats_export::EXPORT_PERIOD);
+    println!("    --stats-export-files=n  number of kept statistics export files");
+    println!("                        (default: {})", stats_export::EXPORT_FILES);
+    if cfg!(feature = "sntp") {
+        println!("    --ntp-server=host[:port]  synchronize the client clock using a given");
+        println!("                        NTP server; the system clock is not modified, the");
+        println!("                        client clock is used for certificate validation");
+        println!("                        and timestamps; this option can be used multiple");
+        println!("                        times");
+    }
+    println!("    --tenant=path[@arr-host[:arr-port]]  run another Arrow client with a");
+    println!("                        given config file within this process (e.g. for");
+    println!("                        cameras of a different customer account); the");
+    println!("                        client uses services from its config file only");
+    println!("                        and it connects to a given Arrow Service (the one");
+    println!("                        given above by default); this option can be used");
+    println!("                        multiple times");
+    println!("    --relay=addr        connect to Arrow Service through a relay running on");
+    println!("                        a given address (\"host:port\" format)");
+    println!("    --relay-listen=addr  act as a relay for other clients and listen on a");
+    println!("                        given address (\"host:port\" format); the relay");
+    println!("                        connects only to the Arrow Service addresses of");
+    println!("                        this client and its tenants");
+    println!("    --relay-allow=addr  allow relaying also to a given Arrow Service address");
+    println!("                        (\"host:port\" format; e.g. a redirect target); this");
+    println!("                        option can be used multiple times");
+    println!("    --relay-secret-file=path  authenticate relay connections using a secret");
+    println!("                        shared by all clients and relays of the federation");
+    println!("                        (required with --relay and --relay-listen)");
+    if cfg!(feature = "socks") {
+        println!("    --socks-proxy=[user:password@]host:port  connect to Arrow Service");
+        println!("                        through a given SOCKS5 proxy (cannot be combined");
+        println!("                        with --relay)");
+        println!("    --service-socks-proxy=[user:password@]host:port  connect to local");
+        println!("                        services through a given SOCKS5 proxy (except");
+        println!("                        sandboxed any-TCP sessions)");
+    }
+    if cfg!(feature = "websocket") {
+        println!("    --transport=tls|wss|auto  transport of the Arrow Service connection: a");
+        println!("                        plain TLS connection (default), WebSocket over TLS");
+        println!("                        or TLS with a WebSocket fallback if the connection");
+        println!("                        cannot be established (wss and auto cannot be");
+        println!("                        combined with --relay or --socks-proxy)");
+        println!("    --wss-url=wss://host[:port][/path]  WebSocket endpoint of Arrow");
+        println!("                        Service (the Arrow Service host, port 443 and the");
+        println!("                        /arrow path by default)");
+    }
+    println!("    --site-name=name    site name reported to Arrow Service");
+    println!("    --location=text     device location reported to Arrow Service");
+    println!("    --customer-id=id    customer ID reported to Arrow Service");
+    println!("    --label=key=value   add a given label reported to Arrow Service (can be");
+    println!("                        used multiple times)");
+    println!("    --hook=event:command  execute a given shell command on a given event");
+    println!("                        (\"connect\", \"disconnect\", \"scan-complete\",");
+    println!("                        \"session-open\", \"session-close\",");
+    println!("                        \"network-alert\" or \"bitrate-exceeded\"); event details");
+    println!("                        are passed in ARROW_* environment variables; this");
+    println!("                        option can be used multiple times");
+    println!("    --status-led=output indicate connection state using a given LED; the output");
+    println!("                        can be either \"gpio:pin\" (sysfs GPIO pin number) or");
+    println!("                        \"led:name\" (sysfs LED class device)");
+    println!("    --led-pattern=state:pattern  set status LED pattern of a given state");
+    println!("                        (\"connecting\", \"registered\" or \"error\"); the");
+    println!("                        pattern is either \"on\", \"off\" or a comma separated");
+    println!("                        list of on/off durations in milliseconds (e.g.");
+    println!("                        \"500,500\"); this option can be used multiple times");
+    println!("    --integrator=name   integrator name reported to Arrow Service within the");
+    println!("                        client info (together with the client version,");
+    println!("                        platform and enabled features)");
+    println!("    --service-connect-timeout=ms  time limit for establishing connections");
+    println!("                        to local services; sessions are closed with the");
+    println!("                        \"service unreachable\" error after the limit");
+    println!("                        (default value: 5000)");
+    println!("    --uplink-write-timeout=ms  time limit for writing pending data into");
+    println!("                        the Arrow Service connection; the client reconnects");
+    println!("                        after the limit (default value: {})", UPLINK_WRITE_TIMEOUT);
+    println!("    --buffer-alert-threshold=ms  log a warning if the Arrow output buffer or");
+    println!("                        a session input buffer stays above 90% of its");
+    println!("                        capacity for longer than a given time (default");
+    println!("                        value: {})", BUFFER_ALERT_THRESHOLD);
+    println!("    --ack-timeout=ms    time limit for receiving ACKs of Control Protocol");
+    println!("                        messages; the client reconnects after the limit");
+    println!("                        (default value: {})", ACK_TIMEOUT);
+    println!("    --session-write-timeout=ms  time limit for writing pending data into");
+    println!("                        connections to local services; sessions are closed");
+    println!("                        with the \"connection error\" error after the limit");
+    println!("                        (default value: {})", SESSION_WRITE_TIMEOUT);
     println!("    --log-stderr        send log messages into stderr instead of syslog");
     println!("    --log-stderr-pretty  send log messages into stderr instead of syslog and");
     println!("                        use colored messages");
+    println!("    --log-json          send log messages into stdout (one JSON object per");
+    println!("                        line) instead of syslog");
+    println!("    --log-journald      send log messages into systemd-journald (with session");
+    println!("                        ID, service ID and connection state fields) instead");
+    println!("                        of syslog");
+    println!("    --log-format=fmt    log message format/destination; supported values:");
+    println!("                        syslog (default), stderr, stderr-pretty, json (the");
+    println!("                        same as --log-json) and journald");
     println!("    --log-file=path     send log messages into a given file instead of syslog");
     println!("    --log-file-size=n   size limit for the log file (in bytes; default value:");
     println!("                        10240)");
     println!("    --log-file-rotations=n  number of backup files (i.e. rotations) for the");
     println!("                        log file (default value: 1)");
+    println!("    --log-file-max-age=s  rotate the log file also once it is older than a");
+    println!("                        given number of seconds (disabled by default)");
+    println!("    --log-file-gzip     compress rotated log files using gzip");
+    if cfg!(feature = "control-socket") {
+        println!("    --control-socket=path  alternative path to the local control socket");
+        println!("                        (default value: /var/run/arrow-client.sock); use");
+        println!("                        \"arrow-client ctl help\" to list the supported");
+        println!("                        commands");
+        println!("    --takeover          take over the control socket of a running client");
+        println!("                        (e.g. after an upgrade); the running client stops");
+        println!("                        accepting new sessions and exits once its current");
+        println!("                        sessions are finished (at most {} seconds), this", DRAIN_TIMEOUT);
+        println!("                        client connects to Arrow Service after that; without");
+        println!("                        this option, the client refuses to start if another");
+        println!("                        client uses the same config file");
+    }
+    if cfg!(feature = "metrics") {
+        println!("    --status-page=addr  serve a read-only status page (connection state,");
+        println!("                        discovered devices and active sessions) on a given");
+        println!("                        address (e.g. 0.0.0.0:8080); the \"/health\" path");
+        println!("                        returns the connection state only, the");
+        println!("                        \"/status\" path returns the status as JSON and");
+        println!("                        the \"/metrics\" path returns Prometheus metrics");
+    }
+    #[cfg(feature = "recording")]
+    {
+        println!("    --tap-dir=path      directory for session payload recordings created");
+        println!("                        using the \"tap-session\" control socket command");
+        println!("                        (default value: {})", TAP_DIR);
+    }
     if cfg!(feature = "discovery") {
         println!("    --rtsp-paths=path   alternative path to a file containing list of RTSP");
         println!("                        paths used on service discovery (default value:");
         println!("                        /etc/arrow/rtsp-paths)");
         println!("    --mjpeg-paths=path  alternative path to a file containing list of MJPEG");
         println!("                        paths used on service discovery (default value:");
-        println!("                        /etc/arrow/mjpeg-paths)\n");
+        println!("                        /etc/arrow/mjpeg-paths)");
+        println!("    --sap-listen        listen for SAP/SDP multicast session announcements and");
+        println!("                        add the announced multicast streams into the service");
+        println!("                        table as UDP services on service discovery\n");
     } else {
         println!("");
     }
@@ -349,18 +746,131 @@ fn load_ca_certificates<P>(
     }
 }
 
+/// Load a given client certificate (PEM chain) and its private key into a
+/// given SSL context. The files are checked using a scratch context first,
+/// so the SSL context is not modified if they are not valid.
+fn load_client_certificate(
+    ssl_context: &mut SslContext,
+    cert_file: &str,
+    key_file: &str) -> Result<(), RuntimeError> {
+    let load = |ssl_context: &mut SslContext| -> Result<(), SslError> {
+        try!(ssl_context.set_certificate_chain_file(cert_file, X509FileType::PEM));
+        try!(ssl_context.set_private_key_file(key_file, X509FileType::PEM));
+        ssl_context.check_private_key()
+    };
+
+    let mut scratch = try!(SslContext::new(SslMethod::Tlsv1_2)
+        .map_err(|err| RuntimeError::from(format!("{}", err))));
+
+    try!(load(&mut scratch)
+        .map_err(|err| RuntimeError::from(format!("{}", err))));
+
+    load(ssl_context)
+        .map_err(|err| RuntimeError::from(format!("{}", err)))
+}
+
+/// Get TLS files (CA certificates and the client certificate and key) of a
+/// given application context.
+fn tls_files(app_context: &AppContext) -> Vec<String> {
+    let mut res = app_context.ca_certificates.clone();
+
+    if let Some((ref cert_file, ref key_file)) = app_context.client_cert {
+        res.push(cert_file.clone());
+        res.push(key_file.clone());
+    }
+
+    res
+}
+
+/// Create a new SSL context with CA certificates from given paths. The
+/// client certificate is not loaded.
+fn reload_ssl_context(ca_certificates: &[String]) -> Result<SslContext, RuntimeError> {
+    let mut ssl_context = try!(
+        init_ssl(SslMethod::Tlsv1_2, "HIGH:!aNULL:!kRSA:!PSK:!MD5:!RC4")
+            .map_err(|err| RuntimeError::from(format!("{}", err))));
+
+    for path in ca_certificates {
+        try!(load_ca_certificates(&mut ssl_context, path)
+            .map_err(|err| RuntimeError::from(format!(
+                "unable to load CA certificate(s) from \"{}\": {}", path, err))));
+    }
+
+    Ok(ssl_context)
+}
+
 /// Data passed to the openssl_verify_callback().
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct VerifyCallbackData {
     /// Current hostname.
     cur_hostname: String,
+    /// Shared application context.
+    app_context:  Shared<AppContext>,
 }
 
 impl VerifyCallbackData {
     /// Create new verify callback data.
-    fn new(address: &str) -> VerifyCallbackData {
+    fn new(
+        address: &str,
+        app_context: Shared<AppContext>) -> VerifyCallbackData {
         VerifyCallbackData {
-            cur_hostname: get_hostname(address)
+            cur_hostname: get_hostname(address),
+            app_context:  app_context
+        }
+    }
+
+    /// Check if a given certificate validation error can be ignored because
+    /// the system clock is probably behind. The clock problem is recorded
+    /// into the application context in such case.
+    fn clock_grace(&self, err: Option<X509ValidationError>) -> bool {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        match err {
+            Some(X509ValidationError::X509CertNotYetValid)
+                if app_context.clock_grace_mode => {
+                app_context.clock_behind = true;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Check if a given certificate validation error can be ignored because
+    /// the certificate becomes valid within the clock skew tolerance.
+    fn clock_skew(&self, x509_ctx: &X509StoreContext) -> bool {
+        let tolerance = self.app_context.lock()
+            .unwrap()
+            .clock_skew_tolerance;
+
+        match x509_ctx.get_error() {
+            Some(X509ValidationError::X509CertNotYetValid) if tolerance > 0 =>
+                x509_ctx.get_current_cert()
+                    .map_or(false, |cert| x509::valid_within(&cert, tolerance)),
+            _ => false
+        }
+    }
+
+    /// Check if a given certificate validation error can be ignored because
+    /// the certificate is valid according to the process clock (i.e. the
+    /// system clock corrected using the built-in SNTP client).
+    ///
+    /// SNTP responses are not authenticated, so only a bounded forward
+    /// correction is trusted and it is used only for certificates that are
+    /// not yet valid. Expired certificates are never accepted.
+    fn process_clock(&self, x509_ctx: &X509StoreContext) -> bool {
+        let offset = clock::offset();
+
+        if !clock::is_synchronized()
+            || offset <= 0
+            || offset > SNTP_CLOCK_TOLERANCE {
+            return false;
+        }
+
+        match x509_ctx.get_error() {
+            Some(X509ValidationError::X509CertNotYetValid) =>
+                x509_ctx.get_current_cert()
+                    .map_or(false, |cert| x509::valid_at(&cert, clock::timestamp())),
+            _ => false
         }
     }
 
@@ -393,6 +903,11 @@ fn openssl_verify_callback(
     let data = data.lock()
         .unwrap();
 
+    let preverify_ok = preverify_ok
+        || data.process_clock(x509_ctx)
+        || data.clock_skew(x509_ctx)
+        || data.clock_grace(x509_ctx.get_error());
+
     preverify_ok && validate_hostname(x509_ctx, data.get_cur_hostname())
 }
 
@@ -428,6 +943,7 @@ fn spawn_arrow_thread<L: 'static + Logger + Clone + Send>(
     ssl_context: SslContext,
     cmd_sender: CommandSender,
     addr: &str,
+    relay: Option<String>,
     arrow_mac: &MacAddr,
     app_context: &Shared<AppContext>) {
     let state_file  = state_file.to_string();
@@ -437,7 +953,8 @@ fn spawn_arrow_thread<L: 'static + Logger + Clone + Send>(
 
     thread::spawn(move || arrow_thread(logger, &state_file,
         ssl_context, cmd_sender,
-        &addr, &arrow_mac, app_context));
+        &addr, relay.as_ref().map(|relay| relay as &str),
+        &arrow_mac, app_context));
 }
 
 /// Arrow Client main thread.
@@ -449,11 +966,25 @@ fn arrow_thread<L: Logger + Clone, Q: Sender<Command> + Clone>(
     mut ssl_context: SslContext,
     cmd_sender: Q,
     addr: &str,
+    relay: Option<&str>,
     arrow_mac: &MacAddr,
     app_context: Shared<AppContext>) {
-    let diagnostic_mode = app_context.lock()
-        .unwrap()
-        .diagnostic_mode;
+    let (diagnostic_mode, register_errors, client_cert) = {
+        let app_context = app_context.lock()
+            .unwrap();
+
+        (app_context.diagnostic_mode,
+            app_context.register_errors.clone(),
+            app_context.client_cert.clone())
+    };
+
+    #[cfg(feature = "websocket")]
+    let (transport, wss_endpoint) = {
+        let app_context = app_context.lock()
+            .unwrap();
+
+        (app_context.transport, app_context.wss_endpoint.clone())
+    };
 
     let t = time::precise_time_s();
 
@@ -461,40 +992,176 @@ fn arrow_thread<L: Logger + Clone, Q: Sender<Command> + Clone>(
     let mut cur_addr = addr.to_string();
     let mut last_attempt;
 
-    let verify_data = Shared::new(VerifyCallbackData::new(&cur_addr));
+    #[cfg(feature = "websocket")]
+    let mut use_websocket = transport == Transport::WebSocket;
+
+    let verify_data = Shared::new(VerifyCallbackData::new(&cur_addr,
+        app_context.clone()));
 
     ssl_context.set_verify_with_data(
         SSL_VERIFY_PEER,
         openssl_verify_callback,
         verify_data.clone());
 
+    let mut tls_generation = app_context.lock()
+        .unwrap()
+        .tls_generation;
+
     loop {
-        log_info!(logger, "connecting to remote Arrow Service {}", cur_addr);
+        #[cfg(feature = "websocket")]
+        let endpoint = if use_websocket {
+            let endpoint = wss_endpoint.clone()
+                .unwrap_or_else(|| WssEndpoint::for_address(&cur_addr));
+
+            log_fields!(logger, Severity::INFO, &[("ARROW_STATE", "connecting")],
+                "connecting to remote Arrow Service {} using WebSocket endpoint {}",
+                cur_addr, endpoint);
+
+            verify_data.lock()
+                .unwrap()
+                .set_cur_address(&endpoint.address());
+
+            Some(endpoint)
+        } else {
+            log_fields!(logger, Severity::INFO, &[("ARROW_STATE", "connecting")],
+                "connecting to remote Arrow Service {}", cur_addr);
+
+            None
+        };
+
+        #[cfg(not(feature = "websocket"))]
+        log_fields!(logger, Severity::INFO, &[("ARROW_STATE", "connecting")],
+            "connecting to remote Arrow Service {}", cur_addr);
 
         let lgr = logger.clone();
         let ctx = app_context.clone();
 
         last_attempt = time::precise_time_s();
 
+        {
+            let mut app_context = app_context.lock()
+                .unwrap();
+
+            // the flag will be set again by the verify callback if needed
+            app_context.clock_behind = false;
+            app_context.connection_state = LedState::Connecting;
+            app_context.status_led.set_state(LedState::Connecting);
+        }
+
         utils::result_or_log(&mut logger, Severity::INFO,
             "unable to save current connection state",
             save_connection_state(CONN_STATE_CONNECTED, state_file));
 
+        // rebuild the SSL context if the TLS files have changed (e.g. on
+        // SIGHUP)
+        let (generation, ca_certificates) = {
+            let app_context = app_context.lock()
+                .unwrap();
+
+            (app_context.tls_generation, app_context.ca_certificates.clone())
+        };
+
+        if generation != tls_generation {
+            tls_generation = generation;
+
+            match reload_ssl_context(&ca_certificates) {
+                Ok(mut new_context) => {
+                    new_context.set_verify_with_data(
+                        SSL_VERIFY_PEER,
+                        openssl_verify_callback,
+                        verify_data.clone());
+
+                    ssl_context = new_context;
+
+                    log_info!(logger, "CA certificates reloaded");
+                },
+                Err(err) => log_warn!(logger, "unable to reload CA certificates, using the previous ones: {}", err)
+            }
+        }
+
+        // reload the client certificate, so that it can be renewed without
+        // restarting the process
+        if let Some((ref cert_file, ref key_file)) = client_cert {
+            utils::result_or_log(&mut logger, Severity::WARN,
+                "unable to reload client certificate, using the previous one",
+                load_client_certificate(&mut ssl_context, cert_file, key_file));
+        }
+
+        #[cfg(feature = "websocket")]
+        let res = match endpoint {
+            Some(ref endpoint) => connect_via_websocket(lgr, &ssl_context,
+                cmd_sender.clone(), &cur_addr, endpoint, arrow_mac, ctx),
+            None => connect(lgr, &ssl_context, cmd_sender.clone(),
+                &cur_addr, relay, arrow_mac, ctx)
+        };
+
+        #[cfg(not(feature = "websocket"))]
         let res = connect(lgr, &ssl_context, cmd_sender.clone(),
-            &cur_addr, arrow_mac, ctx);
+            &cur_addr, relay, arrow_mac, ctx);
+
+        // switch the transport if the connection could not be established
+        // at all; the transport is kept once the client has registered
+        #[cfg(feature = "websocket")]
+        let fallback = {
+            let registered = app_context.lock()
+                .unwrap()
+                .connection_state == LedState::Registered;
+
+            match res {
+                Err(ref err) if transport == Transport::Auto
+                    && !registered
+                    && err.kind() == ErrorKind::ConnectionError => {
+                    use_websocket = !use_websocket;
+                    use_websocket
+                },
+                _ => false
+            }
+        };
+
+        #[cfg(not(feature = "websocket"))]
+        let fallback = false;
+
+        if fallback {
+            log_info!(logger, "unable to connect using TLS, falling back to the WebSocket transport");
+        }
 
         unauthorized_timeout = get_unauthorized_timeout(&res,
             last_attempt,
-            unauthorized_timeout);
+            unauthorized_timeout,
+            &register_errors);
 
         if diagnostic_mode {
             diagnose_connection_result(&res);
         }
 
+        let hook_env = match res {
+            Ok(_)        => Vec::new(),
+            Err(ref err) => vec![("ARROW_ERROR", err.description().to_string())]
+        };
+
+        let hook_res = app_context.lock()
+            .unwrap()
+            .hooks
+            .fire(HookEvent::Disconnected, &hook_env);
+
+        utils::result_or_log(&mut logger, Severity::WARN,
+            "unable to execute disconnect hook", hook_res);
+
         match res {
             Ok(addr) => cur_addr = addr,
             Err(err) => {
-                log_warn!(logger, "{}", err.description());
+                log_fields!(logger, Severity::WARN, &[("ARROW_STATE", "error")],
+                    "{}", err.description());
+
+                {
+                    let mut app_context = app_context.lock()
+                        .unwrap();
+
+                    app_context.connection_state = LedState::Error;
+                    app_context.last_error = Some((clock::timestamp(),
+                        err.description().to_string()));
+                    app_context.status_led.set_state(LedState::Error);
+                }
 
                 let res = match err.kind() {
                     ErrorKind::Unauthorized =>
@@ -507,11 +1174,15 @@ fn arrow_thread<L: Logger + Clone, Q: Sender<Command> + Clone>(
 
                 let t = get_next_retry_timeout(err,
                     last_attempt,
-                    unauthorized_timeout);
+                    unauthorized_timeout,
+                    &register_errors);
 
-                if t > 0.5 {
+                if t > 0.5 && !fallback {
                     log_info!(logger, "retrying in {:.3} seconds", t);
-                    thread::sleep(Duration::from_millis((t * 1000.0) as u64));
+
+                    if let Some(duration) = retry_sleep(t) {
+                        log_info!(logger, "system resume detected (suspended for {} seconds), reconnecting", duration);
+                    }
                 }
 
                 cur_addr = addr.to_string();
@@ -524,31 +1195,100 @@ fn arrow_thread<L: Logger + Clone, Q: Sender<Command> + Clone>(
     }
 }
 
+/// Sleep for a given number of seconds. The sleep is interrupted if the
+/// system gets suspended and resumed in the meantime (the suspend duration
+/// is returned in such case).
+fn retry_sleep(t: f64) -> Option<u64> {
+    let mut detector = SuspendDetector::new();
+
+    let end = time::precise_time_s() + t;
+
+    loop {
+        let remaining = end - time::precise_time_s();
+
+        if remaining <= 0.0 {
+            return None;
+        }
+
+        let step = if remaining > 1.0 { 1.0 } else { remaining };
+
+        thread::sleep(Duration::from_millis((step * 1000.0) as u64));
+
+        if let Some(duration) = detector.check() {
+            return Some(duration);
+        }
+    }
+}
+
 /// Save current connection state.
 fn save_connection_state(
     state: &str,
     state_file: &str) -> Result<(), io::Error> {
-    let file = try!(File::create(state_file));
-    let mut bwriter = BufWriter::new(file);
+    utils::write_file_atomic(state_file, format!("{}\n", state).as_bytes())
+}
 
-    try!(bwriter.write(state.as_bytes()));
-    try!(bwriter.write(b"\n"));
+/// Parse a positive timeout (in milliseconds) from a given "--name=ms"
+/// argument. The process exits if the value is not valid.
+fn parse_timeout(arg: &str) -> u64 {
+    let re = Regex::new(r"^--[a-z-]+=(\d+)$")
+        .unwrap();
 
-    Ok(())
+    match re.captures(arg) {
+        Some(ref caps) if caps.at(1) != Some("0") =>
+            u64::from_str(caps.at(1).unwrap())
+                .unwrap(),
+        _ => utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "positive number expected")
+    }
+}
+
+/// Get path of a given state file within a given state directory.
+fn state_dir_path(dir: &str, file: &str) -> String {
+    let name = Path::new(file)
+        .file_name()
+        .unwrap();
+
+    Path::new(dir)
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Check that a given state directory is writable (the directory is created
+/// if it does not exist).
+fn check_state_dir(dir: &str) -> Result<(), io::Error> {
+    let probe = Path::new(dir)
+        .join(".write-test");
+
+    try!(fs::create_dir_all(dir));
+    try!(File::create(&probe));
+
+    fs::remove_file(&probe)
+}
+
+/// Get action taken on a given connection error. None is returned if the
+/// error is not a REGISTER error.
+fn get_register_action(
+    connection_error: &ArrowError,
+    register_errors:  &RegisterErrorPolicy) -> Option<RegisterAction> {
+    connection_error.ack_code()
+        .map(|code| register_errors.action(code))
 }
 
 /// Get new timeout for the unauthorized state.
 fn get_unauthorized_timeout(
     connection_result:       &Result<String, ArrowError>,
     last_connection_attempt: f64,
-    current_timeout:         f64) -> f64 {
+    current_timeout:         f64,
+    register_errors:         &RegisterErrorPolicy) -> f64 {
     let t = time::precise_time_s();
     match connection_result {
         // We know the client is authorized, we can update the timeout.
         &Ok(_)        => t + 1200.0,
-        &Err(ref err) => match err.kind() {
-            // We don't update the timeout in case the client is unauthorized.
-            ErrorKind::Unauthorized => current_timeout,
+        &Err(ref err) => match get_register_action(err, register_errors) {
+            // We don't update the timeout in case the client is waiting for
+            // its configuration (e.g. it is unauthorized).
+            Some(RegisterAction::WaitForConfig) => current_timeout,
             // We don't know if the client is authorized but we assume it is
             // if the last connection was longer than RETRY_TIMEOUT seconds.
             _ => if (last_connection_attempt + RETRY_TIMEOUT) < t {
@@ -564,12 +1304,14 @@ fn get_unauthorized_timeout(
 fn get_next_retry_timeout(
     connection_error:        ArrowError,
     last_connection_attempt: f64,
-    unauthorized_timeout:    f64) -> f64 {
+    unauthorized_timeout:    f64,
+    register_errors:         &RegisterErrorPolicy) -> f64 {
     let t = time::precise_time_s();
-    match connection_error.kind() {
-        // the client is not authorized to access the service yet; check the
-        // unauthorized state timeout
-        ErrorKind::Unauthorized => match unauthorized_timeout {
+    match get_register_action(&connection_error, register_errors) {
+        // the client is not configured on the service side yet (e.g. it is
+        // not authorized to access the service); check the unauthorized
+        // state timeout
+        Some(RegisterAction::WaitForConfig) => match unauthorized_timeout {
             // retry every 10 seconds in the first 10 minutes since the first
             // "unauthorized" response
             timeout if t < (timeout - 600.0) => 10.0,
@@ -580,9 +1322,12 @@ fn get_next_retry_timeout(
             // "unauthorized" response
             _ => 36000.0
         },
-        // set a very long retry timeout if the version of the Arrow Protocol
-        // is not supported by either side
-        ErrorKind::UnsupportedProtocolVersion => 36000.0,
+        // set a very long retry timeout if the client cannot do anything
+        // about the error (e.g. the version of the Arrow Protocol is not
+        // supported by either side)
+        Some(RegisterAction::Halt) => 36000.0,
+        // reconnect immediately after the system has been resumed
+        _ if connection_error.kind() == ErrorKind::SystemResumed => 0.0,
         // in all other cases
         _ => RETRY_TIMEOUT + last_connection_attempt - time::precise_time_s()
     }
@@ -602,20 +1347,55 @@ fn diagnose_connection_result(
     }
 }
 
+/// Create a new Arrow client builder.
+fn client_builder<L: Logger + Clone, Q: Sender<Command>>(
+    logger: L,
+    ssl_context: &SslContext,
+    cmd_sender: Q,
+    arrow_mac: &MacAddr,
+    app_context: Shared<AppContext>) -> ArrowClientBuilder<L, Q> {
+    // the TLS context is reference counted, so the clone is cheap
+    ArrowClientBuilder::new(app_context, ssl_context.clone())
+        .logger(logger)
+        .cmd_sender(cmd_sender)
+        .arrow_mac(*arrow_mac)
+}
+
 /// Connect to a given Arrow Service.
 fn connect<L: Logger + Clone, Q: Sender<Command>>(
     logger: L,
     ssl_context: &SslContext,
     cmd_sender: Q,
     addr: &str,
+    relay: Option<&str>,
     arrow_mac: &MacAddr,
     app_context: Shared<AppContext>) -> Result<String, ArrowError> {
+    if let Some(relay) = relay {
+        return connect_via_relay(logger, ssl_context, cmd_sender,
+            addr, relay, arrow_mac, app_context);
+    }
+
+    #[cfg(feature = "socks")]
+    {
+        let proxy = app_context.lock()
+            .unwrap()
+            .socks_proxy
+            .clone();
+
+        if let Some(proxy) = proxy {
+            return connect_via_socks(logger, ssl_context, cmd_sender,
+                addr, &proxy, arrow_mac, app_context);
+        }
+    }
+
     let addr = try!(net::utils::get_socket_address(addr)
         .or(Err(ArrowError::connection_error(format!(
             "failed to lookup Arrow Service {} address information", addr)))));
 
-    match ArrowClient::new(logger, ssl_context, cmd_sender,
-        &addr, arrow_mac, app_context) {
+    let builder = client_builder(logger, ssl_context, cmd_sender,
+        arrow_mac, app_context);
+
+    match builder.connect(&addr) {
         Err(err) => Err(ArrowError::connection_error(format!(
             "unable to connect to remote Arrow Service {} ({})",
             addr, err.description()))),
@@ -623,41 +1403,325 @@ fn connect<L: Logger + Clone, Q: Sender<Command>>(
     }
 }
 
-#[cfg(feature = "discovery")]
-/// Run device discovery and update a given service table.
-fn network_scanner_thread<L: Logger + Clone>(
-    mut logger: L,
-    rtsp_paths_file: &str,
-    mjpeg_paths_file: &str,
-    app_context: Shared<AppContext>) {
-    log_info!(logger, "looking for local services...");
-    let report = utils::result_or_log(&mut logger, Severity::WARN,
-        "network scanner error",
-        discovery::scan_network(
-            rtsp_paths_file,
-            mjpeg_paths_file));
-
-    if let Some(report) = report {
-        let mut app_context = app_context.lock()
-            .unwrap();
-
-        {
-            let config   = &mut app_context.config;
-            let services = report.services();
-            let count    = services.len();
+/// Connect to a given Arrow Service through a given relay.
+fn connect_via_relay<L: Logger + Clone, Q: Sender<Command>>(
+    logger: L,
+    ssl_context: &SslContext,
+    cmd_sender: Q,
+    addr: &str,
+    relay: &str,
+    arrow_mac: &MacAddr,
+    app_context: Shared<AppContext>) -> Result<String, ArrowError> {
+    let relay_addr = try!(net::utils::get_socket_address(relay)
+        .or(Err(ArrowError::connection_error(format!(
+            "failed to lookup relay {} address information", relay)))));
+
+    let builder = client_builder(logger, ssl_context, cmd_sender,
+        arrow_mac, app_context);
+
+    match builder.connect_via_relay(&relay_addr, addr) {
+        Err(err) => Err(ArrowError::connection_error(format!(
+            "unable to connect to remote Arrow Service {} via relay {} ({})",
+            addr, relay_addr, err.description()))),
+        Ok(mut client) => client.event_loop()
+    }
+}
+
+/// Connect to a given Arrow Service through a given SOCKS5 proxy.
+#[cfg(feature = "socks")]
+fn connect_via_socks<L: Logger + Clone, Q: Sender<Command>>(
+    logger: L,
+    ssl_context: &SslContext,
+    cmd_sender: Q,
+    addr: &str,
+    proxy: &SocksProxy,
+    arrow_mac: &MacAddr,
+    app_context: Shared<AppContext>) -> Result<String, ArrowError> {
+    let builder = client_builder(logger, ssl_context, cmd_sender,
+        arrow_mac, app_context);
+
+    match builder.connect_via_socks(proxy, addr) {
+        Err(err) => Err(ArrowError::connection_error(format!(
+            "unable to connect to remote Arrow Service {} via SOCKS proxy {} ({})",
+            addr, proxy.address(), err.description()))),
+        Ok(mut client) => client.event_loop()
+    }
+}
+
+/// Connect to a given Arrow Service through a given WebSocket endpoint.
+#[cfg(feature = "websocket")]
+fn connect_via_websocket<L: Logger + Clone, Q: Sender<Command>>(
+    logger: L,
+    ssl_context: &SslContext,
+    cmd_sender: Q,
+    addr: &str,
+    endpoint: &WssEndpoint,
+    arrow_mac: &MacAddr,
+    app_context: Shared<AppContext>) -> Result<String, ArrowError> {
+    let endpoint_addr = try!(net::utils::get_socket_address(&endpoint.address() as &str)
+        .or(Err(ArrowError::connection_error(format!(
+            "failed to lookup WebSocket endpoint {} address information", endpoint)))));
+
+    let builder = client_builder(logger, ssl_context, cmd_sender,
+        arrow_mac, app_context);
+
+    match builder.connect_websocket(&endpoint_addr, endpoint) {
+        Err(err) => Err(ArrowError::connection_error(format!(
+            "unable to connect to remote Arrow Service {} via WebSocket endpoint {} ({})",
+            addr, endpoint, err.description()))),
+        Ok(mut client) => client.event_loop()
+    }
+}
+
+#[cfg(feature = "discovery")]
+/// Run device discovery and update a given service table.
+fn network_scanner_thread<L: Logger + Clone>(
+    mut logger: L,
+    rtsp_paths_file: &str,
+    mjpeg_paths_file: &str,
+    app_context: Shared<AppContext>) {
+    reload_oui_database(&mut logger, &app_context);
+
+    log_info!(logger, "looking for local services...");
+    let start_time = time::now_utc();
+
+    let (multicast, budget, old_services) = {
+        let mut app_context = app_context.lock()
+            .unwrap();
+
+        let multicast = app_context.sap_announcements
+            .streams(clock::timestamp());
+
+        let budget = ScanBudget::new(
+            app_context.scan_time_limit,
+            app_context.scan_probe_limit);
+
+        (multicast, budget, app_context.config.active_services())
+    };
+
+    // services found so far are added into the service table during the
+    // scan, so that the (already connected) Arrow Client can send them to
+    // Arrow Service without waiting for the whole scan to complete
+    let report = discovery::scan_network(
+        rtsp_paths_file,
+        mjpeg_paths_file,
+        &multicast,
+        &budget,
+        |partial| {
+            let mut app_context = app_context.lock()
+                .unwrap();
+
+            let untrusted = app_context.untrusted_interfaces.clone();
+
+            let config = &mut app_context.config;
 
-            for svc in services {
-                config.add(svc.clone());
+            let active = config.active_services();
+
+            add_found_services(&mut logger, config, partial, &untrusted, false);
+
+            config.update_active_services();
+
+            if config.active_services() != active {
+                config.bump_version();
+
+                log_debug!(logger, "services found so far have been added into the service table: {}",
+                    config.service_table());
             }
+        });
+
+    let report = utils::result_or_log(&mut logger, Severity::WARN,
+        "network scanner error",
+        report);
+
+    if let Some(report) = report {
+        if report.is_incomplete() {
+            log_warn!(logger, "network scan probing budget exhausted, the scan report is incomplete (see the --scan-time-limit and --scan-probe-limit options)");
+        }
+
+        let host_names = lookup_host_names(&mut logger, &report, &app_context);
+
+        let mut app_context = app_context.lock()
+            .unwrap();
+
+        // make sure the new names get to Arrow Service
+        if host_names.iter().any(|&(_, ref name)| name.is_some()) {
+            app_context.config.bump_version();
+        }
+
+        app_context.host_names.extend(host_names);
+
+        // media parameters are sent within the UPDATE messages as well
+        let media_changed = report.services()
+            .filter_map(|svc| svc.address())
+            .any(|addr| report.media_info(addr) != app_context.scan_report.media_info(addr));
+
+        if media_changed {
+            app_context.config.bump_version();
+        }
+
+        let untrusted = app_context.untrusted_interfaces.clone();
+
+        let summary = {
+            let config = &mut app_context.config;
+            let count  = report.services().len();
+
+            add_found_services(&mut logger, config, &report, &untrusted, true);
 
             config.update_active_services();
 
+            let new_services = config.active_services();
+
             log_info!(logger, "{} services found, current service table: {}",
                 count, config.service_table());
+
+            let duration = time::now_utc() - start_time;
+
+            ScanSummary {
+                timestamp:        start_time.to_timespec().sec,
+                duration:         duration.num_milliseconds() as u32,
+                hosts:            report.hosts().len() as u32,
+                new_services:     new_services.iter()
+                    .filter(|svc| !old_services.contains(svc))
+                    .count() as u32,
+                removed_services: old_services.iter()
+                    .filter(|svc| !new_services.contains(svc))
+                    .count() as u32
+            }
+        };
+
+        let events = app_context.network_monitor.as_mut()
+            .map(|monitor| monitor.update(&report, summary.timestamp))
+            .unwrap_or(Vec::new());
+
+        for event in events {
+            let vendor = app_context.oui_database.vendor(&event.mac_addr)
+                .unwrap_or("unknown")
+                .to_string();
+
+            log_warn!(logger, "network alert: {} (MAC: {}, vendor: {}, IP: {}, ports: {})",
+                event.name(), event.mac_addr, vendor, event.ip_addr,
+                event.port_list());
+
+            utils::result_or_log(&mut logger, Severity::WARN,
+                "unable to execute network-alert hook",
+                app_context.hooks.fire(HookEvent::NetworkAlert, &[
+                    ("ARROW_ALERT_TYPE", event.name().to_string()),
+                    ("ARROW_ALERT_MAC", format!("{}", event.mac_addr)),
+                    ("ARROW_ALERT_VENDOR", vendor),
+                    ("ARROW_ALERT_IP", format!("{}", event.ip_addr)),
+                    ("ARROW_ALERT_PORTS", event.port_list())]));
+        }
+
+        app_context.scan_report  = report;
+        app_context.scan_summary = summary;
+    }
+}
+
+#[cfg(feature = "discovery")]
+/// Add services from a given scan report into a given service table.
+/// Services asking for plaintext (Basic) credentials within an untrusted
+/// network are skipped (and reported if `report_skipped` is set).
+fn add_found_services<L: Logger>(
+    logger: &mut L,
+    config: &mut ArrowConfig,
+    report: &ScanReport,
+    untrusted: &UntrustedInterfaces,
+    report_skipped: bool) {
+    for svc in report.services() {
+        let untrusted_iface = match svc.address() {
+            Some(addr) if report.is_basic_auth(addr) =>
+                untrusted.interface_of(&addr.ip()),
+            _ => None
+        };
+
+        if let Some(iface) = untrusted_iface {
+            if report_skipped {
+                log_warn!(logger, "security advisory: service {} asks for plaintext (Basic) credentials within the untrusted network of interface {}, the service will not be added", svc, iface);
+            }
+        } else {
+            config.add(svc.clone());
+        }
+    }
+}
+
+#[cfg(feature = "discovery")]
+/// Reload the downloaded OUI vendor database (if it exists). The current
+/// database is kept if the file cannot be loaded.
+fn reload_oui_database<L: Logger>(
+    logger: &mut L,
+    app_context: &Shared<AppContext>) {
+    let file = app_context.lock()
+        .unwrap()
+        .oui_file
+        .clone();
+
+    let file = match file {
+        Some(ref file) if Path::new(file).exists() => file.clone(),
+        _ => return
+    };
+
+    let database = utils::result_or_log(logger, Severity::WARN,
+        format!("unable to load OUI database \"{}\"", file),
+        OuiDatabase::load(&file));
+
+    if let Some(database) = database {
+        app_context.lock()
+            .unwrap()
+            .oui_database = Arc::new(database);
+    }
+}
+
+#[cfg(all(feature = "discovery", feature = "dns"))]
+/// Look up host names of devices providing services found in a given scan
+/// report (if enabled). Only devices without any previous lookup result are
+/// considered.
+fn lookup_host_names<L: Logger>(
+    logger: &mut L,
+    report: &ScanReport,
+    app_context: &Shared<AppContext>) -> Vec<(MacAddr, Option<String>)> {
+    let mut hosts = HashMap::new();
+
+    {
+        let app_context = app_context.lock()
+            .unwrap();
+
+        if !app_context.resolve_host_names {
+            return Vec::new();
+        }
+
+        for svc in report.services() {
+            if let (Some(mac), Some(addr)) = (svc.mac(), svc.address()) {
+                if !app_context.host_names.contains_key(mac) {
+                    hosts.insert(*mac, addr.ip());
+                }
+            }
+        }
+    }
+
+    let mut res = Vec::new();
+
+    for (mac, ip) in hosts {
+        let name = dns::lookup_host_name(&ip);
+
+        if let Some(ref name) = name {
+            log_debug!(logger, "host name of {} ({}): {}", ip, mac, name);
+        } else {
+            log_debug!(logger, "unable to find host name of {} ({})", ip, mac);
         }
 
-        app_context.scan_report = report;
+        res.push((mac, name));
     }
+
+    res
+}
+
+#[cfg(all(feature = "discovery", not(feature = "dns")))]
+/// Dummy host name lookup.
+fn lookup_host_names<L>(
+    _: &mut L,
+    _: &ScanReport,
+    _: &Shared<AppContext>) -> Vec<(MacAddr, Option<String>)> {
+    Vec::new()
 }
 
 #[cfg(not(feature = "discovery"))]
@@ -665,17 +1729,42 @@ fn network_scanner_thread<L: Logger + Clone>(
 fn network_scanner_thread<L>(_: L, _: &str, _: &str, _: Shared<AppContext>) {
 }
 
+#[cfg(feature = "discovery")]
+/// Check if the network scanner can use raw sockets and log a warning if it
+/// cannot.
+fn check_discovery_capabilities<L: Logger>(
+    logger: &mut L,
+    app_context: &AppContext) {
+    if app_context.discovery && !discovery::raw_sockets_available() {
+        log_warn!(logger, "raw sockets are not available (missing NET_RAW capability?), falling back to degraded discovery (TCP connect scan)");
+    }
+}
+
+#[cfg(not(feature = "discovery"))]
+/// Dummy capability check.
+fn check_discovery_capabilities<L>(_: &mut L, _: &AppContext) {
+}
+
 /// Periodical event types.
 #[derive(Debug, Copy, Clone)]
 enum TimerEvent {
-    ScanNetwork
+    ScanNetwork,
+    SaveSvcStats,
+    ExportStats,
+    #[cfg(feature = "metrics")]
+    SampleTraffic,
+    DrainCheck,
+    WatchConfig,
+    ReloadCheck,
+    ResolveServices
 }
 
 /// Arrow Command wrapper/extender.
 #[derive(Debug, Copy, Clone)]
 enum CommandWrapper {
     Wrapped(Command),
-    ScanCompleted
+    ScanCompleted,
+    ResolveCompleted
 }
 
 /// Command channel.
@@ -705,6 +1794,28 @@ impl Sender<Command> for CommandSender {
     }
 }
 
+/// Restore the previous service table if the first network scan after a
+/// service table reset found dramatically fewer services than there were
+/// before the reset (e.g. the reset was sent by mistake or the network was
+/// temporarily unavailable).
+fn check_reset<L: Logger>(logger: &mut L, config: &mut ArrowConfig) {
+    let prev = match config.prev_service_table() {
+        Some(table) => table.active_services().len() - 1,
+        None => return
+    };
+
+    let current = config.service_table()
+        .active_services()
+        .len() - 1;
+
+    if prev >= AUTO_RESTORE_MIN_SERVICES && (current * 2) < prev {
+        log_warn!(logger, "only {} of {} services found after the service table reset, restoring the previous service table", current, prev);
+
+        config.restore_service_table();
+        config.bump_version();
+    }
+}
+
 /// Arrow command handler.
 struct CommandHandler<L: Logger> {
     logger:            L,
@@ -716,6 +1827,11 @@ struct CommandHandler<L: Logger> {
     app_context:       Shared<AppContext>,
     scanner:           Option<JoinHandle<()>>,
     last_scan:         f64,
+    reset_check:       bool,
+    config_watcher:    ConfigWatcher,
+    tls_digest:        [u8; 32],
+    resolver:          Option<ServiceResolver>,
+    resolver_thread:   Option<JoinHandle<(ServiceResolver, Vec<(Service, Service)>)>>,
 }
 
 impl<L: 'static + Logger + Clone + Send> CommandHandler<L> {
@@ -726,12 +1842,14 @@ impl<L: 'static + Logger + Clone + Send> CommandHandler<L> {
         rtsp_paths_file: &str,
         mjpeg_paths_file: &str,
         default_svc_table: ServiceTable,
+        resolver: ServiceResolver,
         app_context: Shared<AppContext>) -> CommandHandler<L> {
         let now = time::precise_time_s();
-        let active_services = {
+        let (active_services, tls_digest) = {
             let app_context = app_context.lock()
                 .unwrap();
-            app_context.config.active_services()
+            (app_context.config.active_services(),
+                reload::tls_digest(&tls_files(&app_context)))
         };
 
         CommandHandler {
@@ -743,7 +1861,12 @@ impl<L: 'static + Logger + Clone + Send> CommandHandler<L> {
             active_services:   active_services,
             app_context:       app_context,
             scanner:           None,
-            last_scan:         now - NETWORK_SCAN_PERIOD
+            last_scan:         now - NETWORK_SCAN_PERIOD,
+            reset_check:       false,
+            config_watcher:    ConfigWatcher::new(config_file),
+            tls_digest:        tls_digest,
+            resolver:          Some(resolver),
+            resolver_thread:   None
         }
     }
 
@@ -810,6 +1933,11 @@ impl<L: 'static + Logger + Clone + Send> CommandHandler<L> {
         let mut app_context = self.app_context.lock()
             .unwrap();
 
+        if self.reset_check && res.is_ok() {
+            self.reset_check = false;
+            check_reset(&mut self.logger, &mut app_context.config);
+        }
+
         {
             let config          = &mut app_context.config;
             let active_services = config.active_services();
@@ -827,111 +1955,712 @@ impl<L: 'static + Logger + Clone + Send> CommandHandler<L> {
 
         if res.is_err() {
             log_warn!(self.logger, "network scanner thread panicked");
+        } else {
+            let summary    = app_context.scan_summary;
+            let incomplete = app_context.scan_report.is_incomplete();
+
+            utils::result_or_log(&mut self.logger, Severity::WARN,
+                "unable to execute scan-complete hook",
+                app_context.hooks.fire(HookEvent::ScanCompleted, &[
+                    ("ARROW_SCAN_HOSTS", format!("{}", summary.hosts)),
+                    ("ARROW_SCAN_NEW_SERVICES",
+                        format!("{}", summary.new_services)),
+                    ("ARROW_SCAN_REMOVED_SERVICES",
+                        format!("{}", summary.removed_services)),
+                    ("ARROW_SCAN_INCOMPLETE",
+                        format!("{}", incomplete as u8))]));
         }
     }
 
-    /// Reinitialize the shared config with the default service table.
-    fn reset_svc_table(&mut self) {
+    /// Save service statistics (if modified) and schedule the next save.
+    /// Statistics of services which are no longer in the service table are
+    /// dropped.
+    fn periodical_svc_stats_save(&mut self, event_loop: &mut EventLoop<Self>) {
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+
+            let app_context = &mut *app_context;
+
+            let config = &app_context.config;
+
+            app_context.svc_stats.prune(|svc_id| config.get(svc_id).is_some());
+
+            utils::result_or_log(&mut self.logger, Severity::WARN,
+                "unable to save service statistics",
+                app_context.svc_stats.save());
+        }
+
+        event_loop.timeout_ms(TimerEvent::SaveSvcStats, SVC_STATS_SAVE_PERIOD)
+            .unwrap();
+    }
+
+    /// Export connection and session statistics and schedule the next
+    /// export.
+    fn periodical_stats_export(&mut self, event_loop: &mut EventLoop<Self>) {
         let mut app_context = self.app_context.lock()
             .unwrap();
-        let config = &mut app_context.config;
-        let table  = &self.default_svc_table;
 
-        config.reinit(table.clone());
-        config.bump_version();
+        let connection = app_context.connection_stats();
 
-        utils::result_or_log(&mut self.logger, Severity::WARN,
-            format!("unable to save config file \"{}\"", self.config_file),
-            config.save(&self.config_file));
+        if let Some(ref mut stats_export) = app_context.stats_export {
+            utils::result_or_log(&mut self.logger, Severity::WARN,
+                "unable to export statistics",
+                stats_export.export(&connection));
+
+            event_loop.timeout_ms(TimerEvent::ExportStats,
+                    stats_export.period() * 1000)
+                .unwrap();
+        }
+
+        // the high-water mark covers a single export period
+        app_context.uplink_high_water = 0;
     }
-}
 
-impl<L: 'static + Logger + Clone + Send> Handler for CommandHandler<L> {
-    type Timeout = TimerEvent;
-    type Message = CommandWrapper;
+    /// Sample network interface traffic and schedule the next sample.
+    #[cfg(feature = "metrics")]
+    fn periodical_traffic_sample(&mut self, event_loop: &mut EventLoop<Self>) {
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
 
-    fn timeout(
-        &mut self,
-        event_loop: &mut EventLoop<Self>,
-        event: TimerEvent) {
-        match event {
-            TimerEvent::ScanNetwork => self.periodical_network_scan(event_loop)
+            utils::result_or_log(&mut self.logger, Severity::DEBUG,
+                "unable to sample network interface traffic",
+                app_context.traffic.sample());
         }
+
+        event_loop.timeout_ms(TimerEvent::SampleTraffic, TRAFFIC_SAMPLE_PERIOD)
+            .unwrap();
     }
 
-    fn notify(
-        &mut self,
-        event_loop: &mut EventLoop<Self>,
-        cmd: CommandWrapper) {
-        match cmd {
-            CommandWrapper::ScanCompleted  => self.scan_completed(),
-            CommandWrapper::Wrapped(cmd)   => match cmd {
-                Command::ResetServiceTable => self.reset_svc_table(),
-                Command::ScanNetwork       => self.scan_network(event_loop)
+    /// Exit if the control socket has been handed over to a new process and
+    /// all sessions are finished (or the drain timeout has expired),
+    /// otherwise schedule the next check.
+    fn periodical_drain_check(&mut self, event_loop: &mut EventLoop<Self>) {
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+
+            if let Some(deadline) = app_context.drain_deadline {
+                let sessions = app_context.active_sessions.len();
+
+                if sessions == 0 || clock::timestamp() >= deadline {
+                    if sessions == 0 {
+                        log_info!(self.logger, "all sessions finished, exiting");
+                    } else {
+                        log_warn!(self.logger, "drain timeout expired, exiting ({} sessions will be closed)", sessions);
+                    }
+
+                    utils::result_or_log(&mut self.logger, Severity::WARN,
+                        "unable to save service statistics",
+                        app_context.svc_stats.save());
+
+                    process::exit(0);
+                }
             }
         }
+
+        event_loop.timeout_ms(TimerEvent::DrainCheck, DRAIN_CHECK_PERIOD)
+            .unwrap();
     }
-}
 
-const EXIT_CODE_USAGE:         i32 = 1;
-const EXIT_CODE_NETWORK_ERROR: i32 = 2;
-const EXIT_CODE_CONFIG_ERROR:  i32 = 3;
-const EXIT_CODE_SSL_ERROR:     i32 = 4;
-const EXIT_CODE_CERT_ERROR:    i32 = 5;
+    /// Reload the service table if the configuration file has been modified
+    /// externally and schedule the next check. The version is bumped if
+    /// the table has changed, so an UPDATE is sent automatically.
+    fn periodical_config_watch(&mut self, event_loop: &mut EventLoop<Self>) {
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
 
-/// Init file logger for a given file, file size limit and a given number of rotations.
-fn init_file_logger(file: &str, limit: usize, rotations: usize) -> logger::file::FileLogger {
-    utils::result_or_error(
-        logger::file::new(file, limit, rotations),
-        EXIT_CODE_CONFIG_ERROR,
-        "unable to open the given log file")
-}
+            let config = &mut app_context.config;
 
-/// Helper struct for application configuration.
-struct AppConfiguration {
-    logger:            LoggerWrapper,
-    ssl_context:       SslContext,
-    app_context:       AppContext,
-    default_svc_table: ServiceTable,
-    arrow_svc_addr:    String,
-    arrow_mac:         MacAddr,
-    config_file:       String,
-    state_file:        String,
-    rtsp_paths_file:   String,
-    mjpeg_paths_file:  String,
-}
+            match self.config_watcher.check(config) {
+                Ok(Some(diff)) => {
+                    if diff.is_empty() {
+                        log_info!(self.logger, "config file \"{}\" modified externally, no changes in active services", self.config_file);
+                    } else {
+                        log_info!(self.logger, "config file \"{}\" modified externally, service table reloaded:\n{}", self.config_file, diff);
+                    }
 
-impl AppConfiguration {
-    /// Initialize application configuration.
-    fn init() -> AppConfiguration {
-        let parser = AppConfigurationParser::parse(&mut env::args());
+                    utils::result_or_log(&mut self.logger, Severity::WARN,
+                        format!("unable to save config file \"{}\"", self.config_file),
+                        config.save(&self.config_file));
+                },
+                Ok(None) => (),
+                Err(err) => log_warn!(self.logger, "unable to reload externally modified config file \"{}\" ({})", self.config_file, err)
+            }
+        }
 
-        let logger = match parser.logger_type {
-            LoggerType::Syslog       => LoggerWrapper::new(logger::syslog::new()),
-            LoggerType::Stderr       => LoggerWrapper::new(logger::stderr::new()),
-            LoggerType::StderrPretty => LoggerWrapper::new(logger::stderr::new_pretty()),
-            LoggerType::FileLogger   => LoggerWrapper::new(init_file_logger(
-                &parser.log_file,
-                parser.log_file_size,
-                parser.log_file_rotations
-            )),
-        };
+        event_loop.timeout_ms(TimerEvent::WatchConfig, CONFIG_WATCH_PERIOD)
+            .unwrap();
+    }
 
-        let ssl_context = utils::result_or_error(
-            init_ssl(SslMethod::Tlsv1_2, "HIGH:!aNULL:!kRSA:!PSK:!MD5:!RC4"),
-            EXIT_CODE_SSL_ERROR,
-            "unable to set up SSL context");
+    /// Spawn a new thread resolving addresses of static services (if it is
+    /// not already running) and schedule the next resolution.
+    fn periodical_service_resolve(&mut self, event_loop: &mut EventLoop<Self>) {
+        if let Some(mut resolver) = self.resolver.take() {
+            let mut logger = self.logger.clone();
+            let sender     = event_loop.channel();
 
-        let config = ArrowConfig::load(&parser.config_file)
-            .unwrap_or(ArrowConfig::new());
+            let handle = thread::spawn(move || {
+                let changes = resolver.resolve(&mut logger);
 
-        let mut config = AppConfiguration {
-            logger:            logger,
-            ssl_context:       ssl_context,
-            app_context:       AppContext::new(config),
-            default_svc_table: ServiceTable::new(),
-            arrow_svc_addr:    parser.arrow_svc_addr,
-            arrow_mac:         parser.arrow_mac,
+                sender.send(CommandWrapper::ResolveCompleted)
+                    .unwrap();
+
+                (resolver, changes)
+            });
+
+            self.resolver_thread = Some(handle);
+        }
+
+        event_loop.timeout_ms(TimerEvent::ResolveServices,
+                SERVICE_RESOLVE_PERIOD)
+            .unwrap();
+    }
+
+    /// Called upon service resolver thread completion. Static services with
+    /// a changed address are replaced keeping their service IDs and the
+    /// version is bumped, so an UPDATE is sent automatically.
+    fn resolve_completed(&mut self) {
+        let res = match self.resolver_thread.take() {
+            Some(handle) => handle.join(),
+            _ => return
+        };
+
+        let changes = match res {
+            Ok((resolver, changes)) => {
+                self.resolver = Some(resolver);
+                changes
+            },
+            Err(_) => {
+                log_warn!(self.logger, "service resolver thread panicked, addresses of static services will not be updated");
+                return;
+            }
+        };
+
+        if changes.is_empty() {
+            return;
+        }
+
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        let config = &mut app_context.config;
+
+        for (old, new) in changes {
+            // static services always have an address
+            log_info!(self.logger, "address of a static service changed ({} -> {})",
+                old.address().unwrap(), new.address().unwrap());
+
+            config.replace(&old, new.clone());
+
+            self.default_svc_table.replace(&old, new);
+        }
+
+        self.active_services = config.active_services();
+
+        config.bump_version();
+
+        utils::result_or_log(&mut self.logger, Severity::WARN,
+            format!("unable to save config file \"{}\"", self.config_file),
+            config.save(&self.config_file));
+    }
+
+    /// Reload the configuration if requested (i.e. on SIGHUP) and schedule
+    /// the next check.
+    fn periodical_reload_check(&mut self, event_loop: &mut EventLoop<Self>) {
+        if reload::take_request() {
+            self.reload_config();
+        }
+
+        event_loop.timeout_ms(TimerEvent::ReloadCheck, RELOAD_CHECK_PERIOD)
+            .unwrap();
+    }
+
+    /// Reload the service table from the configuration file and check the
+    /// TLS files. The running connection is kept: an UPDATE message is sent
+    /// if active services have changed and new TLS settings are used for
+    /// the next connection to Arrow Service.
+    fn reload_config(&mut self) {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        log_info!(self.logger, "reloading configuration...");
+
+        match self.config_watcher.reload(&mut app_context.config) {
+            Ok(diff) => {
+                if diff.is_empty() {
+                    log_info!(self.logger, "config file \"{}\" reloaded, no changes in active services", self.config_file);
+                } else {
+                    log_info!(self.logger, "config file \"{}\" reloaded, service table changes:\n{}", self.config_file, diff);
+                }
+
+                utils::result_or_log(&mut self.logger, Severity::WARN,
+                    format!("unable to save config file \"{}\"", self.config_file),
+                    app_context.config.save(&self.config_file));
+            },
+            Err(err) => log_warn!(self.logger, "unable to reload config file \"{}\", keeping the current service table ({})", self.config_file, err)
+        }
+
+        let tls_digest = reload::tls_digest(&tls_files(&app_context));
+
+        if tls_digest != self.tls_digest {
+            self.tls_digest = tls_digest;
+
+            app_context.tls_generation += 1;
+
+            log_info!(self.logger, "TLS certificates changed, they will be used for the next connection to Arrow Service");
+        }
+    }
+
+    /// Reinitialize the shared config with the default service table. The
+    /// current table is kept as an inactive generation and it is restored
+    /// automatically if the next network scan finds dramatically fewer
+    /// services.
+    fn reset_svc_table(&mut self) {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        let config = &mut app_context.config;
+        let table  = &self.default_svc_table;
+
+        config.reinit(table.clone());
+        config.bump_version();
+
+        log_info!(self.logger, "service table reset, the previous table can be restored using the \"restore-svc-table\" control socket command");
+
+        self.reset_check = true;
+
+        utils::result_or_log(&mut self.logger, Severity::WARN,
+            format!("unable to save config file \"{}\"", self.config_file),
+            config.save(&self.config_file));
+    }
+}
+
+impl<L: 'static + Logger + Clone + Send> Handler for CommandHandler<L> {
+    type Timeout = TimerEvent;
+    type Message = CommandWrapper;
+
+    fn timeout(
+        &mut self,
+        event_loop: &mut EventLoop<Self>,
+        event: TimerEvent) {
+        match event {
+            TimerEvent::ScanNetwork     => self.periodical_network_scan(event_loop),
+            TimerEvent::SaveSvcStats    => self.periodical_svc_stats_save(event_loop),
+            TimerEvent::ExportStats     => self.periodical_stats_export(event_loop),
+            #[cfg(feature = "metrics")]
+            TimerEvent::SampleTraffic   => self.periodical_traffic_sample(event_loop),
+            TimerEvent::DrainCheck      => self.periodical_drain_check(event_loop),
+            TimerEvent::WatchConfig     => self.periodical_config_watch(event_loop),
+            TimerEvent::ReloadCheck     => self.periodical_reload_check(event_loop),
+            TimerEvent::ResolveServices => self.periodical_service_resolve(event_loop)
+        }
+    }
+
+    fn notify(
+        &mut self,
+        event_loop: &mut EventLoop<Self>,
+        cmd: CommandWrapper) {
+        match cmd {
+            CommandWrapper::ScanCompleted    => self.scan_completed(),
+            CommandWrapper::ResolveCompleted => self.resolve_completed(),
+            CommandWrapper::Wrapped(cmd)     => match cmd {
+                Command::ResetServiceTable => self.reset_svc_table(),
+                Command::ScanNetwork       => self.scan_network(event_loop)
+            }
+        }
+    }
+}
+
+const EXIT_CODE_USAGE:         i32 = 1;
+const EXIT_CODE_NETWORK_ERROR: i32 = 2;
+const EXIT_CODE_CONFIG_ERROR:  i32 = 3;
+const EXIT_CODE_SSL_ERROR:     i32 = 4;
+const EXIT_CODE_CERT_ERROR:    i32 = 5;
+
+/// Init file logger for a given file, file size limit, a given number of
+/// rotations, age limit and compression of rotated files.
+fn init_file_logger(
+    file: &str,
+    limit: usize,
+    rotations: usize,
+    max_age: Option<u64>,
+    compress: bool) -> logger::file::FileLogger {
+    let mut logger = utils::result_or_error(
+        logger::file::new(file, limit, rotations),
+        EXIT_CODE_CONFIG_ERROR,
+        "unable to open the given log file");
+
+    logger.set_max_age(max_age);
+    logger.set_compression(compress);
+
+    logger
+}
+
+/// Run startup checks and get the client MAC address. All problems found
+/// are reported at once and the process exits (with the exit code of the
+/// first problem) if there is any.
+fn preflight(
+    parser: &AppConfigurationParser,
+    ssl_context: &mut SslContext) -> MacAddr {
+    let mut problems = Vec::new();
+
+    if parser.ca_certificates.is_empty() {
+        problems.push((EXIT_CODE_CERT_ERROR, String::from("no CA certificates given, Arrow Service certificate cannot be verified (use -c path, e.g. -c /etc/ssl/certs)")));
+    }
+
+    for path in &parser.ca_certificates {
+        if !Path::new(path).exists() {
+            problems.push((EXIT_CODE_CERT_ERROR, format!("CA certificate path \"{}\" does not exist", path)));
+        } else if let Err(err) = load_ca_certificates(ssl_context, path) {
+            problems.push((EXIT_CODE_CERT_ERROR, format!("unable to load CA certificate(s) from \"{}\" ({}), check that the path contains PEM/DER certificates readable by this user", path, err)));
+        }
+    }
+
+    if let Some(ref cert_file) = parser.client_cert {
+        let key_file = parser.client_key.as_ref()
+            .unwrap_or(cert_file);
+
+        if let Err(err) = load_client_certificate(ssl_context, cert_file, key_file) {
+            problems.push((EXIT_CODE_CERT_ERROR, format!("unable to load client certificate from \"{}\" and \"{}\" ({}), check that the files contain a PEM certificate and its matching private key", cert_file, key_file, err)));
+        }
+    } else if parser.client_key.is_some() {
+        problems.push((EXIT_CODE_CERT_ERROR, String::from("client key given without client certificate (use --client-cert=path)")));
+    }
+
+    let arrow_mac = if let Some(ref iface) = parser.interface {
+        match get_mac(iface) {
+            Ok(mac) => Some(mac),
+            Err(_)  => {
+                let available = EthernetDevice::list()
+                    .into_iter()
+                    .map(|dev| dev.name)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                problems.push((EXIT_CODE_NETWORK_ERROR, format!("network interface \"{}\" does not exist (available interfaces: {})", iface, available)));
+
+                None
+            }
+        }
+    } else if let Some(mac) = parser.arrow_mac {
+        Some(mac)
+    } else {
+        match get_default_mac(&parser.config_file, &parser.identity_file) {
+            Ok(mac) => Some(mac),
+            Err(err) => {
+                problems.push((EXIT_CODE_NETWORK_ERROR, format!("unable to get any network interface MAC address ({}), use -i iface or --mac=addr to set it explicitly", err)));
+
+                None
+            }
+        }
+    };
+
+    let config_dir = Path::new(&parser.config_file)
+        .parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or(String::new());
+
+    if !config_dir.is_empty() {
+        if let Err(err) = check_state_dir(&config_dir) {
+            problems.push((EXIT_CODE_CONFIG_ERROR, format!("config directory \"{}\" is not writable ({}), use --config-file=path or --state-dir=path to choose a different location", config_dir, err)));
+        }
+    }
+
+    if problems.is_empty() {
+        return arrow_mac.unwrap();
+    }
+
+    println!("ERROR: startup checks failed:");
+
+    for &(_, ref problem) in &problems {
+        println!("  * {}", problem);
+    }
+
+    println!("");
+
+    process::exit(problems[0].0);
+}
+
+/// Another logical Arrow client running within the same process.
+struct Tenant {
+    config_file:    String,
+    state_file:     String,
+    arrow_svc_addr: String,
+    ssl_context:    SslContext,
+    config:         ArrowConfig,
+}
+
+impl Tenant {
+    /// Load tenant configuration from a given file (a new configuration is
+    /// created if the file does not exist, it is saved once the instance
+    /// lock is acquired). The tenant connects to a given
+    /// Arrow Service address and it verifies its certificate using given CA
+    /// certificates.
+    fn load<L: Logger>(
+        logger: &mut L,
+        config_file: &str,
+        arrow_svc_addr: &str,
+        ca_certificates: &[String]) -> Tenant {
+        let mut ssl_context = utils::result_or_error(
+            init_ssl(SslMethod::Tlsv1_2, "HIGH:!aNULL:!kRSA:!PSK:!MD5:!RC4"),
+            EXIT_CODE_SSL_ERROR,
+            "unable to set up SSL context");
+
+        // the paths have been already checked by the startup checks
+        for path in ca_certificates {
+            utils::result_or_error(
+                load_ca_certificates(&mut ssl_context, path),
+                EXIT_CODE_CERT_ERROR,
+                format!("unable to load CA certificate(s) from \"{}\"", path));
+        }
+
+        let config = match utils::load_with_backup(config_file, ArrowConfig::load) {
+            Ok((config, false)) => config,
+            Ok((config, true))  => {
+                log_warn!(logger, "unable to load tenant config file \"{}\", configuration recovered from its backup", config_file);
+                config
+            },
+            Err(_) => ArrowConfig::new()
+        };
+
+        Tenant {
+            config_file:    config_file.to_string(),
+            state_file:     format!("{}.state", config_file),
+            arrow_svc_addr: arrow_svc_addr.to_string(),
+            ssl_context:    ssl_context,
+            config:         config
+        }
+    }
+}
+
+/// Parser of a static service given on the command line (i.e. a service URL
+/// or a "host:port" address).
+type ServiceParser = fn(&str, &mut DnsCache) -> Result<Service, RuntimeError>;
+
+/// Static service given on the command line.
+struct StaticService {
+    spec:    String,
+    parser:  ServiceParser,
+    service: Service,
+}
+
+/// Resolver of static service addresses. The services are parsed again
+/// periodically, so that they follow address changes of their host names
+/// (host names are queried only once their DNS cache entries expire).
+struct ServiceResolver {
+    dns_cache: DnsCache,
+    services:  Vec<StaticService>,
+}
+
+impl ServiceResolver {
+    /// Create a new resolver using a given DNS cache.
+    fn new(dns_cache: DnsCache) -> ServiceResolver {
+        ServiceResolver {
+            dns_cache: dns_cache,
+            services:  Vec::new()
+        }
+    }
+
+    /// Parse a given static service and remember it.
+    fn add(
+        &mut self,
+        spec: &str,
+        parser: ServiceParser) -> Result<Service, RuntimeError> {
+        let service = try!(parser(spec, &mut self.dns_cache));
+
+        self.services.push(StaticService {
+            spec:    spec.to_string(),
+            parser:  parser,
+            service: service.clone()
+        });
+
+        Ok(service)
+    }
+
+    /// Parse all static services again and return the changed ones (as
+    /// pairs of the old and the new service). Services which cannot be
+    /// resolved are kept unchanged.
+    fn resolve<L: Logger>(&mut self, logger: &mut L) -> Vec<(Service, Service)> {
+        let mut res = Vec::new();
+
+        for svc in &mut self.services {
+            match (svc.parser)(&svc.spec, &mut self.dns_cache) {
+                Ok(service) => if service != svc.service {
+                    let old = mem::replace(&mut svc.service, service.clone());
+
+                    res.push((old, service));
+                },
+                Err(err) => log_warn!(logger, "unable to resolve static service address, keeping the previous one ({})", err)
+            }
+        }
+
+        utils::result_or_log(logger, Severity::WARN,
+            "unable to save DNS cache",
+            self.dns_cache.save());
+
+        res
+    }
+}
+
+/// Parse a given HTTP service address.
+fn parse_http_service(
+    addr: &str,
+    dns_cache: &mut DnsCache) -> Result<Service, RuntimeError> {
+    let addr = try!(dns_cache.resolve_addr(addr)
+        .or(Err(RuntimeError::from(
+            "unable to resolve HTTP service address"))));
+
+    let mac = get_fake_mac_address(0xffff, &addr);
+
+    Ok(Service::HTTP(mac, addr))
+}
+
+/// Parse a given TCP service address.
+fn parse_tcp_service(
+    addr: &str,
+    dns_cache: &mut DnsCache) -> Result<Service, RuntimeError> {
+    let addr = try!(dns_cache.resolve_addr(addr)
+        .or(Err(RuntimeError::from(
+            "unable to resolve TCP service address"))));
+
+    let mac = get_fake_mac_address(0xffff, &addr);
+
+    Ok(Service::TCP(mac, addr))
+}
+
+/// Parse a given UDP service address.
+fn parse_udp_service(
+    addr: &str,
+    dns_cache: &mut DnsCache) -> Result<Service, RuntimeError> {
+    let addr = try!(dns_cache.resolve_addr(addr)
+        .or(Err(RuntimeError::from(
+            "unable to resolve UDP service address"))));
+
+    let mac = get_fake_mac_address(0xffff, &addr);
+
+    Ok(Service::UDP(mac, addr))
+}
+
+/// Helper struct for application configuration.
+struct AppConfiguration {
+    logger:            LoggerWrapper,
+    ssl_context:       SslContext,
+    app_context:       AppContext,
+    default_svc_table: ServiceTable,
+    resolver:          ServiceResolver,
+    arrow_svc_addr:    String,
+    relay_addr:        Option<String>,
+    relay_listen:      Option<String>,
+    relay_allow:       Vec<String>,
+    control_socket:    String,
+    status_page:       Option<String>,
+    takeover:          bool,
+    sap_listen:        bool,
+    one_shot_register: bool,
+    watch_config:      bool,
+    ntp_servers:       Vec<String>,
+    tenants:           Vec<Tenant>,
+    arrow_mac:         MacAddr,
+    config_file:       String,
+    state_file:        String,
+    rtsp_paths_file:   String,
+    mjpeg_paths_file:  String,
+}
+
+impl AppConfiguration {
+    /// Initialize application configuration.
+    fn init() -> AppConfiguration {
+        let mut args = env::args()
+            .collect::<Vec<_>>();
+
+        // take the configuration from environment variables if there are no
+        // command line arguments
+        if args.len() < 2 {
+            if let Some(env_args) = env_config::args(env::vars()) {
+                args.extend(env_args);
+            }
+        }
+
+        let parser = AppConfigurationParser::parse(&mut args.into_iter());
+
+        let logger = match parser.logger_type {
+            LoggerType::Syslog       => LoggerWrapper::new(logger::syslog::new()),
+            LoggerType::Stderr       => LoggerWrapper::new(logger::stderr::new()),
+            LoggerType::StderrPretty => LoggerWrapper::new(logger::stderr::new_pretty()),
+            LoggerType::Json         => LoggerWrapper::new(logger::json::new()),
+            LoggerType::Journald     => LoggerWrapper::new(logger::journald::new()),
+            LoggerType::FileLogger   => LoggerWrapper::new(init_file_logger(
+                &parser.log_file,
+                parser.log_file_size,
+                parser.log_file_rotations,
+                parser.log_file_max_age,
+                parser.log_file_gzip
+            )),
+        };
+
+        // warnings are published into the live event feed as well; the log
+        // level is shared by all logger clones, so that it can be changed
+        // remotely
+        let logger = logger::level::new(
+            EventLogger::new(logger, parser.hooks.events().clone()));
+
+        let log_level = logger.controller();
+
+        let mut logger = LoggerWrapper::new(logger);
+
+        let mut ssl_context = utils::result_or_error(
+            init_ssl(SslMethod::Tlsv1_2, "HIGH:!aNULL:!kRSA:!PSK:!MD5:!RC4"),
+            EXIT_CODE_SSL_ERROR,
+            "unable to set up SSL context");
+
+        let arrow_mac = preflight(&parser, &mut ssl_context);
+
+        let config = match utils::load_with_backup(&parser.config_file, ArrowConfig::load) {
+            Ok((config, false)) => config,
+            Ok((config, true))  => {
+                log_warn!(logger, "unable to load config file \"{}\", configuration recovered from its backup", parser.config_file);
+                config
+            },
+            Err(_) => parser.init_config_file.as_ref()
+                .and_then(|file| ArrowConfig::load(file).ok())
+                .unwrap_or(ArrowConfig::new())
+        };
+
+        if let Some(ref dir) = parser.state_dir {
+            if let Err(err) = check_state_dir(dir) {
+                log_warn!(logger, "state directory \"{}\" is not writable, the client state will not be persisted ({})", dir, err);
+            }
+        }
+
+        let status_led = parser.status_led.as_ref()
+            .and_then(|output| utils::result_or_log(&mut logger,
+                Severity::WARN,
+                "unable to initialize status LED",
+                StatusLed::start(output, &parser.led_patterns)))
+            .unwrap_or(StatusLed::disabled());
+
+        let mut config = AppConfiguration {
+            logger:            logger,
+            ssl_context:       ssl_context,
+            app_context:       AppContext::new(config),
+            default_svc_table: ServiceTable::new(),
+            resolver:          ServiceResolver::new(
+                DnsCache::load(&parser.dns_cache_file)),
+            arrow_svc_addr:    parser.arrow_svc_addr,
+            relay_addr:        parser.relay_addr,
+            relay_listen:      parser.relay_listen,
+            relay_allow:       parser.relay_allow,
+            control_socket:    parser.control_socket,
+            status_page:       parser.status_page,
+            takeover:          parser.takeover,
+            sap_listen:        parser.sap_listen,
+            one_shot_register: parser.one_shot_register,
+            watch_config:      parser.watch_config,
+            ntp_servers:       parser.ntp_servers,
+            tenants:           Vec::new(),
+            arrow_mac:         arrow_mac,
             config_file:       parser.config_file,
             state_file:        parser.state_file,
             rtsp_paths_file:   parser.rtsp_paths_file,
@@ -942,6 +2671,21 @@ impl AppConfiguration {
             config.logger.set_level(Severity::DEBUG);
         }
 
+        config.app_context.log_level = Some(log_level);
+
+        if let Some(ref file) = parser.relay_secret_file {
+            let secret = utils::result_or_error(auth::read_secret_file(file),
+                EXIT_CODE_CONFIG_ERROR,
+                format!("unable to read relay secret file \"{}\"", file));
+
+            if secret.is_empty() {
+                utils::error(RuntimeError::from(file as &str),
+                    EXIT_CODE_CONFIG_ERROR, "relay secret file is empty");
+            }
+
+            config.app_context.relay_secret = Some(Secret::new(secret));
+        }
+
         if parser.discovery {
             config.app_context.discovery = true;
         }
@@ -950,334 +2694,1913 @@ impl AppConfiguration {
             config.app_context.diagnostic_mode = true;
         }
 
-        for ca_certificates in parser.ca_certificates {
-            config.add_ca_certificates(&ca_certificates);
+        if parser.clock_grace_mode {
+            config.app_context.clock_grace_mode = true;
         }
 
-        for rtsp_service in parser.rtsp_services {
-            config.add_rtsp_service(&rtsp_service);
+        config.app_context.metadata = parser.metadata;
+        config.app_context.service_connect_timeout =
+            parser.service_connect_timeout;
+        config.app_context.uplink_write_timeout =
+            parser.uplink_write_timeout;
+        config.app_context.buffer_alert_threshold =
+            parser.buffer_alert_threshold;
+        config.app_context.ack_timeout = parser.ack_timeout;
+        config.app_context.session_write_timeout =
+            parser.session_write_timeout;
+        config.app_context.clock_skew_tolerance =
+            parser.clock_skew_tolerance;
+        config.app_context.svc_stats =
+            ServiceStatsTable::load(&parser.svc_stats_file);
+        config.app_context.report_svc_stats = parser.report_svc_stats;
+
+        #[cfg(feature = "discovery")]
+        {
+            config.app_context.oui_file = Some(parser.oui_file);
+        }
+
+        config.app_context.slow_ack_threshold = parser.slow_ack_threshold;
+
+        #[cfg(feature = "metrics")]
+        {
+            config.app_context.traffic = TrafficMonitor::new(
+                parser.uplink_interface,
+                parser.uplink_capacity);
+        }
+
+        config.app_context.trace_ids = parser.trace_ids;
+
+        config.app_context.resolve_host_names = parser.host_names;
+        config.app_context.scan_time_limit    = parser.scan_time_limit;
+        config.app_context.scan_probe_limit   = parser.scan_probe_limit;
+
+        if parser.adaptive_ping {
+            config.app_context.ping_tuner = PingTuner::adaptive();
+        }
+
+        #[cfg(feature = "sandbox")]
+        {
+            config.app_context.sandbox_any_tcp = parser.sandbox_any_tcp;
+        }
+
+        #[cfg(feature = "conntrack")]
+        {
+            config.app_context.flush_conntrack = parser.flush_conntrack;
+        }
+
+        #[cfg(feature = "socks")]
+        {
+            config.app_context.socks_proxy = parser.socks_proxy.clone();
+            config.app_context.service_socks_proxy =
+                parser.service_socks_proxy.clone();
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            config.app_context.transport = parser.transport;
+            config.app_context.wss_endpoint = parser.wss_endpoint.clone();
+        }
+
+        config.app_context.session_authorizer =
+            parser.session_authorizer.clone();
+        config.app_context.strict_parsing = parser.strict_parsing;
+
+        #[cfg(feature = "discovery")]
+        {
+            if parser.network_alerts {
+                config.app_context.network_monitor = Some(NetworkMonitor::new());
+            }
+        }
+
+        config.app_context.register_errors = parser.register_errors;
+        config.app_context.version_regression = parser.version_regression;
+        config.app_context.auth_provider = parser.auth_provider;
+        config.app_context.config_file = Some(config.config_file.clone());
+
+        if let Some((format, path)) = parser.stats_export {
+            config.app_context.stats_export = Some(StatsExport::new(&path,
+                format,
+                parser.stats_export_period,
+                parser.stats_export_files));
+        }
+
+        #[cfg(feature = "recording")]
+        {
+            config.app_context.session_taps = SessionTaps::new(&parser.tap_dir);
+        }
+
+        config.app_context.overload_policies = parser.overload_policies;
+
+        if !parser.tcp_allowlist.is_empty() {
+            config.app_context.config.add_static(Service::AnyTCP);
+            config.default_svc_table.add_static(Service::AnyTCP);
+        }
+
+        config.app_context.tcp_allowlist = parser.tcp_allowlist;
+        config.app_context.access_policy = parser.access_policy;
+        config.app_context.untrusted_interfaces = parser.untrusted_interfaces;
+        config.app_context.flap_damping =
+            FlapDamping::new(parser.flap_threshold);
+        config.app_context.max_bitrate = parser.max_bitrate;
+
+        #[cfg(feature = "e2e")]
+        {
+            config.app_context.e2e_keys = parser.e2e_keys;
+        }
+
+        let client_key = parser.client_key.clone();
+        config.app_context.client_cert = parser.client_cert.clone()
+            .map(|cert| {
+                let key = client_key.unwrap_or(cert.clone());
+                (cert, key)
+            });
+        config.app_context.ca_certificates = parser.ca_certificates.clone();
+        config.app_context.bitrate_hint = parser.bitrate_hint;
+        config.app_context.hooks = parser.hooks;
+        config.app_context.status_led = status_led;
+
+        for rtsp_service in parser.rtsp_services {
+            config.add_rtsp_service(rtsp_service.expose());
+        }
+
+        for mjpeg_service in parser.mjpeg_services {
+            config.add_mjpeg_service(mjpeg_service.expose());
+        }
+
+        for http_service in parser.http_services {
+            config.add_http_service(&http_service);
+        }
+
+        for tcp_service in parser.tcp_services {
+            config.add_tcp_service(&tcp_service);
+        }
+
+        for udp_service in parser.udp_services {
+            config.add_udp_service(&udp_service);
+        }
+
+        for (file, addr) in parser.tenants {
+            let addr = addr.unwrap_or(config.arrow_svc_addr.clone());
+            let tenant = Tenant::load(&mut config.logger, &file, &addr,
+                &parser.ca_certificates);
+
+            config.tenants.push(tenant);
+        }
+
+        config
+    }
+
+    /// Add a given RTSP service.
+    fn add_rtsp_service(&mut self, url: &str) {
+        self.add_static_service(url, parse_rtsp_url);
+    }
+
+    /// Add a given MJPEG service.
+    fn add_mjpeg_service(&mut self, url: &str) {
+        self.add_static_service(url, parse_mjpeg_url);
+    }
+
+    /// Add a given HTTP service.
+    fn add_http_service(&mut self, addr: &str) {
+        self.add_static_service(addr, parse_http_service);
+    }
+
+    /// Add a given TCP service.
+    fn add_tcp_service(&mut self, addr: &str) {
+        self.add_static_service(addr, parse_tcp_service);
+    }
+
+    /// Add a given UDP service.
+    fn add_udp_service(&mut self, addr: &str) {
+        self.add_static_service(addr, parse_udp_service);
+    }
+
+    /// Add a given static service using a given parser. The service address
+    /// is resolved again periodically.
+    fn add_static_service(&mut self, spec: &str, parser: ServiceParser) {
+        let service = self.resolver.add(spec, parser);
+        let service = result_or_usage(service);
+
+        self.app_context.config.add_static(service.clone());
+        self.default_svc_table.add_static(service);
+    }
+}
+
+/// Type of the logger backend that should be used.
+enum LoggerType {
+    Syslog,
+    Stderr,
+    StderrPretty,
+    Json,
+    Journald,
+    FileLogger,
+}
+
+/// App configuration parser.
+struct AppConfigurationParser {
+    arrow_mac:          Option<MacAddr>,
+    interface:          Option<String>,
+    arrow_svc_addr:     String,
+    relay_addr:         Option<String>,
+    relay_listen:       Option<String>,
+    relay_allow:        Vec<String>,
+    relay_secret_file:  Option<String>,
+    control_socket:     String,
+    status_page:        Option<String>,
+    takeover:           bool,
+    sap_listen:         bool,
+    ntp_servers:        Vec<String>,
+    tenants:            Vec<(String, Option<String>)>,
+    ca_certificates:    Vec<String>,
+    client_cert:        Option<String>,
+    client_key:         Option<String>,
+    rtsp_services:      Vec<Secret<String>>,
+    mjpeg_services:     Vec<Secret<String>>,
+    http_services:      Vec<String>,
+    tcp_services:       Vec<String>,
+    udp_services:       Vec<String>,
+    metadata:           Metadata,
+    logger_type:        LoggerType,
+    config_file:        String,
+    init_config_file:   Option<String>,
+    state_dir:          Option<String>,
+    state_file:         String,
+    identity_file:      String,
+    svc_stats_file:     String,
+    #[cfg(feature = "discovery")]
+    oui_file:           String,
+    dns_cache_file:     String,
+    rtsp_paths_file:    String,
+    mjpeg_paths_file:   String,
+    log_file:           String,
+    discovery:          bool,
+    verbose:            bool,
+    diagnostic_mode:    bool,
+    one_shot_register:  bool,
+    watch_config:       bool,
+    clock_grace_mode:   bool,
+    report_svc_stats:   bool,
+    log_file_size:      usize,
+    log_file_rotations: usize,
+    log_file_max_age:   Option<u64>,
+    log_file_gzip:      bool,
+    service_connect_timeout: u64,
+    uplink_write_timeout: u64,
+    buffer_alert_threshold: u64,
+    ack_timeout:        u64,
+    session_write_timeout: u64,
+    clock_skew_tolerance: u32,
+    overload_policies:  OverloadPolicies,
+    tcp_allowlist:      TcpAllowlist,
+    access_policy:      AccessPolicy,
+    untrusted_interfaces: UntrustedInterfaces,
+    flap_threshold:     usize,
+    max_bitrate:        Option<u32>,
+    #[cfg(feature = "e2e")]
+    e2e_keys:           E2eKeys,
+    bitrate_hint:       Option<String>,
+    slow_ack_threshold: u64,
+    #[cfg(feature = "metrics")]
+    uplink_interface:   Option<String>,
+    #[cfg(feature = "metrics")]
+    uplink_capacity:    Option<u64>,
+    trace_ids:          bool,
+    #[cfg(feature = "discovery")]
+    network_alerts:     bool,
+    host_names:         bool,
+    scan_time_limit:    Option<u64>,
+    scan_probe_limit:   Option<usize>,
+    adaptive_ping:      bool,
+    #[cfg(feature = "sandbox")]
+    sandbox_any_tcp:    bool,
+    #[cfg(feature = "conntrack")]
+    flush_conntrack:    bool,
+    #[cfg(feature = "socks")]
+    socks_proxy:        Option<SocksProxy>,
+    #[cfg(feature = "socks")]
+    service_socks_proxy: Option<SocksProxy>,
+    #[cfg(feature = "websocket")]
+    transport:          Transport,
+    #[cfg(feature = "websocket")]
+    wss_endpoint:       Option<WssEndpoint>,
+    session_authorizer: Option<Arc<SessionAuthorizer>>,
+    strict_parsing:     bool,
+    register_errors:    RegisterErrorPolicy,
+    version_regression: RegressionPolicy,
+    auth_provider:      Arc<AuthProvider>,
+    stats_export:       Option<(ExportFormat, String)>,
+    stats_export_period: u64,
+    stats_export_files: usize,
+    #[cfg(feature = "recording")]
+    tap_dir:            String,
+    integrator:         Option<String>,
+    hooks:              Hooks,
+    status_led:         Option<LedOutput>,
+    led_patterns:       LedPatterns,
+}
+
+impl AppConfigurationParser {
+    /// Create a new app configuration parser.
+    fn new() -> AppConfigurationParser {
+        AppConfigurationParser {
+            arrow_mac:          None,
+            interface:          None,
+            arrow_svc_addr:     String::new(),
+            relay_addr:         None,
+            relay_listen:       None,
+            relay_allow:        Vec::new(),
+            relay_secret_file:  None,
+            control_socket:     CONTROL_SOCKET.to_string(),
+            status_page:        None,
+            takeover:           false,
+            sap_listen:         false,
+            ntp_servers:        Vec::new(),
+            tenants:            Vec::new(),
+            ca_certificates:    Vec::new(),
+            client_cert:        None,
+            client_key:         None,
+            rtsp_services:      Vec::new(),
+            mjpeg_services:     Vec::new(),
+            http_services:      Vec::new(),
+            tcp_services:       Vec::new(),
+            udp_services:       Vec::new(),
+            metadata:           Metadata::new(),
+            logger_type:        LoggerType::Syslog,
+            config_file:        CONFIG_FILE.to_string(),
+            init_config_file:   None,
+            state_dir:          None,
+            state_file:         STATE_FILE.to_string(),
+            identity_file:      IDENTITY_FILE.to_string(),
+            svc_stats_file:     SVC_STATS_FILE.to_string(),
+            #[cfg(feature = "discovery")]
+            oui_file:           OUI_FILE.to_string(),
+            dns_cache_file:     DNS_CACHE_FILE.to_string(),
+            rtsp_paths_file:    RTSP_PATHS_FILE.to_string(),
+            mjpeg_paths_file:   MJPEG_PATHS_FILE.to_string(),
+            log_file:           String::new(),
+            discovery:          false,
+            verbose:            false,
+            diagnostic_mode:    false,
+            one_shot_register:  false,
+            watch_config:       false,
+            clock_grace_mode:   false,
+            report_svc_stats:   false,
+            log_file_size:      10 * 1024,
+            log_file_rotations: 1,
+            log_file_max_age:   None,
+            log_file_gzip:      false,
+            service_connect_timeout: SERVICE_CONNECT_TIMEOUT,
+            uplink_write_timeout: UPLINK_WRITE_TIMEOUT,
+            buffer_alert_threshold: BUFFER_ALERT_THRESHOLD,
+            ack_timeout:        ACK_TIMEOUT,
+            session_write_timeout: SESSION_WRITE_TIMEOUT,
+            clock_skew_tolerance: CLOCK_SKEW_TOLERANCE,
+            overload_policies:  OverloadPolicies::new(),
+            tcp_allowlist:      TcpAllowlist::new(),
+            access_policy:      AccessPolicy::new(),
+            untrusted_interfaces: UntrustedInterfaces::new(),
+            flap_threshold:     FLAP_THRESHOLD,
+            max_bitrate:        None,
+            #[cfg(feature = "e2e")]
+            e2e_keys:           E2eKeys::new(),
+            bitrate_hint:       None,
+            slow_ack_threshold: SLOW_ACK_THRESHOLD,
+            #[cfg(feature = "metrics")]
+            uplink_interface:   None,
+            #[cfg(feature = "metrics")]
+            uplink_capacity:    None,
+            trace_ids:          false,
+            #[cfg(feature = "discovery")]
+            network_alerts:     false,
+            host_names:         false,
+            scan_time_limit:    Some(SCAN_TIME_LIMIT),
+            scan_probe_limit:   None,
+            adaptive_ping:      false,
+            #[cfg(feature = "sandbox")]
+            sandbox_any_tcp:    false,
+            #[cfg(feature = "conntrack")]
+            flush_conntrack:    false,
+            #[cfg(feature = "socks")]
+            socks_proxy:        None,
+            #[cfg(feature = "socks")]
+            service_socks_proxy: None,
+            #[cfg(feature = "websocket")]
+            transport:          Transport::Tls,
+            #[cfg(feature = "websocket")]
+            wss_endpoint:       None,
+            session_authorizer: None,
+            strict_parsing:     false,
+            register_errors:    RegisterErrorPolicy::new(),
+            version_regression: RegressionPolicy::Update,
+            auth_provider:      Arc::new(StaticSecret),
+            stats_export:       None,
+            stats_export_period: stats_export::EXPORT_PERIOD,
+            stats_export_files: stats_export::EXPORT_FILES,
+            #[cfg(feature = "recording")]
+            tap_dir:            TAP_DIR.to_string(),
+            integrator:         None,
+            hooks:              Hooks::new(),
+            status_led:         None,
+            led_patterns:       LedPatterns::new(),
+        }
+    }
+
+    /// Parse given command line arguments.
+    fn parse<I: Iterator<Item=String>>(args: &mut I) -> AppConfigurationParser {
+        let mut parser = AppConfigurationParser::new();
+
+        // skip the application name
+        args.next();
+
+        if let Some(arrow_svc_addr) = args.next() {
+            parser.arrow_svc_addr = arrow_svc_addr;
+        } else {
+            usage(EXIT_CODE_USAGE);
+        }
+
+        while let Some(ref arg) = args.next() {
+            match arg as &str {
+                "-c" => parser.ca_certificates(args),
+                "-d" => parser.discovery(),
+                "-i" => parser.interface(args),
+                "-r" => parser.rtsp_service(args),
+                "-m" => parser.mjpeg_service(args),
+                "-h" => parser.http_service(args),
+                "-t" => parser.tcp_service(args),
+                "-u" => parser.udp_service(args),
+                "-v" => parser.verbose(),
+
+                "--diagnostic-mode"   => parser.diagnostic_mode(),
+                "--clock-grace-mode"  => parser.clock_grace_mode(),
+                "--report-svc-stats"  => parser.report_svc_stats(),
+                "--trace-ids"         => parser.trace_ids(),
+                "--network-alerts"    => parser.network_alerts(),
+                "--host-names"        => parser.host_names(),
+                "--adaptive-ping"     => parser.adaptive_ping(),
+                "--sandbox-any-tcp"   => parser.sandbox_any_tcp(),
+                "--flush-conntrack"   => parser.flush_conntrack(),
+                "--strict-parsing"    => parser.strict_parsing(),
+                "--takeover"          => parser.takeover(),
+                "--sap-listen"        => parser.sap_listen(),
+                "--log-file-gzip"     => parser.log_file_gzip(),
+                "--log-stderr"        => parser.log_stderr(),
+                "--log-stderr-pretty" => parser.log_stderr_pretty(),
+                "--log-json"          => parser.log_json(),
+                "--log-journald"      => parser.log_journald(),
+                "--one-shot-register" => parser.one_shot_register(),
+                "--watch-config"      => parser.watch_config(),
+
+                arg => {
+                    if arg.starts_with("--mac=") {
+                        parser.mac(arg);
+                    } else if arg.starts_with("--identity-file=") {
+                        parser.identity_file(arg);
+                    } else if arg.starts_with("--relay=") {
+                        parser.relay(arg);
+                    } else if arg.starts_with("--relay-listen=") {
+                        parser.relay_listen(arg);
+                    } else if arg.starts_with("--relay-allow=") {
+                        parser.relay_allow(arg);
+                    } else if arg.starts_with("--relay-secret-file=") {
+                        parser.relay_secret_file(arg);
+                    } else if arg.starts_with("--ntp-server=") {
+                        parser.ntp_server(arg);
+                    } else if arg.starts_with("--tenant=") {
+                        parser.tenant(arg);
+                    } else if arg.starts_with("--control-socket=") {
+                        parser.control_socket(arg);
+                    } else if arg.starts_with("--status-page=") {
+                        parser.status_page(arg);
+                    } else if arg.starts_with("--site-name=") {
+                        parser.site_name(arg);
+                    } else if arg.starts_with("--location=") {
+                        parser.location(arg);
+                    } else if arg.starts_with("--customer-id=") {
+                        parser.customer_id(arg);
+                    } else if arg.starts_with("--label=") {
+                        parser.label(arg);
+                    } else if arg.starts_with("--integrator=") {
+                        parser.integrator(arg);
+                    } else if arg.starts_with("--hook=") {
+                        parser.hook(arg);
+                    } else if arg.starts_with("--status-led=") {
+                        parser.status_led(arg);
+                    } else if arg.starts_with("--led-pattern=") {
+                        parser.led_pattern(arg);
+                    } else if arg.starts_with("--config-file=") {
+                        parser.config_file(arg);
+                    } else if arg.starts_with("--conn-state-file=") {
+                        parser.conn_state_file(arg);
+                    } else if arg.starts_with("--state-dir=") {
+                        parser.state_dir(arg);
+                    } else if arg.starts_with("--svc-stats-file=") {
+                        parser.svc_stats_file(arg);
+                    } else if arg.starts_with("--client-cert=") {
+                        parser.client_cert(arg);
+                    } else if arg.starts_with("--client-key=") {
+                        parser.client_key(arg);
+                    } else if arg.starts_with("--oui-file=") {
+                        parser.oui_file(arg);
+                    } else if arg.starts_with("--rtsp-paths=") {
+                        parser.rtsp_paths(arg);
+                    } else if arg.starts_with("--mjpeg-paths=") {
+                        parser.mjpeg_paths(arg);
+                    } else if arg.starts_with("--log-format=") {
+                        parser.log_format(arg);
+                    } else if arg.starts_with("--log-file=") {
+                        parser.log_file(arg);
+                    } else if arg.starts_with("--log-file-size=") {
+                        parser.log_file_size(arg);
+                    } else if arg.starts_with("--log-file-rotations=") {
+                        parser.log_file_rotations(arg);
+                    } else if arg.starts_with("--log-file-max-age=") {
+                        parser.log_file_max_age(arg);
+                    } else if arg.starts_with("--scan-time-limit=") {
+                        parser.scan_time_limit(arg);
+                    } else if arg.starts_with("--scan-probe-limit=") {
+                        parser.scan_probe_limit(arg);
+                    } else if arg.starts_with("--service-connect-timeout=") {
+                        parser.service_connect_timeout(arg);
+                    } else if arg.starts_with("--uplink-write-timeout=") {
+                        parser.uplink_write_timeout(arg);
+                    } else if arg.starts_with("--buffer-alert-threshold=") {
+                        parser.buffer_alert_threshold(arg);
+                    } else if arg.starts_with("--ack-timeout=") {
+                        parser.ack_timeout(arg);
+                    } else if arg.starts_with("--session-write-timeout=") {
+                        parser.session_write_timeout(arg);
+                    } else if arg.starts_with("--clock-skew-tolerance=") {
+                        parser.clock_skew_tolerance(arg);
+                    } else if arg.starts_with("--overload-policy=") {
+                        parser.overload_policy(arg);
+                    } else if arg.starts_with("--allow-tcp=") {
+                        parser.allow_tcp(arg);
+                    } else if arg.starts_with("--session-hours=") {
+                        parser.session_hours(arg);
+                    } else if arg.starts_with("--max-sessions-per-service=") {
+                        parser.max_sessions_per_service(arg);
+                    } else if arg.starts_with("--deny-service=") {
+                        parser.deny_service(arg);
+                    } else if arg.starts_with("--untrusted-iface=") {
+                        parser.untrusted_iface(arg);
+                    } else if arg.starts_with("--flap-threshold=") {
+                        parser.flap_threshold(arg);
+                    } else if arg.starts_with("--e2e-key=") {
+                        parser.e2e_key(arg);
+                    } else if arg.starts_with("--max-bitrate=") {
+                        parser.max_bitrate(arg);
+                    } else if arg.starts_with("--bitrate-hint=") {
+                        parser.bitrate_hint(arg);
+                    } else if arg.starts_with("--slow-ack-threshold=") {
+                        parser.slow_ack_threshold(arg);
+                    } else if arg.starts_with("--uplink-interface=") {
+                        parser.uplink_interface(arg);
+                    } else if arg.starts_with("--uplink-capacity=") {
+                        parser.uplink_capacity(arg);
+                    } else if arg.starts_with("--register-error=") {
+                        parser.register_error(arg);
+                    } else if arg.starts_with("--version-regression=") {
+                        parser.version_regression(arg);
+                    } else if arg.starts_with("--auth=") {
+                        parser.auth(arg);
+                    } else if arg.starts_with("--stats-export=") {
+                        parser.stats_export(arg);
+                    } else if arg.starts_with("--stats-export-interval=") {
+                        parser.stats_export_interval(arg);
+                    } else if arg.starts_with("--stats-export-files=") {
+                        parser.stats_export_files(arg);
+                    } else if arg.starts_with("--tap-dir=") {
+                        parser.tap_dir(arg);
+                    } else if arg.starts_with("--socks-proxy=") {
+                        parser.socks_proxy(arg);
+                    } else if arg.starts_with("--service-socks-proxy=") {
+                        parser.service_socks_proxy(arg);
+                    } else if arg.starts_with("--session-authorizer=") {
+                        parser.session_authorizer(arg);
+                    } else if arg.starts_with("--transport=") {
+                        parser.transport(arg);
+                    } else if arg.starts_with("--wss-url=") {
+                        parser.wss_url(arg);
+                    } else {
+                        utils::error(RuntimeError::from(arg),
+                            EXIT_CODE_USAGE, "unknown argument");
+                    }
+                }
+            }
+        }
+
+        if (parser.relay_addr.is_some() || parser.relay_listen.is_some())
+            && parser.relay_secret_file.is_none() {
+            utils::error(RuntimeError::from("--relay-secret-file"),
+                EXIT_CODE_USAGE, "relay secret file is required in the federation mode");
+        }
+
+        #[cfg(feature = "socks")]
+        {
+            if parser.socks_proxy.is_some() && parser.relay_addr.is_some() {
+                utils::error(RuntimeError::from("--socks-proxy"),
+                    EXIT_CODE_USAGE, "SOCKS proxy cannot be combined with a relay");
+            }
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            if parser.transport != Transport::Tls && parser.relay_addr.is_some() {
+                utils::error(RuntimeError::from("--transport"),
+                    EXIT_CODE_USAGE, "WebSocket transport cannot be combined with a relay or a SOCKS proxy");
+            }
+        }
+
+        #[cfg(all(feature = "websocket", feature = "socks"))]
+        {
+            if parser.transport != Transport::Tls && parser.socks_proxy.is_some() {
+                utils::error(RuntimeError::from("--transport"),
+                    EXIT_CODE_USAGE, "WebSocket transport cannot be combined with a relay or a SOCKS proxy");
+            }
+        }
+
+        if let Some(dir) = parser.state_dir.clone() {
+            parser.move_state_files(&dir);
+        }
+
+        let client_info = get_client_info(parser.integrator.as_ref()
+            .map(|integrator| integrator as &str));
+
+        parser.metadata.set_client_info(&client_info);
+
+        parser
+    }
+
+    /// Get next argument from a given list.
+    fn next_argument<I: Iterator<Item=String>>(
+        &mut self,
+        args: &mut I,
+        emsg: &str) -> String {
+        let arg = args.next()
+            .ok_or(RuntimeError::from(emsg));
+
+        result_or_usage(arg)
+    }
+
+    /// Process the CA certificate argument.
+    fn ca_certificates<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let path = self.next_argument(args, "CA certificate path expected");
+        self.ca_certificates.push(path);
+    }
+
+    /// Process the client-cert argument.
+    fn client_cert(&mut self, arg: &str) {
+        let re = Regex::new(r"^--client-cert=(.+)$")
+            .unwrap();
+
+        match re.captures(arg) {
+            Some(caps) => self.client_cert = Some(caps.at(1).unwrap().to_string()),
+            None => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "client certificate path expected")
+        }
+    }
+
+    /// Process the client-key argument.
+    fn client_key(&mut self, arg: &str) {
+        let re = Regex::new(r"^--client-key=(.+)$")
+            .unwrap();
+
+        match re.captures(arg) {
+            Some(caps) => self.client_key = Some(caps.at(1).unwrap().to_string()),
+            None => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "client key path expected")
+        }
+    }
+
+    /// Process the discovery argument.
+    fn discovery(&mut self) {
+        if cfg!(feature = "discovery") {
+            self.discovery = true;
+        } else {
+            utils::error(RuntimeError::from("-d"),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+    }
+
+    /// Process the interface argument.
+    fn interface<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let iface = self.next_argument(args, "network interface name expected");
+
+        // the interface is checked on startup
+        self.interface = Some(iface);
+        self.arrow_mac = None;
+    }
+
+    /// Process the mac argument.
+    fn mac(&mut self, arg: &str) {
+        let re = Regex::new(r"^--mac=(.*)$")
+            .unwrap();
+
+        let addr = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.arrow_mac = Some(utils::result_or_error(
+            MacAddr::from_str(addr),
+            EXIT_CODE_USAGE,
+            "invalid MAC address"));
+        self.interface = None;
+    }
+
+    /// Process the RTSP service argument.
+    fn rtsp_service<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let url = self.next_argument(args, "RTSP URL expected");
+        // the URL may contain camera credentials
+        self.rtsp_services.push(Secret::new(url));
+    }
+
+    /// Process the MJPEG service argument.
+    fn mjpeg_service<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let url = self.next_argument(args, "HTTP URL expected");
+        // the URL may contain camera credentials
+        self.mjpeg_services.push(Secret::new(url));
+    }
+
+    /// Process the HTTP service argument.
+    fn http_service<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let addr = self.next_argument(args, "TCP socket address expected");
+        self.http_services.push(addr);
+    }
+
+    /// Process the TCP service argument.
+    fn tcp_service<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        let addr = self.next_argument(args, "TCP socket address expected");
+        self.tcp_services.push(addr);
+    }
+
+    /// Process the UDP service argument.
+    fn udp_service<I: Iterator<Item=String>>(&mut self, args: &mut I) {
+        if !cfg!(feature = "datagram") {
+            utils::error(RuntimeError::from("-u"),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+
+        let addr = self.next_argument(args, "UDP socket address expected");
+        self.udp_services.push(addr);
+    }
+
+    /// Process the verbose argument.
+    fn verbose(&mut self) {
+        self.verbose = true;
+    }
+
+    /// Process the diagnostic mode argument.
+    fn diagnostic_mode(&mut self) {
+        self.diagnostic_mode = true;
+    }
+
+    /// Process the one-shot-register argument.
+    fn one_shot_register(&mut self) {
+        self.one_shot_register = true;
+        self.diagnostic_mode   = true;
+    }
+
+    /// Process the watch-config argument.
+    fn watch_config(&mut self) {
+        self.watch_config = true;
+    }
+
+    /// Process the clock-grace-mode argument.
+    fn clock_grace_mode(&mut self) {
+        self.clock_grace_mode = true;
+    }
+
+    /// Process the report-svc-stats argument.
+    fn report_svc_stats(&mut self) {
+        self.report_svc_stats = true;
+    }
+
+    /// Process the trace-ids argument.
+    fn trace_ids(&mut self) {
+        self.trace_ids = true;
+    }
+
+    /// Process the network-alerts argument.
+    #[cfg(feature = "discovery")]
+    fn network_alerts(&mut self) {
+        self.network_alerts = true;
+    }
+
+    /// Process the network-alerts argument (dummy).
+    #[cfg(not(feature = "discovery"))]
+    fn network_alerts(&mut self) {
+        utils::error(RuntimeError::from("--network-alerts"),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the host-names argument.
+    fn host_names(&mut self) {
+        if !cfg!(all(feature = "discovery", feature = "dns")) {
+            utils::error(RuntimeError::from("--host-names"),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+
+        self.host_names = true;
+    }
+
+    /// Process the scan-time-limit argument.
+    fn scan_time_limit(&mut self, arg: &str) {
+        let re = Regex::new(r"^--scan-time-limit=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let limit = u64::from_str(caps.at(1).unwrap())
+                .unwrap();
+
+            self.scan_time_limit = if limit > 0 {
+                Some(limit)
+            } else {
+                None
+            };
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the scan-probe-limit argument.
+    fn scan_probe_limit(&mut self, arg: &str) {
+        let re = Regex::new(r"^--scan-probe-limit=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let limit = usize::from_str(caps.at(1).unwrap())
+                .unwrap();
+
+            if limit == 0 {
+                utils::error(RuntimeError::from(arg),
+                    EXIT_CODE_USAGE, "positive number expected");
+            }
+
+            self.scan_probe_limit = Some(limit);
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the adaptive-ping argument.
+    fn adaptive_ping(&mut self) {
+        self.adaptive_ping = true;
+    }
+
+    /// Process the sandbox-any-tcp argument.
+    #[cfg(feature = "sandbox")]
+    fn sandbox_any_tcp(&mut self) {
+        self.sandbox_any_tcp = true;
+    }
+
+    /// Process the sandbox-any-tcp argument (dummy).
+    #[cfg(not(feature = "sandbox"))]
+    fn sandbox_any_tcp(&mut self) {
+        utils::error(RuntimeError::from("--sandbox-any-tcp"),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the flush-conntrack argument.
+    #[cfg(feature = "conntrack")]
+    fn flush_conntrack(&mut self) {
+        self.flush_conntrack = true;
+    }
+
+    /// Process the flush-conntrack argument (dummy).
+    #[cfg(not(feature = "conntrack"))]
+    fn flush_conntrack(&mut self) {
+        utils::error(RuntimeError::from("--flush-conntrack"),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the strict-parsing argument.
+    fn strict_parsing(&mut self) {
+        self.strict_parsing = true;
+    }
+
+    /// Process the log-stderr argument.
+    fn log_stderr(&mut self) {
+        self.logger_type = LoggerType::Stderr;
+    }
+
+    /// Process the log-stderr-pretty argument.
+    fn log_stderr_pretty(&mut self) {
+        self.logger_type = LoggerType::StderrPretty;
+    }
+
+    /// Process the log-json argument.
+    fn log_json(&mut self) {
+        self.logger_type = LoggerType::Json;
+    }
+
+    /// Process the log-journald argument.
+    fn log_journald(&mut self) {
+        self.logger_type = LoggerType::Journald;
+    }
+
+    /// Process the log-format argument.
+    fn log_format(&mut self, arg: &str) {
+        let re = Regex::new(r"^--log-format=(.*)$")
+            .unwrap();
+
+        let format = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.logger_type = match format {
+            "syslog"        => LoggerType::Syslog,
+            "stderr"        => LoggerType::Stderr,
+            "stderr-pretty" => LoggerType::StderrPretty,
+            "json"          => LoggerType::Json,
+            "journald"      => LoggerType::Journald,
+            _ => utils::error(RuntimeError::from(format),
+                EXIT_CODE_USAGE, "invalid log format")
+        };
+    }
+
+    /// Process the log-file argument.
+    fn log_file(&mut self, arg: &str) {
+        self.logger_type = LoggerType::FileLogger;
+
+        let re = Regex::new(r"^--log-file=(.*)$")
+            .unwrap();
+
+        self.log_file = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string();
+    }
+
+    /// Process the log-file-size argument.
+    fn log_file_size(&mut self, arg: &str) {
+        let re = Regex::new(r"^--log-file-size=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.log_file_size = usize::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the service-connect-timeout argument.
+    fn service_connect_timeout(&mut self, arg: &str) {
+        let re = Regex::new(r"^--service-connect-timeout=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.service_connect_timeout = u64::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the uplink-write-timeout argument.
+    fn uplink_write_timeout(&mut self, arg: &str) {
+        self.uplink_write_timeout = parse_timeout(arg);
+    }
+
+    /// Process the buffer-alert-threshold argument.
+    fn buffer_alert_threshold(&mut self, arg: &str) {
+        self.buffer_alert_threshold = parse_timeout(arg);
+    }
+
+    /// Process the ack-timeout argument.
+    fn ack_timeout(&mut self, arg: &str) {
+        self.ack_timeout = parse_timeout(arg);
+    }
+
+    /// Process the session-write-timeout argument.
+    fn session_write_timeout(&mut self, arg: &str) {
+        self.session_write_timeout = parse_timeout(arg);
+    }
+
+    /// Process the clock-skew-tolerance argument.
+    fn clock_skew_tolerance(&mut self, arg: &str) {
+        let re = Regex::new(r"^--clock-skew-tolerance=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.clock_skew_tolerance = u32::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the overload-policy argument.
+    fn overload_policy(&mut self, arg: &str) {
+        let re = Regex::new(r"^--overload-policy=([a-z]+):([a-z]+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let class  = ServiceClass::from_name(caps.at(1).unwrap());
+            let policy = OverloadPolicy::from_name(caps.at(2).unwrap());
+
+            let res = class.and_then(|class| policy.and_then(|policy|
+                self.overload_policies.set(class, policy)));
+
+            if let Err(err) = res {
+                utils::error(err, EXIT_CODE_USAGE, "invalid overload policy");
+            }
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "class:policy expected");
+        }
+    }
+
+    /// Process the allow-tcp argument.
+    fn allow_tcp(&mut self, arg: &str) {
+        let re = Regex::new(r"^--allow-tcp=(.*)$")
+            .unwrap();
+
+        let rule = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        let rule = utils::result_or_error(
+            AddressRule::from_str(rule),
+            EXIT_CODE_USAGE,
+            "invalid any-TCP allowlist rule");
+
+        self.tcp_allowlist.add(rule);
+    }
+
+    /// Process the session-hours argument.
+    fn session_hours(&mut self, arg: &str) {
+        let re = Regex::new(r"^--session-hours=(.*)$")
+            .unwrap();
+
+        let window = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        let window = utils::result_or_error(
+            TimeWindow::from_str(window),
+            EXIT_CODE_USAGE,
+            "invalid session time window");
+
+        self.access_policy.set_time_window(window);
+    }
+
+    /// Process the max-sessions-per-service argument.
+    fn max_sessions_per_service(&mut self, arg: &str) {
+        let re = Regex::new(r"^--max-sessions-per-service=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let max = usize::from_str(caps.at(1).unwrap())
+                .unwrap();
+
+            self.access_policy.set_max_sessions(max);
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the flap-threshold argument.
+    fn flap_threshold(&mut self, arg: &str) {
+        let re = Regex::new(r"^--flap-threshold=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.flap_threshold = usize::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the e2e-key argument.
+    #[cfg(feature = "e2e")]
+    fn e2e_key(&mut self, arg: &str) {
+        let re = Regex::new(r"^--e2e-key=(([0-9a-fA-F]{2}(:[0-9a-fA-F]{2}){5})=)?(.+)$")
+            .unwrap();
+
+        let caps = match re.captures(arg) {
+            Some(caps) => caps,
+            None => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "invalid end-to-end encryption key")
+        };
+
+        let mac = caps.at(2)
+            .map(|mac| MacAddr::from_str(mac).unwrap());
+
+        let file = caps.at(4)
+            .unwrap();
+
+        let key = utils::result_or_error(E2eKey::load(file),
+            EXIT_CODE_CONFIG_ERROR,
+            "unable to load end-to-end encryption key");
+
+        self.e2e_keys.set(mac, key);
+    }
+
+    /// Process the e2e-key argument (dummy).
+    #[cfg(not(feature = "e2e"))]
+    fn e2e_key(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the max-bitrate argument.
+    fn max_bitrate(&mut self, arg: &str) {
+        let re = Regex::new(r"^--max-bitrate=(\d+)$")
+            .unwrap();
+
+        let max_bitrate = re.captures(arg)
+            .and_then(|caps| u32::from_str(caps.at(1).unwrap()).ok());
+
+        match max_bitrate {
+            Some(max_bitrate) if max_bitrate > 0 =>
+                self.max_bitrate = Some(max_bitrate),
+            _ => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "positive number expected")
+        }
+    }
+
+    /// Process the bitrate-hint argument.
+    fn bitrate_hint(&mut self, arg: &str) {
+        if !cfg!(feature = "discovery") {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+
+        let re = Regex::new(r"^--bitrate-hint=([A-Za-z0-9_.-]+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.bitrate_hint = Some(caps.at(1)
+                .unwrap()
+                .to_string());
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "invalid parameter name");
+        }
+    }
+
+    /// Process the slow-ack-threshold argument.
+    fn slow_ack_threshold(&mut self, arg: &str) {
+        let re = Regex::new(r"^--slow-ack-threshold=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.slow_ack_threshold = u64::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the uplink-interface argument.
+    #[cfg(feature = "metrics")]
+    fn uplink_interface(&mut self, arg: &str) {
+        let re = Regex::new(r"^--uplink-interface=([^/]+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.uplink_interface = Some(caps.at(1).unwrap().to_string());
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "interface name expected");
+        }
+    }
+
+    /// Process the uplink-interface argument (dummy).
+    #[cfg(not(feature = "metrics"))]
+    fn uplink_interface(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the uplink-capacity argument.
+    #[cfg(feature = "metrics")]
+    fn uplink_capacity(&mut self, arg: &str) {
+        let re = Regex::new(r"^--uplink-capacity=(\d+)$")
+            .unwrap();
+
+        let capacity = re.captures(arg)
+            .and_then(|caps| u64::from_str(caps.at(1).unwrap()).ok())
+            .and_then(|kbps| kbps.checked_mul(1000))
+            .and_then(|bps| if bps > 0 { Some(bps) } else { None });
+
+        if capacity.is_some() {
+            self.uplink_capacity = capacity;
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "positive number expected");
+        }
+    }
+
+    /// Process the uplink-capacity argument (dummy).
+    #[cfg(not(feature = "metrics"))]
+    fn uplink_capacity(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the stats-export argument.
+    fn stats_export(&mut self, arg: &str) {
+        let re = Regex::new(r"^--stats-export=([a-z]+):(.+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let format = utils::result_or_error(
+                ExportFormat::from_str(caps.at(1).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid statistics export format");
+
+            let path = caps.at(2)
+                .unwrap()
+                .to_string();
+
+            self.stats_export = Some((format, path));
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "format:path expected");
+        }
+    }
+
+    /// Process the stats-export-interval argument.
+    fn stats_export_interval(&mut self, arg: &str) {
+        let re = Regex::new(r"^--stats-export-interval=(\d+)$")
+            .unwrap();
+
+        match re.captures(arg) {
+            Some(ref caps) if caps.at(1) != Some("0") =>
+                self.stats_export_period = u64::from_str(caps.at(1).unwrap())
+                    .unwrap(),
+            _ => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "positive number expected")
+        }
+    }
+
+    /// Process the stats-export-files argument.
+    fn stats_export_files(&mut self, arg: &str) {
+        let re = Regex::new(r"^--stats-export-files=(\d+)$")
+            .unwrap();
+
+        match re.captures(arg) {
+            Some(ref caps) if caps.at(1) != Some("0") =>
+                self.stats_export_files = usize::from_str(caps.at(1).unwrap())
+                    .unwrap(),
+            _ => utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "positive number expected")
+        }
+    }
+
+    /// Process the tap-dir argument.
+    #[cfg(feature = "recording")]
+    fn tap_dir(&mut self, arg: &str) {
+        let re = Regex::new(r"^--tap-dir=(.+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.tap_dir = caps.at(1)
+                .unwrap()
+                .to_string();
+        } else {
+            utils::error(RuntimeError::from("--tap-dir"),
+                EXIT_CODE_USAGE, "invalid argument");
+        }
+    }
+
+    /// Process the tap-dir argument (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn tap_dir(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
+
+    /// Process the register-error argument.
+    fn register_error(&mut self, arg: &str) {
+        let re = Regex::new(r"^--register-error=([0-9a-fA-Fx]+):([a-z]+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let code = utils::result_or_error(
+                register_policy::parse_error_code(caps.at(1).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid REGISTER error code");
+
+            let action = utils::result_or_error(
+                RegisterAction::from_name(caps.at(2).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid REGISTER error action");
+
+            self.register_errors.set(code, action);
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "code:action expected");
+        }
+    }
+
+    /// Process the version-regression argument.
+    fn version_regression(&mut self, arg: &str) {
+        let re = Regex::new(r"^--version-regression=(.*)$")
+            .unwrap();
+
+        let name = re.captures(arg)
+            .and_then(|caps| caps.at(1))
+            .unwrap_or("");
+
+        self.version_regression = utils::result_or_error(
+            RegressionPolicy::from_name(name),
+            EXIT_CODE_USAGE,
+            "invalid version regression action");
+    }
+
+    /// Process the auth argument.
+    fn auth(&mut self, arg: &str) {
+        let re = Regex::new(r"^--auth=(.*)$")
+            .unwrap();
+
+        let spec = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.auth_provider = utils::result_or_error(auth::from_spec(spec),
+            EXIT_CODE_USAGE, "invalid authentication provider");
+    }
+
+    /// Process the session-authorizer argument.
+    fn session_authorizer(&mut self, arg: &str) {
+        let re = Regex::new(r"^--session-authorizer=(.*)$")
+            .unwrap();
+
+        let spec = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.session_authorizer = Some(utils::result_or_error(
+            authorizer::from_spec(spec),
+            EXIT_CODE_USAGE,
+            "invalid session authorizer"));
+    }
+
+    /// Process the deny-service argument.
+    fn deny_service(&mut self, arg: &str) {
+        let re = Regex::new(r"^--deny-service=(.*)$")
+            .unwrap();
+
+        let rule = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        let rule = utils::result_or_error(
+            AddressRule::from_str(rule),
+            EXIT_CODE_USAGE,
+            "invalid service deny rule");
+
+        self.access_policy.deny(rule);
+    }
+
+    /// Process the untrusted-iface argument.
+    fn untrusted_iface(&mut self, arg: &str) {
+        let re = Regex::new(r"^--untrusted-iface=(.+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.untrusted_interfaces.add(caps.at(1).unwrap());
+        } else {
+            utils::error(RuntimeError::from("--untrusted-iface"),
+                EXIT_CODE_USAGE,
+                "network interface name expected");
+        }
+    }
+
+    /// Process the log-file-rotations argument.
+    fn log_file_rotations(&mut self, arg: &str) {
+        let re = Regex::new(r"^--log-file-rotations=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.log_file_rotations = usize::from_str(caps.at(1).unwrap())
+                .unwrap();
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the log-file-gzip argument.
+    fn log_file_gzip(&mut self) {
+        self.log_file_gzip = true;
+    }
+
+    /// Process the log-file-max-age argument.
+    fn log_file_max_age(&mut self, arg: &str) {
+        let re = Regex::new(r"^--log-file-max-age=(\d+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            let max_age = u64::from_str(caps.at(1).unwrap())
+                .unwrap();
+
+            if max_age == 0 {
+                utils::error(RuntimeError::from(arg),
+                    EXIT_CODE_USAGE, "positive number expected");
+            }
+
+            self.log_file_max_age = Some(max_age);
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "number expected");
+        }
+    }
+
+    /// Process the config-file argument.
+    fn config_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--config-file=(.*)$")
+            .unwrap();
+
+        self.config_file = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string();
+    }
+
+    /// Process the conn-state-file argument.
+    fn conn_state_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--conn-state-file=(.*)$")
+            .unwrap();
+
+        self.state_file = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string();
+    }
+
+    /// Process the state-dir argument.
+    fn state_dir(&mut self, arg: &str) {
+        let re = Regex::new(r"^--state-dir=(.*)$")
+            .unwrap();
+
+        let dir = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.state_dir = Some(dir.to_string());
+    }
+
+    /// Move all files modified by the client into a given directory. Files
+    /// set explicitly are not moved. The original configuration file is used
+    /// only as the initial configuration.
+    fn move_state_files(&mut self, dir: &str) {
+        if self.state_file == STATE_FILE {
+            self.state_file = state_dir_path(dir, STATE_FILE);
         }
 
-        for mjpeg_service in parser.mjpeg_services {
-            config.add_mjpeg_service(&mjpeg_service);
+        if self.identity_file == IDENTITY_FILE {
+            self.identity_file = state_dir_path(dir, IDENTITY_FILE);
         }
 
-        for http_service in parser.http_services {
-            config.add_http_service(&http_service);
+        if self.svc_stats_file == SVC_STATS_FILE {
+            self.svc_stats_file = state_dir_path(dir, SVC_STATS_FILE);
         }
 
-        for tcp_service in parser.tcp_services {
-            config.add_tcp_service(&tcp_service);
+        #[cfg(feature = "discovery")]
+        {
+            if self.oui_file == OUI_FILE {
+                self.oui_file = state_dir_path(dir, OUI_FILE);
+            }
         }
 
-        config
+        self.dns_cache_file   = state_dir_path(dir, DNS_CACHE_FILE);
+        self.init_config_file = Some(self.config_file.clone());
+        self.config_file      = state_dir_path(dir, CONFIG_FILE);
     }
 
-    /// Add CA certificates from a given path.
-    fn add_ca_certificates(&mut self, path: &str) {
-        utils::result_or_error(load_ca_certificates(
-            &mut self.ssl_context, path),
-            EXIT_CODE_CERT_ERROR,
-            format!("unable to load certificate(s) from \"{}\"", path));
+    /// Process the svc-stats-file argument.
+    fn svc_stats_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--svc-stats-file=(.*)$")
+            .unwrap();
+
+        self.svc_stats_file = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string();
     }
 
-    /// Add a given RTSP service.
-    fn add_rtsp_service(&mut self, url: &str) {
-        let service = parse_rtsp_url(url);
-        let service = result_or_usage(service);
+    /// Process the oui-file argument.
+    #[cfg(feature = "discovery")]
+    fn oui_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--oui-file=(.*)$")
+            .unwrap();
 
-        self.app_context.config.add_static(service.clone());
-        self.default_svc_table.add_static(service);
+        self.oui_file = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string();
     }
 
-    /// Add a given MJPEG service.
-    fn add_mjpeg_service(&mut self, url: &str) {
-        let service = parse_mjpeg_url(url);
-        let service = result_or_usage(service);
+    /// Process the oui-file argument (dummy).
+    #[cfg(not(feature = "discovery"))]
+    fn oui_file(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
 
-        self.app_context.config.add_static(service.clone());
-        self.default_svc_table.add_static(service);
+    /// Process the socks-proxy argument.
+    #[cfg(feature = "socks")]
+    fn socks_proxy(&mut self, arg: &str) {
+        let re = Regex::new(r"^--socks-proxy=(.*)$")
+            .unwrap();
+
+        let spec = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.socks_proxy = Some(utils::result_or_error(
+            SocksProxy::parse(spec),
+            EXIT_CODE_USAGE,
+            "invalid SOCKS proxy"));
     }
 
-    /// Add a given HTTP service.
-    fn add_http_service(&mut self, addr: &str) {
-        let addr = net::utils::get_socket_address(addr);
-        let addr = result_or_usage(addr);
+    /// Process the socks-proxy argument (dummy).
+    #[cfg(not(feature = "socks"))]
+    fn socks_proxy(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
 
-        let mac = get_fake_mac_address(0xffff, &addr);
+    /// Process the service-socks-proxy argument.
+    #[cfg(feature = "socks")]
+    fn service_socks_proxy(&mut self, arg: &str) {
+        let re = Regex::new(r"^--service-socks-proxy=(.*)$")
+            .unwrap();
 
-        let service = Service::HTTP(mac, addr);
+        let spec = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
 
-        self.app_context.config.add_static(service.clone());
-        self.default_svc_table.add_static(service);
+        self.service_socks_proxy = Some(utils::result_or_error(
+            SocksProxy::parse(spec),
+            EXIT_CODE_USAGE,
+            "invalid SOCKS proxy"));
     }
 
-    /// Add a given TCP service.
-    fn add_tcp_service(&mut self, addr: &str) {
-        let addr = net::utils::get_socket_address(addr);
-        let addr = result_or_usage(addr);
+    /// Process the service-socks-proxy argument (dummy).
+    #[cfg(not(feature = "socks"))]
+    fn service_socks_proxy(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
 
-        let mac = get_fake_mac_address(0xffff, &addr);
+    /// Process the transport argument.
+    #[cfg(feature = "websocket")]
+    fn transport(&mut self, arg: &str) {
+        let re = Regex::new(r"^--transport=(.*)$")
+            .unwrap();
 
-        let service = Service::TCP(mac, addr);
+        let name = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
 
-        self.app_context.config.add_static(service.clone());
-        self.default_svc_table.add_static(service);
+        self.transport = utils::result_or_error(
+            Transport::from_name(name)
+                .ok_or(RuntimeError::from(name)),
+            EXIT_CODE_USAGE,
+            "invalid transport, \"tls\", \"wss\" or \"auto\" expected");
     }
-}
 
-/// Type of the logger backend that should be used.
-enum LoggerType {
-    Syslog,
-    Stderr,
-    StderrPretty,
-    FileLogger,
-}
+    /// Process the transport argument (dummy).
+    #[cfg(not(feature = "websocket"))]
+    fn transport(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
 
-/// App configuration parser.
-struct AppConfigurationParser {
-    arrow_mac:          MacAddr,
-    arrow_svc_addr:     String,
-    ca_certificates:    Vec<String>,
-    rtsp_services:      Vec<String>,
-    mjpeg_services:     Vec<String>,
-    http_services:      Vec<String>,
-    tcp_services:       Vec<String>,
-    logger_type:        LoggerType,
-    config_file:        String,
-    state_file:         String,
-    rtsp_paths_file:    String,
-    mjpeg_paths_file:   String,
-    log_file:           String,
-    discovery:          bool,
-    verbose:            bool,
-    diagnostic_mode:    bool,
-    log_file_size:      usize,
-    log_file_rotations: usize,
-}
+    /// Process the wss-url argument.
+    #[cfg(feature = "websocket")]
+    fn wss_url(&mut self, arg: &str) {
+        let re = Regex::new(r"^--wss-url=(.*)$")
+            .unwrap();
 
-impl AppConfigurationParser {
-    /// Create a new app configuration parser.
-    fn new() -> AppConfigurationParser {
-        let default_mac_addr = utils::result_or_error(
-            get_first_mac(),
-            EXIT_CODE_NETWORK_ERROR,
-            "unable to get any network interface MAC address");
+        let url = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
 
-        AppConfigurationParser {
-            arrow_mac:          default_mac_addr,
-            arrow_svc_addr:     String::new(),
-            ca_certificates:    Vec::new(),
-            rtsp_services:      Vec::new(),
-            mjpeg_services:     Vec::new(),
-            http_services:      Vec::new(),
-            tcp_services:       Vec::new(),
-            logger_type:        LoggerType::Syslog,
-            config_file:        CONFIG_FILE.to_string(),
-            state_file:         STATE_FILE.to_string(),
-            rtsp_paths_file:    RTSP_PATHS_FILE.to_string(),
-            mjpeg_paths_file:   MJPEG_PATHS_FILE.to_string(),
-            log_file:           String::new(),
-            discovery:          false,
-            verbose:            false,
-            diagnostic_mode:    false,
-            log_file_size:      10 * 1024,
-            log_file_rotations: 1,
-        }
+        self.wss_endpoint = Some(utils::result_or_error(
+            WssEndpoint::parse(url),
+            EXIT_CODE_USAGE,
+            "invalid WebSocket URL"));
     }
 
-    /// Parse given command line arguments.
-    fn parse(args: &mut Args) -> AppConfigurationParser {
-        let mut parser = AppConfigurationParser::new();
+    /// Process the wss-url argument (dummy).
+    #[cfg(not(feature = "websocket"))]
+    fn wss_url(&mut self, arg: &str) {
+        utils::error(RuntimeError::from(arg),
+            EXIT_CODE_USAGE, "unknown argument");
+    }
 
-        // skip the application name
-        args.next();
+    /// Process the relay argument.
+    fn relay(&mut self, arg: &str) {
+        let re = Regex::new(r"^--relay=(.*)$")
+            .unwrap();
 
-        if let Some(arrow_svc_addr) = args.next() {
-            parser.arrow_svc_addr = arrow_svc_addr;
+        self.relay_addr = Some(re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string());
+    }
+
+    /// Process the ntp-server argument.
+    fn ntp_server(&mut self, arg: &str) {
+        if !cfg!(feature = "sntp") {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+
+        let re = Regex::new(r"^--ntp-server=(.+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.ntp_servers.push(caps.at(1)
+                .unwrap()
+                .to_string());
         } else {
-            usage(EXIT_CODE_USAGE);
+            utils::error(RuntimeError::from("--ntp-server"),
+                EXIT_CODE_USAGE, "invalid argument");
         }
+    }
 
-        while let Some(ref arg) = args.next() {
-            match arg as &str {
-                "-c" => parser.ca_certificates(args),
-                "-d" => parser.discovery(),
-                "-i" => parser.interface(args),
-                "-r" => parser.rtsp_service(args),
-                "-m" => parser.mjpeg_service(args),
-                "-h" => parser.http_service(args),
-                "-t" => parser.tcp_service(args),
-                "-v" => parser.verbose(),
+    /// Process the tenant argument.
+    fn tenant(&mut self, arg: &str) {
+        let re = Regex::new(r"^--tenant=([^@]+)(@(.+))?$")
+            .unwrap();
 
-                "--diagnostic-mode"   => parser.diagnostic_mode(),
-                "--log-stderr"        => parser.log_stderr(),
-                "--log-stderr-pretty" => parser.log_stderr_pretty(),
+        if let Some(caps) = re.captures(arg) {
+            let file = caps.at(1)
+                .unwrap()
+                .to_string();
+            let addr = caps.at(3)
+                .map(|addr| addr.to_string());
 
-                arg => {
-                    if arg.starts_with("--config-file=") {
-                        parser.config_file(arg);
-                    } else if arg.starts_with("--conn-state-file=") {
-                        parser.conn_state_file(arg);
-                    } else if arg.starts_with("--rtsp-paths=") {
-                        parser.rtsp_paths(arg);
-                    } else if arg.starts_with("--mjpeg-paths=") {
-                        parser.mjpeg_paths(arg);
-                    } else if arg.starts_with("--log-file=") {
-                        parser.log_file(arg);
-                    } else if arg.starts_with("--log-file-size=") {
-                        parser.log_file_size(arg);
-                    } else if arg.starts_with("--log-file-rotations=") {
-                        parser.log_file_rotations(arg);
-                    } else {
-                        utils::error(RuntimeError::from(arg),
-                            EXIT_CODE_USAGE, "unknown argument");
-                    }
-                }
-            }
+            self.tenants.push((file, addr));
+        } else {
+            utils::error(RuntimeError::from("--tenant"),
+                EXIT_CODE_USAGE, "invalid argument");
         }
-
-        parser
     }
 
-    /// Get next argument from a given list.
-    fn next_argument(&mut self, args: &mut Args, emsg: &str) -> String {
-        let arg = args.next()
-            .ok_or(RuntimeError::from(emsg));
+    /// Process the relay-listen argument.
+    fn relay_listen(&mut self, arg: &str) {
+        let re = Regex::new(r"^--relay-listen=(.*)$")
+            .unwrap();
 
-        result_or_usage(arg)
+        self.relay_listen = Some(re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap()
+            .to_string());
     }
 
-    /// Process the CA certificate argument.
-    fn ca_certificates(&mut self, args: &mut Args) {
-        let path = self.next_argument(args, "CA certificate path expected");
-        self.ca_certificates.push(path);
-    }
+    /// Process the relay-allow argument.
+    fn relay_allow(&mut self, arg: &str) {
+        let re = Regex::new(r"^--relay-allow=(.+)$")
+            .unwrap();
 
-    /// Process the discovery argument.
-    fn discovery(&mut self) {
-        if cfg!(feature = "discovery") {
-            self.discovery = true;
+        if let Some(caps) = re.captures(arg) {
+            self.relay_allow.push(caps.at(1).unwrap().to_string());
         } else {
-            utils::error(RuntimeError::from("-d"),
-                EXIT_CODE_USAGE, "unknown argument");
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "address expected");
         }
     }
 
-    /// Process the interface argument.
-    fn interface(&mut self, args: &mut Args) {
-        let iface = self.next_argument(args, "network interface name expected");
+    /// Process the relay-secret-file argument.
+    fn relay_secret_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--relay-secret-file=(.+)$")
+            .unwrap();
 
-        self.arrow_mac = utils::result_or_error(
-            get_mac(&iface),
-            EXIT_CODE_NETWORK_ERROR,
-            "no such network interface");
+        if let Some(caps) = re.captures(arg) {
+            self.relay_secret_file = Some(caps.at(1).unwrap().to_string());
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "path expected");
+        }
     }
 
-    /// Process the RTSP service argument.
-    fn rtsp_service(&mut self, args: &mut Args) {
-        let url = self.next_argument(args, "RTSP URL expected");
-        self.rtsp_services.push(url);
-    }
+    /// Process the takeover argument.
+    fn takeover(&mut self) {
+        if !cfg!(feature = "control-socket") {
+            utils::error(RuntimeError::from("--takeover"),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
 
-    /// Process the MJPEG service argument.
-    fn mjpeg_service(&mut self, args: &mut Args) {
-        let url = self.next_argument(args, "HTTP URL expected");
-        self.mjpeg_services.push(url);
+        self.takeover = true;
     }
 
-    /// Process the HTTP service argument.
-    fn http_service(&mut self, args: &mut Args) {
-        let addr = self.next_argument(args, "TCP socket address expected");
-        self.http_services.push(addr);
-    }
+    /// Process the sap-listen argument.
+    fn sap_listen(&mut self) {
+        if !cfg!(feature = "discovery") {
+            utils::error(RuntimeError::from("--sap-listen"),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
 
-    /// Process the TCP service argument.
-    fn tcp_service(&mut self, args: &mut Args) {
-        let addr = self.next_argument(args, "TCP socket address expected");
-        self.tcp_services.push(addr);
+        self.sap_listen = true;
     }
 
-    /// Process the verbose argument.
-    fn verbose(&mut self) {
-        self.verbose = true;
-    }
+    /// Process the control-socket argument.
+    fn control_socket(&mut self, arg: &str) {
+        if !cfg!(feature = "control-socket") {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
 
-    /// Process the diagnostic mode argument.
-    fn diagnostic_mode(&mut self) {
-        self.diagnostic_mode = true;
-    }
+        let re = Regex::new(r"^--control-socket=(.+)$")
+            .unwrap();
 
-    /// Process the log-stderr argument.
-    fn log_stderr(&mut self) {
-        self.logger_type = LoggerType::Stderr;
+        if let Some(caps) = re.captures(arg) {
+            self.control_socket = caps.at(1)
+                .unwrap()
+                .to_string();
+        } else {
+            utils::error(RuntimeError::from("--control-socket"),
+                EXIT_CODE_USAGE, "invalid argument");
+        }
     }
 
-    /// Process the log-stderr-pretty argument.
-    fn log_stderr_pretty(&mut self) {
-        self.logger_type = LoggerType::StderrPretty;
+    /// Process the status-page argument.
+    fn status_page(&mut self, arg: &str) {
+        if !cfg!(feature = "metrics") {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+
+        let re = Regex::new(r"^--status-page=(.+)$")
+            .unwrap();
+
+        if let Some(caps) = re.captures(arg) {
+            self.status_page = caps.at(1)
+                .map(|addr| addr.to_string());
+        } else {
+            utils::error(RuntimeError::from("--status-page"),
+                EXIT_CODE_USAGE, "invalid argument");
+        }
     }
 
-    /// Process the log-file argument.
-    fn log_file(&mut self, arg: &str) {
-        self.logger_type = LoggerType::FileLogger;
+    /// Process the site-name argument.
+    fn site_name(&mut self, arg: &str) {
+        let re = Regex::new(r"^--site-name=(.*)$")
+            .unwrap();
+
+        let name = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.metadata.set_site_name(name);
+    }
 
-        let re = Regex::new(r"^--log-file=(.*)$")
+    /// Process the location argument.
+    fn location(&mut self, arg: &str) {
+        let re = Regex::new(r"^--location=(.*)$")
             .unwrap();
 
-        self.log_file = re.captures(arg)
+        let location = re.captures(arg)
             .unwrap()
             .at(1)
+            .unwrap();
+
+        self.metadata.set_location(location);
+    }
+
+    /// Process the customer-id argument.
+    fn customer_id(&mut self, arg: &str) {
+        let re = Regex::new(r"^--customer-id=(.*)$")
+            .unwrap();
+
+        let id = re.captures(arg)
             .unwrap()
-            .to_string();
+            .at(1)
+            .unwrap();
+
+        self.metadata.set_customer_id(id);
     }
 
-    /// Process the log-file-size argument.
-    fn log_file_size(&mut self, arg: &str) {
-        let re = Regex::new(r"^--log-file-size=(\d+)$")
+    /// Process the label argument.
+    fn label(&mut self, arg: &str) {
+        let re = Regex::new(r"^--label=([^=]+)=(.*)$")
             .unwrap();
 
         if let Some(caps) = re.captures(arg) {
-            self.log_file_size = usize::from_str(caps.at(1).unwrap())
-                .unwrap();
+            self.metadata.add_label(
+                caps.at(1).unwrap(),
+                caps.at(2).unwrap());
         } else {
             utils::error(RuntimeError::from(arg),
-                EXIT_CODE_USAGE, "number expected");
+                EXIT_CODE_USAGE, "label in the \"key=value\" format expected");
         }
     }
 
-    /// Process the log-file-rotations argument.
-    fn log_file_rotations(&mut self, arg: &str) {
-        let re = Regex::new(r"^--log-file-rotations=(\d+)$")
+    /// Process the integrator argument.
+    fn integrator(&mut self, arg: &str) {
+        let re = Regex::new(r"^--integrator=(.*)$")
+            .unwrap();
+
+        let integrator = re.captures(arg)
+            .unwrap()
+            .at(1)
+            .unwrap();
+
+        self.integrator = Some(integrator.to_string());
+    }
+
+    /// Process the hook argument.
+    fn hook(&mut self, arg: &str) {
+        let re = Regex::new(r"^--hook=([a-z-]+):(.+)$")
             .unwrap();
 
         if let Some(caps) = re.captures(arg) {
-            self.log_file_rotations = usize::from_str(caps.at(1).unwrap())
-                .unwrap();
+            let event = utils::result_or_error(
+                HookEvent::from_name(caps.at(1).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid hook");
+
+            self.hooks.add(event, caps.at(2).unwrap());
         } else {
             utils::error(RuntimeError::from(arg),
-                EXIT_CODE_USAGE, "number expected");
+                EXIT_CODE_USAGE, "event:command expected");
         }
     }
 
-    /// Process the config-file argument.
-    fn config_file(&mut self, arg: &str) {
-        let re = Regex::new(r"^--config-file=(.*)$")
+    /// Process the status-led argument.
+    fn status_led(&mut self, arg: &str) {
+        let re = Regex::new(r"^--status-led=(.*)$")
             .unwrap();
 
-        self.config_file = re.captures(arg)
+        let output = re.captures(arg)
             .unwrap()
             .at(1)
-            .unwrap()
-            .to_string();
+            .unwrap();
+
+        let output = utils::result_or_error(
+            LedOutput::from_str(output),
+            EXIT_CODE_USAGE,
+            "invalid status LED");
+
+        self.status_led = Some(output);
     }
 
-    /// Process the conn-state-file argument.
-    fn conn_state_file(&mut self, arg: &str) {
-        let re = Regex::new(r"^--conn-state-file=(.*)$")
+    /// Process the led-pattern argument.
+    fn led_pattern(&mut self, arg: &str) {
+        let re = Regex::new(r"^--led-pattern=([a-z]+):(.*)$")
             .unwrap();
 
-        self.state_file = re.captures(arg)
+        if let Some(caps) = re.captures(arg) {
+            let state = utils::result_or_error(
+                LedState::from_name(caps.at(1).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid LED pattern");
+
+            let pattern = utils::result_or_error(
+                LedPattern::from_str(caps.at(2).unwrap()),
+                EXIT_CODE_USAGE,
+                "invalid LED pattern");
+
+            self.led_patterns.set(state, pattern);
+        } else {
+            utils::error(RuntimeError::from(arg),
+                EXIT_CODE_USAGE, "state:pattern expected");
+        }
+    }
+
+    /// Process the identity-file argument.
+    fn identity_file(&mut self, arg: &str) {
+        let re = Regex::new(r"^--identity-file=(.*)$")
+            .unwrap();
+
+        self.identity_file = re.captures(arg)
             .unwrap()
             .at(1)
             .unwrap()
@@ -1319,21 +4642,527 @@ impl AppConfigurationParser {
     }
 }
 
+/// Upgrade a given configuration file to the current schema.
+fn migrate_config(args: &mut Args) -> ! {
+    let mut config_file = CONFIG_FILE.to_string();
+    let mut dry_run     = false;
+
+    // skip the application name and the command
+    args.next();
+    args.next();
+
+    let re = Regex::new(r"^--config-file=(.*)$")
+        .unwrap();
+
+    for arg in args {
+        if arg == "--dry-run" {
+            dry_run = true;
+        } else if let Some(caps) = re.captures(&arg) {
+            config_file = caps.at(1)
+                .unwrap()
+                .to_string();
+        } else {
+            utils::error(RuntimeError::from(&arg as &str),
+                EXIT_CODE_USAGE, "unknown argument");
+        }
+    }
+
+    let content = utils::result_or_error(
+        config::read_file(&config_file),
+        EXIT_CODE_CONFIG_ERROR,
+        format!("unable to read config file \"{}\"", config_file));
+
+    let migration = utils::result_or_error(
+        migration::migrate_config(&content),
+        EXIT_CODE_CONFIG_ERROR,
+        format!("unable to migrate config file \"{}\"", config_file));
+
+    for change in &migration.changes {
+        println!("{}", change);
+    }
+
+    if migration.changes.is_empty() {
+        println!("config file \"{}\" is up to date", config_file);
+    } else if dry_run {
+        println!("\n{}", migration.config);
+    } else {
+        let backup = utils::backup_file(&config_file);
+
+        utils::result_or_error(migration.config.save(&config_file),
+            EXIT_CODE_CONFIG_ERROR,
+            format!("unable to save config file \"{}\"", config_file));
+
+        println!("config file \"{}\" migrated (backup: \"{}\")",
+            config_file, backup);
+    }
+
+    process::exit(0);
+}
+
+/// Send a given command to the control socket of a running client and print
+/// the response.
+#[cfg(feature = "control-socket")]
+fn control_command(args: &mut Args) -> ! {
+    let mut socket  = CONTROL_SOCKET.to_string();
+    let mut command = Vec::new();
+
+    // skip the application name and the command
+    args.next();
+    args.next();
+
+    let re = Regex::new(r"^--control-socket=(.+)$")
+        .unwrap();
+
+    for arg in args {
+        if let Some(caps) = re.captures(&arg) {
+            socket = caps.at(1)
+                .unwrap()
+                .to_string();
+        } else {
+            // the command and its arguments
+            command.push(arg);
+        }
+    }
+
+    let command = if command.is_empty() {
+        "help".to_string()
+    } else {
+        command.join(" ")
+    };
+
+    let response = utils::result_or_error(
+        control_socket::send_command(&socket, &command),
+        EXIT_CODE_NETWORK_ERROR,
+        format!("unable to send command to control socket \"{}\"", socket));
+
+    print!("{}", response);
+
+    if response.starts_with("ERROR:") {
+        process::exit(EXIT_CODE_USAGE);
+    }
+
+    process::exit(0);
+}
+
+/// Send a given command to the control socket of a running client (dummy).
+#[cfg(not(feature = "control-socket"))]
+fn control_command(_: &mut Args) -> ! {
+    utils::error(RuntimeError::from("ctl"),
+        EXIT_CODE_USAGE, "the client was compiled without the control socket");
+}
+
+/// Stream live events of a running client to stdout. Event categories can
+/// be selected using flags (all events are printed if there is none).
+#[cfg(feature = "control-socket")]
+fn monitor(args: &mut Args) -> ! {
+    use std::io::Write;
+
+    let mut socket     = CONTROL_SOCKET.to_string();
+    let mut categories = Vec::new();
+
+    // skip the application name and the command
+    args.next();
+    args.next();
+
+    let re = Regex::new(r"^--control-socket=(.+)$")
+        .unwrap();
+
+    for arg in args {
+        if let Some(caps) = re.captures(&arg) {
+            socket = caps.at(1)
+                .unwrap()
+                .to_string();
+
+            continue;
+        }
+
+        let category = match &arg as &str {
+            "--connection" => "connection",
+            "--sessions"   => "session",
+            "--scans"      => "scan",
+            "--alerts"     => "alert",
+            "--warnings"   => "warning",
+            _ => utils::error(RuntimeError::from(&arg as &str),
+                EXIT_CODE_USAGE, "unknown argument")
+        };
+
+        categories.push(category.to_string());
+    }
+
+    let res = control_socket::monitor(&socket, &categories, |event| {
+        let mut stdout = io::stdout();
+
+        // stop quietly if the output is closed (e.g. piped into head)
+        if writeln!(stdout, "{}", event).and_then(|_| stdout.flush()).is_err() {
+            process::exit(0);
+        }
+    });
+
+    utils::result_or_error(res,
+        EXIT_CODE_NETWORK_ERROR,
+        format!("unable to monitor events using control socket \"{}\"", socket));
+
+    println!("the client has closed the connection");
+
+    process::exit(0);
+}
+
+/// Stream live events of a running client (dummy).
+#[cfg(not(feature = "control-socket"))]
+fn monitor(_: &mut Args) -> ! {
+    utils::error(RuntimeError::from("monitor"),
+        EXIT_CODE_USAGE, "the client was compiled without the control socket");
+}
+
+/// Download a newer OUI vendor database, verify it against a given SHA-256
+/// checksum and save it. The running client picks it up before the next
+/// network scan.
+#[cfg(feature = "discovery")]
+fn update_oui(args: &mut Args) -> ! {
+    let mut oui_file = OUI_FILE.to_string();
+    let mut params   = Vec::new();
+
+    // skip the application name and the command
+    args.next();
+    args.next();
+
+    let re = Regex::new(r"^--oui-file=(.+)$")
+        .unwrap();
+
+    for arg in args {
+        if let Some(caps) = re.captures(&arg) {
+            oui_file = caps.at(1)
+                .unwrap()
+                .to_string();
+        } else if arg.starts_with("-") {
+            utils::error(RuntimeError::from(&arg as &str),
+                EXIT_CODE_USAGE, "unknown argument");
+        } else {
+            params.push(arg);
+        }
+    }
+
+    if params.len() != 2 {
+        usage(EXIT_CODE_USAGE);
+    }
+
+    let content = utils::result_or_error(
+        oui::fetch(&params[0], &params[1]),
+        EXIT_CODE_NETWORK_ERROR,
+        "unable to update OUI database");
+
+    utils::result_or_error(
+        utils::write_file_atomic(&oui_file, &content),
+        EXIT_CODE_CONFIG_ERROR,
+        format!("unable to save OUI database \"{}\"", oui_file));
+
+    println!("OUI database saved into \"{}\"", oui_file);
+
+    process::exit(0);
+}
+
+/// Download a newer OUI vendor database (dummy).
+#[cfg(not(feature = "discovery"))]
+fn update_oui(_: &mut Args) -> ! {
+    utils::error(RuntimeError::from("update-oui"),
+        EXIT_CODE_USAGE, "the client was compiled without network discovery");
+}
+
+/// Print the client version (or a detailed build description) and exit.
+fn print_version(verbose: bool) -> ! {
+    if verbose {
+        println!("{}", build_info::describe());
+    } else {
+        println!("arrow-client {}", build_info::VERSION);
+    }
+
+    process::exit(0);
+}
+
+/// Run the sandbox helper process (this command is used internally for
+/// sandboxed sessions).
+#[cfg(feature = "sandbox")]
+fn sandbox_helper(args: &mut Args) -> ! {
+    // skip the application name and the command
+    args.next();
+    args.next();
+
+    let target = match args.next() {
+        Some(target) => target,
+        None => utils::error(RuntimeError::from(sandbox::HELPER_COMMAND),
+            EXIT_CODE_USAGE, "missing target address")
+    };
+
+    match sandbox::run_helper(&target) {
+        Ok(_)  => process::exit(0),
+        Err(_) => process::exit(EXIT_CODE_NETWORK_ERROR)
+    }
+}
+
+/// Start a given tenant. The tenant shares settings of the main client
+/// (except for the ones bound to the main configuration, such as network
+/// scanning or statistics files) and it runs in its own threads.
+fn start_tenant<L: 'static + Logger + Clone + Send>(
+    mut logger: L,
+    tenant: Tenant,
+    relay: Option<String>,
+    arrow_mac: &MacAddr,
+    rtsp_paths_file: &str,
+    mjpeg_paths_file: &str,
+    app_context: &AppContext) {
+    log_info!(logger, "tenant started (uuid: {}, config file: {}, Arrow Service: {})",
+        tenant.config.uuid_string(), tenant.config_file, tenant.arrow_svc_addr);
+
+    let Tenant {
+        config_file,
+        state_file,
+        arrow_svc_addr,
+        ssl_context,
+        config
+    } = tenant;
+
+    let app_context = Shared::new(
+        app_context.tenant(config, &config_file));
+
+    let rtsp_paths_file  = rtsp_paths_file.to_string();
+    let mjpeg_paths_file = mjpeg_paths_file.to_string();
+    let arrow_mac        = *arrow_mac;
+
+    thread::spawn(move || {
+        let mut event_loop = EventLoop::new()
+            .unwrap();
+
+        let mut cmd_handler = CommandHandler::new(
+            logger.clone(),
+            &config_file,
+            &rtsp_paths_file,
+            &mjpeg_paths_file,
+            ServiceTable::new(),
+            ServiceResolver::new(DnsCache::new()),
+            app_context.clone());
+
+        let cmd_sender = CommandSender::new(event_loop.channel());
+
+        spawn_arrow_thread(
+            logger,
+            &state_file,
+            ssl_context,
+            cmd_sender,
+            &arrow_svc_addr,
+            relay,
+            &arrow_mac,
+            &app_context);
+
+        event_loop.run(&mut cmd_handler)
+            .unwrap();
+    });
+}
+
+/// Start the local control socket (or take over the control socket of a
+/// running client).
+#[cfg(feature = "control-socket")]
+fn start_control_socket<L: 'static + Logger + Clone + Send>(
+    mut logger: L,
+    path: &str,
+    takeover: bool,
+    app_context: Shared<AppContext>) {
+    let socket = ControlSocket::new(logger.clone(), app_context);
+
+    if takeover {
+        if utils::result_or_log(&mut logger, Severity::WARN,
+            format!("unable to take over control socket \"{}\"", path),
+            socket.take_over(path)).is_some() {
+            log_info!(logger, "control socket {} taken over, the previous process is draining its sessions", path);
+        }
+    } else if utils::result_or_log(&mut logger, Severity::WARN,
+        format!("unable to start control socket \"{}\"", path),
+        socket.start(path)).is_some() {
+        log_info!(logger, "control socket listening on {}", path);
+    }
+}
+
+/// Start the local control socket (dummy).
+#[cfg(not(feature = "control-socket"))]
+fn start_control_socket<L>(_: L, _: &str, _: bool, _: Shared<AppContext>) {
+}
+
+/// Start the status page server.
+#[cfg(feature = "metrics")]
+fn start_status_page<L: 'static + Logger + Clone + Send>(
+    mut logger: L,
+    addr: &str,
+    app_context: Shared<AppContext>) {
+    let page = StatusPage::new(logger.clone(), app_context);
+
+    if utils::result_or_log(&mut logger, Severity::WARN,
+        format!("unable to start status page on {}", addr),
+        page.start(addr)).is_some() {
+        log_info!(logger, "status page listening on {}", addr);
+    }
+}
+
+/// Start the status page server (dummy).
+#[cfg(not(feature = "metrics"))]
+fn start_status_page<L>(_: L, _: &str, _: Shared<AppContext>) {
+}
+
+/// Start the SAP/SDP announcement listener.
+#[cfg(feature = "discovery")]
+fn start_sap_listener<L: 'static + Logger + Clone + Send>(
+    mut logger: L,
+    app_context: Shared<AppContext>) {
+    let listener = SapListener::new(logger.clone(), app_context);
+
+    if utils::result_or_log(&mut logger, Severity::WARN,
+        "unable to start SAP listener",
+        listener.start()).is_some() {
+        log_info!(logger, "listening for SAP announcements");
+    }
+}
+
+/// Start the SAP/SDP announcement listener (dummy).
+#[cfg(not(feature = "discovery"))]
+fn start_sap_listener<L>(_: L, _: Shared<AppContext>) {
+}
+
+/// Start the built-in SNTP client synchronizing the process clock with given
+/// servers.
+#[cfg(feature = "sntp")]
+fn start_sntp_client<L: 'static + Logger + Send>(logger: L, servers: Vec<String>) {
+    SntpClient::new(logger, servers)
+        .start();
+}
+
+/// Start the built-in SNTP client (dummy).
+#[cfg(not(feature = "sntp"))]
+fn start_sntp_client<L>(_: L, _: Vec<String>) {
+}
+
 /// Arrow Client main function.
 fn main() {
+    #[cfg(feature = "sandbox")]
+    {
+        if env::args().nth(1).map_or(false, |cmd| cmd == sandbox::HELPER_COMMAND) {
+            sandbox_helper(&mut env::args());
+        }
+    }
+
+    if env::args().nth(1).map_or(false, |cmd| cmd == "migrate-config") {
+        migrate_config(&mut env::args());
+    } else if env::args().nth(1).map_or(false, |cmd| cmd == "ctl") {
+        control_command(&mut env::args());
+    } else if env::args().nth(1).map_or(false, |cmd| cmd == "monitor") {
+        monitor(&mut env::args());
+    } else if env::args().nth(1).map_or(false, |cmd| cmd == "update-oui") {
+        update_oui(&mut env::args());
+    } else if env::args().any(|arg| arg == "--version") {
+        print_version(env::args().any(|arg| arg == "--verbose"));
+    }
+
     let mut app_config = AppConfiguration::init();
 
-    let app_context = app_config.app_context;
+    // the lock of a running client is released once it exits after handing
+    // over its control socket
+    let instance_lock = InstanceLock::new(&app_config.config_file);
 
-    utils::result_or_error(app_context.config.save(&app_config.config_file),
-        EXIT_CODE_CONFIG_ERROR,
-        format!("unable to save config file \"{}\"", &app_config.config_file));
+    if !app_config.takeover {
+        utils::result_or_error(instance_lock.acquire(),
+            EXIT_CODE_CONFIG_ERROR,
+            "unable to start (use --takeover to replace the running client)");
+    }
+
+    let app_context = Shared::new(app_config.app_context);
+
+    start_control_socket(app_config.logger.clone(),
+        &app_config.control_socket,
+        app_config.takeover,
+        app_context.clone());
+
+    if app_config.takeover {
+        utils::result_or_error(
+            instance_lock.acquire_timeout(TAKEOVER_LOCK_TIMEOUT),
+            EXIT_CODE_CONFIG_ERROR,
+            "unable to take over the running client");
+    }
+
+    // the configuration and state files must not be written before the
+    // lock is acquired
+    let stats_export_period = {
+        let app_context = app_context.lock()
+            .unwrap();
+
+        utils::result_or_error(app_context.config.save(&app_config.config_file),
+            EXIT_CODE_CONFIG_ERROR,
+            format!("unable to save config file \"{}\"", &app_config.config_file));
+
+        for tenant in &app_config.tenants {
+            utils::result_or_error(tenant.config.save(&tenant.config_file),
+                EXIT_CODE_CONFIG_ERROR,
+                format!("unable to save config file \"{}\"", tenant.config_file));
+        }
+
+        utils::result_or_log(&mut app_config.logger, Severity::WARN,
+            "unable to save DNS cache",
+            app_config.resolver.dns_cache.save());
+
+        log_info!(&mut app_config.logger,
+            "application started (uuid: {}, mac: {})",
+            app_context.config.uuid_string(), app_config.arrow_mac);
+
+        if app_config.one_shot_register {
+            log_info!(&mut app_config.logger,
+                "client identity saved into \"{}\", registering...",
+                app_config.config_file);
+        }
+
+        check_discovery_capabilities(&mut app_config.logger, &app_context);
+
+        if let Some(ref addr) = app_config.relay_listen {
+            let mut targets = vec![app_config.arrow_svc_addr.clone()];
+
+            targets.extend(app_config.tenants.iter()
+                .map(|tenant| tenant.arrow_svc_addr.clone()));
+            targets.extend(app_config.relay_allow.iter()
+                .cloned());
 
-    log_info!(&mut app_config.logger,
-        "application started (uuid: {}, mac: {})",
-        app_context.config.uuid_string(), app_config.arrow_mac);
+            let secret = app_context.relay_secret.clone()
+                .expect("relay secret expected");
 
-    let app_context = Shared::new(app_context);
+            let relay = RelayServer::new(
+                app_config.logger.clone(),
+                app_context.config.uuid(),
+                app_config.relay_addr.clone(),
+                secret,
+                targets);
+
+            utils::result_or_error(relay.start(addr as &str),
+                EXIT_CODE_NETWORK_ERROR,
+                format!("unable to start relay on {}", addr));
+
+            log_info!(&mut app_config.logger, "relay listening on {}", addr);
+        }
+
+        app_context.stats_export.as_ref()
+            .map(|stats_export| stats_export.period())
+    };
+
+    if !app_config.ntp_servers.is_empty() {
+        start_sntp_client(app_config.logger.clone(), app_config.ntp_servers);
+    }
+
+    if let Some(ref addr) = app_config.status_page {
+        start_status_page(app_config.logger.clone(),
+            addr,
+            app_context.clone());
+    }
+
+    if app_config.sap_listen {
+        start_sap_listener(app_config.logger.clone(), app_context.clone());
+    }
+
+    utils::result_or_log(&mut app_config.logger, Severity::WARN,
+        "unable to install SIGHUP handler, configuration reload will not be available",
+        reload::install_sighup_handler());
 
     let mut event_loop = EventLoop::new()
         .unwrap();
@@ -1344,22 +5173,65 @@ fn main() {
         &app_config.rtsp_paths_file,
         &app_config.mjpeg_paths_file,
         app_config.default_svc_table,
+        app_config.resolver,
         app_context.clone());
 
     let cmd_sender = CommandSender::new(event_loop.channel());
 
+    for tenant in app_config.tenants {
+        let base = app_context.lock()
+            .unwrap();
+
+        start_tenant(app_config.logger.clone(),
+            tenant,
+            app_config.relay_addr.clone(),
+            &app_config.arrow_mac,
+            &app_config.rtsp_paths_file,
+            &app_config.mjpeg_paths_file,
+            &base);
+    }
+
     spawn_arrow_thread(
         app_config.logger,
         &app_config.state_file,
         app_config.ssl_context,
         cmd_sender,
         &app_config.arrow_svc_addr,
+        app_config.relay_addr,
         &app_config.arrow_mac,
         &app_context);
 
     event_loop.timeout_ms(TimerEvent::ScanNetwork, 0)
         .unwrap();
 
+    event_loop.timeout_ms(TimerEvent::SaveSvcStats, SVC_STATS_SAVE_PERIOD)
+        .unwrap();
+
+    #[cfg(feature = "metrics")]
+    {
+        event_loop.timeout_ms(TimerEvent::SampleTraffic, 0)
+            .unwrap();
+    }
+
+    event_loop.timeout_ms(TimerEvent::DrainCheck, DRAIN_CHECK_PERIOD)
+        .unwrap();
+
+    if let Some(period) = stats_export_period {
+        event_loop.timeout_ms(TimerEvent::ExportStats, period * 1000)
+            .unwrap();
+    }
+
+    if app_config.watch_config {
+        event_loop.timeout_ms(TimerEvent::WatchConfig, CONFIG_WATCH_PERIOD)
+            .unwrap();
+    }
+
+    event_loop.timeout_ms(TimerEvent::ReloadCheck, RELOAD_CHECK_PERIOD)
+        .unwrap();
+
+    event_loop.timeout_ms(TimerEvent::ResolveServices, SERVICE_RESOLVE_PERIOD)
+        .unwrap();
+
     event_loop.run(&mut cmd_handler)
         .unwrap();
 }
@@ -0,0 +1,243 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session access control policy.
+//!
+//! The policy controls which services can be opened as sessions. It consists
+//! of an optional time-of-day window (local time), an optional limit of
+//! concurrent sessions per service and a list of denied service addresses.
+//! An empty policy allows everything.
+
+use std::fmt;
+
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::fmt::{Display, Formatter};
+
+use net::arrow::any_tcp::AddressRule;
+
+use utils::RuntimeError;
+
+use time;
+
+/// Time-of-day window (local time). The window can span midnight.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimeWindow {
+    /// Start of the window (minutes since midnight, inclusive).
+    start: u32,
+    /// End of the window (minutes since midnight, exclusive).
+    end:   u32,
+}
+
+impl TimeWindow {
+    /// Check if a given time (minutes since midnight) is within the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = RuntimeError;
+
+    /// Parse a given window in the "HH:MM-HH:MM" format.
+    fn from_str(s: &str) -> Result<TimeWindow, RuntimeError> {
+        let mut parts = s.splitn(2, '-');
+
+        let start = try!(parse_time(parts.next().unwrap()));
+        let end   = try!(parts.next()
+            .ok_or(RuntimeError::from("HH:MM-HH:MM expected"))
+            .and_then(parse_time));
+
+        let res = TimeWindow {
+            start: start,
+            end:   end
+        };
+
+        Ok(res)
+    }
+}
+
+/// Access policy violation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccessViolation {
+    /// The session was requested outside the allowed time window.
+    OutsideTimeWindow,
+    /// The service has already reached the maximum number of sessions.
+    TooManySessions(usize),
+    /// The service address is denied.
+    Denied,
+}
+
+impl Display for AccessViolation {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &AccessViolation::OutsideTimeWindow =>
+                f.write_str("outside of the allowed time window"),
+            &AccessViolation::TooManySessions(max) =>
+                write!(f, "maximum number of sessions ({}) reached", max),
+            &AccessViolation::Denied =>
+                f.write_str("service address denied")
+        }
+    }
+}
+
+/// Session access control policy.
+#[derive(Debug, Clone)]
+pub struct AccessPolicy {
+    window:       Option<TimeWindow>,
+    max_sessions: Option<usize>,
+    deny:         Vec<AddressRule>,
+}
+
+impl AccessPolicy {
+    /// Create a new policy allowing everything.
+    pub fn new() -> AccessPolicy {
+        AccessPolicy {
+            window:       None,
+            max_sessions: None,
+            deny:         Vec::new()
+        }
+    }
+
+    /// Allow sessions only within a given time window.
+    pub fn set_time_window(&mut self, window: TimeWindow) {
+        self.window = Some(window);
+    }
+
+    /// Set maximum number of concurrent sessions per service.
+    pub fn set_max_sessions(&mut self, max: usize) {
+        self.max_sessions = Some(max);
+    }
+
+    /// Deny services matching a given address rule.
+    pub fn deny(&mut self, rule: AddressRule) {
+        self.deny.push(rule);
+    }
+
+    /// Check if a new session to a given service address can be opened.
+    /// The number of currently open sessions of the service is expected.
+    pub fn check(
+        &self,
+        addr: &SocketAddr,
+        sessions: usize) -> Result<(), AccessViolation> {
+        let now = time::now();
+
+        let minute = (now.tm_hour * 60 + now.tm_min) as u32;
+
+        self.check_at(addr, sessions, minute)
+    }
+
+    /// Check if a new session to a given service address can be opened at
+    /// a given time (minutes since midnight).
+    fn check_at(
+        &self,
+        addr: &SocketAddr,
+        sessions: usize,
+        minute: u32) -> Result<(), AccessViolation> {
+        if self.deny.iter().any(|rule| rule.matches(addr)) {
+            return Err(AccessViolation::Denied);
+        }
+
+        if let Some(window) = self.window {
+            if !window.contains(minute) {
+                return Err(AccessViolation::OutsideTimeWindow);
+            }
+        }
+
+        if let Some(max) = self.max_sessions {
+            if sessions >= max {
+                return Err(AccessViolation::TooManySessions(max));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a given time in the "HH:MM" format and return number of minutes
+/// since midnight.
+fn parse_time(s: &str) -> Result<u32, RuntimeError> {
+    let mut parts = s.splitn(2, ':');
+
+    let hours   = u32::from_str(parts.next().unwrap());
+    let minutes = parts.next()
+        .ok_or(())
+        .and_then(|minutes| u32::from_str(minutes).or(Err(())));
+
+    match (hours, minutes) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 =>
+            Ok(hours * 60 + minutes),
+        _ => Err(RuntimeError::from(format!("invalid time: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+
+    use net::arrow::any_tcp::AddressRule;
+
+    #[test]
+    fn test_time_window() {
+        let window = TimeWindow::from_str("08:00-17:30")
+            .unwrap();
+
+        assert!(!window.contains(7 * 60 + 59));
+        assert!(window.contains(8 * 60));
+        assert!(!window.contains(17 * 60 + 30));
+
+        let window = TimeWindow::from_str("22:00-06:00")
+            .unwrap();
+
+        assert!(window.contains(23 * 60));
+        assert!(window.contains(60));
+        assert!(!window.contains(12 * 60));
+
+        assert!(TimeWindow::from_str("24:00-06:00").is_err());
+        assert!(TimeWindow::from_str("08:00").is_err());
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let addr = SocketAddr::from_str("10.0.0.1:554")
+            .unwrap();
+
+        let mut policy = AccessPolicy::new();
+
+        assert_eq!(policy.check_at(&addr, 100, 0), Ok(()));
+
+        policy.set_time_window(TimeWindow::from_str("08:00-17:00")
+            .unwrap());
+        policy.set_max_sessions(2);
+
+        assert_eq!(policy.check_at(&addr, 1, 9 * 60), Ok(()));
+        assert_eq!(policy.check_at(&addr, 2, 9 * 60),
+            Err(AccessViolation::TooManySessions(2)));
+        assert_eq!(policy.check_at(&addr, 0, 18 * 60),
+            Err(AccessViolation::OutsideTimeWindow));
+
+        policy.deny(AddressRule::from_str("10.0.0.0/24:554")
+            .unwrap());
+
+        assert_eq!(policy.check_at(&addr, 0, 9 * 60),
+            Err(AccessViolation::Denied));
+    }
+}
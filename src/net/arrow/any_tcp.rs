@@ -0,0 +1,260 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Any-TCP service definitions.
+//!
+//! The any-TCP service allows Arrow Service to open sessions to TCP services
+//! which are not present in the service table (e.g. NVR web interfaces). The
+//! first message of every any-TCP session starts with a null-terminated
+//! target address ("ip:port") followed by the session data. Only targets
+//! matching the configured allowlist can be connected.
+
+use std::str;
+
+use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+
+use utils::RuntimeError;
+
+/// Maximum length of the target address preamble (including the null byte).
+const MAX_TARGET_LENGTH: usize = 64;
+
+/// Address rule (network, prefix length and an optional port range).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AddressRule {
+    network: IpAddr,
+    prefix:  u8,
+    ports:   Option<(u16, u16)>,
+}
+
+impl AddressRule {
+    /// Check if a given socket address matches this rule.
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        let port = addr.port();
+
+        let port_ok = match self.ports {
+            Some((first, last)) => port >= first && port <= last,
+            None => true
+        };
+
+        port_ok && match (self.network, addr.ip()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) =>
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) =>
+                prefix_matches(&ipv6_bytes(&network.segments()),
+                    &ipv6_bytes(&ip.segments()), self.prefix),
+            _ => false
+        }
+    }
+}
+
+impl FromStr for AddressRule {
+    type Err = RuntimeError;
+
+    /// Parse a given rule in the "network/prefix[:port[-port]]" format.
+    fn from_str(s: &str) -> Result<AddressRule, RuntimeError> {
+        let mut parts = s.splitn(2, '/');
+
+        let network = parts.next()
+            .unwrap();
+        let rest    = try!(parts.next()
+            .ok_or(RuntimeError::from("missing network prefix length")));
+
+        let mut parts = rest.splitn(2, ':');
+
+        let prefix = parts.next()
+            .unwrap();
+        let ports  = parts.next();
+
+        let network = try!(IpAddr::from_str(network)
+            .or(Err(RuntimeError::from("invalid network address"))));
+        let prefix  = try!(u8::from_str(prefix)
+            .or(Err(RuntimeError::from("invalid network prefix length"))));
+
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        if prefix > max_prefix {
+            return Err(RuntimeError::from("invalid network prefix length"));
+        }
+
+        let ports = match ports {
+            Some(ports) => Some(try!(parse_port_range(ports))),
+            None        => None
+        };
+
+        let res = AddressRule {
+            network: network,
+            prefix:  prefix,
+            ports:   ports
+        };
+
+        Ok(res)
+    }
+}
+
+/// List of TCP targets allowed for the any-TCP service.
+#[derive(Debug, Clone)]
+pub struct TcpAllowlist {
+    rules: Vec<AddressRule>,
+}
+
+impl TcpAllowlist {
+    /// Create a new empty allowlist (i.e. nothing is allowed).
+    pub fn new() -> TcpAllowlist {
+        TcpAllowlist {
+            rules: Vec::new()
+        }
+    }
+
+    /// Add a given rule.
+    pub fn add(&mut self, rule: AddressRule) {
+        self.rules.push(rule)
+    }
+
+    /// Check if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Check if a given socket address is allowed.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.rules.iter()
+            .any(|rule| rule.matches(addr))
+    }
+}
+
+/// Parse the target address preamble of a given any-TCP session request.
+/// The target address and the preamble length (including the null byte) are
+/// returned.
+pub fn parse_target(request: &[u8]) -> Result<(SocketAddr, usize), RuntimeError> {
+    let max_len = if request.len() < MAX_TARGET_LENGTH {
+        request.len()
+    } else {
+        MAX_TARGET_LENGTH
+    };
+
+    let end = try!(request[..max_len].iter()
+        .position(|b| *b == 0)
+        .ok_or(RuntimeError::from("missing target address")));
+
+    let addr = try!(str::from_utf8(&request[..end])
+        .ok()
+        .and_then(|addr| SocketAddr::from_str(addr).ok())
+        .ok_or(RuntimeError::from("invalid target address")));
+
+    Ok((addr, end + 1))
+}
+
+/// Parse a given port range in the "port[-port]" format.
+fn parse_port_range(s: &str) -> Result<(u16, u16), RuntimeError> {
+    let mut parts = s.splitn(2, '-');
+
+    let first = parts.next()
+        .unwrap();
+    let last  = parts.next()
+        .unwrap_or(first);
+
+    let first = try!(u16::from_str(first)
+        .or(Err(RuntimeError::from("invalid port number"))));
+    let last  = try!(u16::from_str(last)
+        .or(Err(RuntimeError::from("invalid port number"))));
+
+    if first > last {
+        Err(RuntimeError::from("invalid port range"))
+    } else {
+        Ok((first, last))
+    }
+}
+
+/// Convert given IPv6 address segments into bytes.
+fn ipv6_bytes(segments: &[u16; 8]) -> [u8; 16] {
+    let mut res = [0u8; 16];
+
+    for (i, segment) in segments.iter().enumerate() {
+        res[2 * i]     = (*segment >> 8) as u8;
+        res[2 * i + 1] = *segment as u8;
+    }
+
+    res
+}
+
+/// Check if the first given number of bits of given addresses are equal.
+fn prefix_matches(network: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let bytes = (prefix / 8) as usize;
+    let bits  = prefix % 8;
+
+    if network[..bytes] != addr[..bytes] {
+        false
+    } else if bits == 0 {
+        true
+    } else {
+        let mask = 0xffu8 << (8 - bits);
+
+        (network[bytes] & mask) == (addr[bytes] & mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_allowlist() {
+        let mut allowlist = TcpAllowlist::new();
+
+        let addr = SocketAddr::from_str("192.168.1.10:80")
+            .unwrap();
+
+        assert!(!allowlist.allows(&addr));
+
+        allowlist.add(AddressRule::from_str("192.168.1.0/25:80-90")
+            .unwrap());
+        allowlist.add(AddressRule::from_str("fd00::/8")
+            .unwrap());
+
+        assert!(allowlist.allows(&addr));
+        assert!(allowlist.allows(&SocketAddr::from_str("192.168.1.127:90")
+            .unwrap()));
+        assert!(!allowlist.allows(&SocketAddr::from_str("192.168.1.128:80")
+            .unwrap()));
+        assert!(!allowlist.allows(&SocketAddr::from_str("192.168.1.10:91")
+            .unwrap()));
+        assert!(allowlist.allows(&SocketAddr::from_str("[fd12::1]:443")
+            .unwrap()));
+        assert!(!allowlist.allows(&SocketAddr::from_str("[fe80::1]:443")
+            .unwrap()));
+
+        assert!(AddressRule::from_str("192.168.1.0").is_err());
+        assert!(AddressRule::from_str("192.168.1.0/33").is_err());
+        assert!(AddressRule::from_str("192.168.1.0/24:90-80").is_err());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let (addr, len) = parse_target(b"10.0.0.1:8080\0GET / HTTP/1.0")
+            .unwrap();
+
+        assert_eq!(addr, SocketAddr::from_str("10.0.0.1:8080").unwrap());
+        assert_eq!(len, 14);
+
+        assert!(parse_target(b"10.0.0.1:8080").is_err());
+        assert!(parse_target(b"foo\0").is_err());
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! REGISTER authentication providers.
+//!
+//! An authentication provider supplies the credential sent within the
+//! REGISTER message. The following providers are available:
+//!
+//! * `StaticSecret` - the client password from the configuration file (the
+//!   default),
+//! * `HmacChallenge` - HMAC-SHA256 response to a challenge sent by Arrow
+//!   Service, the shared secret is read from a file,
+//! * `TokenFile` - a short-lived token (e.g. JWT) which is kept up to date
+//!   in a file by a local provisioning agent.
+//!
+//! Credentials of the HMAC and token providers are sent within an
+//! authentication extension appended to the REGISTER message (right after
+//! the client protocol info). The extension consists of the authentication
+//! method (8 bits), data length (16 bits, big endian) and the data. The
+//! password field of the REGISTER header is zeroed in such case.
+
+use std::io;
+
+use std::fs::File;
+use std::sync::Arc;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+
+use net::raw::ether::MacAddr;
+
+use net::arrow::error::{Result, ArrowError};
+
+use utils::clock;
+use utils::Serialize;
+use utils::RuntimeError;
+use utils::secret::Secret;
+use utils::config::ArrowConfig;
+
+use openssl::crypto::hmac;
+
+use openssl::crypto::hash::Type as HashType;
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+
+/// HMAC-SHA256 challenge-response authentication.
+pub const AUTH_METHOD_HMAC:  u8 = 0x01;
+/// Token authentication.
+pub const AUTH_METHOD_TOKEN: u8 = 0x02;
+
+/// REGISTER credential.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Credential {
+    /// Password sent within the REGISTER header.
+    pub passwd: Secret<[u8; 16]>,
+    /// Authentication extension (method and data), if any.
+    pub ext:    Option<(u8, Secret<Vec<u8>>)>,
+}
+
+impl Credential {
+    /// Create a new password-only credential.
+    pub fn password(passwd: Secret<[u8; 16]>) -> Credential {
+        Credential {
+            passwd: passwd,
+            ext:    None
+        }
+    }
+
+    /// Create a new credential sent within the authentication extension.
+    pub fn extension(method: u8, data: Vec<u8>) -> Credential {
+        Credential {
+            passwd: Secret::new([0u8; 16]),
+            ext:    Some((method, Secret::new(data)))
+        }
+    }
+}
+
+impl Serialize for Credential {
+    /// Serialize the authentication extension (nothing is written for
+    /// password-only credentials).
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if let Some((method, ref data)) = self.ext {
+            let data = data.expose();
+
+            try!(method.serialize(w));
+            try!((data.len() as u16).serialize(w));
+            try!(w.write_all(data));
+        }
+
+        Ok(())
+    }
+}
+
+/// Common trait for authentication providers.
+pub trait AuthProvider : Debug + Send + Sync {
+    /// Get credential for a REGISTER message of a given client. A challenge
+    /// is passed if it has been requested by Arrow Service.
+    fn credential(
+        &self,
+        config: &ArrowConfig,
+        arrow_mac: &MacAddr,
+        challenge: Option<&[u8]>) -> Result<Credential>;
+}
+
+/// Client password from the configuration file. A pending password is used
+/// if there is a secret rotation in progress.
+#[derive(Debug, Clone)]
+pub struct StaticSecret;
+
+impl AuthProvider for StaticSecret {
+    fn credential(
+        &self,
+        config: &ArrowConfig,
+        _: &MacAddr,
+        _: Option<&[u8]>) -> Result<Credential> {
+        Ok(Credential::password(config.register_password()))
+    }
+}
+
+/// HMAC-SHA256 challenge-response. The first REGISTER carries an empty
+/// response (i.e. it requests a challenge), the response to a challenge is
+/// computed over the challenge, client UUID and MAC address. The secret
+/// file is read every time, so that it can be replaced at runtime.
+#[derive(Debug, Clone)]
+pub struct HmacChallenge {
+    secret_file: String,
+}
+
+impl HmacChallenge {
+    /// Create a new provider using a secret from a given file.
+    pub fn new(secret_file: &str) -> HmacChallenge {
+        HmacChallenge {
+            secret_file: secret_file.to_string()
+        }
+    }
+}
+
+impl AuthProvider for HmacChallenge {
+    fn credential(
+        &self,
+        config: &ArrowConfig,
+        arrow_mac: &MacAddr,
+        challenge: Option<&[u8]>) -> Result<Credential> {
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => return Ok(Credential::extension(AUTH_METHOD_HMAC, Vec::new()))
+        };
+
+        let secret = Secret::new(try_arr!(read_secret_file(&self.secret_file)));
+
+        let mut data = challenge.to_vec();
+
+        data.extend_from_slice(&config.uuid());
+        data.extend_from_slice(&arrow_mac.octets());
+
+        let response = hmac::hmac(HashType::SHA256, secret.expose(), &data);
+
+        Ok(Credential::extension(AUTH_METHOD_HMAC, response))
+    }
+}
+
+/// Short-lived token kept up to date in a given file by a local
+/// provisioning agent. The file is read every time. JWT expiration (the
+/// "exp" claim) is checked if present.
+#[derive(Debug, Clone)]
+pub struct TokenFile {
+    token_file: String,
+}
+
+impl TokenFile {
+    /// Create a new provider reading tokens from a given file.
+    pub fn new(token_file: &str) -> TokenFile {
+        TokenFile {
+            token_file: token_file.to_string()
+        }
+    }
+}
+
+impl AuthProvider for TokenFile {
+    fn credential(
+        &self,
+        _: &ArrowConfig,
+        _: &MacAddr,
+        _: Option<&[u8]>) -> Result<Credential> {
+        let token = Secret::new(try_arr!(read_secret_file(&self.token_file)));
+
+        if token.expose().is_empty() || token.expose().len() > 0xffff {
+            return Err(ArrowError::other(format!("invalid authentication token in \"{}\"", self.token_file)));
+        }
+
+        if let Some(exp) = jwt_expiration(token.expose()) {
+            if exp <= clock::timestamp() {
+                return Err(ArrowError::other(format!("authentication token in \"{}\" has expired", self.token_file)));
+            }
+        }
+
+        Ok(Credential::extension(AUTH_METHOD_TOKEN, token.expose().clone()))
+    }
+}
+
+/// Create an authentication provider from a given specification ("static",
+/// "hmac:secret-file" or "token:token-file").
+pub fn from_spec(spec: &str) -> ::std::result::Result<Arc<AuthProvider>, RuntimeError> {
+    if spec == "static" {
+        Ok(Arc::new(StaticSecret))
+    } else if spec.starts_with("hmac:") && spec.len() > 5 {
+        Ok(Arc::new(HmacChallenge::new(&spec[5..])))
+    } else if spec.starts_with("token:") && spec.len() > 6 {
+        Ok(Arc::new(TokenFile::new(&spec[6..])))
+    } else {
+        Err(RuntimeError::from("static, hmac:path or token:path expected"))
+    }
+}
+
+/// Read a given secret or token file (trailing white spaces are removed).
+pub fn read_secret_file(path: &str) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+
+    let res = File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut content));
+
+    if let Err(err) = res {
+        return Err(ArrowError::other(format!("unable to read \"{}\": {}", path, err)));
+    }
+
+    while content.last().map_or(false, |c| (*c as char).is_whitespace()) {
+        content.pop();
+    }
+
+    Ok(content)
+}
+
+/// Get expiration time of a given token if it is a JWT containing the "exp"
+/// claim.
+fn jwt_expiration(token: &[u8]) -> Option<i64> {
+    let parts = token.split(|c| *c == b'.')
+        .collect::<Vec<_>>();
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let payload = match parts[1].from_base64() {
+        Ok(payload) => payload,
+        Err(_)      => return None
+    };
+
+    String::from_utf8(payload).ok()
+        .and_then(|payload| Json::from_str(&payload).ok())
+        .and_then(|claims| claims.find("exp")
+            .and_then(|exp| exp.as_i64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::jwt_expiration;
+
+    use net::raw::ether::MacAddr;
+
+    use utils::Serialize;
+    use utils::config::ArrowConfig;
+    use utils::secret::Secret;
+
+    #[test]
+    fn test_static_secret() {
+        let config = ArrowConfig::new();
+        let mac    = MacAddr::new(1, 2, 3, 4, 5, 6);
+
+        let credential = from_spec("static")
+            .unwrap()
+            .credential(&config, &mac, None)
+            .unwrap();
+
+        assert_eq!(credential, Credential::password(config.password()));
+
+        assert!(from_spec("hmac:").is_err());
+        assert!(from_spec("foo").is_err());
+    }
+
+    #[test]
+    fn test_credential_serialization() {
+        let mut buf = Vec::new();
+
+        Credential::extension(AUTH_METHOD_TOKEN, vec![0xab, 0xcd])
+            .serialize(&mut buf)
+            .unwrap();
+
+        assert_eq!(buf, vec![0x02, 0x00, 0x02, 0xab, 0xcd]);
+
+        buf.clear();
+
+        Credential::password(Secret::new([1; 16]))
+            .serialize(&mut buf)
+            .unwrap();
+
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_jwt_expiration() {
+        // {"alg":"none"}.{"exp":1500000000}.
+        let token = b"eyJhbGciOiJub25lIn0.eyJleHAiOjE1MDAwMDAwMDB9.sig";
+
+        assert_eq!(jwt_expiration(token), Some(1500000000));
+        assert_eq!(jwt_expiration(b"opaque-token"), None);
+    }
+}
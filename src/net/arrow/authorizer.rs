@@ -0,0 +1,283 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session pre-authorization.
+//!
+//! A session authorizer is asked before a new service session connects to
+//! its target. It can allow the session, deny it or redirect it to another
+//! address (e.g. a dynamic address of a service). The decision is passed to
+//! a callback which can be invoked either immediately or later from another
+//! thread, the event loop is not blocked in the meantime. Data received for
+//! the session are buffered until the decision is made.
+//!
+//! Embedding applications can supply their own implementation of the
+//! `SessionAuthorizer` trait. The client itself provides the `Exec`
+//! authorizer asking an external command.
+
+use std::io;
+use std::mem;
+use std::thread;
+
+use std::fmt::Debug;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use utils::RuntimeError;
+
+use net::arrow::protocol::Service;
+
+use mio;
+
+/// Session request passed to a session authorizer.
+#[derive(Debug, Clone)]
+pub struct SessionRequest {
+    /// Service ID.
+    pub service_id: u16,
+    /// Session ID.
+    pub session_id: u32,
+    /// Requested service.
+    pub service:    Service,
+    /// Target address.
+    pub addr:       SocketAddr,
+}
+
+/// Session authorization decision.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SessionDecision {
+    /// Connect to the requested address.
+    Allow,
+    /// Connect to a given address instead.
+    Redirect(SocketAddr),
+    /// Refuse the session.
+    Deny,
+}
+
+/// Decision callback. It delivers the decision into the event loop of the
+/// connection which requested it.
+pub struct DecisionCallback {
+    session_id: u32,
+    sender:     mio::Sender<(u32, SessionDecision)>,
+}
+
+impl DecisionCallback {
+    /// Create a new callback for a given session ID.
+    pub fn new(
+        session_id: u32,
+        sender: mio::Sender<(u32, SessionDecision)>) -> DecisionCallback {
+        DecisionCallback {
+            session_id: session_id,
+            sender:     sender
+        }
+    }
+
+    /// Deliver a given decision. Decisions delivered after the connection
+    /// has been closed or after the decision timeout are ignored.
+    pub fn decide(self, decision: SessionDecision) {
+        self.sender.send((self.session_id, decision))
+            .ok();
+    }
+}
+
+/// Maximum amount of data buffered for a session waiting for a decision.
+const MAX_PENDING_DATA: usize = 65536;
+
+/// Session waiting for a decision.
+#[derive(Debug, Clone)]
+pub struct PendingSession {
+    /// Service ID.
+    pub service_id: u16,
+    /// Requested service.
+    pub service:    Service,
+    /// Target address.
+    pub addr:       SocketAddr,
+    /// Decision deadline (monotonic time in milliseconds).
+    pub deadline:   u64,
+    /// Buffered data (with their sequence numbers, if any).
+    data:           Vec<(Option<u32>, Vec<u8>)>,
+    /// Number of buffered bytes.
+    buffered:       usize,
+}
+
+impl PendingSession {
+    /// Create a new pending session with a given decision deadline.
+    pub fn new(
+        service_id: u16,
+        service: Service,
+        addr: SocketAddr,
+        deadline: u64) -> PendingSession {
+        PendingSession {
+            service_id: service_id,
+            service:    service,
+            addr:       addr,
+            deadline:   deadline,
+            data:       Vec::new(),
+            buffered:   0
+        }
+    }
+
+    /// Buffer given data. False is returned if the buffer limit has been
+    /// exceeded.
+    pub fn push(&mut self, seq: Option<u32>, data: Vec<u8>) -> bool {
+        self.buffered += data.len();
+        self.data.push((seq, data));
+        self.buffered <= MAX_PENDING_DATA
+    }
+
+    /// Take all buffered data.
+    pub fn take_data(&mut self) -> Vec<(Option<u32>, Vec<u8>)> {
+        self.buffered = 0;
+        mem::replace(&mut self.data, Vec::new())
+    }
+}
+
+/// Common trait for session authorizers.
+pub trait SessionAuthorizer : Debug + Send + Sync {
+    /// Decide about a given session request. The decision has to be passed
+    /// to a given callback within the service connect timeout, otherwise
+    /// the session is refused.
+    fn authorize(&self, request: SessionRequest, callback: DecisionCallback);
+}
+
+/// Authorizer executing a given command (using `/bin/sh -c`) for every
+/// session request. The request is passed in the `ARROW_SERVICE_ID`,
+/// `ARROW_SESSION_ID`, `ARROW_SERVICE_TYPE` and `ARROW_ADDRESS` environment
+/// variables. The session is denied if the command fails, otherwise it is
+/// redirected to the address printed on the first line of the command output
+/// (if any).
+#[derive(Debug, Clone)]
+pub struct Exec {
+    command: String,
+}
+
+impl Exec {
+    /// Create a new authorizer executing a given command.
+    pub fn new(command: &str) -> Exec {
+        Exec {
+            command: command.to_string()
+        }
+    }
+
+    /// Execute the command for a given request.
+    fn execute(command: &str, request: &SessionRequest) -> io::Result<SessionDecision> {
+        let mut child = try!(Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .env("ARROW_SERVICE_ID", format!("{}", request.service_id))
+            .env("ARROW_SESSION_ID", format!("{:08x}", request.session_id))
+            .env("ARROW_SERVICE_TYPE", format!("{:04x}", request.service.type_id()))
+            .env("ARROW_ADDRESS", format!("{}", request.addr))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn());
+
+        let mut output = String::new();
+
+        if let Some(ref mut stdout) = child.stdout {
+            try!(stdout.read_to_string(&mut output));
+        }
+
+        let status = try!(child.wait());
+
+        Ok(parse_output(status.success(), &output))
+    }
+}
+
+impl SessionAuthorizer for Exec {
+    fn authorize(&self, request: SessionRequest, callback: DecisionCallback) {
+        let command = self.command.clone();
+
+        thread::spawn(move || {
+            let decision = Exec::execute(&command, &request)
+                .unwrap_or(SessionDecision::Deny);
+
+            callback.decide(decision);
+        });
+    }
+}
+
+/// Get decision from a given exit status and output of an authorizer
+/// command.
+fn parse_output(success: bool, output: &str) -> SessionDecision {
+    if !success {
+        return SessionDecision::Deny;
+    }
+
+    let line = output.lines()
+        .next()
+        .unwrap_or("")
+        .trim();
+
+    if line.is_empty() {
+        SessionDecision::Allow
+    } else if let Ok(addr) = SocketAddr::from_str(line) {
+        SessionDecision::Redirect(addr)
+    } else {
+        SessionDecision::Deny
+    }
+}
+
+/// Create a session authorizer from a given specification ("exec:command").
+pub fn from_spec(spec: &str) -> Result<Arc<SessionAuthorizer>, RuntimeError> {
+    if spec.starts_with("exec:") && spec.len() > 5 {
+        Ok(Arc::new(Exec::new(&spec[5..])))
+    } else {
+        Err(RuntimeError::from("exec:command expected"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::parse_output;
+
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use net::arrow::protocol::Service;
+
+    #[test]
+    fn test_pending_session() {
+        let addr = SocketAddr::from_str("10.0.0.1:80").unwrap();
+
+        let mut pending = PendingSession::new(1, Service::AnyTCP, addr, 0);
+
+        assert!(pending.push(None, vec![1, 2, 3]));
+        assert!(pending.push(Some(1), vec![4]));
+        assert!(!pending.push(Some(2), vec![0; 65536]));
+
+        let data = pending.take_data();
+
+        assert_eq!(data.len(), 3);
+        assert_eq!(data[1], (Some(1), vec![4]));
+        assert!(pending.take_data().is_empty());
+    }
+
+    #[test]
+    fn test_output_parsing() {
+        let addr = SocketAddr::from_str("10.0.0.2:554").unwrap();
+
+        assert_eq!(parse_output(true, ""), SessionDecision::Allow);
+        assert_eq!(parse_output(true, "\n"), SessionDecision::Allow);
+        assert_eq!(parse_output(true, "10.0.0.2:554\nfoo\n"), SessionDecision::Redirect(addr));
+        assert_eq!(parse_output(true, "foo"), SessionDecision::Deny);
+        assert_eq!(parse_output(false, "10.0.0.2:554"), SessionDecision::Deny);
+
+        assert!(from_spec("exec:true").is_ok());
+        assert!(from_spec("exec:").is_err());
+        assert!(from_spec("foo").is_err());
+    }
+}
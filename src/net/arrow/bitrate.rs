@@ -0,0 +1,273 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-service bitrate cap.
+//!
+//! Data received from every service (summed over all its sessions) are
+//! measured in fixed windows. A service exceeds the cap if its bitrate is
+//! above the cap in a given number of consecutive windows, so that short
+//! bursts (e.g. key frames) are tolerated. The violation is reported only
+//! once until the bitrate drops below the cap again.
+//!
+//! Optionally, a vendor-specific RTSP SET_PARAMETER request asking for a
+//! lower bitrate can be sent to the offending RTSP service. The request is
+//! sent using a separate connection, so that the relayed session is not
+//! affected.
+
+use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver};
+
+use time;
+
+/// Length of the measurement window (in milliseconds).
+const MEASUREMENT_WINDOW: u64 = 5000;
+
+/// Number of consecutive windows above the cap needed to report the
+/// service.
+const EXCEEDED_WINDOWS: u32 = 6;
+
+/// Timeout of the bitrate hint request (in milliseconds).
+#[cfg(feature = "discovery")]
+const HINT_TIMEOUT: u64 = 10000;
+
+/// Bitrate measurement of a single service.
+#[derive(Debug, Clone)]
+struct ServiceBitrate {
+    /// Start of the current window (in milliseconds).
+    start:    u64,
+    /// Bytes received within the current window.
+    bytes:    u64,
+    /// Number of consecutive windows above the cap.
+    exceeded: u32,
+    /// The violation has been already reported.
+    reported: bool,
+}
+
+impl ServiceBitrate {
+    /// Create a new measurement starting at a given time.
+    fn new(start: u64) -> ServiceBitrate {
+        ServiceBitrate {
+            start:    start,
+            bytes:    0,
+            exceeded: 0,
+            reported: false
+        }
+    }
+}
+
+/// Bitrate monitor of all services.
+#[derive(Debug, Clone)]
+pub struct BitrateMonitor {
+    /// Bitrate cap (in kbit/s, None disables the monitor).
+    limit:    Option<u32>,
+    /// Measurements of individual services.
+    services: HashMap<u16, ServiceBitrate>,
+}
+
+impl BitrateMonitor {
+    /// Create a new monitor with a given bitrate cap (in kbit/s, None
+    /// disables the monitor).
+    pub fn new(limit: Option<u32>) -> BitrateMonitor {
+        BitrateMonitor {
+            limit:    limit,
+            services: HashMap::new()
+        }
+    }
+
+    /// Get the bitrate cap (in kbit/s).
+    pub fn limit(&self) -> Option<u32> {
+        self.limit
+    }
+
+    /// Record a given number of bytes received from a given service. The
+    /// measured bitrate (in kbit/s) is returned if the service has just been
+    /// found exceeding the cap.
+    pub fn record(&mut self, svc_id: u16, bytes: usize) -> Option<u32> {
+        self.record_at(svc_id, bytes, time::precise_time_ns() / 1000000)
+    }
+
+    /// Record a given number of bytes received from a given service at a
+    /// given time (in milliseconds).
+    fn record_at(&mut self, svc_id: u16, bytes: usize, now: u64) -> Option<u32> {
+        let limit = match self.limit {
+            Some(limit) => limit as u64,
+            None => return None
+        };
+
+        let state = self.services.entry(svc_id)
+            .or_insert(ServiceBitrate::new(now));
+
+        let elapsed = now.saturating_sub(state.start);
+
+        let mut res = None;
+
+        if elapsed >= MEASUREMENT_WINDOW {
+            // bytes * 8 / elapsed ms = kbit/s
+            let bitrate = state.bytes * 8 / elapsed;
+
+            if bitrate > limit {
+                state.exceeded += 1;
+            } else {
+                state.exceeded = 0;
+                state.reported = false;
+            }
+
+            if state.exceeded >= EXCEEDED_WINDOWS && !state.reported {
+                state.reported = true;
+
+                res = Some(bitrate as u32);
+            }
+
+            state.start = now;
+            state.bytes = 0;
+        }
+
+        state.bytes += bytes as u64;
+
+        res
+    }
+}
+
+/// Result of a bitrate hint (a message to be logged as info or warning).
+pub type HintResult = Result<String, String>;
+
+/// Sender of bitrate hints.
+///
+/// The hints are sent in background threads. Their results can be collected
+/// later using the `results()` method.
+pub struct HintSender {
+    tx: Sender<HintResult>,
+    rx: Receiver<HintResult>,
+}
+
+impl HintSender {
+    /// Create a new hint sender.
+    pub fn new() -> HintSender {
+        let (tx, rx) = mpsc::channel();
+
+        HintSender {
+            tx: tx,
+            rx: rx
+        }
+    }
+
+    /// Send a vendor-specific SET_PARAMETER request asking a given RTSP
+    /// service for a given maximum bitrate (in kbit/s). The parameter name
+    /// is given by the camera vendor.
+    #[cfg(feature = "discovery")]
+    pub fn send(&self, addr: SocketAddr, path: &str, param: &str, bitrate: u32) {
+        use std::thread;
+
+        use net::rtsp::Client as RtspClient;
+
+        let tx     = self.tx.clone();
+        let path   = path.to_string();
+        let params = format!("{}: {}\r\n", param, bitrate);
+
+        thread::spawn(move || {
+            let res = RtspClient::new(&format!("{}", addr.ip()), addr.port())
+                .and_then(|mut client| {
+                    try!(client.set_timeout(Some(HINT_TIMEOUT)));
+                    client.set_parameter(&path, &params)
+                });
+
+            let res = match res {
+                Ok(ref response) if response.header.code == 200 =>
+                    Ok(format!("bitrate hint accepted by {}{}", addr, path)),
+                Ok(response) =>
+                    Err(format!("bitrate hint rejected by {}{}: {} {}", addr, path, response.header.code, response.header.line)),
+                Err(err) =>
+                    Err(format!("unable to send bitrate hint to {}{}: {}", addr, path, err))
+            };
+
+            tx.send(res)
+                .ok();
+        });
+    }
+
+    /// Send a vendor-specific SET_PARAMETER request asking a given RTSP
+    /// service for a given maximum bitrate (dummy).
+    #[cfg(not(feature = "discovery"))]
+    pub fn send(&self, addr: SocketAddr, path: &str, _: &str, _: u32) {
+        self.tx.send(Err(format!("unable to send bitrate hint to {}{}: RTSP support is not compiled in", addr, path)))
+            .ok();
+    }
+
+    /// Take results of all finished hints.
+    pub fn results(&self) -> Vec<HintResult> {
+        let mut res = Vec::new();
+
+        while let Ok(result) = self.rx.try_recv() {
+            res.push(result);
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitrate_monitor() {
+        // 1000 kbit/s = 125 bytes per millisecond
+        let mut monitor = BitrateMonitor::new(Some(1000));
+
+        let mut now = 0;
+
+        assert_eq!(monitor.record_at(1, 0, now), None);
+
+        // 2000 kbit/s in the first windows
+        for _ in 0..EXCEEDED_WINDOWS - 1 {
+            monitor.record_at(1, 250 * MEASUREMENT_WINDOW as usize, now);
+
+            now += MEASUREMENT_WINDOW;
+
+            assert_eq!(monitor.record_at(1, 0, now), None);
+        }
+
+        monitor.record_at(1, 250 * MEASUREMENT_WINDOW as usize, now);
+
+        now += MEASUREMENT_WINDOW;
+
+        assert_eq!(monitor.record_at(1, 0, now), Some(2000));
+
+        // reported only once
+        monitor.record_at(1, 250 * MEASUREMENT_WINDOW as usize, now);
+
+        now += MEASUREMENT_WINDOW;
+
+        assert_eq!(monitor.record_at(1, 0, now), None);
+
+        // other services are not affected
+        assert_eq!(monitor.record_at(2, 1000000, now), None);
+
+        // a window below the cap resets the counter
+        monitor.record_at(1, 100 * MEASUREMENT_WINDOW as usize, now);
+
+        now += MEASUREMENT_WINDOW;
+
+        assert_eq!(monitor.record_at(1, 0, now), None);
+        assert_eq!(monitor.services[&1].exceeded, 0);
+        assert!(!monitor.services[&1].reported);
+
+        let mut monitor = BitrateMonitor::new(None);
+
+        assert_eq!(monitor.record_at(1, 1000000, 0), None);
+        assert_eq!(monitor.record_at(1, 1000000, 100000), None);
+    }
+}
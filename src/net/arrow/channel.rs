@@ -0,0 +1,882 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arrow Control Protocol channel definitions.
+
+use std::cmp;
+
+use std::collections::VecDeque;
+
+use utils::Serialize;
+
+use net::utils::Timeout;
+use net::raw::ether::MacAddr;
+
+use utils::config::ArrowConfig;
+use utils::secret::Secret;
+
+use utils::config::ACK_TIMEOUT;
+use net::arrow::auth::Credential;
+use net::arrow::version::{self, VersionChange};
+use net::arrow::protocol::*;
+use net::arrow::error::{Result, ArrowError};
+
+use time;
+
+/// Number of message IDs tracked by the replay window.
+const REPLAY_WINDOW_SIZE: u16 = 64;
+
+/// Maximum serialized size of a service table sent within a single message
+/// (bigger tables are split into UPDATE_PAGE messages if the server supports
+/// them).
+pub const MAX_TABLE_PAGE_SIZE: usize = 32768;
+
+/// Cache of the serialized REGISTER message body.
+///
+/// The body contains the whole service table, so it is serialized only when
+/// the configuration changes. This way the REGISTER message can be sent
+/// right after reconnecting, without waiting for the service table lock and
+/// serialization of a potentially large table.
+#[derive(Debug, Clone)]
+pub struct RegisterCache {
+    /// Config version, client UUID, MAC address, password, the paging flag
+    /// and the legacy format flag of the cached body (the password is
+    /// compared in constant time).
+    key:  Option<(usize, [u8; 16], [u8; 6], Secret<[u8; 16]>, bool, bool)>,
+    /// Serialized REGISTER message body (it contains the password).
+    body: Secret<Vec<u8>>,
+}
+
+impl RegisterCache {
+    /// Create a new empty cache.
+    pub fn new() -> RegisterCache {
+        RegisterCache {
+            key:  None,
+            body: Secret::new(Vec::new())
+        }
+    }
+
+    /// Check if the cache contains the REGISTER body for a given
+    /// configuration, MAC address, password, paging flag and legacy format
+    /// flag.
+    pub fn is_valid(
+        &self,
+        config: &ArrowConfig,
+        arrow_mac: &MacAddr,
+        passwd: &Secret<[u8; 16]>,
+        paged: bool,
+        legacy: bool) -> bool {
+        self.key == Some(register_key(config, arrow_mac, passwd, paged, legacy))
+    }
+
+    /// Get the REGISTER body for a given configuration, MAC address and
+    /// password. The body is serialized again only if the cached one is
+    /// outdated. If the paging flag is set and the service table is too big,
+    /// the body will contain an empty service table (the table is expected
+    /// to be sent using UPDATE_PAGE messages). If the legacy format flag is
+    /// set, the body will not contain the client protocol info.
+    pub fn get(
+        &mut self,
+        config: &ArrowConfig,
+        arrow_mac: &MacAddr,
+        passwd: &Secret<[u8; 16]>,
+        paged: bool,
+        legacy: bool) -> Vec<u8> {
+        if !self.is_valid(config, arrow_mac, passwd, paged, legacy) {
+            let svc_table = if is_oversized(config.service_table(), paged) {
+                ServiceTable::new()
+            } else {
+                config.service_table()
+                    .clone()
+            };
+            let msg = if legacy {
+                RegisterMessage::legacy(
+                    config.uuid(),
+                    arrow_mac.octets(),
+                    *passwd.expose(),
+                    svc_table)
+            } else {
+                RegisterMessage::new(
+                    config.uuid(),
+                    arrow_mac.octets(),
+                    *passwd.expose(),
+                    svc_table)
+            };
+
+            let mut body = Vec::with_capacity(msg.len());
+
+            msg.serialize(&mut body)
+                .unwrap();
+
+            self.key  = Some(register_key(config, arrow_mac, passwd, paged,
+                legacy));
+            self.body = Secret::new(body);
+        }
+
+        self.body.expose()
+            .clone()
+    }
+}
+
+/// Get REGISTER cache key for a given configuration, MAC address, password,
+/// paging flag and legacy format flag.
+fn register_key(
+    config: &ArrowConfig,
+    arrow_mac: &MacAddr,
+    passwd: &Secret<[u8; 16]>,
+    paged: bool,
+    legacy: bool) -> (usize, [u8; 16], [u8; 6], Secret<[u8; 16]>, bool, bool) {
+    (config.version(), config.uuid(), arrow_mac.octets(), passwd.clone(),
+        paged, legacy)
+}
+
+/// Check if a given service table should be paged.
+fn is_oversized(svc_table: &ServiceTable, paged: bool) -> bool {
+    paged && svc_table.len() > MAX_TABLE_PAGE_SIZE
+}
+
+/// Arrow Protocol states.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ProtocolState {
+    Handshake,
+    Established
+}
+
+/// Sliding window of Control Protocol message IDs received from Arrow
+/// Service.
+///
+/// Message IDs are expected to be assigned sequentially (with wrap-around).
+/// IDs which have been already seen or which are too old to be tracked are
+/// considered to be replayed.
+struct ReplayWindow {
+    /// The highest message ID received so far.
+    highest: Option<u16>,
+    /// Bitmap of received IDs (bit N corresponds to the ID highest - N).
+    bitmap:  u64,
+}
+
+impl ReplayWindow {
+    /// Create a new empty window.
+    fn new() -> ReplayWindow {
+        ReplayWindow {
+            highest: None,
+            bitmap:  0
+        }
+    }
+
+    /// Record a given message ID. The method returns false if the ID has
+    /// been already seen or if it is too old.
+    fn accept(&mut self, msg_id: u16) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(msg_id);
+                self.bitmap  = 1;
+                return true;
+            }
+        };
+
+        let diff = msg_id.wrapping_sub(highest) as i16;
+
+        if diff > 0 {
+            let shift = diff as u16;
+
+            self.bitmap = if shift < REPLAY_WINDOW_SIZE {
+                (self.bitmap << shift) | 1
+            } else {
+                1
+            };
+
+            self.highest = Some(msg_id);
+
+            true
+        } else {
+            let offset = (-(diff as i32)) as u16;
+
+            if offset >= REPLAY_WINDOW_SIZE {
+                false
+            } else if (self.bitmap & (1 << offset)) != 0 {
+                false
+            } else {
+                self.bitmap |= 1 << offset;
+                true
+            }
+        }
+    }
+}
+
+/// Control Protocol channel.
+///
+/// The channel keeps the protocol state, assigns message IDs to outgoing
+/// Control Protocol messages and keeps track of expected ACKs. It does not do
+/// any I/O.
+pub struct ControlChannel {
+    /// Protocol state.
+    state:         ProtocolState,
+    /// Current Control Message ID.
+    msg_id:        u16,
+    /// Expected ACKs (message ID, send time and number of bytes queued in
+    /// the uplink before the message).
+    expected_acks: VecDeque<(u16, f64, usize)>,
+    /// ACK timeout.
+    ack_tout:      Timeout,
+    /// ACK time limit (in milliseconds).
+    ack_timeout:   u64,
+    /// Version of the last sent service table.
+    last_update:   Option<usize>,
+    /// Negotiated protocol version and features.
+    protocol:      ProtocolInfo,
+    /// IDs of messages received from Arrow Service.
+    received:      ReplayWindow,
+    /// An authentication challenge has been received.
+    challenged:    bool,
+    /// Send REGISTER messages without the client protocol info.
+    legacy_register: bool,
+}
+
+impl ControlChannel {
+    /// Create a new Control Protocol channel in the Handshake state. Message
+    /// IDs of outgoing messages start at a given value (and wrap around
+    /// after 0xffff).
+    pub fn starting_at(msg_id: u16) -> ControlChannel {
+        ControlChannel {
+            state:         ProtocolState::Handshake,
+            msg_id:        msg_id,
+            expected_acks: VecDeque::new(),
+            ack_tout:      Timeout::new(),
+            ack_timeout:   ACK_TIMEOUT,
+            last_update:   None,
+            protocol:      ProtocolInfo::legacy(),
+            received:      ReplayWindow::new(),
+            challenged:    false,
+            legacy_register: false
+        }
+    }
+
+    /// Set time limit for receiving ACKs (in milliseconds).
+    pub fn set_ack_timeout(&mut self, timeout: u64) {
+        self.ack_timeout = timeout;
+    }
+
+    /// Send REGISTER messages without the client protocol info (i.e. in the
+    /// format understood by legacy servers).
+    pub fn set_legacy_register(&mut self, legacy: bool) {
+        self.legacy_register = legacy;
+    }
+
+    /// Check if the protocol is in the Established state.
+    pub fn is_established(&self) -> bool {
+        self.state == ProtocolState::Established
+    }
+
+    /// Get the negotiated protocol version and features (the result is valid
+    /// only in the Established state).
+    pub fn protocol(&self) -> ProtocolInfo {
+        self.protocol
+    }
+
+    /// Check if a given optional feature has been negotiated.
+    pub fn supports(&self, feature: u32) -> bool {
+        self.protocol.supports(feature)
+    }
+
+    /// Check if the ACK timeout has not expired yet.
+    pub fn check_timeout(&self) -> bool {
+        self.ack_tout.check()
+    }
+
+    /// Get ID of the next outgoing Control Message (without consuming it).
+    pub fn peek_msg_id(&self) -> u16 {
+        self.msg_id
+    }
+
+    /// Get the next Control Message ID.
+    fn next_msg_id(&mut self) -> u16 {
+        let res = self.msg_id;
+        self.msg_id = self.msg_id.wrapping_add(1);
+        res
+    }
+
+    /// Record ID of a message received from Arrow Service (except ACKs which
+    /// are validated against the expected ACKs). The method returns false if
+    /// the message is a duplicate or a replay of an older message. All
+    /// messages are accepted unless Arrow Service has announced that it
+    /// assigns message IDs sequentially.
+    pub fn accept_msg_id(&mut self, msg_id: u16) -> bool {
+        if self.supports(FEATURE_SEQUENTIAL_MSG_IDS) {
+            self.received.accept(msg_id)
+        } else {
+            true
+        }
+    }
+
+    /// Mark a given message as one which needs to be confirmed by ACK. The
+    /// number of bytes queued in the uplink before the message is expected.
+    pub fn expect_ack<B: ControlMessageBody>(
+        &mut self,
+        control_msg: &ControlMessage<B>,
+        queued: usize) {
+        if self.expected_acks.is_empty() {
+            self.ack_tout.set(self.ack_timeout);
+        }
+
+        self.expected_acks.push_back((
+            control_msg.header().msg_id,
+            time::precise_time_s(),
+            queued));
+    }
+
+    /// Process an ACK with a given message ID. The method returns the ACK
+    /// latency (in milliseconds) and the number of bytes which were queued
+    /// in the uplink before the message or an error if the ACK was not
+    /// expected.
+    pub fn process_ack(&mut self, msg_id: u16) -> Result<(u64, usize)> {
+        let expected_ack = self.expected_acks.pop_front();
+
+        if self.expected_acks.is_empty() {
+            self.ack_tout.clear();
+        } else {
+            self.ack_tout.set(self.ack_timeout);
+        }
+
+        match expected_ack {
+            Some((id, sent, queued)) if id == msg_id => {
+                let latency = (time::precise_time_s() - sent) * 1000.0;
+
+                Ok((latency as u64, queued))
+            },
+            Some(_) => Err(ArrowError::other("unexpected ACK message ID")),
+            None    => Err(ArrowError::other("no ACK message expected"))
+        }
+    }
+
+    /// Process a given REGISTER response and switch the protocol into the
+    /// Established state on success. If Arrow Service responds with an
+    /// authentication challenge, the challenge is returned and the protocol
+    /// stays in the Handshake state (a new REGISTER message is expected to
+    /// be sent). Only one challenge is accepted per connection.
+    pub fn process_handshake_ack(
+        &mut self,
+        msg: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.state != ProtocolState::Handshake {
+            panic!("unexpected protocol state");
+        }
+
+        let ack = try_arr!(control::parse_ack_message(
+            &msg[..cmp::min(msg.len(), 4)]));
+
+        if ack == ACK_AUTH_CHALLENGE {
+            if self.challenged {
+                return Err(ArrowError::unauthorized("Arrow REGISTER failed (repeated authentication challenge)")
+                    .with_ack_code(ack));
+            }
+
+            self.challenged = true;
+
+            return control::parse_auth_challenge(msg)
+                .map(|challenge| Some(challenge));
+        }
+
+        let (ack, server) = try_arr!(control::parse_register_ack(msg));
+        if ack == ACK_NO_ERROR {
+            self.state    = ProtocolState::Established;
+            self.protocol = ProtocolInfo::client()
+                .negotiate(&server);
+
+            // metadata cannot be sent within REGISTER (the server features
+            // are not known at that time), so force an UPDATE
+            if self.protocol.supports(FEATURE_METADATA) {
+                self.last_update = None;
+            }
+
+            Ok(None)
+        } else {
+            let err = if ack == ACK_UNAUTHORIZED {
+                ArrowError::unauthorized("Arrow REGISTER failed (unauthorized)")
+            } else if ack == ACK_UNSUPPORTED_PROTOCOL_VERSION {
+                ArrowError::unsupported_protocol_version("Arrow REGISTER failed (unsupported version of the Arrow Protocol)")
+            } else if ack == ACK_INTERNAL_SERVER_ERROR {
+                ArrowError::arrow_server_error("Arrow REGISTER failed (internal server error)")
+            } else {
+                ArrowError::other(format!("Arrow REGISTER failed (unknown error {:08x})", ack))
+            };
+
+            Err(err.with_ack_code(ack))
+        }
+    }
+
+    /// Check if a given service table version needs to be sent to the
+    /// Arrow Service.
+    pub fn update_needed(&self, version: usize) -> bool {
+        self.version_change(version) != VersionChange::Unchanged
+    }
+
+    /// Compare a given service table version with the last sent one (the
+    /// comparison is wrap-around safe). A version is considered newer if no
+    /// table has been sent yet.
+    pub fn version_change(&self, version: usize) -> VersionChange {
+        match self.last_update {
+            Some(sent_version) => version::compare(version, sent_version),
+            None => VersionChange::Newer
+        }
+    }
+
+    /// Create a new REGISTER message from a given configuration and
+    /// credential. The message body is taken from a given cache if possible
+    /// (the authentication extension is appended to it).
+    ///
+    /// Server features are not known before the handshake. The paging flag
+    /// tells if the server supported paged service tables last time. In
+    /// such case, an oversized service table is not sent within the
+    /// REGISTER message and an UPDATE is forced instead.
+    pub fn register_message(
+        &mut self,
+        config: &ArrowConfig,
+        arrow_mac: &MacAddr,
+        credential: &Credential,
+        cache: &mut RegisterCache,
+        paged: bool) -> ControlMessage<Vec<u8>> {
+        let mut body = cache.get(config, arrow_mac, &credential.passwd, paged,
+            self.legacy_register);
+
+        credential.serialize(&mut body)
+            .unwrap();
+
+        if is_oversized(config.service_table(), paged) {
+            self.last_update = None;
+        } else {
+            self.last_update = Some(config.version());
+        }
+
+        let msg_id = self.next_msg_id();
+
+        control::create_encoded_register_message(msg_id, body)
+    }
+
+    /// Create a new UPDATE message for a given service table version. The
+    /// metadata are sent only if they are supported by the server.
+    pub fn update_message(
+        &mut self,
+        version: usize,
+        svc_table: ServiceTable,
+        metadata: Metadata) -> ControlMessage<UpdateMessage> {
+        let metadata = if self.supports(FEATURE_METADATA) {
+            metadata
+        } else {
+            Metadata::new()
+        };
+
+        self.last_update = Some(version);
+
+        let msg_id = self.next_msg_id();
+        let msg    = UpdateMessage::new(svc_table, metadata);
+
+        control::create_update_message(msg_id, msg)
+    }
+
+    /// Check if a given service table needs to be split into UPDATE_PAGE
+    /// messages.
+    pub fn paging_needed(&self, svc_table: &ServiceTable) -> bool {
+        is_oversized(svc_table, self.supports(FEATURE_TABLE_PAGES))
+    }
+
+    /// Create a sequence of UPDATE_PAGE messages for a given service table
+    /// version. The metadata are sent within the last page.
+    pub fn update_page_messages(
+        &mut self,
+        version: usize,
+        svc_table: ServiceTable,
+        metadata: Metadata) -> Vec<ControlMessage<UpdatePageMessage>> {
+        let pages = svc_table.pages(MAX_TABLE_PAGE_SIZE);
+        let count = pages.len() as u16;
+
+        self.last_update = Some(version);
+
+        let mut res = Vec::with_capacity(pages.len());
+
+        for (index, page) in pages.into_iter().enumerate() {
+            let msg_id = self.next_msg_id();
+            let msg    = UpdatePageMessage::new(
+                index as u16, count, page, metadata.clone());
+
+            res.push(control::create_update_page_message(msg_id, msg));
+        }
+
+        res
+    }
+
+    /// Create a new PING message.
+    pub fn ping_message(&mut self) -> ControlMessage<EmptyBody> {
+        let msg_id = self.next_msg_id();
+
+        control::create_ping_message(msg_id)
+    }
+
+    /// Replace a given extended HUP error code (e.g.
+    /// HUP_SERVICE_UNREACHABLE) with HUP_CONNECTION_ERROR if extended error
+    /// codes are not supported by the server.
+    fn hup_error_code(&self, error_code: u32) -> u32 {
+        if error_code > HUP_CONNECTION_ERROR
+            && !self.supports(FEATURE_HUP_CODES) {
+            HUP_CONNECTION_ERROR
+        } else {
+            error_code
+        }
+    }
+
+    /// Create a new HUP message. Extended error codes are replaced with
+    /// HUP_CONNECTION_ERROR if they are not supported by the server.
+    pub fn hup_message(
+        &mut self,
+        session_id: u32,
+        error_code: u32) -> ControlMessage<HupMessage> {
+        let error_code = self.hup_error_code(error_code);
+
+        let msg_id = self.next_msg_id();
+
+        control::create_hup_message(msg_id, session_id, error_code)
+    }
+
+    /// Create a new STATUS message.
+    pub fn status_message(
+        &mut self,
+        status_msg: StatusMessage) -> ControlMessage<StatusMessage> {
+        let msg_id = self.next_msg_id();
+
+        control::create_status_message(msg_id, status_msg)
+    }
+
+    /// Create a new SCAN_REPORT message.
+    pub fn scan_report_message(
+        &mut self,
+        scan_report: ScanReportMessage) -> ControlMessage<ScanReportMessage> {
+        let msg_id = self.next_msg_id();
+
+        control::create_scan_report_message(msg_id, scan_report)
+    }
+
+    /// Create a new SVC_STATS message.
+    pub fn svc_stats_message(
+        &mut self,
+        svc_stats: SvcStatsMessage) -> ControlMessage<SvcStatsMessage> {
+        let msg_id = self.next_msg_id();
+
+        control::create_svc_stats_message(msg_id, svc_stats)
+    }
+
+    /// Create a new EVENT message.
+    #[cfg(feature = "discovery")]
+    pub fn event_message(
+        &mut self,
+        event: NetworkEvent) -> ControlMessage<NetworkEvent> {
+        let msg_id = self.next_msg_id();
+
+        control::create_event_message(msg_id, event)
+    }
+
+    /// Create a new PROTOCOL_VIOLATION message.
+    pub fn protocol_violation_message(
+        &mut self,
+        violation: ProtocolViolation) -> ControlMessage<ProtocolViolation> {
+        let msg_id = self.next_msg_id();
+
+        control::create_protocol_violation_message(msg_id, violation)
+    }
+
+    /// Create a new HUP_REPORT message. Extended error codes are replaced
+    /// with HUP_CONNECTION_ERROR if they are not supported by the server.
+    pub fn hup_report_message(
+        &mut self,
+        mut report: HupReport) -> ControlMessage<HupReport> {
+        report.last_error = self.hup_error_code(report.last_error);
+
+        let msg_id = self.next_msg_id();
+
+        control::create_hup_report_message(msg_id, report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use utils::config::ArrowConfig;
+    use utils::secret::Secret;
+    use net::utils::WriteBuffer;
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::control;
+    use net::arrow::auth::{Credential, AUTH_METHOD_TOKEN};
+
+    #[test]
+    fn test_message_ids() {
+        let mut channel = ControlChannel::starting_at(0);
+
+        assert_eq!(channel.ping_message().header().msg_id, 0);
+        assert_eq!(channel.hup_message(1, 0).header().msg_id, 1);
+        assert_eq!(channel.ping_message().header().msg_id, 2);
+    }
+
+    #[test]
+    fn test_message_id_wrapping() {
+        let mut channel = ControlChannel::starting_at(0xfffe);
+
+        let first  = channel.ping_message();
+        let second = channel.ping_message();
+        let third  = channel.ping_message();
+
+        assert_eq!(first.header().msg_id, 0xfffe);
+        assert_eq!(second.header().msg_id, 0xffff);
+        assert_eq!(third.header().msg_id, 0);
+        assert_eq!(channel.peek_msg_id(), 1);
+
+        channel.expect_ack(&first, 0);
+        channel.expect_ack(&second, 0);
+        channel.expect_ack(&third, 0);
+
+        // ACKs are matched across the wrap-around
+        assert!(channel.process_ack(0xfffe).is_ok());
+        assert!(channel.process_ack(0xffff).is_ok());
+        assert!(channel.process_ack(0).is_ok());
+        assert!(channel.process_ack(0).is_err());
+    }
+
+    #[test]
+    fn test_register_cache() {
+        let mut config  = ArrowConfig::new();
+        let mut cache   = RegisterCache::new();
+        let mut channel = ControlChannel::starting_at(0);
+
+        let mac    = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let passwd = config.password();
+        let cred   = Credential::password(passwd.clone());
+
+        assert!(!cache.is_valid(&config, &mac, &passwd, false, false));
+
+        let msg = channel.register_message(&config, &mac, &cred, &mut cache,
+            false);
+
+        assert!(cache.is_valid(&config, &mac, &passwd, false, false));
+        assert!(!cache.is_valid(&config, &mac, &passwd, true, false));
+        assert!(!cache.is_valid(&config, &mac, &passwd, false, true));
+        assert!(!cache.is_valid(&config, &mac, &Secret::new([0; 16]), false, false));
+        assert!(!cache.is_valid(&config, &MacAddr::new(0, 0, 0, 0, 0, 0), &passwd, false, false));
+
+        config.bump_version();
+
+        assert!(!cache.is_valid(&config, &mac, &passwd, false, false));
+
+        let mut expected = Vec::new();
+        let mut actual   = Vec::new();
+
+        control::create_register_message(1, RegisterMessage::new(
+                config.uuid(),
+                mac.octets(),
+                *config.password().expose(),
+                config.service_table().clone()))
+            .serialize(&mut expected)
+            .unwrap();
+
+        channel.register_message(&config, &mac, &cred, &mut cache, false)
+            .serialize(&mut actual)
+            .unwrap();
+
+        assert_eq!(msg.header().msg_id, 0);
+        assert_eq!(actual, expected);
+
+        // the authentication extension is appended to the cached body
+        let cred = Credential::extension(AUTH_METHOD_TOKEN, vec![0xab]);
+
+        actual.clear();
+
+        channel.register_message(&config, &mac, &cred, &mut cache, false)
+            .serialize(&mut actual)
+            .unwrap();
+
+        assert_eq!(&actual[actual.len() - 4..], &[0x02, 0x00, 0x01, 0xab]);
+
+        // the protocol info is omitted in the legacy format
+        let cred = Credential::password(passwd.clone());
+
+        let mut legacy = Vec::new();
+
+        channel.set_legacy_register(true);
+        channel.register_message(&config, &mac, &cred, &mut cache, false)
+            .serialize(&mut legacy)
+            .unwrap();
+
+        assert!(cache.is_valid(&config, &mac, &passwd, false, true));
+        assert_eq!(legacy.len(), expected.len() - 6);
+        assert_eq!(&legacy[4..], &expected[4..expected.len() - 6]);
+    }
+
+    #[test]
+    fn test_ack_tracking() {
+        let mut channel = ControlChannel::starting_at(0);
+
+        let ping1 = channel.ping_message();
+        let ping2 = channel.ping_message();
+
+        channel.expect_ack(&ping1, 0);
+        channel.expect_ack(&ping2, 100);
+
+        assert!(channel.process_ack(0).is_ok());
+        assert!(channel.process_ack(2).is_err());
+        assert!(channel.process_ack(0).is_err());
+    }
+
+    #[test]
+    fn test_replay_detection() {
+        let mut channel = ControlChannel::starting_at(0);
+
+        // the window is not used unless negotiated
+        assert!(channel.accept_msg_id(10));
+        assert!(channel.accept_msg_id(10));
+
+        let mut channel = ControlChannel::starting_at(0);
+
+        channel.protocol = ProtocolInfo::new(2, FEATURE_SEQUENTIAL_MSG_IDS);
+
+        assert!(channel.accept_msg_id(10));
+        assert!(channel.accept_msg_id(12));
+        assert!(!channel.accept_msg_id(12));
+        assert!(channel.accept_msg_id(11));
+        assert!(!channel.accept_msg_id(10));
+
+        // too old
+        assert!(channel.accept_msg_id(100));
+        assert!(!channel.accept_msg_id(20));
+
+        // wrap-around
+        let mut channel = ControlChannel::starting_at(0);
+
+        channel.protocol = ProtocolInfo::new(2, FEATURE_SEQUENTIAL_MSG_IDS);
+
+        assert!(channel.accept_msg_id(0xffff));
+        assert!(channel.accept_msg_id(0));
+        assert!(!channel.accept_msg_id(0xffff));
+        assert!(!channel.accept_msg_id(0));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut channel = ControlChannel::starting_at(0);
+        let mut buffer  = WriteBuffer::new(0);
+
+        assert!(!channel.is_established());
+
+        control::create_ack_message(0, ACK_UNAUTHORIZED)
+            .serialize(&mut buffer)
+            .unwrap();
+
+        assert!(channel.process_handshake_ack(&buffer.as_bytes()[4..])
+            .is_err());
+        assert!(!channel.is_established());
+
+        buffer.clear();
+
+        control::create_ack_message(0, ACK_NO_ERROR)
+            .serialize(&mut buffer)
+            .unwrap();
+
+        assert!(channel.process_handshake_ack(&buffer.as_bytes()[4..])
+            .is_ok());
+        assert!(channel.is_established());
+        assert_eq!(channel.protocol(), ProtocolInfo::legacy());
+    }
+
+    #[test]
+    fn test_auth_challenge() {
+        let mut channel = ControlChannel::starting_at(0);
+
+        let challenge = channel.process_handshake_ack(&[0, 0, 0, 5, 1, 2, 3])
+            .unwrap();
+
+        assert_eq!(challenge, Some(vec![1, 2, 3]));
+        assert!(!channel.is_established());
+
+        // only one challenge is accepted
+        assert!(channel.process_handshake_ack(&[0, 0, 0, 5, 1, 2, 3])
+            .is_err());
+
+        let mut channel = ControlChannel::starting_at(0);
+
+        assert!(channel.process_handshake_ack(&[0, 0, 0, 5]).is_err());
+        assert!(channel.process_handshake_ack(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_feature_downgrade() {
+        let mut legacy = ControlChannel::starting_at(0);
+        let mut buffer = WriteBuffer::new(0);
+
+        legacy.process_handshake_ack(&[0, 0, 0, 0])
+            .unwrap();
+
+        legacy.hup_message(1, HUP_SERVICE_UNREACHABLE)
+            .serialize(&mut buffer)
+            .unwrap();
+
+        assert_eq!(&buffer.as_bytes()[8..], &[0, 0, 0, 2]);
+
+        buffer.clear();
+
+        legacy.hup_report_message(HupReport::new(1, 5, 4, 60, HUP_SERVICE_UNREACHABLE))
+            .serialize(&mut buffer)
+            .unwrap();
+
+        assert!(buffer.as_bytes().ends_with(&[0, 0, 0, 2]));
+
+        let mut channel = ControlChannel::starting_at(0);
+
+        channel.update_message(1, ServiceTable::new(), Metadata::new());
+        channel.process_handshake_ack(&[0, 0, 0, 0, 0, 2, 0, 0, 0, 0x06])
+            .unwrap();
+
+        assert!(channel.supports(FEATURE_METADATA));
+        assert!(!channel.supports(FEATURE_SCAN_SUMMARY));
+
+        // the metadata need to be sent within an UPDATE
+        assert!(channel.update_needed(1));
+
+        buffer.clear();
+
+        channel.hup_message(1, HUP_SERVICE_UNREACHABLE)
+            .serialize(&mut buffer)
+            .unwrap();
+
+        assert_eq!(&buffer.as_bytes()[8..], &[0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn test_update_needed() {
+        let mut channel = ControlChannel::starting_at(0);
+
+        assert!(channel.update_needed(0));
+
+        channel.update_message(1, ServiceTable::new(), Metadata::new());
+
+        assert!(!channel.update_needed(1));
+        assert!(channel.update_needed(2));
+
+        // a regressed version has to be sent as well
+        assert!(channel.update_needed(0));
+        assert_eq!(channel.version_change(0), VersionChange::Regressed);
+
+        channel.update_message(usize::max_value(), ServiceTable::new(),
+            Metadata::new());
+
+        assert_eq!(channel.version_change(0), VersionChange::Newer);
+    }
+}
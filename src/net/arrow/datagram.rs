@@ -0,0 +1,250 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Datagram (UDP) sessions.
+//!
+//! Data frames of the Arrow Protocol do not preserve message boundaries, so
+//! the payload of a datagram session is a sequence of datagrams, each of them
+//! prefixed with its length (16-bit, big-endian). Datagrams received from the
+//! service are encoded this way and the payload received from Arrow Service
+//! is split back into datagrams before sending them to the service.
+//! Datagrams from other sources than the service address are dropped.
+
+use std::io;
+use std::cmp;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+use mio::udp::UdpSocket;
+
+/// Size of the datagram length prefix.
+const PREFIX_SIZE: usize = 2;
+
+/// Maximum size of a UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Decoder of length-prefixed datagrams.
+#[derive(Debug, Clone)]
+pub struct DatagramDecoder {
+    /// Current datagram (including the length prefix).
+    partial: Vec<u8>,
+}
+
+impl DatagramDecoder {
+    /// Create a new decoder.
+    pub fn new() -> DatagramDecoder {
+        DatagramDecoder {
+            partial: Vec::new()
+        }
+    }
+
+    /// Consume bytes of the current datagram from given data and return the
+    /// number of consumed bytes. No bytes are consumed if the current
+    /// datagram is already complete.
+    pub fn feed(&mut self, data: &[u8]) -> usize {
+        let mut consumed = 0;
+
+        if self.partial.len() < PREFIX_SIZE {
+            consumed = cmp::min(PREFIX_SIZE - self.partial.len(), data.len());
+
+            self.partial.extend_from_slice(&data[..consumed]);
+
+            if self.partial.len() < PREFIX_SIZE {
+                return consumed;
+            }
+        }
+
+        let missing = self.expected() - self.partial.len();
+        let take    = cmp::min(missing, data.len() - consumed);
+
+        self.partial.extend_from_slice(&data[consumed..consumed + take]);
+
+        consumed + take
+    }
+
+    /// Get the current datagram if it is complete.
+    pub fn datagram(&self) -> Option<&[u8]> {
+        if self.partial.len() >= PREFIX_SIZE
+            && self.partial.len() == self.expected() {
+            Some(&self.partial[PREFIX_SIZE..])
+        } else {
+            None
+        }
+    }
+
+    /// Drop the current datagram.
+    pub fn clear(&mut self) {
+        self.partial.clear();
+    }
+
+    /// Get the expected size of the current datagram including its length
+    /// prefix (the prefix must be complete).
+    fn expected(&self) -> usize {
+        let len = ((self.partial[0] as usize) << 8) | (self.partial[1] as usize);
+
+        len + PREFIX_SIZE
+    }
+}
+
+/// Encode a given datagram into a given buffer. The number of bytes written
+/// is returned, the datagram is dropped (zero is returned) if it does not
+/// fit into the buffer (i.e. datagrams bigger than the session read buffer
+/// are not relayed).
+pub fn encode(datagram: &[u8], buf: &mut [u8]) -> usize {
+    let len = datagram.len();
+
+    if (len + PREFIX_SIZE) > buf.len() || len > MAX_DATAGRAM_SIZE {
+        return 0;
+    }
+
+    buf[0] = (len >> 8) as u8;
+    buf[1] = len as u8;
+
+    buf[PREFIX_SIZE..PREFIX_SIZE + len].copy_from_slice(datagram);
+
+    len + PREFIX_SIZE
+}
+
+/// UDP socket exchanging datagrams with a single service address. The
+/// socket can be used as a byte stream of length-prefixed datagrams.
+pub struct DatagramSocket {
+    /// UDP socket.
+    socket:  UdpSocket,
+    /// Service address.
+    addr:    SocketAddr,
+    /// Datagram decoder.
+    decoder: DatagramDecoder,
+    /// Receive buffer.
+    buffer:  Box<[u8]>,
+}
+
+impl DatagramSocket {
+    /// Create a new UDP socket for a given service address.
+    pub fn connect(addr: &SocketAddr) -> io::Result<DatagramSocket> {
+        let local = match addr {
+            &SocketAddr::V4(_) => SocketAddr::V4(
+                SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            &SocketAddr::V6(_) => SocketAddr::V6(
+                SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0))
+        };
+
+        let res = DatagramSocket {
+            socket:  try!(UdpSocket::bound(&local)),
+            addr:    *addr,
+            decoder: DatagramDecoder::new(),
+            buffer:  vec![0u8; MAX_DATAGRAM_SIZE].into_boxed_slice()
+        };
+
+        Ok(res)
+    }
+
+    /// Get reference to the underlaying UDP socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Get the service address.
+    pub fn peer_addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+}
+
+impl Read for DatagramSocket {
+    /// Receive a single datagram and store it (including its length prefix)
+    /// into a given buffer. Zero is returned if there is no datagram or if
+    /// the datagram has been dropped (e.g. because it did not come from the
+    /// service address).
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match try!(self.socket.recv_from(&mut self.buffer)) {
+            Some((len, ref addr)) if *addr == self.addr =>
+                Ok(encode(&self.buffer[..len], buf)),
+            _ => Ok(0)
+        }
+    }
+}
+
+impl Write for DatagramSocket {
+    /// Send all complete datagrams from a given buffer. All bytes are
+    /// always consumed, an incomplete datagram is sent once the remaining
+    /// bytes arrive. Datagrams which cannot be sent immediately are dropped.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+
+        while consumed < buf.len() {
+            consumed += self.decoder.feed(&buf[consumed..]);
+
+            let complete = match self.decoder.datagram() {
+                Some(datagram) => {
+                    if let Err(err) = self.socket.send_to(datagram, &self.addr) {
+                        if err.kind() != ErrorKind::WouldBlock {
+                            return Err(err);
+                        }
+                    }
+
+                    true
+                },
+                None => false
+            };
+
+            if complete {
+                self.decoder.clear();
+            }
+        }
+
+        Ok(consumed)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagram_coding() {
+        let mut buf = [0u8; 8];
+
+        assert_eq!(encode(&[1, 2, 3], &mut buf), 5);
+        assert_eq!(&buf[..5], &[0, 3, 1, 2, 3]);
+        assert_eq!(encode(&[0; 7], &mut buf), 0);
+        assert_eq!(encode(&[], &mut buf), 2);
+
+        let data = [0, 3, 1, 2, 3, 0, 0, 0, 1, 4];
+
+        let mut decoder   = DatagramDecoder::new();
+        let mut datagrams = Vec::new();
+
+        // feed the data byte by byte
+        for b in data.iter() {
+            assert_eq!(decoder.feed(&[*b]), 1);
+
+            if let Some(datagram) = decoder.datagram().map(|d| d.to_vec()) {
+                datagrams.push(datagram);
+                decoder.clear();
+            }
+        }
+
+        assert_eq!(datagrams, vec![vec![1, 2, 3], vec![], vec![4]]);
+        assert!(decoder.datagram().is_none());
+
+        // a complete datagram is not extended
+        assert_eq!(decoder.feed(&data), 5);
+        assert_eq!(decoder.feed(&data[5..]), 0);
+        assert_eq!(decoder.datagram(), Some(&[1u8, 2, 3] as &[u8]));
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * Copyright 2016 click2stream, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <stddef.h>
+
+#include <openssl/evp.h>
+
+#define GCM_IV_SIZE         12
+#define GCM_TAG_SIZE        16
+
+/*
+ * Encrypt given data using AES-256-GCM. The output buffer must be at least
+ * as long as the input. Return 1 on success, 0 otherwise.
+ */
+int e2e_aes_gcm_seal(
+    const unsigned char* key,
+    const unsigned char* iv,
+    const unsigned char* aad, size_t aad_len,
+    const unsigned char* input, size_t len,
+    unsigned char* output,
+    unsigned char* tag) {
+    EVP_CIPHER_CTX* ctx;
+    int outl;
+    int res = 0;
+
+    ctx = EVP_CIPHER_CTX_new();
+    if (!ctx)
+        return 0;
+
+    if (!EVP_EncryptInit_ex(ctx, EVP_aes_256_gcm(), NULL, NULL, NULL))
+        goto done;
+    if (!EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_SET_IVLEN, GCM_IV_SIZE, NULL))
+        goto done;
+    if (!EVP_EncryptInit_ex(ctx, NULL, NULL, key, iv))
+        goto done;
+    if (aad_len > 0
+        && !EVP_EncryptUpdate(ctx, NULL, &outl, aad, (int)aad_len))
+        goto done;
+    if (len > 0
+        && !EVP_EncryptUpdate(ctx, output, &outl, input, (int)len))
+        goto done;
+    if (!EVP_EncryptFinal_ex(ctx, output + len, &outl))
+        goto done;
+    if (!EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_GET_TAG, GCM_TAG_SIZE, tag))
+        goto done;
+
+    res = 1;
+
+done:
+    EVP_CIPHER_CTX_free(ctx);
+
+    return res;
+}
+
+/*
+ * Decrypt and authenticate given data using AES-256-GCM. The output buffer
+ * must be at least as long as the input. Return 1 if the data are
+ * authentic, 0 otherwise.
+ */
+int e2e_aes_gcm_open(
+    const unsigned char* key,
+    const unsigned char* iv,
+    const unsigned char* aad, size_t aad_len,
+    const unsigned char* input, size_t len,
+    const unsigned char* tag,
+    unsigned char* output) {
+    EVP_CIPHER_CTX* ctx;
+    int outl;
+    int res = 0;
+
+    ctx = EVP_CIPHER_CTX_new();
+    if (!ctx)
+        return 0;
+
+    if (!EVP_DecryptInit_ex(ctx, EVP_aes_256_gcm(), NULL, NULL, NULL))
+        goto done;
+    if (!EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_SET_IVLEN, GCM_IV_SIZE, NULL))
+        goto done;
+    if (!EVP_DecryptInit_ex(ctx, NULL, NULL, key, iv))
+        goto done;
+    if (aad_len > 0
+        && !EVP_DecryptUpdate(ctx, NULL, &outl, aad, (int)aad_len))
+        goto done;
+    if (len > 0
+        && !EVP_DecryptUpdate(ctx, output, &outl, input, (int)len))
+        goto done;
+    if (!EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_SET_TAG, GCM_TAG_SIZE,
+        (void*)tag))
+        goto done;
+    if (EVP_DecryptFinal_ex(ctx, output + len, &outl) <= 0)
+        goto done;
+
+    res = 1;
+
+done:
+    EVP_CIPHER_CTX_free(ctx);
+
+    return res;
+}
@@ -0,0 +1,697 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end encryption of session payloads.
+//!
+//! Session payloads of selected services can be encrypted using 256-bit keys
+//! shared out-of-band with the final consumer, so that the relayed data
+//! remain confidential even against Arrow Service. The encryption is used
+//! only if Arrow Service supports the FEATURE_E2E_ENCRYPTION feature; the
+//! encrypted services are announced in the client metadata (as
+//! "service_id:key_id" items, the key ID is the first four bytes of the
+//! SHA-256 hash of the key).
+//!
+//! Each direction of a session is a separate stream. It starts with a header
+//! (u8 version, 4 bytes key ID, 16 bytes random nonce) followed by records
+//! (u32 flags and length, ciphertext, 16 bytes tag). Both peers contribute a
+//! nonce. The session secret is derived from the shared key and both nonces
+//! (the consumer nonce first) using HMAC-SHA256 and each direction has its own
+//! key derived from the session secret and a direction label. The device
+//! therefore cannot send any records before it receives the consumer header.
+//!
+//! Records are encrypted using AES-256-GCM. The IV is the record counter of
+//! the direction and the record header is authenticated as additional data,
+//! so records cannot be replayed, reordered or reflected back to the sender.
+//! The most significant bit of the record header marks the final (empty)
+//! record which authenticates the end of the stream. Records are independent
+//! of data frame boundaries.
+
+use std::cmp;
+use std::fmt;
+
+use std::fmt::{Debug, Formatter};
+
+use std::collections::HashMap;
+
+use net::raw::ether::MacAddr;
+use net::arrow::protocol::Service;
+
+use utils::RuntimeError;
+use utils::config::read_file;
+use utils::secret::Secret;
+
+use libc::{c_int, size_t};
+
+use openssl::crypto::hash;
+use openssl::crypto::hmac;
+use openssl::crypto::rand;
+
+use openssl::crypto::hash::Type as HashType;
+
+use rustc_serialize::hex::FromHex;
+
+#[link(name = "e2e_utils")]
+extern "C" {
+    fn e2e_aes_gcm_seal(
+        key: *const u8,
+        iv: *const u8,
+        aad: *const u8, aad_len: size_t,
+        input: *const u8, len: size_t,
+        output: *mut u8,
+        tag: *mut u8) -> c_int;
+    fn e2e_aes_gcm_open(
+        key: *const u8,
+        iv: *const u8,
+        aad: *const u8, aad_len: size_t,
+        input: *const u8, len: size_t,
+        tag: *const u8,
+        output: *mut u8) -> c_int;
+}
+
+/// Current version of the stream format.
+pub const E2E_VERSION: u8 = 2;
+
+/// Key size (in bytes).
+const KEY_SIZE: usize = 32;
+
+/// Nonce size (in bytes).
+const NONCE_SIZE: usize = 16;
+
+/// Stream header size (in bytes).
+const HEADER_SIZE: usize = 1 + 4 + NONCE_SIZE;
+
+/// Record tag size (in bytes).
+const TAG_SIZE: usize = 16;
+
+/// Maximum length of a record (in bytes).
+const MAX_RECORD_LENGTH: usize = 1 << 20;
+
+/// Record header flag marking the final record of a stream.
+const FINAL_RECORD_FLAG: u32 = 1 << 31;
+
+/// Label of the session secret.
+const SESSION_LABEL: &'static [u8] = b"arrow-e2e-session";
+
+/// Label of the device -> consumer direction key.
+const DEVICE_TO_CONSUMER: &'static [u8] = b"arrow-e2e-device-to-consumer";
+
+/// Label of the consumer -> device direction key.
+const CONSUMER_TO_DEVICE: &'static [u8] = b"arrow-e2e-consumer-to-device";
+
+/// Shared end-to-end encryption key.
+#[derive(Clone)]
+pub struct E2eKey {
+    key: Secret<Vec<u8>>,
+    id:  [u8; 4],
+}
+
+impl E2eKey {
+    /// Create a new key from given 32 bytes.
+    pub fn new(key: &[u8]) -> Result<E2eKey, RuntimeError> {
+        if key.len() != KEY_SIZE {
+            return Err(RuntimeError::from("invalid key length, 32 bytes expected"));
+        }
+
+        let digest = hash::hash(HashType::SHA256, key);
+
+        let res = E2eKey {
+            key: Secret::new(key.to_vec()),
+            id:  [digest[0], digest[1], digest[2], digest[3]]
+        };
+
+        Ok(res)
+    }
+
+    /// Parse a given hex-encoded key.
+    pub fn parse(key: &str) -> Result<E2eKey, RuntimeError> {
+        let key = try!(key.trim().from_hex()
+            .or(Err(RuntimeError::from("the key is not hex encoded"))));
+
+        E2eKey::new(Secret::new(key).expose())
+    }
+
+    /// Load a hex-encoded key from a given file.
+    pub fn load(file: &str) -> Result<E2eKey, RuntimeError> {
+        let content = try!(read_file(file)
+            .map_err(|err| RuntimeError::from(format!(
+                "unable to read key file \"{}\": {}", file, err))));
+
+        E2eKey::parse(Secret::new(content).expose())
+    }
+
+    /// Get key ID as a hex string.
+    pub fn id(&self) -> String {
+        self.id.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl Debug for E2eKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // never print the key itself
+        write!(f, "E2eKey {{ id: {} }}", self.id())
+    }
+}
+
+/// Keys of individual devices (and an optional default key).
+#[derive(Debug, Clone)]
+pub struct E2eKeys {
+    default: Option<E2eKey>,
+    devices: HashMap<MacAddr, E2eKey>,
+}
+
+impl E2eKeys {
+    /// Create a new empty key set.
+    pub fn new() -> E2eKeys {
+        E2eKeys {
+            default: None,
+            devices: HashMap::new()
+        }
+    }
+
+    /// Check if there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.devices.is_empty()
+    }
+
+    /// Set key of a device with a given MAC address (None sets the default
+    /// key used for all other devices).
+    pub fn set(&mut self, mac: Option<MacAddr>, key: E2eKey) {
+        match mac {
+            Some(mac) => { self.devices.insert(mac, key); },
+            None      => self.default = Some(key)
+        }
+    }
+
+    /// Get key of a given service (if its payload should be encrypted).
+    /// Only services of devices (i.e. services with a MAC address) can be
+    /// encrypted.
+    pub fn get(&self, svc: &Service) -> Option<&E2eKey> {
+        match svc {
+            &Service::ControlProtocol => None,
+            &Service::AnyTCP          => None,
+            svc => svc.mac()
+                .and_then(|mac| self.devices.get(mac))
+                .or(self.default.as_ref())
+        }
+    }
+
+    /// Get "service_id:key_id" items of encrypted services from a given
+    /// list of services.
+    pub fn announcements(&self, services: &[(u16, Service)]) -> Vec<String> {
+        services.iter()
+            .filter_map(|&(id, ref svc)| {
+                self.get(svc)
+                    .map(|key| format!("{:04x}:{}", id, key.id()))
+            })
+            .collect()
+    }
+}
+
+/// Get big-endian bytes of a given number.
+fn u32_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+/// Encryption/decryption state of a single direction.
+struct CipherState {
+    key:     Secret<Vec<u8>>,
+    counter: u64,
+}
+
+impl CipherState {
+    /// Create a new cipher state for a given session secret and direction
+    /// label.
+    fn new(secret: &[u8], label: &[u8]) -> CipherState {
+        CipherState {
+            key:     Secret::new(hmac::hmac(HashType::SHA256, secret, label)),
+            counter: 0
+        }
+    }
+
+    /// Get IV of the next record.
+    fn iv(&self) -> [u8; 12] {
+        let mut res = [0u8; 12];
+
+        for i in 0..8 {
+            res[4 + i] = (self.counter >> (56 - i * 8)) as u8;
+        }
+
+        res
+    }
+
+    /// Encrypt the next record with a given header and return its
+    /// ciphertext followed by the tag.
+    fn seal(&mut self, header: &[u8], data: &[u8]) -> Vec<u8> {
+        let iv = self.iv();
+
+        let mut res = vec![0u8; data.len() + TAG_SIZE];
+
+        let ok = unsafe {
+            let (ciphertext, tag) = res.split_at_mut(data.len());
+
+            e2e_aes_gcm_seal(self.key.expose().as_ptr(), iv.as_ptr(),
+                header.as_ptr(), header.len() as size_t,
+                data.as_ptr(), data.len() as size_t,
+                ciphertext.as_mut_ptr(), tag.as_mut_ptr())
+        };
+
+        assert!(ok != 0, "unable to encrypt an end-to-end encryption record");
+
+        self.counter += 1;
+
+        res
+    }
+
+    /// Decrypt the next record with a given header, ciphertext and tag.
+    /// None is returned if the record cannot be authenticated.
+    fn open(
+        &mut self,
+        header: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8]) -> Option<Vec<u8>> {
+        let iv = self.iv();
+
+        let mut res = vec![0u8; ciphertext.len()];
+
+        let ok = unsafe {
+            e2e_aes_gcm_open(self.key.expose().as_ptr(), iv.as_ptr(),
+                header.as_ptr(), header.len() as size_t,
+                ciphertext.as_ptr(), ciphertext.len() as size_t,
+                tag.as_ptr(), res.as_mut_ptr())
+        };
+
+        if ok == 0 {
+            return None;
+        }
+
+        self.counter += 1;
+
+        Some(res)
+    }
+}
+
+/// End-to-end encryption state of a single session (i.e. both directions).
+pub struct E2eSession {
+    key:       E2eKey,
+    nonce:     Vec<u8>,
+    initiator: bool,
+    tx_label:  &'static [u8],
+    rx_label:  &'static [u8],
+    header:    Option<Vec<u8>>,
+    tx:        Option<CipherState>,
+    rx:        Option<CipherState>,
+    buffer:    Vec<u8>,
+    finished:  bool,
+    closed:    bool,
+}
+
+impl E2eSession {
+    /// Create a new device-side session using a given key and a random
+    /// nonce.
+    pub fn new(key: &E2eKey) -> E2eSession {
+        E2eSession::with_role(key, false,
+            DEVICE_TO_CONSUMER, CONSUMER_TO_DEVICE)
+    }
+
+    /// Create a new consumer-side session using a given key and a random
+    /// nonce.
+    #[cfg(test)]
+    fn consumer(key: &E2eKey) -> E2eSession {
+        E2eSession::with_role(key, true,
+            CONSUMER_TO_DEVICE, DEVICE_TO_CONSUMER)
+    }
+
+    /// Create a new session with a given role.
+    fn with_role(
+        key: &E2eKey,
+        initiator: bool,
+        tx_label: &'static [u8],
+        rx_label: &'static [u8]) -> E2eSession {
+        let nonce = rand::rand_bytes(NONCE_SIZE);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+
+        header.push(E2E_VERSION);
+        header.extend_from_slice(&key.id);
+        header.extend_from_slice(&nonce);
+
+        E2eSession {
+            key:       key.clone(),
+            nonce:     nonce,
+            initiator: initiator,
+            tx_label:  tx_label,
+            rx_label:  rx_label,
+            header:    Some(header),
+            tx:        None,
+            rx:        None,
+            buffer:    Vec::new(),
+            finished:  false,
+            closed:    false
+        }
+    }
+
+    /// Check if the stream header has not been sent yet.
+    pub fn has_header(&self) -> bool {
+        self.header.is_some()
+    }
+
+    /// Check if the peer header has been received, i.e. data can be sealed.
+    pub fn is_established(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Check if the final record has been received from the peer.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Encrypt given data into one or more records. The stream header is
+    /// prepended to the first output. Data can be given only once the
+    /// session is established.
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        assert!(data.is_empty() || self.is_established(),
+            "end-to-end encryption session not established");
+
+        assert!(!self.finished, "end-to-end encryption stream finished");
+
+        let mut res = self.header.take()
+            .unwrap_or(Vec::new());
+
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let len = cmp::min(data.len() - offset, MAX_RECORD_LENGTH);
+
+            self.seal_record(len as u32, &data[offset..offset + len],
+                &mut res);
+
+            offset += len;
+        }
+
+        res
+    }
+
+    /// Finish the outgoing stream. The final record is returned (preceded
+    /// by the stream header if it has not been sent yet). The final record
+    /// can be created only if the session is established.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut res = self.header.take()
+            .unwrap_or(Vec::new());
+
+        if self.is_established() && !self.finished {
+            self.seal_record(FINAL_RECORD_FLAG, &[], &mut res);
+
+            self.finished = true;
+        }
+
+        res
+    }
+
+    /// Seal a single record with a given header value.
+    fn seal_record(&mut self, header: u32, data: &[u8], output: &mut Vec<u8>) {
+        let header = u32_bytes(header);
+
+        let sealed = self.tx.as_mut()
+            .unwrap()
+            .seal(&header, data);
+
+        output.extend_from_slice(&header);
+        output.extend_from_slice(&sealed);
+    }
+
+    /// Process the peer stream header and derive the session keys.
+    fn process_header(&mut self, header: &[u8]) -> Result<(), RuntimeError> {
+        if header[0] != E2E_VERSION {
+            return Err(RuntimeError::from("unsupported end-to-end encryption version"));
+        }
+
+        if header[1..5] != self.key.id[..] {
+            return Err(RuntimeError::from("end-to-end encryption key mismatch"));
+        }
+
+        let peer_nonce = &header[5..HEADER_SIZE];
+
+        // a reflected header
+        if peer_nonce == &self.nonce[..] {
+            return Err(RuntimeError::from("end-to-end encryption nonce reused"));
+        }
+
+        let mut data = SESSION_LABEL.to_vec();
+
+        if self.initiator {
+            data.extend_from_slice(&self.nonce);
+            data.extend_from_slice(peer_nonce);
+        } else {
+            data.extend_from_slice(peer_nonce);
+            data.extend_from_slice(&self.nonce);
+        }
+
+        let secret = Secret::new(
+            hmac::hmac(HashType::SHA256, self.key.key.expose(), &data));
+
+        self.tx = Some(CipherState::new(secret.expose(), self.tx_label));
+        self.rx = Some(CipherState::new(secret.expose(), self.rx_label));
+
+        Ok(())
+    }
+
+    /// Process given stream data and return all decrypted data. Incomplete
+    /// records are buffered. An error is returned if the stream header is
+    /// invalid, if a record cannot be authenticated or if there are any data
+    /// after the final record.
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+        self.buffer.extend_from_slice(data);
+
+        let mut res    = Vec::new();
+        let mut offset = 0;
+
+        if self.rx.is_none() {
+            if self.buffer.len() < HEADER_SIZE {
+                return Ok(res);
+            }
+
+            let header = self.buffer[..HEADER_SIZE].to_vec();
+
+            try!(self.process_header(&header));
+
+            offset = HEADER_SIZE;
+        }
+
+        while (self.buffer.len() - offset) >= 4 {
+            if self.closed {
+                return Err(RuntimeError::from("end-to-end encryption data after the final record"));
+            }
+
+            let header = &self.buffer[offset..offset + 4];
+
+            let value = ((header[0] as u32) << 24)
+                | ((header[1] as u32) << 16)
+                | ((header[2] as u32) << 8)
+                | (header[3] as u32);
+
+            let last = (value & FINAL_RECORD_FLAG) != 0;
+            let len  = (value & !FINAL_RECORD_FLAG) as usize;
+
+            if len > MAX_RECORD_LENGTH || (last && len > 0) {
+                return Err(RuntimeError::from("invalid end-to-end encryption record"));
+            }
+
+            if (self.buffer.len() - offset) < (4 + len + TAG_SIZE) {
+                break;
+            }
+
+            let ciphertext = &self.buffer[offset + 4..offset + 4 + len];
+            let tag        = &self.buffer[offset + 4 + len..offset + 4 + len + TAG_SIZE];
+
+            let plaintext = try!(self.rx.as_mut()
+                .unwrap()
+                .open(header, ciphertext, tag)
+                .ok_or(RuntimeError::from("end-to-end encryption record authentication failed")));
+
+            res.extend_from_slice(&plaintext);
+
+            self.closed = last;
+
+            offset += 4 + len + TAG_SIZE;
+        }
+
+        self.buffer.drain(..offset);
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::Service;
+
+    fn test_key(b: u8) -> E2eKey {
+        E2eKey::new(&[b; 32]).unwrap()
+    }
+
+    fn hex_key(byte: &str) -> String {
+        (0..32).map(|_| byte)
+            .collect()
+    }
+
+    /// Create an established pair of device and consumer sessions.
+    fn session_pair(key: &E2eKey) -> (E2eSession, E2eSession) {
+        let mut device   = E2eSession::new(key);
+        let mut consumer = E2eSession::consumer(key);
+
+        assert!(!device.is_established());
+
+        assert!(consumer.open(&device.seal(&[])).unwrap().is_empty());
+        assert!(device.open(&consumer.seal(&[])).unwrap().is_empty());
+
+        assert!(device.is_established());
+        assert!(consumer.is_established());
+
+        (device, consumer)
+    }
+
+    #[test]
+    fn test_e2e_roundtrip() {
+        let key = test_key(1);
+
+        let (mut device, mut consumer) = session_pair(&key);
+
+        let mut stream = consumer.seal(b"DESCRIBE rtsp://cam/ RTSP/1.0\r\n");
+
+        stream.extend(consumer.seal(b"CSeq: 1\r\n\r\n"));
+
+        assert!(!stream.windows(4).any(|w| w == b"CSeq"));
+
+        let mut plaintext = Vec::new();
+
+        // feed the stream byte by byte
+        for b in &stream {
+            plaintext.extend(device.open(&[*b]).unwrap());
+        }
+
+        assert_eq!(&plaintext[..],
+            &b"DESCRIBE rtsp://cam/ RTSP/1.0\r\nCSeq: 1\r\n\r\n"[..]);
+
+        let response = device.seal(b"RTSP/1.0 200 OK\r\n\r\n");
+
+        assert_eq!(&consumer.open(&response).unwrap()[..],
+            &b"RTSP/1.0 200 OK\r\n\r\n"[..]);
+
+        // authenticated end of the stream
+        assert!(!device.is_closed());
+        assert!(device.open(&consumer.finish()).unwrap().is_empty());
+        assert!(device.is_closed());
+
+        assert!(device.open(&[0, 0, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_e2e_attacks() {
+        let key = test_key(1);
+
+        // tampered record
+        let (mut device, mut consumer) = session_pair(&key);
+
+        let mut record = consumer.seal(b"hello");
+
+        record[5] ^= 1;
+
+        assert!(device.open(&record).is_err());
+
+        // replayed record
+        let (mut device, mut consumer) = session_pair(&key);
+
+        let record = consumer.seal(b"hello");
+
+        assert!(device.open(&record).is_ok());
+        assert!(device.open(&record).is_err());
+
+        // record reflected back to its sender
+        let (mut device, _) = session_pair(&key);
+
+        let record = device.seal(b"hello");
+
+        assert!(device.open(&record).is_err());
+
+        // record replayed into a different session
+        let (_, mut consumer) = session_pair(&key);
+        let (mut device, _)   = session_pair(&key);
+
+        assert!(device.open(&consumer.seal(b"hello")).is_err());
+
+        // reflected header
+        let mut device = E2eSession::new(&key);
+
+        let header = device.seal(&[]);
+
+        assert!(device.open(&header).is_err());
+
+        // truncated stream
+        let (mut device, mut consumer) = session_pair(&key);
+
+        let mut stream = consumer.seal(b"hello");
+
+        stream.extend(consumer.finish());
+
+        let len = stream.len();
+
+        assert!(device.open(&stream[..len - 1]).is_ok());
+        assert!(!device.is_closed());
+
+        // wrong key
+        let mut device   = E2eSession::new(&key);
+        let mut consumer = E2eSession::consumer(&test_key(2));
+
+        assert!(device.open(&consumer.seal(&[])).is_err());
+    }
+
+    #[test]
+    fn test_e2e_keys() {
+        let mac  = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let addr = SocketAddr::from_str("10.0.0.1:554").unwrap();
+        let svc1 = Service::RTSP(mac, addr, "/1".to_string());
+        let svc2 = Service::HTTP(MacAddr::new(1, 1, 1, 1, 1, 1), addr);
+
+        assert!(E2eKey::parse("0011").is_err());
+        assert!(E2eKey::parse(&hex_key("zz")).is_err());
+
+        let key = E2eKey::parse(&hex_key("01")).unwrap();
+
+        assert_eq!(key.id(), test_key(1).id());
+
+        let mut keys = E2eKeys::new();
+
+        assert!(keys.is_empty());
+
+        keys.set(Some(mac), test_key(1));
+
+        assert!(keys.get(&svc1).is_some());
+        assert!(keys.get(&svc2).is_none());
+        assert!(keys.get(&Service::AnyTCP).is_none());
+
+        keys.set(None, test_key(2));
+
+        assert_eq!(keys.get(&svc2).map(|key| key.id()), Some(test_key(2).id()));
+        assert_eq!(keys.announcements(&[(1, svc1), (2, Service::AnyTCP)]),
+            vec![format!("0001:{}", test_key(1).id())]);
+    }
+}
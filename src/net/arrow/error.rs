@@ -85,6 +85,8 @@ pub enum ErrorKind {
     ServiceConnectionError,
     /// An internal Arrow Server error.
     ArrowServerError,
+    /// The system has been resumed from suspend.
+    SystemResumed,
     /// Unspecified error.
     Other,
 }
@@ -94,6 +96,7 @@ pub enum ErrorKind {
 pub struct ArrowError {
     kind: ErrorKind,
     msg:  String,
+    ack:  Option<u32>,
 }
 
 impl ArrowError {
@@ -103,7 +106,8 @@ impl ArrowError {
         let err = ArrowError::from(val);
         ArrowError {
             kind: kind,
-            msg:  err.msg
+            msg:  err.msg,
+            ack:  None
         }
     }
     
@@ -137,16 +141,34 @@ impl ArrowError {
         ArrowError::new(ErrorKind::ArrowServerError, val)
     }
     
+    /// Create a new system resume error.
+    pub fn system_resumed<T>(val: T) -> ArrowError
+        where ArrowError: From<T> {
+        ArrowError::new(ErrorKind::SystemResumed, val)
+    }
+    
     /// Create another error.
     pub fn other<T>(val: T) -> ArrowError
         where ArrowError: From<T> {
         ArrowError::new(ErrorKind::Other, val)
     }
     
+    /// Attach a given REGISTER ACK error code to this error.
+    pub fn with_ack_code(mut self, code: u32) -> ArrowError {
+        self.ack = Some(code);
+        self
+    }
+    
     /// Get error kind.
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+    
+    /// Get REGISTER ACK error code (if the error was caused by a REGISTER
+    /// failure).
+    pub fn ack_code(&self) -> Option<u32> {
+        self.ack
+    }
 }
 
 impl Error for ArrowError {
@@ -168,7 +190,8 @@ impl From<String> for ArrowError {
     fn from(msg: String) -> ArrowError {
         ArrowError {
             kind: ErrorKind::Other,
-            msg:  msg
+            msg:  msg,
+            ack:  None
         }
     }
 }
@@ -0,0 +1,192 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Flap damping for unstable services.
+//!
+//! A service is flapping if its sessions fail repeatedly within a short time
+//! (connection errors or sessions dying shortly after they were opened).
+//! Flapping services are held down, i.e. new sessions to them are refused
+//! for some time. The hold-down period is doubled every time the service
+//! gets held down again and it is reset once the service delivers a stable
+//! session.
+
+use std::cmp;
+
+use std::collections::{HashMap, VecDeque};
+
+use time;
+
+/// Default number of failures within the detection window needed to hold
+/// down a service.
+pub const FLAP_THRESHOLD: usize = 5;
+
+/// Failure detection window (in seconds).
+const FLAP_WINDOW: f64 = 60.0;
+
+/// Initial hold-down period (in seconds).
+const INITIAL_HOLD_DOWN: u64 = 30;
+
+/// Maximum hold-down period (in seconds).
+const MAX_HOLD_DOWN: u64 = 900;
+
+/// Sessions closed with an error sooner than this (in milliseconds) are
+/// considered to be failures, longer sessions are considered to be stable.
+pub const MIN_STABLE_LIFETIME: u64 = 10000;
+
+/// Flap state of a single service.
+#[derive(Debug, Clone)]
+struct FlapState {
+    /// Timestamps of the recent failures.
+    failures:   VecDeque<f64>,
+    /// Number of consecutive hold-downs.
+    penalty:    u32,
+    /// End of the current hold-down (if any).
+    hold_until: Option<f64>,
+}
+
+impl FlapState {
+    /// Create a new state of a stable service.
+    fn new() -> FlapState {
+        FlapState {
+            failures:   VecDeque::new(),
+            penalty:    0,
+            hold_until: None
+        }
+    }
+}
+
+/// Flap detector of all services.
+#[derive(Debug, Clone)]
+pub struct FlapDamping {
+    /// Number of failures needed to hold down a service (zero disables the
+    /// damping).
+    threshold: usize,
+    /// Flap states of individual services.
+    services:  HashMap<u16, FlapState>,
+}
+
+impl FlapDamping {
+    /// Create a new flap detector holding down services after a given number
+    /// of failures within the detection window (zero disables the damping).
+    pub fn new(threshold: usize) -> FlapDamping {
+        FlapDamping {
+            threshold: threshold,
+            services:  HashMap::new()
+        }
+    }
+
+    /// Get the remaining hold-down period of a given service (in seconds).
+    /// None is returned if the service is not held down.
+    pub fn check(&self, svc_id: u16) -> Option<u64> {
+        self.check_at(svc_id, time::precise_time_s())
+    }
+
+    /// Record a failure of a given service. The hold-down period (in
+    /// seconds) is returned if the service got held down.
+    pub fn failure(&mut self, svc_id: u16) -> Option<u64> {
+        self.failure_at(svc_id, time::precise_time_s())
+    }
+
+    /// Record a stable session of a given service.
+    pub fn stable(&mut self, svc_id: u16) {
+        self.services.remove(&svc_id);
+    }
+
+    /// Get the remaining hold-down period of a given service at a given
+    /// time.
+    fn check_at(&self, svc_id: u16, now: f64) -> Option<u64> {
+        self.services.get(&svc_id)
+            .and_then(|state| state.hold_until)
+            .and_then(|hold_until| {
+                if hold_until > now {
+                    Some((hold_until - now).ceil() as u64)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Record a failure of a given service at a given time.
+    fn failure_at(&mut self, svc_id: u16, now: f64) -> Option<u64> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let state = self.services.entry(svc_id)
+            .or_insert(FlapState::new());
+
+        while let Some(&t) = state.failures.front() {
+            if (now - t) > FLAP_WINDOW {
+                state.failures.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        state.failures.push_back(now);
+
+        if state.failures.len() < self.threshold {
+            return None;
+        }
+
+        let hold_down = cmp::min(
+            INITIAL_HOLD_DOWN << cmp::min(state.penalty, 16),
+            MAX_HOLD_DOWN);
+
+        state.failures.clear();
+        state.penalty   += 1;
+        state.hold_until = Some(now + hold_down as f64);
+
+        Some(hold_down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flap_damping() {
+        let mut damping = FlapDamping::new(3);
+
+        assert_eq!(damping.failure_at(1, 0.0), None);
+        assert_eq!(damping.failure_at(1, 1.0), None);
+
+        // failures outside the window are forgotten
+        assert_eq!(damping.failure_at(1, 100.0), None);
+        assert_eq!(damping.failure_at(1, 101.0), None);
+        assert_eq!(damping.check_at(1, 101.0), None);
+
+        assert_eq!(damping.failure_at(1, 102.0), Some(30));
+        assert_eq!(damping.check_at(1, 110.0), Some(22));
+        assert_eq!(damping.check_at(1, 132.0), None);
+        assert_eq!(damping.check_at(2, 110.0), None);
+
+        // the hold-down period is doubled
+        damping.failure_at(1, 140.0);
+        damping.failure_at(1, 141.0);
+
+        assert_eq!(damping.failure_at(1, 142.0), Some(60));
+
+        damping.stable(1);
+
+        assert_eq!(damping.check_at(1, 150.0), None);
+
+        let mut damping = FlapDamping::new(0);
+
+        for i in 0..10 {
+            assert_eq!(damping.failure_at(1, i as f64), None);
+        }
+    }
+}
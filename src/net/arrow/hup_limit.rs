@@ -0,0 +1,254 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HUP message rate limiting.
+//!
+//! A flapping service may fail many sessions per second and every failure
+//! would be reported using a separate HUP message. Repeated HUP messages for
+//! the same session are dropped. If the server supports the
+//! FEATURE_HUP_REPORTS Control Protocol feature, only the first few error
+//! HUP messages of a service are sent within the rate limiting window, the
+//! remaining failures are counted and reported using a single HUP_REPORT
+//! message once the window ends.
+
+use std::collections::HashMap;
+
+use time;
+
+use net::arrow::protocol::{HupReport, HUP_NO_ERROR};
+
+/// Number of error HUP messages of a single service sent within the rate
+/// limiting window.
+const HUP_BURST: u32 = 5;
+
+/// Rate limiting window (in seconds).
+const HUP_WINDOW: f64 = 10.0;
+
+/// HUP messages of the same session sent within this time (in seconds) are
+/// considered to be duplicates.
+const DEDUP_WINDOW: f64 = 30.0;
+
+/// Decision about a HUP message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HupDecision {
+    /// Send the message.
+    Send,
+    /// Drop the message, the session has been already hung up.
+    Duplicate,
+    /// Drop the message, it will be reported within a HUP_REPORT message.
+    Suppressed,
+}
+
+/// HUP statistics of a single service within the current window.
+#[derive(Debug, Clone)]
+struct ServiceWindow {
+    /// Start of the window.
+    start:      f64,
+    /// Number of failures within the window.
+    failures:   u32,
+    /// Number of suppressed HUP messages within the window.
+    suppressed: u32,
+    /// Last error code.
+    last_error: u32,
+}
+
+impl ServiceWindow {
+    /// Create a new window starting at a given time.
+    fn new(start: f64) -> ServiceWindow {
+        ServiceWindow {
+            start:      start,
+            failures:   0,
+            suppressed: 0,
+            last_error: HUP_NO_ERROR
+        }
+    }
+}
+
+/// HUP message limiter.
+#[derive(Debug, Clone)]
+pub struct HupLimiter {
+    /// Recently hung up sessions (session ID and time).
+    sessions: HashMap<u32, f64>,
+    /// Windows of individual services.
+    services: HashMap<u16, ServiceWindow>,
+}
+
+impl HupLimiter {
+    /// Create a new HUP limiter.
+    pub fn new() -> HupLimiter {
+        HupLimiter {
+            sessions: HashMap::new(),
+            services: HashMap::new()
+        }
+    }
+
+    /// Decide about a HUP message for a given service, session and error
+    /// code. Error HUP messages are suppressed only if they can be
+    /// aggregated.
+    pub fn check(
+        &mut self,
+        service_id: u16,
+        session_id: u32,
+        error_code: u32,
+        aggregate: bool) -> HupDecision {
+        self.check_at(service_id, session_id, error_code, aggregate,
+            time::precise_time_s())
+    }
+
+    /// Forget a previous HUP of a given session (the session ID has been
+    /// used for a new session).
+    pub fn session_opened(&mut self, session_id: u32) {
+        self.sessions.remove(&session_id);
+    }
+
+    /// Take reports of all services with suppressed HUP messages whose
+    /// window has ended.
+    pub fn take_reports(&mut self) -> Vec<HupReport> {
+        self.take_reports_at(time::precise_time_s())
+    }
+
+    /// Decide about a HUP message at a given time.
+    fn check_at(
+        &mut self,
+        service_id: u16,
+        session_id: u32,
+        error_code: u32,
+        aggregate: bool,
+        now: f64) -> HupDecision {
+        let expired = self.sessions.iter()
+            .filter(|&(_, t)| (now - *t) > DEDUP_WINDOW)
+            .map(|(session_id, _)| *session_id)
+            .collect::<Vec<_>>();
+
+        for session_id in expired {
+            self.sessions.remove(&session_id);
+        }
+
+        if self.sessions.contains_key(&session_id) {
+            return HupDecision::Duplicate;
+        }
+
+        self.sessions.insert(session_id, now);
+
+        if error_code == HUP_NO_ERROR {
+            return HupDecision::Send;
+        }
+
+        let window = self.services.entry(service_id)
+            .or_insert(ServiceWindow::new(now));
+
+        // windows with suppressed messages are closed by take_reports()
+        if (now - window.start) > HUP_WINDOW && window.suppressed == 0 {
+            *window = ServiceWindow::new(now);
+        }
+
+        window.failures  += 1;
+        window.last_error = error_code;
+
+        if aggregate && window.failures > HUP_BURST {
+            window.suppressed += 1;
+            HupDecision::Suppressed
+        } else {
+            HupDecision::Send
+        }
+    }
+
+    /// Take reports of all ended windows with suppressed HUP messages at a
+    /// given time.
+    fn take_reports_at(&mut self, now: f64) -> Vec<HupReport> {
+        let ended = self.services.iter()
+            .filter(|&(_, window)| (now - window.start) > HUP_WINDOW)
+            .map(|(service_id, _)| *service_id)
+            .collect::<Vec<_>>();
+
+        let mut res = Vec::new();
+
+        for service_id in ended {
+            let window = self.services.remove(&service_id)
+                .unwrap();
+
+            if window.suppressed > 0 {
+                res.push(HupReport::new(service_id,
+                    window.failures,
+                    window.suppressed,
+                    (now - window.start) as u32,
+                    window.last_error));
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use net::arrow::protocol::{HUP_NO_ERROR, HUP_SERVICE_UNREACHABLE};
+
+    #[test]
+    fn test_hup_limiter() {
+        let mut limiter = HupLimiter::new();
+
+        assert_eq!(limiter.check_at(1, 1, HUP_NO_ERROR, true, 0.0), HupDecision::Send);
+        assert_eq!(limiter.check_at(1, 1, HUP_NO_ERROR, true, 1.0), HupDecision::Duplicate);
+
+        limiter.session_opened(1);
+
+        assert_eq!(limiter.check_at(1, 1, HUP_NO_ERROR, true, 1.0), HupDecision::Send);
+
+        for i in 0..5 {
+            let decision = limiter.check_at(2, 10 + i, HUP_SERVICE_UNREACHABLE,
+                true, 2.0);
+
+            assert_eq!(decision, HupDecision::Send);
+        }
+
+        assert_eq!(limiter.check_at(2, 20, HUP_SERVICE_UNREACHABLE, true, 3.0),
+            HupDecision::Suppressed);
+        assert_eq!(limiter.check_at(2, 21, HUP_SERVICE_UNREACHABLE, true, 4.0),
+            HupDecision::Suppressed);
+
+        // other services are not affected
+        assert_eq!(limiter.check_at(3, 30, HUP_SERVICE_UNREACHABLE, true, 4.0),
+            HupDecision::Send);
+
+        assert!(limiter.take_reports_at(5.0).is_empty());
+
+        let reports = limiter.take_reports_at(13.0);
+
+        assert_eq!(reports.len(), 1);
+
+        let report = reports[0];
+
+        assert_eq!((report.service_id, report.failures, report.suppressed, report.period),
+            (2, 7, 2, 11));
+
+        assert!(limiter.take_reports_at(14.0).is_empty());
+
+        // a new window
+        assert_eq!(limiter.check_at(2, 22, HUP_SERVICE_UNREACHABLE, true, 14.0),
+            HupDecision::Send);
+
+        // nothing is suppressed if the failures cannot be aggregated
+        let mut limiter = HupLimiter::new();
+
+        for i in 0..10 {
+            let decision = limiter.check_at(2, i, HUP_SERVICE_UNREACHABLE,
+                false, 0.0);
+
+            assert_eq!(decision, HupDecision::Send);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Adaptive PING period.
+//!
+//! NATs and firewalls drop idle TCP bindings after some time. The PING period
+//! has to be shorter than this time, otherwise the connection silently breaks.
+//! On the other hand, unnecessarily frequent PINGs waste traffic on metered
+//! links. The tuner looks for the binding lifetime using binary search: every
+//! PING confirmed after a given idle interval proves that the binding survives
+//! such interval and every connection lost after a PING following a given idle
+//! interval suggests that it does not. Once the interval is known with a
+//! sufficient precision, PINGs are sent just below it.
+
+use std::cmp;
+
+/// Default PING period (in milliseconds).
+pub const PING_PERIOD: u64 = 60000;
+
+/// Minimum PING period (in milliseconds).
+const MIN_PING_PERIOD: u64 = 20000;
+
+/// Maximum PING period (in milliseconds).
+const MAX_PING_PERIOD: u64 = 900000;
+
+/// The search stops once the binding lifetime is known with this precision
+/// (in milliseconds).
+const RESOLUTION: u64 = 10000;
+
+/// PING period tuner.
+#[derive(Debug, Clone)]
+pub struct PingTuner {
+    /// Adaptive PING period enabled.
+    enabled: bool,
+    /// The longest idle interval the binding is known to survive (in
+    /// milliseconds).
+    good:    u64,
+    /// The shortest idle interval the binding is known not to survive (in
+    /// milliseconds).
+    bad:     Option<u64>,
+}
+
+impl PingTuner {
+    /// Create a new tuner using the default PING period.
+    pub fn new() -> PingTuner {
+        PingTuner {
+            enabled: false,
+            good:    0,
+            bad:     None
+        }
+    }
+
+    /// Create a new tuner looking for the binding lifetime.
+    pub fn adaptive() -> PingTuner {
+        PingTuner {
+            enabled: true,
+            good:    0,
+            bad:     None
+        }
+    }
+
+    /// Check if the binding lifetime is already known.
+    pub fn is_converged(&self) -> bool {
+        match self.bad {
+            Some(bad) => bad <= self.good + RESOLUTION,
+            None      => self.good >= MAX_PING_PERIOD
+        }
+    }
+
+    /// Get the current PING period (in milliseconds).
+    pub fn period(&self) -> u64 {
+        if !self.enabled {
+            return PING_PERIOD;
+        }
+
+        let period = match self.bad {
+            None if self.good == 0 => PING_PERIOD,
+            None => self.good * 2,
+            Some(_) if self.is_converged() => self.good - self.good / 10,
+            Some(bad) => (self.good + bad) / 2
+        };
+
+        cmp::max(MIN_PING_PERIOD, cmp::min(MAX_PING_PERIOD, period))
+    }
+
+    /// Get the known bounds of the binding lifetime (in milliseconds).
+    pub fn bounds(&self) -> (u64, Option<u64>) {
+        (self.good, self.bad)
+    }
+
+    /// Record a PING confirmed after a given idle interval (in
+    /// milliseconds). The method returns true if the PING period has been
+    /// changed.
+    pub fn success(&mut self, idle: u64) -> bool {
+        if !self.enabled || idle <= self.good {
+            return false;
+        }
+
+        let period = self.period();
+
+        self.good = idle;
+
+        // the previous failure must have had a different cause
+        if self.bad.map_or(false, |bad| bad <= idle) {
+            self.bad = None;
+        }
+
+        period != self.period()
+    }
+
+    /// Record a connection lost after a PING following a given idle interval
+    /// (in milliseconds). The method returns true if the PING period has
+    /// been changed.
+    pub fn failure(&mut self, idle: u64) -> bool {
+        // the binding is known to survive such interval, so the connection
+        // must have been lost for a different reason
+        if !self.enabled || idle <= self.good {
+            return false;
+        }
+
+        let period = self.period();
+
+        self.bad = Some(self.bad.map_or(idle, |bad| cmp::min(bad, idle)));
+
+        period != self.period()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled() {
+        let mut tuner = PingTuner::new();
+
+        assert!(!tuner.success(120000));
+        assert!(!tuner.failure(240000));
+        assert_eq!(tuner.period(), PING_PERIOD);
+    }
+
+    #[test]
+    fn test_binary_search() {
+        let mut tuner = PingTuner::adaptive();
+
+        assert_eq!(tuner.period(), 60000);
+
+        // the binding lifetime is 200 seconds
+        assert!(tuner.success(60000));
+        assert_eq!(tuner.period(), 120000);
+
+        assert!(tuner.success(120000));
+        assert_eq!(tuner.period(), 240000);
+
+        assert!(tuner.failure(240000));
+        assert_eq!(tuner.period(), 180000);
+
+        assert!(tuner.success(180000));
+        assert_eq!(tuner.period(), 210000);
+
+        assert!(tuner.failure(210000));
+        assert_eq!(tuner.period(), 195000);
+
+        assert!(tuner.success(195000));
+        assert!(!tuner.is_converged());
+        assert_eq!(tuner.period(), 202500);
+
+        assert!(tuner.failure(202500));
+        assert!(tuner.is_converged());
+        assert_eq!(tuner.period(), 175500);
+
+        // shorter intervals do not change anything
+        assert!(!tuner.success(30000));
+        assert!(!tuner.failure(30000));
+        assert_eq!(tuner.bounds(), (195000, Some(202500)));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut tuner = PingTuner::adaptive();
+
+        tuner.failure(60000);
+        tuner.failure(25000);
+
+        assert_eq!(tuner.period(), MIN_PING_PERIOD);
+
+        let mut tuner = PingTuner::adaptive();
+
+        tuner.success(600000);
+
+        assert_eq!(tuner.period(), MAX_PING_PERIOD);
+
+        tuner.success(MAX_PING_PERIOD);
+
+        assert!(tuner.is_converged());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Control Protocol ACK latency histogram.
+
+use std::cmp;
+
+/// Upper bounds of the histogram buckets (in milliseconds). There is one
+/// more bucket for latencies above the last bound.
+const BUCKETS: [u64; 8] = [50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Default ACK latency (in milliseconds) considered to be slow.
+pub const SLOW_ACK_THRESHOLD: u64 = 2000;
+
+/// Histogram of latencies between sending a Control Protocol message and
+/// receiving its ACK.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    /// Bucket counters.
+    counts: [u64; 9],
+    /// Number of recorded latencies.
+    count:  u64,
+    /// Sum of all recorded latencies (in milliseconds).
+    sum:    u64,
+    /// Maximum recorded latency (in milliseconds).
+    max:    u64,
+}
+
+impl LatencyHistogram {
+    /// Create a new empty histogram.
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: [0; 9],
+            count:  0,
+            sum:    0,
+            max:    0
+        }
+    }
+
+    /// Record a given latency (in milliseconds).
+    pub fn record(&mut self, latency: u64) {
+        let bucket = BUCKETS.iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(BUCKETS.len());
+
+        self.counts[bucket] += 1;
+        self.count          += 1;
+        self.sum            += latency;
+        self.max             = cmp::max(self.max, latency);
+    }
+
+    /// Get number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Get average latency (in milliseconds).
+    pub fn avg(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.sum / self.count
+        }
+    }
+
+    /// Get sum of all recorded latencies (in milliseconds).
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Get maximum latency (in milliseconds).
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Get bucket upper bounds (None for the last bucket) and the
+    /// corresponding (non-cumulative) counters.
+    pub fn buckets(&self) -> Vec<(Option<u64>, u64)> {
+        BUCKETS.iter()
+            .map(|bound| Some(*bound))
+            .chain(Some(None))
+            .zip(self.counts.iter().cloned())
+            .collect()
+    }
+
+    /// Get a short text summary (suitable for client metadata) in the
+    /// "avg:max:count/count/.../count" format (all latencies are in
+    /// milliseconds, the counters correspond to the histogram buckets).
+    pub fn summary(&self) -> String {
+        let counts = self.counts.iter()
+            .map(|count| count.to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        format!("{}:{}:{}", self.avg(), self.max, counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::new();
+
+        assert_eq!(histogram.avg(), 0);
+
+        histogram.record(10);
+        histogram.record(50);
+        histogram.record(300);
+        histogram.record(20000);
+
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.avg(), 5090);
+        assert_eq!(histogram.sum(), 20360);
+        assert_eq!(histogram.max(), 20000);
+        assert_eq!(histogram.summary(), "5090:20000:2/0/0/1/0/0/0/0/1");
+
+        let buckets = histogram.buckets();
+
+        assert_eq!(buckets.len(), 9);
+        assert_eq!(buckets[0], (Some(50), 2));
+        assert_eq!(buckets[8], (None, 1));
+    }
+}
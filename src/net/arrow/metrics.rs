@@ -0,0 +1,395 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection metrics.
+//!
+//! Counters of transferred bytes, sessions, connections, Control Protocol
+//! messages, errors, ACK latencies, dropped frames, data frame loss and
+//! buffer high-water marks updated by the connection handler. The counters
+//! can be rendered in the Prometheus text exposition format. Counters of
+//! individual sessions exist only while the sessions are active, the totals
+//! include finished sessions as well.
+
+use std::cmp;
+
+use std::fmt::Write;
+
+use std::collections::{BTreeMap, HashMap};
+
+use net::arrow::latency::LatencyHistogram;
+use net::arrow::sequence::SequenceStats;
+
+/// Byte counters of a single session.
+#[derive(Debug, Copy, Clone)]
+struct SessionCounters {
+    /// Service ID.
+    service_id: u16,
+    /// Number of bytes received from the service.
+    bytes_in:   u64,
+    /// Number of bytes sent to the service.
+    bytes_out:  u64,
+    /// High-water mark of the session input buffer (in bytes).
+    input_hwm:  usize,
+    /// High-water mark of the session output buffer (in bytes).
+    output_hwm: usize,
+}
+
+/// Connection metrics.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    /// Active sessions.
+    sessions:          HashMap<u32, SessionCounters>,
+    /// Number of bytes received from services in all sessions.
+    bytes_in:          u64,
+    /// Number of bytes sent to services in all sessions.
+    bytes_out:         u64,
+    /// Number of connections to Arrow Service.
+    connections:       u64,
+    /// Received Control Protocol messages by type.
+    received_messages: BTreeMap<String, u64>,
+    /// Sent Control Protocol messages by type.
+    sent_messages:     BTreeMap<String, u64>,
+    /// Number of lost connections to Arrow Service.
+    connection_errors: u64,
+    /// Number of failed sessions.
+    session_errors:    u64,
+    /// Control Protocol ACK latencies.
+    ack_latency:       LatencyHistogram,
+    /// Numbers of frames and bytes dropped because of overload by service
+    /// ID.
+    dropped:           BTreeMap<u16, (u64, u64)>,
+    /// Loss and reordering statistics of data frames received in finished
+    /// sessions.
+    sequence:          SequenceStats,
+    /// High-water mark of the Arrow output buffer (in bytes).
+    uplink_hwm:        usize,
+}
+
+impl Metrics {
+    /// Create new metrics.
+    pub fn new() -> Metrics {
+        Metrics {
+            sessions:          HashMap::new(),
+            bytes_in:          0,
+            bytes_out:         0,
+            connections:       0,
+            received_messages: BTreeMap::new(),
+            sent_messages:     BTreeMap::new(),
+            connection_errors: 0,
+            session_errors:    0,
+            ack_latency:       LatencyHistogram::new(),
+            dropped:           BTreeMap::new(),
+            sequence:          SequenceStats::new(),
+            uplink_hwm:        0
+        }
+    }
+
+    /// Record a new connection to Arrow Service. Sessions of the previous
+    /// connection are gone.
+    pub fn connection_started(&mut self) {
+        self.connections += 1;
+        self.sessions.clear();
+    }
+
+    /// Record a lost connection to Arrow Service.
+    pub fn connection_failed(&mut self) {
+        self.connection_errors += 1;
+    }
+
+    /// Record a new session.
+    pub fn session_opened(&mut self, session_id: u32, service_id: u16) {
+        self.sessions.insert(session_id, SessionCounters {
+            service_id: service_id,
+            bytes_in:   0,
+            bytes_out:  0,
+            input_hwm:  0,
+            output_hwm: 0
+        });
+    }
+
+    /// Record bytes received from and sent to the service by a given
+    /// session.
+    pub fn session_transferred(
+        &mut self,
+        session_id: u32,
+        bytes_in: u64,
+        bytes_out: u64) {
+        self.bytes_in  += bytes_in;
+        self.bytes_out += bytes_out;
+
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.bytes_in  += bytes_in;
+            session.bytes_out += bytes_out;
+        }
+    }
+
+    /// Record high-water marks of the input and output buffers of a given
+    /// session.
+    pub fn session_buffered(
+        &mut self,
+        session_id: u32,
+        input_hwm: usize,
+        output_hwm: usize) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.input_hwm  = cmp::max(session.input_hwm, input_hwm);
+            session.output_hwm = cmp::max(session.output_hwm, output_hwm);
+        }
+    }
+
+    /// Record a high-water mark of the Arrow output buffer.
+    pub fn uplink_buffered(&mut self, high_water: usize) {
+        self.uplink_hwm = cmp::max(self.uplink_hwm, high_water);
+    }
+
+    /// Record frames and bytes of a given service dropped because of
+    /// overload.
+    pub fn frames_dropped(&mut self, service_id: u16, frames: usize, bytes: usize) {
+        let counters = self.dropped.entry(service_id)
+            .or_insert((0, 0));
+
+        counters.0 += frames as u64;
+        counters.1 += bytes as u64;
+    }
+
+    /// Record loss and reordering statistics of data frames received in a
+    /// finished session.
+    pub fn sequence_finished(&mut self, stats: &SequenceStats) {
+        self.sequence.add(stats);
+    }
+
+    /// Record a Control Protocol ACK latency (in milliseconds).
+    pub fn ack_received(&mut self, latency: u64) {
+        self.ack_latency.record(latency);
+    }
+
+    /// Record a finished session.
+    pub fn session_closed(&mut self, session_id: u32, failed: bool) {
+        self.sessions.remove(&session_id);
+
+        if failed {
+            self.session_errors += 1;
+        }
+    }
+
+    /// Record a received Control Protocol message of a given type.
+    pub fn message_received(&mut self, msg_type: &str) {
+        *self.received_messages.entry(msg_type.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Record a sent Control Protocol message of a given type.
+    pub fn message_sent(&mut self, msg_type: &str) {
+        *self.sent_messages.entry(msg_type.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Get the number of active sessions.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut res = String::new();
+
+        let reconnects = if self.connections > 0 {
+            self.connections - 1
+        } else {
+            0
+        };
+
+        let mut sessions = self.sessions.iter()
+            .collect::<Vec<_>>();
+
+        sessions.sort_by_key(|&(session_id, _)| *session_id);
+
+        res.push_str("# HELP arrow_connections_total Connections to Arrow Service.\n");
+        res.push_str("# TYPE arrow_connections_total counter\n");
+        writeln!(res, "arrow_connections_total {}", self.connections).unwrap();
+
+        res.push_str("# HELP arrow_reconnects_total Reconnections to Arrow Service.\n");
+        res.push_str("# TYPE arrow_reconnects_total counter\n");
+        writeln!(res, "arrow_reconnects_total {}", reconnects).unwrap();
+
+        res.push_str("# HELP arrow_sessions_active Active service sessions.\n");
+        res.push_str("# TYPE arrow_sessions_active gauge\n");
+        writeln!(res, "arrow_sessions_active {}", self.sessions.len()).unwrap();
+
+        res.push_str("# HELP arrow_bytes_total Bytes transferred in all service sessions.\n");
+        res.push_str("# TYPE arrow_bytes_total counter\n");
+        writeln!(res, "arrow_bytes_total{{direction=\"in\"}} {}", self.bytes_in).unwrap();
+        writeln!(res, "arrow_bytes_total{{direction=\"out\"}} {}", self.bytes_out).unwrap();
+
+        res.push_str("# HELP arrow_session_bytes_total Bytes transferred in active service sessions.\n");
+        res.push_str("# TYPE arrow_session_bytes_total counter\n");
+
+        for &(session_id, session) in &sessions {
+            writeln!(res, "arrow_session_bytes_total{{session=\"{:08x}\",service=\"{}\",direction=\"in\"}} {}",
+                session_id, session.service_id, session.bytes_in).unwrap();
+            writeln!(res, "arrow_session_bytes_total{{session=\"{:08x}\",service=\"{}\",direction=\"out\"}} {}",
+                session_id, session.service_id, session.bytes_out).unwrap();
+        }
+
+        res.push_str("# HELP arrow_control_messages_total Control Protocol messages.\n");
+        res.push_str("# TYPE arrow_control_messages_total counter\n");
+
+        for (msg_type, count) in &self.received_messages {
+            writeln!(res, "arrow_control_messages_total{{direction=\"received\",type=\"{}\"}} {}",
+                msg_type, count).unwrap();
+        }
+
+        for (msg_type, count) in &self.sent_messages {
+            writeln!(res, "arrow_control_messages_total{{direction=\"sent\",type=\"{}\"}} {}",
+                msg_type, count).unwrap();
+        }
+
+        res.push_str("# HELP arrow_errors_total Lost connections and failed sessions.\n");
+        res.push_str("# TYPE arrow_errors_total counter\n");
+        writeln!(res, "arrow_errors_total{{kind=\"connection\"}} {}", self.connection_errors).unwrap();
+        writeln!(res, "arrow_errors_total{{kind=\"session\"}} {}", self.session_errors).unwrap();
+
+        res.push_str("# HELP arrow_ack_latency_milliseconds Latency of Control Protocol ACKs.\n");
+        res.push_str("# TYPE arrow_ack_latency_milliseconds histogram\n");
+
+        let mut cumulative = 0;
+
+        for (bound, count) in self.ack_latency.buckets() {
+            cumulative += count;
+
+            match bound {
+                Some(bound) => writeln!(res, "arrow_ack_latency_milliseconds_bucket{{le=\"{}\"}} {}", bound, cumulative).unwrap(),
+                None => writeln!(res, "arrow_ack_latency_milliseconds_bucket{{le=\"+Inf\"}} {}", cumulative).unwrap()
+            }
+        }
+
+        writeln!(res, "arrow_ack_latency_milliseconds_sum {}", self.ack_latency.sum()).unwrap();
+        writeln!(res, "arrow_ack_latency_milliseconds_count {}", self.ack_latency.count()).unwrap();
+
+        res.push_str("# HELP arrow_dropped_frames_total Frames dropped because of overload.\n");
+        res.push_str("# TYPE arrow_dropped_frames_total counter\n");
+
+        for (service_id, &(frames, _)) in &self.dropped {
+            writeln!(res, "arrow_dropped_frames_total{{service=\"{}\"}} {}", service_id, frames).unwrap();
+        }
+
+        res.push_str("# HELP arrow_dropped_bytes_total Bytes of frames dropped because of overload.\n");
+        res.push_str("# TYPE arrow_dropped_bytes_total counter\n");
+
+        for (service_id, &(_, bytes)) in &self.dropped {
+            writeln!(res, "arrow_dropped_bytes_total{{service=\"{}\"}} {}", service_id, bytes).unwrap();
+        }
+
+        res.push_str("# HELP arrow_data_frames_total Sequenced data frames from Arrow Service in finished sessions.\n");
+        res.push_str("# TYPE arrow_data_frames_total counter\n");
+        writeln!(res, "arrow_data_frames_total{{kind=\"received\"}} {}", self.sequence.received).unwrap();
+        writeln!(res, "arrow_data_frames_total{{kind=\"lost\"}} {}", self.sequence.lost).unwrap();
+        writeln!(res, "arrow_data_frames_total{{kind=\"reordered\"}} {}", self.sequence.reordered).unwrap();
+
+        res.push_str("# HELP arrow_buffer_high_water_bytes Buffer high-water marks.\n");
+        res.push_str("# TYPE arrow_buffer_high_water_bytes gauge\n");
+        writeln!(res, "arrow_buffer_high_water_bytes{{buffer=\"uplink\"}} {}", self.uplink_hwm).unwrap();
+
+        for &(session_id, session) in &sessions {
+            writeln!(res, "arrow_buffer_high_water_bytes{{session=\"{:08x}\",service=\"{}\",buffer=\"input\"}} {}",
+                session_id, session.service_id, session.input_hwm).unwrap();
+            writeln!(res, "arrow_buffer_high_water_bytes{{session=\"{:08x}\",service=\"{}\",buffer=\"output\"}} {}",
+                session_id, session.service_id, session.output_hwm).unwrap();
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = Metrics::new();
+
+        metrics.connection_started();
+        metrics.session_opened(1, 2);
+        metrics.session_opened(3, 4);
+        metrics.session_transferred(1, 100, 10);
+        metrics.session_transferred(3, 5, 0);
+        metrics.message_received("PING");
+        metrics.message_sent("ACK");
+        metrics.message_sent("ACK");
+
+        assert_eq!(metrics.active_sessions(), 2);
+
+        let text = metrics.render();
+
+        assert!(text.contains("arrow_reconnects_total 0\n"));
+        assert!(text.contains("arrow_sessions_active 2\n"));
+        assert!(text.contains("arrow_session_bytes_total{session=\"00000001\",service=\"2\",direction=\"in\"} 100\n"));
+        assert!(text.contains("arrow_control_messages_total{direction=\"sent\",type=\"ACK\"} 2\n"));
+
+        metrics.session_closed(1, true);
+
+        let text = metrics.render();
+
+        assert!(!text.contains("session=\"00000001\""));
+        assert!(text.contains("arrow_bytes_total{direction=\"in\"} 105\n"));
+        assert!(text.contains("arrow_errors_total{kind=\"session\"} 1\n"));
+
+        // sessions do not survive reconnects
+        metrics.connection_failed();
+        metrics.connection_started();
+
+        let text = metrics.render();
+
+        assert!(text.contains("arrow_reconnects_total 1\n"));
+        assert!(text.contains("arrow_sessions_active 0\n"));
+        assert!(text.contains("arrow_errors_total{kind=\"connection\"} 1\n"));
+    }
+
+    #[test]
+    fn test_runtime_metrics() {
+        let mut metrics = Metrics::new();
+
+        metrics.connection_started();
+        metrics.session_opened(1, 2);
+        metrics.session_buffered(1, 1000, 10);
+        metrics.session_buffered(1, 500, 20);
+        metrics.uplink_buffered(4096);
+        metrics.uplink_buffered(1024);
+        metrics.frames_dropped(2, 3, 1500);
+        metrics.frames_dropped(2, 1, 500);
+        metrics.ack_received(10);
+        metrics.ack_received(300);
+        metrics.ack_received(20000);
+
+        metrics.sequence_finished(&SequenceStats {
+            received:  10,
+            lost:      2,
+            reordered: 1
+        });
+
+        let text = metrics.render();
+
+        assert!(text.contains("arrow_ack_latency_milliseconds_bucket{le=\"50\"} 1\n"));
+        assert!(text.contains("arrow_ack_latency_milliseconds_bucket{le=\"500\"} 2\n"));
+        assert!(text.contains("arrow_ack_latency_milliseconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("arrow_ack_latency_milliseconds_sum 20310\n"));
+        assert!(text.contains("arrow_ack_latency_milliseconds_count 3\n"));
+        assert!(text.contains("arrow_dropped_frames_total{service=\"2\"} 4\n"));
+        assert!(text.contains("arrow_dropped_bytes_total{service=\"2\"} 2000\n"));
+        assert!(text.contains("arrow_data_frames_total{kind=\"lost\"} 2\n"));
+        assert!(text.contains("arrow_buffer_high_water_bytes{buffer=\"uplink\"} 4096\n"));
+        assert!(text.contains("arrow_buffer_high_water_bytes{session=\"00000001\",service=\"2\",buffer=\"input\"} 1000\n"));
+        assert!(text.contains("arrow_buffer_high_water_bytes{session=\"00000001\",service=\"2\",buffer=\"output\"} 20\n"));
+    }
+}
@@ -13,46 +13,112 @@
 // limitations under the License.
 
 //! Arrow Protocol implementation.
+//! 
+//! The connection handler is composed of three components:
+//! 
+//! * `Uplink` - the TLS connection to Arrow Service and its output buffer,
+//! * `ControlChannel` - Control Protocol state, message IDs and ACKs,
+//! * `SessionRouter` - service sessions and their I/O buffers.
 
 #[macro_use]
 pub mod error;
 pub mod protocol;
 
-use std::io;
+pub mod relay;
+pub mod overload;
+pub mod any_tcp;
+pub mod access;
+pub mod flap;
+pub mod bitrate;
+#[cfg(feature = "e2e")]
+pub mod e2e;
+pub mod latency;
+pub mod register_policy;
+pub mod version;
+pub mod suspend;
+pub mod sequence;
+pub mod timestamp;
+pub mod auth;
+#[cfg(feature = "recording")]
+pub mod tap;
+pub mod keepalive;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+pub mod hup_limit;
+pub mod authorizer;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod occupancy;
+#[cfg(feature = "datagram")]
+pub mod datagram;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod untrusted;
+
+mod uplink;
+mod channel;
+mod session;
+mod spin;
+
+pub use self::channel::RegisterCache;
+pub use self::session::SessionOptions;
+
 use std::cmp;
-use std::mem;
 use std::result;
 
 use std::ffi::CStr;
-use std::error::Error;
-use std::collections::VecDeque;
 use std::collections::HashMap;
+use std::error::Error;
 use std::net::SocketAddr;
-use std::io::{Read, Write, ErrorKind};
-
-use utils;
 
 use net::raw::ether::MacAddr;
-use net::utils::{Timeout, WriteBuffer};
+use net::raw::devices::EthernetDevice;
+#[cfg(feature = "socks")]
+use net::socks::SocksProxy;
+#[cfg(feature = "websocket")]
+use net::arrow::websocket::WssEndpoint;
 
-use utils::logger::Logger;
-use utils::config::AppContext;
-use utils::{Shared, Serialize};
+use utils::logger::{Logger, Severity, DummyLogger};
+use utils::config::{AppContext, ConfigError};
+use utils::hooks::HookEvent;
+use utils::status_led::LedState;
+use utils::stats_export::SessionRecord;
+use utils::secret::Secret;
+use utils::Shared;
+use utils::clock;
 
 use self::protocol::*;
-use self::error::{Result, ArrowError};
+use self::protocol::violation;
+use self::uplink::Uplink;
+use self::relay::RelayHello;
+use self::overload::{OverloadPolicy, ServiceClass};
+use self::bitrate::{BitrateMonitor, HintSender};
+use self::spin::{SpinDetector, SpinReport};
+use self::suspend::SuspendDetector;
+use self::hup_limit::{HupLimiter, HupDecision};
+use self::occupancy::OccupancyAlarm;
+use self::authorizer::{SessionRequest, SessionDecision, DecisionCallback};
+use self::authorizer::PendingSession;
+use self::sequence::SequencedFrame;
+use self::version::{VersionChange, RegressionPolicy};
+#[cfg(feature = "recording")]
+use self::tap::SessionTap;
+use self::channel::ControlChannel;
+use self::session::{SessionRouter, session2token, token2session};
+use self::error::{Result, ArrowError, ErrorKind};
 
-use mio::tcp::TcpStream;
-use mio::{EventLoop, EventSet, Token, PollOpt, Handler};
+use mio::{EventLoop, EventSet, Token, PollOpt, Evented, Handler};
 
-use openssl::ssl;
+use openssl::ssl::{IntoSsl, SslContext};
 
-use openssl::ssl::{SslStream, IntoSsl};
+use time;
 
-/// Register a given TCP stream in a given event loop.
-fn register_socket<H: Handler>(
+use uuid::Uuid;
+
+/// Register a given socket in a given event loop.
+fn register_socket<E: Evented + ?Sized, H: Handler>(
     token_id: usize, 
-    stream: &TcpStream, 
+    stream: &E, 
     readable: bool,
     writable: bool, 
     event_loop: &mut EventLoop<H>) {
@@ -71,10 +137,10 @@ fn register_socket<H: Handler>(
         .unwrap();
 }
 
-/// Re-register a given TCP stream in a given event loop.
-fn reregister_socket<H: Handler>(
+/// Re-register a given socket in a given event loop.
+fn reregister_socket<E: Evented + ?Sized, H: Handler>(
     token_id: usize, 
-    stream: &TcpStream, 
+    stream: &E, 
     readable: bool,
     writable: bool, 
     event_loop: &mut EventLoop<H>) {
@@ -94,8 +160,8 @@ fn reregister_socket<H: Handler>(
 }
 
 /// Deregister a given socket.
-fn deregister_socket<H: Handler>(
-    stream: &TcpStream, 
+fn deregister_socket<E: Evented + ?Sized, H: Handler>(
+    stream: &E, 
     event_loop: &mut EventLoop<H>) {
     event_loop.deregister(stream)
         .unwrap();
@@ -115,404 +181,36 @@ pub trait Sender<C: Send> {
     fn send(&self, cmd: C) -> result::Result<(), C>;
 }
 
-/// ArrowStream states.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum ArrowStreamState {
-    Ok,
-    ReaderWantRead,
-    ReaderWantWrite,
-    WriterWantRead,
-    WriterWantWrite,
-}
-
-/// Abstraction over the Arrow SSL stream.
-struct ArrowStream {
-    stream:   SslStream<TcpStream>,
-    state:    ArrowStreamState,
-    token_id: usize,
-}
-
-impl ArrowStream {
-    /// Create a new ArrowStream instance and register the underlaying socket 
-    /// within a given event loop.
-    fn connect<S: IntoSsl, H: Handler>(
-        s: S, 
-        arrow_addr: &SocketAddr,
-        token_id: usize,
-        event_loop: &mut EventLoop<H>) -> Result<ArrowStream> {
-        let tcp_stream = try_io!(TcpStream::connect(arrow_addr));
-        let ssl_stream = try_io!(SslStream::connect(s, tcp_stream));
-        
-        register_socket(token_id, ssl_stream.get_ref(), 
-            true, true, event_loop);
-        
-        let res = ArrowStream {
-            stream:   ssl_stream,
-            state:    ArrowStreamState::Ok,
-            token_id: token_id
-        };
-        
-        Ok(res)
-    }
-    
-    /// Enable receiving writable events for the underlaying TCP socket.
-    fn enable_socket_events<H: Handler>(
-        &mut self, 
-        readable: bool, 
-        writable: bool, 
-        event_loop: &mut EventLoop<H>) {
-        reregister_socket(self.token_id, self.stream.get_ref(), 
-            readable, writable, event_loop);
-    }
-    
-    /// Read available data from the underlaying SSL stream into a given 
-    /// buffer.
-    fn read<H: Handler>(
-        &mut self, 
-        buf: &mut [u8], 
-        event_loop: &mut EventLoop<H>) -> Result<usize> {
-        match self.stream.ssl_read(buf) {
-            Err(ssl::error::Error::WantRead(_)) => {
-                self.state = ArrowStreamState::ReaderWantRead;
-                self.enable_socket_events(true, false, event_loop);
-                Ok(0)
-            },
-            Err(ssl::error::Error::WantWrite(_)) => {
-                self.state = ArrowStreamState::ReaderWantWrite;
-                self.enable_socket_events(false, true, event_loop);
-                Ok(0)
-            },
-            other => {
-                self.state = ArrowStreamState::Ok;
-                self.enable_socket_events(true, true, event_loop);
-                Ok(try_io!(other))
-            }
-        }
-    }
-    
-    /// Write given data using the underlaying SSL stream.
-    fn write<H: Handler>(
-        &mut self, 
-        data: &[u8], 
-        event_loop: &mut EventLoop<H>) -> Result<usize> {
-        match self.stream.ssl_write(data) {
-            Err(ssl::error::Error::WantRead(_)) => {
-                self.state = ArrowStreamState::WriterWantRead;
-                self.enable_socket_events(true, false, event_loop);
-                Ok(0)
-            },
-            Err(ssl::error::Error::WantWrite(_)) => {
-                self.state = ArrowStreamState::WriterWantWrite;
-                self.enable_socket_events(false, true, event_loop);
-                Ok(0)
-            },
-            other => {
-                self.state = ArrowStreamState::Ok;
-                self.enable_socket_events(true, true, event_loop);
-                Ok(try_io!(other))
-            }
-        }
-    }
-    
-    /// Check if the underlaying socket is ready to read.
-    fn can_read(&self, event_set: EventSet) -> bool {
-        match self.state {
-            ArrowStreamState::Ok              => event_set.is_readable(),
-            ArrowStreamState::ReaderWantRead  => event_set.is_readable(),
-            ArrowStreamState::ReaderWantWrite => event_set.is_writable(),
-            _ => false
-        }
-    }
-    
-    /// Check if the underlaying socket is ready to write.
-    fn can_write(&self, event_set: EventSet) -> bool {
-        match self.state {
-            ArrowStreamState::Ok              => event_set.is_writable(),
-            ArrowStreamState::WriterWantRead  => event_set.is_readable(),
-            ArrowStreamState::WriterWantWrite => event_set.is_writable(),
-            _ => false
-        }
-    }
-    
-    fn take_socket_error(&self) -> io::Result<()> {
-        self.stream.get_ref()
-            .take_socket_error()
-    }
-}
-
-/// TCP stream abstraction for ignoring EWOULDBLOCKs.
-struct ServiceStream {
-    /// TCP stream.
-    stream: TcpStream,
-}
-
-impl ServiceStream {
-    /// Connect to a given TCP socket address.
-    fn connect(addr: &SocketAddr) -> io::Result<ServiceStream> {
-        let stream = try!(TcpStream::connect(addr));
-        let res    = ServiceStream {
-            stream: stream
-        };
-        
-        Ok(res)
-    }
-    
-    /// Get reference to the underlaying TCP stream.
-    fn get_ref(&self) -> &TcpStream {
-        &self.stream
-    }
-    
-    /// Take error from the underlaying TCP stream.
-    fn take_socket_error(&self) -> io::Result<()> {
-        self.stream.take_socket_error()
-    }
-}
-
-impl Read for ServiceStream {
-    /// Read data from the underlaying socket (EWOULDBLOCK is silently 
-    /// ignored).
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.stream.read(buf) {
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
-            other => other
-        }
-    }
-}
-
-impl Write for ServiceStream {
-    /// Write data into the underlaying socket (EWOULDBLOCK is silently 
-    /// ignored).
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.stream.write(buf) {
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
-            other => other
-        }
-    }
-    
-    /// Flush buffered data into the underlaying socket (EWOULDBLOCK is not
-    /// ignored in this case).
-    fn flush(&mut self) -> io::Result<()> {
-        self.stream.flush()
-    }
-}
-
-/// External service session context.
-/// 
-/// This struct holds connection to an external service (e.g. RTSP) and 
-/// its I/O buffers.
-struct SessionContext<L: Logger> {
-    /// Logger.
-    #[allow(dead_code)]
-    logger:        L,
-    /// Service ID.
-    service_id:    u16,
-    /// Session ID.
-    session_id:    u32,
-    /// TCP stream.
-    stream:        ServiceStream,
-    /// Input buffer.
-    input_buffer:  WriteBuffer,
-    /// Output buffer.
-    output_buffer: WriteBuffer,
-    /// Read buffer.
-    read_buffer:   Box<[u8]>,
-    /// Write timeout.
-    write_tout:    Timeout,
-}
+/// Command sender ignoring all commands.
+#[derive(Debug, Copy, Clone)]
+pub struct DummySender;
 
-impl<L: Logger> SessionContext<L> {
-    /// Create a new session context for a given session ID and service 
-    /// address.
-    fn new<T: Handler>(
-        logger:     L,
-        service_id: u16,
-        session_id: u32, 
-        addr: &SocketAddr,
-        event_loop: &mut EventLoop<T>) -> Result<SessionContext<L>> {
-        let stream = try_svc_io!(ServiceStream::connect(addr));
-        
-        register_socket(session2token(session_id), stream.get_ref(), 
-            true, true, event_loop);
-        
-        let res = SessionContext {
-            logger:        logger,
-            service_id:    service_id,
-            session_id:    session_id,
-            stream:        stream,
-            input_buffer:  WriteBuffer::new(256 * 1024),
-            output_buffer: WriteBuffer::new(0),
-            read_buffer:   Box::new([0u8; 32768]),
-            write_tout:    Timeout::new()
-        };
-        
-        Ok(res)
-    }
-    
-    /// Dispose resources held by this object.
-    fn dispose<T: Handler>(&self, event_loop: &mut EventLoop<T>) {
-        deregister_socket(self.stream.get_ref(), event_loop);
-    }
-    
-    /// Enable/disable notifications for the underlaying socket.
-    fn update_socket_events<T: Handler>(
-        &mut self, 
-        event_loop: &mut EventLoop<T>) {
-        let readable = !self.input_buffer.is_full();
-        let writable = !self.output_buffer.is_empty();
-        reregister_socket(
-            session2token(self.session_id), 
-            self.stream.get_ref(), 
-            readable, writable, event_loop);
-    }
-    
-    /// Process a given set of socket events and return size of the input 
-    /// buffer or None in case the connection has been closed.
-    fn socket_ready<T: Handler>(
-        &mut self, 
-        event_loop: &mut EventLoop<T>, 
-        event_set: EventSet) -> Result<Option<usize>> {
-        let read = try_arr!(self.check_read_event(event_loop, event_set));
-        
-        try_arr!(self.check_write_event(event_loop, event_set));
-        
-        if event_set.is_error() {
-            let err = self.get_socket_error()
-                .ok_or(ArrowError::other("socket error expected"));
-            Err(try_arr!(err))
-        } else if event_set.is_hup() && read == 0 {
-            Ok(None)
-        } else {
-            Ok(Some(self.input_buffer.buffered()))
-        }
-    }
-    
-    /// Read a message if the underlaying socket is readable and the input 
-    /// buffer is not already full. Return the number of bytes read.
-    fn check_read_event<T: Handler>(
-        &mut self, 
-        event_loop: &mut EventLoop<T>, 
-        event_set: EventSet) -> Result<usize> {
-        if event_set.is_readable() {
-            if !self.input_buffer.is_full() || event_set.is_hup() {
-                let buffer = &mut *self.read_buffer;
-                let len    = try_svc_io!(self.stream.read(buffer));
-                self.input_buffer.write_all(&buffer[..len])
-                    .unwrap();
-                
-                //log_debug!(self.logger, "{} bytes read from session socket {:08x} (buffer size: {})", len, self.session_id, self.input_buffer.buffered());
-                
-                return Ok(len);
-            } else {
-                self.update_socket_events(event_loop);
-            }
-        }
-        
-        Ok(0)
-    }
-    
-    /// Write data from the output buffer into the underlaying socket if the 
-    /// socket is writable.
-    fn check_write_event<T: Handler>(
-        &mut self, 
-        event_loop: &mut EventLoop<T>, 
-        event_set: EventSet) -> Result<()> {
-        if event_set.is_writable() {
-            if self.output_buffer.is_empty() {
-                self.update_socket_events(event_loop);
-                self.write_tout.clear();
-            } else {
-                let len = try_svc_io!(self.stream.write(
-                    self.output_buffer.as_bytes()));
-                
-                if len > 0 {
-                    //log_debug!(self.logger, "{} bytes written into session socket {:08x} (buffer size: {})", len, self.session_id, self.output_buffer.buffered());
-                    self.output_buffer.drop(len);
-                    self.write_tout.set(CONNECTION_TIMEOUT);
-                }
-            }
-        }
-        
+impl Sender<Command> for DummySender {
+    fn send(&self, _: Command) -> result::Result<(), Command> {
         Ok(())
     }
-    
-    /// Get socket error.
-    fn get_socket_error(&self) -> Option<ArrowError> {
-        let err = self.stream.take_socket_error();
-        match err.err() {
-            Some(err) => Some(ArrowError::service_connection_error(err)),
-            None      => None
-        }
-    }
-    
-    /// Check if there are some data in the input buffer.
-    fn input_ready(&self) -> bool {
-        !self.input_buffer.is_empty()
-    }
-    
-    /// Get buffered input data.
-    fn input_buffer(&self) -> &[u8] {
-        self.input_buffer.as_bytes()
-    }
-    
-    /// Drop a given number of bytes from the input buffer.
-    fn drop_input_bytes<T: Handler>(
-        &mut self, 
-        count: usize, 
-        event_loop: &mut EventLoop<T>) {
-        let was_full = self.input_buffer.is_full();
-        
-        self.input_buffer.drop(count);
-        
-        if was_full && !self.input_buffer.is_full() {
-            self.update_socket_events(event_loop);
-        }
-    }
-    
-    /// Send a given message.
-    fn send_message<T: Handler>(
-        &mut self, 
-        data: &[u8], 
-        event_loop: &mut EventLoop<T>) {
-        let was_empty = self.output_buffer.is_empty();
-        
-        self.output_buffer.write_all(data)
-            .unwrap();
-        
-        if was_empty {
-            self.write_tout.set(CONNECTION_TIMEOUT);
-            self.update_socket_events(event_loop);
-        }
-    }
-}
-
-/// Convert a given session ID into a token (socket) ID.
-fn session2token(session_id: u32) -> usize {
-    assert!(mem::size_of::<usize>() >= 4);
-    (session_id as usize) | (1 << 24)
-}
-
-/// Convert a given token (socket) ID into a session ID.
-fn token2session(token_id: usize) -> u32 {
-    assert!(mem::size_of::<usize>() >= 4);
-    let mask = ((1 as usize) << 24) - 1;
-    assert!((token_id & !mask) == (1 << 24));
-    (token_id & mask) as u32
-}
-
-/// Arrow Protocol states.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum ProtocolState {
-    Handshake,
-    Established
 }
 
 type SocketEventResult = Result<Option<String>>;
 
 const UPDATE_CHECK_PERIOD:  u64 = 5000;
 const TIMEOUT_CHECK_PERIOD: u64 = 1000;
-const PING_PERIOD:          u64 = 60000;
+const BUFFER_CHECK_PERIOD:  u64 = 1000;
+
+const SPIN_MAX_EVENTS:      usize = 10000;
+const SPIN_WINDOW:          u64   = 1000;
+const SPIN_BACKOFF:         u64   = 100;
+
+const CLOCK_JUMP_THRESHOLD: i64   = 60;
+
+// maximum duration of a log level change requested by Arrow Service (in
+// seconds)
+const MAX_LOG_LEVEL_DURATION: u64 = 86400;
 
-const CONNECTION_TIMEOUT:   u64 = 20000;
+// the offending message is dumped for the first few protocol violations and
+// then only for every n-th one
+const VIOLATION_DUMP_COUNT:  usize = 10;
+const VIOLATION_DUMP_PERIOD: usize = 100;
 
 /// Arrow client connection handler.
 struct ConnectionHandler<L: Logger, Q: Sender<Command>> {
@@ -522,69 +220,123 @@ struct ConnectionHandler<L: Logger, Q: Sender<Command>> {
     app_context:   Shared<AppContext>,
     /// Channel for sending Arrow Commands.
     cmd_sender:    Q,
-    /// SSL/TLS connection to a remote Arrow Service.
-    stream:        ArrowStream,
-    /// Session contexts.
-    sessions:      HashMap<u32, SessionContext<L>>,
-    /// Session read queue.
-    session_queue: VecDeque<u32>,
-    /// Buffer for reading Arrow Protocol requests.
-    read_buffer:   Box<[u8]>,
-    /// Buffer for writing Arrow Protocol responses.
-    write_buffer:  Box<[u8]>,
+    /// Connection to a remote Arrow Service.
+    uplink:        Uplink,
+    /// Control Protocol channel.
+    control:       ControlChannel,
+    /// Service sessions.
+    router:        SessionRouter<L>,
     /// Parser for requests received from Arrow Service.
     req_parser:    ArrowMessageParser,
-    /// Output buffer for messages to be passed to Arrow Service.
-    output_buffer: WriteBuffer,
     /// Arrow Client result returned after the connection shut down.
     result:        Option<Result<String>>,
-    /// Protocol state.
-    state:         ProtocolState,
-    /// Version of the last sent service table.
-    last_update:   Option<usize>,
-    /// Write timeout.
-    write_tout:    Timeout,
-    /// ACK timeout.
-    ack_tout:      Timeout,
-    /// Current Control Message ID.
-    msg_id:        u16,
-    /// Expected ACKs.
-    expected_acks: VecDeque<u16>,
+    /// Busy-loop detector.
+    spin:          SpinDetector,
+    /// The Arrow Service certificate has been accepted in the clock grace
+    /// mode.
+    clock_behind:  bool,
+    /// Difference between the system time and the monotonic time (in
+    /// seconds) at the time of connection.
+    clock_offset:  i64,
+    /// Connection trace ID (if message tracing is enabled).
+    trace_id:      Option<String>,
+    /// System suspend detector.
+    suspend:       SuspendDetector,
+    /// Client MAC address.
+    arrow_mac:     MacAddr,
+    /// Time of the last Arrow Service socket activity (in milliseconds).
+    last_activity: u64,
+    /// Message ID of the last PING waiting for ACK and the idle interval
+    /// before the PING (in milliseconds).
+    ping:          Option<(u16, u64)>,
+    /// Per-service bitrate monitor.
+    bitrate:       BitrateMonitor,
+    /// Sender of RTSP bitrate hints.
+    hints:         HintSender,
+    /// HUP message limiter.
+    hup_limiter:   HupLimiter,
+    /// Sessions waiting for a session authorizer decision.
+    pending:       HashMap<u32, PendingSession>,
+    /// Arrow output buffer occupancy alarm.
+    uplink_alarm:  OccupancyAlarm,
 }
 
 impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
-    /// Create a new connection handler.
-    fn new<S: IntoSsl>(
+    /// Create a new connection handler for a given Arrow Service uplink.
+    fn new(
         logger: L,
-        s: S, 
+        mut uplink: Uplink,
         cmd_sender: Q,
-        addr: &SocketAddr, 
         arrow_mac: &MacAddr,
         app_context: Shared<AppContext>, 
         event_loop: &mut EventLoop<Self>) -> Result<Self> {
-        let stream = try_arr!(ArrowStream::connect(s, addr, 0, event_loop));
+        let (clock_behind, trace_ids, ack_timeout, session_write_timeout, session_buffer_size, first_msg_id, max_bitrate) = {
+            let mut app_context = app_context.lock()
+                .unwrap();
+            
+            // sessions of the previous connection are gone
+            app_context.connection_started();
+            
+            uplink.set_write_timeout(app_context.uplink_write_timeout);
+            uplink.set_buffer_size(app_context.uplink_buffer_size);
+            
+            (app_context.clock_behind, app_context.trace_ids,
+                app_context.ack_timeout, app_context.session_write_timeout,
+                app_context.session_buffer_size, app_context.next_msg_id, app_context.max_bitrate)
+        };
+        
+        let trace_id = if trace_ids {
+            Some(new_trace_id())
+        } else {
+            None
+        };
         
         let mut res = ConnectionHandler {
-            logger:        logger,
+            logger:        logger.clone(),
             app_context:   app_context,
             cmd_sender:    cmd_sender,
-            stream:        stream,
-            sessions:      HashMap::new(),
-            session_queue: VecDeque::new(),
-            read_buffer:   Box::new([0u8; 32768]),
-            write_buffer:  Box::new([0u8; 16384]),
+            uplink:        uplink,
+            control:       ControlChannel::starting_at(first_msg_id),
+            router:        SessionRouter::new(logger),
             req_parser:    ArrowMessageParser::new(),
-            output_buffer: WriteBuffer::new(256 * 1024),
             result:        None,
-            state:         ProtocolState::Handshake,
-            last_update:   None,
-            write_tout:    Timeout::new(),
-            ack_tout:      Timeout::new(),
-            msg_id:        0,
-            expected_acks: VecDeque::new()
+            spin:          SpinDetector::new(SPIN_MAX_EVENTS, SPIN_WINDOW),
+            clock_behind:  clock_behind,
+            clock_offset:  get_clock_offset(),
+            trace_id:      trace_id,
+            suspend:       SuspendDetector::new(),
+            arrow_mac:     *arrow_mac,
+            last_activity: get_time_ms(),
+            ping:          None,
+            bitrate:       BitrateMonitor::new(max_bitrate),
+            hints:         HintSender::new(),
+            hup_limiter:   HupLimiter::new(),
+            pending:       HashMap::new(),
+            uplink_alarm:  OccupancyAlarm::new()
         };
         
-        res.create_register_request(arrow_mac, event_loop);
+        res.control.set_ack_timeout(ack_timeout);
+        res.router.set_write_timeout(session_write_timeout);
+        res.router.set_buffer_size(session_buffer_size);
+        
+        #[cfg(feature = "conntrack")]
+        {
+            let flush_conntrack = res.app_context.lock()
+                .unwrap()
+                .flush_conntrack;
+            
+            res.router.set_flush_conntrack(flush_conntrack);
+        }
+        
+        if let Some(ref trace_id) = res.trace_id {
+            log_info!(res.logger, "connection trace ID: {}", trace_id);
+        }
+        
+        if clock_behind {
+            log_warn!(res.logger, "Arrow Service certificate is not valid yet, the system clock is probably behind; all service requests will be refused until the clock is fixed");
+        }
+        
+        try_arr!(res.send_register_request(None, event_loop));
         
         // start timeout checker:
         event_loop.timeout_ms(
@@ -595,161 +347,669 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         Ok(res)
     }
     
-    /// Get session context for a given session ID.
-    fn get_session_context(
-        &self, 
-        session_id: u32) -> Option<&SessionContext<L>> {
-        self.sessions.get(&session_id)
-    }
-    
-    /// Get session context for a given session ID.
-    fn get_session_context_mut(
-        &mut self, 
-        session_id: u32) -> Option<&mut SessionContext<L>> {
-        self.sessions.get_mut(&session_id)
-    }
-    
     /// Create a new session context for a given service and session IDs.
+    /// The method returns a HUP error code if the session could not be
+    /// created. The request is needed for any-TCP sessions only (the target
+    /// address preamble is removed from it). If there is a session
+    /// authorizer, the session is only marked as pending and it is opened
+    /// once the decision arrives.
     fn create_session_context(
         &mut self, 
         service_id: u16, 
         session_id: u32, 
-        event_loop: &mut EventLoop<Self>) -> Option<&mut SessionContext<L>> {
-        if !self.sessions.contains_key(&session_id) {
+        request: &mut Vec<u8>,
+        event_loop: &mut EventLoop<Self>) -> result::Result<(), u32> {
+        if self.router.contains(session_id) || self.pending.contains_key(&session_id) {
+            return Ok(());
+        }
+        
+        let (svc, connect_timeout, draining, e2e_required, authorizer) = {
             let app_context = self.app_context.lock()
                 .unwrap();
-            let config = &app_context.config;
-            if let Some(svc) = config.get(service_id) {
-                if let Some(addr) = svc.address() {
-                    log_info!(self.logger, "connecting to remote service: {}, service ID: {:04x}, session ID: {:08x}", addr, service_id, session_id);
-                    match SessionContext::new(self.logger.clone(),
-                        service_id, session_id, addr, event_loop) {
-                        Err(err) => log_warn!(self.logger, "unable to open connection to a remote service (address: {}, service ID: {:04x}, session ID: {:08x}): {}", addr, service_id, session_id, err.description()),
-                        Ok(ctx)  => {
-                            let token_id = session2token(session_id);
-                            let tevent   = TimerEvent::TimeoutCheck(token_id);
-                            self.sessions.insert(session_id, ctx);
-                            self.session_queue.push_back(session_id);
-                            event_loop.timeout_ms(tevent, TIMEOUT_CHECK_PERIOD)
-                                .unwrap();
-                        }
-                    }
-                } else {
+            let svc = app_context.config.get(service_id);
+            let e2e_required = svc.as_ref()
+                .map_or(false, |svc| app_context.requires_e2e(svc));
+            (svc,
+                app_context.service_connect_timeout,
+                app_context.drain_deadline.is_some(),
+                e2e_required,
+                app_context.session_authorizer.clone())
+        };
+        
+        if draining {
+            log_info!(self.logger, "session refused, the client is being replaced by a new process (service ID: {:04x}, session ID: {:08x})", service_id, session_id);
+            return Err(HUP_SERVICE_UNREACHABLE);
+        }
+        
+        let svc = match svc {
+            Some(svc) => svc,
+            None      => {
+                log_warn!(self.logger, "non-existing service requested (service ID: {}, session ID: {:08x})", service_id, session_id);
+                return Err(HUP_SESSION_ERROR);
+            }
+        };
+        
+        let addr = match svc {
+            Service::AnyTCP => {
+                try!(self.any_tcp_target(service_id, session_id, request))
+            },
+            ref svc => match svc.address() {
+                Some(addr) => *addr,
+                None       => {
                     log_warn!(self.logger, "requested service ID belongs to a Control Protocol service (session ID: {:08x})", session_id);
+                    return Err(HUP_SESSION_ERROR);
                 }
-            } else {
-                log_warn!(self.logger, "non-existing service requested (service ID: {}, session ID: {:08x})", service_id, session_id);
             }
-        }
+        };
         
-        self.sessions.get_mut(&session_id)
-    }
-    
-    /// Remove session context with a given session ID.
-    fn remove_session_context(
-        &mut self, 
-        session_id: u32,
-        event_loop: &mut EventLoop<Self>) {
-        if let Some(ctx) = self.sessions.remove(&session_id) {
-            ctx.dispose(event_loop);
+        let access = self.app_context.lock()
+            .unwrap()
+            .access_policy
+            .check(&addr, self.router.service_sessions(service_id));
+        
+        if let Err(violation) = access {
+            log_warn!(self.logger, "session refused by the access policy: {} (address: {}, service ID: {:04x}, session ID: {:08x})", violation, addr, service_id, session_id);
+            return Err(HUP_ACCESS_DENIED);
         }
-    }
-    
-    /// Create a new REGISTER request.
-    fn create_register_request(
-        &mut self, 
-        arrow_mac: &MacAddr, 
-        event_loop: &mut EventLoop<Self>) {
-        let control_msg = {
+        
+        let (basic_auth, untrusted) = {
             let app_context = self.app_context.lock()
                 .unwrap();
-            let config    = &app_context.config;
-            let svc_table = config.service_table()
-                .clone();
-            let msg    = RegisterMessage::new(
-                config.uuid(),
-                arrow_mac.octets(),
-                config.password(),
-                svc_table);
-            let control_msg = control::create_register_message(self.msg_id, 
-                msg);
-            self.last_update = Some(config.version());
-            self.msg_id = self.msg_id.wrapping_add(1);
-            control_msg
+            (app_context.scan_report.is_basic_auth(&addr),
+                app_context.untrusted_interfaces.clone())
         };
         
-        log_debug!(self.logger, "sending REGISTER request...");
-        
-        self.send_unconfirmed_control_message(control_msg, event_loop);
-    }
-    
-    /// Send an update message (if needed) and schedule the next update event.
-    fn send_update_message(
-        &mut self,
-        svc_table: ServiceTable,
-        event_loop: &mut EventLoop<Self>) {
-        let control_msg = control::create_update_message(self.msg_id, 
-            svc_table);
-            
-        self.msg_id = self.msg_id.wrapping_add(1);
-        
-        log_debug!(self.logger, "sending an UPDATE message...");
-        
-        self.send_control_message(control_msg, event_loop);
-    }
-    
-    /// Send the PING message and schedule the next PING event.
-    fn send_ping_message(&mut self, event_loop: &mut EventLoop<Self>) {
-        let control_msg = control::create_ping_message(self.msg_id);
-        
-        self.msg_id = self.msg_id.wrapping_add(1);
+        let untrusted_iface = if basic_auth {
+            untrusted.interface_of(&addr.ip())
+        } else {
+            None
+        };
         
-        log_debug!(self.logger, "sending a PING message...");
+        if let Some(iface) = untrusted_iface {
+            log_warn!(self.logger, "security advisory: session refused, the service asks for plaintext (Basic) credentials within the untrusted network of interface {} (address: {}, service ID: {:04x}, session ID: {:08x})", iface, addr, service_id, session_id);
+            return Err(HUP_ACCESS_DENIED);
+        }
         
-        self.send_unconfirmed_control_message(control_msg, event_loop);
-    }
-    
-    /// Send HUP message for a given session ID.
-    fn send_hup_message(
-        &mut self, 
-        session_id: u32, 
-        error_code: u32, 
-        event_loop: &mut EventLoop<Self>) {
-        let control_msg = control::create_hup_message(self.msg_id, 
-            session_id, error_code);
+        let hold_down = self.app_context.lock()
+            .unwrap()
+            .flap_damping
+            .check(service_id);
         
-        self.msg_id = self.msg_id.wrapping_add(1);
+        if let Some(remaining) = hold_down {
+            log_debug!(self.logger, "session refused, the service is held down for another {} seconds (service ID: {:04x}, session ID: {:08x})", remaining, service_id, session_id);
+            return Err(HUP_SERVICE_UNREACHABLE);
+        }
         
-        log_debug!(self.logger, "sending a HUP message (session ID: {:08x}, error_code: {:08x})...", session_id, error_code);
+        if e2e_required && !self.router.is_e2e() {
+            log_warn!(self.logger, "session refused, the service requires end-to-end encryption which is not supported by Arrow Service (service ID: {:04x}, session ID: {:08x})", service_id, session_id);
+            return Err(HUP_ACCESS_DENIED);
+        }
         
-        self.send_control_message(control_msg, event_loop);
+        if let Some(authorizer) = authorizer {
+            log_debug!(self.logger, "waiting for session authorization (address: {}, service ID: {:04x}, session ID: {:08x})", addr, service_id, session_id);
+            
+            let request = SessionRequest {
+                service_id: service_id,
+                session_id: session_id,
+                service:    svc.clone(),
+                addr:       addr
+            };
+            
+            self.pending.insert(session_id, PendingSession::new(
+                service_id, svc, addr, get_time_ms() + connect_timeout));
+            
+            let callback = DecisionCallback::new(session_id, 
+                event_loop.channel());
+            
+            authorizer.authorize(request, callback);
+            
+            let tevent = TimerEvent::AuthorizeCheck(session_id);
+            event_loop.timeout_ms(tevent, connect_timeout)
+                .unwrap();
+            
+            Ok(())
+        } else {
+            self.open_session(service_id, session_id, svc, addr, event_loop)
+        }
     }
     
-    /// Send status message for a given request ID.
-    fn send_status(
+    /// Open a new session to a given service address. The method returns a
+    /// HUP error code if the session could not be opened.
+    fn open_session(
         &mut self,
-        request_id: u16,
-        event_loop: &mut EventLoop<Self>) {
-        let active_sessions  = self.sessions.len() as u32;
-        let mut status_flags = 0;
-        
-        {
+        service_id: u16,
+        session_id: u32,
+        svc: Service,
+        addr: SocketAddr,
+        event_loop: &mut EventLoop<Self>) -> result::Result<(), u32> {
+        let (connect_timeout, policies, options) = {
             let app_context = self.app_context.lock()
                 .unwrap();
-            
-            if app_context.scanning {
-                status_flags |= control::STATUS_FLAG_SCAN;
-            }
-        }
+            (app_context.service_connect_timeout,
+                app_context.overload_policies,
+                app_context.session_options(&svc))
+        };
         
-        let status_msg = StatusMessage::new(request_id, 
-            status_flags, active_sessions);
-        let control_msg = control::create_status_message(self.msg_id,
-            status_msg);
+        let policy = match ServiceClass::of(&svc) {
+            Some(class) => policies.get(class),
+            None        => OverloadPolicy::Block
+        };
         
-        self.msg_id = self.msg_id.wrapping_add(1);
+        if options.datagram {
+            log_info!(self.logger, "opening datagram session to remote service: {}, service ID: {:04x}, session ID: {:08x}", addr, service_id, session_id);
+        } else if options.sandboxed {
+            log_info!(self.logger, "connecting to remote service using a sandbox helper: {}, service ID: {:04x}, session ID: {:08x}", addr, service_id, session_id);
+        } else {
+            self.log_connect(&addr, service_id, session_id, &options);
+        }
         
-        log_debug!(self.logger, "sending a STATUS message...");
+        match self.router.open(service_id, session_id, &addr, &options,
+            connect_timeout, policy, event_loop) {
+            Err(err) => {
+                log_warn!(self.logger, "unable to open connection to a remote service (address: {}, service ID: {:04x}, session ID: {:08x}): {}", addr, service_id, session_id, err.description());
+                self.app_context.lock()
+                    .unwrap()
+                    .svc_stats
+                    .connect_failed(service_id, err.description());
+                self.service_failed(service_id);
+                Err(HUP_SESSION_ERROR)
+            },
+            Ok(_)    => {
+                self.hup_limiter.session_opened(session_id);
+                {
+                    let mut app_context = self.app_context.lock()
+                        .unwrap();
+                    
+                    app_context.session_opened(session_id, service_id);
+                }
+                let token_id = session2token(session_id);
+                let tevent   = TimerEvent::TimeoutCheck(token_id);
+                event_loop.timeout_ms(tevent, TIMEOUT_CHECK_PERIOD)
+                    .unwrap();
+                let tevent   = TimerEvent::ConnectCheck(token_id);
+                event_loop.timeout_ms(tevent, connect_timeout)
+                    .unwrap();
+                // send the end-to-end encryption header (if any)
+                self.check_session_input(session_id, event_loop);
+                Ok(())
+            }
+        }
+    }
+    
+    /// Log a TCP connection attempt to a given service address (through
+    /// the SOCKS5 proxy from given session options if any).
+    #[cfg(feature = "socks")]
+    fn log_connect(
+        &mut self,
+        addr: &SocketAddr,
+        service_id: u16,
+        session_id: u32,
+        options: &SessionOptions) {
+        if let Some(ref proxy) = options.proxy {
+            log_info!(self.logger, "connecting to remote service through SOCKS proxy {}: {}, service ID: {:04x}, session ID: {:08x}", proxy.address(), addr, service_id, session_id);
+        } else {
+            log_info!(self.logger, "connecting to remote service: {}, service ID: {:04x}, session ID: {:08x}", addr, service_id, session_id);
+        }
+    }
+    
+    /// Log a TCP connection attempt to a given service address (dummy).
+    #[cfg(not(feature = "socks"))]
+    fn log_connect(
+        &mut self,
+        addr: &SocketAddr,
+        service_id: u16,
+        session_id: u32,
+        _: &SessionOptions) {
+        log_info!(self.logger, "connecting to remote service: {}, service ID: {:04x}, session ID: {:08x}", addr, service_id, session_id);
+    }
+    
+    /// Get target address of a new any-TCP session and remove the target
+    /// address preamble from a given request. A HUP error code is returned if
+    /// the target address is invalid or if it is not allowed.
+    fn any_tcp_target(
+        &mut self,
+        service_id: u16,
+        session_id: u32,
+        request: &mut Vec<u8>) -> result::Result<SocketAddr, u32> {
+        let (addr, len) = match any_tcp::parse_target(request) {
+            Ok(target) => target,
+            Err(err)   => {
+                log_warn!(self.logger, "invalid any-TCP session request (service ID: {:04x}, session ID: {:08x}): {}", service_id, session_id, err);
+                return Err(HUP_SESSION_ERROR);
+            }
+        };
+        
+        let allowed = self.app_context.lock()
+            .unwrap()
+            .tcp_allowlist
+            .allows(&addr);
+        
+        if allowed {
+            request.drain(..len);
+            Ok(addr)
+        } else {
+            log_warn!(self.logger, "any-TCP session refused, target address {} is not allowed (service ID: {:04x}, session ID: {:08x})", addr, service_id, session_id);
+            Err(HUP_ACCESS_DENIED)
+        }
+    }
+    
+    /// Record a successful connection of a given session into the service
+    /// statistics.
+    fn session_connected(&mut self, session_id: u32) {
+        let service_id = match self.router.get(session_id) {
+            Some(ctx) => ctx.service_id(),
+            None      => return
+        };
+        
+        self.app_context.lock()
+            .unwrap()
+            .svc_stats
+            .connect_succeeded(service_id);
+        
+        self.fire_hook(HookEvent::SessionOpened, &[
+            ("ARROW_SERVICE_ID", format!("{}", service_id)),
+            ("ARROW_SESSION_ID", format!("{:08x}", session_id))]);
+    }
+    
+    /// Record frames dropped by a given session into the service
+    /// statistics.
+    fn session_dropped_frames(
+        &mut self, 
+        session_id: u32, 
+        frames: usize, 
+        bytes: usize) {
+        let service_id = match self.router.get(session_id) {
+            Some(ctx) => ctx.service_id(),
+            None      => return
+        };
+        
+        log_debug!(self.logger, "session overloaded, {} frames ({} bytes) dropped (session ID: {:08x})", frames, bytes, session_id);
+        
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        app_context.svc_stats
+            .frames_dropped(service_id, frames, bytes);
+        
+        #[cfg(feature = "metrics")]
+        {
+            app_context.metrics
+                .frames_dropped(service_id, frames, bytes);
+        }
+    }
+    
+    /// Record result of a given session into the service statistics. The 
+    /// method needs to be called before the session is removed.
+    fn session_finished(&mut self, session_id: u32, err: Option<&str>) {
+        let (service_id, lifetime, transferred, high_water, sequence) =
+            match self.router.get(session_id) {
+                Some(ctx) => (ctx.service_id(), ctx.lifetime(),
+                    ctx.transferred(), ctx.high_water(),
+                    ctx.sequence_stats()),
+                None      => return
+            };
+        
+        #[cfg(feature = "recording")]
+        {
+            if let Some(tap) = self.router.get(session_id).and_then(|ctx| ctx.tap()) {
+                log_info!(self.logger, "session tap closed, {} bytes written into {}-*.bin (session ID: {:08x})", tap.written(), tap.prefix(), session_id);
+            }
+        }
+        
+        if sequence.lost > 0 || sequence.reordered > 0 {
+            log_debug!(self.logger, "data frames lost: {}, reordered: {} (session ID: {:08x})", sequence.lost, sequence.reordered, session_id);
+        }
+        
+        {
+            let mut guard = self.app_context.lock()
+                .unwrap();
+            
+            let app_context = &mut *guard;
+            
+            app_context.data_sequence.add(&sequence);
+            
+            #[cfg(feature = "metrics")]
+            {
+                app_context.metrics.sequence_finished(&sequence);
+            }
+            
+            app_context.session_closed(session_id, err.is_some());
+            
+            let svc_stats = &mut app_context.svc_stats;
+            
+            match (lifetime, err) {
+                (Some(lifetime), err) => 
+                    svc_stats.session_closed(service_id, lifetime, err),
+                (None, Some(err)) => svc_stats.connect_failed(service_id, err),
+                (None, None)      => ()
+            }
+            
+            if let Some(ref mut stats_export) = app_context.stats_export {
+                stats_export.record_session(SessionRecord::new(
+                    service_id, session_id, lifetime, transferred,
+                    high_water, err));
+            }
+        }
+        
+        match (lifetime, err) {
+            (Some(lifetime), _) if lifetime >= flap::MIN_STABLE_LIFETIME =>
+                self.app_context.lock()
+                    .unwrap()
+                    .flap_damping
+                    .stable(service_id),
+            (_, Some(_)) => self.service_failed(service_id),
+            _ => ()
+        }
+        
+        // only sessions reported as opened are reported as closed
+        if lifetime.is_some() {
+            let mut env = vec![
+                ("ARROW_SERVICE_ID", format!("{}", service_id)),
+                ("ARROW_SESSION_ID", format!("{:08x}", session_id))];
+            
+            if let Some(err) = err {
+                env.push(("ARROW_ERROR", err.to_string()));
+            }
+            
+            self.fire_hook(HookEvent::SessionClosed, &env);
+        }
+    }
+    
+    /// Record a given number of bytes received by a given session into the
+    /// bitrate monitor and report the service if it exceeds the bitrate cap.
+    fn session_received(&mut self, session_id: u32, bytes: usize) {
+        let service_id = match self.router.get(session_id) {
+            Some(ctx) => ctx.service_id(),
+            None      => return
+        };
+        
+        if let Some(bitrate) = self.bitrate.record(service_id, bytes) {
+            self.service_bitrate_exceeded(service_id, bitrate);
+        }
+    }
+    
+    /// Report a service exceeding the bitrate cap and send the bitrate hint
+    /// if enabled.
+    fn service_bitrate_exceeded(&mut self, service_id: u16, bitrate: u32) {
+        let max_bitrate = self.bitrate.limit()
+            .unwrap_or(0);
+        
+        log_warn!(self.logger, "service exceeds the bitrate cap, {} kbit/s measured, {} kbit/s allowed (service ID: {:04x})", bitrate, max_bitrate, service_id);
+        
+        self.fire_hook(HookEvent::BitrateExceeded, &[
+            ("ARROW_SERVICE_ID", format!("{}", service_id)),
+            ("ARROW_BITRATE", format!("{}", bitrate)),
+            ("ARROW_MAX_BITRATE", format!("{}", max_bitrate))]);
+        
+        let (svc, hint) = {
+            let app_context = self.app_context.lock()
+                .unwrap();
+            
+            (app_context.config.get(service_id),
+                app_context.bitrate_hint.clone())
+        };
+        
+        if let (Some(Service::RTSP(_, addr, path)), Some(param)) = (svc, hint) {
+            log_info!(self.logger, "asking {}{} for a lower bitrate (service ID: {:04x})", addr, path, service_id);
+            
+            self.hints.send(addr, &path, &param, max_bitrate);
+        }
+    }
+    
+    /// Log results of the finished bitrate hints.
+    fn check_bitrate_hints(&mut self) {
+        for res in self.hints.results() {
+            match res {
+                Ok(msg)  => log_info!(self.logger, "{}", msg),
+                Err(msg) => log_warn!(self.logger, "{}", msg)
+            }
+        }
+    }
+    
+    /// Record a failure of a given service into the flap detector and hold
+    /// down the service if it is flapping.
+    fn service_failed(&mut self, service_id: u16) {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        if let Some(hold_down) = app_context.flap_damping.failure(service_id) {
+            log_warn!(self.logger, "service is flapping, holding it down for {} seconds (service ID: {:04x})", hold_down, service_id);
+            
+            app_context.svc_stats
+                .held_down(service_id, hold_down);
+        }
+    }
+    
+    /// Execute hooks of a given event with given environment variables.
+    fn fire_hook(&mut self, event: HookEvent, env: &[(&str, String)]) {
+        let res = self.app_context.lock()
+            .unwrap()
+            .hooks
+            .fire(event, env);
+        
+        if let Err(err) = res {
+            log_warn!(self.logger, "unable to execute {} hook: {}", event.name(), err);
+        }
+    }
+    
+    /// Send a new REGISTER request.
+    fn send_register_request(
+        &mut self, 
+        challenge: Option<&[u8]>,
+        event_loop: &mut EventLoop<Self>) -> Result<()> {
+        let control_msg = {
+            let mut guard = self.app_context.lock()
+                .unwrap();
+            
+            let app_context = &mut *guard;
+            let paged       = app_context.table_pages;
+            let legacy      = app_context.legacy_register;
+            let arrow_mac   = &self.arrow_mac;
+            
+            let credential = try_arr!(app_context.auth_provider.credential(
+                &app_context.config, arrow_mac, challenge));
+            
+            if app_context.register_cache.is_valid(&app_context.config, arrow_mac, &credential.passwd, paged, legacy) {
+                log_debug!(self.logger, "using cached REGISTER message body");
+            }
+            
+            self.control.set_legacy_register(legacy);
+            
+            let msg = self.control.register_message(
+                &app_context.config,
+                arrow_mac,
+                &credential,
+                &mut app_context.register_cache,
+                paged);
+            
+            let config = &app_context.config;
+            
+            // an oversized table is not sent within REGISTER, the channel
+            // forces an UPDATE in such case
+            app_context.sent_svc_table = if self.control.update_needed(config.version()) {
+                Some(ServiceTable::new())
+            } else {
+                Some(config.service_table().clone())
+            };
+            
+            msg
+        };
+        
+        log_debug!(self.logger, "sending REGISTER request...");
+        
+        self.send_unconfirmed_control_message(control_msg, event_loop);
+        
+        Ok(())
+    }
+    
+    /// Send an UPDATE message with a given service table and metadata.
+    fn send_update_message(
+        &mut self,
+        version: usize,
+        svc_table: ServiceTable,
+        metadata: Metadata,
+        event_loop: &mut EventLoop<Self>) {
+        self.app_context.lock()
+            .unwrap()
+            .sent_svc_table = Some(svc_table.clone());
+        
+        if self.control.paging_needed(&svc_table) {
+            let messages = self.control.update_page_messages(
+                version, svc_table, metadata);
+            
+            log_debug!(self.logger, "sending the service table in {} UPDATE_PAGE messages...", messages.len());
+            
+            for control_msg in messages {
+                self.send_control_message(control_msg, event_loop);
+            }
+        } else {
+            let control_msg = self.control.update_message(
+                version, svc_table, metadata);
+            
+            log_debug!(self.logger, "sending an UPDATE message...");
+            
+            self.send_control_message(control_msg, event_loop);
+        }
+    }
+    
+    /// Send the PING message.
+    fn send_ping_message(&mut self, event_loop: &mut EventLoop<Self>) {
+        let control_msg = self.control.ping_message();
+        let idle        = get_time_ms() - self.last_activity;
+        
+        self.ping = Some((control_msg.header().msg_id, idle));
+        
+        log_debug!(self.logger, "sending a PING message...");
+        
+        self.send_unconfirmed_control_message(control_msg, event_loop);
+    }
+    
+    /// Enable uplink write events if a given session has some data to be
+    /// sent.
+    fn check_session_input(
+        &mut self,
+        session_id: u32,
+        event_loop: &mut EventLoop<Self>) {
+        let ready = self.router.get(session_id)
+            .map_or(false, |ctx| ctx.input_ready());
+        
+        if ready {
+            self.uplink.enable_write_events(event_loop);
+        }
+    }
+    
+    /// Send HUP message for a given (existing) session ID.
+    fn send_hup_message(
+        &mut self, 
+        session_id: u32, 
+        error_code: u32, 
+        event_loop: &mut EventLoop<Self>) {
+        let service_id = self.router.get(session_id)
+            .map(|ctx| ctx.service_id())
+            .unwrap_or(0);
+        
+        self.send_service_hup_message(service_id, session_id, error_code,
+            event_loop);
+    }
+    
+    /// Send HUP message for a given service ID and session ID. Duplicate HUP
+    /// messages are dropped and error HUP messages are rate limited if the
+    /// server accepts aggregated HUP reports.
+    fn send_service_hup_message(
+        &mut self, 
+        service_id: u16,
+        session_id: u32, 
+        error_code: u32, 
+        event_loop: &mut EventLoop<Self>) {
+        let aggregate = self.control.supports(FEATURE_HUP_REPORTS);
+        
+        match self.hup_limiter.check(service_id, session_id, error_code, aggregate) {
+            HupDecision::Send => (),
+            HupDecision::Duplicate => {
+                log_debug!(self.logger, "dropping a duplicate HUP message (session ID: {:08x}, error_code: {:08x})", session_id, error_code);
+                return;
+            },
+            HupDecision::Suppressed => {
+                log_debug!(self.logger, "HUP message suppressed, it will be reported within a HUP_REPORT message (service ID: {:04x}, session ID: {:08x}, error_code: {:08x})", service_id, session_id, error_code);
+                return;
+            }
+        }
+        
+        let control_msg = self.control.hup_message(session_id, error_code);
+        
+        log_debug!(self.logger, "sending a HUP message (session ID: {:08x}, error_code: {:08x})...", session_id, error_code);
+        
+        self.send_control_message(control_msg, event_loop);
+    }
+    
+    /// Send HUP_REPORT messages for all services with suppressed HUP
+    /// messages.
+    fn send_hup_reports(&mut self, event_loop: &mut EventLoop<Self>) {
+        for report in self.hup_limiter.take_reports() {
+            let service_id = report.service_id;
+            let failures   = report.failures;
+            let period     = report.period;
+            
+            log_warn!(self.logger, "service {:04x} failed {} sessions in {} seconds", service_id, failures, period);
+            
+            let control_msg = self.control.hup_report_message(report);
+            
+            log_debug!(self.logger, "sending a HUP_REPORT message...");
+            
+            self.send_control_message(control_msg, event_loop);
+        }
+    }
+    
+    /// Send status message for a given request ID.
+    fn send_status(
+        &mut self,
+        request_id: u16,
+        event_loop: &mut EventLoop<Self>) {
+        let active_sessions  = self.router.len() as u32;
+        let mut status_flags = 0;
+        let scan_summary;
+        let traffic;
+        
+        {
+            let app_context = self.app_context.lock()
+                .unwrap();
+            
+            if app_context.scanning {
+                status_flags |= control::STATUS_FLAG_SCAN;
+            }
+            
+            if self.clock_behind {
+                status_flags |= control::STATUS_FLAG_CLOCK_BEHIND;
+            }
+            
+            if app_context.scan_report.is_incomplete() {
+                status_flags |= control::STATUS_FLAG_SCAN_INCOMPLETE;
+            }
+            
+            scan_summary = app_context.scan_summary;
+            traffic      = app_context.uplink_traffic();
+        }
+        
+        let scan_summary = if self.control.supports(FEATURE_SCAN_SUMMARY) {
+            Some(&scan_summary)
+        } else {
+            None
+        };
+        
+        let mut status_msg = StatusMessage::new(request_id, 
+            status_flags, active_sessions, scan_summary);
+        
+        if self.control.supports(FEATURE_TRAFFIC_STATS) {
+            status_msg.set_traffic(&traffic);
+        }
+        
+        if self.control.supports(FEATURE_BUILD_INFO) {
+            status_msg.set_build_info(BuildInfo::current());
+        }
+        
+        let control_msg = self.control.status_message(status_msg);
+        
+        log_debug!(self.logger, "sending a STATUS message...");
         
         self.send_control_message(control_msg, event_loop);
     }
@@ -772,16 +1032,60 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
                     .clone());
         }
         
-        let control_msg = control::create_scan_report_message(self.msg_id,
-            scan_report);
-        
-        self.msg_id += 1;
+        let control_msg = self.control.scan_report_message(scan_report);
         
         log_debug!(self.logger, "sending a SCAN_REPORT message...");
         
         self.send_control_message(control_msg, event_loop);
     }
     
+    /// Send service statistics message for a given request ID.
+    fn send_svc_stats(
+        &mut self, 
+        request_id: u16, 
+        event_loop: &mut EventLoop<Self>) {
+        let svc_stats = self.app_context.lock()
+            .unwrap()
+            .svc_stats
+            .clone();
+        
+        let svc_stats   = SvcStatsMessage::new(request_id, svc_stats);
+        let control_msg = self.control.svc_stats_message(svc_stats);
+        
+        log_debug!(self.logger, "sending a SVC_STATS message...");
+        
+        self.send_control_message(control_msg, event_loop);
+    }
+    
+    /// Send all pending network events (if supported by Arrow Service).
+    #[cfg(feature = "discovery")]
+    fn send_network_events(&mut self, event_loop: &mut EventLoop<Self>) {
+        if !self.control.is_established()
+            || !self.control.supports(FEATURE_NETWORK_EVENTS) {
+            return;
+        }
+        
+        let events = self.app_context.lock()
+            .unwrap()
+            .network_monitor
+            .as_mut()
+            .map(|monitor| monitor.take_pending())
+            .unwrap_or(Vec::new());
+        
+        for event in events {
+            let control_msg = self.control.event_message(event);
+            
+            log_debug!(self.logger, "sending an EVENT message...");
+            
+            self.send_control_message(control_msg, event_loop);
+        }
+    }
+    
+    /// Send all pending network events (dummy).
+    #[cfg(not(feature = "discovery"))]
+    fn send_network_events(&mut self, _: &mut EventLoop<Self>) {
+    }
+    
     /// Send ACK message with a given message id and error code.
     fn send_ack_message(
         &mut self,
@@ -800,48 +1104,43 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         &mut self,
         control_msg: ControlMessage<B>,
         event_loop: &mut EventLoop<Self>) {
+        if let Some(ref trace_id) = self.trace_id {
+            let header = control_msg.header();
+            
+            log_debug!(self.logger, "sending control message: {:?} (trace ID: {}-c{:04x})", header.message_type(), trace_id, header.msg_id);
+        }
+        
+        #[cfg(feature = "metrics")]
+        {
+            self.app_context.lock()
+                .unwrap()
+                .metrics
+                .message_sent(&format!("{:?}", control_msg.header().message_type()));
+        }
+        
         let arrow_msg = ArrowMessage::new(0, 0, control_msg);
-        self.send_message(&arrow_msg, event_loop);
+        self.uplink.send_message(&arrow_msg, event_loop);
     }
     
     /// Send a given Control Protocol message which needs to be confirmed by 
-    // ACK.
+    /// ACK.
     fn send_unconfirmed_control_message<B: ControlMessageBody>(
         &mut self, 
         control_msg: ControlMessage<B>, 
         event_loop: &mut EventLoop<Self>) {
-        if self.expected_acks.is_empty() {
-            self.ack_tout.set(CONNECTION_TIMEOUT);
-        }
-        
-        let msg_id = control_msg.header()
-            .msg_id;
-        
-        self.expected_acks.push_back(msg_id);
-        
+        let queued = self.uplink.buffered();
+        self.control.expect_ack(&control_msg, queued);
         self.send_control_message(control_msg, event_loop);
     }
     
-    /// Send a given Arrow Message.
-    fn send_message<B: ArrowMessageBody>(
-        &mut self, 
-        arrow_msg: &ArrowMessage<B>, 
-        event_loop: &mut EventLoop<Self>) {
-        if self.output_buffer.is_empty() {
-            self.write_tout.set(CONNECTION_TIMEOUT);
-        }
-        
-        arrow_msg.serialize(&mut self.output_buffer)
-            .unwrap();
-        
-        self.stream.enable_socket_events(true, true, event_loop);
-    }
-    
     /// Check if the service table has been updated and send an UPDATE message
-    /// if needed.
-    fn check_update(&mut self, event_loop: &mut EventLoop<Self>) {
+    /// if needed. A version regression is handled according to the
+    /// configured policy.
+    fn check_update(&mut self, event_loop: &mut EventLoop<Self>) -> Result<()> {
         let cur_version;
         let svc_table;
+        let mut metadata;
+        let regression;
         
         {
             let app_context = self.app_context.lock()
@@ -850,17 +1149,59 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
             cur_version = config.version();
             svc_table   = config.service_table()
                 .clone();
+            metadata    = app_context.client_metadata();
+            regression  = app_context.version_regression;
+            
+            #[cfg(feature = "e2e")]
+            {
+                if self.router.is_e2e() {
+                    let services = svc_table.active_entries();
+                    
+                    for item in app_context.e2e_keys.announcements(&services) {
+                        metadata.add_e2e_service(&item);
+                    }
+                }
+            }
+            
+            if self.control.supports(FEATURE_MEDIA_INFO) {
+                for (svc_id, svc) in svc_table.active_entries() {
+                    let info = svc.address()
+                        .and_then(|addr| app_context.scan_report.media_info(addr));
+                    
+                    if let Some(info) = info {
+                        metadata.add_media_info(svc_id, info);
+                    }
+                }
+            }
+        }
+        
+        match self.control.version_change(cur_version) {
+            VersionChange::Unchanged => return Ok(()),
+            VersionChange::Newer     => (),
+            VersionChange::Regressed => {
+                log_warn!(self.logger, "service table version regression detected (version: {})", cur_version);
+                
+                if regression == RegressionPolicy::Reconnect {
+                    return Err(ArrowError::connection_error("service table version regression, reconnecting"));
+                }
+                
+                log_info!(self.logger, "sending the whole service table...");
+            }
         }
         
-        let send_update = match self.last_update {
-            Some(sent_version) => cur_version > sent_version,
-            None => true
+        let metadata = match self.trace_id {
+            Some(ref trace_id) => {
+                let mut metadata = metadata;
+                metadata.set_trace_id(trace_id);
+                metadata
+            },
+            None => metadata
         };
         
-        if send_update {
-            self.send_update_message(svc_table, event_loop);
-            self.last_update = Some(cur_version);
-        }
+        self.send_update_message(cur_version, svc_table, metadata,
+            event_loop);
+        
+        Ok(())
     }
     
     /// Check if the service table has been updated and send an UPDATE message
@@ -868,7 +1209,13 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
     fn te_check_update(
         &mut self, 
         event_loop: &mut EventLoop<Self>) -> Result<()> {
-        self.check_update(event_loop);
+        if self.clock_behind && self.clock_changed() {
+            return Err(ArrowError::connection_error("system clock has been changed, reconnecting in order to verify the Arrow Service certificate"));
+        }
+        
+        try!(self.check_update(event_loop));
+        self.send_network_events(event_loop);
+        self.send_hup_reports(event_loop);
         
         event_loop.timeout_ms(TimerEvent::Update, UPDATE_CHECK_PERIOD)
             .unwrap();
@@ -876,18 +1223,145 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         Ok(())
     }
     
+    /// Periodical buffer occupancy check. The Arrow output buffer
+    /// high-water mark is passed to the application context (session buffer
+    /// high-water marks are passed to the connection metrics, if enabled)
+    /// and a warning is logged for every buffer which has been almost full
+    /// for too long.
+    fn te_check_buffers(
+        &mut self, 
+        event_loop: &mut EventLoop<Self>) -> Result<()> {
+        let high_water = self.uplink.take_high_water();
+        
+        let threshold = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            if high_water > app_context.uplink_high_water {
+                app_context.uplink_high_water = high_water;
+            }
+            
+            #[cfg(feature = "metrics")]
+            {
+                app_context.metrics.uplink_buffered(high_water);
+                
+                for (session_id, input, output) in self.router.high_water() {
+                    app_context.metrics.session_buffered(session_id, input, output);
+                }
+            }
+            
+            app_context.buffer_alert_threshold
+        };
+        
+        let buffered = self.uplink.buffered();
+        let capacity = self.uplink.capacity();
+        
+        if let Some(duration) = self.uplink_alarm.check(buffered, capacity, threshold) {
+            log_warn!(self.logger, "Arrow output buffer has been almost full for {:.0} seconds ({} of {} bytes), the uplink may be undersized", duration, buffered, capacity);
+        }
+        
+        for (session_id, duration, buffered, capacity) in self.router.check_occupancy(threshold) {
+            log_warn!(self.logger, "session input buffer has been almost full for {:.0} seconds ({} of {} bytes, session ID: {:08x})", duration, buffered, capacity, session_id);
+        }
+        
+        event_loop.timeout_ms(TimerEvent::BufferCheck, BUFFER_CHECK_PERIOD)
+            .unwrap();
+        
+        Ok(())
+    }
+    
+    /// Check if the system clock has been changed since the connection was
+    /// established.
+    fn clock_changed(&self) -> bool {
+        let diff = get_clock_offset() - self.clock_offset;
+        
+        diff.abs() > CLOCK_JUMP_THRESHOLD
+    }
+    
     /// Periodical connection check.
     fn te_check_connection(
         &mut self, 
         event_loop: &mut EventLoop<Self>) -> Result<()> {
         self.send_ping_message(event_loop);
         
-        event_loop.timeout_ms(TimerEvent::Ping, PING_PERIOD)
+        event_loop.timeout_ms(TimerEvent::Ping, self.ping_period())
             .unwrap();
         
         Ok(())
     }
     
+    /// Get the current PING period (in milliseconds).
+    fn ping_period(&self) -> u64 {
+        self.app_context.lock()
+            .unwrap()
+            .ping_tuner
+            .period()
+    }
+    
+    /// Record an ACK of the last PING into the PING period tuner.
+    fn ping_confirmed(&mut self, msg_id: u16) {
+        let idle = match self.ping {
+            Some((id, idle)) if id == msg_id => idle,
+            _ => return
+        };
+        
+        self.ping = None;
+        
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        let tuner = &mut app_context.ping_tuner;
+        
+        if tuner.success(idle) {
+            log_info!(self.logger, "NAT binding survived {} s of inactivity, PING period changed to {} s", idle / 1000, tuner.period() / 1000);
+        }
+    }
+    
+    /// Record a connection lost while waiting for an ACK of the last PING
+    /// into the PING period tuner.
+    fn ping_failed(&mut self) {
+        let idle = match self.ping.take() {
+            Some((_, idle)) => idle,
+            None => return
+        };
+        
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        let tuner = &mut app_context.ping_tuner;
+        
+        if tuner.failure(idle) {
+            let (good, bad) = tuner.bounds();
+            
+            log_info!(self.logger, "connection lost after {} s of inactivity, NAT binding lifetime is probably between {} and {} s, PING period changed to {} s", idle / 1000, good / 1000, bad.unwrap_or(idle) / 1000, tuner.period() / 1000);
+        }
+    }
+    
+    /// Remember ID of the next Control Protocol message, so that the next
+    /// connection continues from it.
+    fn save_msg_id(&mut self) {
+        self.app_context.lock()
+            .unwrap()
+            .next_msg_id = self.control.peek_msg_id();
+    }
+    
+    /// Set the result of a connection which has been lost.
+    fn connection_failed(&mut self, err: ArrowError) {
+        if err.kind() == ErrorKind::ConnectionError {
+            self.ping_failed();
+        }
+        
+        #[cfg(feature = "metrics")]
+        {
+            self.app_context.lock()
+                .unwrap()
+                .metrics
+                .connection_failed();
+        }
+        
+        self.result = Some(Err(err));
+    }
+    
     /// Check connection timeout.
     fn te_check_timeout(
         &mut self,
@@ -903,9 +1377,23 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
     fn check_arrow_timeout(
         &mut self, 
         event_loop: &mut EventLoop<Self>) -> Result<()> {
-        if !self.write_tout.check() || !self.ack_tout.check() {
-            Err(ArrowError::connection_error("Arrow Service connection timeout"))
+        if let Some(duration) = self.suspend.check() {
+            // the local network may be different after resume
+            self.process_command(Command::ScanNetwork)
+                .ok();
+            
+            return Err(ArrowError::system_resumed(format!("system resume detected (suspended for {} seconds), reconnecting", duration)));
+        }
+        
+        if !self.uplink.check_timeout() {
+            Err(ArrowError::connection_error("Arrow Service write timeout"))
+        } else if !self.control.check_timeout() {
+            Err(ArrowError::connection_error("Arrow Service ACK timeout"))
         } else {
+            self.spin.cleanup();
+            self.check_session_taps();
+            self.check_bitrate_hints();
+            
             event_loop.timeout_ms(
                     TimerEvent::TimeoutCheck(0), 
                     TIMEOUT_CHECK_PERIOD)
@@ -915,24 +1403,126 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         }
     }
     
+    /// Attach and detach session taps requested using the control socket
+    /// and detach taps which are full.
+    #[cfg(feature = "recording")]
+    fn check_session_taps(&mut self) {
+        let (attach, detach) = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            (app_context.session_taps.take_attach_requests(),
+                app_context.session_taps.take_detach_requests())
+        };
+        
+        let mut finished = Vec::new();
+        let mut failed   = Vec::new();
+        
+        for (session_id, request) in attach {
+            if let Some(ctx) = self.router.get_mut(session_id) {
+                let tap = match SessionTap::open(&request) {
+                    Ok(tap)  => tap,
+                    Err(err) => {
+                        log_warn!(self.logger, "unable to create session tap {}-*.bin (session ID: {:08x}): {}", request.prefix(), session_id, err);
+                        failed.push(session_id);
+                        continue;
+                    }
+                };
+                
+                log_info!(self.logger, "session tap attached, writing into {}-*.bin (session ID: {:08x})", tap.prefix(), session_id);
+                
+                if let Some(old) = ctx.detach_tap() {
+                    finished.push((session_id, old));
+                }
+                
+                ctx.attach_tap(tap);
+            }
+        }
+        
+        for session_id in detach {
+            let tap = self.router.get_mut(session_id)
+                .and_then(|ctx| ctx.detach_tap());
+            
+            if let Some(tap) = tap {
+                finished.push((session_id, tap));
+            }
+        }
+        
+        let full = self.router.take_finished_taps();
+        
+        failed.extend(full.iter().map(|&(session_id, _)| session_id));
+        
+        if !failed.is_empty() {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            for session_id in failed {
+                app_context.session_taps.detached(session_id);
+            }
+        }
+        
+        finished.extend(full);
+        
+        for (session_id, tap) in finished {
+            log_info!(self.logger, "session tap closed, {} bytes written into {}-*.bin (session ID: {:08x})", tap.written(), tap.prefix(), session_id);
+        }
+    }
+    
+    /// Attach and detach session taps (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn check_session_taps(&mut self) {
+    }
+    
     /// Check session communication timeout.
     fn check_session_timeout(
         &mut self, 
         session_id: u32, 
         event_loop: &mut EventLoop<Self>) -> Result<()> {
-        let mut timeout = false;
+        let timeout = match self.router.get(session_id) {
+            Some(ctx) => !ctx.check_timeout(),
+            None      => return Ok(())
+        };
         
-        if let Some(ctx) = self.get_session_context(session_id) {
-            timeout = !ctx.write_tout.check();
+        if timeout {
+            log_warn!(self.logger, "session {:08x} write timeout", session_id);
+            self.send_hup_message(session_id, HUP_CONNECTION_ERROR,
+                event_loop);
+            self.session_finished(session_id, Some("write timeout"));
+            self.router.remove(session_id, event_loop);
+        } else {
+            event_loop.timeout_ms(
+                    TimerEvent::TimeoutCheck(session2token(session_id)), 
+                    TIMEOUT_CHECK_PERIOD)
+                .unwrap();
         }
         
+        Ok(())
+    }
+    
+    /// Check if the connection of a given session has been established in
+    /// time.
+    fn te_check_connect(
+        &mut self,
+        token: usize,
+        event_loop: &mut EventLoop<Self>) -> Result<()> {
+        let session_id = token2session(token);
+        let timeout    = match self.router.get(session_id) {
+            Some(ctx) if ctx.is_connected() => return Ok(()),
+            Some(ctx) => !ctx.check_connect_timeout(),
+            None      => return Ok(())
+        };
+        
         if timeout {
-            log_warn!(self.logger, "session {:08x} connection timeout", session_id);
-            self.send_hup_message(session_id, 0, event_loop);
-            self.remove_session_context(session_id, event_loop);
+            log_warn!(self.logger, "service connection was not established in time (session ID: {:08x})", session_id);
+            self.send_hup_message(session_id, HUP_SERVICE_UNREACHABLE, 
+                event_loop);
+            self.session_finished(session_id, 
+                Some("connection was not established in time"));
+            self.router.remove(session_id, event_loop);
         } else {
+            // the timer might belong to an older session with the same ID
             event_loop.timeout_ms(
-                    TimerEvent::TimeoutCheck(session2token(session_id)), 
+                    TimerEvent::ConnectCheck(token), 
                     TIMEOUT_CHECK_PERIOD)
                 .unwrap();
         }
@@ -940,52 +1530,182 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         Ok(())
     }
     
-    /// Process all notifications for the underlaying TLS socket.
-    fn arrow_socket_ready(
+    /// Suspend a given token (socket) for a while because it generates
+    /// too many events.
+    fn suspend_token(
         &mut self, 
-        event_loop: &mut EventLoop<Self>, 
-        event_set: EventSet) -> SocketEventResult {
-        let res = try_arr!(self.check_arrow_read_event(event_loop, event_set));
-        if res.is_some() {
-            return Ok(res);
+        token_id: usize, 
+        report: SpinReport, 
+        event_loop: &mut EventLoop<Self>) {
+        log_warn!(self.logger, "busy loop detected on token {:x} ({} events in {} ms, last event set: {:?}), suspending the socket for {} ms", token_id, report.events, report.elapsed, report.event_set, SPIN_BACKOFF);
+        
+        match token_id {
+            0  => self.uplink.suspend(event_loop),
+            id => match self.router.get_mut(token2session(id)) {
+                Some(ctx) => ctx.suspend(event_loop),
+                None      => return
+            }
         }
         
-        let res = try_arr!(self.check_arrow_write_event(event_loop, event_set));
-        if res.is_some() {
-            return Ok(res);
+        event_loop.timeout_ms(TimerEvent::Resume(token_id), SPIN_BACKOFF)
+            .unwrap();
+    }
+    
+    /// Resume a given suspended token (socket).
+    fn te_resume(
+        &mut self, 
+        token_id: usize, 
+        event_loop: &mut EventLoop<Self>) -> Result<()> {
+        match token_id {
+            0  => self.uplink.resume(event_loop),
+            id => if let Some(ctx) = self.router.get_mut(token2session(id)) {
+                ctx.resume(event_loop);
+            }
         }
         
-        if event_set.is_error() {
-            let socket_err = self.stream.take_socket_error();
-            Err(ArrowError::connection_error(socket_err.unwrap_err()))
-        } else if event_set.is_hup() {
-            Err(ArrowError::connection_error("connection to Arrow Service lost"))
-        } else {
-            Ok(None)
+        Ok(())
+    }
+    
+    /// Check if a decision about a given pending session has been made in
+    /// time.
+    fn te_check_authorize(
+        &mut self,
+        session_id: u32,
+        event_loop: &mut EventLoop<Self>) -> Result<()> {
+        let now       = get_time_ms();
+        let remaining = match self.pending.get(&session_id) {
+            Some(pending) if pending.deadline > now => pending.deadline - now,
+            Some(_) => 0,
+            None    => return Ok(())
+        };
+        
+        if remaining > 0 {
+            // the timer fired a bit earlier or it belongs to an older session
+            // with the same ID
+            event_loop.timeout_ms(TimerEvent::AuthorizeCheck(session_id),
+                    remaining)
+                .unwrap();
+        } else if let Some(pending) = self.pending.remove(&session_id) {
+            log_warn!(self.logger, "session authorization timeout (service ID: {:04x}, session ID: {:08x})", pending.service_id, session_id);
+            self.send_service_hup_message(pending.service_id, session_id,
+                HUP_SERVICE_UNREACHABLE, event_loop);
         }
+        
+        Ok(())
     }
     
-    /// Read a request/response chunk if the underlaying TLS socket is 
-    /// readable.
-    fn check_arrow_read_event(
-        &mut self, 
-        event_loop: &mut EventLoop<Self>, 
-        event_set: EventSet) -> SocketEventResult {
-        if self.stream.can_read(event_set) {
-            self.read_request(event_loop)
+    /// Buffer data of a given session waiting for a decision. The session
+    /// is refused if there is too much data.
+    fn buffer_pending_data(
+        &mut self,
+        session_id: u32,
+        seq: Option<u32>,
+        data: Vec<u8>,
+        event_loop: &mut EventLoop<Self>) {
+        let overflow = match self.pending.get_mut(&session_id) {
+            Some(pending) => !pending.push(seq, data),
+            None => false
+        };
+        
+        if overflow {
+            if let Some(pending) = self.pending.remove(&session_id) {
+                log_warn!(self.logger, "too much data received while waiting for session authorization (service ID: {:04x}, session ID: {:08x})", pending.service_id, session_id);
+                self.send_service_hup_message(pending.service_id, session_id,
+                    HUP_SESSION_ERROR, event_loop);
+            }
+        }
+    }
+    
+    /// Process a session authorizer decision about a given pending session.
+    fn session_authorized(
+        &mut self,
+        session_id: u32,
+        decision: SessionDecision,
+        event_loop: &mut EventLoop<Self>) {
+        let mut pending = match self.pending.remove(&session_id) {
+            Some(pending) => pending,
+            None => {
+                log_debug!(self.logger, "ignoring a late session authorization decision (session ID: {:08x})", session_id);
+                return;
+            }
+        };
+        
+        let service_id = pending.service_id;
+        
+        let addr = match decision {
+            SessionDecision::Allow => pending.addr,
+            SessionDecision::Redirect(addr) => {
+                log_info!(self.logger, "session redirected by the session authorizer: {} -> {} (service ID: {:04x}, session ID: {:08x})", pending.addr, addr, service_id, session_id);
+                addr
+            },
+            SessionDecision::Deny => {
+                log_warn!(self.logger, "session refused by the session authorizer (service ID: {:04x}, session ID: {:08x})", service_id, session_id);
+                self.send_service_hup_message(service_id, session_id,
+                    HUP_ACCESS_DENIED, event_loop);
+                return;
+            }
+        };
+        
+        let svc = pending.service.clone();
+        
+        if let Err(code) = self.open_session(service_id, session_id, svc,
+            addr, event_loop) {
+            self.send_service_hup_message(service_id, session_id, code,
+                event_loop);
+            return;
+        }
+        
+        let mut res = Ok(());
+        
+        if let Some(ctx) = self.router.get_mut(session_id) {
+            for (seq, data) in pending.take_data() {
+                if let Some(seq) = seq {
+                    ctx.record_sequence(seq);
+                }
+                
+                res = ctx.send_message(&data, event_loop);
+                
+                if res.is_err() {
+                    break;
+                }
+            }
+        }
+        
+        if let Err(err) = res {
+            log_warn!(self.logger, "invalid end-to-end encrypted data (service ID: {:04x}, session ID: {:08x}): {}", service_id, session_id, err.description());
+            self.send_hup_message(session_id, HUP_SESSION_ERROR, event_loop);
+            self.session_finished(session_id, Some(err.description()));
+            self.router.remove(session_id, event_loop);
         } else {
-            Ok(None)
+            self.check_session_input(session_id, event_loop);
         }
     }
     
-    /// Write a request/response chunk if the underlaying TLS socket is 
-    /// writable.
-    fn check_arrow_write_event(
+    /// Process all notifications for the underlaying TLS socket.
+    fn arrow_socket_ready(
         &mut self, 
         event_loop: &mut EventLoop<Self>, 
         event_set: EventSet) -> SocketEventResult {
-        if self.stream.can_write(event_set) {
-            self.send_response(event_loop)
+        if event_set.is_readable() || event_set.is_writable() {
+            self.last_activity = get_time_ms();
+        }
+        
+        if self.uplink.can_read(event_set) {
+            let res = try_arr!(self.read_request(event_loop));
+            if res.is_some() {
+                return Ok(res);
+            }
+        }
+        
+        if self.uplink.can_write(event_set) {
+            self.router.fill_uplink(&mut self.uplink, event_loop);
+            try_arr!(self.uplink.write(event_loop));
+        }
+        
+        if event_set.is_error() {
+            Err(self.uplink.socket_error())
+        } else if event_set.is_hup() {
+            Err(ArrowError::connection_error("connection to Arrow Service lost"))
         } else {
             Ok(None)
         }
@@ -997,13 +1717,11 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
         let mut consumed = 0;
         
-        let len = try_arr!(self.stream.read(&mut *self.read_buffer, event_loop));
-        
-        //log_debug!(self.logger, "{} bytes read from the Arrow socket", len);
+        let len = try_arr!(self.uplink.read(event_loop));
         
         while consumed < len {
             consumed += try_arr!(self.req_parser.add(
-                &self.read_buffer[consumed..len]));
+                &self.uplink.received()[consumed..]));
             if self.req_parser.is_complete() {
                 let redirect = try_arr!(self.process_request(event_loop));
                 if redirect.is_some() {
@@ -1032,6 +1750,14 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
             panic!("incomplete message")
         }
         
+        let strict_parsing = self.app_context.lock()
+            .unwrap()
+            .strict_parsing;
+        
+        if strict_parsing {
+            self.check_protocol_violations(event_loop);
+        }
+        
         match service_id {
             0 => self.process_control_message(event_loop),
             _ => self.process_service_request(service_id, session_id, 
@@ -1039,13 +1765,78 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         }
     }
     
+    /// Check the last complete request for protocol violations. All
+    /// violations are counted, logged (the offending message is dumped only
+    /// for a sample of them) and reported to Arrow Service (if supported).
+    fn check_protocol_violations(&mut self, event_loop: &mut EventLoop<Self>) {
+        let established = self.control.is_established();
+        
+        let violations = match self.req_parser.raw() {
+            Some(msg) => violation::check_message(msg, established)
+                .into_iter()
+                .map(|t| ProtocolViolation::new(t, clock::timestamp(), msg))
+                .collect::<Vec<_>>(),
+            None => panic!("incomplete message")
+        };
+        
+        for violation in violations {
+            let count = {
+                let mut app_context = self.app_context.lock()
+                    .unwrap();
+                
+                app_context.protocol_violations += 1;
+                app_context.protocol_violations
+            };
+            
+            if count <= VIOLATION_DUMP_COUNT || count % VIOLATION_DUMP_PERIOD == 0 {
+                log_warn!(self.logger, "protocol violation: {} (violations found so far: {}, message: {})", violation.name(), count, violation.hexdump());
+            } else {
+                log_warn!(self.logger, "protocol violation: {} (violations found so far: {})", violation.name(), count);
+            }
+            
+            if established && self.control.supports(FEATURE_PROTOCOL_VIOLATIONS) {
+                let control_msg = self.control.protocol_violation_message(violation);
+                
+                log_debug!(self.logger, "sending a PROTOCOL_VIOLATION message...");
+                
+                self.send_control_message(control_msg, event_loop);
+            }
+        }
+    }
+    
     /// Process a Control Protocol message.
     fn process_control_message(
         &mut self, 
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
         let (header, body) = try_arr!(self.parse_control_message());
         
-        log_debug!(self.logger, "received control message: {:?}", header.message_type());
+        #[cfg(feature = "metrics")]
+        {
+            self.app_context.lock()
+                .unwrap()
+                .metrics
+                .message_received(&format!("{:?}", header.message_type()));
+        }
+        
+        match self.trace_id {
+            Some(ref trace_id) => {
+                // ACKs carry ID of the message being confirmed
+                let origin = if header.message_type() == ControlMessageType::ACK {
+                    'c'
+                } else {
+                    's'
+                };
+                
+                log_debug!(self.logger, "received control message: {:?} (trace ID: {}-{}{:04x})", header.message_type(), trace_id, origin, header.msg_id);
+            },
+            None => log_debug!(self.logger, "received control message: {:?}", header.message_type())
+        }
+        
+        if header.message_type() != ControlMessageType::ACK
+            && !self.control.accept_msg_id(header.msg_id) {
+            self.req_parser.clear();
+            return self.process_replayed_message(&header, event_loop);
+        }
         
         let res = match header.message_type() {
             ControlMessageType::ACK => 
@@ -1064,7 +1855,15 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
                 self.process_status_request(header.msg_id, event_loop),
             ControlMessageType::GET_SCAN_REPORT =>
                 self.process_scan_report_request(header.msg_id, event_loop),
-            mt => Err(ArrowError::other(format!("cannot handle Control Protocol message type: {:?}", mt)))
+            ControlMessageType::GET_SVC_STATS =>
+                self.process_svc_stats_request(header.msg_id, event_loop),
+            ControlMessageType::ROTATE_SECRET =>
+                self.process_rotate_secret(header.msg_id, &body, event_loop),
+            ControlMessageType::REMOVE_SERVICE =>
+                self.process_remove_service(header.msg_id, &body, event_loop),
+            ControlMessageType::SET_LOG_LEVEL =>
+                self.process_set_log_level(header.msg_id, &body, event_loop),
+            _ => self.process_unsupported_message(&header, event_loop)
         };
         
         self.req_parser.clear();
@@ -1080,11 +1879,7 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
             try_arr!(parser.process(body));
             let header = parser.header();
             let body   = parser.body();
-            if header.message_type() == ControlMessageType::UNKNOWN {
-                Err(ArrowError::other("unknown Control Protocol message type"))
-            } else {
-                Ok((header.clone(), body.to_vec()))
-            }
+            Ok((header.clone(), body.to_vec()))
         } else {
             panic!("incomplete message");
         }
@@ -1096,26 +1891,32 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         msg_id: u16, 
         msg: &[u8],
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        let expected_ack = self.expected_acks.pop_front();
+        let (latency, queued) = try_arr!(self.control.process_ack(msg_id));
         
-        if self.expected_acks.is_empty() {
-            self.ack_tout.clear();
-        } else {
-            self.ack_tout.set(CONNECTION_TIMEOUT);
-        }
+        self.ping_confirmed(msg_id);
         
-        if let Some(expected_ack) = expected_ack {
-            if msg_id == expected_ack {
-                if self.state == ProtocolState::Handshake {
-                    self.process_handshake_ack(msg, event_loop)
-                } else {
-                    Ok(None)
-                }
-            } else {
-                Err(ArrowError::other("unexpected ACK message ID"))
+        let threshold = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            app_context.ack_latency.record(latency);
+            
+            #[cfg(feature = "metrics")]
+            {
+                app_context.metrics.ack_received(latency);
             }
+            
+            app_context.slow_ack_threshold
+        };
+        
+        if threshold > 0 && latency >= threshold {
+            log_warn!(self.logger, "slow ACK: {} ms (message ID: {:04x}, {} bytes were queued in the uplink before the message)", latency, msg_id, queued);
+        }
+        
+        if self.control.is_established() {
+            Ok(None)
         } else {
-            Err(ArrowError::other("no ACK message expected"))
+            self.process_handshake_ack(msg, event_loop)
         }
     }
     
@@ -1124,50 +1925,148 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         &mut self, 
         msg: &[u8],
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        if self.state == ProtocolState::Handshake {
-            let ack = try_arr!(control::parse_ack_message(msg));
-            if ack == ACK_NO_ERROR {
-                // switch the protocol state into normal operation
-                self.state = ProtocolState::Established;
-                
-                // start sending update messages
-                event_loop.timeout_ms(TimerEvent::Update, UPDATE_CHECK_PERIOD)
-                    .unwrap();
-                
-                // start sending PING messages
-                event_loop.timeout_ms(TimerEvent::Ping, PING_PERIOD)
-                    .unwrap();
-                
-                let diagnostic_mode = self.app_context.lock()
-                    .unwrap()
-                    .diagnostic_mode;
-                
-                // report a fake redirect in case of the diagnostic mode
-                if diagnostic_mode {
-                    Ok(Some(String::new()))
-                } else {
-                    Ok(None)
-                }
-            } else if ack == ACK_UNAUTHORIZED {
-                Err(ArrowError::unauthorized("Arrow REGISTER failed (unauthorized)"))
-            } else if ack == ACK_UNSUPPORTED_PROTOCOL_VERSION {
-                Err(ArrowError::unsupported_protocol_version("Arrow REGISTER failed (unsupported version of the Arrow Protocol)"))
-            } else if ack == ACK_INTERNAL_SERVER_ERROR {
-                Err(ArrowError::arrow_server_error("Arrow REGISTER failed (internal server error)"))
+        let res = self.control.process_handshake_ack(msg);
+        
+        self.update_register_format(msg, res.is_err());
+        
+        match res {
+            Err(ref err) if err.kind() == ErrorKind::Unauthorized =>
+                self.finish_secret_rotation(false),
+            Ok(None) => self.finish_secret_rotation(true),
+            _ => ()
+        }
+        
+        if let Some(challenge) = try_arr!(res) {
+            log_debug!(self.logger, "authentication challenge received");
+            try_arr!(self.send_register_request(Some(&challenge), event_loop));
+            return Ok(None);
+        }
+        
+        let protocol = self.control.protocol();
+        
+        log_debug!(self.logger, "Control Protocol version: {}, features: {:08x}", protocol.version, protocol.features);
+        
+        self.fire_hook(HookEvent::Connected, &[]);
+        
+        self.router.set_sequenced(protocol.supports(FEATURE_DATA_SEQUENCE));
+        self.router.set_timestamped(
+            protocol.supports(FEATURE_DATA_TIMESTAMPS));
+        self.router.set_e2e(protocol.supports(FEATURE_E2E_ENCRYPTION));
+        
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            app_context.table_pages = protocol.supports(FEATURE_TABLE_PAGES);
+            app_context.connection_state = LedState::Registered;
+            app_context.status_led
+                .set_state(LedState::Registered);
+        }
+        
+        log_fields!(self.logger, Severity::INFO, &[("ARROW_STATE", "registered")],
+            "registered with Arrow Service");
+        
+        // start sending update messages
+        event_loop.timeout_ms(TimerEvent::Update, UPDATE_CHECK_PERIOD)
+            .unwrap();
+        
+        // start sending PING messages
+        event_loop.timeout_ms(TimerEvent::Ping, self.ping_period())
+            .unwrap();
+        
+        // start checking buffer occupancy
+        event_loop.timeout_ms(TimerEvent::BufferCheck, BUFFER_CHECK_PERIOD)
+            .unwrap();
+        
+        let diagnostic_mode = self.app_context.lock()
+            .unwrap()
+            .diagnostic_mode;
+        
+        // report a fake redirect in case of the diagnostic mode
+        if diagnostic_mode {
+            Ok(Some(String::new()))
+        } else {
+            Ok(None)
+        }
+    }
+    
+    /// Choose the REGISTER format for the next handshake. Servers responding
+    /// with their protocol info are known to accept the client protocol info
+    /// in REGISTER. If a REGISTER fails with a legacy or garbled ACK, the
+    /// other format is used next time (a legacy server may not understand
+    /// the protocol info, but the failure might be unrelated to the format).
+    fn update_register_format(&mut self, msg: &[u8], failed: bool) {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        if !control::is_legacy_register_ack(msg) {
+            app_context.legacy_register = false;
+        } else if failed {
+            let legacy = !app_context.legacy_register;
+            
+            app_context.legacy_register = legacy;
+            
+            if legacy {
+                log_info!(self.logger, "REGISTER failed with a legacy or garbled ACK, the next REGISTER will be sent without the protocol info");
             } else {
-                Err(ArrowError::other("Arrow REGISTER failed (unknown error)"))
+                log_info!(self.logger, "REGISTER failed with a legacy or garbled ACK, the next REGISTER will be sent with the protocol info");
             }
-        } else {
-            panic!("unexpected protocol state");
         }
     }
     
+    /// Process a Control Protocol message which cannot be handled by this 
+    /// client (i.e. an unknown message type or a message type which is not 
+    /// expected to be received by the client). The message is rejected using 
+    /// an ACK with the ACK_UNSUPPORTED_METHOD error code and the connection 
+    /// stays open.
+    fn process_unsupported_message(
+        &mut self,
+        header: &ControlMessageHeader,
+        event_loop: &mut EventLoop<Self>) -> SocketEventResult {
+        let count = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            app_context.unsupported_messages += 1;
+            app_context.unsupported_messages
+        };
+        
+        log_warn!(self.logger, "unsupported Control Protocol message: {:?} (unsupported messages received so far: {})", header, count);
+        
+        self.send_ack_message(header.msg_id, ACK_UNSUPPORTED_METHOD, 
+            event_loop);
+        
+        Ok(None)
+    }
+    
+    /// Process a duplicated or replayed Control Protocol message. The message
+    /// is acknowledged again (e.g. the previous ACK might have been lost)
+    /// but it is not processed.
+    fn process_replayed_message(
+        &mut self,
+        header: &ControlMessageHeader,
+        event_loop: &mut EventLoop<Self>) -> SocketEventResult {
+        let count = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+            
+            app_context.replayed_messages += 1;
+            app_context.replayed_messages
+        };
+        
+        log_warn!(self.logger, "duplicated or replayed Control Protocol message ignored: {:?} (replayed messages received so far: {})", header, count);
+        
+        self.send_ack_message(header.msg_id, ACK_NO_ERROR, event_loop);
+        
+        Ok(None)
+    }
+    
     /// Process a Control Protocol PING message.
     fn process_ping_message(
         &mut self, 
         msg_id: u16, 
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        if self.state == ProtocolState::Established {
+        if self.control.is_established() {
             self.send_ack_message(msg_id, 0, event_loop);
             Ok(None)
         } else {
@@ -1177,7 +2076,7 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
     
     /// Process a Control Protocol REDIRECT message.
     fn process_redirect_message(&mut self, msg: &[u8]) -> SocketEventResult {
-        if self.state == ProtocolState::Established {
+        if self.control.is_established() {
             let ptr  = msg.as_ptr();
             let cstr = unsafe {
                 CStr::from_ptr(ptr as *const _)
@@ -1196,12 +2095,19 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         &mut self, 
         msg: &[u8], 
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        if self.state == ProtocolState::Established {
+        if self.control.is_established() {
             let msg        = try_arr!(HupMessage::from_bytes(msg));
             let session_id = msg.session_id;
             // XXX: the HUP error code should be processed here
             log_info!(self.logger, "session {:08x} closed", session_id);
-            self.remove_session_context(session_id, event_loop);
+            let truncated = self.router.get(session_id)
+                .map_or(false, |ctx| ctx.is_e2e_truncated());
+            if truncated {
+                log_warn!(self.logger, "end-to-end encrypted stream was not finished by the consumer, the data might have been truncated (session ID: {:08x})", session_id);
+            }
+            self.pending.remove(&session_id);
+            self.session_finished(session_id, None);
+            self.router.remove(session_id, event_loop);
             Ok(None)
         } else {
             Err(ArrowError::other("cannot handle HUP message in the Handshake state"))
@@ -1250,139 +2156,265 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         Ok(None)
     }
     
-    /// Process request for a remote service.
-    fn process_service_request(
+    /// Process service statistics request (GET_SVC_STATS message) with a 
+    /// given ID.
+    fn process_svc_stats_request(
         &mut self, 
-        service_id: u16,
-        session_id: u32,
+        msg_id: u16, 
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        if self.state == ProtocolState::Established {
-            let request = match self.req_parser.body() {
-                Some(body) => body.to_vec(),
-                None => panic!("incomplete message")
-            };
+        if self.control.supports(FEATURE_SVC_STATS) {
+            self.send_svc_stats(msg_id, event_loop);
+        } else {
+            self.send_ack_message(msg_id, ACK_UNSUPPORTED_METHOD, event_loop);
+        }
+        
+        Ok(None)
+    }
+    
+    /// Process a ROTATE_SECRET message. The new secret is persisted as
+    /// pending and confirmed by ACK. It replaces the current secret once a
+    /// registration using the new secret succeeds.
+    fn process_rotate_secret(
+        &mut self,
+        msg_id: u16,
+        msg: &[u8],
+        event_loop: &mut EventLoop<Self>) -> SocketEventResult {
+        if !self.control.supports(FEATURE_SECRET_ROTATION) {
+            self.send_ack_message(msg_id, ACK_UNSUPPORTED_METHOD, event_loop);
+            return Ok(None);
+        }
+        
+        let secret = Secret::new(try_arr!(control::parse_rotate_secret(msg)));
+        
+        let res = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
             
-            self.req_parser.clear();
+            app_context.config.set_pending_password(secret.expose());
             
-            let send_hup = match self.create_session_context(
-                service_id, session_id, event_loop) {
-                None      => true,
-                Some(ctx) => {
-                    ctx.send_message(&request, event_loop);
-                    false
-                }
-            };
+            let res = save_config(&app_context);
             
-            if send_hup {
-                self.send_hup_message(session_id, 1, event_loop);
+            // the old secret stays in use if the new one cannot be persisted
+            if res.is_err() {
+                app_context.config.rollback_password();
             }
             
-            Ok(None)
-        } else {
-            Err(ArrowError::other("cannot handle service requests in the Handshake state"))
+            res
+        };
+        
+        match res {
+            Ok(_) => {
+                log_info!(self.logger, "new secret installed, it will be used for the next registration");
+                self.send_ack_message(msg_id, ACK_NO_ERROR, event_loop);
+            },
+            Err(err) => {
+                log_warn!(self.logger, "unable to install new secret: {}", err);
+                self.send_ack_message(msg_id, ACK_INTERNAL_SERVER_ERROR,
+                    event_loop);
+            }
         }
+        
+        Ok(None)
     }
     
-    /// Fill the Arrow Protocol output buffer with data from session input 
-    /// buffers.
-    fn fill_output_buffer(&mut self, event_loop: &mut EventLoop<Self>) {
-        // using round robin alg. here in order to avoid session read 
-        // starvation
-        let mut queue_size = self.session_queue.len();
-        while queue_size > 0 && !self.output_buffer.is_full() {
-            if let Some(session_id) = self.session_queue.pop_front() {
-                if let Some(ctx) = self.sessions.get_mut(&session_id) {
-                    // avoid sending empty packets
-                    let len = if ctx.input_ready() {
-                        let data = ctx.input_buffer();
-                        let len  = cmp::min(32768, data.len());
-                        let arrow_msg = ArrowMessage::new(
-                            ctx.service_id, ctx.session_id, 
-                            &data[..len]);
-                        
-                        if self.output_buffer.is_empty() {
-                            self.write_tout.set(CONNECTION_TIMEOUT);
-                        }
-                        
-                        arrow_msg.serialize(&mut self.output_buffer)
-                            .unwrap();
-                        
-                        len
-                    } else {
-                        0
-                    };
-                    
-                    ctx.drop_input_bytes(len, event_loop);
-                    
-                    self.session_queue.push_back(session_id);
-                    
-                    //log_debug!(self.logger, "{} bytes moved from session {:08x} input buffer into the Arrow output buffer", len, session_id);
-                }
-            }
+    /// Process a given REMOVE_SERVICE message. The service is deactivated
+    /// and the change is persisted. The UPDATE message is sent on the next
+    /// service table check.
+    fn process_remove_service(
+        &mut self,
+        msg_id: u16,
+        msg: &[u8],
+        event_loop: &mut EventLoop<Self>) -> SocketEventResult {
+        if !self.control.supports(FEATURE_SERVICE_REMOVAL) {
+            self.send_ack_message(msg_id, ACK_UNSUPPORTED_METHOD, event_loop);
+            return Ok(None);
+        }
+        
+        let svc_id = try_arr!(control::parse_remove_service(msg));
+        
+        let res = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
             
-            queue_size -= 1;
+            if app_context.config.get(svc_id).is_none() {
+                Err("unknown service ID".to_string())
+            } else if !app_context.config.remove(svc_id) {
+                Err("the service is pinned".to_string())
+            } else {
+                app_context.config.bump_version();
+                app_context.svc_stats.remove(svc_id);
+                
+                save_config(&app_context)
+                    .map_err(|err| format!("unable to save config: {}", err))
+            }
+        };
+        
+        match res {
+            Ok(_) => {
+                log_info!(self.logger, "service {} removed by Arrow Service", svc_id);
+                self.send_ack_message(msg_id, ACK_NO_ERROR, event_loop);
+            },
+            Err(err) => {
+                log_warn!(self.logger, "unable to remove service {}: {}", svc_id, err);
+                self.send_ack_message(msg_id, ACK_INTERNAL_SERVER_ERROR,
+                    event_loop);
+            }
         }
+        
+        Ok(None)
     }
     
-    /// Send response data using the underlaying TLS socket.
-    fn send_response(
-        &mut self, 
+    /// Process a given SET_LOG_LEVEL message. The log level of the whole
+    /// process is changed temporarily, zero duration restores the configured
+    /// level immediately.
+    fn process_set_log_level(
+        &mut self,
+        msg_id: u16,
+        msg: &[u8],
         event_loop: &mut EventLoop<Self>) -> SocketEventResult {
-        self.fill_output_buffer(event_loop);
+        if !self.control.supports(FEATURE_LOG_LEVEL_CONTROL) {
+            self.send_ack_message(msg_id, ACK_UNSUPPORTED_METHOD, event_loop);
+            return Ok(None);
+        }
+        
+        let controller = self.app_context.lock()
+            .unwrap()
+            .log_level
+            .clone();
+        
+        let controller = match controller {
+            Some(controller) => controller,
+            None => {
+                self.send_ack_message(msg_id, ACK_UNSUPPORTED_METHOD, event_loop);
+                return Ok(None);
+            }
+        };
+        
+        let (level, duration) = try_arr!(control::parse_set_log_level(msg));
+        
+        let level = match level {
+            0 => Severity::DEBUG,
+            1 => Severity::INFO,
+            2 => Severity::WARN,
+            3 => Severity::ERROR,
+            _ => {
+                log_warn!(self.logger, "unknown log level requested by Arrow Service: {}", level);
+                self.send_ack_message(msg_id, ACK_INTERNAL_SERVER_ERROR,
+                    event_loop);
+                return Ok(None);
+            }
+        };
         
-        if self.output_buffer.is_empty() {
-            self.stream.enable_socket_events(true, false, event_loop);
-            self.write_tout.clear();
+        let duration = cmp::min(duration as u64, MAX_LOG_LEVEL_DURATION);
+        
+        if duration > 0 {
+            controller.set_temporary(level, duration);
+            
+            log_info!(self.logger, "log level set to {:?} by Arrow Service for {} seconds", level, duration);
         } else {
-            let len = {
-                let data   = self.output_buffer.as_bytes();
-                let len    = cmp::min(data.len(), self.write_buffer.len());
-                let buffer = &mut self.write_buffer[..len];
-                utils::memcpy(buffer, &data[..len]);
-                try_arr!(self.stream.write(buffer, event_loop))
-            };
+            controller.restore();
             
-            if len > 0 {
-                //log_debug!(self.logger, "{} bytes written into the Arrow socket", len);
-                self.write_tout.set(CONNECTION_TIMEOUT);
-                self.output_buffer.drop(len);
-            }
+            log_info!(self.logger, "log level restored by Arrow Service");
         }
         
+        self.send_ack_message(msg_id, ACK_NO_ERROR, event_loop);
+        
         Ok(None)
     }
     
-    /// Move all data from the session input buffer into the Arrow output 
-    /// buffer.
-    fn flush_session(
+    /// Finish a pending secret rotation (if any) depending on the result of
+    /// the last registration. The new secret replaces the old one if the
+    /// registration succeeded, otherwise the old secret is restored.
+    fn finish_secret_rotation(&mut self, registered: bool) {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+        
+        let finished = if registered {
+            app_context.config.commit_password()
+        } else {
+            app_context.config.rollback_password()
+        };
+        
+        if !finished {
+            return;
+        }
+        
+        if registered {
+            log_info!(self.logger, "secret rotation confirmed by Arrow Service, the old secret has been retired");
+        } else {
+            log_warn!(self.logger, "new secret rejected by Arrow Service, rolling back to the previous secret");
+        }
+        
+        if let Err(err) = save_config(&app_context) {
+            log_warn!(self.logger, "unable to save config file: {}", err);
+        }
+    }
+    
+    /// Process request for a remote service.
+    fn process_service_request(
         &mut self, 
-        session_id: u32, 
-        event_loop: &mut EventLoop<Self>) {
-        if let Some(ctx) = self.sessions.get_mut(&session_id) {
-            // avoid sending empty packets
-            let len = if ctx.input_ready() {
-                let data = ctx.input_buffer();
-                let arrow_msg = ArrowMessage::new(
-                    ctx.service_id, ctx.session_id, 
-                    data);
-                
-                if self.output_buffer.is_empty() {
-                    self.write_tout.set(CONNECTION_TIMEOUT);
-                }
-                
-                arrow_msg.serialize(&mut self.output_buffer)
-                    .unwrap();
-                
-                data.len()
-            } else {
-                0
+        service_id: u16,
+        session_id: u32,
+        event_loop: &mut EventLoop<Self>) -> SocketEventResult {
+        if self.control.is_established() {
+            let (seq, mut request) = match self.req_parser.body() {
+                Some(body) if self.router.is_sequenced() => {
+                    let frame = try_arr!(SequencedFrame::parse(body));
+                    (Some(frame.seq()), frame.data().to_vec())
+                },
+                Some(body) => (None, body.to_vec()),
+                None => panic!("incomplete message")
             };
             
-            ctx.drop_input_bytes(len, event_loop);
+            self.req_parser.clear();
             
-            self.stream.enable_socket_events(true, true, event_loop);
+            if self.pending.contains_key(&session_id) {
+                self.buffer_pending_data(session_id, seq, request, event_loop);
+            } else if self.clock_behind {
+                log_warn!(self.logger, "service request refused, the system clock is behind (service ID: {:04x}, session ID: {:08x})", service_id, session_id);
+                self.send_service_hup_message(service_id, session_id,
+                    HUP_CLOCK_BEHIND, event_loop);
+            } else {
+                match self.create_session_context(service_id, session_id, 
+                    &mut request, event_loop) {
+                    Ok(_) if self.pending.contains_key(&session_id) => {
+                        self.buffer_pending_data(session_id, seq, request,
+                            event_loop);
+                    },
+                    Ok(_) => {
+                        let res = match self.router.get_mut(session_id) {
+                            Some(ctx) => {
+                                if let Some(seq) = seq {
+                                    ctx.record_sequence(seq);
+                                }
+                                
+                                ctx.send_message(&request, event_loop)
+                            },
+                            None => Ok(())
+                        };
+                        
+                        if let Err(err) = res {
+                            log_warn!(self.logger, "invalid end-to-end encrypted data (service ID: {:04x}, session ID: {:08x}): {}", service_id, session_id, err.description());
+                            self.send_hup_message(session_id,
+                                HUP_SESSION_ERROR, event_loop);
+                            self.session_finished(session_id,
+                                Some(err.description()));
+                            self.router.remove(session_id, event_loop);
+                        } else {
+                            // the end-to-end encryption session might have
+                            // been established
+                            self.check_session_input(session_id,
+                                event_loop);
+                        }
+                    },
+                    Err(code) => self.send_service_hup_message(service_id,
+                        session_id, code, event_loop)
+                }
+            }
             
-            //log_debug!(self.logger, "{} bytes moved from session {:08x} input buffer into the Arrow output buffer", len, session_id);
+            Ok(None)
+        } else {
+            Err(ArrowError::other("cannot handle service requests in the Handshake state"))
         }
     }
     
@@ -1392,26 +2424,58 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
         session_id: u32, 
         event_loop: &mut EventLoop<Self>, 
         event_set: EventSet) -> SocketEventResult {
-        let res = match self.get_session_context_mut(session_id) {
-            Some(ctx) => ctx.socket_ready(event_loop, event_set),
-            None      => Ok(Some(0))
+        let (res, connected, dropped, received, sent) = match self.router.get_mut(session_id) {
+            Some(ctx) => {
+                let was_connected = ctx.is_connected();
+                let (bytes_in, bytes_out) = ctx.transferred();
+                let res = ctx.socket_ready(event_loop, event_set);
+                let connected = !was_connected && ctx.is_connected();
+                let received = ctx.transferred().0 - bytes_in;
+                let sent = ctx.transferred().1 - bytes_out;
+                (res, connected, ctx.take_dropped(), received, sent)
+            },
+            None => (Ok(Some(0)), false, (0, 0), 0, 0)
         };
         
+        if connected {
+            self.session_connected(session_id);
+        }
+        
+        if received > 0 || sent > 0 {
+            self.app_context.lock()
+                .unwrap()
+                .session_transferred(session_id, received, sent);
+        }
+        
+        if received > 0 {
+            self.session_received(session_id, received as usize);
+        }
+        
+        let (dropped_frames, dropped_bytes) = dropped;
+        
+        if dropped_frames > 0 {
+            self.session_dropped_frames(session_id, dropped_frames, 
+                dropped_bytes);
+        }
+        
         match res {
             Err(err) => {
                 log_warn!(self.logger, "service connection error (session ID: {:08x}): {}", session_id, err.description());
-                self.flush_session(session_id, event_loop);
-                self.send_hup_message(session_id, 2, event_loop);
-                self.remove_session_context(session_id, event_loop);
+                self.router.flush(session_id, &mut self.uplink, event_loop);
+                self.send_hup_message(session_id, HUP_CONNECTION_ERROR, 
+                    event_loop);
+                self.session_finished(session_id, Some(err.description()));
+                self.router.remove(session_id, event_loop);
             },
             Ok(None) => {
                 log_info!(self.logger, "service connection closed (session ID: {:08x})", session_id);
-                self.flush_session(session_id, event_loop);
-                self.send_hup_message(session_id, 0, event_loop);
-                self.remove_session_context(session_id, event_loop);
+                self.router.finish(session_id, &mut self.uplink, event_loop);
+                self.send_hup_message(session_id, HUP_NO_ERROR, event_loop);
+                self.session_finished(session_id, None);
+                self.router.remove(session_id, event_loop);
             },
             Ok(Some(size)) if size > 0 => {
-                self.stream.enable_socket_events(true, true, event_loop);
+                self.uplink.enable_write_events(event_loop);
             },
             _ => ()
         }
@@ -1420,19 +2484,54 @@ impl<L: Logger + Clone, Q: Sender<Command>> ConnectionHandler<L, Q> {
     }
 }
 
+/// Save the configuration into the configuration file (if known).
+fn save_config(app_context: &AppContext) -> result::Result<(), ConfigError> {
+    match app_context.config_file {
+        Some(ref file) => app_context.config.save(file),
+        None => Ok(())
+    }
+}
+
+/// Get the monotonic time (in milliseconds).
+fn get_time_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+/// Get difference between the system time and the monotonic time (in
+/// seconds).
+fn get_clock_offset() -> i64 {
+    let monotonic = (time::precise_time_ns() / 1000000000) as i64;
+    
+    time::get_time().sec - monotonic
+}
+
+/// Generate a new random connection trace ID.
+fn new_trace_id() -> String {
+    Uuid::new_v4()
+        .as_bytes()
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 /// Types of epoll() timer events.
 #[derive(Debug, Copy, Clone)]
 enum TimerEvent {
     Update,
     Ping,
     TimeoutCheck(usize),
+    ConnectCheck(usize),
+    Resume(usize),
+    AuthorizeCheck(u32),
+    BufferCheck,
 }
 
 impl<L, Q> Handler for ConnectionHandler<L, Q>
     where L: Logger + Clone,
           Q: Sender<Command> {
     type Timeout = TimerEvent;
-    type Message = ();
+    type Message = (u32, SessionDecision);
     
     /// Event loop handler method.
     fn ready(
@@ -1449,8 +2548,24 @@ impl<L, Q> Handler for ConnectionHandler<L, Q>
         match res {
             Ok(None)           => (),
             Ok(Some(redirect)) => self.result = Some(Ok(redirect)),
-            Err(err)           => self.result = Some(Err(err))
+            Err(err)           => self.connection_failed(err)
+        }
+        
+        if self.result.is_some() {
+            event_loop.shutdown();
+        } else if let Some(report) = self.spin.event(token.0, event_set) {
+            self.suspend_token(token.0, report, event_loop);
         }
+    }
+    
+    /// Session authorizer decision handler method.
+    fn notify(
+        &mut self,
+        event_loop: &mut EventLoop<Self>,
+        msg: (u32, SessionDecision)) {
+        let (session_id, decision) = msg;
+        
+        self.session_authorized(session_id, decision, event_loop);
         
         if self.result.is_some() {
             event_loop.shutdown();
@@ -1463,11 +2578,18 @@ impl<L, Q> Handler for ConnectionHandler<L, Q>
             TimerEvent::Update => self.te_check_update(event_loop),
             TimerEvent::Ping   => self.te_check_connection(event_loop),
             TimerEvent::TimeoutCheck(token) => 
-                self.te_check_timeout(token, event_loop)
+                self.te_check_timeout(token, event_loop),
+            TimerEvent::ConnectCheck(token) =>
+                self.te_check_connect(token, event_loop),
+            TimerEvent::Resume(token) =>
+                self.te_resume(token, event_loop),
+            TimerEvent::AuthorizeCheck(session_id) =>
+                self.te_check_authorize(session_id, event_loop),
+            TimerEvent::BufferCheck => self.te_check_buffers(event_loop)
         };
         
         match res {
-            Err(err) => self.result = Some(Err(err)),
+            Err(err) => self.connection_failed(err),
             _        => ()
         }
         
@@ -1485,17 +2607,113 @@ pub struct ArrowClient<L: Logger + Clone, Q: Sender<Command>> {
 
 impl<L: Logger + Clone, Q: Sender<Command>> ArrowClient<L, Q> {
     /// Create a new Arrow client.
-    pub fn new<S: IntoSsl>(
+    fn new<S: IntoSsl>(
+        logger: L,
+        s: S, 
+        cmd_sender: Q,
+        addr: &SocketAddr, 
+        arrow_mac: &MacAddr,
+        app_context: Shared<AppContext>) -> Result<Self> {
+        let mut event_loop = try_other!(EventLoop::new());
+        let uplink         = try_arr!(Uplink::connect(s, addr, 
+            0, &mut event_loop));
+        let connection     = try_arr!(ConnectionHandler::new(
+            logger, uplink, cmd_sender, 
+            arrow_mac, app_context, 
+            &mut event_loop));
+        
+        let res = ArrowClient {
+            connection: connection,
+            event_loop: event_loop
+        };
+        
+        Ok(res)
+    }
+    
+    /// Create a new Arrow client connected to a given Arrow Service address
+    /// through a given relay.
+    fn new_via_relay<S: IntoSsl>(
+        logger: L,
+        s: S, 
+        cmd_sender: Q,
+        relay_addr: &SocketAddr, 
+        addr: &str, 
+        arrow_mac: &MacAddr,
+        app_context: Shared<AppContext>) -> Result<Self> {
+        let (uuid, secret) = {
+            let app_context = app_context.lock()
+                .unwrap();
+            
+            (app_context.config.uuid(), app_context.relay_secret.clone())
+        };
+        
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return Err(ArrowError::other("relay secret is not configured"))
+        };
+        
+        let hello          = RelayHello::new(uuid, addr)
+            .sign(&secret);
+        let mut event_loop = try_other!(EventLoop::new());
+        let uplink         = try_arr!(Uplink::connect_via_relay(s, 
+            relay_addr, &hello, 0, &mut event_loop));
+        let connection     = try_arr!(ConnectionHandler::new(
+            logger, uplink, cmd_sender, 
+            arrow_mac, app_context, 
+            &mut event_loop));
+        
+        let res = ArrowClient {
+            connection: connection,
+            event_loop: event_loop
+        };
+        
+        Ok(res)
+    }
+    
+    /// Create a new Arrow client connected to a given Arrow Service address
+    /// ("host:port" format) through a given SOCKS5 proxy.
+    #[cfg(feature = "socks")]
+    fn new_via_socks<S: IntoSsl>(
+        logger: L,
+        s: S, 
+        cmd_sender: Q,
+        proxy: &SocksProxy, 
+        addr: &str, 
+        arrow_mac: &MacAddr,
+        app_context: Shared<AppContext>) -> Result<Self> {
+        let mut event_loop = try_other!(EventLoop::new());
+        let uplink         = try_arr!(Uplink::connect_via_socks(s, 
+            proxy, addr, 0, &mut event_loop));
+        let connection     = try_arr!(ConnectionHandler::new(
+            logger, uplink, cmd_sender, 
+            arrow_mac, app_context, 
+            &mut event_loop));
+        
+        let res = ArrowClient {
+            connection: connection,
+            event_loop: event_loop
+        };
+        
+        Ok(res)
+    }
+    
+    /// Create a new Arrow client connected to a given WebSocket endpoint
+    /// (resolved as a given socket address).
+    #[cfg(feature = "websocket")]
+    fn new_via_websocket<S: IntoSsl>(
         logger: L,
         s: S, 
         cmd_sender: Q,
         addr: &SocketAddr, 
+        endpoint: &WssEndpoint, 
         arrow_mac: &MacAddr,
         app_context: Shared<AppContext>) -> Result<Self> {
-        let mut event_loop    = try_other!(EventLoop::new());
-        let connection        = try_arr!(ConnectionHandler::new(
-            logger, s, cmd_sender, 
-            addr, arrow_mac, app_context, 
+        let mut event_loop = try_other!(EventLoop::new());
+        let uplink         = try_arr!(Uplink::connect_websocket(s, 
+            addr, endpoint, 0, &mut event_loop));
+        let connection     = try_arr!(ConnectionHandler::new(
+            logger, uplink, cmd_sender, 
+            arrow_mac, app_context, 
             &mut event_loop));
         
         let res = ArrowClient {
@@ -1510,10 +2728,536 @@ impl<L: Logger + Clone, Q: Sender<Command>> ArrowClient<L, Q> {
     /// requests. Return error or redirect address in case the connection has 
     /// been shut down.
     pub fn event_loop(&mut self) -> Result<String> {
-        try_other!(self.event_loop.run(&mut self.connection));
+        let res = self.event_loop.run(&mut self.connection);
+        
+        self.connection.save_msg_id();
+        
+        try_other!(res);
+        
         match self.connection.result {
             Some(ref res) => res.clone(),
             _             => panic!("result expected")
         }
     }
 }
+
+/// Arrow client builder.
+///
+/// The application context (holding the client configuration) and the TLS
+/// context are required. The TLS context has to verify Arrow Service
+/// certificates including the host name (OpenSSL does not check host names
+/// on its own). By default, all log messages are dropped, commands from
+/// Arrow Service are ignored, the MAC address of the first ethernet device
+/// is used and timeouts and buffer sizes are taken from the application
+/// context.
+pub struct ArrowClientBuilder<L: Logger + Clone, Q: Sender<Command>> {
+    logger:      L,
+    cmd_sender:  Q,
+    ssl_context: SslContext,
+    arrow_mac:   Option<MacAddr>,
+    app_context: Shared<AppContext>,
+    service_connect_timeout: Option<u64>,
+    uplink_write_timeout:    Option<u64>,
+    ack_timeout:             Option<u64>,
+    session_write_timeout:   Option<u64>,
+    uplink_buffer_size:      Option<usize>,
+    session_buffer_size:     Option<usize>,
+}
+
+impl ArrowClientBuilder<DummyLogger, DummySender> {
+    /// Create a new builder for a given application context and TLS
+    /// context.
+    pub fn new(app_context: Shared<AppContext>, ssl_context: SslContext) -> Self {
+        ArrowClientBuilder {
+            logger:      DummyLogger::new(),
+            cmd_sender:  DummySender,
+            ssl_context: ssl_context,
+            arrow_mac:   None,
+            app_context: app_context,
+            service_connect_timeout: None,
+            uplink_write_timeout:    None,
+            ack_timeout:             None,
+            session_write_timeout:   None,
+            uplink_buffer_size:      None,
+            session_buffer_size:     None
+        }
+    }
+}
+
+impl<L: Logger + Clone, Q: Sender<Command>> ArrowClientBuilder<L, Q> {
+    /// Use a given logger.
+    pub fn logger<T: Logger + Clone>(self, logger: T) -> ArrowClientBuilder<T, Q> {
+        ArrowClientBuilder {
+            logger:      logger,
+            cmd_sender:  self.cmd_sender,
+            ssl_context: self.ssl_context,
+            arrow_mac:   self.arrow_mac,
+            app_context: self.app_context,
+            service_connect_timeout: self.service_connect_timeout,
+            uplink_write_timeout:    self.uplink_write_timeout,
+            ack_timeout:             self.ack_timeout,
+            session_write_timeout:   self.session_write_timeout,
+            uplink_buffer_size:      self.uplink_buffer_size,
+            session_buffer_size:     self.session_buffer_size
+        }
+    }
+
+    /// Pass commands from Arrow Service to a given command sender.
+    pub fn cmd_sender<T: Sender<Command>>(self, cmd_sender: T) -> ArrowClientBuilder<L, T> {
+        ArrowClientBuilder {
+            logger:      self.logger,
+            cmd_sender:  cmd_sender,
+            ssl_context: self.ssl_context,
+            arrow_mac:   self.arrow_mac,
+            app_context: self.app_context,
+            service_connect_timeout: self.service_connect_timeout,
+            uplink_write_timeout:    self.uplink_write_timeout,
+            ack_timeout:             self.ack_timeout,
+            session_write_timeout:   self.session_write_timeout,
+            uplink_buffer_size:      self.uplink_buffer_size,
+            session_buffer_size:     self.session_buffer_size
+        }
+    }
+
+    /// Identify the client using a given MAC address.
+    pub fn arrow_mac(mut self, arrow_mac: MacAddr) -> Self {
+        self.arrow_mac = Some(arrow_mac);
+        self
+    }
+
+    /// Set time limit for establishing service connections (in
+    /// milliseconds).
+    pub fn service_connect_timeout(mut self, timeout: u64) -> Self {
+        self.service_connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set time limit for writing pending data into the Arrow Service
+    /// connection (in milliseconds).
+    pub fn uplink_write_timeout(mut self, timeout: u64) -> Self {
+        self.uplink_write_timeout = Some(timeout);
+        self
+    }
+
+    /// Set time limit for receiving ACKs of Control Protocol messages (in
+    /// milliseconds).
+    pub fn ack_timeout(mut self, timeout: u64) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Set time limit for writing pending data into service connections (in
+    /// milliseconds).
+    pub fn session_write_timeout(mut self, timeout: u64) -> Self {
+        self.session_write_timeout = Some(timeout);
+        self
+    }
+
+    /// Set capacity of the Arrow Service connection output buffer (in
+    /// bytes).
+    pub fn uplink_buffer_size(mut self, size: usize) -> Self {
+        self.uplink_buffer_size = Some(size);
+        self
+    }
+
+    /// Set capacity of session input buffers (in bytes).
+    pub fn session_buffer_size(mut self, size: usize) -> Self {
+        self.session_buffer_size = Some(size);
+        self
+    }
+
+    /// Create a new Arrow client connected to a given Arrow Service address.
+    pub fn connect(self, addr: &SocketAddr) -> Result<ArrowClient<L, Q>> {
+        let (logger, cmd_sender, ssl_context, arrow_mac, app_context) =
+            try!(self.prepare());
+
+        ArrowClient::new(logger, &ssl_context, cmd_sender,
+            addr, &arrow_mac, app_context)
+    }
+
+    /// Create a new Arrow client connected to a given Arrow Service address
+    /// through a given relay.
+    pub fn connect_via_relay(
+        self,
+        relay_addr: &SocketAddr,
+        addr: &str) -> Result<ArrowClient<L, Q>> {
+        let (logger, cmd_sender, ssl_context, arrow_mac, app_context) =
+            try!(self.prepare());
+
+        ArrowClient::new_via_relay(logger, &ssl_context, cmd_sender,
+            relay_addr, addr, &arrow_mac, app_context)
+    }
+
+    /// Create a new Arrow client connected to a given Arrow Service address
+    /// ("host:port" format) through a given SOCKS5 proxy.
+    #[cfg(feature = "socks")]
+    pub fn connect_via_socks(
+        self,
+        proxy: &SocksProxy,
+        addr: &str) -> Result<ArrowClient<L, Q>> {
+        let (logger, cmd_sender, ssl_context, arrow_mac, app_context) =
+            try!(self.prepare());
+
+        ArrowClient::new_via_socks(logger, &ssl_context, cmd_sender,
+            proxy, addr, &arrow_mac, app_context)
+    }
+
+    /// Create a new Arrow client connected to a given WebSocket endpoint
+    /// (resolved as a given socket address).
+    #[cfg(feature = "websocket")]
+    pub fn connect_websocket(
+        self,
+        addr: &SocketAddr,
+        endpoint: &WssEndpoint) -> Result<ArrowClient<L, Q>> {
+        let (logger, cmd_sender, ssl_context, arrow_mac, app_context) =
+            try!(self.prepare());
+
+        ArrowClient::new_via_websocket(logger, &ssl_context, cmd_sender,
+            addr, endpoint, &arrow_mac, app_context)
+    }
+
+    /// Apply the timeouts and buffer sizes to the application context and
+    /// fill in the defaults.
+    fn prepare(self) -> Result<(L, Q, SslContext, MacAddr, Shared<AppContext>)> {
+        {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+
+            if let Some(timeout) = self.service_connect_timeout {
+                app_context.service_connect_timeout = timeout;
+            }
+
+            if let Some(timeout) = self.uplink_write_timeout {
+                app_context.uplink_write_timeout = timeout;
+            }
+
+            if let Some(timeout) = self.ack_timeout {
+                app_context.ack_timeout = timeout;
+            }
+
+            if let Some(timeout) = self.session_write_timeout {
+                app_context.session_write_timeout = timeout;
+            }
+
+            if let Some(size) = self.uplink_buffer_size {
+                app_context.uplink_buffer_size = size;
+            }
+
+            if let Some(size) = self.session_buffer_size {
+                app_context.session_buffer_size = size;
+            }
+        }
+
+        let arrow_mac = match self.arrow_mac {
+            Some(mac) => mac,
+            None => try_arr!(default_arrow_mac())
+        };
+
+        Ok((self.logger, self.cmd_sender, self.ssl_context, arrow_mac, self.app_context))
+    }
+}
+
+/// Get MAC address of the first ethernet device. An error is returned if
+/// there is no ethernet device (the MAC address identifies the client, so
+/// it cannot be made up).
+fn default_arrow_mac() -> Result<MacAddr> {
+    EthernetDevice::list()
+        .into_iter()
+        .next()
+        .map(|dev| dev.mac_addr)
+        .ok_or(ArrowError::other(
+            "there is no ethernet device, the MAC address has to be given explicitly"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    use std::str;
+    use std::thread;
+    
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    
+    use utils::{Shared, Serialize};
+    use utils::logger::DummyLogger;
+    use utils::config::{ArrowConfig, AppContext};
+    
+    use net::utils::WriteBuffer;
+    use net::raw::ether::MacAddr;
+    
+    use openssl::crypto::hash::Type as HashType;
+    use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_NONE};
+    use openssl::x509::X509Generator;
+    
+    /// Command sender ignoring all commands.
+    #[derive(Clone)]
+    struct DummySender;
+    
+    impl Sender<Command> for DummySender {
+        fn send(&self, _: Command) -> result::Result<(), Command> {
+            Ok(())
+        }
+    }
+    
+    /// Start a tiny RTSP server answering all requests with 200 OK and 
+    /// return its address.
+    fn start_rtsp_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .unwrap();
+        let addr = listener.local_addr()
+            .unwrap();
+        
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream  = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buffer  = [0u8; 1024];
+                
+                loop {
+                    let len = stream.read(&mut buffer)
+                        .unwrap();
+                    
+                    if len == 0 {
+                        break;
+                    }
+                    
+                    request.extend(buffer[..len].iter());
+                    
+                    if request.ends_with(b"\r\n\r\n") {
+                        let cseq = str::from_utf8(&request)
+                            .unwrap()
+                            .lines()
+                            .find(|line| line.starts_with("CSeq:"))
+                            .map(|line| line[5..].trim().to_string())
+                            .unwrap_or(String::new());
+                        
+                        let response = format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\nPublic: OPTIONS, DESCRIBE\r\n\r\n", cseq);
+                        
+                        stream.write_all(response.as_bytes())
+                            .unwrap();
+                        
+                        request.clear();
+                    }
+                }
+            }
+        });
+        
+        addr
+    }
+    
+    /// Read a single Arrow Message from a given stream.
+    fn read_message<R: Read>(stream: &mut R) -> (ArrowMessageHeader, Vec<u8>) {
+        let mut parser = ArrowMessageParser::new();
+        let mut buffer = [0u8; 1];
+        
+        while !parser.is_complete() {
+            let len = stream.read(&mut buffer)
+                .unwrap();
+            assert!(len > 0);
+            parser.add(&buffer[..len])
+                .unwrap();
+        }
+        
+        let header = *parser.header()
+            .unwrap();
+        let body   = parser.body()
+            .unwrap()
+            .to_vec();
+        
+        (header, body)
+    }
+    
+    /// Write a given Arrow Message into a given stream.
+    fn write_message<W: Write, B: ArrowMessageBody>(
+        stream: &mut W, 
+        msg: &ArrowMessage<B>) {
+        let mut buffer = WriteBuffer::new(0);
+        msg.serialize(&mut buffer)
+            .unwrap();
+        stream.write_all(buffer.as_bytes())
+            .unwrap();
+    }
+    
+    /// Start a mock Arrow Service, which accepts a single client, sends a 
+    /// message of an unknown type and a given request to service 1 (session 
+    /// 1), waits for the response and redirects the client. The response is 
+    /// returned through the join handle.
+    fn start_arrow_service(request: &[u8]) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let (cert, pkey) = X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(1)
+            .add_name("CN".to_string(), "localhost".to_string())
+            .set_sign_hash(HashType::SHA256)
+            .generate()
+            .unwrap();
+        
+        let mut ssl_context = SslContext::new(SslMethod::Tlsv1_2)
+            .unwrap();
+        
+        ssl_context.set_certificate(&cert)
+            .unwrap();
+        ssl_context.set_private_key(&pkey)
+            .unwrap();
+        
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .unwrap();
+        let addr = listener.local_addr()
+            .unwrap();
+        
+        let request = request.to_vec();
+        
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept()
+                .unwrap();
+            let mut stream  = SslStream::accept(&ssl_context, stream)
+                .unwrap();
+            
+            // wait for REGISTER and confirm it
+            let (header, body) = read_message(&mut stream);
+            
+            assert_eq!(header.service, 0);
+            
+            let mut parser = ControlMessageParser::new();
+            
+            parser.process(&body)
+                .unwrap();
+            
+            assert_eq!(parser.header().message_type(), 
+                ControlMessageType::REGISTER);
+            
+            let ack = control::create_ack_message(parser.header().msg_id, 
+                ACK_NO_ERROR);
+            
+            write_message(&mut stream, &ArrowMessage::new(0, 0, ack));
+            
+            // send a message of an unknown type (it should be rejected)
+            let unknown = vec![0x42, 0x42, 0x7f, 0xff];
+            
+            write_message(&mut stream, &ArrowMessage::new(0, 0, unknown));
+            
+            // send the service request
+            write_message(&mut stream, 
+                &ArrowMessage::new(1, 1, request));
+            
+            // collect the service response (ignoring other control messages)
+            let mut response = Vec::new();
+            let mut rejected = false;
+            
+            while !response.ends_with(b"\r\n\r\n") {
+                let (header, body) = read_message(&mut stream);
+                if header.service == 1 && header.session == 1 {
+                    response.extend(body);
+                } else if header.service == 0 {
+                    rejected |= body == [0x42, 0x42, 0x00, 0x00, 
+                        0x00, 0x00, 0x00, 0x04];
+                }
+            }
+            
+            assert!(rejected);
+            
+            // make the client leave its event loop (REDIRECT)
+            let mut redirect = vec![0xff, 0xff, 0x00, 0x03];
+            
+            redirect.extend(b"localhost:1\0".iter());
+            
+            write_message(&mut stream, &ArrowMessage::new(0, 0, redirect));
+            
+            response
+        });
+        
+        (addr, handle)
+    }
+    
+    #[test]
+    fn test_session_round_trip() {
+        let rtsp_addr = start_rtsp_server();
+        let request   = b"OPTIONS rtsp://127.0.0.1/stream RTSP/1.0\r\nCSeq: 7\r\n\r\n";
+        
+        let (arrow_addr, arrow_service) = start_arrow_service(request);
+        
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        
+        let mut config = ArrowConfig::new();
+        
+        assert_eq!(config.add_static(Service::RTSP(mac, rtsp_addr, 
+            "/stream".to_string())), Some(1));
+        
+        let app_context = Shared::new(AppContext::new(config));
+        
+        let mut ssl_context = SslContext::new(SslMethod::Tlsv1_2)
+            .unwrap();
+        
+        ssl_context.set_verify(SSL_VERIFY_NONE, None);
+        
+        let mut client = ArrowClient::new(DummyLogger::new(), &ssl_context, 
+                DummySender, &arrow_addr, &mac, app_context)
+            .unwrap();
+        
+        let redirect = client.event_loop()
+            .unwrap();
+        
+        assert_eq!(redirect, "localhost:1");
+        
+        let response = arrow_service.join()
+            .unwrap();
+        
+        let response = str::from_utf8(&response)
+            .unwrap();
+        
+        assert!(response.starts_with("RTSP/1.0 200 OK\r\n"));
+        assert!(response.contains("CSeq: 7\r\n"));
+    }
+    
+    #[test]
+    fn test_client_builder() {
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        
+        let app_context = Shared::new(AppContext::new(ArrowConfig::new()));
+        
+        let ssl_context = SslContext::new(SslMethod::Tlsv1_2)
+            .unwrap();
+        
+        let (_, _, _, arrow_mac, app_context) = ArrowClientBuilder::new(app_context, ssl_context)
+            .logger(DummyLogger::new())
+            .cmd_sender(DummySender)
+            .arrow_mac(mac)
+            .ack_timeout(1000)
+            .session_write_timeout(2000)
+            .uplink_buffer_size(65536)
+            .session_buffer_size(4096)
+            .prepare()
+            .unwrap();
+        
+        let app_context = app_context.lock()
+            .unwrap();
+        
+        assert_eq!(arrow_mac, mac);
+        assert_eq!(app_context.ack_timeout, 1000);
+        assert_eq!(app_context.session_write_timeout, 2000);
+        assert_eq!(app_context.uplink_buffer_size, 65536);
+        assert_eq!(app_context.session_buffer_size, 4096);
+        
+        // settings which have not been given are kept
+        assert_eq!(app_context.service_connect_timeout,
+            ::utils::config::SERVICE_CONNECT_TIMEOUT);
+    }
+    
+    #[test]
+    fn test_session_token_mapping() {
+        let session_id = 0x00abcdef;
+        let token_id   = session2token(session_id);
+        
+        assert!(token_id != 0);
+        assert_eq!(token2session(token_id), session_id);
+    }
+    
+    #[test]
+    fn test_trace_id() {
+        let trace_id = super::new_trace_id();
+        
+        assert_eq!(trace_id.len(), 8);
+        assert!(trace_id.chars().all(|c| c.is_digit(16)));
+        assert!(trace_id != super::new_trace_id());
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Buffer occupancy alerts.
+//!
+//! A buffer which stays almost full for a long time is the main early signal
+//! of an undersized uplink. The occupancy of a buffer is sampled
+//! periodically and an alert is raised once per episode if all samples taken
+//! within a given time were above the occupancy limit.
+
+use time;
+
+/// Default time (in milliseconds) a buffer has to stay above the occupancy
+/// limit before an alert is raised.
+pub const BUFFER_ALERT_THRESHOLD: u64 = 10000;
+
+/// Occupancy limit (fraction of the buffer capacity).
+const HIGH_OCCUPANCY: f64 = 0.9;
+
+/// Occupancy alarm of a single buffer.
+#[derive(Debug, Copy, Clone)]
+pub struct OccupancyAlarm {
+    /// Time of the first sample above the limit in the current episode.
+    since:    Option<f64>,
+    /// The current episode has been already reported.
+    reported: bool,
+}
+
+impl OccupancyAlarm {
+    /// Create a new alarm.
+    pub fn new() -> OccupancyAlarm {
+        OccupancyAlarm {
+            since:    None,
+            reported: false
+        }
+    }
+
+    /// Take a new sample of a buffer with a given number of buffered bytes
+    /// and a given capacity. The time (in seconds) the buffer has been above
+    /// the occupancy limit is returned if it exceeds a given threshold (in
+    /// milliseconds) and the episode has not been reported yet. Buffers
+    /// without capacity are ignored.
+    pub fn check(
+        &mut self,
+        buffered: usize,
+        capacity: usize,
+        threshold: u64) -> Option<f64> {
+        self.check_at(buffered, capacity, threshold, time::precise_time_s())
+    }
+
+    /// Take a new sample at a given time.
+    fn check_at(
+        &mut self,
+        buffered: usize,
+        capacity: usize,
+        threshold: u64,
+        now: f64) -> Option<f64> {
+        if capacity == 0 || (buffered as f64) < (capacity as f64 * HIGH_OCCUPANCY) {
+            self.since    = None;
+            self.reported = false;
+
+            return None;
+        }
+
+        let since = self.since.unwrap_or(now);
+
+        self.since = Some(since);
+
+        let duration = now - since;
+
+        if !self.reported && (duration * 1000.0) >= threshold as f64 {
+            self.reported = true;
+
+            Some(duration)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_occupancy_alarm() {
+        let mut alarm = OccupancyAlarm::new();
+
+        assert_eq!(alarm.check_at(95, 100, 10000, 0.0), None);
+        assert_eq!(alarm.check_at(99, 100, 10000, 5.0), None);
+        assert_eq!(alarm.check_at(90, 100, 10000, 10.0), Some(10.0));
+
+        // reported only once per episode
+        assert_eq!(alarm.check_at(100, 100, 10000, 20.0), None);
+
+        // a new episode
+        assert_eq!(alarm.check_at(50, 100, 10000, 21.0), None);
+        assert_eq!(alarm.check_at(95, 100, 10000, 22.0), None);
+        assert_eq!(alarm.check_at(95, 100, 10000, 31.0), None);
+        assert_eq!(alarm.check_at(95, 100, 10000, 32.0), Some(10.0));
+
+        // buffers without capacity are ignored
+        let mut alarm = OccupancyAlarm::new();
+
+        assert_eq!(alarm.check_at(1000, 0, 0, 0.0), None);
+        assert_eq!(alarm.check_at(1000, 0, 0, 100.0), None);
+    }
+}
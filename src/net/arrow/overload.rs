@@ -0,0 +1,529 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session overload policy definitions.
+//!
+//! A session is overloaded if its service produces data faster than they can
+//! be sent to Arrow Service (i.e. the session input buffer is full). There are
+//! two policies:
+//!
+//! * `Block` - stop reading from the service until there is some space in the
+//!   input buffer (the service will be slowed down by TCP flow control),
+//! * `DropOldest` - keep reading from the service and drop the oldest
+//!   buffered media frames (suitable for real-time streams where stale frames
+//!   are useless). Frames are recognized only in RTSP interleaved streams.
+//!   A frame consists of all RTP packets of a channel up to the packet with
+//!   the marker bit set. RTCP packets and RTSP messages are never dropped.
+
+use std::str;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use net::arrow::protocol::Service;
+
+use utils::RuntimeError;
+
+/// Maximum length of an RTSP message header. Data without a valid header are
+/// split into non-droppable segments of this size.
+const MAX_HEADER_LENGTH: usize = 16384;
+
+/// Number of bytes of an interleaved binary frame needed to recognize RTP
+/// packets ('$', channel, u16 length and the first two bytes of the RTP
+/// header).
+const INTERLEAVED_HEADER_LENGTH: usize = 6;
+
+/// Identification of a media frame (channel, frame number).
+type FrameId = (u8, u64);
+
+/// Session overload policy.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OverloadPolicy {
+    /// Stop reading from the service.
+    Block,
+    /// Drop the oldest media frames.
+    DropOldest,
+}
+
+impl OverloadPolicy {
+    /// Parse a given policy name ("block" or "drop").
+    pub fn from_name(name: &str) -> Result<OverloadPolicy, RuntimeError> {
+        match name {
+            "block" => Ok(OverloadPolicy::Block),
+            "drop"  => Ok(OverloadPolicy::DropOldest),
+            _ => Err(RuntimeError::from(format!("unknown overload policy: {}", name)))
+        }
+    }
+}
+
+/// Service classes with configurable overload policy.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ServiceClass {
+    RTSP,
+    MJPEG,
+    HTTP,
+    TCP,
+    UDP,
+}
+
+impl ServiceClass {
+    /// Get class of a given service (None for the Control Protocol service).
+    pub fn of(svc: &Service) -> Option<ServiceClass> {
+        match svc {
+            &Service::ControlProtocol          => None,
+            &Service::RTSP(_, _, _)            => Some(ServiceClass::RTSP),
+            &Service::LockedRTSP(_, _)         => Some(ServiceClass::RTSP),
+            &Service::UnknownRTSP(_, _)        => Some(ServiceClass::RTSP),
+            &Service::UnsupportedRTSP(_, _, _) => Some(ServiceClass::RTSP),
+            &Service::HTTP(_, _)               => Some(ServiceClass::HTTP),
+            &Service::MJPEG(_, _, _)           => Some(ServiceClass::MJPEG),
+            &Service::LockedMJPEG(_, _)        => Some(ServiceClass::MJPEG),
+            &Service::TCP(_, _)                => Some(ServiceClass::TCP),
+            &Service::AnyTCP                   => Some(ServiceClass::TCP),
+            &Service::UDP(_, _)                => Some(ServiceClass::UDP)
+        }
+    }
+
+    /// Parse a given class name ("rtsp", "mjpeg", "http", "tcp" or "udp").
+    pub fn from_name(name: &str) -> Result<ServiceClass, RuntimeError> {
+        match name {
+            "rtsp"  => Ok(ServiceClass::RTSP),
+            "mjpeg" => Ok(ServiceClass::MJPEG),
+            "http"  => Ok(ServiceClass::HTTP),
+            "tcp"   => Ok(ServiceClass::TCP),
+            "udp"   => Ok(ServiceClass::UDP),
+            _ => Err(RuntimeError::from(format!("unknown service class: {}", name)))
+        }
+    }
+}
+
+/// Overload policies of all service classes.
+#[derive(Debug, Copy, Clone)]
+pub struct OverloadPolicies {
+    rtsp:  OverloadPolicy,
+    mjpeg: OverloadPolicy,
+    http:  OverloadPolicy,
+    tcp:   OverloadPolicy,
+    udp:   OverloadPolicy,
+}
+
+impl OverloadPolicies {
+    /// Create a new set of policies (all service classes use the Block
+    /// policy).
+    pub fn new() -> OverloadPolicies {
+        OverloadPolicies {
+            rtsp:  OverloadPolicy::Block,
+            mjpeg: OverloadPolicy::Block,
+            http:  OverloadPolicy::Block,
+            tcp:   OverloadPolicy::Block,
+            udp:   OverloadPolicy::Block
+        }
+    }
+
+    /// Get policy for a given service class.
+    pub fn get(&self, class: ServiceClass) -> OverloadPolicy {
+        match class {
+            ServiceClass::RTSP  => self.rtsp,
+            ServiceClass::MJPEG => self.mjpeg,
+            ServiceClass::HTTP  => self.http,
+            ServiceClass::TCP   => self.tcp,
+            ServiceClass::UDP   => self.udp
+        }
+    }
+
+    /// Set policy for a given service class. The DropOldest policy is
+    /// supported only for RTSP services.
+    pub fn set(
+        &mut self,
+        class: ServiceClass,
+        policy: OverloadPolicy) -> Result<(), RuntimeError> {
+        if policy == OverloadPolicy::DropOldest
+            && class != ServiceClass::RTSP {
+            return Err(RuntimeError::from("the drop policy is supported only for RTSP services"));
+        }
+
+        match class {
+            ServiceClass::RTSP  => self.rtsp  = policy,
+            ServiceClass::MJPEG => self.mjpeg = policy,
+            ServiceClass::HTTP  => self.http  = policy,
+            ServiceClass::TCP   => self.tcp   = policy,
+            ServiceClass::UDP   => self.udp   = policy
+        }
+
+        Ok(())
+    }
+}
+
+/// Result of dropping frames.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DroppedFrames {
+    /// Ranges (offset, length) of the dropped data relative to the start
+    /// of the buffered data (in ascending order, not adjacent).
+    pub ranges: Vec<(usize, usize)>,
+    /// Number of dropped frames.
+    pub frames: usize,
+    /// Number of dropped bytes.
+    pub bytes:  usize,
+}
+
+/// RTSP interleaved framing tracker.
+///
+/// The tracker splits data read from an RTSP service into RTSP messages and
+/// interleaved binary frames ('$', channel, u16 length, data) and keeps
+/// track of which of them are still buffered. RTP packets are grouped into
+/// media frames using the marker bit. Only complete media frames which have
+/// not been partially sent yet can be dropped.
+pub struct FrameTracker {
+    /// Complete segments (length, media frame of RTP packets).
+    segments:  VecDeque<(usize, Option<FrameId>)>,
+    /// Number of the current media frame of each channel.
+    current:   HashMap<u8, u64>,
+    /// Number of the last partially sent media frame of each channel.
+    sent:      HashMap<u8, u64>,
+    /// Number of bytes of the first segment which are no longer buffered.
+    consumed:  usize,
+    /// Header of the current incomplete segment.
+    header:    Vec<u8>,
+    /// Number of bytes of the current incomplete segment.
+    length:    usize,
+    /// Number of missing bytes of the current incomplete segment (if known).
+    remaining: Option<usize>,
+}
+
+impl FrameTracker {
+    /// Create a new frame tracker.
+    pub fn new() -> FrameTracker {
+        FrameTracker {
+            segments:  VecDeque::new(),
+            current:   HashMap::new(),
+            sent:      HashMap::new(),
+            consumed:  0,
+            header:    Vec::new(),
+            length:    0,
+            remaining: None
+        }
+    }
+
+    /// Process data appended to the buffer.
+    pub fn push(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if let Some(remaining) = self.remaining {
+                let len = if remaining < data.len() {
+                    remaining
+                } else {
+                    data.len()
+                };
+
+                self.length    += len;
+                self.remaining  = Some(remaining - len);
+
+                data = &data[len..];
+            } else {
+                self.header.push(data[0]);
+                self.length += 1;
+
+                data = &data[1..];
+
+                self.remaining = self.parse_header();
+
+                if self.remaining.is_none()
+                    && self.header.len() >= MAX_HEADER_LENGTH {
+                    self.remaining = Some(0);
+                }
+            }
+
+            if self.remaining == Some(0) {
+                let frame = self.rtp_frame();
+
+                self.segments.push_back((self.length, frame));
+                self.header.clear();
+                self.length    = 0;
+                self.remaining = None;
+            }
+        }
+    }
+
+    /// Process data removed from the front of the buffer.
+    pub fn consume(&mut self, count: usize) {
+        self.consumed += count;
+
+        while let Some(&(len, frame)) = self.segments.front() {
+            if self.consumed < len {
+                break;
+            }
+
+            if let Some((channel, number)) = frame {
+                self.sent.insert(channel, number);
+            }
+
+            self.consumed -= len;
+            self.segments.pop_front();
+        }
+    }
+
+    /// Drop the oldest complete media frames until at least a given number
+    /// of bytes is freed (if possible). The caller is responsible for
+    /// removing the returned ranges from the buffer.
+    pub fn drop_oldest(&mut self, count: usize) -> DroppedFrames {
+        let mut sent = self.sent.clone();
+
+        // the first segment has been partially sent
+        if self.consumed > 0 {
+            if let Some(&(_, Some((channel, number)))) = self.segments.front() {
+                sent.insert(channel, number);
+            }
+        }
+
+        let mut frames = Vec::new();
+        let mut sizes  = HashMap::new();
+
+        for &(len, frame) in &self.segments {
+            if let Some((channel, number)) = frame {
+                let complete = self.current.get(&channel)
+                    .map_or(false, |current| number < *current);
+                let unsent = sent.get(&channel)
+                    .map_or(true, |sent| number > *sent);
+
+                if complete && unsent {
+                    *sizes.entry((channel, number)).or_insert_with(|| {
+                        frames.push((channel, number));
+                        0
+                    }) += len;
+                }
+            }
+        }
+
+        let mut dropped = HashSet::new();
+        let mut freed   = 0;
+
+        for frame in frames {
+            if freed >= count {
+                break;
+            }
+
+            freed += sizes[&frame];
+
+            dropped.insert(frame);
+        }
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut kept   = VecDeque::new();
+        let mut offset = 0;
+
+        for (i, (len, frame)) in self.segments.drain(..).enumerate() {
+            let consumed = if i == 0 { self.consumed } else { 0 };
+
+            if frame.map_or(false, |frame| dropped.contains(&frame)) {
+                match ranges.last_mut() {
+                    Some(&mut (start, ref mut length))
+                        if start + *length == offset => *length += len,
+                    _ => ranges.push((offset, len))
+                }
+            } else {
+                kept.push_back((len, frame));
+            }
+
+            offset += len - consumed;
+        }
+
+        self.segments = kept;
+
+        DroppedFrames {
+            ranges: ranges,
+            frames: dropped.len(),
+            bytes:  freed
+        }
+    }
+
+    /// Get media frame of the current segment if it is an RTP packet. The
+    /// frame number of the channel is advanced if the marker bit is set.
+    fn rtp_frame(&mut self) -> Option<FrameId> {
+        if self.header[0] != b'$'
+            || self.header.len() < INTERLEAVED_HEADER_LENGTH {
+            return None;
+        }
+
+        let channel = self.header[1];
+        let version = self.header[4] >> 6;
+        let marker  = (self.header[5] & 0x80) != 0;
+
+        // RTCP packet types 192-223 are distinguished from RTP payload types
+        // as in RFC 5761
+        if version != 2 || (self.header[5] >= 192 && self.header[5] < 224) {
+            return None;
+        }
+
+        let current = self.current.entry(channel)
+            .or_insert(0);
+
+        let number = *current;
+
+        if marker {
+            *current += 1;
+        }
+
+        Some((channel, number))
+    }
+
+    /// Parse header of the current segment and return the number of
+    /// remaining bytes of the segment if it is already known.
+    fn parse_header(&self) -> Option<usize> {
+        if self.header[0] == b'$' {
+            if self.header.len() < 4 {
+                return None;
+            }
+
+            let len = ((self.header[2] as usize) << 8)
+                | (self.header[3] as usize);
+
+            // collect the beginning of the RTP header if there is one
+            let read = self.header.len() - 4;
+
+            if self.header.len() < INTERLEAVED_HEADER_LENGTH && read < len {
+                None
+            } else {
+                Some(len - read)
+            }
+        } else if self.header.ends_with(b"\r\n\r\n") {
+            Some(get_content_length(&self.header))
+        } else {
+            None
+        }
+    }
+}
+
+/// Get value of the Content-Length header of a given RTSP message header
+/// (zero is returned if there is no such header).
+fn get_content_length(header: &[u8]) -> usize {
+    let header = match str::from_utf8(header) {
+        Ok(header) => header,
+        Err(_)     => return 0
+    };
+
+    for line in header.lines() {
+        let mut parts = line.splitn(2, ':');
+
+        let name  = parts.next()
+            .unwrap_or("");
+        let value = parts.next()
+            .unwrap_or("");
+
+        if name.trim().to_lowercase() == "content-length" {
+            return value.trim()
+                .parse()
+                .unwrap_or(0);
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let mut policies = OverloadPolicies::new();
+
+        assert_eq!(policies.get(ServiceClass::RTSP), OverloadPolicy::Block);
+
+        assert!(policies.set(ServiceClass::RTSP, OverloadPolicy::DropOldest)
+            .is_ok());
+        assert!(policies.set(ServiceClass::HTTP, OverloadPolicy::DropOldest)
+            .is_err());
+
+        assert_eq!(policies.get(ServiceClass::RTSP),
+            OverloadPolicy::DropOldest);
+        assert_eq!(policies.get(ServiceClass::HTTP), OverloadPolicy::Block);
+    }
+
+    #[test]
+    fn test_frame_tracking() {
+        let response = b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nContent-Length: 2\r\n\r\nab";
+        let frame1   = [b'$', 0, 0, 3, 0x80, 0xe0, 3];
+        let frame2   = [b'$', 2, 0, 3, 0x80, 0x80, 4];
+
+        let mut tracker = FrameTracker::new();
+
+        tracker.push(&frame1[..2]);
+        tracker.push(&frame1[2..5]);
+        tracker.push(&frame1[5..]);
+        tracker.push(response);
+        tracker.push(&frame2);
+        tracker.push(&frame1[..5]);
+
+        // the first frame has been partially sent
+        tracker.consume(1);
+
+        let dropped = tracker.drop_oldest(1);
+
+        assert_eq!(dropped.ranges, vec![(6 + response.len(), 7)]);
+        assert_eq!(dropped.frames, 1);
+        assert_eq!(dropped.bytes, 7);
+
+        tracker.consume(6);
+
+        let dropped = tracker.drop_oldest(100);
+
+        assert!(dropped.ranges.is_empty());
+
+        // complete the last frame
+        tracker.push(&frame1[5..]);
+        tracker.consume(response.len());
+
+        let dropped = tracker.drop_oldest(100);
+
+        assert_eq!(dropped.ranges, vec![(0, 7)]);
+    }
+
+    #[test]
+    fn test_frame_boundaries() {
+        // two RTP packets of a single video frame, an RTCP packet and
+        // another video frame
+        let first  = [b'$', 0, 0, 3, 0x80, 0x60, 1];
+        let last   = [b'$', 0, 0, 3, 0x80, 0xe0, 2];
+        let rtcp   = [b'$', 1, 0, 3, 0x80, 0xc8, 3];
+        let next   = [b'$', 0, 0, 3, 0x80, 0x60, 4];
+
+        let mut tracker = FrameTracker::new();
+
+        tracker.push(&first);
+        tracker.push(&rtcp);
+        tracker.push(&last);
+        tracker.push(&next);
+
+        // the whole first frame is dropped even though its first packet
+        // would be enough, the RTCP packet and the incomplete frame are kept
+        let dropped = tracker.drop_oldest(1);
+
+        assert_eq!(dropped.ranges, vec![(0, 7), (14, 7)]);
+        assert_eq!(dropped.frames, 1);
+        assert_eq!(dropped.bytes, 14);
+
+        let dropped = tracker.drop_oldest(100);
+
+        assert!(dropped.ranges.is_empty());
+
+        // frames which have been partially sent are never dropped
+        tracker.consume(8);
+        tracker.push(&last);
+        tracker.push(&first);
+        tracker.push(&last);
+
+        let dropped = tracker.drop_oldest(100);
+
+        assert_eq!(dropped.ranges, vec![(6 + 7, 14)]);
+        assert_eq!(dropped.frames, 1);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Build info definitions.
+//!
+//! Build info is appended to the STATUS messages if the server supports the
+//! FEATURE_BUILD_INFO Control Protocol feature. It uses the same TLV encoding
+//! as the client metadata (u16 type, u16 length, value) and the list is
+//! terminated by a record of the BUILD_END type with zero length.
+
+use std::io;
+
+use std::io::Write;
+
+use utils::Serialize;
+use utils::build_info;
+
+use net::arrow::protocol::ControlMessageBody;
+
+pub const BUILD_END:        u16 = 0x0000;
+pub const BUILD_VERSION:    u16 = 0x0001;
+pub const BUILD_GIT_HASH:   u16 = 0x0002;
+pub const BUILD_DATE:       u16 = 0x0003;
+pub const BUILD_FEATURES:   u16 = 0x0004;
+pub const BUILD_OPENSSL:    u16 = 0x0005;
+
+/// Build info of the client.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BuildInfo {
+    items: Vec<(u16, String)>,
+}
+
+impl BuildInfo {
+    /// Create build info from given values.
+    pub fn new(
+        version: &str,
+        git_hash: &str,
+        build_date: &str,
+        features: &[&str],
+        openssl: &str) -> BuildInfo {
+        let items = vec![
+            (BUILD_VERSION,  version.to_string()),
+            (BUILD_GIT_HASH, git_hash.to_string()),
+            (BUILD_DATE,     build_date.to_string()),
+            (BUILD_FEATURES, features.join(",")),
+            (BUILD_OPENSSL,  openssl.to_string())
+        ];
+
+        BuildInfo {
+            items: items
+        }
+    }
+
+    /// Get build info of this client.
+    pub fn current() -> BuildInfo {
+        BuildInfo::new(
+            build_info::VERSION,
+            build_info::GIT_HASH,
+            build_info::BUILD_DATE,
+            &build_info::features(),
+            build_info::openssl_version())
+    }
+}
+
+impl Serialize for BuildInfo {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for &(item_type, ref value) in &self.items {
+            try!(item_type.serialize(w));
+            try!((value.len() as u16).serialize(w));
+            try!(w.write_all(value.as_bytes()));
+        }
+
+        try!(BUILD_END.serialize(w));
+        0u16.serialize(w)
+    }
+}
+
+impl ControlMessageBody for BuildInfo {
+    fn len(&self) -> usize {
+        4 + self.items.iter()
+            .fold(0, |sum, &(_, ref value)| sum + 4 + value.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::arrow::protocol::ControlMessageBody;
+
+    #[test]
+    fn test_build_info_serialization() {
+        let info = BuildInfo::new("1.0", "ab12", "d", &["x", "y"], "ssl");
+
+        let mut buffer = Vec::new();
+
+        info.serialize(&mut buffer)
+            .unwrap();
+
+        let expected = [
+            0x00, 0x01, 0x00, 0x03, b'1', b'.', b'0',
+            0x00, 0x02, 0x00, 0x04, b'a', b'b', b'1', b'2',
+            0x00, 0x03, 0x00, 0x01, b'd',
+            0x00, 0x04, 0x00, 0x03, b'x', b',', b'y',
+            0x00, 0x05, 0x00, 0x03, b's', b's', b'l',
+            0x00, 0x00, 0x00, 0x00];
+
+        assert_eq!(info.len(), expected.len());
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+}
@@ -15,6 +15,7 @@
 //! Common Arrow Control Protocol definitions.
 
 use std::io;
+use std::cmp;
 use std::mem;
 
 use std::io::Write;
@@ -22,8 +23,13 @@ use std::io::Write;
 use utils;
 
 use utils::Serialize;
+use utils::endian;
 use net::arrow::error::{ArrowError, Result};
 use net::arrow::protocol::{ArrowMessageBody, ServiceTable, ScanReportMessage};
+use net::arrow::protocol::{ScanSummary, Metadata, SvcStatsMessage};
+use net::arrow::protocol::{ServiceTablePage, NetworkEvent, BuildInfo};
+use net::arrow::protocol::ProtocolViolation;
+use net::traffic::TrafficStats;
 
 /// Arrow Control Protocol message types.
 #[allow(non_camel_case_types)]
@@ -42,6 +48,15 @@ pub enum ControlMessageType {
     UNKNOWN,
     GET_SCAN_REPORT,
     SCAN_REPORT,
+    GET_SVC_STATS,
+    SVC_STATS,
+    UPDATE_PAGE,
+    ROTATE_SECRET,
+    EVENT,
+    PROTOCOL_VIOLATION,
+    HUP_REPORT,
+    REMOVE_SERVICE,
+    SET_LOG_LEVEL,
 }
 
 pub const ACK_NO_ERROR:                     u32 = 0x00000000;
@@ -49,8 +64,78 @@ pub const ACK_UNSUPPORTED_PROTOCOL_VERSION: u32 = 0x00000001;
 pub const ACK_UNAUTHORIZED:                 u32 = 0x00000002;
 pub const ACK_CONNECTION_ERROR:             u32 = 0x00000003;
 pub const ACK_UNSUPPORTED_METHOD:           u32 = 0x00000004;
+pub const ACK_AUTH_CHALLENGE:               u32 = 0x00000005;
 pub const ACK_INTERNAL_SERVER_ERROR:        u32 = 0xffffffff;
 
+// error codes above HUP_CONNECTION_ERROR can be sent only to servers
+// supporting FEATURE_HUP_CODES (see ControlChannel::hup_message())
+pub const HUP_NO_ERROR:                     u32 = 0x00000000;
+pub const HUP_SESSION_ERROR:                u32 = 0x00000001;
+pub const HUP_CONNECTION_ERROR:             u32 = 0x00000002;
+pub const HUP_SERVICE_UNREACHABLE:          u32 = 0x00000003;
+pub const HUP_CLOCK_BEHIND:                 u32 = 0x00000004;
+pub const HUP_ACCESS_DENIED:                u32 = 0x00000005;
+
+/// Control Protocol version implemented by this client.
+pub const CONTROL_PROTOCOL_VERSION: u16 = 2;
+
+/// Control Protocol version of servers which do not send their protocol info.
+const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+// optional Control Protocol features
+pub const FEATURE_SCAN_SUMMARY: u32 = 0x00000001;
+pub const FEATURE_METADATA:     u32 = 0x00000002;
+pub const FEATURE_HUP_CODES:    u32 = 0x00000004;
+pub const FEATURE_SVC_STATS:    u32 = 0x00000008;
+pub const FEATURE_TABLE_PAGES:  u32 = 0x00000010;
+pub const FEATURE_DATA_SEQUENCE: u32 = 0x00000020;
+pub const FEATURE_SECRET_ROTATION: u32 = 0x00000040;
+pub const FEATURE_NETWORK_EVENTS: u32 = 0x00000080;
+pub const FEATURE_TRAFFIC_STATS: u32 = 0x00000100;
+pub const FEATURE_BUILD_INFO:   u32 = 0x00000200;
+pub const FEATURE_PROTOCOL_VIOLATIONS: u32 = 0x00000400;
+pub const FEATURE_DATA_TIMESTAMPS: u32 = 0x00000800;
+pub const FEATURE_E2E_ENCRYPTION: u32 = 0x00001000;
+pub const FEATURE_HUP_REPORTS:  u32 = 0x00002000;
+pub const FEATURE_DATAGRAM_SESSIONS: u32 = 0x00004000;
+pub const FEATURE_SERVICE_REMOVAL: u32 = 0x00008000;
+pub const FEATURE_LOG_LEVEL_CONTROL: u32 = 0x00010000;
+pub const FEATURE_MEDIA_INFO:   u32 = 0x00020000;
+pub const FEATURE_SEQUENTIAL_MSG_IDS: u32 = 0x00040000;
+
+/// End-to-end encryption support of this build.
+#[cfg(feature = "e2e")]
+const E2E_FEATURES: u32 = FEATURE_E2E_ENCRYPTION;
+#[cfg(not(feature = "e2e"))]
+const E2E_FEATURES: u32 = 0;
+
+/// Datagram session support of this build.
+#[cfg(feature = "datagram")]
+const DATAGRAM_FEATURES: u32 = FEATURE_DATAGRAM_SESSIONS;
+#[cfg(not(feature = "datagram"))]
+const DATAGRAM_FEATURES: u32 = 0;
+
+/// Features supported by this client.
+pub const CLIENT_FEATURES: u32 = FEATURE_SCAN_SUMMARY
+    | FEATURE_METADATA
+    | FEATURE_HUP_CODES
+    | FEATURE_SVC_STATS
+    | FEATURE_TABLE_PAGES
+    | FEATURE_DATA_SEQUENCE
+    | FEATURE_SECRET_ROTATION
+    | FEATURE_NETWORK_EVENTS
+    | FEATURE_TRAFFIC_STATS
+    | FEATURE_BUILD_INFO
+    | FEATURE_PROTOCOL_VIOLATIONS
+    | FEATURE_DATA_TIMESTAMPS
+    | E2E_FEATURES
+    | FEATURE_HUP_REPORTS
+    | DATAGRAM_FEATURES
+    | FEATURE_SERVICE_REMOVAL
+    | FEATURE_LOG_LEVEL_CONTROL
+    | FEATURE_MEDIA_INFO
+    | FEATURE_SEQUENTIAL_MSG_IDS;
+
 // message type constants
 const CMSG_ACK:             u16 = 0x0000;
 const CMSG_PING:            u16 = 0x0001;
@@ -64,6 +149,36 @@ const CMSG_GET_STATUS:      u16 = 0x0008;
 const CMSG_STATUS:          u16 = 0x0009;
 const CMSG_GET_SCAN_REPORT: u16 = 0x000a;
 const CMSG_SCAN_REPORT:     u16 = 0x000b;
+const CMSG_GET_SVC_STATS:   u16 = 0x000c;
+const CMSG_SVC_STATS:       u16 = 0x000d;
+const CMSG_UPDATE_PAGE:     u16 = 0x000e;
+const CMSG_ROTATE_SECRET:   u16 = 0x000f;
+const CMSG_EVENT:           u16 = 0x0010;
+const CMSG_PROTOCOL_VIOLATION: u16 = 0x0011;
+const CMSG_HUP_REPORT:      u16 = 0x0012;
+const CMSG_REMOVE_SERVICE:  u16 = 0x0013;
+const CMSG_SET_LOG_LEVEL:   u16 = 0x0014;
+
+/// Size of the Control Protocol message header in bytes.
+const CONTROL_HEADER_SIZE: usize = 4;
+
+/// Size of the REGISTER message header in bytes.
+const REGISTER_HEADER_SIZE: usize = 38;
+
+/// Size of the UPDATE_PAGE message header in bytes.
+const UPDATE_PAGE_HEADER_SIZE: usize = 4;
+
+/// Size of the HUP message in bytes.
+const HUP_MESSAGE_SIZE: usize = 8;
+
+/// Size of the HUP_REPORT message in bytes.
+const HUP_REPORT_SIZE: usize = 18;
+
+/// Size of the SET_LOG_LEVEL message in bytes.
+const SET_LOG_LEVEL_SIZE: usize = 6;
+
+/// Size of the STATUS message header in bytes.
+const STATUS_HEADER_SIZE: usize = 10;
 
 /// Common trait for Control Protocol payload types.
 pub trait ControlMessageBody : Serialize {
@@ -73,7 +188,6 @@ pub trait ControlMessageBody : Serialize {
 
 /// Arrow Control Protocol message header.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
 pub struct ControlMessageHeader {
     /// Message ID.
     pub msg_id:   u16,
@@ -93,13 +207,11 @@ impl ControlMessageHeader {
     
     /// Deserialize a Control Message header.
     fn from_bytes(data: &[u8]) -> ControlMessageHeader {
-        assert_eq!(data.len(), mem::size_of::<ControlMessageHeader>());
-        let ptr    = data.as_ptr() as *const ControlMessageHeader;
-        let header = unsafe { &*ptr };
+        assert_eq!(data.len(), CONTROL_HEADER_SIZE);
         
         ControlMessageHeader {
-            msg_id:   u16::from_be(header.msg_id),
-            msg_type: u16::from_be(header.msg_type)
+            msg_id:   endian::get_u16(data),
+            msg_type: endian::get_u16(&data[2..])
         }
     }
     
@@ -118,6 +230,15 @@ impl ControlMessageHeader {
             CMSG_STATUS          => ControlMessageType::STATUS,
             CMSG_GET_SCAN_REPORT => ControlMessageType::GET_SCAN_REPORT,
             CMSG_SCAN_REPORT     => ControlMessageType::SCAN_REPORT,
+            CMSG_GET_SVC_STATS   => ControlMessageType::GET_SVC_STATS,
+            CMSG_SVC_STATS       => ControlMessageType::SVC_STATS,
+            CMSG_UPDATE_PAGE     => ControlMessageType::UPDATE_PAGE,
+            CMSG_ROTATE_SECRET   => ControlMessageType::ROTATE_SECRET,
+            CMSG_EVENT           => ControlMessageType::EVENT,
+            CMSG_PROTOCOL_VIOLATION => ControlMessageType::PROTOCOL_VIOLATION,
+            CMSG_HUP_REPORT      => ControlMessageType::HUP_REPORT,
+            CMSG_REMOVE_SERVICE  => ControlMessageType::REMOVE_SERVICE,
+            CMSG_SET_LOG_LEVEL   => ControlMessageType::SET_LOG_LEVEL,
             _ => ControlMessageType::UNKNOWN
         }
     }
@@ -125,12 +246,8 @@ impl ControlMessageHeader {
 
 impl Serialize for ControlMessageHeader {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_header = ControlMessageHeader {
-            msg_id:   self.msg_id.to_be(),
-            msg_type: self.msg_type.to_be()
-        };
-        
-        w.write_all(utils::as_bytes(&be_header))
+        try!(self.msg_id.serialize(w));
+        self.msg_type.serialize(w)
     }
 }
 
@@ -168,7 +285,7 @@ impl<B: ControlMessageBody> Serialize for ControlMessage<B> {
 
 impl<B: ControlMessageBody> ArrowMessageBody for ControlMessage<B> {
     fn len(&self) -> usize {
-        mem::size_of::<ControlMessageHeader>() + self.body.len()
+        CONTROL_HEADER_SIZE + self.body.len()
     }
 }
 
@@ -189,11 +306,26 @@ pub fn create_register_message(
     ControlMessage::new(msg_id, CMSG_REGISTER, body)
 }
 
-/// Create a new UPDATE message for a given message ID and service table.
+/// Create a new REGISTER message for a given message ID and an already
+/// serialized message body.
+pub fn create_encoded_register_message(
+    msg_id: u16,
+    body: Vec<u8>) -> ControlMessage<Vec<u8>> {
+    ControlMessage::new(msg_id, CMSG_REGISTER, body)
+}
+
+/// Create a new UPDATE message for a given message ID and message body.
 pub fn create_update_message(
     msg_id: u16, 
-    svc_table: ServiceTable) -> ControlMessage<ServiceTable> {
-    ControlMessage::new(msg_id, CMSG_UPDATE, svc_table.clone())
+    body: UpdateMessage) -> ControlMessage<UpdateMessage> {
+    ControlMessage::new(msg_id, CMSG_UPDATE, body)
+}
+
+/// Create a new UPDATE_PAGE message for a given message ID and message body.
+pub fn create_update_page_message(
+    msg_id: u16,
+    body: UpdatePageMessage) -> ControlMessage<UpdatePageMessage> {
+    ControlMessage::new(msg_id, CMSG_UPDATE_PAGE, body)
 }
 
 /// Create a new HUP message for a given message ID, session ID and error code.
@@ -205,6 +337,13 @@ pub fn create_hup_message(
         HupMessage::new(session_id, error_code))
 }
 
+/// Create a new HUP_REPORT message for a given message ID and report.
+pub fn create_hup_report_message(
+    msg_id: u16,
+    report: HupReport) -> ControlMessage<HupReport> {
+    ControlMessage::new(msg_id, CMSG_HUP_REPORT, report)
+}
+
 /// Create a new STATUS control message for a given message ID and message 
 /// body.
 pub fn create_status_message(
@@ -221,6 +360,30 @@ pub fn create_scan_report_message(
     ControlMessage::new(msg_id, CMSG_SCAN_REPORT, scan_report_msg)
 }
 
+/// Create a new SVC_STATS control message for a given message ID and message 
+/// body.
+pub fn create_svc_stats_message(
+    msg_id: u16,
+    svc_stats_msg: SvcStatsMessage) -> ControlMessage<SvcStatsMessage> {
+    ControlMessage::new(msg_id, CMSG_SVC_STATS, svc_stats_msg)
+}
+
+/// Create a new EVENT control message for a given message ID and network
+/// event.
+pub fn create_event_message(
+    msg_id: u16,
+    event: NetworkEvent) -> ControlMessage<NetworkEvent> {
+    ControlMessage::new(msg_id, CMSG_EVENT, event)
+}
+
+/// Create a new PROTOCOL_VIOLATION control message for a given message ID
+/// and protocol violation.
+pub fn create_protocol_violation_message(
+    msg_id: u16,
+    violation: ProtocolViolation) -> ControlMessage<ProtocolViolation> {
+    ControlMessage::new(msg_id, CMSG_PROTOCOL_VIOLATION, violation)
+}
+
 /// Arrow Control Protocol message parser.
 pub struct ControlMessageParser<'a> {
     header: Option<ControlMessageHeader>,
@@ -238,7 +401,7 @@ impl<'a> ControlMessageParser<'a> {
     
     /// Process given message data.
     pub fn process(&mut self, data: &'a [u8]) -> Result<()> {
-        let header_size = mem::size_of::<ControlMessageHeader>();
+        let header_size = CONTROL_HEADER_SIZE;
         if data.len() < header_size {
             return Err(ArrowError::other("not enough data to parse an Arrow Control Protocol message"));
         }
@@ -280,6 +443,12 @@ impl ControlMessageBody for u32 {
 #[derive(Debug, Copy, Clone)]
 pub struct EmptyBody;
 
+impl ControlMessageBody for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::<u8>::len(self)
+    }
+}
+
 impl Serialize for EmptyBody {
     fn serialize<W: Write>(&self, _: &mut W) -> io::Result<()> {
         Ok(())
@@ -294,7 +463,6 @@ impl ControlMessageBody for EmptyBody {
 
 /// REGISTER message header.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
 pub struct RegisterMessageHeader {
     /// Client identifier.
     pub uuid:     [u8; 16],
@@ -320,7 +488,82 @@ impl RegisterMessageHeader {
 
 impl Serialize for RegisterMessageHeader {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(utils::as_bytes(self))
+        try!(w.write_all(&self.uuid));
+        try!(w.write_all(&self.mac_addr));
+        w.write_all(&self.passwd)
+    }
+}
+
+/// Control Protocol version and feature set.
+///
+/// The client sends its protocol info within the REGISTER message (right 
+/// after the service table) and the server responds with its own protocol 
+/// info within the REGISTER ACK (right after the error code). Optional 
+/// features are used only if they are supported by both sides.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProtocolInfo {
+    /// Control Protocol version.
+    pub version:  u16,
+    /// Supported optional features.
+    pub features: u32,
+}
+
+impl ProtocolInfo {
+    /// Create a new protocol info.
+    pub fn new(version: u16, features: u32) -> ProtocolInfo {
+        ProtocolInfo {
+            version:  version,
+            features: features
+        }
+    }
+    
+    /// Get protocol info of this client.
+    pub fn client() -> ProtocolInfo {
+        ProtocolInfo::new(CONTROL_PROTOCOL_VERSION, CLIENT_FEATURES)
+    }
+    
+    /// Get protocol info of a legacy server (i.e. a server which does not 
+    /// send any protocol info).
+    pub fn legacy() -> ProtocolInfo {
+        ProtocolInfo::new(LEGACY_PROTOCOL_VERSION, 0)
+    }
+    
+    /// Negotiate protocol version and features with a given peer.
+    pub fn negotiate(&self, peer: &ProtocolInfo) -> ProtocolInfo {
+        ProtocolInfo {
+            version:  cmp::min(self.version, peer.version),
+            features: self.features & peer.features
+        }
+    }
+    
+    /// Check if a given feature is supported.
+    pub fn supports(&self, feature: u32) -> bool {
+        (self.features & feature) == feature
+    }
+    
+    /// Parse protocol info.
+    pub fn from_bytes(data: &[u8]) -> Result<ProtocolInfo> {
+        if data.len() < 6 {
+            return Err(ArrowError::other("invalid size of Arrow Control Protocol info"));
+        }
+        
+        let version  = endian::get_u16(data);
+        let features = endian::get_u32(&data[2..]);
+        
+        Ok(ProtocolInfo::new(version, features))
+    }
+}
+
+impl Serialize for ProtocolInfo {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.version.serialize(w));
+        self.features.serialize(w)
+    }
+}
+
+impl ControlMessageBody for ProtocolInfo {
+    fn len(&self) -> usize {
+        mem::size_of::<u16>() + mem::size_of::<u32>()
     }
 }
 
@@ -328,9 +571,11 @@ impl Serialize for RegisterMessageHeader {
 #[derive(Debug, Clone)]
 pub struct RegisterMessage {
     /// Message header.
-    header: RegisterMessageHeader,
+    header:   RegisterMessageHeader,
     /// Service table.
-    table:  ServiceTable,
+    table:    ServiceTable,
+    /// Client protocol info (not sent to legacy servers).
+    protocol: Option<ProtocolInfo>,
 }
 
 impl RegisterMessage {
@@ -341,8 +586,23 @@ impl RegisterMessage {
         passwd: [u8; 16], 
         svc_table: ServiceTable) -> RegisterMessage {
         RegisterMessage {
-            header: RegisterMessageHeader::new(uuid, mac_addr, passwd),
-            table:  svc_table
+            header:   RegisterMessageHeader::new(uuid, mac_addr, passwd),
+            table:    svc_table,
+            protocol: Some(ProtocolInfo::client())
+        }
+    }
+    
+    /// Create a new REGISTER message without the client protocol info (i.e.
+    /// a message understood by legacy servers).
+    pub fn legacy(
+        uuid: [u8; 16], 
+        mac_addr: [u8; 6], 
+        passwd: [u8; 16], 
+        svc_table: ServiceTable) -> RegisterMessage {
+        RegisterMessage {
+            header:   RegisterMessageHeader::new(uuid, mac_addr, passwd),
+            table:    svc_table,
+            protocol: None
         }
     }
     
@@ -360,19 +620,144 @@ impl RegisterMessage {
 impl Serialize for RegisterMessage {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
         try!(self.header.serialize(w));
-        self.table.serialize(w)
+        try!(self.table.serialize(w));
+        
+        match self.protocol {
+            Some(ref protocol) => protocol.serialize(w),
+            None => Ok(())
+        }
     }
 }
 
 impl ControlMessageBody for RegisterMessage {
     fn len(&self) -> usize {
-        mem::size_of::<RegisterMessageHeader>() + self.table.len()
+        let protocol_size = match self.protocol {
+            Some(ref protocol) => protocol.len(),
+            None => 0
+        };
+        
+        REGISTER_HEADER_SIZE
+            + self.table.len()
+            + protocol_size
+    }
+}
+
+/// UPDATE message.
+#[derive(Debug, Clone)]
+pub struct UpdateMessage {
+    /// Service table.
+    table:    ServiceTable,
+    /// Client metadata.
+    metadata: Metadata,
+}
+
+impl UpdateMessage {
+    /// Create a new UPDATE message.
+    pub fn new(svc_table: ServiceTable, metadata: Metadata) -> UpdateMessage {
+        UpdateMessage {
+            table:    svc_table,
+            metadata: metadata
+        }
+    }
+}
+
+impl Serialize for UpdateMessage {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.table.serialize(w));
+        self.metadata.serialize(w)
+    }
+}
+
+impl ControlMessageBody for UpdateMessage {
+    fn len(&self) -> usize {
+        self.table.len() + self.metadata.len()
+    }
+}
+
+/// UPDATE_PAGE message header.
+#[derive(Debug, Copy, Clone)]
+struct UpdatePageHeader {
+    /// Page index (starting from zero).
+    page:  u16,
+    /// Total number of pages.
+    pages: u16,
+}
+
+impl Serialize for UpdatePageHeader {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.page.serialize(w));
+        self.pages.serialize(w)
+    }
+}
+
+/// UPDATE_PAGE message. It carries a single page of a service table which
+/// is too big to be sent in a single UPDATE message. Arrow Service replaces
+/// its copy of the service table once it receives the last page (i.e. the
+/// last page works as a commit marker). Client metadata are sent only with
+/// the last page.
+#[derive(Debug, Clone)]
+pub struct UpdatePageMessage {
+    /// Page header.
+    header:   UpdatePageHeader,
+    /// Service table page.
+    table:    ServiceTablePage,
+    /// Client metadata (only in the last page).
+    metadata: Option<Metadata>,
+}
+
+impl UpdatePageMessage {
+    /// Create a new UPDATE_PAGE message with a given page index, total
+    /// number of pages and service table page. Metadata are ignored unless
+    /// this is the last page.
+    pub fn new(
+        page: u16,
+        pages: u16,
+        table: ServiceTablePage,
+        metadata: Metadata) -> UpdatePageMessage {
+        let metadata = if (page + 1) == pages {
+            Some(metadata)
+        } else {
+            None
+        };
+
+        UpdatePageMessage {
+            header:   UpdatePageHeader {
+                page:  page,
+                pages: pages
+            },
+            table:    table,
+            metadata: metadata
+        }
+    }
+}
+
+impl Serialize for UpdatePageMessage {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.header.serialize(w));
+        try!(self.table.serialize(w));
+
+        match self.metadata {
+            Some(ref metadata) => metadata.serialize(w),
+            None => Ok(())
+        }
+    }
+}
+
+impl ControlMessageBody for UpdatePageMessage {
+    fn len(&self) -> usize {
+        let metadata_len = match self.metadata {
+            Some(ref metadata) => metadata.len(),
+            None => 0
+        };
+
+        UPDATE_PAGE_HEADER_SIZE
+            + self.table.len()
+            + metadata_len
     }
 }
 
 /// HUP message.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
 pub struct HupMessage {
     /// Session ID (note: the upper 8 bits are reserved).
     pub session_id: u32,
@@ -391,16 +776,13 @@ impl HupMessage {
     
     /// Parse a HUP message.
     pub fn from_bytes(data: &[u8]) -> Result<HupMessage> {
-        let msg_size = mem::size_of::<HupMessage>();
-        if data.len() != msg_size {
+        if data.len() != HUP_MESSAGE_SIZE {
             return Err(ArrowError::other("invalid size of an Arrow Control Protocol HUP message"));
         }
         
-        let ptr = data.as_ptr() as *const HupMessage;
-        let msg = unsafe { &*ptr };
         let res = HupMessage {
-            session_id: u32::from_be(msg.session_id),
-            error_code: u32::from_be(msg.error_code)
+            session_id: endian::get_u32(data),
+            error_code: endian::get_u32(&data[4..])
         };
         
         Ok(res)
@@ -409,73 +791,269 @@ impl HupMessage {
 
 impl Serialize for HupMessage {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_msg = HupMessage {
-            session_id: self.session_id.to_be(),
-            error_code: self.error_code.to_be()
-        };
-        
-        w.write_all(utils::as_bytes(&be_msg))
+        try!(self.session_id.serialize(w));
+        self.error_code.serialize(w)
     }
 }
 
 impl ControlMessageBody for HupMessage {
     fn len(&self) -> usize {
-        mem::size_of::<HupMessage>()
+        HUP_MESSAGE_SIZE
+    }
+}
+
+/// HUP_REPORT message (aggregated session failures of a single service).
+#[derive(Debug, Copy, Clone)]
+pub struct HupReport {
+    /// Service ID.
+    pub service_id: u16,
+    /// Number of failed sessions within the reported period.
+    pub failures:   u32,
+    /// Number of failed sessions without a HUP message.
+    pub suppressed: u32,
+    /// Length of the reported period (in seconds).
+    pub period:     u32,
+    /// Error code of the last failure.
+    pub last_error: u32,
+}
+
+impl HupReport {
+    /// Create a new HUP report.
+    pub fn new(
+        service_id: u16,
+        failures: u32,
+        suppressed: u32,
+        period: u32,
+        last_error: u32) -> HupReport {
+        HupReport {
+            service_id: service_id,
+            failures:   failures,
+            suppressed: suppressed,
+            period:     period,
+            last_error: last_error
+        }
+    }
+}
+
+impl Serialize for HupReport {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.service_id.serialize(w));
+        try!(self.failures.serialize(w));
+        try!(self.suppressed.serialize(w));
+        try!(self.period.serialize(w));
+        self.last_error.serialize(w)
+    }
+}
+
+impl ControlMessageBody for HupReport {
+    fn len(&self) -> usize {
+        HUP_REPORT_SIZE
     }
 }
 
 /// Status flag indicating that there is a network scan currently in progress.
 pub const STATUS_FLAG_SCAN: u32 = 0x00000001;
 
-/// Status message.
+/// Status flag indicating that the client system clock is behind (the Arrow
+/// Service certificate was accepted in the clock grace mode).
+pub const STATUS_FLAG_CLOCK_BEHIND: u32 = 0x00000002;
+
+/// Status flag indicating that the last network scan has been cut short
+/// because its probing budget has been exhausted.
+pub const STATUS_FLAG_SCAN_INCOMPLETE: u32 = 0x00000004;
+
+/// Status message header.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
-pub struct StatusMessage {
+struct StatusMessageHeader {
     request_id:      u16,
     status_flags:    u32,
     active_sessions: u32,
 }
 
+impl Serialize for StatusMessageHeader {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.request_id.serialize(w));
+        try!(self.status_flags.serialize(w));
+        self.active_sessions.serialize(w)
+    }
+}
+
+/// Status message.
+#[derive(Debug, Clone)]
+pub struct StatusMessage {
+    header:       StatusMessageHeader,
+    scan_summary: Option<ScanSummary>,
+    traffic:      Option<TrafficStats>,
+    build_info:   Option<BuildInfo>,
+}
+
 impl StatusMessage {
+    /// Create a new status message. The scan summary is sent only if it is 
+    /// given (i.e. only if it is supported by the server).
     pub fn new(
         request_id: u16, 
         status_flags: u32, 
-        active_sessions: u32) -> StatusMessage {
-        StatusMessage {
+        active_sessions: u32,
+        scan_summary: Option<&ScanSummary>) -> StatusMessage {
+        let header = StatusMessageHeader {
             request_id:      request_id,
             status_flags:    status_flags,
             active_sessions: active_sessions
+        };
+        
+        StatusMessage {
+            header:       header,
+            scan_summary: scan_summary.map(|summary| *summary),
+            traffic:      None,
+            build_info:   None
         }
     }
+    
+    /// Append given uplink traffic statistics. The statistics are sent only
+    /// together with the scan summary (they follow it in the message).
+    pub fn set_traffic(&mut self, traffic: &TrafficStats) {
+        self.traffic = Some(*traffic);
+    }
+    
+    /// Append given build info. The build info is sent only together with
+    /// the traffic statistics (it follows them in the message).
+    pub fn set_build_info(&mut self, build_info: BuildInfo) {
+        self.build_info = Some(build_info);
+    }
+    
+    /// Check if the build info will be sent.
+    fn has_build_info(&self) -> bool {
+        self.scan_summary.is_some()
+            && self.traffic.is_some()
+            && self.build_info.is_some()
+    }
 }
 
 impl Serialize for StatusMessage {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_msg = StatusMessage {
-            request_id:      self.request_id.to_be(),
-            status_flags:    self.status_flags.to_be(),
-            active_sessions: self.active_sessions.to_be()
-        };
+        try!(self.header.serialize(w));
+        
+        if let Some(ref summary) = self.scan_summary {
+            try!(summary.timestamp.serialize(w));
+            try!(summary.duration.serialize(w));
+            try!(summary.hosts.serialize(w));
+            try!(summary.new_services.serialize(w));
+            try!(summary.removed_services.serialize(w));
+            
+            if let Some(ref traffic) = self.traffic {
+                try!(traffic.rx_rate.serialize(w));
+                try!(traffic.tx_rate.serialize(w));
+                try!(traffic.utilization.serialize(w));
+                
+                if let Some(ref build_info) = self.build_info {
+                    try!(build_info.serialize(w));
+                }
+            }
+        }
         
-        w.write_all(utils::as_bytes(&be_msg))
+        Ok(())
     }
 }
 
 impl ControlMessageBody for StatusMessage {
     fn len(&self) -> usize {
-        mem::size_of::<StatusMessage>()
+        let summary_size = if self.scan_summary.is_some() {
+            mem::size_of::<i64>() + 4 * mem::size_of::<u32>()
+        } else {
+            0
+        };
+        
+        let traffic_size = if self.scan_summary.is_some() && self.traffic.is_some() {
+            2 * mem::size_of::<u32>() + mem::size_of::<u8>()
+        } else {
+            0
+        };
+        
+        let build_info_size = match self.build_info {
+            Some(ref build_info) if self.has_build_info() => build_info.len(),
+            _ => 0
+        };
+        
+        STATUS_HEADER_SIZE + summary_size + traffic_size
+            + build_info_size
+    }
+}
+
+/// Parse a given REGISTER ACK message body and return the error code and 
+/// the server protocol info. Legacy servers send only the error code.
+pub fn parse_register_ack(msg: &[u8]) -> Result<(u32, ProtocolInfo)> {
+    let ack_size = mem::size_of::<u32>();
+    
+    if msg.len() < ack_size {
+        return Err(ArrowError::other("incorrect Control Protocol ACK message length"));
+    }
+    
+    let ack = try_arr!(parse_ack_message(&msg[..ack_size]));
+    
+    if msg.len() == ack_size {
+        Ok((ack, ProtocolInfo::legacy()))
+    } else {
+        Ok((ack, try_arr!(ProtocolInfo::from_bytes(&msg[ack_size..]))))
+    }
+}
+
+/// Check if a given REGISTER ACK message body comes from a legacy server
+/// (i.e. it contains only the error code) or if it is garbled (a server which
+/// does not know the client protocol info may misparse the whole REGISTER
+/// message).
+pub fn is_legacy_register_ack(msg: &[u8]) -> bool {
+    msg.len() <= mem::size_of::<u32>() || parse_register_ack(msg).is_err()
+}
+
+/// Parse a given REGISTER ACK message body carrying an authentication
+/// challenge and return the challenge (i.e. everything after the error
+/// code).
+pub fn parse_auth_challenge(msg: &[u8]) -> Result<Vec<u8>> {
+    let ack_size = mem::size_of::<u32>();
+    
+    if msg.len() > ack_size {
+        Ok(msg[ack_size..].to_vec())
+    } else {
+        Err(ArrowError::other("empty authentication challenge"))
+    }
+}
+
+/// Parse a given ROTATE_SECRET message body and return the new secret.
+pub fn parse_rotate_secret(msg: &[u8]) -> Result<[u8; 16]> {
+    if msg.len() != 16 {
+        return Err(ArrowError::other("incorrect Control Protocol ROTATE_SECRET message length"));
+    }
+    
+    let mut res = [0u8; 16];
+    
+    utils::memcpy(&mut res, msg);
+    
+    Ok(res)
+}
+
+/// Parse a given REMOVE_SERVICE message body and return the service ID.
+pub fn parse_remove_service(msg: &[u8]) -> Result<u16> {
+    if msg.len() == mem::size_of::<u16>() {
+        Ok(endian::get_u16(msg))
+    } else {
+        Err(ArrowError::other("incorrect Control Protocol REMOVE_SERVICE message length"))
+    }
+}
+
+/// Parse a given SET_LOG_LEVEL message body and return the log level
+/// (0 - debug, 1 - info, 2 - warning, 3 - error) and its duration in seconds.
+pub fn parse_set_log_level(msg: &[u8]) -> Result<(u16, u32)> {
+    if msg.len() == SET_LOG_LEVEL_SIZE {
+        Ok((endian::get_u16(msg), endian::get_u32(&msg[2..])))
+    } else {
+        Err(ArrowError::other("incorrect Control Protocol SET_LOG_LEVEL message length"))
     }
 }
 
 /// Parse a given ACK message body and return the error code.
 pub fn parse_ack_message(msg: &[u8]) -> Result<u32> {
     if msg.len() == mem::size_of::<u32>() {
-        let ptr = msg.as_ptr() as *const u32;
-        let ack = unsafe {
-            u32::from_be(*ptr)
-        };
-        
-        Ok(ack)
+        Ok(endian::get_u32(msg))
     } else {
         Err(ArrowError::other("incorrect Control Protocol ACK message length"))
     }
@@ -487,6 +1065,8 @@ mod tests {
     use utils::Serialize;
     use net::utils::WriteBuffer;
     use net::arrow::protocol::svc_table::ServiceTable;
+    use net::arrow::protocol::scan_report::ScanSummary;
+    use net::traffic::TrafficStats;
     
     #[test]
     fn test_control_msg_serialization() {
@@ -527,7 +1107,7 @@ mod tests {
     
     #[test]
     fn test_register_msg_serialization() {
-        let data = [
+        let mut data = vec![
             1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
             2, 2, 2, 2, 2, 2,
             3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
@@ -539,12 +1119,16 @@ mod tests {
             0, 0, 
             0];
         
+        // client protocol info
+        data.extend_from_slice(&endian::u16_to_bytes(CONTROL_PROTOCOL_VERSION));
+        data.extend_from_slice(&endian::u32_to_bytes(CLIENT_FEATURES));
+        
         let svc_table = ServiceTable::new();
         let register  = RegisterMessage::new(
             [1u8; 16],
             [2u8; 6],
             [3u8; 16],
-            svc_table);
+            svc_table.clone());
         
         let mut buf = WriteBuffer::new(0);
         
@@ -553,5 +1137,217 @@ mod tests {
         let data_bytes: &[u8] = &data;
         
         assert_eq!(data_bytes, buf.as_bytes());
+        assert_eq!(register.len(), data.len());
+        
+        // the protocol info is omitted for legacy servers
+        let register = RegisterMessage::legacy(
+            [1u8; 16],
+            [2u8; 6],
+            [3u8; 16],
+            svc_table);
+        
+        buf.clear();
+        
+        register.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..data.len() - 6], buf.as_bytes());
+        assert_eq!(register.len(), data.len() - 6);
+    }
+    
+    #[test]
+    fn test_status_msg_serialization() {
+        let data = [
+            0x12, 0x34,
+            0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x00, 0x57, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x03, 0xe8,
+            0x00, 0x00, 0x00, 0x05,
+            0x00, 0x00, 0x00, 0x03,
+            0x00, 0x00, 0x00, 0x01];
+        
+        let summary = ScanSummary {
+            timestamp:        0x57000000,
+            duration:         1000,
+            hosts:            5,
+            new_services:     3,
+            removed_services: 1
+        };
+        
+        let status = StatusMessage::new(0x1234, 1, 2, Some(&summary));
+        
+        let mut buf = WriteBuffer::new(0);
+        
+        status.serialize(&mut buf).unwrap();
+        
+        let data_bytes: &[u8] = &data;
+        
+        assert_eq!(data_bytes, buf.as_bytes());
+        assert_eq!(status.len(), data.len());
+        
+        let traffic = TrafficStats {
+            rx_rate:     0x100,
+            tx_rate:     0x200,
+            utilization: 40
+        };
+        
+        let mut status = StatusMessage::new(0x1234, 1, 2, Some(&summary));
+        
+        status.set_traffic(&traffic);
+        
+        buf.clear();
+        
+        status.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..], &buf.as_bytes()[..data.len()]);
+        assert_eq!(&buf.as_bytes()[data.len()..],
+            &[0, 0, 1, 0, 0, 0, 2, 0, 40]);
+        assert_eq!(status.len(), data.len() + 9);
+        
+        let mut status = StatusMessage::new(0x1234, 1, 2, None);
+        
+        status.set_traffic(&traffic);
+        
+        buf.clear();
+        
+        status.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..10], buf.as_bytes());
+        assert_eq!(status.len(), 10);
+        
+        let build_info = BuildInfo::new("1.0", "ab12", "d", &[], "ssl");
+        
+        let mut status = StatusMessage::new(0x1234, 1, 2, Some(&summary));
+        
+        status.set_traffic(&traffic);
+        status.set_build_info(build_info.clone());
+        
+        buf.clear();
+        
+        status.serialize(&mut buf).unwrap();
+        
+        assert_eq!(status.len(), data.len() + 9 + build_info.len());
+        assert_eq!(buf.as_bytes().len(), status.len());
+        
+        // the build info is sent only together with the traffic statistics
+        let mut status = StatusMessage::new(0x1234, 1, 2, Some(&summary));
+        
+        status.set_build_info(build_info);
+        
+        buf.clear();
+        
+        status.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..], buf.as_bytes());
+        assert_eq!(status.len(), data.len());
+    }
+    
+    #[test]
+    fn test_protocol_negotiation() {
+        let legacy_ack = [0x00, 0x00, 0x00, 0x00];
+        let ack        = [
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x03,
+            0x00, 0x00, 0x01, 0x05];
+        
+        let (code, server) = parse_register_ack(&legacy_ack).unwrap();
+        
+        assert_eq!(code, ACK_NO_ERROR);
+        assert_eq!(server, ProtocolInfo::legacy());
+        
+        let protocol = ProtocolInfo::client()
+            .negotiate(&server);
+        
+        assert_eq!(protocol.version, 1);
+        assert!(!protocol.supports(FEATURE_METADATA));
+        
+        let (code, server) = parse_register_ack(&ack).unwrap();
+        
+        assert_eq!(code, ACK_NO_ERROR);
+        assert_eq!(server, ProtocolInfo::new(3, 0x0105));
+        
+        let protocol = ProtocolInfo::client()
+            .negotiate(&server);
+        
+        assert_eq!(protocol.version, CONTROL_PROTOCOL_VERSION);
+        assert!(protocol.supports(FEATURE_SCAN_SUMMARY));
+        assert!(protocol.supports(FEATURE_HUP_CODES));
+        assert!(!protocol.supports(FEATURE_METADATA));
+        assert!(!protocol.supports(FEATURE_SVC_STATS));
+        
+        assert!(parse_register_ack(&ack[..6]).is_err());
+        
+        assert!(is_legacy_register_ack(&legacy_ack));
+        assert!(is_legacy_register_ack(&ack[..6]));
+        assert!(!is_legacy_register_ack(&ack));
+    }
+    
+    #[test]
+    fn test_hup_report_serialization() {
+        let report = HupReport::new(0x0102, 7, 2, 11, HUP_SERVICE_UNREACHABLE);
+        let data   = [
+            0x01, 0x02,
+            0x00, 0x00, 0x00, 0x07,
+            0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x0b,
+            0x00, 0x00, 0x00, 0x03];
+        
+        let mut buf = Vec::new();
+        
+        report.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..], &buf[..]);
+        assert_eq!(report.len(), data.len());
+    }
+    
+    #[test]
+    fn test_hup_message_round_trip() {
+        let data = [0x00, 0x12, 0x34, 0x56, 0xde, 0xad, 0xbe, 0xef];
+        let msg  = HupMessage::new(0x78123456, 0xdeadbeef);
+        
+        let mut buf = Vec::new();
+        
+        msg.serialize(&mut buf).unwrap();
+        
+        assert_eq!(&data[..], &buf[..]);
+        assert_eq!(msg.len(), data.len());
+        
+        // parse the message from an unaligned position
+        let mut unaligned = vec![0u8];
+        
+        unaligned.extend_from_slice(&data);
+        
+        let msg        = HupMessage::from_bytes(&unaligned[1..]).unwrap();
+        let session_id = msg.session_id;
+        let error_code = msg.error_code;
+        
+        assert_eq!(session_id, 0x00123456);
+        assert_eq!(error_code, 0xdeadbeef);
+        
+        assert!(HupMessage::from_bytes(&data[1..]).is_err());
+        assert_eq!(parse_ack_message(&unaligned[1..5]).unwrap(), 0x00123456);
+    }
+    
+    #[test]
+    fn test_rotate_secret_parsing() {
+        let secret = [0x11; 16];
+        
+        assert_eq!(parse_rotate_secret(&secret).unwrap(), secret);
+        assert!(parse_rotate_secret(&secret[..15]).is_err());
+    }
+    
+    #[test]
+    fn test_remove_service_parsing() {
+        assert_eq!(parse_remove_service(&[0x01, 0x02]).unwrap(), 0x0102);
+        assert!(parse_remove_service(&[0x01]).is_err());
+        assert!(parse_remove_service(&[0x00, 0x01, 0x02]).is_err());
+    }
+    
+    #[test]
+    fn test_set_log_level_parsing() {
+        let msg = [0x00, 0x01, 0x00, 0x00, 0x0e, 0x10];
+        
+        assert_eq!(parse_set_log_level(&msg).unwrap(), (1, 3600));
+        assert!(parse_set_log_level(&msg[..5]).is_err());
     }
 }
@@ -0,0 +1,171 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network event definitions.
+
+use std::io;
+use std::mem;
+
+use std::io::Write;
+use std::net::IpAddr;
+
+use utils::Serialize;
+use net::utils::IpAddrEx;
+use net::raw::ether::MacAddr;
+use net::arrow::protocol::ControlMessageBody;
+
+pub const EVENT_NEW_HOST:            u8 = 0x01;
+pub const EVENT_FINGERPRINT_CHANGED: u8 = 0x02;
+
+/// Size of the network event header in bytes.
+const EVENT_HEADER_SIZE: usize = 34;
+
+/// Network event detected by the network scanner.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NetworkEvent {
+    /// Event type.
+    pub event_type: u8,
+    /// UNIX timestamp (in UTC) of the scan which detected the event.
+    pub timestamp:  i64,
+    /// MAC address of the host.
+    pub mac_addr:   MacAddr,
+    /// IP address of the host.
+    pub ip_addr:    IpAddr,
+    /// Open ports of the host (sorted).
+    pub ports:      Vec<u16>,
+}
+
+impl NetworkEvent {
+    /// Create a new network event.
+    pub fn new(
+        event_type: u8,
+        timestamp: i64,
+        mac: MacAddr,
+        ip: IpAddr,
+        ports: Vec<u16>) -> NetworkEvent {
+        NetworkEvent {
+            event_type: event_type,
+            timestamp:  timestamp,
+            mac_addr:   mac,
+            ip_addr:    ip,
+            ports:      ports
+        }
+    }
+
+    /// Get event name.
+    pub fn name(&self) -> &'static str {
+        match self.event_type {
+            EVENT_NEW_HOST            => "new-host",
+            EVENT_FINGERPRINT_CHANGED => "fingerprint-changed",
+            _                         => "unknown"
+        }
+    }
+
+    /// Get open ports as a comma separated list.
+    pub fn port_list(&self) -> String {
+        self.ports.iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl Serialize for NetworkEvent {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let header = NetworkEventHeader::new(self);
+
+        try!(header.serialize(w));
+
+        for port in &self.ports {
+            try!(port.serialize(w));
+        }
+
+        Ok(())
+    }
+}
+
+impl ControlMessageBody for NetworkEvent {
+    fn len(&self) -> usize {
+        EVENT_HEADER_SIZE
+            + self.ports.len() * mem::size_of::<u16>()
+    }
+}
+
+/// Network event header.
+#[derive(Copy, Clone)]
+struct NetworkEventHeader {
+    event_type:  u8,
+    timestamp:   i64,
+    mac_address: [u8; 6],
+    ip_version:  u8,
+    ip_address:  [u8; 16],
+    port_count:  u16,
+}
+
+impl NetworkEventHeader {
+    /// Create a new network event header.
+    fn new(event: &NetworkEvent) -> NetworkEventHeader {
+        NetworkEventHeader {
+            event_type:  event.event_type,
+            timestamp:   event.timestamp,
+            mac_address: event.mac_addr.octets(),
+            ip_version:  event.ip_addr.version(),
+            ip_address:  event.ip_addr.bytes(),
+            port_count:  event.ports.len() as u16
+        }
+    }
+}
+
+impl Serialize for NetworkEventHeader {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.event_type.serialize(w));
+        try!(self.timestamp.serialize(w));
+        try!(w.write_all(&self.mac_address));
+        try!(self.ip_version.serialize(w));
+        try!(w.write_all(&self.ip_address));
+        self.port_count.serialize(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use utils::Serialize;
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::ControlMessageBody;
+
+    #[test]
+    fn test_network_event() {
+        let event = NetworkEvent::new(EVENT_FINGERPRINT_CHANGED, 1,
+            MacAddr::new(1, 2, 3, 4, 5, 6),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+            vec![80, 554]);
+
+        let mut buf = Vec::new();
+
+        event.serialize(&mut buf)
+            .unwrap();
+
+        assert_eq!(buf.len(), event.len());
+        assert_eq!(buf.len(), 34 + 4);
+        assert_eq!(&buf[..9], &[2, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&buf[9..15], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(&buf[32..], &[0, 2, 0, 80, 2, 42]);
+        assert_eq!(event.name(), "fingerprint-changed");
+        assert_eq!(event.port_list(), "80,554");
+    }
+}
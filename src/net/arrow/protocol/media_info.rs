@@ -0,0 +1,109 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service media info definitions.
+//!
+//! Media parameters of a stream (video codec, resolution, frame rate and
+//! audio codec) gathered by the network scanner. They are sent to Arrow
+//! Service within the client metadata as "key=value" pairs separated by
+//! commas (e.g. `codec=H264,resolution=1920x1080,fps=25,audio=none`); unknown
+//! parameters are omitted.
+
+use std::fmt;
+
+use std::fmt::{Display, Formatter};
+
+/// Media parameters of a stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaInfo {
+    /// Video codec (e.g. H264).
+    pub codec:      Option<String>,
+    /// Video resolution (width and height).
+    pub resolution: Option<(u32, u32)>,
+    /// Frame rate.
+    pub fps:        Option<f64>,
+    /// Audio codec (Some("none") if the stream has no audio).
+    pub audio:      Option<String>,
+}
+
+impl MediaInfo {
+    /// Create a new media info with all parameters unknown.
+    pub fn new() -> MediaInfo {
+        MediaInfo {
+            codec:      None,
+            resolution: None,
+            fps:        None,
+            audio:      None
+        }
+    }
+
+    /// Check if all parameters are unknown.
+    pub fn is_empty(&self) -> bool {
+        self.codec.is_none()
+            && self.resolution.is_none()
+            && self.fps.is_none()
+            && self.audio.is_none()
+    }
+}
+
+impl Display for MediaInfo {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        let mut items = Vec::new();
+
+        if let Some(ref codec) = self.codec {
+            items.push(format!("codec={}", codec));
+        }
+
+        if let Some((width, height)) = self.resolution {
+            items.push(format!("resolution={}x{}", width, height));
+        }
+
+        if let Some(fps) = self.fps {
+            items.push(format!("fps={}", fps));
+        }
+
+        if let Some(ref audio) = self.audio {
+            items.push(format!("audio={}", audio));
+        }
+
+        f.write_str(&items.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_info_encoding() {
+        let mut info = MediaInfo::new();
+
+        assert!(info.is_empty());
+        assert_eq!(info.to_string(), "");
+
+        info.codec      = Some("H264".to_string());
+        info.resolution = Some((1920, 1080));
+        info.fps        = Some(29.97);
+        info.audio      = Some("none".to_string());
+
+        assert!(!info.is_empty());
+        assert_eq!(info.to_string(),
+            "codec=H264,resolution=1920x1080,fps=29.97,audio=none");
+
+        info.resolution = None;
+        info.fps        = Some(25.0);
+
+        assert_eq!(info.to_string(), "codec=H264,fps=25,audio=none");
+    }
+}
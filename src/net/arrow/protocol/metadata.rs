@@ -0,0 +1,230 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client metadata definitions.
+//!
+//! Metadata are user-defined labels sent to Arrow Service within the UPDATE
+//! messages (right after the service table) if the server supports the
+//! FEATURE_METADATA Control Protocol feature. Each item is encoded as a TLV
+//! record (u16 type, u16 length, value) and the list is terminated by a record
+//! of the META_END type with zero length. Nothing is sent if there are no
+//! metadata.
+
+use std::io;
+
+use std::io::Write;
+
+use utils::Serialize;
+
+use net::raw::ether::MacAddr;
+use net::arrow::protocol::{ControlMessageBody, MediaInfo};
+
+pub const META_END:         u16 = 0x0000;
+pub const META_SITE_NAME:   u16 = 0x0001;
+pub const META_LOCATION:    u16 = 0x0002;
+pub const META_CUSTOMER_ID: u16 = 0x0003;
+pub const META_LABEL:       u16 = 0x0004;
+pub const META_SVC_STATS:   u16 = 0x0005;
+pub const META_CLOCK_ALERT: u16 = 0x0006;
+pub const META_CLIENT_INFO: u16 = 0x0007;
+pub const META_ACK_LATENCY: u16 = 0x0008;
+pub const META_TRACE_ID:    u16 = 0x0009;
+pub const META_HOST_NAME:   u16 = 0x000a;
+pub const META_E2E_SERVICE: u16 = 0x000b;
+pub const META_MEDIA_INFO:  u16 = 0x000c;
+
+/// Maximum length of a metadata value (in bytes).
+pub const MAX_VALUE_LENGTH: usize = 255;
+
+/// Client metadata.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    items: Vec<(u16, String)>,
+}
+
+impl Metadata {
+    /// Create a new empty metadata list.
+    pub fn new() -> Metadata {
+        Metadata {
+            items: Vec::new()
+        }
+    }
+
+    /// Check if there are no metadata.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Set site name.
+    pub fn set_site_name(&mut self, name: &str) {
+        self.set(META_SITE_NAME, name);
+    }
+
+    /// Set device location.
+    pub fn set_location(&mut self, location: &str) {
+        self.set(META_LOCATION, location);
+    }
+
+    /// Set customer ID.
+    pub fn set_customer_id(&mut self, id: &str) {
+        self.set(META_CUSTOMER_ID, id);
+    }
+
+    /// Set system clock alert.
+    pub fn set_clock_alert(&mut self, alert: &str) {
+        self.set(META_CLOCK_ALERT, alert);
+    }
+
+    /// Set client info (version, platform, enabled features and integrator).
+    pub fn set_client_info(&mut self, info: &str) {
+        self.set(META_CLIENT_INFO, info);
+    }
+
+    /// Set service statistics summary.
+    pub fn set_svc_stats(&mut self, summary: &str) {
+        self.set(META_SVC_STATS, summary);
+    }
+
+    /// Set ACK latency histogram summary.
+    pub fn set_ack_latency(&mut self, summary: &str) {
+        self.set(META_ACK_LATENCY, summary);
+    }
+
+    /// Set connection trace ID.
+    pub fn set_trace_id(&mut self, trace_id: &str) {
+        self.set(META_TRACE_ID, trace_id);
+    }
+
+    /// Add a given key-value label.
+    pub fn add_label(&mut self, key: &str, value: &str) {
+        let label = format!("{}={}", key, value);
+        self.push(META_LABEL, &label);
+    }
+
+    /// Add host name of a device with a given MAC address (encoded as
+    /// "mac=name").
+    pub fn add_host_name(&mut self, mac: &MacAddr, name: &str) {
+        let item = format!("{}={}", mac, name);
+        self.push(META_HOST_NAME, &item);
+    }
+
+    /// Add an end-to-end encrypted service (encoded as
+    /// "service_id:key_id").
+    pub fn add_e2e_service(&mut self, item: &str) {
+        self.push(META_E2E_SERVICE, item);
+    }
+
+    /// Add media info of a service with a given ID (encoded as
+    /// "service_id:key=value,...").
+    pub fn add_media_info(&mut self, svc_id: u16, info: &MediaInfo) {
+        let item = format!("{:04x}:{}", svc_id, info);
+        self.push(META_MEDIA_INFO, &item);
+    }
+
+    /// Replace all items of a given type with a given value.
+    fn set(&mut self, item_type: u16, value: &str) {
+        self.items.retain(|&(t, _)| t != item_type);
+        self.push(item_type, value);
+    }
+
+    /// Append a given item (the value is truncated to MAX_VALUE_LENGTH
+    /// bytes).
+    fn push(&mut self, item_type: u16, value: &str) {
+        let mut end = value.len();
+
+        if end > MAX_VALUE_LENGTH {
+            end = MAX_VALUE_LENGTH;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+        }
+
+        self.items.push((item_type, value[..end].to_string()));
+    }
+}
+
+impl Serialize for Metadata {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.items.is_empty() {
+            return Ok(());
+        }
+
+        for &(item_type, ref value) in &self.items {
+            try!(item_type.serialize(w));
+            try!((value.len() as u16).serialize(w));
+            try!(w.write_all(value.as_bytes()));
+        }
+
+        try!(META_END.serialize(w));
+        0u16.serialize(w)
+    }
+}
+
+impl ControlMessageBody for Metadata {
+    fn len(&self) -> usize {
+        if self.items.is_empty() {
+            0
+        } else {
+            4 + self.items.iter()
+                .fold(0, |sum, &(_, ref value)| sum + 4 + value.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::arrow::protocol::ControlMessageBody;
+
+    #[test]
+    fn test_metadata_serialization() {
+        let mut metadata = Metadata::new();
+        let mut buffer   = Vec::new();
+
+        metadata.serialize(&mut buffer)
+            .unwrap();
+
+        assert_eq!(metadata.len(), 0);
+        assert!(buffer.is_empty());
+
+        metadata.set_site_name("foo");
+        metadata.set_site_name("site");
+        metadata.add_label("k", "v");
+
+        metadata.serialize(&mut buffer)
+            .unwrap();
+
+        let expected = [
+            0x00, 0x01, 0x00, 0x04, b's', b'i', b't', b'e',
+            0x00, 0x04, 0x00, 0x03, b'k', b'=', b'v',
+            0x00, 0x00, 0x00, 0x00];
+
+        assert_eq!(metadata.len(), expected.len());
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_value_truncation() {
+        let mut metadata = Metadata::new();
+
+        let value = (0..300).map(|_| "\u{e9}")
+            .collect::<String>();
+
+        metadata.set_location(&value);
+
+        assert_eq!(metadata.len(), 4 + 4 + 254);
+    }
+}
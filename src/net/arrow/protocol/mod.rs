@@ -18,13 +18,47 @@ pub mod control;
 pub mod svc_table;
 
 pub mod scan_report;
+pub mod metadata;
+pub mod media_info;
+pub mod svc_stats;
+pub mod event;
+pub mod build_info;
+pub mod violation;
 
 pub use self::control::ACK_NO_ERROR;
 pub use self::control::ACK_UNSUPPORTED_PROTOCOL_VERSION;
 pub use self::control::ACK_UNAUTHORIZED;
 pub use self::control::ACK_UNSUPPORTED_METHOD;
+pub use self::control::ACK_AUTH_CHALLENGE;
 pub use self::control::ACK_INTERNAL_SERVER_ERROR;
 
+pub use self::control::HUP_NO_ERROR;
+pub use self::control::HUP_SESSION_ERROR;
+pub use self::control::HUP_CONNECTION_ERROR;
+pub use self::control::HUP_SERVICE_UNREACHABLE;
+pub use self::control::HUP_CLOCK_BEHIND;
+pub use self::control::HUP_ACCESS_DENIED;
+
+pub use self::control::FEATURE_SCAN_SUMMARY;
+pub use self::control::FEATURE_METADATA;
+pub use self::control::FEATURE_HUP_CODES;
+pub use self::control::FEATURE_SVC_STATS;
+pub use self::control::FEATURE_TABLE_PAGES;
+pub use self::control::FEATURE_DATA_SEQUENCE;
+pub use self::control::FEATURE_SECRET_ROTATION;
+pub use self::control::FEATURE_NETWORK_EVENTS;
+pub use self::control::FEATURE_TRAFFIC_STATS;
+pub use self::control::FEATURE_BUILD_INFO;
+pub use self::control::FEATURE_PROTOCOL_VIOLATIONS;
+pub use self::control::FEATURE_DATA_TIMESTAMPS;
+pub use self::control::FEATURE_E2E_ENCRYPTION;
+pub use self::control::FEATURE_HUP_REPORTS;
+pub use self::control::FEATURE_DATAGRAM_SESSIONS;
+pub use self::control::FEATURE_SERVICE_REMOVAL;
+pub use self::control::FEATURE_LOG_LEVEL_CONTROL;
+pub use self::control::FEATURE_MEDIA_INFO;
+pub use self::control::FEATURE_SEQUENTIAL_MSG_IDS;
+
 pub use self::control::ControlMessage;
 pub use self::control::ControlMessageHeader;
 pub use self::control::ControlMessageBody;
@@ -33,34 +67,58 @@ pub use self::control::ControlMessageType;
 
 pub use self::control::EmptyBody;
 
+pub use self::control::ProtocolInfo;
+
 pub use self::control::RegisterMessage;
 pub use self::control::RegisterMessageHeader;
 
+pub use self::control::UpdateMessage;
+pub use self::control::UpdatePageMessage;
+
 pub use self::control::HupMessage;
+pub use self::control::HupReport;
 
 pub use self::control::StatusMessage;
 
 pub use self::svc_table::Service;
 pub use self::svc_table::ServiceTable;
+pub use self::svc_table::ServiceTablePage;
+pub use self::svc_table::ServiceTableDiff;
 
 pub use self::scan_report::HostInfo;
 pub use self::scan_report::ScanReport;
+pub use self::scan_report::ScanSummary;
 pub use self::scan_report::ScanReportMessage;
 pub use self::scan_report::HINFO_FLAG_ARP;
 pub use self::scan_report::HINFO_FLAG_ICMP;
+pub use self::scan_report::HINFO_FLAG_TCP;
+
+pub use self::metadata::Metadata;
+
+pub use self::media_info::MediaInfo;
+
+pub use self::build_info::BuildInfo;
+
+pub use self::svc_stats::ServiceStatsTable;
+pub use self::svc_stats::SvcStatsMessage;
+
+pub use self::event::NetworkEvent;
+
+pub use self::violation::ProtocolViolation;
 
 use std::io;
-use std::mem;
 
 use std::io::Write;
 
-use utils;
-
 use utils::Serialize;
+use utils::endian::ByteReader;
 use net::arrow::error::{Result, ArrowError};
 
 const ARROW_PROTOCOL_VERSION: u8 = 1;
 
+/// Size of the Arrow Message header in bytes.
+const ARROW_HEADER_SIZE: usize = 11;
+
 /// Common trait for Arrow Message payload types.
 pub trait ArrowMessageBody : Serialize {
     /// Get body size in bytes.
@@ -69,7 +127,6 @@ pub trait ArrowMessageBody : Serialize {
 
 /// Arrow Message header.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
 pub struct ArrowMessageHeader {
     /// Arrow Protocol major version.
     pub version: u8,
@@ -95,15 +152,14 @@ impl ArrowMessageHeader {
     
     /// Deserialize an Arrow Message header.
     fn from_bytes(slice: &[u8]) -> Result<ArrowMessageHeader> {
-        assert_eq!(slice.len(), mem::size_of::<ArrowMessageHeader>());
-        let ptr    = slice.as_ptr() as *const ArrowMessageHeader;
-        let header = unsafe { &*ptr };
+        assert_eq!(slice.len(), ARROW_HEADER_SIZE);
+        let mut reader = ByteReader::new(slice);
         
         let res = ArrowMessageHeader {
-            version: header.version,
-            service: u16::from_be(header.service),
-            session: u32::from_be(header.session) & ((1 << 24) - 1),
-            size:    u32::from_be(header.size)
+            version: reader.read_u8(),
+            service: reader.read_u16(),
+            session: reader.read_u32() & ((1 << 24) - 1),
+            size:    reader.read_u32()
         };
         
         if res.version == ARROW_PROTOCOL_VERSION {
@@ -116,14 +172,10 @@ impl ArrowMessageHeader {
 
 impl Serialize for ArrowMessageHeader {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_header = ArrowMessageHeader {
-            version: self.version,
-            service: self.service.to_be(),
-            session: self.session.to_be(),
-            size:    self.size.to_be()
-        };
-        
-        w.write_all(utils::as_bytes(&be_header))
+        try!(self.version.serialize(w));
+        try!(self.service.serialize(w));
+        try!(self.session.serialize(w));
+        self.size.serialize(w)
     }
 }
 
@@ -223,11 +275,19 @@ impl ArrowMessageParser {
         }
     }
     
+    /// Get the last message including its header.
+    pub fn raw(&self) -> Option<&[u8]> {
+        if self.is_complete() {
+            Some(&self.buffer)
+        } else {
+            None
+        }
+    }
+    
     /// Get last message body.
     pub fn body(&self) -> Option<&[u8]> {
-        let header_size = mem::size_of::<ArrowMessageHeader>();
         if self.is_complete() {
-            Some(&self.buffer[header_size..])
+            Some(&self.buffer[ARROW_HEADER_SIZE..])
         } else {
             None
         }
@@ -235,7 +295,7 @@ impl ArrowMessageParser {
     
     /// Read header chunk.
     fn read_header(&mut self, data: &[u8]) -> Result<usize> {
-        let size         = mem::size_of::<ArrowMessageHeader>();
+        let size         = ARROW_HEADER_SIZE;
         let mut consumed = size - self.buffer.len();
         
         if consumed > data.len() {
@@ -286,6 +346,7 @@ impl<'a> ArrowMessageBody for &'a [u8] {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::ARROW_HEADER_SIZE;
     use utils::Serialize;
     use net::utils::WriteBuffer;
     
@@ -306,6 +367,33 @@ mod tests {
         assert_eq!(&msg_data, buf.as_bytes());
     }
     
+    #[test]
+    fn test_header_round_trip() {
+        let headers = [
+            (0x0000, 0x00000000, 0),
+            (0xffff, 0x00ffffff, 0xffffffff),
+            (0x1234, 0x0056789a, 0x0bcdef01)];
+        
+        let mut buf = WriteBuffer::new(0);
+        
+        for &(service, session, size) in &headers {
+            buf.clear();
+            
+            ArrowMessageHeader::new(service, session, size)
+                .serialize(&mut buf)
+                .unwrap();
+            
+            assert_eq!(buf.as_bytes().len(), ARROW_HEADER_SIZE);
+            
+            let header = ArrowMessageHeader::from_bytes(buf.as_bytes())
+                .unwrap();
+            
+            assert_eq!(header.service, service);
+            assert_eq!(header.session, session);
+            assert_eq!(header.size, size);
+        }
+    }
+    
     #[test]
     fn test_message_deserialization() {
         let mut parser = ArrowMessageParser::new();
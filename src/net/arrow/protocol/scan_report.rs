@@ -15,7 +15,6 @@
 //! Scan report definitions.
 
 use std::io;
-use std::mem;
 
 use std::io::Write;
 use std::net::{IpAddr, SocketAddr};
@@ -23,32 +22,55 @@ use std::collections::{HashSet, HashMap};
 use std::collections::hash_set::Iter as HashSetIterator;
 use std::collections::hash_map::Iter as HashMapIterator;
 
-use utils;
-
 use utils::Serialize;
 use net::raw::ether::MacAddr;
+#[cfg(feature = "discovery")]
+use net::sap::MulticastStream;
 use net::arrow::protocol::{ControlMessageBody, Service, ServiceTable};
+use net::arrow::protocol::MediaInfo;
 
 pub use self::host_info::HINFO_FLAG_ARP;
 pub use self::host_info::HINFO_FLAG_ICMP;
+pub use self::host_info::HINFO_FLAG_TCP;
 
 pub use self::host_info::HostInfo;
 
 type HostInfoKey = (MacAddr, IpAddr);
 
+/// Size of the scan report message header in bytes.
+const SCAN_REPORT_HEADER_SIZE: usize = 6;
+
 /// Network scan report.
 #[derive(Debug, Clone)]
 pub struct ScanReport {
-    hosts:    HashMap<HostInfoKey, HostInfo>,
-    services: HashSet<Service>
+    hosts:      HashMap<HostInfoKey, HostInfo>,
+    services:   HashSet<Service>,
+    /// Addresses of services asking for the Basic (plaintext) HTTP/RTSP
+    /// authentication (not sent to Arrow Service).
+    basic_auth: HashSet<SocketAddr>,
+    /// Multicast details of services announced using SAP (not sent to
+    /// Arrow Service).
+    #[cfg(feature = "discovery")]
+    multicast:  HashMap<SocketAddr, MulticastStream>,
+    /// Media parameters of streaming services (sent to Arrow Service within
+    /// the client metadata).
+    media:      HashMap<SocketAddr, MediaInfo>,
+    /// The scan has been cut short because its probing budget has been
+    /// exhausted (i.e. the report may be incomplete).
+    incomplete: bool,
 }
 
 impl ScanReport {
     /// Create a new network scan report.
     pub fn new() -> ScanReport {
         ScanReport {
-            hosts:    HashMap::new(),
-            services: HashSet::new()
+            hosts:      HashMap::new(),
+            services:   HashSet::new(),
+            basic_auth: HashSet::new(),
+            #[cfg(feature = "discovery")]
+            multicast:  HashMap::new(),
+            media:      HashMap::new(),
+            incomplete: false
         }
     }
     
@@ -94,6 +116,51 @@ impl ScanReport {
         self.services.insert(svc);
     }
     
+    /// Mark a service with a given address as asking for the Basic
+    /// authentication.
+    pub fn add_basic_auth(&mut self, addr: SocketAddr) {
+        self.basic_auth.insert(addr);
+    }
+    
+    /// Check if a service with a given address asks for the Basic
+    /// authentication.
+    pub fn is_basic_auth(&self, addr: &SocketAddr) -> bool {
+        self.basic_auth.contains(addr)
+    }
+    
+    /// Record multicast details of a service announced using SAP.
+    #[cfg(feature = "discovery")]
+    pub fn add_multicast(&mut self, stream: MulticastStream) {
+        self.multicast.insert(stream.group, stream);
+    }
+    
+    /// Get multicast details of a service with a given address (if it has
+    /// been announced using SAP).
+    #[cfg(feature = "discovery")]
+    pub fn multicast(&self, addr: &SocketAddr) -> Option<&MulticastStream> {
+        self.multicast.get(addr)
+    }
+    
+    /// Record media parameters of a service with a given address.
+    pub fn add_media_info(&mut self, addr: SocketAddr, info: MediaInfo) {
+        self.media.insert(addr, info);
+    }
+    
+    /// Get media parameters of a service with a given address (if known).
+    pub fn media_info(&self, addr: &SocketAddr) -> Option<&MediaInfo> {
+        self.media.get(addr)
+    }
+    
+    /// Mark the report as incomplete (the scan has been cut short).
+    pub fn set_incomplete(&mut self) {
+        self.incomplete = true;
+    }
+    
+    /// Check if the scan has been cut short.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+    
     /// Get host infos.
     pub fn hosts(&self) -> HostInfoIterator {
         HostInfoIterator::new(self.hosts.iter())
@@ -121,6 +188,43 @@ impl ScanReport {
         }
         
         self.services.extend(other.services);
+        self.basic_auth.extend(other.basic_auth);
+        
+        #[cfg(feature = "discovery")]
+        {
+            self.multicast.extend(other.multicast);
+        }
+        
+        self.media.extend(other.media);
+        self.incomplete |= other.incomplete;
+    }
+}
+
+/// Summary of the last network scan.
+#[derive(Debug, Copy, Clone)]
+pub struct ScanSummary {
+    /// UNIX timestamp (in UTC) of the scan start.
+    pub timestamp:        i64,
+    /// Scan duration in miliseconds.
+    pub duration:         u32,
+    /// Number of hosts found.
+    pub hosts:            u32,
+    /// Number of services that became active during the scan.
+    pub new_services:     u32,
+    /// Number of services that became inactive during the scan.
+    pub removed_services: u32,
+}
+
+impl ScanSummary {
+    /// Create a new empty scan summary (i.e. no scan has been done yet).
+    pub fn new() -> ScanSummary {
+        ScanSummary {
+            timestamp:        0,
+            duration:         0,
+            hosts:            0,
+            new_services:     0,
+            removed_services: 0
+        }
     }
 }
 
@@ -272,7 +376,7 @@ impl Serialize for ScanReportMessage {
 
 impl ControlMessageBody for ScanReportMessage {
     fn len(&self) -> usize {
-        let mut size = mem::size_of::<ScanReportMesssageHeader>();
+        let mut size = SCAN_REPORT_HEADER_SIZE;
         
         for host in self.scan_report.hosts() {
             size += host.size();
@@ -290,7 +394,6 @@ impl ControlMessageBody for ScanReportMessage {
 
 /// Scan report message header.
 #[derive(Clone)]
-#[repr(packed)]
 struct ScanReportMesssageHeader {
     request_id: u16,
     host_count: u32,
@@ -308,12 +411,8 @@ impl ScanReportMesssageHeader {
 
 impl Serialize for ScanReportMesssageHeader {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_header = ScanReportMesssageHeader {
-            request_id: self.request_id.to_be(),
-            host_count: self.host_count.to_be()
-        };
-        
-        w.write_all(utils::as_bytes(&be_header))
+        try!(self.request_id.serialize(w));
+        self.host_count.serialize(w)
     }
 }
 
@@ -328,14 +427,16 @@ mod host_info {
     use std::collections::hash_set::Iter as HashSetIterator;
     use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
     
-    use utils;
-    
     use utils::Serialize;
     use net::utils::IpAddrEx;
     use net::raw::ether::MacAddr;
     
     pub const HINFO_FLAG_ARP: u8  = 0x01;
     pub const HINFO_FLAG_ICMP: u8 = 0x02;
+    pub const HINFO_FLAG_TCP: u8  = 0x04;
+
+    /// Size of the host info header in bytes.
+    const HOST_INFO_HEADER_SIZE: usize = 26;
 
     /// Host info.
     #[derive(Debug, Clone)]
@@ -379,7 +480,7 @@ mod host_info {
         
         /// Get serialized size in bytes.
         pub fn size(&self) -> usize {
-            mem::size_of::<HostInfoHeader>()
+            HOST_INFO_HEADER_SIZE
                 + self.ports.len() * mem::size_of::<u16>()
         }
     }
@@ -397,8 +498,7 @@ mod host_info {
         }
     }
 
-    /// Raw host info.
-    #[repr(packed)]
+    /// Host info header.
     #[derive(Copy, Clone)]
     struct HostInfoHeader {
         flags:       u8,
@@ -422,15 +522,11 @@ mod host_info {
 
     impl Serialize for HostInfoHeader {
         fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-            let be_header = HostInfoHeader {
-                flags:       self.flags,
-                mac_address: self.mac_address,
-                ip_version:  self.ip_version,
-                ip_address:  self.ip_address,
-                port_count:  self.port_count.to_be()
-            };
-            
-            w.write_all(utils::as_bytes(&be_header))
+            try!(self.flags.serialize(w));
+            try!(w.write_all(&self.mac_address));
+            try!(self.ip_version.serialize(w));
+            try!(w.write_all(&self.ip_address));
+            self.port_count.serialize(w)
         }
     }
     
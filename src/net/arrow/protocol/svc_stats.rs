@@ -0,0 +1,573 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service connection statistics definitions.
+//!
+//! The statistics are kept for the last HISTORY_SIZE connection attempts and
+//! sessions of each service in order to spot unreliable services (e.g. flaky
+//! cameras).
+
+use std::io;
+use std::cmp;
+
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::collections::VecDeque;
+
+use utils;
+
+use utils::clock;
+use utils::Serialize;
+use net::arrow::protocol::ControlMessageBody;
+
+use rustc_serialize::json;
+
+/// Size of the serialized service statistics header in bytes.
+const SERVICE_STATS_HEADER_SIZE: usize = 29;
+
+/// Number of connection attempts and sessions taken into account.
+const HISTORY_SIZE: usize = 100;
+
+/// Number of recent errors kept for each service.
+const ERROR_HISTORY_SIZE: usize = 10;
+
+/// Maximum length of an error message (in bytes).
+const MAX_ERROR_LENGTH: usize = 255;
+
+/// Service connection error record.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
+pub struct ServiceError {
+    /// UNIX timestamp (in UTC) of the error.
+    pub timestamp: i64,
+    /// Error message.
+    pub message:   String,
+}
+
+impl ServiceError {
+    /// Create a new error record with a given message and the current time.
+    fn new(message: &str) -> ServiceError {
+        let mut end = message.len();
+
+        if end > MAX_ERROR_LENGTH {
+            end = MAX_ERROR_LENGTH;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+        }
+
+        ServiceError {
+            timestamp: clock::timestamp(),
+            message:   message[..end].to_string()
+        }
+    }
+}
+
+impl Serialize for ServiceError {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.timestamp.serialize(w));
+        try!((self.message.len() as u8).serialize(w));
+        w.write_all(self.message.as_bytes())
+    }
+}
+
+/// Numbers of media frames dropped due to session overload.
+#[derive(Debug, Copy, Clone, RustcDecodable, RustcEncodable)]
+pub struct DropCounters {
+    /// Number of dropped frames.
+    pub frames: u64,
+    /// Number of dropped bytes.
+    pub bytes:  u64,
+}
+
+/// Connection statistics of a single service.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
+pub struct ServiceStats {
+    /// Service ID.
+    svc_id:    u16,
+    /// Results of the recent connection attempts.
+    attempts:  VecDeque<bool>,
+    /// Lifetimes of the recent sessions (in milliseconds).
+    lifetimes: VecDeque<u64>,
+    /// Recent errors.
+    errors:    VecDeque<ServiceError>,
+    /// Dropped frames (None for statistics saved by older versions).
+    dropped:   Option<DropCounters>,
+}
+
+impl ServiceStats {
+    /// Create new empty statistics for a given service ID.
+    fn new(svc_id: u16) -> ServiceStats {
+        ServiceStats {
+            svc_id:    svc_id,
+            attempts:  VecDeque::new(),
+            lifetimes: VecDeque::new(),
+            errors:    VecDeque::new(),
+            dropped:   None
+        }
+    }
+
+    /// Get the service ID.
+    pub fn svc_id(&self) -> u16 {
+        self.svc_id
+    }
+
+    /// Get number of the recent connection attempts.
+    pub fn connect_attempts(&self) -> usize {
+        self.attempts.len()
+    }
+
+    /// Get number of the recent successful connection attempts.
+    pub fn connect_successes(&self) -> usize {
+        self.attempts.iter()
+            .filter(|success| **success)
+            .count()
+    }
+
+    /// Get number of the recent sessions.
+    pub fn sessions(&self) -> usize {
+        self.lifetimes.len()
+    }
+
+    /// Get average lifetime of the recent sessions (in milliseconds).
+    pub fn avg_session_lifetime(&self) -> u64 {
+        if self.lifetimes.is_empty() {
+            0
+        } else {
+            self.lifetimes.iter()
+                .fold(0, |sum, lifetime| sum + lifetime)
+                / self.lifetimes.len() as u64
+        }
+    }
+
+    /// Get total numbers of dropped frames and bytes.
+    pub fn dropped(&self) -> DropCounters {
+        self.dropped.unwrap_or(DropCounters {
+            frames: 0,
+            bytes:  0
+        })
+    }
+
+    /// Get the most recent error (if any).
+    pub fn last_error(&self) -> Option<&ServiceError> {
+        self.errors.back()
+    }
+
+    /// Record a connection attempt.
+    fn add_attempt(&mut self, success: bool) {
+        push_limited(&mut self.attempts, success, HISTORY_SIZE);
+    }
+
+    /// Record session lifetime (in milliseconds).
+    fn add_session(&mut self, lifetime: u64) {
+        push_limited(&mut self.lifetimes, lifetime, HISTORY_SIZE);
+    }
+
+    /// Record an error.
+    fn add_error(&mut self, message: &str) {
+        push_limited(&mut self.errors, ServiceError::new(message),
+            ERROR_HISTORY_SIZE);
+    }
+
+    /// Record dropped frames.
+    fn add_dropped(&mut self, frames: u64, bytes: u64) {
+        let mut dropped = self.dropped();
+
+        dropped.frames += frames;
+        dropped.bytes  += bytes;
+
+        self.dropped = Some(dropped);
+    }
+
+    /// Get serialized size in bytes.
+    fn size(&self) -> usize {
+        let errors = self.errors.iter()
+            .fold(0, |sum, err| sum + 9 + err.message.len());
+
+        SERVICE_STATS_HEADER_SIZE + errors
+    }
+}
+
+impl Serialize for ServiceStats {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let header = ServiceStatsHeader::new(self);
+
+        try!(header.serialize(w));
+
+        for err in &self.errors {
+            try!(err.serialize(w));
+        }
+
+        Ok(())
+    }
+}
+
+/// Serialized service statistics header.
+#[derive(Copy, Clone)]
+struct ServiceStatsHeader {
+    svc_id:         u16,
+    attempts:       u16,
+    successes:      u16,
+    sessions:       u16,
+    avg_lifetime:   u32,
+    dropped_frames: u64,
+    dropped_bytes:  u64,
+    error_count:    u8,
+}
+
+impl ServiceStatsHeader {
+    /// Create a new header for given service statistics (the average session
+    /// lifetime is sent in seconds).
+    fn new(stats: &ServiceStats) -> ServiceStatsHeader {
+        let avg_lifetime = stats.avg_session_lifetime() / 1000;
+        let dropped      = stats.dropped();
+
+        ServiceStatsHeader {
+            svc_id:         stats.svc_id,
+            attempts:       stats.connect_attempts() as u16,
+            successes:      stats.connect_successes() as u16,
+            sessions:       stats.sessions() as u16,
+            avg_lifetime:   cmp::min(avg_lifetime, 0xffffffff) as u32,
+            dropped_frames: dropped.frames,
+            dropped_bytes:  dropped.bytes,
+            error_count:    stats.errors.len() as u8
+        }
+    }
+}
+
+impl Serialize for ServiceStatsHeader {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.svc_id.serialize(w));
+        try!(self.attempts.serialize(w));
+        try!(self.successes.serialize(w));
+        try!(self.sessions.serialize(w));
+        try!(self.avg_lifetime.serialize(w));
+        try!(self.dropped_frames.serialize(w));
+        try!(self.dropped_bytes.serialize(w));
+        self.error_count.serialize(w)
+    }
+}
+
+/// Connection statistics of all services.
+#[derive(Debug, Clone)]
+pub struct ServiceStatsTable {
+    /// File for persisting the statistics.
+    file:     Option<String>,
+    /// Statistics of individual services.
+    services: Vec<ServiceStats>,
+    /// Modification indicator.
+    modified: bool,
+}
+
+impl ServiceStatsTable {
+    /// Create a new empty in-memory statistics table.
+    pub fn new() -> ServiceStatsTable {
+        ServiceStatsTable {
+            file:     None,
+            services: Vec::new(),
+            modified: false
+        }
+    }
+
+    /// Create a new statistics table persisted in a given file. Existing
+    /// statistics are loaded from the file (if possible).
+    pub fn load(file: &str) -> ServiceStatsTable {
+        let services = utils::load_with_backup(file, load_services)
+            .map(|(services, _)| services)
+            .unwrap_or(Vec::new());
+
+        ServiceStatsTable {
+            file:     Some(file.to_string()),
+            services: services,
+            modified: false
+        }
+    }
+
+    /// Save the statistics into the underlaying file (if any) if they were
+    /// modified since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        if let Some(ref file) = self.file {
+            let content = try!(json::encode(&self.services)
+                .or(Err(io::Error::new(ErrorKind::Other,
+                    "unable to encode service statistics"))));
+
+            try!(utils::write_file_with_backup(file, content.as_bytes()));
+        }
+
+        self.modified = false;
+
+        Ok(())
+    }
+
+    /// Get statistics of all services.
+    pub fn services(&self) -> &[ServiceStats] {
+        &self.services
+    }
+
+    /// Record a successful connection to a given service.
+    pub fn connect_succeeded(&mut self, svc_id: u16) {
+        self.get_mut(svc_id)
+            .add_attempt(true);
+    }
+
+    /// Record a failed connection attempt to a given service.
+    pub fn connect_failed(&mut self, svc_id: u16, err: &str) {
+        let stats = self.get_mut(svc_id);
+
+        stats.add_attempt(false);
+        stats.add_error(err);
+    }
+
+    /// Record end of a session with a given lifetime (in milliseconds) and
+    /// an optional error.
+    pub fn session_closed(
+        &mut self,
+        svc_id: u16,
+        lifetime: u64,
+        err: Option<&str>) {
+        let stats = self.get_mut(svc_id);
+
+        stats.add_session(lifetime);
+
+        if let Some(err) = err {
+            stats.add_error(err);
+        }
+    }
+
+    /// Record a hold-down of a given flapping service for a given number of
+    /// seconds.
+    pub fn held_down(&mut self, svc_id: u16, hold_down: u64) {
+        self.get_mut(svc_id)
+            .add_error(&format!("service flapping, held down for {} seconds",
+                hold_down));
+    }
+
+    /// Record frames of a given service dropped due to session overload.
+    pub fn frames_dropped(&mut self, svc_id: u16, frames: usize, bytes: usize) {
+        self.get_mut(svc_id)
+            .add_dropped(frames as u64, bytes as u64);
+    }
+
+    /// Remove statistics of a given service.
+    pub fn remove(&mut self, svc_id: u16) {
+        self.prune(|id| id != svc_id);
+    }
+
+    /// Remove statistics of all services for which a given predicate
+    /// returns false (e.g. services which are no longer in the service
+    /// table).
+    pub fn prune<F>(&mut self, keep: F)
+        where F: Fn(u16) -> bool {
+        let count = self.services.len();
+
+        self.services.retain(|stats| keep(stats.svc_id));
+
+        if self.services.len() != count {
+            self.modified = true;
+        }
+    }
+
+    /// Get a short text summary (suitable for client metadata) in the
+    /// "svc_id:successes/attempts:avg_lifetime" format (the average session
+    /// lifetime is in seconds).
+    pub fn summary(&self) -> String {
+        self.services.iter()
+            .map(|stats| format!("{}:{}/{}:{}",
+                stats.svc_id,
+                stats.connect_successes(),
+                stats.connect_attempts(),
+                stats.avg_session_lifetime() / 1000))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Get mutable statistics of a given service (an empty record is created
+    /// if there is no record for the service yet).
+    fn get_mut(&mut self, svc_id: u16) -> &mut ServiceStats {
+        self.modified = true;
+
+        let index = self.services.iter()
+            .position(|stats| stats.svc_id == svc_id);
+
+        let index = match index {
+            Some(index) => index,
+            None => {
+                self.services.push(ServiceStats::new(svc_id));
+                self.services.len() - 1
+            }
+        };
+
+        &mut self.services[index]
+    }
+}
+
+/// Service statistics control protocol message.
+#[derive(Clone)]
+pub struct SvcStatsMessage {
+    request_id: u16,
+    stats:      ServiceStatsTable,
+}
+
+impl SvcStatsMessage {
+    /// Create a new service statistics control protocol message.
+    pub fn new(request_id: u16, stats: ServiceStatsTable) -> SvcStatsMessage {
+        SvcStatsMessage {
+            request_id: request_id,
+            stats:      stats
+        }
+    }
+}
+
+impl Serialize for SvcStatsMessage {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let services = self.stats.services();
+
+        try!(self.request_id.serialize(w));
+        try!((services.len() as u16).serialize(w));
+
+        for stats in services {
+            try!(stats.serialize(w));
+        }
+
+        Ok(())
+    }
+}
+
+impl ControlMessageBody for SvcStatsMessage {
+    fn len(&self) -> usize {
+        self.stats.services()
+            .iter()
+            .fold(4, |sum, stats| sum + stats.size())
+    }
+}
+
+/// Append a given item to a given queue and drop the oldest items if the
+/// queue is longer than a given limit.
+fn push_limited<T>(queue: &mut VecDeque<T>, item: T, limit: usize) {
+    queue.push_back(item);
+
+    while queue.len() > limit {
+        queue.pop_front();
+    }
+}
+
+/// Load service statistics from a given file.
+fn load_services(file: &str) -> io::Result<Vec<ServiceStats>> {
+    let mut content = String::new();
+    let file        = try!(File::open(file));
+    let mut breader = BufReader::new(file);
+
+    try!(breader.read_to_string(&mut content));
+
+    json::decode(&content)
+        .or(Err(io::Error::new(ErrorKind::InvalidData,
+            "unable to decode service statistics")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::arrow::protocol::ControlMessageBody;
+
+    #[test]
+    fn test_rolling_stats() {
+        let mut table = ServiceStatsTable::new();
+
+        for _ in 0..150 {
+            table.connect_succeeded(1);
+        }
+
+        table.connect_failed(1, "connection refused");
+        table.session_closed(1, 2000, None);
+        table.session_closed(1, 4000, Some("connection reset"));
+
+        for i in 0..20 {
+            table.connect_failed(2, &format!("error {}", i));
+        }
+
+        let stats = &table.services()[0];
+
+        assert_eq!(stats.connect_attempts(), 100);
+        assert_eq!(stats.connect_successes(), 99);
+        assert_eq!(stats.sessions(), 2);
+        assert_eq!(stats.avg_session_lifetime(), 3000);
+        assert_eq!(stats.errors.len(), 2);
+
+        let stats = &table.services()[1];
+
+        assert_eq!(stats.connect_successes(), 0);
+        assert_eq!(stats.errors.len(), 10);
+        assert_eq!(stats.errors[0].message, "error 10");
+
+        assert_eq!(table.summary(), "1:99/100:3,2:0/20:0");
+
+        table.frames_dropped(2, 3, 1000);
+        table.frames_dropped(2, 2, 500);
+
+        let dropped = table.services()[1].dropped();
+
+        assert_eq!(dropped.frames, 5);
+        assert_eq!(dropped.bytes, 1500);
+
+        assert_eq!(table.services()[0].last_error().unwrap().message,
+            "connection reset");
+    }
+
+    #[test]
+    fn test_stats_pruning() {
+        let mut table = ServiceStatsTable::new();
+
+        table.connect_succeeded(1);
+        table.connect_succeeded(2);
+        table.connect_succeeded(3);
+
+        table.save()
+            .unwrap();
+
+        table.prune(|svc_id| svc_id < 10);
+
+        assert!(!table.modified);
+
+        table.remove(2);
+
+        assert!(table.modified);
+        assert_eq!(table.summary(), "1:1/1:0,3:1/1:0");
+
+        table.prune(|svc_id| svc_id != 3);
+
+        assert_eq!(table.summary(), "1:1/1:0");
+    }
+
+    #[test]
+    fn test_message_serialization() {
+        let mut table  = ServiceStatsTable::new();
+        let mut buffer = Vec::new();
+
+        table.connect_failed(3, "timeout");
+        table.connect_succeeded(7);
+
+        let msg = SvcStatsMessage::new(5, table);
+
+        msg.serialize(&mut buffer)
+            .unwrap();
+
+        assert_eq!(buffer.len(), msg.len());
+        assert_eq!(&buffer[..4], &[0, 5, 0, 2]);
+    }
+}
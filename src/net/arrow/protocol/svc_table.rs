@@ -15,27 +15,23 @@
 //! Service table definitions.
 
 use std::io;
-use std::mem;
 use std::fmt;
 use std::result;
 
 use std::io::Write;
 use std::str::FromStr;
 use std::error::Error;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::fmt::{Display, Formatter};
 use std::net::{ToSocketAddrs, SocketAddr, SocketAddrV4, Ipv4Addr};
 
-use utils;
-
+use utils::clock;
 use utils::Serialize;
 use utils::config::ConfigError;
 use net::utils::IpAddrEx;
 use net::raw::ether::MacAddr;
 use net::arrow::protocol::control::ControlMessageBody;
 
-use time;
-
 use rustc_serialize::json;
 
 use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
@@ -48,11 +44,15 @@ const SVC_TYPE_UNSUPPORTED_RTSP: u16 = 0x0004;
 const SVC_TYPE_HTTP:             u16 = 0x0005;
 const SVC_TYPE_MJPEG:            u16 = 0x0006;
 const SVC_TYPE_LOCKED_MJPEG:     u16 = 0x0007;
+const SVC_TYPE_UDP:              u16 = 0xfffd;
+const SVC_TYPE_ANY_TCP:          u16 = 0xfffe;
 const SVC_TYPE_TCP:              u16 = 0xffff;
 
+/// Size of the Service Table item header in bytes.
+const SERVICE_HEADER_SIZE: usize = 29;
+
 /// Service Table item header.
 #[derive(Debug, Copy, Clone)]
-#[repr(packed)]
 struct ServiceHeader {
     svc_id:     u16,
     svc_type:   u16,
@@ -84,16 +84,12 @@ impl ServiceHeader {
 
 impl Serialize for ServiceHeader {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        let be_header = ServiceHeader {
-            svc_id:     self.svc_id.to_be(),
-            svc_type:   self.svc_type.to_be(),
-            mac_addr:   self.mac_addr,
-            ip_version: self.ip_version,
-            ip_addr:    self.ip_addr,
-            port:       self.port.to_be(),
-        };
-
-        w.write_all(utils::as_bytes(&be_header))
+        try!(self.svc_id.serialize(w));
+        try!(self.svc_type.serialize(w));
+        try!(w.write_all(&self.mac_addr));
+        try!(self.ip_version.serialize(w));
+        try!(w.write_all(&self.ip_addr));
+        self.port.serialize(w)
     }
 }
 
@@ -118,6 +114,11 @@ pub enum Service {
     LockedMJPEG(MacAddr, SocketAddr),
     /// General purpose TCP service (mac, addr).
     TCP(MacAddr, SocketAddr),
+    /// TCP service with address given by Arrow Service on session request.
+    AnyTCP,
+    /// General purpose UDP service (mac, addr); the session payload is a
+    /// sequence of length-prefixed datagrams.
+    UDP(MacAddr, SocketAddr),
 }
 
 impl Service {
@@ -132,7 +133,9 @@ impl Service {
             &Service::HTTP(_, _)               => SVC_TYPE_HTTP,
             &Service::MJPEG(_, _, _)           => SVC_TYPE_MJPEG,
             &Service::LockedMJPEG(_, _)        => SVC_TYPE_LOCKED_MJPEG,
-            &Service::TCP(_, _)                => SVC_TYPE_TCP
+            &Service::TCP(_, _)                => SVC_TYPE_TCP,
+            &Service::AnyTCP                   => SVC_TYPE_ANY_TCP,
+            &Service::UDP(_, _)                => SVC_TYPE_UDP
         }
     }
 
@@ -147,11 +150,14 @@ impl Service {
             &Service::HTTP(ref addr, _)               => Some(addr),
             &Service::MJPEG(ref addr, _, _)           => Some(addr),
             &Service::LockedMJPEG(ref addr, _)        => Some(addr),
-            &Service::TCP(ref addr, _)                => Some(addr)
+            &Service::TCP(ref addr, _)                => Some(addr),
+            &Service::AnyTCP                          => None,
+            &Service::UDP(ref addr, _)                => Some(addr)
         }
     }
 
-    /// Get service address (in case it is not the Control Protocol svc).
+    /// Get service address (in case it is not the Control Protocol svc or
+    /// the any-TCP service).
     pub fn address(&self) -> Option<&SocketAddr> {
         match self {
             &Service::ControlProtocol                 => None,
@@ -162,7 +168,17 @@ impl Service {
             &Service::HTTP(_, ref addr)               => Some(addr),
             &Service::MJPEG(_, ref addr, _)           => Some(addr),
             &Service::LockedMJPEG(_, ref addr)        => Some(addr),
-            &Service::TCP(_, ref addr)                => Some(addr)
+            &Service::TCP(_, ref addr)                => Some(addr),
+            &Service::AnyTCP                          => None,
+            &Service::UDP(_, ref addr)                => Some(addr)
+        }
+    }
+
+    /// Check if this is a datagram service.
+    pub fn is_datagram(&self) -> bool {
+        match self {
+            &Service::UDP(_, _) => true,
+            _ => false
         }
     }
 
@@ -205,7 +221,41 @@ impl Service {
             None       => &[] as &[u8]
         };
 
-        mem::size_of::<ServiceHeader>() + path_bytes.len() + 1
+        SERVICE_HEADER_SIZE + path_bytes.len() + 1
+    }
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
+        let name = match self {
+            &Service::ControlProtocol          => "control-protocol",
+            &Service::RTSP(_, _, _)            => "rtsp",
+            &Service::LockedRTSP(_, _)         => "locked-rtsp",
+            &Service::UnknownRTSP(_, _)        => "unknown-rtsp",
+            &Service::UnsupportedRTSP(_, _, _) => "unsupported-rtsp",
+            &Service::HTTP(_, _)               => "http",
+            &Service::MJPEG(_, _, _)           => "mjpeg",
+            &Service::LockedMJPEG(_, _)        => "locked-mjpeg",
+            &Service::TCP(_, _)                => "tcp",
+            &Service::AnyTCP                   => "any-tcp",
+            &Service::UDP(_, _)                => "udp"
+        };
+
+        try!(f.write_str(name));
+
+        if let Some(mac) = self.mac() {
+            try!(write!(f, " {}", mac));
+        }
+
+        if let Some(addr) = self.address() {
+            try!(write!(f, " {}", addr));
+        }
+
+        if let Some(path) = self.path() {
+            try!(write!(f, " {}", path));
+        }
+
+        Ok(())
     }
 }
 
@@ -251,6 +301,10 @@ impl JsonService {
             SVC_TYPE_TCP => Ok(Service::TCP(
                 try!(MacAddr::from_str(&self.mac)),
                 try!(parse_socket_addr(&self.address)))),
+            SVC_TYPE_ANY_TCP => Ok(Service::AnyTCP),
+            SVC_TYPE_UDP => Ok(Service::UDP(
+                try!(MacAddr::from_str(&self.mac)),
+                try!(parse_socket_addr(&self.address)))),
             _ => Err(ConfigError::from("unknown service type"))
         };
 
@@ -312,9 +366,7 @@ const ACTIVE_THRESHOLD: u32 = 1200;
 
 /// Get current UNIX timestamp in UTC.
 fn get_utc_timestamp() -> i64 {
-    time::now_utc()
-        .to_timespec()
-        .sec
+    clock::timestamp()
 }
 
 /// Service table element.
@@ -447,6 +499,55 @@ impl ServiceTable {
         }
     }
 
+    /// Replace a given service with a new one (e.g. a stream with a changed
+    /// path) keeping its service ID. The last_seen timestamp will be set to
+    /// the current time. Return the service ID or None if the old service is
+    /// not in the table or the new one is already there.
+    pub fn replace(&mut self, old: &Service, new: Service) -> Option<u16> {
+        let old_key = get_service_table_key(old);
+        let new_key = get_service_table_key(&new);
+
+        if *old == Service::ControlProtocol || new == Service::ControlProtocol {
+            return None;
+        } else if old_key != new_key && self.map.contains_key(&new_key) {
+            return None;
+        }
+
+        let index = match self.map.remove(&old_key) {
+            Some(index) => index,
+            None => return None
+        };
+
+        self.map.insert(new_key, index);
+
+        let elem = &mut self.services[index];
+
+        elem.last_seen = get_utc_timestamp();
+        elem.service   = new;
+
+        Some(elem.service_id)
+    }
+
+    /// Remove a service with a given ID. The service is only deactivated, its
+    /// ID stays reserved, so that the service gets the same ID (and becomes
+    /// active again) if it is discovered later. Static services and the
+    /// Control Protocol service cannot be removed. Return true if the
+    /// service has been removed.
+    pub fn remove(&mut self, id: u16) -> bool {
+        if id == 0 {
+            return false;
+        }
+
+        match self.services.get_mut((id - 1) as usize) {
+            Some(ref mut elem) if !elem.static_service => {
+                elem.last_seen = 0;
+                elem.active    = false;
+                true
+            },
+            _ => false
+        }
+    }
+
     /// Update active flags of all services.
     pub fn update_active_services(&mut self) -> bool {
         let timestamp = get_utc_timestamp();
@@ -468,6 +569,173 @@ impl ServiceTable {
 
         res
     }
+
+    /// Get all active services with their IDs (ordered by the IDs).
+    pub fn active_entries(&self) -> Vec<(u16, Service)> {
+        self.active_elements()
+            .into_iter()
+            .map(|(svc_id, svc)| (svc_id, svc.clone()))
+            .collect()
+    }
+
+    /// Get differences between this table (e.g. the one last sent to Arrow
+    /// Service) and a given one. Only active services are compared.
+    pub fn diff(&self, other: &ServiceTable) -> ServiceTableDiff {
+        let old = self.active_elements();
+        let new = other.active_elements();
+
+        let mut res = ServiceTableDiff::new();
+
+        for (&svc_id, old_svc) in &old {
+            match new.get(&svc_id) {
+                None => res.removed.push((svc_id, (*old_svc).clone())),
+                Some(new_svc) if new_svc != old_svc => res.changed.push(
+                    (svc_id, (*old_svc).clone(), (*new_svc).clone())),
+                _ => ()
+            }
+        }
+
+        for (&svc_id, new_svc) in &new {
+            if !old.contains_key(&svc_id) {
+                res.added.push((svc_id, (*new_svc).clone()));
+            }
+        }
+
+        res
+    }
+
+    /// Get active services ordered by their IDs.
+    fn active_elements(&self) -> BTreeMap<u16, &Service> {
+        self.services.iter()
+            .filter(|elem| elem.active)
+            .map(|elem| (elem.service_id, &elem.service))
+            .collect()
+    }
+
+    /// Split active services into pages. Serialized size of each page will
+    /// not exceed a given limit unless a single service is bigger than the
+    /// limit. There is always at least one page.
+    pub fn pages(&self, max_size: usize) -> Vec<ServiceTablePage> {
+        let mut res  = Vec::new();
+        let mut page = ServiceTablePage::new();
+
+        for elem in &self.services {
+            if !elem.active {
+                continue;
+            }
+
+            let len = elem.service.len();
+
+            if !page.is_empty() && (page.len() + len) > max_size {
+                res.push(page);
+                page = ServiceTablePage::new();
+            }
+
+            page.push(elem.service_id, elem.service.clone());
+        }
+
+        res.push(page);
+        res
+    }
+}
+
+/// Differences between two service tables.
+#[derive(Debug, Clone)]
+pub struct ServiceTableDiff {
+    /// Added services.
+    pub added:   Vec<(u16, Service)>,
+    /// Removed services.
+    pub removed: Vec<(u16, Service)>,
+    /// Services with the same ID but different parameters (old and new).
+    pub changed: Vec<(u16, Service, Service)>,
+}
+
+impl ServiceTableDiff {
+    /// Create a new empty diff.
+    fn new() -> ServiceTableDiff {
+        ServiceTableDiff {
+            added:   Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new()
+        }
+    }
+
+    /// Check if there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+impl Display for ServiceTableDiff {
+    fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
+        if self.is_empty() {
+            return f.write_str("no differences\n");
+        }
+
+        for &(svc_id, ref svc) in &self.added {
+            try!(write!(f, "+ {:04x} {}\n", svc_id, svc));
+        }
+
+        for &(svc_id, ref svc) in &self.removed {
+            try!(write!(f, "- {:04x} {}\n", svc_id, svc));
+        }
+
+        for &(svc_id, ref old, ref new) in &self.changed {
+            try!(write!(f, "~ {:04x} {} -> {}\n", svc_id, old, new));
+        }
+
+        Ok(())
+    }
+}
+
+/// A part of the service table sent within a single message. The page is
+/// serialized in the same way as the service table (i.e. the list of
+/// services is terminated by the Control Protocol service).
+#[derive(Debug, Clone)]
+pub struct ServiceTablePage {
+    services: Vec<(u16, Service)>,
+    size:     usize,
+}
+
+impl ServiceTablePage {
+    /// Create a new empty page.
+    fn new() -> ServiceTablePage {
+        ServiceTablePage {
+            services: Vec::new(),
+            size:     Service::ControlProtocol.len()
+        }
+    }
+
+    /// Check if there are no services in this page.
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Add a given service.
+    fn push(&mut self, svc_id: u16, svc: Service) {
+        self.size += svc.len();
+        self.services.push((svc_id, svc));
+    }
+}
+
+impl Serialize for ServiceTablePage {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for &(svc_id, ref svc) in &self.services {
+            try!(svc.serialize(w, svc_id));
+        }
+
+        let cp_svc = Service::ControlProtocol;
+
+        cp_svc.serialize(w, 0)
+    }
+}
+
+impl ControlMessageBody for ServiceTablePage {
+    fn len(&self) -> usize {
+        self.size
+    }
 }
 
 impl Serialize for ServiceTable {
@@ -570,6 +838,7 @@ mod tests {
     use net::raw::ether::MacAddr;
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
     use net::arrow::protocol::control::ControlMessageBody;
+    use std::io::Write;
 
     #[test]
     fn test_service_table() {
@@ -591,6 +860,55 @@ mod tests {
 
         assert!(table.contains(&rtsp));
         assert!(table.contains(&lrtsp));
+
+        let new_rtsp = Service::RTSP(
+            mac.clone(), addr.clone(), "/bar".to_string());
+
+        assert_eq!(table.replace(&rtsp, new_rtsp.clone()), Some(1));
+
+        assert!(!table.contains(&rtsp));
+        assert!(table.contains(&new_rtsp));
+        assert_eq!(table.get_id(&new_rtsp), Some(1));
+
+        assert_eq!(table.replace(&rtsp, new_rtsp.clone()), None);
+        assert_eq!(table.replace(&lrtsp, new_rtsp.clone()), None);
+    }
+
+    #[test]
+    fn test_service_removal() {
+        let mac  = MacAddr::new(0, 0, 0, 0, 0, 0);
+        let addr = SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(1, 2, 3, 4), 5));
+        let http = Service::HTTP(mac.clone(), addr.clone());
+        let tcp  = Service::TCP(mac.clone(), addr.clone());
+        let mut table = ServiceTable::new();
+
+        assert_eq!(table.add(http.clone()), Some(1));
+        assert_eq!(table.add_static(tcp.clone()), Some(2));
+
+        // the Control Protocol, static and unknown services cannot be removed
+        assert!(!table.remove(0));
+        assert!(!table.remove(2));
+        assert!(!table.remove(3));
+
+        assert!(table.remove(1));
+        assert_eq!(table.active_services(),
+            vec![Service::ControlProtocol, tcp.clone()]);
+
+        // the flag is not restored by the periodic update
+        table.update_active_services();
+
+        assert_eq!(table.active_services(),
+            vec![Service::ControlProtocol, tcp.clone()]);
+
+        // a rediscovered service keeps its ID
+        assert_eq!(table.add(http.clone()), None);
+        assert_eq!(table.get_id(&http), Some(1));
+
+        table.update_active_services();
+
+        assert_eq!(table.active_services(),
+            vec![Service::ControlProtocol, http, tcp]);
     }
 
     #[test]
@@ -630,6 +948,85 @@ mod tests {
         assert_eq!(data_bytes, buf.as_bytes());
     }
 
+    #[test]
+    fn test_service_table_diff() {
+        let mac   = MacAddr::new(0, 0, 0, 0, 0, 0);
+        let addr1 = SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(1, 2, 3, 4), 1));
+        let addr2 = SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(1, 2, 3, 4), 2));
+        let mut table = ServiceTable::new();
+
+        table.add(Service::LockedRTSP(mac.clone(), addr1));
+
+        let sent = table.clone();
+
+        assert!(sent.diff(&table).is_empty());
+
+        table.replace(&Service::LockedRTSP(mac.clone(), addr1),
+            Service::RTSP(mac.clone(), addr1, "/foo".to_string()));
+        table.add(Service::HTTP(mac.clone(), addr2));
+
+        let diff = sent.diff(&table);
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].0, 2);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].0, 1);
+
+        let diff = table.diff(&ServiceTable::new());
+
+        assert_eq!(diff.removed.len(), 2);
+        assert_eq!(diff.to_string(),
+            "- 0001 rtsp 00:00:00:00:00:00 1.2.3.4:1 /foo\n\
+             - 0002 http 00:00:00:00:00:00 1.2.3.4:2\n");
+    }
+
+    #[test]
+    fn test_service_table_pages() {
+        let mac   = MacAddr::new(0, 0, 0, 0, 0, 0);
+        let mut table = ServiceTable::new();
+
+        assert_eq!(table.pages(100).len(), 1);
+        assert!(table.pages(100)[0].is_empty());
+
+        for port in 0..10 {
+            let addr = SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(1, 2, 3, 4), port));
+
+            table.add(Service::LockedRTSP(mac.clone(), addr));
+        }
+
+        let cp_len = Service::ControlProtocol.len();
+        let len    = table.len() - cp_len;
+        let pages  = table.pages(cp_len + len / 2);
+
+        assert_eq!(pages.len(), 2);
+
+        let mut whole = WriteBuffer::new(0);
+        let mut paged = WriteBuffer::new(0);
+
+        table.serialize(&mut whole).unwrap();
+
+        for page in &pages {
+            assert!(page.len() <= cp_len + len / 2);
+
+            let mut buf = WriteBuffer::new(0);
+
+            page.serialize(&mut buf).unwrap();
+
+            assert_eq!(buf.as_bytes().len(), page.len());
+
+            // strip the terminating Control Protocol service
+            paged.write_all(&buf.as_bytes()[..page.len() - cp_len])
+                .unwrap();
+        }
+
+        let whole = whole.as_bytes();
+
+        assert_eq!(&whole[..whole.len() - cp_len], paged.as_bytes());
+    }
+
     #[test]
     fn test_service_table_json_serialization() {
         let mac  = MacAddr::new(0, 0, 0, 0, 0, 0);
@@ -639,18 +1036,22 @@ mod tests {
             mac.clone(), addr.clone(), "/foo".to_string());
         let lrtsp = Service::LockedRTSP(
             mac.clone(), addr.clone());
+        let udp = Service::UDP(
+            mac.clone(), addr.clone());
         let mut table = ServiceTable::new();
 
         table.add(rtsp.clone());
         table.add(lrtsp.clone());
+        table.add(udp.clone());
 
         let json  = json::encode(&table).unwrap();
         let table = json::decode::<ServiceTable>(&json).unwrap();
 
         assert!(table.contains(&rtsp));
         assert!(table.contains(&lrtsp));
+        assert!(table.contains(&udp));
         assert!(table.contains(&Service::ControlProtocol));
 
-        assert_eq!(table.services.len(), 2);
+        assert_eq!(table.services.len(), 3);
     }
 }
@@ -0,0 +1,240 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocol violation definitions.
+//!
+//! The client normally ignores framing anomalies which do not prevent it from
+//! processing a message (e.g. non-zero reserved bits or trailing data). In
+//! the strict parsing mode, every received message is checked for such
+//! anomalies and each of them is reported to Arrow Service using the
+//! PROTOCOL_VIOLATION message (if the server supports the
+//! FEATURE_PROTOCOL_VIOLATIONS Control Protocol feature). The message is
+//! processed as usual afterwards.
+
+use std::io;
+
+use std::io::Write;
+
+use utils::Serialize;
+use net::arrow::protocol::ARROW_HEADER_SIZE;
+use net::arrow::protocol::{ControlMessageBody, ControlMessageParser};
+use net::arrow::protocol::ControlMessageType;
+
+pub const VIOLATION_RESERVED_BITS:   u8 = 0x01;
+pub const VIOLATION_CONTROL_SESSION: u8 = 0x02;
+pub const VIOLATION_BODY_LENGTH:     u8 = 0x03;
+pub const VIOLATION_REDIRECT_FORMAT: u8 = 0x04;
+
+/// Maximum number of message bytes included in a violation report.
+pub const MAX_SAMPLE_LENGTH: usize = 64;
+
+/// Size of the protocol violation header in bytes.
+const VIOLATION_HEADER_SIZE: usize = 11;
+
+/// Protocol violation found in a received message.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProtocolViolation {
+    /// Violation type.
+    pub violation_type: u8,
+    /// UNIX timestamp (in UTC) of the violation.
+    pub timestamp:      i64,
+    /// Beginning of the offending message (at most MAX_SAMPLE_LENGTH bytes).
+    pub sample:         Vec<u8>,
+}
+
+impl ProtocolViolation {
+    /// Create a new protocol violation of a given type found in a given
+    /// message (the message is truncated to MAX_SAMPLE_LENGTH bytes).
+    pub fn new(
+        violation_type: u8,
+        timestamp: i64,
+        msg: &[u8]) -> ProtocolViolation {
+        let len = if msg.len() > MAX_SAMPLE_LENGTH {
+            MAX_SAMPLE_LENGTH
+        } else {
+            msg.len()
+        };
+
+        ProtocolViolation {
+            violation_type: violation_type,
+            timestamp:      timestamp,
+            sample:         msg[..len].to_vec()
+        }
+    }
+
+    /// Get violation name.
+    pub fn name(&self) -> &'static str {
+        match self.violation_type {
+            VIOLATION_RESERVED_BITS   => "non-zero reserved bits",
+            VIOLATION_CONTROL_SESSION => "non-zero Control Protocol session ID",
+            VIOLATION_BODY_LENGTH     => "unexpected Control Protocol message length",
+            VIOLATION_REDIRECT_FORMAT => "malformed REDIRECT address",
+            _                         => "unknown"
+        }
+    }
+
+    /// Get hexdump of the message sample.
+    pub fn hexdump(&self) -> String {
+        self.sample.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Serialize for ProtocolViolation {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.violation_type.serialize(w));
+        try!(self.timestamp.serialize(w));
+        try!((self.sample.len() as u16).serialize(w));
+
+        w.write_all(&self.sample)
+    }
+}
+
+impl ControlMessageBody for ProtocolViolation {
+    fn len(&self) -> usize {
+        VIOLATION_HEADER_SIZE + self.sample.len()
+    }
+}
+
+/// Check a given complete Arrow Message (including its header) for
+/// anomalies and return types of all violations found. Lengths of ACK
+/// messages are checked only once the connection is established (handshake
+/// ACKs have variable length).
+pub fn check_message(msg: &[u8], established: bool) -> Vec<u8> {
+    let mut res = Vec::new();
+
+    if msg.len() < ARROW_HEADER_SIZE {
+        return res;
+    }
+
+    let service = ((msg[1] as u16) << 8) | (msg[2] as u16);
+    let session = ((msg[4] as u32) << 16)
+        | ((msg[5] as u32) << 8)
+        | (msg[6] as u32);
+
+    // the upper 8 bits of the session ID are reserved
+    if msg[3] != 0 {
+        res.push(VIOLATION_RESERVED_BITS);
+    }
+
+    if service != 0 {
+        return res;
+    }
+
+    if session != 0 {
+        res.push(VIOLATION_CONTROL_SESSION);
+    }
+
+    let mut parser = ControlMessageParser::new();
+
+    if parser.process(&msg[ARROW_HEADER_SIZE..]).is_err() {
+        return res;
+    }
+
+    let body = parser.body();
+
+    let expected = match parser.header().message_type() {
+        ControlMessageType::PING            => Some(0),
+        ControlMessageType::RESET_SVC_TABLE => Some(0),
+        ControlMessageType::SCAN_NETWORK    => Some(0),
+        ControlMessageType::GET_STATUS      => Some(0),
+        ControlMessageType::GET_SCAN_REPORT => Some(0),
+        ControlMessageType::GET_SVC_STATS   => Some(0),
+        ControlMessageType::HUP             => Some(8),
+        ControlMessageType::ROTATE_SECRET   => Some(16),
+        ControlMessageType::ACK if established => Some(4),
+        ControlMessageType::REDIRECT => {
+            // null-terminated address without any trailing data
+            if body.iter().position(|&b| b == 0) != Some(body.len().wrapping_sub(1)) {
+                res.push(VIOLATION_REDIRECT_FORMAT);
+            }
+
+            None
+        },
+        _ => None
+    };
+
+    if let Some(expected) = expected {
+        if body.len() != expected {
+            res.push(VIOLATION_BODY_LENGTH);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::arrow::protocol::ControlMessageBody;
+
+    #[test]
+    fn test_check_message() {
+        // valid PING
+        let ping = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 1, 0, 1];
+
+        assert!(check_message(&ping, true).is_empty());
+
+        // PING with reserved bits, a session ID and trailing data
+        let ping = [1, 0, 0, 0xff, 0, 0, 1, 0, 0, 0, 5, 0, 1, 0, 1, 0];
+
+        assert_eq!(check_message(&ping, true), vec![
+            VIOLATION_RESERVED_BITS,
+            VIOLATION_CONTROL_SESSION,
+            VIOLATION_BODY_LENGTH]);
+
+        // handshake ACKs have variable length
+        let ack = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 6, 0, 1, 0, 0, 0, 0];
+
+        assert!(check_message(&ack, false).is_empty());
+        assert_eq!(check_message(&ack, true), vec![VIOLATION_BODY_LENGTH]);
+
+        // REDIRECT without the null terminator
+        let redirect = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 6, 0, 1, 0, 3, b'a', b'b'];
+
+        assert_eq!(check_message(&redirect, true),
+            vec![VIOLATION_REDIRECT_FORMAT]);
+
+        let redirect = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 1, 0, 3, b'a', b'b', 0];
+
+        assert!(check_message(&redirect, true).is_empty());
+
+        // service data are not checked
+        let data = [1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0xaa];
+
+        assert!(check_message(&data, true).is_empty());
+    }
+
+    #[test]
+    fn test_violation_serialization() {
+        let msg = (0..100).collect::<Vec<u8>>();
+
+        let violation = ProtocolViolation::new(VIOLATION_BODY_LENGTH, 1, &msg);
+
+        let mut buf = Vec::new();
+
+        violation.serialize(&mut buf)
+            .unwrap();
+
+        assert_eq!(violation.sample.len(), MAX_SAMPLE_LENGTH);
+        assert_eq!(buf.len(), violation.len());
+        assert_eq!(&buf[..11], &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 64]);
+        assert_eq!(&buf[11..], &msg[..64]);
+        assert!(violation.hexdump().starts_with("00 01 02 03"));
+    }
+}
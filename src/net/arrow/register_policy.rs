@@ -0,0 +1,131 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! REGISTER error handling policy.
+//!
+//! The policy maps error codes returned by Arrow Service in the REGISTER ACK
+//! to actions taken by the client:
+//!
+//! * `Retry` - reconnect after the usual retry timeout,
+//! * `WaitForConfig` - the client needs to be configured on the service side
+//!   (e.g. paired with an account); reconnect frequently for a while and
+//!   then back off to a very long timeout,
+//! * `Halt` - stop reconnecting for a very long time (e.g. the protocol
+//!   version is not supported and the client needs to be upgraded).
+
+use std::str::FromStr;
+
+use std::collections::HashMap;
+
+use net::arrow::protocol::{ACK_UNSUPPORTED_PROTOCOL_VERSION, ACK_UNAUTHORIZED};
+
+use utils::RuntimeError;
+
+/// Client action taken on a REGISTER error.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegisterAction {
+    /// Reconnect after the usual retry timeout.
+    Retry,
+    /// Wait until the client is configured on the service side.
+    WaitForConfig,
+    /// Stop reconnecting for a very long time.
+    Halt,
+}
+
+impl RegisterAction {
+    /// Parse a given action name ("retry", "wait" or "halt").
+    pub fn from_name(name: &str) -> Result<RegisterAction, RuntimeError> {
+        match name {
+            "retry" => Ok(RegisterAction::Retry),
+            "wait"  => Ok(RegisterAction::WaitForConfig),
+            "halt"  => Ok(RegisterAction::Halt),
+            _ => Err(RuntimeError::from(format!("unknown REGISTER error action: {}", name)))
+        }
+    }
+}
+
+/// Mapping of REGISTER error codes to client actions.
+#[derive(Debug, Clone)]
+pub struct RegisterErrorPolicy {
+    actions: HashMap<u32, RegisterAction>,
+}
+
+impl RegisterErrorPolicy {
+    /// Create a new policy with the default actions (wait if the client is
+    /// unauthorized, halt if the protocol version is not supported and retry
+    /// on all other errors).
+    pub fn new() -> RegisterErrorPolicy {
+        let mut res = RegisterErrorPolicy {
+            actions: HashMap::new()
+        };
+
+        res.set(ACK_UNAUTHORIZED, RegisterAction::WaitForConfig);
+        res.set(ACK_UNSUPPORTED_PROTOCOL_VERSION, RegisterAction::Halt);
+
+        res
+    }
+
+    /// Set action for a given error code.
+    pub fn set(&mut self, code: u32, action: RegisterAction) {
+        self.actions.insert(code, action);
+    }
+
+    /// Get action for a given error code.
+    pub fn action(&self, code: u32) -> RegisterAction {
+        self.actions.get(&code)
+            .cloned()
+            .unwrap_or(RegisterAction::Retry)
+    }
+}
+
+/// Parse a given REGISTER error code (decimal or hexadecimal with the "0x"
+/// prefix).
+pub fn parse_error_code(s: &str) -> Result<u32, RuntimeError> {
+    let res = if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        u32::from_str(s)
+    };
+
+    res.or(Err(RuntimeError::from(format!("invalid error code: {}", s))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use net::arrow::protocol::*;
+
+    #[test]
+    fn test_register_error_policy() {
+        let mut policy = RegisterErrorPolicy::new();
+
+        assert_eq!(policy.action(ACK_UNAUTHORIZED),
+            RegisterAction::WaitForConfig);
+        assert_eq!(policy.action(ACK_UNSUPPORTED_PROTOCOL_VERSION),
+            RegisterAction::Halt);
+        assert_eq!(policy.action(ACK_INTERNAL_SERVER_ERROR),
+            RegisterAction::Retry);
+        assert_eq!(policy.action(0x1234), RegisterAction::Retry);
+
+        policy.set(0x1234, RegisterAction::Halt);
+
+        assert_eq!(policy.action(0x1234), RegisterAction::Halt);
+
+        assert_eq!(parse_error_code("0xffffffff").unwrap(), 0xffffffff);
+        assert_eq!(parse_error_code("2").unwrap(), 2);
+        assert!(parse_error_code("0xfoo").is_err());
+        assert!(RegisterAction::from_name("foo").is_err());
+    }
+}
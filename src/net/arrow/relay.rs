@@ -0,0 +1,650 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arrow relay definitions (federation mode).
+//!
+//! A client which cannot reach Arrow Service directly (e.g. a client on an
+//! isolated camera VLAN) can connect to a relay running on another Arrow
+//! Client instead. The client sends a relay HELLO message (an Arrow Message
+//! with the RELAY_SERVICE_ID service ID) containing the target Arrow Service
+//! address and the list of clients/relays the connection has already passed
+//! through. The relay connects to the target (possibly through its own
+//! upstream relay) and forwards all data between the two connections from
+//! then on. The TLS session is established end-to-end between the client and
+//! Arrow Service.
+//!
+//! A relay refuses connections that have already passed through it or that
+//! have passed through more than MAX_RELAY_HOPS clients/relays.
+//!
+//! HELLO messages are authenticated using HMAC-SHA256 with a secret shared
+//! by all clients and relays of the federation. The signed data include a
+//! timestamp and a random nonce. A relay refuses messages older (or newer)
+//! than HELLO_MAX_AGE seconds and messages with a nonce it has already seen,
+//! so a captured message cannot be replayed (the clocks of all clients and
+//! relays of the federation have to be roughly synchronized). A relay
+//! connects only to the Arrow Service addresses it has been configured with
+//! (it must not be usable as a general TCP proxy) and it relays at most
+//! MAX_RELAY_CONNECTIONS connections at once.
+
+use std::io;
+use std::mem;
+use std::thread;
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+
+use utils;
+
+use utils::clock;
+use utils::endian;
+use utils::Serialize;
+use utils::logger::Logger;
+use utils::secret::{Secret, constant_time_eq};
+
+use openssl::crypto::hash::Type as HashType;
+use openssl::crypto::hmac;
+use openssl::crypto::rand;
+
+use net::arrow::protocol::{ArrowMessage, ArrowMessageBody, ArrowMessageParser};
+use net::arrow::error::{Result, ArrowError};
+
+/// Service ID reserved for the relay HELLO message.
+pub const RELAY_SERVICE_ID: u16 = 0xffff;
+
+/// Maximum number of clients/relays a connection can pass through.
+pub const MAX_RELAY_HOPS: usize = 8;
+
+/// Maximum time for receiving the relay HELLO message (in milliseconds).
+const HELLO_TIMEOUT: u64 = 10000;
+
+/// Maximum difference between the HELLO timestamp and the current time (in
+/// seconds).
+const HELLO_MAX_AGE: u64 = 60;
+
+/// Size of the HELLO nonce.
+const NONCE_SIZE: usize = 16;
+
+/// Maximum time for connecting to the target or to the upstream relay (in
+/// milliseconds).
+const CONNECT_TIMEOUT: u64 = 10000;
+
+/// Maximum number of connections relayed at once.
+const MAX_RELAY_CONNECTIONS: usize = 64;
+
+/// Size of the HELLO authentication tag.
+const AUTH_TAG_SIZE: usize = 32;
+
+/// Relay HELLO message.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RelayHello {
+    /// UUIDs of all clients/relays the connection has passed through.
+    path:      Vec<[u8; 16]>,
+    /// Time of creating the message (UNIX timestamp in seconds).
+    timestamp: u64,
+    /// Random nonce.
+    nonce:     [u8; NONCE_SIZE],
+    /// Target Arrow Service address.
+    target:    String,
+    /// Authentication tag (empty if the message has not been signed).
+    tag:       Vec<u8>,
+}
+
+impl RelayHello {
+    /// Create a new relay HELLO message for a given client UUID and target
+    /// Arrow Service address.
+    pub fn new(uuid: [u8; 16], target: &str) -> RelayHello {
+        RelayHello {
+            path:      vec![uuid],
+            timestamp: clock::timestamp() as u64,
+            nonce:     new_nonce(),
+            target:    target.to_string(),
+            tag:       Vec::new()
+        }
+    }
+
+    /// Get target Arrow Service address.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Check if the connection has already passed through a given relay or
+    /// if it has passed through too many relays.
+    pub fn is_loop(&self, uuid: &[u8; 16]) -> bool {
+        self.path.len() >= MAX_RELAY_HOPS || self.path.contains(uuid)
+    }
+
+    /// Check if the message has been created at most HELLO_MAX_AGE seconds
+    /// before (or after) a given time (UNIX timestamp in seconds).
+    pub fn is_fresh(&self, now: u64) -> bool {
+        self.timestamp + HELLO_MAX_AGE >= now
+            && self.timestamp <= now + HELLO_MAX_AGE
+    }
+
+    /// Create a HELLO message for the next relay (it gets a new timestamp
+    /// and nonce and it has to be signed again).
+    pub fn forward(&self, uuid: [u8; 16]) -> RelayHello {
+        let mut res = self.clone();
+        res.path.push(uuid);
+        res.timestamp = clock::timestamp() as u64;
+        res.nonce = new_nonce();
+        res.tag.clear();
+        res
+    }
+
+    /// Sign the message using a given shared secret.
+    pub fn sign(mut self, secret: &Secret<Vec<u8>>) -> RelayHello {
+        self.tag = self.auth_tag(secret);
+        self
+    }
+
+    /// Check the authentication tag using a given shared secret.
+    pub fn verify(&self, secret: &Secret<Vec<u8>>) -> bool {
+        constant_time_eq(&self.tag, &self.auth_tag(secret))
+    }
+
+    /// Compute the authentication tag (over the path, the timestamp, the
+    /// nonce and the target).
+    fn auth_tag(&self, secret: &Secret<Vec<u8>>) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        self.serialize_content(&mut data)
+            .unwrap();
+
+        hmac::hmac(HashType::SHA256, secret.expose(), &data)
+    }
+
+    /// Serialize the message without the authentication tag.
+    fn serialize_content<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!((self.path.len() as u8).serialize(w));
+
+        for uuid in &self.path {
+            try!(w.write_all(uuid));
+        }
+
+        try!(self.timestamp.serialize(w));
+        try!(w.write_all(&self.nonce));
+        try!(w.write_all(self.target.as_bytes()));
+        w.write_all(&[0])
+    }
+
+    /// Parse a relay HELLO message body.
+    pub fn parse(data: &[u8]) -> Result<RelayHello> {
+        let count = match data.first() {
+            Some(count) => *count as usize,
+            None => return Err(ArrowError::other("empty relay HELLO message"))
+        };
+
+        let nonce_offset  = 1 + count * 16 + 8;
+        let target_offset = nonce_offset + NONCE_SIZE;
+
+        if data.len() <= target_offset {
+            return Err(ArrowError::other("malformed relay HELLO message"));
+        }
+
+        let mut path = Vec::new();
+
+        for i in 0..count {
+            let mut uuid = [0u8; 16];
+            let offset   = 1 + i * 16;
+            utils::memcpy(&mut uuid, &data[offset..offset + 16]);
+            path.push(uuid);
+        }
+
+        let mut nonce = [0u8; NONCE_SIZE];
+
+        utils::memcpy(&mut nonce, &data[nonce_offset..target_offset]);
+
+        let rest = &data[target_offset..];
+        let end  = match rest.iter().position(|b| *b == 0) {
+            Some(end) => end,
+            None => return Err(ArrowError::other("malformed relay HELLO message"))
+        };
+
+        let target = try_other!(String::from_utf8(rest[..end].to_vec())
+            .or(Err("malformed relay HELLO message")));
+
+        let tag = &rest[end + 1..];
+
+        if !tag.is_empty() && tag.len() != AUTH_TAG_SIZE {
+            return Err(ArrowError::other("malformed relay HELLO message"));
+        }
+
+        let res = RelayHello {
+            path:      path,
+            timestamp: endian::get_u64(&data[nonce_offset - 8..]),
+            nonce:     nonce,
+            target:    target,
+            tag:       tag.to_vec()
+        };
+
+        Ok(res)
+    }
+}
+
+impl Serialize for RelayHello {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.serialize_content(w));
+        w.write_all(&self.tag)
+    }
+}
+
+impl ArrowMessageBody for RelayHello {
+    fn len(&self) -> usize {
+        1 + self.path.len() * mem::size_of::<[u8; 16]>()
+            + 8 + NONCE_SIZE
+            + self.target.len() + 1
+            + self.tag.len()
+    }
+}
+
+/// Generate a new random HELLO nonce.
+fn new_nonce() -> [u8; NONCE_SIZE] {
+    let mut res = [0u8; NONCE_SIZE];
+
+    utils::memcpy(&mut res, &rand::rand_bytes(NONCE_SIZE));
+
+    res
+}
+
+/// Connect to a given address (trying all its resolved socket addresses)
+/// within the connect timeout.
+fn connect_timeout<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    let timeout = Duration::from_millis(CONNECT_TIMEOUT);
+
+    let mut last_err = io::Error::new(io::ErrorKind::Other,
+        "no address to connect to");
+
+    for addr in try!(addr.to_socket_addrs()) {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err)   => last_err = err
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Connect to a given relay and send a given (signed) HELLO message.
+pub fn connect<A: ToSocketAddrs>(
+    relay_addr: A,
+    hello: &RelayHello) -> Result<TcpStream> {
+    let mut stream = try_io!(connect_timeout(relay_addr));
+    let msg        = ArrowMessage::new(RELAY_SERVICE_ID, 0, hello.clone());
+
+    try_io!(msg.serialize(&mut stream));
+
+    Ok(stream)
+}
+
+/// Read a relay HELLO message from a given stream within the HELLO timeout
+/// (the deadline covers the whole message, so that a peer cannot keep the
+/// connection open by sending a byte at a time). Return the message and
+/// data received after the message.
+fn read_hello(stream: &mut TcpStream) -> Result<(RelayHello, Vec<u8>)> {
+    let mut parser = ArrowMessageParser::new();
+    let mut buffer = [0u8; 4096];
+
+    let deadline = Instant::now() + Duration::from_millis(HELLO_TIMEOUT);
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(ArrowError::connection_error(
+                "relay HELLO timeout"));
+        }
+
+        try_io!(stream.set_read_timeout(Some(deadline - now)));
+
+        let len = try_io!(stream.read(&mut buffer));
+        if len == 0 {
+            return Err(ArrowError::connection_error(
+                "connection closed before relay HELLO"));
+        }
+
+        let consumed = try_arr!(parser.add(&buffer[..len]));
+
+        if parser.is_complete() {
+            let service = parser.header()
+                .map(|header| header.service)
+                .unwrap_or(0);
+
+            if service != RELAY_SERVICE_ID {
+                return Err(ArrowError::other("relay HELLO message expected"));
+            }
+
+            let hello = try_arr!(RelayHello::parse(parser.body().unwrap()));
+            let rest  = buffer[consumed..len].to_vec();
+
+            return Ok((hello, rest));
+        }
+    }
+}
+
+/// Forward all data from one stream to another and shut the streams down
+/// afterwards.
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    io::copy(&mut from, &mut to)
+        .ok();
+
+    to.shutdown(Shutdown::Write)
+        .ok();
+    from.shutdown(Shutdown::Read)
+        .ok();
+}
+
+/// Relay settings shared by all connection threads.
+struct RelayContext {
+    /// UUID of this client.
+    uuid:     [u8; 16],
+    /// Upstream relay address (if this client is behind another relay).
+    upstream: Option<String>,
+    /// Shared secret of the federation.
+    secret:   Secret<Vec<u8>>,
+    /// Arrow Service addresses the relay is allowed to connect to.
+    targets:  Vec<String>,
+    /// Number of connections being relayed.
+    active:   AtomicUsize,
+    /// Nonces (and timestamps) of HELLO messages accepted in the last
+    /// HELLO_MAX_AGE seconds.
+    nonces:   Mutex<HashMap<[u8; NONCE_SIZE], u64>>,
+}
+
+impl RelayContext {
+    /// Check that a given (authenticated) HELLO message is fresh and that it
+    /// has not been used before. The nonce is remembered until the message
+    /// expires.
+    fn check_replay(&self, hello: &RelayHello, now: u64) -> Result<()> {
+        if !hello.is_fresh(now) {
+            return Err(ArrowError::other("stale relay HELLO message"));
+        }
+
+        let mut nonces = self.nonces.lock()
+            .unwrap();
+
+        nonces.retain(|_, timestamp| *timestamp + HELLO_MAX_AGE >= now);
+
+        if nonces.contains_key(&hello.nonce) {
+            return Err(ArrowError::other("replayed relay HELLO message"));
+        }
+
+        nonces.insert(hello.nonce, hello.timestamp);
+
+        Ok(())
+    }
+}
+
+/// Guard decrementing the number of relayed connections when dropped.
+struct ConnectionGuard {
+    context: Arc<RelayContext>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.context.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Arrow relay.
+pub struct RelayServer<L: Logger + Clone + Send + 'static> {
+    /// Logger.
+    logger:  L,
+    /// Relay settings.
+    context: Arc<RelayContext>,
+}
+
+impl<L: Logger + Clone + Send + 'static> RelayServer<L> {
+    /// Create a new relay for a given client UUID and upstream relay. HELLO
+    /// messages are authenticated using a given shared secret and only
+    /// given Arrow Service addresses ("host:port" format) are accepted as
+    /// targets.
+    pub fn new(
+        logger: L,
+        uuid: [u8; 16],
+        upstream: Option<String>,
+        secret: Secret<Vec<u8>>,
+        targets: Vec<String>) -> RelayServer<L> {
+        let context = RelayContext {
+            uuid:     uuid,
+            upstream: upstream,
+            secret:   secret,
+            targets:  targets,
+            active:   AtomicUsize::new(0),
+            nonces:   Mutex::new(HashMap::new())
+        };
+
+        RelayServer {
+            logger:  logger,
+            context: Arc::new(context)
+        }
+    }
+
+    /// Start listening on a given address. Incoming connections are handled
+    /// in background threads.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = try_io!(TcpListener::bind(addr));
+
+        thread::spawn(move || self.accept_connections(listener));
+
+        Ok(())
+    }
+
+    /// Accept incoming connections.
+    fn accept_connections(mut self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Err(err) => log_warn!(self.logger, "relay error: {}", err),
+                Ok(stream) => {
+                    let active = self.context.active
+                        .fetch_add(1, Ordering::SeqCst);
+
+                    let guard = ConnectionGuard {
+                        context: self.context.clone()
+                    };
+
+                    if active >= MAX_RELAY_CONNECTIONS {
+                        log_warn!(self.logger, "relay connection refused (too many connections)");
+                        continue;
+                    }
+
+                    let mut logger = self.logger.clone();
+                    let context    = self.context.clone();
+
+                    thread::spawn(move || {
+                        let res = relay_connection(stream, &context);
+                        if let Err(err) = res {
+                            log_warn!(logger, "relay error: {}", err);
+                        }
+
+                        mem::drop(guard);
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Relay a given downstream connection.
+fn relay_connection(
+    mut downstream: TcpStream,
+    context: &RelayContext) -> Result<()> {
+    let (hello, rest) = try_arr!(read_hello(&mut downstream));
+
+    if !hello.verify(&context.secret) {
+        return Err(ArrowError::other(
+            "relay HELLO authentication failed"));
+    }
+
+    try_arr!(context.check_replay(&hello, clock::timestamp() as u64));
+
+    if !context.targets.iter().any(|target| target == hello.target()) {
+        return Err(ArrowError::other(format!(
+            "relay target {} is not allowed", hello.target())));
+    }
+
+    if hello.is_loop(&context.uuid) {
+        return Err(ArrowError::other(format!(
+            "relay loop detected (target: {})", hello.target())));
+    }
+
+    let mut upstream = match context.upstream {
+        Some(ref relay) => {
+            let hello = hello.forward(context.uuid)
+                .sign(&context.secret);
+
+            try_arr!(connect(&relay[..], &hello))
+        },
+        None => try_io!(connect_timeout(hello.target()))
+    };
+
+    try_io!(downstream.set_read_timeout(None));
+    try_io!(upstream.write_all(&rest));
+
+    let down_reader = try_io!(downstream.try_clone());
+    let up_writer   = try_io!(upstream.try_clone());
+
+    thread::spawn(move || pipe(down_reader, up_writer));
+
+    pipe(upstream, downstream);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+
+    use utils::Serialize;
+    use utils::secret::Secret;
+
+    #[test]
+    fn test_hello_serialization() {
+        let secret = Secret::new(b"secret".to_vec());
+
+        let hello = RelayHello::new([1u8; 16], "localhost:8900")
+            .forward([2u8; 16])
+            .sign(&secret);
+
+        let mut buffer = Vec::new();
+
+        hello.serialize(&mut buffer)
+            .unwrap();
+
+        assert_eq!(buffer.len(), hello.len());
+        assert_eq!(RelayHello::parse(&buffer).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_hello_authentication() {
+        let secret = Secret::new(b"secret".to_vec());
+        let other  = Secret::new(b"other".to_vec());
+
+        let hello = RelayHello::new([1u8; 16], "localhost:8900");
+
+        assert!(!hello.verify(&secret));
+
+        let hello = hello.sign(&secret);
+
+        assert!(hello.verify(&secret));
+        assert!(!hello.verify(&other));
+
+        // the tag covers the path, the timestamp, the nonce and the target
+        assert!(!hello.forward([2u8; 16]).verify(&secret));
+
+        let mut forged = hello.clone();
+
+        forged.target = "10.0.0.1:22".to_string();
+
+        assert!(!forged.verify(&secret));
+
+        let mut forged = hello.clone();
+
+        forged.timestamp += 1;
+
+        assert!(!forged.verify(&secret));
+
+        let mut forged = hello.clone();
+
+        forged.nonce[0] ^= 1;
+
+        assert!(!forged.verify(&secret));
+    }
+
+    #[test]
+    fn test_hello_replay() {
+        let secret = Secret::new(b"secret".to_vec());
+
+        let context = RelayContext {
+            uuid:     [2u8; 16],
+            upstream: None,
+            secret:   secret.clone(),
+            targets:  Vec::new(),
+            active:   AtomicUsize::new(0),
+            nonces:   Mutex::new(HashMap::new())
+        };
+
+        let hello = RelayHello::new([1u8; 16], "localhost:8900")
+            .sign(&secret);
+
+        let now = hello.timestamp;
+
+        assert!(context.check_replay(&hello, now).is_ok());
+
+        // the same message cannot be used twice
+        assert!(context.check_replay(&hello, now + 1).is_err());
+
+        // messages from a different time are refused
+        let mut other = RelayHello::new([1u8; 16], "localhost:8900");
+
+        other.timestamp = now;
+
+        assert!(context.check_replay(&other, now + HELLO_MAX_AGE + 1).is_err());
+        assert!(context.check_replay(&other, now - HELLO_MAX_AGE - 1).is_err());
+        assert!(context.check_replay(&other, now + HELLO_MAX_AGE).is_ok());
+
+        assert_eq!(context.nonces.lock().unwrap().len(), 2);
+
+        // expired nonces are forgotten
+        let mut later = RelayHello::new([1u8; 16], "localhost:8900");
+
+        later.timestamp = now + 2 * HELLO_MAX_AGE;
+
+        assert!(context.check_replay(&later, later.timestamp).is_ok());
+
+        assert_eq!(context.nonces.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let hello = RelayHello::new([1u8; 16], "localhost:8900");
+
+        assert!(hello.is_loop(&[1u8; 16]));
+        assert!(!hello.is_loop(&[2u8; 16]));
+
+        let mut hello = hello;
+
+        for i in 2..(MAX_RELAY_HOPS + 1) {
+            hello = hello.forward([i as u8; 16]);
+        }
+
+        assert!(hello.is_loop(&[0xffu8; 16]));
+    }
+}
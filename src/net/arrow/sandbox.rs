@@ -0,0 +1,278 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sandboxed service sessions.
+//!
+//! Sessions of risky service types (i.e. any-TCP sessions) can be handled by
+//! a separate helper process. The helper is a fresh instance of this
+//! executable (so it does not share any memory with the client), it runs
+//! with tight resource limits and without privileges and it connects to the
+//! target service on behalf of the client. The client and the helper
+//! communicate over a UNIX socket pair which is used by the client in place
+//! of the service connection.
+//!
+//! Note that the helper only relays bytes. It keeps the connection to the
+//! target (and the kernel state of the connection) out of the client
+//! process, but the session data are still processed by the client.
+
+use std::io;
+use std::env;
+use std::ptr;
+use std::thread;
+
+use std::ffi::CString;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+
+use mio;
+
+use libc;
+
+/// Name of the command used for starting the helper process.
+pub const HELPER_COMMAND: &'static str = "sandbox-helper";
+
+/// Address space limit of the helper process (in bytes).
+const HELPER_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Open file limit of the helper process.
+const HELPER_FILE_LIMIT: u64 = 16;
+
+/// CPU time limit of the helper process (in seconds).
+const HELPER_CPU_LIMIT: u64 = 3600;
+
+/// Stack size of the relay thread (in bytes).
+const RELAY_STACK_SIZE: usize = 64 * 1024;
+
+/// User the helper process switches to if it is started by root.
+const HELPER_USER: &'static str = "nobody";
+
+/// User and group ID used if the helper user does not exist.
+const NOBODY_ID: u32 = 65534;
+
+/// Start a new helper process for a given service address. The method
+/// returns the client end of the socket pair (as a non-blocking mio UNIX
+/// stream) and the helper process handle.
+pub fn spawn(addr: &SocketAddr) -> io::Result<(mio::unix::UnixStream, Child)> {
+    let (client, helper) = try!(socket_pair());
+
+    let exe = try!(env::current_exe());
+
+    // the helper end becomes stdin of the helper process, the client end
+    // is not inherited (close-on-exec)
+    let child = Command::new(exe)
+        .arg(HELPER_COMMAND)
+        .arg(format!("{}", addr))
+        .env_clear()
+        .stdin(unsafe { Stdio::from_raw_fd(helper) })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+
+    let child = match child {
+        Ok(child) => child,
+        Err(err)  => {
+            unsafe {
+                libc::close(client);
+            }
+
+            return Err(err);
+        }
+    };
+
+    let stream = unsafe {
+        mio::unix::UnixStream::from_raw_fd(client)
+    };
+
+    Ok((stream, child))
+}
+
+/// Helper process entry point. The method applies the resource limits,
+/// drops privileges, connects to a given service and relays data between the
+/// service and stdin (the helper end of the socket pair) until one of the
+/// connections is closed.
+pub fn run_helper(target: &str) -> io::Result<()> {
+    try!(set_limits());
+    try!(drop_privileges());
+
+    let client = unsafe {
+        UnixStream::from_raw_fd(0)
+    };
+
+    let service = try!(TcpStream::connect(target));
+
+    let mut client_reader  = try!(client.try_clone());
+    let mut service_writer = try!(service.try_clone());
+
+    let relay = thread::Builder::new()
+        .stack_size(RELAY_STACK_SIZE)
+        .spawn(move || {
+            let res = io::copy(&mut client_reader, &mut service_writer);
+            let _ = service_writer.shutdown(Shutdown::Write);
+            res
+        });
+
+    let relay = try!(relay);
+
+    let mut service_reader = service;
+    let mut client_writer  = client;
+
+    let res = io::copy(&mut service_reader, &mut client_writer);
+
+    let _ = client_writer.shutdown(Shutdown::Both);
+    let _ = service_reader.shutdown(Shutdown::Both);
+
+    try!(res);
+
+    match relay.join() {
+        Ok(res) => res.map(|_| ()),
+        Err(_)  => Err(io::Error::new(io::ErrorKind::Other,
+            "relay thread panicked"))
+    }
+}
+
+/// Terminate a given helper process.
+pub fn terminate(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Create a new UNIX socket pair. The first socket is non-blocking, both
+/// sockets are close-on-exec.
+fn socket_pair() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0 as libc::c_int; 2];
+
+    let ret = unsafe {
+        libc::socketpair(libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0, fds.as_mut_ptr())
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let flags = unsafe {
+        libc::fcntl(fds[0], libc::F_GETFL)
+    };
+
+    let ret = unsafe {
+        libc::fcntl(fds[0], libc::F_SETFL, flags | libc::O_NONBLOCK)
+    };
+
+    if flags < 0 || ret < 0 {
+        let err = io::Error::last_os_error();
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+
+        return Err(err);
+    }
+
+    Ok((fds[0], fds[1]))
+}
+
+/// Apply the helper process resource limits (both the soft and the hard
+/// ones).
+fn set_limits() -> io::Result<()> {
+    let limits = [
+        (libc::RLIMIT_AS,     HELPER_MEMORY_LIMIT),
+        (libc::RLIMIT_NOFILE, HELPER_FILE_LIMIT),
+        (libc::RLIMIT_CPU,    HELPER_CPU_LIMIT),
+        (libc::RLIMIT_CORE,   0)
+    ];
+
+    for &(resource, limit) in &limits {
+        let rlim = libc::rlimit {
+            rlim_cur: limit as libc::rlim_t,
+            rlim_max: limit as libc::rlim_t
+        };
+
+        let ret = unsafe {
+            libc::setrlimit(resource, &rlim)
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Prevent the helper process from gaining privileges and switch to the
+/// helper user if the process runs as root. Supplementary groups are
+/// dropped as well.
+fn drop_privileges() -> io::Result<()> {
+    let ret = unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0)
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let (uid, gid) = get_user_ids(HELPER_USER)
+        .unwrap_or((NOBODY_ID, NOBODY_ID));
+
+    let ret = unsafe {
+        if libc::setgroups(0, ptr::null()) < 0 {
+            -1
+        } else if libc::setgid(gid) < 0 {
+            -1
+        } else {
+            libc::setuid(uid)
+        }
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // make sure the privileges cannot be regained
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            "unable to drop privileges"));
+    }
+
+    Ok(())
+}
+
+/// Get user and group ID of a given user.
+fn get_user_ids(user: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let user = match CString::new(user) {
+        Ok(user) => user,
+        Err(_)   => return None
+    };
+
+    // note: the helper process is single-threaded at this point
+    let passwd = unsafe {
+        libc::getpwnam(user.as_ptr())
+    };
+
+    if passwd.is_null() {
+        None
+    } else {
+        unsafe {
+            Some(((*passwd).pw_uid, (*passwd).pw_gid))
+        }
+    }
+}
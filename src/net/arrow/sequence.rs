@@ -0,0 +1,224 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sequence-numbered session data frames.
+//!
+//! If both sides support the `FEATURE_DATA_SEQUENCE` Control Protocol
+//! feature, every session data message starts with a 32-bit big endian
+//! sequence number. Sequence numbers are counted separately for every session
+//! and direction (starting with zero). The receiving side uses them to
+//! estimate the number of frames lost or reordered by middleboxes between
+//! the client and Arrow Service.
+
+use std::io;
+
+use std::io::Write;
+
+use utils::endian;
+use utils::Serialize;
+use net::arrow::protocol::ArrowMessageBody;
+use net::arrow::error::{Result, ArrowError};
+
+/// Size of the sequence number preceding the frame data.
+pub const SEQUENCE_SIZE: usize = 4;
+
+/// Session data frame with a sequence number.
+pub struct SequencedFrame<'a> {
+    seq:  u32,
+    data: &'a [u8],
+}
+
+impl<'a> SequencedFrame<'a> {
+    /// Create a new frame with a given sequence number and data.
+    pub fn new(seq: u32, data: &'a [u8]) -> SequencedFrame<'a> {
+        SequencedFrame {
+            seq:  seq,
+            data: data
+        }
+    }
+
+    /// Split a given message body into the sequence number and frame data.
+    pub fn parse(body: &'a [u8]) -> Result<SequencedFrame<'a>> {
+        if body.len() < SEQUENCE_SIZE {
+            return Err(ArrowError::other("sequence number expected"));
+        }
+
+        let seq = endian::get_u32(body);
+
+        Ok(SequencedFrame::new(seq, &body[SEQUENCE_SIZE..]))
+    }
+
+    /// Get the sequence number.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Get the frame data.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl<'a> Serialize for SequencedFrame<'a> {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.seq.serialize(w));
+
+        w.write_all(self.data)
+    }
+}
+
+impl<'a> ArrowMessageBody for SequencedFrame<'a> {
+    fn len(&self) -> usize {
+        SEQUENCE_SIZE + self.data.len()
+    }
+}
+
+/// Loss and reordering statistics of received frames.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SequenceStats {
+    /// Number of received frames.
+    pub received:  u64,
+    /// Number of frames which are missing (gaps in the sequence).
+    pub lost:      u64,
+    /// Number of frames received out of order.
+    pub reordered: u64,
+}
+
+impl SequenceStats {
+    /// Create new empty statistics.
+    pub fn new() -> SequenceStats {
+        SequenceStats {
+            received:  0,
+            lost:      0,
+            reordered: 0
+        }
+    }
+
+    /// Add given statistics to these ones.
+    pub fn add(&mut self, other: &SequenceStats) {
+        self.received  += other.received;
+        self.lost      += other.lost;
+        self.reordered += other.reordered;
+    }
+
+    /// Get the estimated loss ratio (zero if there are no frames).
+    pub fn loss_ratio(&self) -> f64 {
+        let total = self.received + self.lost;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f64 / total as f64
+        }
+    }
+}
+
+/// Receive-side sequence tracker of a single session.
+#[derive(Debug, Clone)]
+pub struct SequenceTracker {
+    /// Next expected sequence number.
+    expected: u32,
+    /// Statistics.
+    stats:    SequenceStats,
+}
+
+impl SequenceTracker {
+    /// Create a new tracker expecting sequence number zero.
+    pub fn new() -> SequenceTracker {
+        SequenceTracker {
+            expected: 0,
+            stats:    SequenceStats::new()
+        }
+    }
+
+    /// Record a received frame with a given sequence number. Frames ahead
+    /// of the expected sequence number mark the skipped ones as lost; a late
+    /// frame is counted as reordered and it is no longer considered lost.
+    pub fn record(&mut self, seq: u32) {
+        let delta = seq.wrapping_sub(self.expected);
+
+        self.stats.received += 1;
+
+        if delta < 0x80000000 {
+            self.stats.lost += delta as u64;
+            self.expected    = seq.wrapping_add(1);
+        } else {
+            self.stats.reordered += 1;
+
+            if self.stats.lost > 0 {
+                self.stats.lost -= 1;
+            }
+        }
+    }
+
+    /// Get statistics.
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::utils::WriteBuffer;
+
+    #[test]
+    fn test_sequenced_frame() {
+        let frame = SequencedFrame::new(0x01020304, &[0xab, 0xcd]);
+
+        let mut buf = WriteBuffer::new(0);
+
+        frame.serialize(&mut buf)
+            .unwrap();
+
+        assert_eq!(buf.as_bytes(), &[1, 2, 3, 4, 0xab, 0xcd]);
+
+        let frame = SequencedFrame::parse(buf.as_bytes())
+            .unwrap();
+
+        assert_eq!(frame.seq(), 0x01020304);
+        assert_eq!(frame.data(), &[0xab, 0xcd]);
+
+        assert!(SequencedFrame::parse(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut tracker = SequenceTracker::new();
+
+        tracker.record(0);
+        tracker.record(1);
+        tracker.record(4);
+        tracker.record(2);
+        tracker.record(5);
+
+        let stats = tracker.stats();
+
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.loss_ratio(), 1.0 / 6.0);
+
+        let mut tracker = SequenceTracker::new();
+
+        tracker.expected = 0xffffffff;
+        tracker.record(0xffffffff);
+        tracker.record(0);
+
+        assert_eq!(tracker.stats().lost, 0);
+        assert_eq!(tracker.stats().reordered, 0);
+    }
+}
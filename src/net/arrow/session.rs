@@ -0,0 +1,1543 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service session definitions.
+
+use std::io;
+use std::cmp;
+use std::mem;
+
+use std::collections::VecDeque;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "sandbox")]
+use std::process::Child;
+use std::io::{Read, Write, ErrorKind};
+
+use net::utils::{Timeout, WriteBuffer};
+#[cfg(feature = "socks")]
+use net::utils::into_mio_stream;
+#[cfg(feature = "socks")]
+use net::socks::SocksProxy;
+#[cfg(feature = "conntrack")]
+use net::conntrack;
+#[cfg(feature = "conntrack")]
+use net::conntrack::{ConnectionTuple, Protocol};
+
+use utils::clock;
+use utils::logger::Logger;
+use utils::config::{SESSION_WRITE_TIMEOUT, SESSION_BUFFER_SIZE};
+
+use net::arrow::{register_socket, reregister_socket, deregister_socket};
+use net::arrow::uplink::Uplink;
+use net::arrow::overload::{OverloadPolicy, FrameTracker};
+use net::arrow::sequence::{SequencedFrame, SequenceTracker, SequenceStats};
+use net::arrow::timestamp::{TimestampedFrame, ReadTimes};
+#[cfg(feature = "recording")]
+use net::arrow::tap::SessionTap;
+#[cfg(feature = "e2e")]
+use net::arrow::e2e::{E2eKey, E2eSession};
+use net::arrow::occupancy::OccupancyAlarm;
+#[cfg(feature = "sandbox")]
+use net::arrow::sandbox;
+#[cfg(feature = "datagram")]
+use net::arrow::datagram::DatagramSocket;
+use net::arrow::protocol::ArrowMessage;
+use net::arrow::error::{Result, ArrowError};
+
+use mio::tcp::TcpStream;
+#[cfg(feature = "sandbox")]
+use mio::unix::UnixStream;
+use mio::{EventLoop, EventSet, Evented, Handler};
+
+use time;
+
+/// Service socket.
+enum ServiceSocket {
+    /// TCP stream.
+    Tcp(TcpStream),
+    /// UDP socket exchanging length-prefixed datagrams.
+    #[cfg(feature = "datagram")]
+    Udp(DatagramSocket),
+    /// UNIX stream connected to a sandbox helper process.
+    #[cfg(feature = "sandbox")]
+    Unix(UnixStream),
+}
+
+/// Service socket abstraction for ignoring EWOULDBLOCKs.
+struct ServiceStream {
+    /// Service socket.
+    socket: ServiceSocket,
+    /// Sandbox helper process (if the session is sandboxed).
+    #[cfg(feature = "sandbox")]
+    helper: Option<Child>,
+}
+
+/// Connection options of a new service session.
+#[derive(Default)]
+pub struct SessionOptions {
+    /// Connect using a sandbox helper process.
+    pub sandboxed: bool,
+    /// Exchange UDP datagrams with the service.
+    pub datagram:  bool,
+    /// SOCKS5 proxy for the service connection (if any).
+    #[cfg(feature = "socks")]
+    pub proxy:     Option<SocksProxy>,
+    /// End-to-end encryption key of the session payload (if any).
+    #[cfg(feature = "e2e")]
+    pub e2e_key:   Option<E2eKey>,
+}
+
+impl ServiceStream {
+    /// Open a service connection to a given socket address using given
+    /// options. The connection has to be established within a given time (in
+    /// milliseconds).
+    fn open(
+        addr: &SocketAddr,
+        options: &SessionOptions,
+        timeout: u64) -> io::Result<ServiceStream> {
+        if options.datagram {
+            return ServiceStream::datagram(addr);
+        } else if options.sandboxed {
+            return ServiceStream::sandboxed(addr);
+        }
+
+        ServiceStream::connect_via_proxy(addr, options, timeout)
+    }
+
+    /// Connect to a given TCP socket address through the SOCKS5 proxy from
+    /// given options (if any).
+    #[cfg(feature = "socks")]
+    fn connect_via_proxy(
+        addr: &SocketAddr,
+        options: &SessionOptions,
+        timeout: u64) -> io::Result<ServiceStream> {
+        match options.proxy {
+            Some(ref proxy) => ServiceStream::via_socks(proxy, addr, timeout),
+            None => ServiceStream::connect(addr)
+        }
+    }
+
+    /// Connect to a given TCP socket address (dummy).
+    #[cfg(not(feature = "socks"))]
+    fn connect_via_proxy(
+        addr: &SocketAddr,
+        _: &SessionOptions,
+        _: u64) -> io::Result<ServiceStream> {
+        ServiceStream::connect(addr)
+    }
+
+    /// Connect to a given TCP socket address.
+    fn connect(addr: &SocketAddr) -> io::Result<ServiceStream> {
+        let stream = try!(TcpStream::connect(addr));
+        let res    = ServiceStream {
+            socket: ServiceSocket::Tcp(stream),
+            #[cfg(feature = "sandbox")]
+            helper: None
+        };
+
+        Ok(res)
+    }
+
+    /// Create a datagram socket for a given UDP service address.
+    #[cfg(feature = "datagram")]
+    fn datagram(addr: &SocketAddr) -> io::Result<ServiceStream> {
+        let socket = try!(DatagramSocket::connect(addr));
+        let res    = ServiceStream {
+            socket: ServiceSocket::Udp(socket),
+            #[cfg(feature = "sandbox")]
+            helper: None
+        };
+
+        Ok(res)
+    }
+
+    /// Create a datagram socket for a given UDP service address (dummy).
+    #[cfg(not(feature = "datagram"))]
+    fn datagram(_: &SocketAddr) -> io::Result<ServiceStream> {
+        Err(io::Error::new(io::ErrorKind::Other,
+            "datagram sessions are not supported"))
+    }
+
+    /// Connect to a given TCP socket address through a given SOCKS5 proxy.
+    /// The proxy handshake has to be finished within a given time (in
+    /// milliseconds).
+    #[cfg(feature = "socks")]
+    fn via_socks(
+        proxy: &SocksProxy,
+        addr: &SocketAddr,
+        timeout: u64) -> io::Result<ServiceStream> {
+        let stream = try!(proxy.connect(&addr.to_string(), timeout));
+        let res    = ServiceStream {
+            socket: ServiceSocket::Tcp(try!(into_mio_stream(stream))),
+            #[cfg(feature = "sandbox")]
+            helper: None
+        };
+
+        Ok(res)
+    }
+
+    /// Connect to a given TCP socket address using a sandbox helper
+    /// process.
+    #[cfg(feature = "sandbox")]
+    fn sandboxed(addr: &SocketAddr) -> io::Result<ServiceStream> {
+        let (stream, helper) = try!(sandbox::spawn(addr));
+        let res = ServiceStream {
+            socket: ServiceSocket::Unix(stream),
+            helper: Some(helper)
+        };
+
+        Ok(res)
+    }
+
+    /// Connect to a given TCP socket address using a sandbox helper process
+    /// (dummy).
+    #[cfg(not(feature = "sandbox"))]
+    fn sandboxed(_: &SocketAddr) -> io::Result<ServiceStream> {
+        Err(io::Error::new(io::ErrorKind::Other,
+            "sandboxed sessions are not supported"))
+    }
+
+    /// Get reference to the underlaying socket.
+    fn get_ref(&self) -> &Evented {
+        match self.socket {
+            ServiceSocket::Tcp(ref stream) => stream,
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(ref socket) => socket.get_ref(),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(ref stream) => stream
+        }
+    }
+
+    /// Get the connection tracking tuple of the underlaying socket. None is
+    /// returned for sandboxed sessions (the connection belongs to the helper
+    /// process) or if the addresses are not known.
+    #[cfg(feature = "conntrack")]
+    fn connection_tuple(&self) -> Option<ConnectionTuple> {
+        let (protocol, src, dst) = match self.socket {
+            ServiceSocket::Tcp(ref stream) =>
+                (Protocol::Tcp, stream.local_addr(), stream.peer_addr()),
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(ref socket) =>
+                (Protocol::Udp, socket.get_ref().local_addr(),
+                    Ok(*socket.peer_addr())),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(_) => return None
+        };
+
+        match (src, dst) {
+            (Ok(src), Ok(dst)) => Some(ConnectionTuple {
+                protocol: protocol,
+                src:      src,
+                dst:      dst
+            }),
+            _ => None
+        }
+    }
+
+    /// Take error from the underlaying socket (there are no pending errors
+    /// on UDP sockets and sandbox helper streams).
+    fn take_socket_error(&self) -> io::Result<()> {
+        match self.socket {
+            ServiceSocket::Tcp(ref stream) => stream.take_socket_error(),
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(_)          => Ok(()),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(_)         => Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "sandbox")]
+impl Drop for ServiceStream {
+    /// Terminate the sandbox helper process (if any).
+    fn drop(&mut self) {
+        if let Some(ref mut helper) = self.helper {
+            sandbox::terminate(helper);
+        }
+    }
+}
+
+impl Read for ServiceStream {
+    /// Read data from the underlaying socket (EWOULDBLOCK is silently
+    /// ignored).
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = match self.socket {
+            ServiceSocket::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(ref mut socket) => socket.read(buf),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(ref mut stream) => stream.read(buf)
+        };
+
+        match res {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+            other => other
+        }
+    }
+}
+
+impl Write for ServiceStream {
+    /// Write data into the underlaying socket (EWOULDBLOCK is silently
+    /// ignored).
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = match self.socket {
+            ServiceSocket::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(ref mut socket) => socket.write(buf),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(ref mut stream) => stream.write(buf)
+        };
+
+        match res {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+            other => other
+        }
+    }
+
+    /// Flush buffered data into the underlaying socket (EWOULDBLOCK is not
+    /// ignored in this case).
+    fn flush(&mut self) -> io::Result<()> {
+        match self.socket {
+            ServiceSocket::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(feature = "datagram")]
+            ServiceSocket::Udp(ref mut socket) => socket.flush(),
+            #[cfg(feature = "sandbox")]
+            ServiceSocket::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+/// External service session context.
+///
+/// This struct holds connection to an external service (e.g. RTSP) and
+/// its I/O buffers.
+pub struct SessionContext<L: Logger> {
+    /// Logger.
+    #[cfg_attr(not(feature = "recording"), allow(dead_code))]
+    logger:        L,
+    /// Service ID.
+    service_id:    u16,
+    /// Session ID.
+    session_id:    u32,
+    /// Service stream.
+    stream:        ServiceStream,
+    /// Input buffer.
+    input_buffer:  WriteBuffer,
+    /// Output buffer.
+    output_buffer: WriteBuffer,
+    /// Read buffer.
+    read_buffer:   Box<[u8]>,
+    /// Write timeout.
+    write_tout:    Timeout,
+    /// Write time limit (in milliseconds).
+    write_timeout: u64,
+    /// Connection establishment timeout.
+    connect_tout:  Timeout,
+    /// Connection establishment indicator.
+    connected:     bool,
+    /// Time when the connection was established (in nanoseconds).
+    connect_time:  u64,
+    /// Overload policy.
+    policy:        OverloadPolicy,
+    /// Frame tracker (used only with the DropOldest policy).
+    frames:        Option<FrameTracker>,
+    /// Number of dropped frames (since the last check).
+    dropped_frames: usize,
+    /// Number of dropped bytes (since the last check).
+    dropped_bytes:  usize,
+    /// Number of bytes received from the service.
+    bytes_in:      u64,
+    /// Number of bytes sent to the service.
+    bytes_out:     u64,
+    /// Sequence number of the next data frame sent to Arrow Service.
+    tx_seq:        u32,
+    /// Sequence tracker of data frames received from Arrow Service.
+    rx_seq:        SequenceTracker,
+    /// Read times of the buffered input data.
+    read_times:    ReadTimes,
+    /// Socket events suspended.
+    suspended:     bool,
+    /// Payload tap (if attached).
+    #[cfg(feature = "recording")]
+    tap:           Option<SessionTap>,
+    /// The tap could not be written.
+    #[cfg(feature = "recording")]
+    tap_failed:    bool,
+    /// End-to-end encryption state (if enabled).
+    #[cfg(feature = "e2e")]
+    e2e:           Option<E2eSession>,
+    /// Input buffer occupancy alarm.
+    input_alarm:   OccupancyAlarm,
+}
+
+impl<L: Logger> SessionContext<L> {
+    /// Create a new session context for a given session ID and service
+    /// address. The connection has to be established within a given time
+    /// and pending data have to be written within a given time (both in
+    /// milliseconds). A given overload policy is applied when the input
+    /// buffer (of a given capacity) is full. The service connection is
+    /// opened using given options (see `SessionOptions`).
+    fn new<T: Handler>(
+        logger:     L,
+        service_id: u16,
+        session_id: u32,
+        addr: &SocketAddr,
+        options: &SessionOptions,
+        connect_timeout: u64,
+        write_timeout: u64,
+        buffer_size: usize,
+        policy: OverloadPolicy,
+        event_loop: &mut EventLoop<T>) -> Result<SessionContext<L>> {
+        let stream = try_svc_io!(ServiceStream::open(addr, options,
+            connect_timeout));
+
+        register_socket(session2token(session_id), stream.get_ref(),
+            true, true, event_loop);
+
+        let mut connect_tout = Timeout::new();
+
+        connect_tout.set(connect_timeout);
+
+        let frames = match policy {
+            OverloadPolicy::Block      => None,
+            OverloadPolicy::DropOldest => Some(FrameTracker::new())
+        };
+
+        let res = SessionContext {
+            logger:        logger,
+            service_id:    service_id,
+            session_id:    session_id,
+            stream:        stream,
+            input_buffer:  WriteBuffer::new(buffer_size),
+            output_buffer: WriteBuffer::new(0),
+            read_buffer:   Box::new([0u8; 32768]),
+            write_tout:    Timeout::new(),
+            write_timeout: write_timeout,
+            connect_tout:  connect_tout,
+            connected:     false,
+            connect_time:  0,
+            policy:        policy,
+            frames:        frames,
+            dropped_frames: 0,
+            dropped_bytes:  0,
+            bytes_in:      0,
+            bytes_out:     0,
+            tx_seq:        0,
+            rx_seq:        SequenceTracker::new(),
+            read_times:    ReadTimes::new(),
+            suspended:     false,
+            #[cfg(feature = "recording")]
+            tap:           None,
+            #[cfg(feature = "recording")]
+            tap_failed:    false,
+            #[cfg(feature = "e2e")]
+            e2e:           options.e2e_key.as_ref()
+                .map(|key| E2eSession::new(key)),
+            input_alarm:   OccupancyAlarm::new()
+        };
+
+        Ok(res)
+    }
+
+    /// Dispose resources held by this object.
+    fn dispose<T: Handler>(&self, event_loop: &mut EventLoop<T>) {
+        if !self.suspended {
+            deregister_socket(self.stream.get_ref(), event_loop);
+        }
+    }
+
+    /// Temporarily stop receiving events for the underlaying socket.
+    pub fn suspend<T: Handler>(&mut self, event_loop: &mut EventLoop<T>) {
+        if !self.suspended {
+            deregister_socket(self.stream.get_ref(), event_loop);
+            self.suspended = true;
+        }
+    }
+
+    /// Start receiving events for the underlaying socket again.
+    pub fn resume<T: Handler>(&mut self, event_loop: &mut EventLoop<T>) {
+        if self.suspended {
+            register_socket(session2token(self.session_id),
+                self.stream.get_ref(),
+                !self.is_overloaded(), !self.output_buffer.is_empty(),
+                event_loop);
+            self.suspended = false;
+        }
+    }
+
+    /// Enable/disable notifications for the underlaying socket.
+    fn update_socket_events<T: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<T>) {
+        if self.suspended {
+            return;
+        }
+
+        let readable = !self.is_overloaded();
+        let writable = !self.output_buffer.is_empty();
+        reregister_socket(
+            session2token(self.session_id),
+            self.stream.get_ref(),
+            readable, writable, event_loop);
+    }
+
+    /// Process a given set of socket events and return size of the input
+    /// buffer or None in case the connection has been closed.
+    pub fn socket_ready<T: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<T>,
+        event_set: EventSet) -> Result<Option<usize>> {
+        // the socket becomes readable or writable once the connection is
+        // established
+        if !self.connected && !event_set.is_error()
+            && (event_set.is_readable() || event_set.is_writable()) {
+            self.connected    = true;
+            self.connect_time = time::precise_time_ns();
+            self.connect_tout.clear();
+        }
+
+        let read = try_arr!(self.check_read_event(event_loop, event_set));
+
+        try_arr!(self.check_write_event(event_loop, event_set));
+
+        if event_set.is_error() {
+            let err = self.get_socket_error()
+                .ok_or(ArrowError::other("socket error expected"));
+            Err(try_arr!(err))
+        } else if event_set.is_hup() && read == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(self.input_buffer.buffered()))
+        }
+    }
+
+    /// Check if reading from the service should be suspended.
+    fn is_overloaded(&self) -> bool {
+        match self.policy {
+            OverloadPolicy::Block      => self.input_buffer.is_full(),
+            OverloadPolicy::DropOldest => self.input_buffer.buffered()
+                >= 2 * self.input_buffer.capacity()
+        }
+    }
+
+    /// Read a message if the underlaying socket is readable and the input
+    /// buffer is not already full. Return the number of bytes read.
+    fn check_read_event<T: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<T>,
+        event_set: EventSet) -> Result<usize> {
+        if event_set.is_readable() {
+            if !self.is_overloaded() || event_set.is_hup() {
+                let len = try_svc_io!(self.stream.read(&mut *self.read_buffer));
+
+                self.input_buffer.write_all(&self.read_buffer[..len])
+                    .unwrap();
+
+                self.bytes_in += len as u64;
+
+                self.read_times.push(len, clock::timestamp_ms());
+
+                if len > 0 {
+                    self.tap_from_service(len);
+                }
+
+                if let Some(ref mut frames) = self.frames {
+                    frames.push(&self.read_buffer[..len]);
+                }
+
+                self.drop_oldest_frames();
+
+                return Ok(len);
+            } else {
+                self.update_socket_events(event_loop);
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Drop the oldest complete media frames from the input buffer if the
+    /// buffer is full (applicable only for the DropOldest policy).
+    fn drop_oldest_frames(&mut self) {
+        let buffered = self.input_buffer.buffered();
+        let capacity = self.input_buffer.capacity();
+
+        if buffered <= capacity {
+            return;
+        }
+
+        let dropped = match self.frames {
+            Some(ref mut frames) => frames.drop_oldest(
+                buffered - capacity),
+            None => return
+        };
+
+        if dropped.ranges.is_empty() {
+            return;
+        }
+
+        // remove the last ranges first, so that the offsets stay valid
+        for &(start, len) in dropped.ranges.iter().rev() {
+            self.read_times.remove(start, len);
+        }
+
+        self.input_buffer.remove(&dropped.ranges);
+
+        self.dropped_frames += dropped.frames;
+        self.dropped_bytes  += dropped.bytes;
+    }
+
+    /// Write data from the output buffer into the underlaying socket if the
+    /// socket is writable.
+    fn check_write_event<T: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<T>,
+        event_set: EventSet) -> Result<()> {
+        if event_set.is_writable() {
+            if self.output_buffer.is_empty() {
+                self.update_socket_events(event_loop);
+                self.write_tout.clear();
+            } else {
+                let len = try_svc_io!(self.stream.write(
+                    self.output_buffer.as_bytes()));
+
+                if len > 0 {
+                    self.bytes_out += len as u64;
+                    self.tap_to_service(len);
+                    self.output_buffer.drop(len);
+                    self.write_tout.set(self.write_timeout);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a given number of bytes from the read buffer into the tap (if
+    /// any).
+    #[cfg(feature = "recording")]
+    fn tap_from_service(&mut self, len: usize) {
+        if self.tap_failed {
+            return;
+        }
+
+        let res = match self.tap {
+            Some(ref mut tap) => tap.from_service(&self.read_buffer[..len]),
+            None => return
+        };
+
+        self.check_tap_result(res);
+    }
+
+    /// Record a given number of bytes from the read buffer into the tap
+    /// (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn tap_from_service(&mut self, _: usize) {
+    }
+
+    /// Record a given number of bytes from the output buffer into the tap
+    /// (if any).
+    #[cfg(feature = "recording")]
+    fn tap_to_service(&mut self, len: usize) {
+        if self.tap_failed {
+            return;
+        }
+
+        let res = match self.tap {
+            Some(ref mut tap) => tap.to_service(&self.output_buffer.as_bytes()[..len]),
+            None => return
+        };
+
+        self.check_tap_result(res);
+    }
+
+    /// Record a given number of bytes from the output buffer into the tap
+    /// (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn tap_to_service(&mut self, _: usize) {
+    }
+
+    /// Stop writing into the tap if it could not be written.
+    #[cfg(feature = "recording")]
+    fn check_tap_result(&mut self, res: io::Result<()>) {
+        if let Err(err) = res {
+            log_warn!(self.logger, "unable to write session tap (session ID: {:08x}): {}", self.session_id, err);
+            self.tap_failed = true;
+        }
+    }
+
+    /// Attach a given payload tap (any previous tap is replaced).
+    #[cfg(feature = "recording")]
+    pub fn attach_tap(&mut self, tap: SessionTap) {
+        self.tap        = Some(tap);
+        self.tap_failed = false;
+    }
+
+    /// Detach the payload tap (if any).
+    #[cfg(feature = "recording")]
+    pub fn detach_tap(&mut self) -> Option<SessionTap> {
+        self.tap_failed = false;
+        self.tap.take()
+    }
+
+    /// Get the payload tap (if any).
+    #[cfg(feature = "recording")]
+    pub fn tap(&self) -> Option<&SessionTap> {
+        self.tap.as_ref()
+    }
+
+    /// Check if the payload tap is full or if it could not be written.
+    #[cfg(feature = "recording")]
+    fn tap_finished(&self) -> bool {
+        match self.tap {
+            Some(ref tap) => self.tap_failed || tap.is_full(),
+            None => false
+        }
+    }
+
+    /// Get socket error.
+    fn get_socket_error(&self) -> Option<ArrowError> {
+        let err = self.stream.take_socket_error();
+        match err.err() {
+            Some(err) => Some(ArrowError::service_connection_error(err)),
+            None      => None
+        }
+    }
+
+    /// Check if the write timeout has not expired yet.
+    pub fn check_timeout(&self) -> bool {
+        self.write_tout.check()
+    }
+
+    /// Check if the connection has been established or if the connection
+    /// timeout has not expired yet.
+    pub fn check_connect_timeout(&self) -> bool {
+        self.connected || self.connect_tout.check()
+    }
+
+    /// Check if the connection has been established.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Get service ID.
+    pub fn service_id(&self) -> u16 {
+        self.service_id
+    }
+
+    /// Get time elapsed since the connection was established (in
+    /// milliseconds) or None if the connection has not been established.
+    pub fn lifetime(&self) -> Option<u64> {
+        if self.connected {
+            Some((time::precise_time_ns() - self.connect_time) / 1000000)
+        } else {
+            None
+        }
+    }
+
+    /// Get the connection tracking tuple of the service connection (if
+    /// known).
+    #[cfg(feature = "conntrack")]
+    pub fn connection_tuple(&self) -> Option<ConnectionTuple> {
+        self.stream.connection_tuple()
+    }
+
+    /// Get the number of bytes received from and sent to the service.
+    pub fn transferred(&self) -> (u64, u64) {
+        (self.bytes_in, self.bytes_out)
+    }
+
+    /// Record sequence number of a data frame received from Arrow Service.
+    pub fn record_sequence(&mut self, seq: u32) {
+        self.rx_seq.record(seq);
+    }
+
+    /// Get loss and reordering statistics of data frames received from
+    /// Arrow Service.
+    pub fn sequence_stats(&self) -> SequenceStats {
+        self.rx_seq.stats()
+    }
+
+    /// Get high-water marks of the input and output buffers.
+    pub fn high_water(&self) -> (usize, usize) {
+        (self.input_buffer.high_water(), self.output_buffer.high_water())
+    }
+
+    /// Sample occupancy of the input buffer. The time (in seconds) the
+    /// buffer has been almost full is returned together with the number of
+    /// buffered bytes and the buffer capacity once the time exceeds a given
+    /// threshold (in milliseconds).
+    pub fn check_occupancy(&mut self, threshold: u64) -> Option<(f64, usize, usize)> {
+        let buffered = self.input_buffer.buffered();
+        let capacity = self.input_buffer.capacity();
+
+        self.input_alarm.check(buffered, capacity, threshold)
+            .map(|duration| (duration, buffered, capacity))
+    }
+
+    /// Take the number of frames and bytes dropped since the last call.
+    pub fn take_dropped(&mut self) -> (usize, usize) {
+        let res = (self.dropped_frames, self.dropped_bytes);
+
+        self.dropped_frames = 0;
+        self.dropped_bytes  = 0;
+
+        res
+    }
+
+    /// Check if there are some data to be sent to Arrow Service, i.e. the
+    /// end-to-end encryption header or some data in the input buffer.
+    /// Encrypted data cannot be sent until the end-to-end encryption session
+    /// is established.
+    #[cfg(feature = "e2e")]
+    pub fn input_ready(&self) -> bool {
+        match self.e2e {
+            Some(ref e2e) => e2e.has_header()
+                || (e2e.is_established() && !self.input_buffer.is_empty()),
+            None => !self.input_buffer.is_empty()
+        }
+    }
+
+    /// Check if there are some data to be sent to Arrow Service, i.e. some
+    /// data in the input buffer (dummy).
+    #[cfg(not(feature = "e2e"))]
+    pub fn input_ready(&self) -> bool {
+        !self.input_buffer.is_empty()
+    }
+
+    /// Check if input data cannot be sent yet because the end-to-end
+    /// encryption session is not established.
+    #[cfg(feature = "e2e")]
+    fn input_blocked(&self) -> bool {
+        self.e2e.as_ref()
+            .map_or(false, |e2e| !e2e.is_established())
+    }
+
+    /// Check if input data cannot be sent yet (dummy).
+    #[cfg(not(feature = "e2e"))]
+    fn input_blocked(&self) -> bool {
+        false
+    }
+
+    /// Put at most a given number of bytes from the input buffer into the
+    /// uplink output buffer (as a single data frame). The data are end-to-end
+    /// encrypted if enabled. The frame is prefixed with a timestamp record
+    /// and/or a sequence number if requested. Return the number of bytes
+    /// taken from the input buffer.
+    fn buffer_input(
+        &mut self,
+        max_len: usize,
+        sequenced: bool,
+        timestamped: bool,
+        uplink: &mut Uplink) -> usize {
+        let now = clock::timestamp_ms();
+
+        let read_time = self.read_times.oldest()
+            .unwrap_or(now);
+
+        let delay = cmp::min(now - read_time, u32::max_value() as i64);
+        let delay = cmp::max(0, delay) as u32;
+
+        let len = {
+            let data = self.input_buffer.as_bytes();
+            let len  = if self.input_blocked() {
+                0
+            } else {
+                cmp::min(max_len, data.len())
+            };
+
+            let data = &data[..len];
+
+            #[cfg(feature = "e2e")]
+            let sealed;
+
+            #[cfg(feature = "e2e")]
+            let data = match self.e2e {
+                Some(ref mut e2e) => {
+                    sealed = e2e.seal(data);
+                    &sealed[..]
+                },
+                None => data
+            };
+
+            let seq = if sequenced {
+                Some(self.tx_seq)
+            } else {
+                None
+            };
+
+            let timestamp = if timestamped {
+                Some((read_time, delay))
+            } else {
+                None
+            };
+
+            buffer_frame(self.service_id, self.session_id, seq, timestamp,
+                data, uplink);
+
+            len
+        };
+
+        if sequenced {
+            self.tx_seq = self.tx_seq.wrapping_add(1);
+        }
+
+        len
+    }
+
+    /// Put the final end-to-end encryption record into the uplink output
+    /// buffer (if the end-to-end encryption is enabled). The record
+    /// authenticates the end of the stream, so it should be sent only if
+    /// the service connection has been closed without an error and all
+    /// input data have been sent.
+    #[cfg(feature = "e2e")]
+    fn buffer_e2e_finish(
+        &mut self,
+        sequenced: bool,
+        timestamped: bool,
+        uplink: &mut Uplink) {
+        let data = match self.e2e {
+            Some(ref mut e2e) => e2e.finish(),
+            None => return
+        };
+
+        if data.is_empty() {
+            return;
+        }
+
+        let seq = if sequenced {
+            Some(self.tx_seq)
+        } else {
+            None
+        };
+
+        let timestamp = if timestamped {
+            Some((clock::timestamp_ms(), 0))
+        } else {
+            None
+        };
+
+        buffer_frame(self.service_id, self.session_id, seq, timestamp,
+            &data, uplink);
+
+        if sequenced {
+            self.tx_seq = self.tx_seq.wrapping_add(1);
+        }
+    }
+
+    /// Check if the end-to-end encrypted stream from the consumer has not
+    /// been finished by the final record (i.e. it might have been
+    /// truncated). Always false if the end-to-end encryption is disabled.
+    #[cfg(feature = "e2e")]
+    pub fn is_e2e_truncated(&self) -> bool {
+        self.e2e.as_ref()
+            .map_or(false, |e2e| !e2e.is_closed())
+    }
+
+    /// Put the final end-to-end encryption record into the uplink output
+    /// buffer (dummy).
+    #[cfg(not(feature = "e2e"))]
+    fn buffer_e2e_finish(&mut self, _: bool, _: bool, _: &mut Uplink) {
+    }
+
+    /// Check if the end-to-end encrypted stream from the consumer might
+    /// have been truncated (dummy).
+    #[cfg(not(feature = "e2e"))]
+    pub fn is_e2e_truncated(&self) -> bool {
+        false
+    }
+
+    /// Drop a given number of bytes from the input buffer.
+    fn drop_input_bytes<T: Handler>(
+        &mut self,
+        count: usize,
+        event_loop: &mut EventLoop<T>) {
+        let was_overloaded = self.is_overloaded();
+
+        self.input_buffer.drop(count);
+        self.read_times.consume(count);
+
+        if let Some(ref mut frames) = self.frames {
+            frames.consume(count);
+        }
+
+        if was_overloaded && !self.is_overloaded() {
+            self.update_socket_events(event_loop);
+        }
+    }
+
+    /// Send a given message. The message is decrypted first if end-to-end
+    /// encryption is enabled.
+    pub fn send_message<T: Handler>(
+        &mut self,
+        data: &[u8],
+        event_loop: &mut EventLoop<T>) -> Result<()> {
+        let was_empty = self.output_buffer.is_empty();
+
+        #[cfg(feature = "e2e")]
+        let opened;
+
+        #[cfg(feature = "e2e")]
+        let data = match self.e2e {
+            Some(ref mut e2e) => {
+                opened = try!(e2e.open(data)
+                    .map_err(|err| ArrowError::other(format!("{}", err))));
+
+                &opened[..]
+            },
+            None => data
+        };
+
+        self.output_buffer.write_all(data)
+            .unwrap();
+
+        if was_empty && !self.output_buffer.is_empty() {
+            self.write_tout.set(self.write_timeout);
+            self.update_socket_events(event_loop);
+        }
+
+        Ok(())
+    }
+}
+
+/// Put a given session data frame into the uplink output buffer. The frame
+/// is prefixed with a given sequence number and/or timestamp (read time and
+/// delay) if given.
+fn buffer_frame(
+    service_id: u16,
+    session_id: u32,
+    seq: Option<u32>,
+    timestamp: Option<(i64, u32)>,
+    data: &[u8],
+    uplink: &mut Uplink) {
+    match (seq, timestamp) {
+        (None, None) => uplink.buffer_message(&ArrowMessage::new(
+            service_id, session_id,
+            data)),
+        (Some(seq), None) => uplink.buffer_message(&ArrowMessage::new(
+            service_id, session_id,
+            SequencedFrame::new(seq, data))),
+        (None, Some((read_time, delay))) => uplink.buffer_message(&ArrowMessage::new(
+            service_id, session_id,
+            TimestampedFrame::new(read_time, delay, data))),
+        (Some(seq), Some((read_time, delay))) => uplink.buffer_message(&ArrowMessage::new(
+            service_id, session_id,
+            TimestampedFrame::new(read_time, delay,
+                SequencedFrame::new(seq, data)))),
+    }
+}
+
+/// Convert a given session ID into a token (socket) ID.
+pub fn session2token(session_id: u32) -> usize {
+    assert!(mem::size_of::<usize>() >= 4);
+    (session_id as usize) | (1 << 24)
+}
+
+/// Convert a given token (socket) ID into a session ID.
+pub fn token2session(token_id: usize) -> u32 {
+    assert!(mem::size_of::<usize>() >= 4);
+    let mask = ((1 as usize) << 24) - 1;
+    assert!((token_id & !mask) == (1 << 24));
+    (token_id & mask) as u32
+}
+
+/// Session router.
+///
+/// The router owns all service sessions and it moves data between the
+/// sessions and the Arrow uplink.
+pub struct SessionRouter<L: Logger> {
+    /// Application logger.
+    logger:   L,
+    /// Session contexts.
+    sessions: HashMap<u32, SessionContext<L>>,
+    /// Session read queue.
+    queue:    VecDeque<u32>,
+    /// Prefix data frames with sequence numbers.
+    sequenced: bool,
+    /// Prefix data frames with timestamp records.
+    timestamped: bool,
+    /// End-to-end encryption has been negotiated.
+    e2e:      bool,
+    /// Session write time limit (in milliseconds).
+    write_timeout: u64,
+    /// Capacity of session input buffers (in bytes).
+    buffer_size: usize,
+    /// Remover of connection tracking entries of finished sessions (if
+    /// enabled).
+    #[cfg(feature = "conntrack")]
+    conntrack: Option<conntrack::Flusher>,
+}
+
+impl<L: Logger + Clone> SessionRouter<L> {
+    /// Create a new session router.
+    pub fn new(logger: L) -> SessionRouter<L> {
+        SessionRouter {
+            logger:   logger,
+            sessions: HashMap::new(),
+            queue:    VecDeque::new(),
+            sequenced: false,
+            timestamped: false,
+            e2e:      false,
+            write_timeout: SESSION_WRITE_TIMEOUT,
+            buffer_size: SESSION_BUFFER_SIZE,
+            #[cfg(feature = "conntrack")]
+            conntrack: None
+        }
+    }
+
+    /// Set time limit for writing pending data into service connections
+    /// (in milliseconds). It applies only to newly opened sessions.
+    pub fn set_write_timeout(&mut self, timeout: u64) {
+        self.write_timeout = timeout;
+    }
+
+    /// Set capacity of session input buffers (in bytes). It applies only to
+    /// newly opened sessions.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer_size = size;
+    }
+
+    /// Enable or disable removal of connection tracking entries of finished
+    /// sessions. The entries are removed by a background thread.
+    #[cfg(feature = "conntrack")]
+    pub fn set_flush_conntrack(&mut self, flush: bool) {
+        if !flush {
+            self.conntrack = None;
+        } else if self.conntrack.is_none() {
+            self.conntrack = Some(conntrack::Flusher::start());
+        }
+    }
+
+    /// Enable or disable sequence numbers of data frames (both sides must
+    /// support them).
+    pub fn set_sequenced(&mut self, sequenced: bool) {
+        self.sequenced = sequenced;
+    }
+
+    /// Check if data frames are prefixed with sequence numbers.
+    pub fn is_sequenced(&self) -> bool {
+        self.sequenced
+    }
+
+    /// Enable or disable timestamp records of data frames (both sides must
+    /// support them).
+    pub fn set_timestamped(&mut self, timestamped: bool) {
+        self.timestamped = timestamped;
+    }
+
+    /// Enable or disable end-to-end encryption of session payloads (Arrow
+    /// Service must support it).
+    pub fn set_e2e(&mut self, e2e: bool) {
+        self.e2e = e2e;
+    }
+
+    /// Check if end-to-end encryption of session payloads can be used.
+    pub fn is_e2e(&self) -> bool {
+        self.e2e
+    }
+
+    /// Get number of active sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Get number of active sessions of a given service.
+    pub fn service_sessions(&self, service_id: u16) -> usize {
+        self.sessions.values()
+            .filter(|ctx| ctx.service_id() == service_id)
+            .count()
+    }
+
+    /// Check if there is a session with a given ID.
+    pub fn contains(&self, session_id: u32) -> bool {
+        self.sessions.contains_key(&session_id)
+    }
+
+    /// Get session context for a given session ID.
+    pub fn get(&self, session_id: u32) -> Option<&SessionContext<L>> {
+        self.sessions.get(&session_id)
+    }
+
+    /// Get session context for a given session ID.
+    pub fn get_mut(
+        &mut self,
+        session_id: u32) -> Option<&mut SessionContext<L>> {
+        self.sessions.get_mut(&session_id)
+    }
+
+    /// Sample occupancy of input buffers of all sessions and return session
+    /// IDs of the sessions whose input buffer has been almost full for
+    /// longer than a given threshold (in milliseconds) together with the
+    /// time, the number of buffered bytes and the buffer capacity (see
+    /// SessionContext::check_occupancy()).
+    pub fn check_occupancy(&mut self, threshold: u64) -> Vec<(u32, f64, usize, usize)> {
+        let mut res = Vec::new();
+
+        for (&session_id, ctx) in &mut self.sessions {
+            if let Some((duration, buffered, capacity)) = ctx.check_occupancy(threshold) {
+                res.push((session_id, duration, buffered, capacity));
+            }
+        }
+
+        res
+    }
+
+    /// Get high-water marks of the input and output buffers of all
+    /// sessions (see SessionContext::high_water()).
+    #[cfg(feature = "metrics")]
+    pub fn high_water(&self) -> Vec<(u32, usize, usize)> {
+        self.sessions.iter()
+            .map(|(&session_id, ctx)| {
+                let (input, output) = ctx.high_water();
+
+                (session_id, input, output)
+            })
+            .collect()
+    }
+
+    /// Detach all payload taps which are full or which could not be
+    /// written.
+    #[cfg(feature = "recording")]
+    pub fn take_finished_taps(&mut self) -> Vec<(u32, SessionTap)> {
+        let mut res = Vec::new();
+
+        for (&session_id, ctx) in &mut self.sessions {
+            if ctx.tap_finished() {
+                if let Some(tap) = ctx.detach_tap() {
+                    res.push((session_id, tap));
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Open a new session to a given service address using given options.
+    /// The connection has to be established within a given time (in
+    /// milliseconds).
+    pub fn open<T: Handler>(
+        &mut self,
+        service_id: u16,
+        session_id: u32,
+        addr: &SocketAddr,
+        options: &SessionOptions,
+        connect_timeout: u64,
+        policy: OverloadPolicy,
+        event_loop: &mut EventLoop<T>) -> Result<()> {
+        let ctx = try_arr!(SessionContext::new(self.logger.clone(),
+            service_id, session_id, addr, options, connect_timeout,
+            self.write_timeout, self.buffer_size, policy, event_loop));
+
+        self.sessions.insert(session_id, ctx);
+        self.queue.push_back(session_id);
+
+        Ok(())
+    }
+
+    /// Remove session context with a given session ID.
+    pub fn remove<T: Handler>(
+        &mut self,
+        session_id: u32,
+        event_loop: &mut EventLoop<T>) {
+        if let Some(ctx) = self.sessions.remove(&session_id) {
+            ctx.dispose(event_loop);
+
+            self.close(session_id, ctx);
+        }
+    }
+
+    /// Close a given (already disposed) session context and queue removal
+    /// of its connection tracking entry if requested. Errors of previously
+    /// queued removals are logged.
+    #[cfg(feature = "conntrack")]
+    fn close(&mut self, session_id: u32, ctx: SessionContext<L>) {
+        if let Some(ref flusher) = self.conntrack {
+            let tuple = ctx.connection_tuple();
+
+            // close the service connection first, otherwise the entry would
+            // be created again by the next packet
+            drop(ctx);
+
+            if let Some(tuple) = tuple {
+                flusher.flush(session_id, tuple);
+            }
+
+            for (session_id, err) in flusher.take_errors() {
+                log_warn!(self.logger, "unable to remove connection tracking entry (session ID: {:08x}): {}", session_id, err);
+            }
+        }
+    }
+
+    /// Close a given (already disposed) session context.
+    #[cfg(not(feature = "conntrack"))]
+    fn close(&mut self, _: u32, ctx: SessionContext<L>) {
+        drop(ctx);
+    }
+
+    /// Fill the uplink output buffer with data from session input buffers.
+    pub fn fill_uplink<T: Handler>(
+        &mut self,
+        uplink: &mut Uplink,
+        event_loop: &mut EventLoop<T>) {
+        // using round robin alg. here in order to avoid session read
+        // starvation
+        let mut queue_size = self.queue.len();
+        while queue_size > 0 && !uplink.is_full() {
+            if let Some(session_id) = self.queue.pop_front() {
+                if let Some(ctx) = self.sessions.get_mut(&session_id) {
+                    // avoid sending empty packets
+                    let len = if ctx.input_ready() {
+                        ctx.buffer_input(32768, self.sequenced,
+                            self.timestamped, uplink)
+                    } else {
+                        0
+                    };
+
+                    ctx.drop_input_bytes(len, event_loop);
+
+                    self.queue.push_back(session_id);
+                }
+            }
+
+            queue_size -= 1;
+        }
+    }
+
+    /// Move all data from the session input buffer into the uplink output
+    /// buffer.
+    pub fn flush<T: Handler>(
+        &mut self,
+        session_id: u32,
+        uplink: &mut Uplink,
+        event_loop: &mut EventLoop<T>) {
+        if let Some(ctx) = self.sessions.get_mut(&session_id) {
+            // avoid sending empty packets
+            let len = if ctx.input_ready() {
+                ctx.buffer_input(usize::max_value(), self.sequenced,
+                    self.timestamped, uplink)
+            } else {
+                0
+            };
+
+            ctx.drop_input_bytes(len, event_loop);
+
+            uplink.enable_write_events(event_loop);
+        }
+    }
+
+    /// Move all data from the session input buffer into the uplink output
+    /// buffer and finish the end-to-end encrypted stream (if enabled). This
+    /// should be used when the service connection has been closed without
+    /// an error.
+    pub fn finish<T: Handler>(
+        &mut self,
+        session_id: u32,
+        uplink: &mut Uplink,
+        event_loop: &mut EventLoop<T>) {
+        self.flush(session_id, uplink, event_loop);
+
+        if let Some(ctx) = self.sessions.get_mut(&session_id) {
+            // the input buffer is not empty if the end-to-end encryption
+            // session has not been established, the stream must not be
+            // finished in such case
+            if ctx.input_buffer.is_empty() {
+                ctx.buffer_e2e_finish(self.sequenced, self.timestamped,
+                    uplink);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+
+    use utils::Serialize;
+    use utils::logger::DummyLogger;
+
+    use mio::Token;
+
+    use openssl::ssl::{SslContext, SslMethod};
+
+    /// Event handler collecting socket events.
+    struct EventCollector {
+        events: Vec<(usize, EventSet)>,
+    }
+
+    impl Handler for EventCollector {
+        type Timeout = ();
+        type Message = ();
+
+        fn ready(
+            &mut self,
+            _: &mut EventLoop<Self>,
+            token: Token,
+            event_set: EventSet) {
+            self.events.push((token.as_usize(), event_set));
+        }
+    }
+
+    /// Run a given event loop until there is a readable event for a given
+    /// token.
+    fn wait_readable(
+        event_loop: &mut EventLoop<EventCollector>,
+        token_id: usize) -> EventSet {
+        let mut handler = EventCollector {
+            events: Vec::new()
+        };
+
+        for _ in 0..100 {
+            event_loop.run_once(&mut handler, Some(100))
+                .unwrap();
+
+            for &(token, event_set) in &handler.events {
+                if token == token_id && event_set.is_readable() {
+                    return event_set;
+                }
+            }
+
+            handler.events.clear();
+        }
+
+        panic!("no readable event for token {}", token_id);
+    }
+
+    /// Open a new session to a given listener and accept the connection.
+    fn open_session(
+        router: &mut SessionRouter<DummyLogger>,
+        service_id: u16,
+        session_id: u32,
+        listener: &TcpListener,
+        event_loop: &mut EventLoop<EventCollector>) -> StdTcpStream {
+        let addr = listener.local_addr()
+            .unwrap();
+
+        router.open(service_id, session_id, &addr,
+            &SessionOptions::default(), 10000, OverloadPolicy::Block,
+            event_loop)
+            .unwrap();
+
+        let (stream, _) = listener.accept()
+            .unwrap();
+
+        stream
+    }
+
+    #[test]
+    fn test_session_open_close() {
+        let listener       = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut router     = SessionRouter::new(DummyLogger::new());
+
+        let mut s1 = open_session(&mut router, 1, 1, &listener,
+            &mut event_loop);
+        let _s2 = open_session(&mut router, 1, 2, &listener,
+            &mut event_loop);
+        let _s3 = open_session(&mut router, 2, 3, &listener,
+            &mut event_loop);
+
+        assert_eq!(router.len(), 3);
+        assert_eq!(router.service_sessions(1), 2);
+        assert_eq!(router.service_sessions(2), 1);
+        assert!(router.contains(1));
+        assert_eq!(router.get(3).map(|ctx| ctx.service_id()), Some(2));
+
+        router.remove(1, &mut event_loop);
+
+        assert_eq!(router.len(), 2);
+        assert_eq!(router.service_sessions(1), 1);
+        assert!(!router.contains(1));
+        assert!(router.get(1).is_none());
+
+        // the service connection has been closed
+        let mut buffer = [0u8; 16];
+
+        assert_eq!(s1.read(&mut buffer).unwrap(), 0);
+
+        // removing an unknown session does nothing
+        router.remove(1, &mut event_loop);
+
+        assert_eq!(router.len(), 2);
+    }
+
+    #[test]
+    fn test_session_routing() {
+        let listener       = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut router     = SessionRouter::new(DummyLogger::new());
+
+        let mut s1 = open_session(&mut router, 1, 1, &listener,
+            &mut event_loop);
+        let mut s2 = open_session(&mut router, 2, 2, &listener,
+            &mut event_loop);
+
+        // data from Arrow Service go to the corresponding service
+        router.get_mut(1).unwrap()
+            .send_message(b"foo", &mut event_loop)
+            .unwrap();
+        router.get_mut(2).unwrap()
+            .send_message(b"bar", &mut event_loop)
+            .unwrap();
+
+        for &session_id in &[1, 2] {
+            router.get_mut(session_id).unwrap()
+                .socket_ready(&mut event_loop, EventSet::writable())
+                .unwrap();
+        }
+
+        let mut buffer = [0u8; 3];
+
+        s1.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"foo");
+
+        s2.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"bar");
+
+        // data from a service are sent using the corresponding session ID
+        s2.write_all(b"hello").unwrap();
+
+        let event_set = wait_readable(&mut event_loop, session2token(2));
+
+        let buffered = router.get_mut(2).unwrap()
+            .socket_ready(&mut event_loop, event_set)
+            .unwrap();
+
+        assert_eq!(buffered, Some(5));
+        assert!(!router.get(1).unwrap().input_ready());
+        assert!(router.get(2).unwrap().input_ready());
+
+        let ssl_context = SslContext::new(SslMethod::Tlsv1_2)
+            .unwrap();
+        let arrow_addr = listener.local_addr()
+            .unwrap();
+
+        let mut uplink = Uplink::connect(&ssl_context, &arrow_addr, 0,
+            &mut event_loop)
+            .unwrap();
+
+        router.fill_uplink(&mut uplink, &mut event_loop);
+
+        let mut expected = Vec::new();
+
+        ArrowMessage::new(2, 2, b"hello".to_vec())
+            .serialize(&mut expected)
+            .unwrap();
+
+        assert_eq!(uplink.buffered(), expected.len());
+        assert!(!router.get(2).unwrap().input_ready());
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Busy-loop detection.
+//!
+//! All sockets are registered as level-triggered. A socket stuck in a state
+//! which is reported on every poll (and which cannot be resolved by the
+//! corresponding handler) would make the event loop spin and peg the CPU.
+//! The detector counts events of each token within a time window and reports
+//! tokens exceeding a given event rate, so they can be suspended for a while.
+
+use std::collections::HashMap;
+
+use mio::EventSet;
+
+use time;
+
+/// Event statistics of a single token.
+struct TokenStats {
+    /// Start of the current window (in nanoseconds).
+    window_start: u64,
+    /// Number of events within the current window.
+    events:       usize,
+}
+
+/// Report of a spinning token.
+#[derive(Debug, Copy, Clone)]
+pub struct SpinReport {
+    /// Number of events within the window.
+    pub events:    usize,
+    /// Time elapsed since start of the window (in milliseconds).
+    pub elapsed:   u64,
+    /// The last event set.
+    pub event_set: EventSet,
+}
+
+/// Per-token event rate accounting.
+pub struct SpinDetector {
+    /// Statistics of individual tokens.
+    tokens:     HashMap<usize, TokenStats>,
+    /// Maximum number of events within a window.
+    max_events: usize,
+    /// Window length (in nanoseconds).
+    window:     u64,
+}
+
+impl SpinDetector {
+    /// Create a new detector allowing at most a given number of events
+    /// per token within a given time window (in milliseconds).
+    pub fn new(max_events: usize, window: u64) -> SpinDetector {
+        SpinDetector {
+            tokens:     HashMap::new(),
+            max_events: max_events,
+            window:     window * 1000000
+        }
+    }
+
+    /// Record an event of a given token. A report is returned if the token
+    /// exceeded the maximum event rate (its statistics are reset in such
+    /// case).
+    pub fn event(
+        &mut self,
+        token_id: usize,
+        event_set: EventSet) -> Option<SpinReport> {
+        self.event_at(token_id, event_set, time::precise_time_ns())
+    }
+
+    /// Remove statistics of tokens without any events within the last
+    /// window.
+    pub fn cleanup(&mut self) {
+        let now    = time::precise_time_ns();
+        let window = self.window;
+
+        let expired = self.tokens.iter()
+            .filter(|&(_, stats)| (now - stats.window_start) > 2 * window)
+            .map(|(token_id, _)| *token_id)
+            .collect::<Vec<_>>();
+
+        for token_id in expired {
+            self.tokens.remove(&token_id);
+        }
+    }
+
+    /// Record an event of a given token at a given time (in nanoseconds).
+    fn event_at(
+        &mut self,
+        token_id: usize,
+        event_set: EventSet,
+        now: u64) -> Option<SpinReport> {
+        let stats = self.tokens.entry(token_id)
+            .or_insert(TokenStats {
+                window_start: now,
+                events:       0
+            });
+
+        if (now - stats.window_start) > self.window {
+            stats.window_start = now;
+            stats.events       = 0;
+        }
+
+        stats.events += 1;
+
+        if stats.events > self.max_events {
+            let res = SpinReport {
+                events:    stats.events,
+                elapsed:   (now - stats.window_start) / 1000000,
+                event_set: event_set
+            };
+
+            stats.window_start = now;
+            stats.events       = 0;
+
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mio::EventSet;
+
+    #[test]
+    fn test_spin_detection() {
+        let mut detector = SpinDetector::new(10, 1000);
+
+        let ms = 1000000;
+
+        // slow events are fine
+        for i in 0..100 {
+            assert!(detector.event_at(1, EventSet::readable(), i * 200 * ms)
+                .is_none());
+        }
+
+        let start = 100 * 200 * ms;
+
+        for i in 0..10 {
+            assert!(detector.event_at(2, EventSet::readable(), start + i)
+                .is_none());
+        }
+
+        let report = detector.event_at(2, EventSet::hup(), start + 10 * ms)
+            .unwrap();
+
+        assert_eq!(report.events, 11);
+        assert_eq!(report.elapsed, 10);
+        assert!(report.event_set.is_hup());
+
+        // the statistics are reset after a report
+        assert!(detector.event_at(2, EventSet::readable(), start + 11 * ms)
+            .is_none());
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! System suspend detection.
+//!
+//! The monotonic clock (used for all timers) does not advance while the
+//! system is suspended, while the boot-time clock and the wall clock do. A
+//! growing difference between these clocks means that the system has been
+//! suspended. The boot-time clock is preferred because it is not affected by
+//! wall clock adjustments. The wall clock is used on systems without the
+//! boot-time clock.
+
+use libc;
+
+use time;
+
+/// Minimum suspend duration (in seconds) which is reported.
+pub const SUSPEND_THRESHOLD: f64 = 5.0;
+
+/// Linux boot-time clock ID (it may be missing in the libc bindings).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CLOCK_BOOTTIME: libc::clockid_t = 7;
+
+/// System suspend detector.
+#[derive(Debug, Clone)]
+pub struct SuspendDetector {
+    /// Difference between the reference clock and the monotonic clock at
+    /// the time of the last check (in seconds).
+    offset: f64,
+}
+
+impl SuspendDetector {
+    /// Create a new suspend detector.
+    pub fn new() -> SuspendDetector {
+        SuspendDetector {
+            offset: get_suspend_offset()
+        }
+    }
+
+    /// Check if the system has been suspended since the last check. The
+    /// suspend duration (in seconds) is returned in such case.
+    pub fn check(&mut self) -> Option<u64> {
+        self.check_offset(get_suspend_offset())
+    }
+
+    /// Check a given clock difference.
+    fn check_offset(&mut self, offset: f64) -> Option<u64> {
+        let gap = offset - self.offset;
+
+        self.offset = offset;
+
+        if gap >= SUSPEND_THRESHOLD {
+            Some(gap as u64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Get difference between the boot-time clock (or the wall clock) and the
+/// monotonic clock (in seconds).
+fn get_suspend_offset() -> f64 {
+    let monotonic = time::precise_time_s();
+
+    match boot_time() {
+        Some(t) => t - monotonic,
+        None    => {
+            let t = time::get_time();
+
+            t.sec as f64 + (t.nsec as f64 / 1000000000.0) - monotonic
+        }
+    }
+}
+
+/// Get the boot-time clock (in seconds).
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn boot_time() -> Option<f64> {
+    let mut ts = libc::timespec {
+        tv_sec:  0,
+        tv_nsec: 0
+    };
+
+    let res = unsafe {
+        libc::clock_gettime(CLOCK_BOOTTIME, &mut ts)
+    };
+
+    if res == 0 {
+        Some(ts.tv_sec as f64 + (ts.tv_nsec as f64 / 1000000000.0))
+    } else {
+        None
+    }
+}
+
+/// Get the boot-time clock (in seconds).
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn boot_time() -> Option<f64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspend_detection() {
+        let mut detector = SuspendDetector::new();
+
+        let offset = detector.offset;
+
+        assert_eq!(detector.check_offset(offset + 0.5), None);
+        assert_eq!(detector.check_offset(offset + 1.0), None);
+        assert_eq!(detector.check_offset(offset + 121.0), Some(120));
+        assert_eq!(detector.check_offset(offset + 121.0), None);
+
+        // wall clock adjustments backwards are ignored
+        assert_eq!(detector.check_offset(offset - 100.0), None);
+    }
+}
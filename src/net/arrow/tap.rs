@@ -0,0 +1,389 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session payload taps.
+//!
+//! A tap records raw payload of a single service session into two files
+//! (data received from the service and data sent to the service) until a
+//! given size limit is reached. Taps are requested using the control socket
+//! and attached to the sessions by the connection handler.
+
+use std::io;
+use std::fs;
+use std::cmp;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+
+use utils::clock;
+
+use time;
+
+/// Default directory for tap files.
+pub const TAP_DIR: &'static str = "/var/lib/arrow/taps";
+
+/// Default size limit of a single tap (in bytes, both directions together).
+pub const TAP_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// Request for a session tap.
+#[derive(Debug, Clone)]
+pub struct TapRequest {
+    /// Common prefix of both file paths.
+    prefix: String,
+    /// Size limit (in bytes, both directions together).
+    limit:  u64,
+}
+
+impl TapRequest {
+    /// Create a new tap request for a given session. Files named
+    /// `session-<id>-<time>-from-service.bin` and
+    /// `session-<id>-<time>-to-service.bin` will be created in a given
+    /// directory (the directory is created if it does not exist).
+    pub fn new(dir: &str, session_id: u32, limit: u64) -> io::Result<TapRequest> {
+        try!(fs::create_dir_all(dir));
+
+        let now = time::at_utc(clock::now());
+        let tstr = time::strftime("%Y%m%d-%H%M%S", &now)
+            .unwrap();
+
+        let prefix = Path::new(dir)
+            .join(format!("session-{:08x}-{}", session_id, tstr))
+            .to_string_lossy()
+            .into_owned();
+
+        let res = TapRequest {
+            prefix: prefix,
+            limit:  limit
+        };
+
+        Ok(res)
+    }
+
+    /// Get the common prefix of the tap file paths.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+/// Payload tap of a single session.
+#[derive(Debug)]
+pub struct SessionTap {
+    /// File with data received from the service.
+    from_service: File,
+    /// File with data sent to the service.
+    to_service:   File,
+    /// Common prefix of both file paths.
+    prefix:       String,
+    /// Size limit (in bytes, both directions together).
+    limit:        u64,
+    /// Number of bytes written so far.
+    written:      u64,
+}
+
+impl SessionTap {
+    /// Create the tap files for a given request.
+    pub fn open(request: &TapRequest) -> io::Result<SessionTap> {
+        let prefix = request.prefix.clone();
+
+        let from_service = try!(File::create(
+            format!("{}-from-service.bin", prefix)));
+        let to_service = try!(File::create(
+            format!("{}-to-service.bin", prefix)));
+
+        let res = SessionTap {
+            from_service: from_service,
+            to_service:   to_service,
+            prefix:       prefix,
+            limit:        request.limit,
+            written:      0
+        };
+
+        Ok(res)
+    }
+
+    /// Get the common prefix of the tap file paths.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Get the number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Check if the size limit has been reached.
+    pub fn is_full(&self) -> bool {
+        self.written >= self.limit
+    }
+
+    /// Record data received from the service. Data exceeding the size limit
+    /// are silently truncated.
+    pub fn from_service(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = self.available(data.len());
+
+        try!(self.from_service.write_all(&data[..len]));
+
+        self.written += len as u64;
+
+        Ok(())
+    }
+
+    /// Record data sent to the service. Data exceeding the size limit are
+    /// silently truncated.
+    pub fn to_service(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = self.available(data.len());
+
+        try!(self.to_service.write_all(&data[..len]));
+
+        self.written += len as u64;
+
+        Ok(())
+    }
+
+    /// Get the number of bytes (at most a given number) which can be still
+    /// written.
+    fn available(&self, len: usize) -> usize {
+        cmp::min(len as u64, self.limit - cmp::min(self.written, self.limit))
+            as usize
+    }
+}
+
+/// Tap requests shared between the control socket and the connection
+/// handler.
+#[derive(Debug, Clone)]
+pub struct SessionTaps {
+    /// Directory for tap files.
+    dir:     String,
+    /// Currently active sessions.
+    active:  HashSet<u32>,
+    /// Sessions with an attached tap.
+    tapped:  HashSet<u32>,
+    /// Taps waiting to be attached.
+    attach:  HashMap<u32, TapRequest>,
+    /// Sessions waiting for their taps to be detached.
+    detach:  HashSet<u32>,
+}
+
+impl SessionTaps {
+    /// Create a new tap registry writing tap files into a given directory.
+    pub fn new(dir: &str) -> SessionTaps {
+        SessionTaps {
+            dir:    dir.to_string(),
+            active: HashSet::new(),
+            tapped: HashSet::new(),
+            attach: HashMap::new(),
+            detach: HashSet::new()
+        }
+    }
+
+    /// Get the directory for tap files.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Mark a given session as active.
+    pub fn session_opened(&mut self, session_id: u32) {
+        self.active.insert(session_id);
+    }
+
+    /// Forget a given session including all its pending requests.
+    pub fn session_closed(&mut self, session_id: u32) {
+        self.active.remove(&session_id);
+        self.tapped.remove(&session_id);
+        self.attach.remove(&session_id);
+        self.detach.remove(&session_id);
+    }
+
+    /// Forget all sessions (e.g. after the connection has been closed).
+    pub fn clear(&mut self) {
+        self.active.clear();
+        self.tapped.clear();
+        self.attach.clear();
+        self.detach.clear();
+    }
+
+    /// Check if a given session is active.
+    pub fn is_active(&self, session_id: u32) -> bool {
+        self.active.contains(&session_id)
+    }
+
+    /// Check if a given session has a tap (either attached or waiting).
+    pub fn is_tapped(&self, session_id: u32) -> bool {
+        self.tapped.contains(&session_id)
+            || self.attach.contains_key(&session_id)
+    }
+
+    /// Get sorted IDs of all active sessions.
+    pub fn sessions(&self) -> Vec<u32> {
+        let mut res = self.active.iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        res.sort();
+
+        res
+    }
+
+    /// Request attaching a new tap to a given session (any previous tap of
+    /// the session is replaced). The tap files are created once the request
+    /// is processed by the connection handler.
+    pub fn attach(&mut self, session_id: u32, limit: u64) -> io::Result<TapRequest> {
+        let request = try!(TapRequest::new(&self.dir, session_id, limit));
+
+        self.detach.remove(&session_id);
+        self.attach.insert(session_id, request.clone());
+
+        Ok(request)
+    }
+
+    /// Request detaching the tap of a given session.
+    pub fn detach(&mut self, session_id: u32) {
+        if self.attach.remove(&session_id).is_none() {
+            self.detach.insert(session_id);
+        }
+    }
+
+    /// Take all taps waiting to be attached.
+    pub fn take_attach_requests(&mut self) -> Vec<(u32, TapRequest)> {
+        let res = self.attach.drain()
+            .collect::<Vec<_>>();
+
+        for &(session_id, _) in &res {
+            self.tapped.insert(session_id);
+        }
+
+        res
+    }
+
+    /// Take all sessions waiting for their taps to be detached.
+    pub fn take_detach_requests(&mut self) -> Vec<u32> {
+        let res = self.detach.drain()
+            .collect::<Vec<_>>();
+
+        for session_id in &res {
+            self.tapped.remove(session_id);
+        }
+
+        res
+    }
+
+    /// Mark the tap of a given session as detached (e.g. because it is
+    /// full).
+    pub fn detached(&mut self, session_id: u32) {
+        self.tapped.remove(&session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use std::fs::File;
+    use std::io::Read;
+
+    /// Read content of a given file.
+    fn read_file(path: &str) -> Vec<u8> {
+        let mut res = Vec::new();
+
+        File::open(path)
+            .unwrap()
+            .read_to_end(&mut res)
+            .unwrap();
+
+        res
+    }
+
+    #[test]
+    fn test_session_tap() {
+        let dir = "test-taps";
+
+        let request = TapRequest::new(dir, 0x2a, 8)
+            .unwrap();
+
+        let mut tap = SessionTap::open(&request)
+            .unwrap();
+
+        let prefix = tap.prefix()
+            .to_string();
+
+        assert!(prefix.starts_with("test-taps/session-0000002a-"));
+
+        tap.from_service(b"abc").unwrap();
+        tap.to_service(b"defg").unwrap();
+        assert!(!tap.is_full());
+
+        tap.from_service(b"hij").unwrap();
+        tap.to_service(b"klm").unwrap();
+        assert!(tap.is_full());
+        assert_eq!(tap.written(), 8);
+
+        drop(tap);
+
+        let from = format!("{}-from-service.bin", prefix);
+        let to   = format!("{}-to-service.bin", prefix);
+
+        assert_eq!(read_file(&from), b"abch");
+        assert_eq!(read_file(&to), b"defg");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_session_taps() {
+        let dir = "test-taps-registry";
+
+        let mut taps = SessionTaps::new(dir);
+
+        taps.session_opened(2);
+        taps.session_opened(1);
+
+        assert_eq!(taps.sessions(), vec![1, 2]);
+        assert!(!taps.is_tapped(1));
+
+        let request = taps.attach(1, 10)
+            .unwrap();
+
+        assert!(request.prefix().starts_with("test-taps-registry/session-00000001-"));
+
+        assert!(taps.is_tapped(1));
+
+        let attached = taps.take_attach_requests();
+
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].0, 1);
+        assert!(taps.is_tapped(1));
+
+        taps.detach(1);
+
+        assert_eq!(taps.take_detach_requests(), vec![1]);
+        assert!(!taps.is_tapped(1));
+
+        // detaching a waiting tap cancels the request
+        taps.attach(2, 10).unwrap();
+        taps.detach(2);
+
+        assert!(taps.take_attach_requests().is_empty());
+        assert!(taps.take_detach_requests().is_empty());
+
+        taps.session_closed(1);
+
+        assert_eq!(taps.sessions(), vec![2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timestamped session data frames.
+//!
+//! If both sides support the `FEATURE_DATA_TIMESTAMPS` Control Protocol
+//! feature, every session data message sent by the client starts with a
+//! timestamp TLV record (u16 type, u16 length, value). The value contains
+//! the process time (a UNIX timestamp in milliseconds) when the first byte of
+//! the frame was read from the service connection and the time (in
+//! milliseconds) the data spent in the client buffers. Arrow Service can use
+//! it to distinguish live data from a backlog delivered after an uplink
+//! stall. The record precedes the sequence number (if sequence numbers are
+//! enabled as well).
+
+use std::io;
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use utils::endian;
+use utils::Serialize;
+use net::arrow::protocol::ArrowMessageBody;
+use net::arrow::error::{Result, ArrowError};
+
+/// Type of the timestamp record.
+pub const TIMESTAMP_RECORD_TYPE: u16 = 0x0001;
+
+/// Length of the timestamp record value.
+const TIMESTAMP_VALUE_LENGTH: usize = 12;
+
+/// Size of the timestamp record preceding the frame data.
+pub const TIMESTAMP_SIZE: usize = 4 + TIMESTAMP_VALUE_LENGTH;
+
+/// Session data frame with a timestamp record.
+pub struct TimestampedFrame<B: ArrowMessageBody> {
+    read_time: i64,
+    delay:     u32,
+    body:      B,
+}
+
+impl<B: ArrowMessageBody> TimestampedFrame<B> {
+    /// Create a new frame with a given read time (UNIX timestamp in
+    /// milliseconds), buffering delay (in milliseconds) and body.
+    pub fn new(read_time: i64, delay: u32, body: B) -> TimestampedFrame<B> {
+        TimestampedFrame {
+            read_time: read_time,
+            delay:     delay,
+            body:      body
+        }
+    }
+
+    /// Get the read time.
+    pub fn read_time(&self) -> i64 {
+        self.read_time
+    }
+
+    /// Get the buffering delay.
+    pub fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    /// Get the frame body.
+    pub fn body(&self) -> &B {
+        &self.body
+    }
+}
+
+impl<'a> TimestampedFrame<&'a [u8]> {
+    /// Split a given message body into the timestamp record and the rest of
+    /// the frame.
+    pub fn parse(body: &'a [u8]) -> Result<TimestampedFrame<&'a [u8]>> {
+        if body.len() < TIMESTAMP_SIZE {
+            return Err(ArrowError::other("timestamp record expected"));
+        }
+
+        let record_type = endian::get_u16(body);
+        let length      = endian::get_u16(&body[2..]) as usize;
+
+        if record_type != TIMESTAMP_RECORD_TYPE
+            || length != TIMESTAMP_VALUE_LENGTH {
+            return Err(ArrowError::other("invalid timestamp record"));
+        }
+
+        let read_time = endian::get_u64(&body[4..]) as i64;
+        let delay     = endian::get_u32(&body[12..]);
+
+        Ok(TimestampedFrame::new(read_time, delay, &body[TIMESTAMP_SIZE..]))
+    }
+}
+
+impl<B: ArrowMessageBody> Serialize for TimestampedFrame<B> {
+    fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(TIMESTAMP_RECORD_TYPE.serialize(w));
+        try!((TIMESTAMP_VALUE_LENGTH as u16).serialize(w));
+        try!(self.read_time.serialize(w));
+        try!(self.delay.serialize(w));
+
+        self.body.serialize(w)
+    }
+}
+
+impl<B: ArrowMessageBody> ArrowMessageBody for TimestampedFrame<B> {
+    fn len(&self) -> usize {
+        TIMESTAMP_SIZE + self.body.len()
+    }
+}
+
+/// Read times of data in a session input buffer. The tracker keeps a read
+/// time for every chunk of data read from the service connection.
+#[derive(Debug, Clone)]
+pub struct ReadTimes {
+    /// Buffered chunks (length, read time).
+    chunks: VecDeque<(usize, i64)>,
+}
+
+impl ReadTimes {
+    /// Create a new empty tracker.
+    pub fn new() -> ReadTimes {
+        ReadTimes {
+            chunks: VecDeque::new()
+        }
+    }
+
+    /// Append a chunk of a given length read at a given time.
+    pub fn push(&mut self, len: usize, read_time: i64) {
+        if len > 0 {
+            self.chunks.push_back((len, read_time));
+        }
+    }
+
+    /// Get read time of the oldest buffered byte.
+    pub fn oldest(&self) -> Option<i64> {
+        self.chunks.front()
+            .map(|&(_, read_time)| read_time)
+    }
+
+    /// Remove a given number of bytes from the beginning.
+    pub fn consume(&mut self, count: usize) {
+        self.remove(0, count);
+    }
+
+    /// Remove a given range of bytes (e.g. a dropped media frame).
+    pub fn remove(&mut self, start: usize, len: usize) {
+        let end = start + len;
+
+        let mut offset = 0;
+        let mut chunks = VecDeque::with_capacity(self.chunks.len());
+
+        for &(chunk_len, read_time) in &self.chunks {
+            let chunk_start = offset;
+            let chunk_end   = offset + chunk_len;
+
+            let mut remaining = chunk_len;
+
+            if chunk_start < end && start < chunk_end {
+                let overlap_start = if start > chunk_start {
+                    start
+                } else {
+                    chunk_start
+                };
+
+                let overlap_end = if end < chunk_end {
+                    end
+                } else {
+                    chunk_end
+                };
+
+                remaining -= overlap_end - overlap_start;
+            }
+
+            if remaining > 0 {
+                chunks.push_back((remaining, read_time));
+            }
+
+            offset = chunk_end;
+        }
+
+        self.chunks = chunks;
+    }
+
+    /// Remove all chunks.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::Serialize;
+    use net::utils::WriteBuffer;
+
+    #[test]
+    fn test_timestamped_frame() {
+        let data: &[u8] = &[0xab, 0xcd];
+        let frame = TimestampedFrame::new(0x0102030405, 0x0607, data);
+
+        let mut buf = WriteBuffer::new(0);
+
+        frame.serialize(&mut buf)
+            .unwrap();
+
+        assert_eq!(buf.as_bytes(), &[
+            0x00, 0x01, 0x00, 0x0c,
+            0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05,
+            0x00, 0x00, 0x06, 0x07,
+            0xab, 0xcd]);
+
+        let frame = TimestampedFrame::parse(buf.as_bytes())
+            .unwrap();
+
+        assert_eq!(frame.read_time(), 0x0102030405);
+        assert_eq!(frame.delay(), 0x0607);
+        assert_eq!(*frame.body(), &[0xab, 0xcd]);
+
+        assert!(TimestampedFrame::parse(&buf.as_bytes()[..15]).is_err());
+    }
+
+    #[test]
+    fn test_read_times() {
+        let mut times = ReadTimes::new();
+
+        assert_eq!(times.oldest(), None);
+
+        times.push(10, 100);
+        times.push(0, 150);
+        times.push(10, 200);
+        times.push(10, 300);
+
+        assert_eq!(times.oldest(), Some(100));
+
+        times.consume(5);
+
+        assert_eq!(times.oldest(), Some(100));
+
+        times.consume(5);
+
+        assert_eq!(times.oldest(), Some(200));
+
+        // drop the rest of the second chunk and a part of the third one
+        times.remove(5, 10);
+
+        assert_eq!(times.oldest(), Some(200));
+
+        times.consume(5);
+
+        assert_eq!(times.oldest(), Some(300));
+
+        times.consume(5);
+
+        assert_eq!(times.oldest(), None);
+    }
+}
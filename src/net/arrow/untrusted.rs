@@ -0,0 +1,126 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Untrusted network interfaces.
+//!
+//! Services asking for the Basic authentication receive camera credentials
+//! in plaintext. Network interfaces (e.g. VLANs shared with other tenants)
+//! can be marked as untrusted, services asking for the Basic authentication
+//! within networks of such interfaces are neither added into the service
+//! table nor tunneled.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use net::raw::devices::EthernetDevice;
+
+/// Set of untrusted network interfaces.
+#[derive(Debug, Clone)]
+pub struct UntrustedInterfaces {
+    names: Vec<String>,
+}
+
+impl UntrustedInterfaces {
+    /// Create an empty set (i.e. all interfaces are trusted).
+    pub fn new() -> UntrustedInterfaces {
+        UntrustedInterfaces {
+            names: Vec::new()
+        }
+    }
+
+    /// Mark a network interface with a given name as untrusted.
+    pub fn add(&mut self, name: &str) {
+        self.names.push(name.to_string());
+    }
+
+    /// Check if all interfaces are trusted.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Get name of an untrusted interface whose network contains a given
+    /// address (None if the address is not within any untrusted network).
+    pub fn interface_of(&self, addr: &IpAddr) -> Option<String> {
+        if self.names.is_empty() {
+            None
+        } else {
+            self.find(&EthernetDevice::list(), addr)
+        }
+    }
+
+    /// Find an untrusted interface among given devices whose network
+    /// contains a given address.
+    fn find(&self, devices: &[EthernetDevice], addr: &IpAddr) -> Option<String> {
+        let addr = match addr {
+            &IpAddr::V4(ref addr) => addr,
+            _ => return None
+        };
+
+        devices.iter()
+            .filter(|dev| self.names.contains(&dev.name))
+            .find(|dev| same_network(&dev.ip_addr, addr, &dev.netmask))
+            .map(|dev| dev.name.clone())
+    }
+}
+
+/// Check if two given addresses are in the same network.
+fn same_network(a: &Ipv4Addr, b: &Ipv4Addr, netmask: &Ipv4Addr) -> bool {
+    let a       = a.octets();
+    let b       = b.octets();
+    let netmask = netmask.octets();
+
+    (0..4).all(|i| (a[i] & netmask[i]) == (b[i] & netmask[i]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use net::raw::ether::MacAddr;
+    use net::raw::devices::EthernetDevice;
+
+    #[test]
+    fn test_untrusted_interfaces() {
+        let devices = vec![
+            EthernetDevice {
+                name:     "eth0".to_string(),
+                mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
+                ip_addr:  Ipv4Addr::new(192, 168, 1, 10),
+                netmask:  Ipv4Addr::new(255, 255, 255, 0)
+            },
+            EthernetDevice {
+                name:     "eth0.20".to_string(),
+                mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
+                ip_addr:  Ipv4Addr::new(10, 0, 20, 1),
+                netmask:  Ipv4Addr::new(255, 255, 255, 0)
+            }
+        ];
+
+        let trusted   = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let untrusted = IpAddr::V4(Ipv4Addr::new(10, 0, 20, 30));
+        let unknown   = IpAddr::V4(Ipv4Addr::new(10, 0, 21, 30));
+
+        let mut interfaces = UntrustedInterfaces::new();
+
+        assert!(interfaces.find(&devices, &untrusted).is_none());
+
+        interfaces.add("eth0.20");
+
+        assert_eq!(interfaces.find(&devices, &untrusted),
+            Some("eth0.20".to_string()));
+        assert!(interfaces.find(&devices, &trusted).is_none());
+        assert!(interfaces.find(&devices, &unknown).is_none());
+    }
+}
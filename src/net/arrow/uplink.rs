@@ -0,0 +1,835 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arrow Service uplink definitions.
+
+use std::io;
+use std::cmp;
+
+#[cfg(feature = "websocket")]
+use std::io::Write;
+
+use std::net::SocketAddr;
+
+use utils;
+
+use utils::Serialize;
+use net::utils::{Timeout, WriteBuffer, into_mio_stream};
+
+use utils::config::{UPLINK_WRITE_TIMEOUT, UPLINK_BUFFER_SIZE};
+use net::arrow::{register_socket, reregister_socket, deregister_socket};
+use net::arrow::relay;
+use net::arrow::relay::RelayHello;
+#[cfg(feature = "socks")]
+use net::socks::SocksProxy;
+#[cfg(feature = "websocket")]
+use net::arrow::websocket;
+#[cfg(feature = "websocket")]
+use net::arrow::websocket::{FrameDecoder, WssEndpoint};
+use net::arrow::protocol::{ArrowMessage, ArrowMessageBody};
+use net::arrow::error::{Result, ArrowError};
+
+use mio::tcp::TcpStream;
+use mio::{EventLoop, EventSet, Handler};
+
+use openssl::ssl;
+
+use openssl::ssl::{SslStream, IntoSsl};
+
+/// Time limit for the SOCKS5 proxy handshake (in milliseconds).
+#[cfg(feature = "socks")]
+const SOCKS_TIMEOUT: u64 = 10000;
+
+/// Socket readiness required by a pending SSL operation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Want {
+    Read,
+    Write,
+}
+
+/// State of the SSL reader and writer.
+///
+/// The reader and the writer are tracked independently. A TLS renegotiation
+/// (or a key update) initiated by the server may require the reader to wait
+/// for the socket to become writable or the writer to wait for the socket
+/// to become readable and neither of them can block the other one.
+#[derive(Debug, Copy, Clone)]
+struct SslIoState {
+    /// Requirement of the last unfinished read operation.
+    reader:     Option<Want>,
+    /// Requirement of the last unfinished write operation.
+    writer:     Option<Want>,
+    /// There are some data to be written.
+    want_write: bool,
+}
+
+impl SslIoState {
+    /// Create a new state.
+    fn new() -> SslIoState {
+        SslIoState {
+            reader:     None,
+            writer:     None,
+            want_write: true
+        }
+    }
+
+    /// Check if the reader should be invoked for a given event set.
+    fn can_read(&self, event_set: EventSet) -> bool {
+        match self.reader {
+            Some(Want::Write) => event_set.is_writable(),
+            _ => event_set.is_readable()
+        }
+    }
+
+    /// Check if the writer should be invoked for a given event set.
+    fn can_write(&self, event_set: EventSet) -> bool {
+        match self.writer {
+            Some(Want::Read)  => event_set.is_readable(),
+            Some(Want::Write) => event_set.is_writable(),
+            None => self.want_write && event_set.is_writable()
+        }
+    }
+
+    /// Get socket events (readable, writable) required by the current
+    /// state.
+    fn socket_events(&self) -> (bool, bool) {
+        let readable = self.reader != Some(Want::Write)
+            || self.writer == Some(Want::Read);
+        let writable = self.reader == Some(Want::Write)
+            || self.writer == Some(Want::Write)
+            || (self.want_write && self.writer.is_none());
+
+        (readable, writable)
+    }
+}
+
+/// Abstraction over the Arrow SSL stream.
+struct ArrowStream {
+    stream:    SslStream<TcpStream>,
+    state:     SslIoState,
+    token_id:  usize,
+    suspended: bool,
+}
+
+impl ArrowStream {
+    /// Create a new ArrowStream instance and register the underlaying socket
+    /// within a given event loop.
+    fn connect<S: IntoSsl, H: Handler>(
+        s: S,
+        arrow_addr: &SocketAddr,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<ArrowStream> {
+        let tcp_stream = try_io!(TcpStream::connect(arrow_addr));
+
+        ArrowStream::new(s, tcp_stream, token_id, event_loop)
+    }
+
+    /// Create a new ArrowStream instance over a given TCP stream and register
+    /// the underlaying socket within a given event loop.
+    fn new<S: IntoSsl, H: Handler>(
+        s: S,
+        tcp_stream: TcpStream,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<ArrowStream> {
+        let ssl_stream = try_io!(SslStream::connect(s, tcp_stream));
+
+        register_socket(token_id, ssl_stream.get_ref(),
+            true, true, event_loop);
+
+        let res = ArrowStream {
+            stream:    ssl_stream,
+            state:     SslIoState::new(),
+            token_id:  token_id,
+            suspended: false
+        };
+
+        Ok(res)
+    }
+
+    /// Update socket events of the underlaying TCP socket according to the
+    /// current state.
+    fn update_socket_events<H: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<H>) {
+        if !self.suspended {
+            let (readable, writable) = self.state.socket_events();
+            reregister_socket(self.token_id, self.stream.get_ref(),
+                readable, writable, event_loop);
+        }
+    }
+
+    /// Enable/disable writing of application data.
+    fn set_want_write<H: Handler>(
+        &mut self,
+        want_write: bool,
+        event_loop: &mut EventLoop<H>) {
+        if self.state.want_write != want_write {
+            self.state.want_write = want_write;
+            self.update_socket_events(event_loop);
+        }
+    }
+
+    /// Stop receiving any events for the underlaying TCP socket.
+    fn suspend<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) {
+        if !self.suspended {
+            deregister_socket(self.stream.get_ref(), event_loop);
+            self.suspended = true;
+        }
+    }
+
+    /// Start receiving events for the underlaying TCP socket again.
+    fn resume<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) {
+        if self.suspended {
+            let (readable, writable) = self.state.socket_events();
+            register_socket(self.token_id, self.stream.get_ref(),
+                readable, writable, event_loop);
+            self.suspended = false;
+        }
+    }
+
+    /// Read available data from the underlaying SSL stream into a given
+    /// buffer.
+    fn read<H: Handler>(
+        &mut self,
+        buf: &mut [u8],
+        event_loop: &mut EventLoop<H>) -> Result<usize> {
+        let res = match self.stream.ssl_read(buf) {
+            Err(ssl::error::Error::WantRead(_)) => {
+                self.state.reader = Some(Want::Read);
+                Ok(0)
+            },
+            Err(ssl::error::Error::WantWrite(_)) => {
+                self.state.reader = Some(Want::Write);
+                Ok(0)
+            },
+            other => {
+                self.state.reader = None;
+                other
+            }
+        };
+
+        // a read might also complete a renegotiation the writer waits for
+        if self.state.writer == Some(Want::Read) {
+            self.state.writer = None;
+        }
+
+        self.update_socket_events(event_loop);
+
+        Ok(try_io!(res))
+    }
+
+    /// Write given data using the underlaying SSL stream.
+    fn write<H: Handler>(
+        &mut self,
+        data: &[u8],
+        event_loop: &mut EventLoop<H>) -> Result<usize> {
+        let res = match self.stream.ssl_write(data) {
+            Err(ssl::error::Error::WantRead(_)) => {
+                self.state.writer = Some(Want::Read);
+                Ok(0)
+            },
+            Err(ssl::error::Error::WantWrite(_)) => {
+                self.state.writer = Some(Want::Write);
+                Ok(0)
+            },
+            other => {
+                self.state.writer = None;
+                other
+            }
+        };
+
+        self.update_socket_events(event_loop);
+
+        Ok(try_io!(res))
+    }
+
+    /// Check if the underlaying socket is ready to read.
+    fn can_read(&self, event_set: EventSet) -> bool {
+        self.state.can_read(event_set)
+    }
+
+    /// Check if the underlaying socket is ready to write.
+    fn can_write(&self, event_set: EventSet) -> bool {
+        self.state.can_write(event_set)
+    }
+
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.stream.get_ref()
+            .take_socket_error()
+    }
+}
+
+/// State of a WebSocket uplink.
+#[cfg(feature = "websocket")]
+struct WebSocketState {
+    /// Sec-WebSocket-Key of the opening handshake.
+    key:      String,
+    /// The opening handshake has been completed.
+    upgraded: bool,
+    /// Handshake response received so far.
+    response: Vec<u8>,
+    /// Frames waiting for the opening handshake.
+    pending:  Vec<u8>,
+    /// Frame decoder.
+    decoder:  FrameDecoder,
+}
+
+#[cfg(feature = "websocket")]
+impl WebSocketState {
+    /// Create a new WebSocket state for a given handshake key.
+    fn new(key: String) -> WebSocketState {
+        WebSocketState {
+            key:      key,
+            upgraded: false,
+            response: Vec::new(),
+            pending:  Vec::new(),
+            decoder:  FrameDecoder::new()
+        }
+    }
+
+    /// Process given raw data received from the server. Decoded payload is
+    /// stored at the beginning of a given buffer, control frame responses
+    /// are put into a given output buffer. The number of decoded bytes is
+    /// returned together with a flag indicating that the output buffer has
+    /// been modified.
+    fn process(
+        &mut self,
+        buf: &mut [u8],
+        len: usize,
+        output: &mut WriteBuffer) -> Result<(usize, bool)> {
+        let mut input = buf[..len].to_vec();
+        let mut output_modified = false;
+
+        if !self.upgraded {
+            self.response.extend_from_slice(&input);
+
+            let header_len = match try!(websocket::parse_handshake_response(
+                &self.response, &self.key)) {
+                Some(header_len) => header_len,
+                None => return Ok((0, false))
+            };
+
+            // all data following the header come from the last read
+            input = self.response[header_len..].to_vec();
+
+            self.response = Vec::new();
+            self.upgraded = true;
+
+            output.write_all(&self.pending)
+                .unwrap();
+
+            self.pending  = Vec::new();
+
+            output_modified = true;
+        }
+
+        let mut data    = Vec::new();
+        let mut control = Vec::new();
+
+        try!(self.decoder.decode(&input, &mut data, &mut control));
+
+        for (opcode, payload) in control {
+            match opcode {
+                websocket::OPCODE_PING => {
+                    output.write_all(&websocket::encode_frame(
+                        websocket::OPCODE_PONG, &payload))
+                        .unwrap();
+
+                    output_modified = true;
+                },
+                websocket::OPCODE_CLOSE => return Err(ArrowError::connection_error(
+                    "WebSocket connection closed by Arrow Service")),
+                _ => ()
+            }
+        }
+
+        utils::memcpy(&mut buf[..data.len()], &data);
+
+        Ok((data.len(), output_modified))
+    }
+}
+
+/// Arrow Service uplink.
+///
+/// The uplink owns the TLS connection to a remote Arrow Service together with
+/// its I/O buffers and the write timeout.
+pub struct Uplink {
+    /// SSL/TLS connection to a remote Arrow Service.
+    stream:        ArrowStream,
+    /// Buffer for reading Arrow Protocol requests.
+    read_buffer:   Box<[u8]>,
+    /// Number of bytes in the read buffer.
+    received:      usize,
+    /// Buffer for writing Arrow Protocol responses.
+    write_buffer:  Box<[u8]>,
+    /// Output buffer for messages to be passed to Arrow Service.
+    output_buffer: WriteBuffer,
+    /// Write timeout.
+    write_tout:    Timeout,
+    /// Write time limit (in milliseconds).
+    write_timeout: u64,
+    /// WebSocket state (if the WebSocket transport is used).
+    #[cfg(feature = "websocket")]
+    websocket:     Option<WebSocketState>,
+}
+
+impl Uplink {
+    /// Connect to a given Arrow Service and register the underlaying socket
+    /// within a given event loop.
+    pub fn connect<S: IntoSsl, H: Handler>(
+        s: S,
+        arrow_addr: &SocketAddr,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<Uplink> {
+        let stream = try_arr!(ArrowStream::connect(s, arrow_addr,
+            token_id, event_loop));
+
+        Ok(Uplink::new(stream))
+    }
+
+    /// Connect to Arrow Service through a given relay and register the
+    /// underlaying socket within a given event loop.
+    pub fn connect_via_relay<S: IntoSsl, H: Handler>(
+        s: S,
+        relay_addr: &SocketAddr,
+        hello: &RelayHello,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<Uplink> {
+        let tcp_stream = try_arr!(relay::connect(relay_addr, hello));
+        let tcp_stream = try_io!(into_mio_stream(tcp_stream));
+
+        let stream = try_arr!(ArrowStream::new(s, tcp_stream,
+            token_id, event_loop));
+
+        Ok(Uplink::new(stream))
+    }
+
+    /// Connect to a given Arrow Service address ("host:port" format)
+    /// through a given SOCKS5 proxy and register the underlaying socket
+    /// within a given event loop. The host name is resolved by the proxy.
+    #[cfg(feature = "socks")]
+    pub fn connect_via_socks<S: IntoSsl, H: Handler>(
+        s: S,
+        proxy: &SocksProxy,
+        arrow_addr: &str,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<Uplink> {
+        let tcp_stream = try_io!(proxy.connect(arrow_addr, SOCKS_TIMEOUT));
+        let tcp_stream = try_io!(into_mio_stream(tcp_stream));
+
+        let stream = try_arr!(ArrowStream::new(s, tcp_stream,
+            token_id, event_loop));
+
+        Ok(Uplink::new(stream))
+    }
+
+    /// Connect to a given WebSocket endpoint of Arrow Service (resolved as
+    /// a given socket address) and register the underlaying socket within a
+    /// given event loop. Arrow Protocol messages are sent once the opening
+    /// handshake completes.
+    #[cfg(feature = "websocket")]
+    pub fn connect_websocket<S: IntoSsl, H: Handler>(
+        s: S,
+        addr: &SocketAddr,
+        endpoint: &WssEndpoint,
+        token_id: usize,
+        event_loop: &mut EventLoop<H>) -> Result<Uplink> {
+        let stream = try_arr!(ArrowStream::connect(s, addr,
+            token_id, event_loop));
+
+        let key = websocket::new_key();
+
+        let mut uplink = Uplink::new(stream);
+
+        uplink.output_buffer.write_all(
+            &websocket::handshake_request(endpoint, &key))
+            .unwrap();
+
+        uplink.write_tout.set(uplink.write_timeout);
+        uplink.websocket = Some(WebSocketState::new(key));
+
+        Ok(uplink)
+    }
+
+    /// Create a new uplink for a given Arrow stream.
+    fn new(stream: ArrowStream) -> Uplink {
+        Uplink {
+            stream:        stream,
+            read_buffer:   Box::new([0u8; 32768]),
+            received:      0,
+            write_buffer:  Box::new([0u8; 16384]),
+            output_buffer: WriteBuffer::new(UPLINK_BUFFER_SIZE),
+            write_tout:    Timeout::new(),
+            write_timeout: UPLINK_WRITE_TIMEOUT,
+            #[cfg(feature = "websocket")]
+            websocket:     None
+        }
+    }
+
+    /// Set time limit for writing pending data (in milliseconds).
+    pub fn set_write_timeout(&mut self, timeout: u64) {
+        self.write_timeout = timeout;
+    }
+
+    /// Set capacity of the output buffer (in bytes). The buffer can be
+    /// resized only while it is empty.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        if self.output_buffer.is_empty() {
+            self.output_buffer = WriteBuffer::new(size);
+        }
+    }
+
+    /// Check if the output buffer is full.
+    pub fn is_full(&self) -> bool {
+        self.output_buffer.is_full()
+    }
+
+    /// Get number of bytes waiting in the output buffer.
+    pub fn buffered(&self) -> usize {
+        self.output_buffer.buffered()
+    }
+
+    /// Get capacity of the output buffer.
+    pub fn capacity(&self) -> usize {
+        self.output_buffer.capacity()
+    }
+
+    /// Take the output buffer high-water mark. The mark is reset to the
+    /// current number of buffered bytes.
+    pub fn take_high_water(&mut self) -> usize {
+        let res = self.output_buffer.high_water();
+
+        self.output_buffer.reset_high_water();
+
+        res
+    }
+
+    /// Check if the write timeout has not expired yet.
+    pub fn check_timeout(&self) -> bool {
+        self.write_tout.check()
+    }
+
+    /// Check if the underlaying socket is ready to read.
+    pub fn can_read(&self, event_set: EventSet) -> bool {
+        self.stream.can_read(event_set)
+    }
+
+    /// Check if the underlaying socket is ready to write.
+    pub fn can_write(&self, event_set: EventSet) -> bool {
+        self.stream.can_write(event_set)
+    }
+
+    /// Enable receiving writable events for the underlaying socket.
+    pub fn enable_write_events<H: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<H>) {
+        self.stream.set_want_write(true, event_loop);
+    }
+
+    /// Temporarily stop receiving events for the underlaying socket.
+    pub fn suspend<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) {
+        self.stream.suspend(event_loop);
+    }
+
+    /// Start receiving events for the underlaying socket again.
+    pub fn resume<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) {
+        self.stream.resume(event_loop);
+    }
+
+    /// Put a given Arrow Message into the output buffer without enabling
+    /// writable events.
+    pub fn buffer_message<B: ArrowMessageBody>(
+        &mut self,
+        arrow_msg: &ArrowMessage<B>) {
+        if self.output_buffer.is_empty() {
+            self.write_tout.set(self.write_timeout);
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(ref mut ws) = self.websocket {
+                let mut payload = Vec::new();
+
+                arrow_msg.serialize(&mut payload)
+                    .unwrap();
+
+                let frame = websocket::encode_frame(
+                    websocket::OPCODE_BINARY, &payload);
+
+                if ws.upgraded {
+                    self.output_buffer.write_all(&frame)
+                        .unwrap();
+                } else {
+                    ws.pending.extend_from_slice(&frame);
+                }
+
+                return;
+            }
+        }
+
+        arrow_msg.serialize(&mut self.output_buffer)
+            .unwrap();
+    }
+
+    /// Send a given Arrow Message.
+    pub fn send_message<B: ArrowMessageBody, H: Handler>(
+        &mut self,
+        arrow_msg: &ArrowMessage<B>,
+        event_loop: &mut EventLoop<H>) {
+        self.buffer_message(arrow_msg);
+        self.enable_write_events(event_loop);
+    }
+
+    /// Read available data from the underlaying socket and return the number
+    /// of bytes read. The data can be accessed using the received() method.
+    pub fn read<H: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<H>) -> Result<usize> {
+        let len = try_arr!(self.stream.read(
+            &mut *self.read_buffer, event_loop));
+
+        self.received = len;
+
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(ref mut ws) = self.websocket {
+                let (len, output_modified) = try_arr!(ws.process(
+                    &mut *self.read_buffer, len, &mut self.output_buffer));
+
+                if output_modified && !self.output_buffer.is_empty() {
+                    self.write_tout.set(self.write_timeout);
+                    self.stream.set_want_write(true, event_loop);
+                }
+
+                self.received = len;
+            }
+        }
+
+        Ok(self.received)
+    }
+
+    /// Get data received by the last read() call.
+    pub fn received(&self) -> &[u8] {
+        &self.read_buffer[..self.received]
+    }
+
+    /// Write a chunk of data from the output buffer into the underlaying
+    /// socket. Writable events are disabled if the output buffer is empty.
+    pub fn write<H: Handler>(
+        &mut self,
+        event_loop: &mut EventLoop<H>) -> Result<()> {
+        if self.output_buffer.is_empty() {
+            self.stream.set_want_write(false, event_loop);
+            self.write_tout.clear();
+        } else {
+            let len = {
+                let data   = self.output_buffer.as_bytes();
+                let len    = cmp::min(data.len(), self.write_buffer.len());
+                let buffer = &mut self.write_buffer[..len];
+                utils::memcpy(buffer, &data[..len]);
+                try_arr!(self.stream.write(buffer, event_loop))
+            };
+
+            if len > 0 {
+                self.write_tout.set(self.write_timeout);
+                self.output_buffer.drop(len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get socket error as an ArrowError.
+    pub fn socket_error(&self) -> ArrowError {
+        match self.stream.take_socket_error() {
+            Err(err) => ArrowError::connection_error(err),
+            Ok(_)    => ArrowError::connection_error("socket error expected")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use mio::EventSet;
+
+    use openssl::ssl::{SslContext, SslMethod};
+
+    /// Event handler ignoring all events.
+    struct DummyHandler;
+
+    impl Handler for DummyHandler {
+        type Timeout = ();
+        type Message = ();
+    }
+
+    /// Create a new uplink connected to a given listener. The TLS handshake
+    /// is never completed.
+    fn connect_uplink(
+        listener: &TcpListener,
+        event_loop: &mut EventLoop<DummyHandler>) -> Uplink {
+        let ssl_context = SslContext::new(SslMethod::Tlsv1_2)
+            .unwrap();
+        let addr = listener.local_addr()
+            .unwrap();
+
+        Uplink::connect(&ssl_context, &addr, 0, event_loop)
+            .unwrap()
+    }
+
+    /// Get length of a given serialized Arrow Message.
+    fn message_len(msg: &ArrowMessage<Vec<u8>>) -> usize {
+        let mut buffer = Vec::new();
+
+        msg.serialize(&mut buffer)
+            .unwrap();
+
+        buffer.len()
+    }
+
+    #[test]
+    fn test_uplink_buffering() {
+        let listener       = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut uplink     = connect_uplink(&listener, &mut event_loop);
+
+        assert_eq!(uplink.capacity(), UPLINK_BUFFER_SIZE);
+        assert_eq!(uplink.buffered(), 0);
+
+        uplink.set_buffer_size(64);
+        uplink.set_write_timeout(0);
+
+        assert_eq!(uplink.capacity(), 64);
+        assert!(!uplink.is_full());
+        assert!(uplink.check_timeout());
+
+        let msg = ArrowMessage::new(1, 2, vec![0u8; 16]);
+        let len = message_len(&msg);
+
+        uplink.buffer_message(&msg);
+
+        assert_eq!(uplink.buffered(), len);
+        assert!(!uplink.is_full());
+
+        // the buffer cannot be resized while it is not empty
+        uplink.set_buffer_size(128);
+
+        assert_eq!(uplink.capacity(), 64);
+
+        // the capacity is only a soft limit
+        uplink.buffer_message(&msg);
+        uplink.buffer_message(&msg);
+
+        assert_eq!(uplink.buffered(), 3 * len);
+        assert!(uplink.is_full());
+
+        assert_eq!(uplink.take_high_water(), 3 * len);
+        assert_eq!(uplink.take_high_water(), 3 * len);
+
+        // nothing has been written since the first message was buffered
+        thread::sleep(Duration::from_millis(10));
+
+        assert!(!uplink.check_timeout());
+    }
+
+    #[test]
+    fn test_uplink_write_readiness() {
+        let listener       = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut uplink     = connect_uplink(&listener, &mut event_loop);
+
+        // writable events are disabled if there is nothing to write
+        uplink.write(&mut event_loop)
+            .unwrap();
+
+        assert!(!uplink.can_write(EventSet::writable()));
+        assert!(uplink.check_timeout());
+
+        let msg = ArrowMessage::new(1, 2, vec![0u8; 16]);
+
+        // buffering a message does not enable writable events
+        uplink.buffer_message(&msg);
+
+        assert!(!uplink.can_write(EventSet::writable()));
+
+        uplink.enable_write_events(&mut event_loop);
+
+        assert!(uplink.can_write(EventSet::writable()));
+        assert!(!uplink.can_write(EventSet::readable()));
+
+        // sending a message enables writable events
+        let mut uplink = connect_uplink(&listener, &mut event_loop);
+
+        uplink.write(&mut event_loop)
+            .unwrap();
+        uplink.send_message(&msg, &mut event_loop);
+
+        assert!(uplink.can_write(EventSet::writable()));
+        assert_eq!(uplink.buffered(), message_len(&msg));
+    }
+
+    #[test]
+    fn test_renegotiation_states() {
+        let mut state = SslIoState::new();
+
+        assert_eq!(state.socket_events(), (true, true));
+
+        // nothing to write
+        state.want_write = false;
+
+        assert_eq!(state.socket_events(), (true, false));
+        assert!(!state.can_write(EventSet::writable()));
+
+        // the server requested renegotiation; the reader needs to send
+        // the handshake
+        state.reader = Some(Want::Write);
+
+        assert_eq!(state.socket_events(), (false, true));
+        assert!(state.can_read(EventSet::writable()));
+        assert!(!state.can_read(EventSet::readable()));
+
+        // the reader waits for the server handshake response; the writer
+        // must not be blocked by the reader
+        state.reader     = Some(Want::Read);
+        state.want_write = true;
+
+        assert_eq!(state.socket_events(), (true, true));
+        assert!(state.can_write(EventSet::writable()));
+
+        // the writer needs the server handshake response as well
+        state.writer = Some(Want::Read);
+
+        assert_eq!(state.socket_events(), (true, false));
+        assert!(state.can_read(EventSet::readable()));
+        assert!(state.can_write(EventSet::readable()));
+        assert!(!state.can_write(EventSet::writable()));
+
+        // both the reader and the writer are waiting for different events
+        state.reader = Some(Want::Write);
+
+        assert_eq!(state.socket_events(), (true, true));
+
+        // renegotiation completed
+        state.reader = None;
+        state.writer = None;
+
+        assert_eq!(state.socket_events(), (true, true));
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Service table version tracking.
+//!
+//! Versions of the service table are compared using serial number
+//! arithmetic, so that the comparison keeps working after the version
+//! counter wraps around. A version which is neither the same nor newer than
+//! the last sent one is considered a regression (e.g. the client state has
+//! been reset). The client reacts on a regression according to a
+//! configurable policy:
+//!
+//! * `Update` - send the whole service table in a new UPDATE message,
+//! * `Reconnect` - reconnect to Arrow Service (the table is sent again
+//!   during the handshake).
+
+use utils::RuntimeError;
+
+/// Result of a service table version check.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VersionChange {
+    /// The version is the same as the last sent one.
+    Unchanged,
+    /// The version is newer than the last sent one.
+    Newer,
+    /// The version is older than the last sent one.
+    Regressed,
+}
+
+/// Compare a given version with the last sent one.
+pub fn compare(version: usize, last: usize) -> VersionChange {
+    let diff = version.wrapping_sub(last);
+
+    if diff == 0 {
+        VersionChange::Unchanged
+    } else if diff <= usize::max_value() / 2 {
+        VersionChange::Newer
+    } else {
+        VersionChange::Regressed
+    }
+}
+
+/// Client action taken on a service table version regression.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegressionPolicy {
+    /// Send a full UPDATE message.
+    Update,
+    /// Reconnect to Arrow Service.
+    Reconnect,
+}
+
+impl RegressionPolicy {
+    /// Parse a given policy name ("update" or "reconnect").
+    pub fn from_name(name: &str) -> Result<RegressionPolicy, RuntimeError> {
+        match name {
+            "update"    => Ok(RegressionPolicy::Update),
+            "reconnect" => Ok(RegressionPolicy::Reconnect),
+            _ => Err(RuntimeError::from(format!("unknown version regression policy: {}", name)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_compare() {
+        assert_eq!(compare(5, 5), VersionChange::Unchanged);
+        assert_eq!(compare(6, 5), VersionChange::Newer);
+        assert_eq!(compare(4, 5), VersionChange::Regressed);
+        assert_eq!(compare(0, 5), VersionChange::Regressed);
+
+        // wrap-around
+        assert_eq!(compare(0, usize::max_value()), VersionChange::Newer);
+        assert_eq!(compare(1, usize::max_value() - 1), VersionChange::Newer);
+        assert_eq!(compare(usize::max_value(), 0), VersionChange::Regressed);
+    }
+
+    #[test]
+    fn test_regression_policy() {
+        assert_eq!(RegressionPolicy::from_name("update").unwrap(),
+            RegressionPolicy::Update);
+        assert_eq!(RegressionPolicy::from_name("reconnect").unwrap(),
+            RegressionPolicy::Reconnect);
+        assert!(RegressionPolicy::from_name("foo").is_err());
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebSocket transport for the Arrow Protocol (RFC 6455).
+//!
+//! Some networks allow only HTTP(S) traffic. The Arrow Protocol messages can
+//! be carried in binary WebSocket frames over a TLS connection (WSS) in such
+//! case. The frame boundaries are not related to the Arrow Protocol message
+//! boundaries, the WebSocket connection is used as a plain byte stream.
+
+use std::cmp;
+use std::fmt;
+use std::result;
+
+use std::str::FromStr;
+
+use utils::RuntimeError;
+
+use net::arrow::error::{Result, ArrowError};
+
+use openssl::crypto::hash;
+use openssl::crypto::rand;
+
+use openssl::crypto::hash::Type as HashType;
+
+use regex::Regex;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+/// Default WSS port.
+const DEFAULT_PORT: u16 = 443;
+
+/// Default path of the Arrow Service WebSocket endpoint.
+const DEFAULT_PATH: &'static str = "/arrow";
+
+/// WebSocket subprotocol name.
+const SUBPROTOCOL: &'static str = "arrow";
+
+/// GUID used for computing the Sec-WebSocket-Accept value.
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of the handshake response header.
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+pub const OPCODE_CONTINUATION: u8 = 0x00;
+pub const OPCODE_TEXT:         u8 = 0x01;
+pub const OPCODE_BINARY:       u8 = 0x02;
+pub const OPCODE_CLOSE:        u8 = 0x08;
+pub const OPCODE_PING:         u8 = 0x09;
+pub const OPCODE_PONG:         u8 = 0x0a;
+
+/// Arrow connection transport.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Transport {
+    /// Plain TLS connection.
+    Tls,
+    /// WebSocket over TLS.
+    WebSocket,
+    /// Plain TLS connection falling back to WebSocket if the TLS connection
+    /// cannot be established.
+    Auto,
+}
+
+impl Transport {
+    /// Get transport with a given name ("tls", "wss" or "auto").
+    pub fn from_name(name: &str) -> Option<Transport> {
+        match name {
+            "tls"  => Some(Transport::Tls),
+            "wss"  => Some(Transport::WebSocket),
+            "auto" => Some(Transport::Auto),
+            _      => None
+        }
+    }
+}
+
+/// Arrow Service WebSocket endpoint.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WssEndpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WssEndpoint {
+    /// Parse a given URL ("wss://host[:port][/path]" format).
+    pub fn parse(url: &str) -> result::Result<WssEndpoint, RuntimeError> {
+        let re = Regex::new(r"^wss://([^/:\[\]]+|\[[0-9a-fA-F:.]+\])(?::([0-9]+))?(/.*)?$")
+            .unwrap();
+
+        let caps = match re.captures(url) {
+            Some(caps) => caps,
+            None => return Err(RuntimeError::from(
+                "invalid WebSocket URL, \"wss://host[:port][/path]\" expected"))
+        };
+
+        let port = match caps.at(2) {
+            Some(port) => match u16::from_str(port) {
+                Ok(port) => port,
+                Err(_)   => return Err(RuntimeError::from(
+                    "invalid WebSocket URL port"))
+            },
+            None => DEFAULT_PORT
+        };
+
+        let res = WssEndpoint {
+            host: caps.at(1).unwrap().to_string(),
+            port: port,
+            path: caps.at(3).unwrap_or("/").to_string()
+        };
+
+        Ok(res)
+    }
+
+    /// Get the default endpoint for a given Arrow Service address
+    /// ("host:port" format). The default endpoint uses the same host, the
+    /// standard HTTPS port and the "/arrow" path.
+    pub fn for_address(arrow_addr: &str) -> WssEndpoint {
+        let host = match arrow_addr.rfind(':') {
+            Some(pos) => &arrow_addr[..pos],
+            None      => arrow_addr
+        };
+
+        WssEndpoint {
+            host: host.to_string(),
+            port: DEFAULT_PORT,
+            path: DEFAULT_PATH.to_string()
+        }
+    }
+
+    /// Get endpoint address ("host:port" format).
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Get value of the Host header.
+    fn host_header(&self) -> String {
+        if self.port == DEFAULT_PORT {
+            self.host.clone()
+        } else {
+            self.address()
+        }
+    }
+}
+
+impl fmt::Display for WssEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "wss://{}{}", self.host_header(), self.path)
+    }
+}
+
+/// Generate a new random Sec-WebSocket-Key value.
+pub fn new_key() -> String {
+    rand::rand_bytes(16)
+        .to_base64(STANDARD)
+}
+
+/// Create the opening handshake request for a given endpoint and key.
+pub fn handshake_request(endpoint: &WssEndpoint, key: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {}\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Protocol: {}\r\n\
+        \r\n", endpoint.path, endpoint.host_header(), key, SUBPROTOCOL)
+        .into_bytes()
+}
+
+/// Get the expected Sec-WebSocket-Accept value for a given key.
+fn accept_key(key: &str) -> String {
+    let mut data = String::from(key);
+
+    data.push_str(ACCEPT_GUID);
+
+    hash::hash(HashType::SHA1, data.as_bytes())
+        .to_base64(STANDARD)
+}
+
+/// Parse the opening handshake response received so far. None is returned
+/// if the response header is not complete yet, otherwise the size of the
+/// header is returned.
+pub fn parse_handshake_response(data: &[u8], key: &str) -> Result<Option<usize>> {
+    let end = data.windows(4)
+        .position(|w| w == b"\r\n\r\n");
+
+    let end = match end {
+        Some(pos) => pos + 4,
+        None if data.len() > MAX_RESPONSE_SIZE => return Err(
+            ArrowError::connection_error("WebSocket handshake response too long")),
+        None => return Ok(None)
+    };
+
+    let header = String::from_utf8_lossy(&data[..end]);
+
+    let mut lines = header.split("\r\n");
+
+    let status = lines.next()
+        .unwrap_or("");
+
+    let mut parts = status.split(' ');
+
+    let version = parts.next().unwrap_or("");
+    let code    = parts.next().unwrap_or("");
+
+    if !version.starts_with("HTTP/1.") || code != "101" {
+        return Err(ArrowError::connection_error(
+            format!("WebSocket handshake rejected: {}", status)));
+    }
+
+    let expected = accept_key(key);
+
+    let accepted = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let name  = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            if name.to_lowercase() == "sec-websocket-accept" {
+                Some(value)
+            } else {
+                None
+            }
+        })
+        .any(|value| value == expected);
+
+    if accepted {
+        Ok(Some(end))
+    } else {
+        Err(ArrowError::connection_error("invalid WebSocket handshake response"))
+    }
+}
+
+/// Create a new masked client frame with a given opcode and payload.
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = rand::rand_bytes(4);
+
+    encode_frame_with_mask(opcode, payload, [mask[0], mask[1], mask[2], mask[3]])
+}
+
+/// Create a new frame with a given opcode, payload and mask.
+fn encode_frame_with_mask(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let len = payload.len();
+
+    let mut res = Vec::with_capacity(len + 14);
+
+    res.push(0x80 | opcode);
+
+    if len < 126 {
+        res.push(0x80 | len as u8);
+    } else if len < 65536 {
+        res.push(0x80 | 126);
+        res.push((len >> 8) as u8);
+        res.push(len as u8);
+    } else {
+        res.push(0x80 | 127);
+        for i in 0..8 {
+            res.push(((len as u64) >> (56 - 8 * i)) as u8);
+        }
+    }
+
+    res.extend_from_slice(&mask);
+
+    for (i, b) in payload.iter().enumerate() {
+        res.push(*b ^ mask[i & 3]);
+    }
+
+    res
+}
+
+/// Streaming decoder of server frames.
+///
+/// Payload of data frames is passed on as soon as it arrives, so the decoded
+/// data are never longer than the input. Payload of control frames is
+/// collected until the frame is complete.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    /// Incomplete frame header.
+    header:    Vec<u8>,
+    /// Opcode of the current frame.
+    opcode:    u8,
+    /// Remaining payload bytes of the current frame.
+    remaining: u64,
+    /// Masking key of the current frame (if any).
+    mask:      Option<[u8; 4]>,
+    /// Payload offset within the current frame.
+    offset:    usize,
+    /// Payload of the current control frame.
+    control:   Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Create a new frame decoder.
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            header:    Vec::new(),
+            opcode:    OPCODE_BINARY,
+            remaining: 0,
+            mask:      None,
+            offset:    0,
+            control:   Vec::new()
+        }
+    }
+
+    /// Decode given input. Payload of data frames is appended to a given
+    /// buffer, completed control frames (opcode and payload) are appended to
+    /// a given vector.
+    pub fn decode(
+        &mut self,
+        mut input: &[u8],
+        data: &mut Vec<u8>,
+        control: &mut Vec<(u8, Vec<u8>)>) -> Result<()> {
+        while !input.is_empty() {
+            if self.header.is_empty() && self.remaining > 0 {
+                let len = cmp::min(self.remaining, input.len() as u64) as usize;
+
+                for b in &input[..len] {
+                    let b = match self.mask {
+                        Some(ref mask) => *b ^ mask[self.offset & 3],
+                        None           => *b
+                    };
+
+                    if self.opcode & 0x08 == 0 {
+                        data.push(b);
+                    } else {
+                        self.control.push(b);
+                    }
+
+                    self.offset += 1;
+                }
+
+                self.remaining -= len as u64;
+
+                input = &input[len..];
+            } else {
+                self.header.push(input[0]);
+
+                input = &input[1..];
+
+                try!(self.process_header());
+            }
+
+            if self.header.is_empty()
+                && self.remaining == 0
+                && self.opcode & 0x08 != 0 {
+                control.push((self.opcode, self.control.clone()));
+                self.control.clear();
+                self.opcode = OPCODE_BINARY;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process the current frame header if it is complete.
+    fn process_header(&mut self) -> Result<()> {
+        if self.header.len() < 2 {
+            return Ok(());
+        }
+
+        let masked  = self.header[1] & 0x80 != 0;
+        let len_ext = match self.header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _   => 0
+        };
+
+        let mask_len = if masked { 4 } else { 0 };
+
+        if self.header.len() < (2 + len_ext + mask_len) {
+            return Ok(());
+        }
+
+        let opcode = self.header[0] & 0x0f;
+
+        let mut len = 0u64;
+
+        if len_ext == 0 {
+            len = (self.header[1] & 0x7f) as u64;
+        } else {
+            for b in &self.header[2..2 + len_ext] {
+                len = (len << 8) | (*b as u64);
+            }
+        }
+
+        self.mask = if masked {
+            let m = &self.header[2 + len_ext..];
+            Some([m[0], m[1], m[2], m[3]])
+        } else {
+            None
+        };
+
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_BINARY => (),
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if len > 125 {
+                    return Err(ArrowError::connection_error(
+                        "invalid WebSocket control frame"));
+                }
+            },
+            OPCODE_TEXT => return Err(ArrowError::connection_error(
+                "unexpected WebSocket text frame")),
+            _ => return Err(ArrowError::connection_error(
+                "unknown WebSocket frame opcode"))
+        }
+
+        self.header.clear();
+
+        self.opcode    = opcode;
+        self.remaining = len;
+        self.offset    = 0;
+
+        // empty control frames are reported by decode()
+        if len == 0 && opcode & 0x08 == 0 {
+            self.opcode = OPCODE_BINARY;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{accept_key, encode_frame_with_mask};
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint = WssEndpoint::parse("wss://arrow.local").unwrap();
+
+        assert_eq!(endpoint.address(), "arrow.local:443");
+        assert_eq!(endpoint.to_string(), "wss://arrow.local/");
+
+        let endpoint = WssEndpoint::parse("wss://[::1]:8443/ws/arrow").unwrap();
+
+        assert_eq!(endpoint.address(), "[::1]:8443");
+        assert_eq!(endpoint.to_string(), "wss://[::1]:8443/ws/arrow");
+
+        assert_eq!(WssEndpoint::for_address("arrow.local:8900").to_string(),
+            "wss://arrow.local/arrow");
+
+        assert!(WssEndpoint::parse("https://arrow.local").is_err());
+        assert!(WssEndpoint::parse("wss://arrow.local:99999").is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        // example from RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+
+        assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let endpoint = WssEndpoint::parse("wss://arrow.local:8443/arrow").unwrap();
+        let request  = String::from_utf8(handshake_request(&endpoint, key)).unwrap();
+
+        assert!(request.starts_with("GET /arrow HTTP/1.1\r\nHost: arrow.local:8443\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            \r\n\x82\x00";
+
+        assert_eq!(parse_handshake_response(&response[..20], key).unwrap(), None);
+        assert_eq!(parse_handshake_response(response, key).unwrap(),
+            Some(response.len() - 2));
+
+        assert!(parse_handshake_response(b"HTTP/1.1 403 Forbidden\r\n\r\n", key)
+            .is_err());
+        assert!(parse_handshake_response(b"HTTP/1.1 101 OK\r\nSec-WebSocket-Accept: foo\r\n\r\n", key)
+            .is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let mask = [1, 2, 3, 4];
+
+        assert_eq!(encode_frame_with_mask(OPCODE_BINARY, &[1, 2, 3, 4, 5], mask),
+            vec![0x82, 0x85, 1, 2, 3, 4, 0, 0, 0, 0, 4]);
+
+        let frame = encode_frame_with_mask(OPCODE_BINARY, &[0; 300], mask);
+
+        assert_eq!(&frame[..4], &[0x82, 0xfe, 0x01, 0x2c]);
+        assert_eq!(frame.len(), 308);
+
+        let frame = encode_frame_with_mask(OPCODE_BINARY, &[0; 70000], mask);
+
+        assert_eq!(&frame[..10], &[0x82, 0xff, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+
+        let mut decoder = FrameDecoder::new();
+        let mut data    = Vec::new();
+        let mut control = Vec::new();
+
+        let input = [
+            0x02, 0x03, 1, 2, 3,        // binary, not final
+            0x89, 0x01, 9,              // ping
+            0x80, 0x02, 4, 5,           // final continuation
+            0x82, 0x82, 1, 1, 1, 1, 7, 7 // masked binary
+        ];
+
+        // split the input at every possible position
+        for i in 0..input.len() {
+            decoder.decode(&input[..i], &mut data, &mut control).unwrap();
+            decoder.decode(&input[i..], &mut data, &mut control).unwrap();
+
+            assert_eq!(data, vec![1, 2, 3, 4, 5, 6, 6]);
+            assert_eq!(control, vec![(OPCODE_PING, vec![9])]);
+
+            data.clear();
+            control.clear();
+        }
+
+        decoder.decode(&[0x88, 0x00], &mut data, &mut control).unwrap();
+
+        assert_eq!(control, vec![(OPCODE_CLOSE, vec![])]);
+
+        assert!(FrameDecoder::new().decode(&[0x81, 0x00], &mut data, &mut control).is_err());
+        assert!(FrameDecoder::new().decode(&[0x89, 0x7e, 0, 200], &mut data, &mut control).is_err());
+    }
+}
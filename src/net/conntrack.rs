@@ -0,0 +1,514 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection tracking cleanup.
+//!
+//! Finished service connections stay in the kernel connection tracking table
+//! for a while (e.g. TCP connections in the TIME_WAIT state). Sites with
+//! thousands of short sessions can exhaust the NAT state of the router this
+//! way. The entries can be removed using the ctnetlink interface right after
+//! the session is closed. The CAP_NET_ADMIN capability is required.
+//!
+//! The entries are removed by a background thread in batches, so that the
+//! caller is never blocked waiting for the kernel.
+
+use std::io;
+use std::mem;
+use std::thread;
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver};
+
+use utils::endian;
+
+use libc;
+
+/// Netlink protocol of the netfilter subsystems.
+const NETLINK_NETFILTER: libc::c_int = 12;
+
+const NLM_F_REQUEST: u16 = 0x0001;
+const NLM_F_ACK:     u16 = 0x0004;
+
+const NLMSG_ERROR: u16 = 0x0002;
+
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_DELETE:  u16 = 2;
+
+const NLA_F_NESTED: u16 = 0x8000;
+
+const CTA_TUPLE_ORIG:  u16 = 1;
+const CTA_TUPLE_IP:    u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_IP_V6_SRC: u16 = 3;
+const CTA_IP_V6_DST: u16 = 4;
+
+const CTA_PROTO_NUM:      u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+
+/// Size of the netlink message header.
+const NLMSG_HEADER_SIZE: usize = 16;
+
+/// Time limit for receiving the kernel response (in milliseconds).
+const RESPONSE_TIMEOUT: u64 = 1000;
+
+/// Maximum number of entries removed using a single netlink request.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// Transport protocol of a tracked connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// Get IP protocol number.
+    fn number(&self) -> u8 {
+        match self {
+            &Protocol::Tcp => 6,
+            &Protocol::Udp => 17
+        }
+    }
+}
+
+/// Original direction tuple of a tracked connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConnectionTuple {
+    /// Transport protocol.
+    pub protocol: Protocol,
+    /// Source (i.e. local) address.
+    pub src:      SocketAddr,
+    /// Destination (i.e. service) address.
+    pub dst:      SocketAddr,
+}
+
+/// Background remover of connection tracking entries.
+pub struct Flusher {
+    requests: Sender<(u32, ConnectionTuple)>,
+    errors:   Receiver<(u32, io::Error)>,
+}
+
+impl Flusher {
+    /// Start a new worker thread. The thread exits once the flusher is
+    /// dropped.
+    pub fn start() -> Flusher {
+        let (requests, request_rx) = mpsc::channel();
+        let (error_tx, errors)     = mpsc::channel();
+
+        thread::spawn(move || flusher_thread(request_rx, error_tx));
+
+        Flusher {
+            requests: requests,
+            errors:   errors
+        }
+    }
+
+    /// Queue removal of the connection tracking entry of a given session
+    /// connection.
+    pub fn flush(&self, session_id: u32, tuple: ConnectionTuple) {
+        // the worker thread lives as long as the flusher
+        self.requests.send((session_id, tuple))
+            .unwrap();
+    }
+
+    /// Take errors of the already processed requests (as pairs of the
+    /// session ID and the error).
+    pub fn take_errors(&self) -> Vec<(u32, io::Error)> {
+        self.errors.try_iter()
+            .collect()
+    }
+}
+
+/// Flusher worker thread. All queued requests are processed using a single
+/// netlink socket.
+fn flusher_thread(
+    requests: Receiver<(u32, ConnectionTuple)>,
+    errors: Sender<(u32, io::Error)>) {
+    while let Ok(request) = requests.recv() {
+        let mut batch = vec![request];
+
+        while batch.len() < MAX_BATCH_SIZE {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_)      => break
+            }
+        }
+
+        let tuples = batch.iter()
+            .map(|&(_, tuple)| tuple)
+            .collect::<Vec<_>>();
+
+        let results = match flush_batch(&tuples) {
+            Ok(results) => results,
+            Err(err) => tuples.iter()
+                .map(|_| Err(io::Error::new(err.kind(), err.to_string())))
+                .collect()
+        };
+
+        for (&(session_id, _), res) in batch.iter().zip(results) {
+            if let Err(err) = res {
+                // the flusher has been dropped
+                if errors.send((session_id, err)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Remove connection tracking entries of given connections. Missing entries
+/// are not considered to be an error. A result is returned for every given
+/// connection.
+fn flush_batch(tuples: &[ConnectionTuple]) -> io::Result<Vec<io::Result<()>>> {
+    let socket = try!(NetlinkSocket::open());
+
+    let mut request = Vec::new();
+    let mut results = Vec::with_capacity(tuples.len());
+    let mut pending = 0;
+
+    // sequence numbers are indices of the results (starting from 1)
+    for (index, tuple) in tuples.iter().enumerate() {
+        match delete_request(index as u32 + 1, tuple) {
+            Ok(msg) => {
+                request.extend_from_slice(&msg);
+                results.push(None);
+                pending += 1;
+            },
+            Err(err) => results.push(Some(Err(err)))
+        }
+    }
+
+    if pending > 0 {
+        try!(socket.send(&request));
+    }
+
+    let mut buffer = [0u8; 8192];
+
+    while pending > 0 {
+        let len = try!(socket.recv(&mut buffer));
+
+        for (seq, res) in parse_acks(&buffer[..len]) {
+            let index = (seq as usize).wrapping_sub(1);
+
+            if index < results.len() && results[index].is_none() {
+                results[index] = Some(res);
+                pending -= 1;
+            }
+        }
+    }
+
+    let res = results.into_iter()
+        .map(|res| res.unwrap())
+        .collect();
+
+    Ok(res)
+}
+
+/// Create a conntrack DELETE request for a given connection.
+fn delete_request(seq: u32, tuple: &ConnectionTuple) -> io::Result<Vec<u8>> {
+    let mut ip = Vec::new();
+
+    let family = match (&tuple.src, &tuple.dst) {
+        (&SocketAddr::V4(ref src), &SocketAddr::V4(ref dst)) => {
+            push_attribute(&mut ip, CTA_IP_V4_SRC, &src.ip().octets());
+            push_attribute(&mut ip, CTA_IP_V4_DST, &dst.ip().octets());
+            libc::AF_INET
+        },
+        (&SocketAddr::V6(ref src), &SocketAddr::V6(ref dst)) => {
+            push_attribute(&mut ip, CTA_IP_V6_SRC, &src.ip().octets());
+            push_attribute(&mut ip, CTA_IP_V6_DST, &dst.ip().octets());
+            libc::AF_INET6
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "address family mismatch"))
+    };
+
+    let mut proto = Vec::new();
+
+    push_attribute(&mut proto, CTA_PROTO_NUM, &[tuple.protocol.number()]);
+    push_attribute(&mut proto, CTA_PROTO_SRC_PORT,
+        &endian::u16_to_bytes(tuple.src.port()));
+    push_attribute(&mut proto, CTA_PROTO_DST_PORT,
+        &endian::u16_to_bytes(tuple.dst.port()));
+
+    let mut orig = Vec::new();
+
+    push_attribute(&mut orig, CTA_TUPLE_IP | NLA_F_NESTED, &ip);
+    push_attribute(&mut orig, CTA_TUPLE_PROTO | NLA_F_NESTED, &proto);
+
+    // nfgenmsg (family, version and resource ID)
+    let mut payload = vec![family as u8, 0, 0, 0];
+
+    push_attribute(&mut payload, CTA_TUPLE_ORIG | NLA_F_NESTED, &orig);
+
+    let msg_type = (NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_DELETE;
+    let msg_len  = NLMSG_HEADER_SIZE + payload.len();
+
+    let mut res = Vec::with_capacity(msg_len);
+
+    res.extend_from_slice(&endian::u32_to_native_bytes(msg_len as u32));
+    res.extend_from_slice(&endian::u16_to_native_bytes(msg_type));
+    res.extend_from_slice(&endian::u16_to_native_bytes(NLM_F_REQUEST | NLM_F_ACK));
+    res.extend_from_slice(&endian::u32_to_native_bytes(seq));
+    res.extend_from_slice(&endian::u32_to_native_bytes(0));
+    res.extend_from_slice(&payload);
+
+    Ok(res)
+}
+
+/// Append a netlink attribute (including padding) into a given buffer.
+fn push_attribute(buffer: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    let len = 4 + data.len();
+
+    buffer.extend_from_slice(&endian::u16_to_native_bytes(len as u16));
+    buffer.extend_from_slice(&endian::u16_to_native_bytes(attr_type));
+    buffer.extend_from_slice(data);
+
+    while (buffer.len() & 3) != 0 {
+        buffer.push(0);
+    }
+}
+
+/// Parse all netlink ACK messages in a given buffer. Pairs of the sequence
+/// number and the result are returned.
+fn parse_acks(data: &[u8]) -> Vec<(u32, io::Result<()>)> {
+    let mut res    = Vec::new();
+    let mut offset = 0;
+
+    while (data.len() - offset) >= NLMSG_HEADER_SIZE {
+        let msg = &data[offset..];
+        let len = endian::get_native_u32(msg) as usize;
+
+        if len < NLMSG_HEADER_SIZE || len > msg.len() {
+            break;
+        }
+
+        let seq = endian::get_native_u32(&msg[8..]);
+
+        res.push((seq, parse_ack(&msg[..len])));
+
+        offset += (len + 3) & !3;
+
+        if offset > data.len() {
+            break;
+        }
+    }
+
+    res
+}
+
+/// Parse a given netlink ACK message.
+fn parse_ack(msg: &[u8]) -> io::Result<()> {
+    if msg.len() < (NLMSG_HEADER_SIZE + 4)
+        || endian::get_native_u16(&msg[4..]) != NLMSG_ERROR {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "unexpected netlink response"));
+    }
+
+    let err = -(endian::get_native_u32(&msg[NLMSG_HEADER_SIZE..]) as i32);
+
+    if err == 0 || err == libc::ENOENT {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(err))
+    }
+}
+
+/// Netfilter netlink socket.
+struct NetlinkSocket {
+    fd: libc::c_int,
+}
+
+impl NetlinkSocket {
+    /// Open a new netfilter netlink socket.
+    fn open() -> io::Result<NetlinkSocket> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_NETFILTER)
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let res = NetlinkSocket {
+            fd: fd
+        };
+
+        let timeout = libc::timeval {
+            tv_sec:  (RESPONSE_TIMEOUT / 1000) as libc::time_t,
+            tv_usec: ((RESPONSE_TIMEOUT % 1000) * 1000) as libc::suseconds_t
+        };
+
+        let ret = unsafe {
+            libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res)
+        }
+    }
+
+    /// Send a given message to the kernel.
+    fn send(&self, msg: &[u8]) -> io::Result<()> {
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+
+        let ret = unsafe {
+            libc::sendto(self.fd,
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Receive a message from the kernel.
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::recv(self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{delete_request, parse_ack, parse_acks};
+
+    use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+    use utils::endian;
+
+    #[test]
+    fn test_delete_request() {
+        let tuple = ConnectionTuple {
+            protocol: Protocol::Tcp,
+            src:      SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(192, 168, 1, 1), 40000)),
+            dst:      SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(192, 168, 1, 10), 554))
+        };
+
+        let msg = delete_request(7, &tuple).unwrap();
+
+        // header (16), nfgenmsg (4), orig tuple (4), IP tuple (4 + 2 * 8),
+        // proto tuple (4 + 3 * 8)
+        assert_eq!(msg.len(), 72);
+        assert_eq!(endian::get_native_u32(&msg), 72);
+        assert_eq!(endian::get_native_u16(&msg[4..]), 0x0102);
+        assert_eq!(endian::get_native_u32(&msg[8..]), 7);
+        assert_eq!(msg[16], 2);
+
+        // source address and destination port
+        assert_eq!(&msg[32..36], &[192, 168, 1, 1]);
+        assert_eq!(&msg[68..70], &[0x02, 0x2a]);
+
+        let tuple = ConnectionTuple {
+            protocol: Protocol::Udp,
+            src:      tuple.src,
+            dst:      SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 554, 0, 0))
+        };
+
+        assert!(delete_request(1, &tuple).is_err());
+    }
+
+    #[test]
+    fn test_ack_parsing() {
+        let mut msg = vec![0u8; 36];
+
+        msg[4..6].copy_from_slice(&endian::u16_to_native_bytes(2));
+
+        assert!(parse_ack(&msg).is_ok());
+
+        // ENOENT
+        msg[16..20].copy_from_slice(&endian::u32_to_native_bytes(-2i32 as u32));
+
+        assert!(parse_ack(&msg).is_ok());
+
+        // EPERM
+        msg[16..20].copy_from_slice(&endian::u32_to_native_bytes(-1i32 as u32));
+
+        assert!(parse_ack(&msg).is_err());
+        assert!(parse_ack(&msg[..16]).is_err());
+    }
+
+    #[test]
+    fn test_batch_ack_parsing() {
+        let mut data = Vec::new();
+
+        for seq in 1..4 {
+            let mut msg = vec![0u8; 36];
+
+            msg[0..4].copy_from_slice(&endian::u32_to_native_bytes(36));
+            msg[4..6].copy_from_slice(&endian::u16_to_native_bytes(2));
+            msg[8..12].copy_from_slice(&endian::u32_to_native_bytes(seq));
+
+            // EPERM
+            if seq == 2 {
+                msg[16..20].copy_from_slice(&endian::u32_to_native_bytes(-1i32 as u32));
+            }
+
+            data.extend_from_slice(&msg);
+        }
+
+        // truncated message
+        data.extend_from_slice(&[0u8; 8]);
+
+        let acks = parse_acks(&data);
+
+        assert_eq!(acks.len(), 3);
+        assert_eq!(acks.iter().map(|&(seq, _)| seq).collect::<Vec<_>>(),
+            vec![1, 2, 3]);
+        assert!(acks[0].1.is_ok());
+        assert!(acks[1].1.is_err());
+        assert!(acks[2].1.is_ok());
+    }
+}
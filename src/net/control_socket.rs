@@ -0,0 +1,775 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local control socket.
+//!
+//! The control socket is a Unix domain socket accepting simple text commands
+//! for debugging a running client. A client connects to the socket, sends a
+//! single command terminated by a new line and reads the response until the
+//! socket is closed. Command arguments are separated by spaces.
+//!
+//! The `monitor` command is an exception. It keeps the connection open and
+//! streams live client events (one event per line) until the peer closes it.
+//!
+//! A new client process can take over the listening socket of a running one
+//! (e.g. after a binary upgrade) using the internal `handover` command. The
+//! listening socket is passed to the new process as a file descriptor and
+//! the old process stops accepting new service sessions and exits once all
+//! its sessions are finished. The old process keeps its instance lock until
+//! it exits, so the new process cannot connect to Arrow Service while the
+//! old connection (with the same UUID) is still open.
+//!
+//! The socket file is accessible only by its owner and connections of
+//! processes running under other users (except root) are refused.
+
+use std::io;
+use std::fs;
+use std::mem;
+use std::thread;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use libc;
+
+use utils::clock;
+
+use utils::Shared;
+use utils::secret::Secret;
+use utils::config::{AppContext, DRAIN_TIMEOUT};
+use utils::logger::Logger;
+use utils::events::EventCategory;
+
+use net::dns::DnsCache;
+use net::service_url;
+use net::arrow::protocol::Service;
+
+#[cfg(feature = "recording")]
+use net::arrow::tap::TAP_LIMIT;
+
+#[cfg(feature = "discovery")]
+use net::discovery;
+
+/// Read timeout for incoming commands (in milliseconds).
+const COMMAND_TIMEOUT: u64 = 5000;
+
+/// Internal command used for taking over the control socket.
+const HANDOVER_COMMAND: &'static str = "handover";
+
+/// Command streaming live events.
+const MONITOR_COMMAND: &'static str = "monitor";
+
+/// Command adding a static service.
+const ADD_SERVICE_COMMAND: &'static str = "add-service";
+
+/// Control socket server.
+pub struct ControlSocket<L: Logger> {
+    logger:      L,
+    app_context: Shared<AppContext>,
+    handed_over: bool,
+}
+
+impl<L: 'static + Logger + Clone + Send> ControlSocket<L> {
+    /// Create a new control socket server.
+    pub fn new(logger: L, app_context: Shared<AppContext>) -> ControlSocket<L> {
+        ControlSocket {
+            logger:      logger,
+            app_context: app_context,
+            handed_over: false
+        }
+    }
+
+    /// Start listening on a given path. A stale socket file is removed and
+    /// the new one is made accessible only by its owner. Incoming
+    /// connections are handled in a background thread.
+    pub fn start<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            try!(fs::remove_file(path));
+        }
+
+        let listener = try!(UnixListener::bind(path));
+
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+
+        thread::spawn(move || self.accept_connections(listener));
+
+        Ok(())
+    }
+
+    /// Take over the listening socket of another client process listening
+    /// on a given path. The other process stops accepting new sessions and
+    /// exits once its current sessions are finished. Incoming connections
+    /// are handled in a background thread.
+    pub fn take_over<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let mut stream = try!(UnixStream::connect(path));
+
+        try!(stream.write_all(HANDOVER_COMMAND.as_bytes()));
+        try!(stream.write_all(b"\n"));
+
+        let fd = try!(recv_fd(&stream));
+
+        let listener = unsafe {
+            UnixListener::from_raw_fd(fd)
+        };
+
+        let mut response = String::new();
+
+        try!(stream.read_to_string(&mut response));
+
+        if response.starts_with("ERROR: ") {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                response[7..].trim().to_string()));
+        }
+
+        thread::spawn(move || self.accept_connections(listener));
+
+        Ok(())
+    }
+
+    /// Accept incoming connections. Commands are processed one by one. The
+    /// method returns once the socket has been handed over to another
+    /// process.
+    fn accept_connections(mut self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            let res = stream.and_then(|stream| {
+                self.handle_connection(stream, &listener)
+            });
+
+            if let Err(err) = res {
+                log_warn!(self.logger, "control socket error: {}", err);
+            }
+
+            if self.handed_over {
+                break;
+            }
+        }
+    }
+
+    /// Read a command from a given connection and send the response.
+    /// Connections of processes running under a different user (except
+    /// root) are refused.
+    fn handle_connection(
+        &mut self,
+        mut stream: UnixStream,
+        listener: &UnixListener) -> io::Result<()> {
+        let uid = try!(peer_uid(&stream));
+
+        if uid != 0 && uid != unsafe { libc::getuid() } {
+            log_warn!(self.logger, "control socket connection refused (peer UID: {})", uid);
+            return stream.write_all(b"ERROR: access denied\n");
+        }
+
+        let timeout = Duration::from_millis(COMMAND_TIMEOUT);
+
+        try!(stream.set_read_timeout(Some(timeout)));
+
+        let mut line = String::new();
+
+        {
+            let mut reader = BufReader::new(&mut stream);
+
+            try!(reader.read_line(&mut line));
+        }
+
+        // the command may contain a service URL with camera credentials
+        let command = Secret::new(line);
+
+        let line = command.expose();
+
+        if line.trim() == HANDOVER_COMMAND {
+            return self.handover(stream, listener);
+        }
+
+        if line.trim() == MONITOR_COMMAND || line.starts_with("monitor ") {
+            return self.monitor(stream, line[MONITOR_COMMAND.len()..].trim());
+        }
+
+        if line.trim() == ADD_SERVICE_COMMAND || line.starts_with("add-service ") {
+            return self.add_service(stream, line[ADD_SERVICE_COMMAND.len()..].trim());
+        }
+
+        let response = self.execute(line.trim());
+
+        stream.write_all(response.as_bytes())
+    }
+
+    /// Pass a given listening socket to another process connected using a
+    /// given stream and start draining sessions.
+    fn handover(
+        &mut self,
+        mut stream: UnixStream,
+        listener: &UnixListener) -> io::Result<()> {
+        log_info!(self.logger, "handing over the control socket to a new process...");
+
+        try!(send_fd(&stream, listener.as_raw_fd()));
+
+        self.handed_over = true;
+
+        // the instance lock is kept until this process exits (i.e. until
+        // its Arrow Service connection is closed), the new process waits
+        // for it before connecting
+        let sessions = {
+            let mut app_context = self.app_context.lock()
+                .unwrap();
+
+            app_context.drain_deadline = Some(clock::timestamp() + DRAIN_TIMEOUT);
+
+            app_context.active_sessions.len()
+        };
+
+        log_info!(self.logger, "control socket handed over, waiting for {} active sessions to finish (at most {} seconds)", sessions, DRAIN_TIMEOUT);
+
+        stream.write_all(format!("draining {} sessions\n", sessions).as_bytes())
+    }
+
+    /// Stream live events of given categories (all categories if there are
+    /// none) into a given connection. The events are written by a
+    /// background thread, so that other commands can be processed in the
+    /// meantime. The thread exits once a write fails (i.e. the peer has
+    /// closed the connection).
+    fn monitor(&mut self, mut stream: UnixStream, args: &str) -> io::Result<()> {
+        let mut categories = Vec::new();
+
+        for name in args.split_whitespace() {
+            match EventCategory::from_name(name) {
+                Ok(category) => categories.push(category),
+                Err(err) => return stream.write_all(
+                    format!("ERROR: {}\n", err).as_bytes())
+            }
+        }
+
+        let events = self.app_context.lock()
+            .unwrap()
+            .hooks
+            .events()
+            .subscribe(&categories);
+
+        log_debug!(self.logger, "control socket monitor connected");
+
+        thread::spawn(move || {
+            for event in events.iter() {
+                if writeln!(stream, "{}", event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Add a given service into the service table. Parsing the service URL
+    /// (i.e. resolving the host name) and probing the service may take a
+    /// while, so the command is executed by a background thread and the
+    /// response is written from there.
+    fn add_service(&mut self, mut stream: UnixStream, args: &str) -> io::Result<()> {
+        log_debug!(self.logger, "control socket command: {}", ADD_SERVICE_COMMAND);
+
+        let mut logger = self.logger.clone();
+
+        let app_context = self.app_context.clone();
+
+        // the URL may contain camera credentials
+        let args = Secret::new(args.to_string());
+
+        thread::spawn(move || {
+            let response = add_service(&mut logger, &app_context, args.expose());
+
+            if let Err(err) = stream.write_all(response.as_bytes()) {
+                log_warn!(logger, "control socket error: {}", err);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Execute a given command and return the response.
+    fn execute(&mut self, cmd: &str) -> String {
+        let mut parts = cmd.splitn(2, ' ');
+
+        let name = parts.next().unwrap_or("");
+        let args = parts.next().unwrap_or("").trim();
+
+        // log only the command name, arguments may contain credentials
+        log_debug!(self.logger, "control socket command: {}", name);
+
+        match name {
+            "help"           => help(),
+            "svc-table-diff" => self.svc_table_diff(),
+            "restore-svc-table" => self.restore_svc_table(),
+            "sessions"       => self.sessions(),
+            "svc-stats"      => self.svc_stats(),
+            "tap-session"    => self.tap_session(args),
+            "untap-session"  => self.untap_session(args),
+            _ => format!("ERROR: unknown command \"{}\"\n", name)
+        }
+    }
+
+    /// Swap the service table with the one replaced by the last service
+    /// table reset. The configuration is saved and the UPDATE message is
+    /// sent on the next service table check.
+    fn restore_svc_table(&mut self) -> String {
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        let app_context = &mut *app_context;
+
+        if !app_context.config.restore_service_table() {
+            return "ERROR: there is no previous service table\n".to_string();
+        }
+
+        app_context.config.bump_version();
+
+        log_info!(self.logger, "previous service table restored using the control socket");
+
+        if let Some(ref file) = app_context.config_file {
+            if let Err(err) = app_context.config.save(file) {
+                return format!("service table restored (unable to save config file \"{}\": {})\n", file, err);
+            }
+        }
+
+        "service table restored\n".to_string()
+    }
+
+    /// List active sessions (sessions with a tap are marked with "*").
+    fn sessions(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        let mut res = String::new();
+
+        for &session_id in &app_context.active_sessions {
+            if app_context.is_tapped(session_id) {
+                res.push_str(&format!("{:08x} *\n", session_id));
+            } else {
+                res.push_str(&format!("{:08x}\n", session_id));
+            }
+        }
+
+        if res.is_empty() {
+            res.push_str("no active sessions\n");
+        }
+
+        res
+    }
+
+    /// List connection statistics of all services.
+    fn svc_stats(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        let mut res = String::new();
+
+        for stats in app_context.svc_stats.services() {
+            let dropped = stats.dropped();
+
+            res.push_str(&format!("{:04x} connected {}/{}, {} sessions (avg {} s), {} frames ({} bytes) dropped",
+                stats.svc_id(),
+                stats.connect_successes(),
+                stats.connect_attempts(),
+                stats.sessions(),
+                stats.avg_session_lifetime() / 1000,
+                dropped.frames,
+                dropped.bytes));
+
+            if let Some(err) = stats.last_error() {
+                res.push_str(&format!(", last error: {}", err.message));
+            }
+
+            res.push('\n');
+        }
+
+        if res.is_empty() {
+            res.push_str("no service statistics\n");
+        }
+
+        res
+    }
+
+    /// Attach a payload tap to a given session (session ID optionally
+    /// followed by the size limit in bytes).
+    #[cfg(feature = "recording")]
+    fn tap_session(&mut self, args: &str) -> String {
+        let mut args = args.split_whitespace();
+
+        let session_id = match args.next().map(parse_session_id) {
+            Some(Ok(id))  => id,
+            Some(Err(err)) => return format!("ERROR: {}\n", err),
+            None => return "ERROR: session ID expected\n".to_string()
+        };
+
+        let limit = match args.next().map(|limit| limit.parse::<u64>()) {
+            Some(Ok(limit)) if limit > 0 => limit,
+            Some(_) => return "ERROR: invalid size limit\n".to_string(),
+            None    => TAP_LIMIT
+        };
+
+        if let Some(arg) = args.next() {
+            return format!("ERROR: unexpected argument \"{}\"\n", arg);
+        }
+
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        let taps = &mut app_context.session_taps;
+
+        if !taps.is_active(session_id) {
+            return format!("ERROR: no active session {:08x}\n", session_id);
+        }
+
+        match taps.attach(session_id, limit) {
+            Ok(request) => format!("recording session {:08x} into {}-*.bin (up to {} bytes)\n", session_id, request.prefix(), limit),
+            Err(err) => format!("ERROR: unable to create tap directory \"{}\": {}\n", taps.dir(), err)
+        }
+    }
+
+    /// Attach a payload tap to a given session (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn tap_session(&mut self, _: &str) -> String {
+        "ERROR: session recording is not supported\n".to_string()
+    }
+
+    /// Detach the payload tap of a given session.
+    #[cfg(feature = "recording")]
+    fn untap_session(&mut self, args: &str) -> String {
+        let session_id = match parse_session_id(args) {
+            Ok(id)   => id,
+            Err(err) => return format!("ERROR: {}\n", err)
+        };
+
+        let mut app_context = self.app_context.lock()
+            .unwrap();
+
+        let taps = &mut app_context.session_taps;
+
+        if !taps.is_tapped(session_id) {
+            return format!("ERROR: session {:08x} is not being recorded\n", session_id);
+        }
+
+        taps.detach(session_id);
+
+        format!("recording of session {:08x} stopped\n", session_id)
+    }
+
+    /// Detach the payload tap of a given session (dummy).
+    #[cfg(not(feature = "recording"))]
+    fn untap_session(&mut self, _: &str) -> String {
+        "ERROR: session recording is not supported\n".to_string()
+    }
+
+    /// Get differences between the service table last sent to Arrow
+    /// Service and the current one.
+    fn svc_table_diff(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        match app_context.sent_svc_table {
+            Some(ref sent) => sent.diff(app_context.config.service_table())
+                .to_string(),
+            None => "no service table has been sent to Arrow Service yet\n"
+                .to_string()
+        }
+    }
+}
+
+/// Get list of supported commands.
+fn help() -> String {
+    let mut res = String::new();
+
+    res.push_str("help            show this help\n");
+    res.push_str("svc-table-diff  show differences between the service table last sent\n");
+    res.push_str("                to Arrow Service and the current one (\"+\" added,\n");
+    res.push_str("                \"-\" removed, \"~\" changed)\n");
+    res.push_str("add-service [--probe] url\n");
+    res.push_str("                add a given RTSP (rtsp://...) or MJPEG (http://...)\n");
+    res.push_str("                service into the service table; the service is kept\n");
+    res.push_str("                even if it is not found by the network scanner, use\n");
+    res.push_str("                --probe to check that the service is available first\n");
+    res.push_str("restore-svc-table\n");
+    res.push_str("                swap the service table with the one replaced by the\n");
+    res.push_str("                last service table reset (running the command again\n");
+    res.push_str("                reverts the restore)\n");
+    res.push_str("sessions        list active sessions (\"*\" marks recorded sessions)\n");
+    res.push_str("svc-stats       show connection statistics of all services (recent\n");
+    res.push_str("                connection attempts, sessions, dropped frames and the\n");
+    res.push_str("                last error)\n");
+
+    #[cfg(feature = "recording")]
+    {
+        res.push_str("tap-session session-id [max-bytes]\n");
+        res.push_str("                record payload of a given session in both directions\n");
+        res.push_str("                into files in the tap directory (at most max-bytes,\n");
+        res.push_str(&format!("                default: {})\n", TAP_LIMIT));
+        res.push_str("untap-session session-id\n");
+        res.push_str("                stop recording of a given session\n");
+    }
+
+    res.push_str("monitor [category...]\n");
+    res.push_str("                stream live events of given categories (connection,\n");
+    res.push_str("                session, scan, alert, warning; all by default) until\n");
+    res.push_str("                the connection is closed\n");
+
+    res
+}
+
+/// Add a given service (URL optionally preceded by "--probe") into the
+/// service table as a static service. The configuration is saved and
+/// the UPDATE message is sent on the next service table check.
+fn add_service<L: Logger>(
+    logger: &mut L,
+    app_context: &Shared<AppContext>,
+    args: &str) -> String {
+    let mut probe = false;
+    let mut url   = None;
+
+    for arg in args.split_whitespace() {
+        if arg == "--probe" {
+            probe = true;
+        } else if url.is_none() {
+            url = Some(arg);
+        } else {
+            return format!("ERROR: unexpected argument \"{}\"\n", arg);
+        }
+    }
+
+    let url = match url {
+        Some(url) => url,
+        None => return "ERROR: service URL expected\n".to_string()
+    };
+
+    let mut dns_cache = DnsCache::new();
+
+    let mut svc = match service_url::parse_service_url(url, &mut dns_cache) {
+        Ok(svc)  => svc,
+        Err(err) => return format!("ERROR: {}\n", err)
+    };
+
+    if probe {
+        svc = match probe_service(&svc) {
+            Ok(svc)  => svc,
+            Err(err) => return format!("ERROR: {}\n", err)
+        };
+    }
+
+    let mut app_context = app_context.lock()
+        .unwrap();
+
+    let app_context = &mut *app_context;
+
+    if app_context.config.service_table().contains(&svc) {
+        return "ERROR: the service is already in the service table\n"
+            .to_string();
+    }
+
+    let svc_id = app_context.config.add_static(svc)
+        .unwrap_or(0);
+
+    app_context.config.bump_version();
+
+    log_info!(logger, "service {} added using the control socket", svc_id);
+
+    if let Some(ref file) = app_context.config_file {
+        if let Err(err) = app_context.config.save(file) {
+            return format!("service {} added (unable to save config file \"{}\": {})\n", svc_id, file, err);
+        }
+    }
+
+    format!("service {} added\n", svc_id)
+}
+
+/// Parse a given hexadecimal session ID.
+#[cfg(feature = "recording")]
+fn parse_session_id(id: &str) -> Result<u32, String> {
+    if id.is_empty() {
+        return Err("session ID expected".to_string());
+    }
+
+    u32::from_str_radix(id, 16)
+        .map_err(|_| format!("invalid session ID \"{}\"", id))
+}
+
+/// Check that a given service is available.
+#[cfg(feature = "discovery")]
+fn probe_service(svc: &Service) -> Result<Service, String> {
+    discovery::probe_service(svc)
+        .map_err(|err| format!("{}", err))
+}
+
+/// Check that a given service is available (not supported without the
+/// discovery feature).
+#[cfg(not(feature = "discovery"))]
+fn probe_service(_: &Service) -> Result<Service, String> {
+    Err("service probing is not supported".to_string())
+}
+
+/// Send a given file descriptor over a given stream (a single data byte is
+/// sent together with the descriptor).
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let mut data = [0u8; 1];
+
+    let space = unsafe {
+        libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32)
+    } as usize;
+
+    let mut control = vec![0u8; space];
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  data.len()
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+
+    msg.msg_iov        = &mut iov;
+    msg.msg_iovlen     = 1;
+    msg.msg_control    = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let ret = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type  = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len   = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+
+        *(libc::CMSG_DATA(cmsg) as *mut RawFd) = fd;
+
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Get UID of the process connected using a given stream.
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len)
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(cred.uid)
+    }
+}
+
+/// Receive a file descriptor sent using send_fd() over a given stream.
+fn recv_fd(stream: &UnixStream) -> io::Result<RawFd> {
+    let mut data = [0u8; 1];
+
+    let space = unsafe {
+        libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32)
+    } as usize;
+
+    let mut control = vec![0u8; space];
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  data.len()
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+
+    msg.msg_iov        = &mut iov;
+    msg.msg_iovlen     = 1;
+    msg.msg_control    = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let ret = unsafe {
+        libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                "the control socket has not been handed over (the other process does not support it)"));
+        }
+
+        Ok(*(libc::CMSG_DATA(cmsg) as *const RawFd))
+    }
+}
+
+/// Stream live events of given categories from a control socket at a given
+/// path. Every event line is passed to a given closure. The function returns
+/// once the client closes the connection.
+pub fn monitor<P, F>(path: P, categories: &[String], mut f: F) -> io::Result<()>
+    where P: AsRef<Path>,
+          F: FnMut(&str) {
+    let mut stream = try!(UnixStream::connect(path));
+
+    let mut cmd = MONITOR_COMMAND.to_string();
+
+    for category in categories {
+        cmd.push(' ');
+        cmd.push_str(category);
+    }
+
+    try!(stream.write_all(cmd.as_bytes()));
+    try!(stream.write_all(b"\n"));
+
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = try!(line);
+
+        if line.starts_with("ERROR: ") {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                line[7..].to_string()));
+        }
+
+        f(&line);
+    }
+
+    Ok(())
+}
+
+/// Send a given command to a control socket at a given path and return the
+/// response.
+pub fn send_command<P: AsRef<Path>>(path: P, cmd: &str) -> io::Result<String> {
+    let mut stream = try!(UnixStream::connect(path));
+
+    try!(stream.write_all(cmd.as_bytes()));
+    try!(stream.write_all(b"\n"));
+
+    let mut response = String::new();
+
+    try!(stream.read_to_string(&mut response));
+
+    Ok(response)
+}
@@ -20,17 +20,21 @@ use std::thread;
 use std::result;
 
 use std::fs::File;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use std::error::Error;
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::io::{BufReader, BufRead};
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use net::http;
 use net::rtsp;
 use net::raw::pcap;
+use net::sap::MulticastStream;
 
 use net::http::Client as HttpClient;
 use net::http::ResponseHeader as HttpResponseHeader;
@@ -39,10 +43,15 @@ use net::raw::devices::EthernetDevice;
 use net::raw::ether::MacAddr;
 use net::raw::arp::scanner::Ipv4ArpScanner;
 use net::raw::icmp::scanner::IcmpScanner;
-use net::arrow::protocol::{Service, ScanReport};
-use net::arrow::protocol::{HINFO_FLAG_ARP, HINFO_FLAG_ICMP};
+use net::arrow::protocol::{Service, ScanReport, MediaInfo};
+use net::arrow::protocol::{HINFO_FLAG_ARP, HINFO_FLAG_ICMP, HINFO_FLAG_TCP};
 use net::raw::tcp::scanner::{TcpPortScanner, PortCollection};
-use net::rtsp::sdp::{SessionDescription, MediaType, RTPMap, FromAttribute};
+use net::rtsp::sdp::{SessionDescription, MediaDescription, MediaType};
+use net::rtsp::sdp::{RTPMap, FromAttribute};
+
+use mio::tcp::TcpStream;
+
+use time;
 
 /// Discovery error.
 #[derive(Debug, Clone)]
@@ -112,11 +121,122 @@ static HTTP_PORT_CANDIDATES: &'static [u16] = &[
        80,    81,  8080,  8081,  8090
 ];
 
+/// Maximum number of hosts probed at once by the TCP connect scanner.
+const TCP_CONNECT_BATCH_SIZE: usize = 16;
+
+/// Time given to the TCP connect scanner to establish connections (in
+/// milliseconds).
+const TCP_CONNECT_TIMEOUT: u64 = 1000;
+
+/// Prefix of fake MAC addresses assigned to hosts found by the TCP connect
+/// scanner.
+const TCP_CONNECT_MAC_PREFIX: u16 = 0xfffe;
+
+/// Probing budget shared by all operations of a single network scan.
+///
+/// Every probe (ARP/ICMP sweep or TCP port scan of a network, TCP connect
+/// batch, RTSP or HTTP request) takes one unit of the budget. Once the time
+/// limit passes or the probe limit is reached, all remaining probes are
+/// skipped and the scan completes with partial results, so that a single
+/// large unresponsive network cannot keep the scanner running for hours.
+#[derive(Clone)]
+pub struct ScanBudget {
+    /// Deadline (monotonic time in ns).
+    deadline:    Option<u64>,
+    /// Maximum number of probes.
+    probe_limit: Option<usize>,
+    /// Number of probes taken so far.
+    probes:      Arc<AtomicUsize>,
+    /// The budget has been exhausted.
+    exhausted:   Arc<AtomicBool>,
+}
+
+impl ScanBudget {
+    /// Create a new budget with a given time limit (in seconds) and
+    /// a given maximum number of probes.
+    pub fn new(time_limit: Option<u64>, probe_limit: Option<usize>) -> ScanBudget {
+        let deadline = time_limit
+            .map(|limit| time::precise_time_ns() + limit * 1000000000);
+
+        ScanBudget {
+            deadline:    deadline,
+            probe_limit: probe_limit,
+            probes:      Arc::new(AtomicUsize::new(0)),
+            exhausted:   Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Create a new unlimited budget.
+    pub fn unlimited() -> ScanBudget {
+        ScanBudget::new(None, None)
+    }
+
+    /// Take one probe from the budget. The method returns false if the
+    /// budget has been exhausted (i.e. the probe should be skipped).
+    pub fn acquire(&self) -> bool {
+        if self.exhausted.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let probes = self.probes.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let timeout = self.deadline
+            .map(|deadline| time::precise_time_ns() >= deadline)
+            .unwrap_or(false);
+
+        let too_many = self.probe_limit
+            .map(|limit| probes > limit)
+            .unwrap_or(false);
+
+        if timeout || too_many {
+            self.exhausted.store(true, Ordering::SeqCst);
+        }
+
+        !(timeout || too_many)
+    }
+
+    /// Check if the budget has been exhausted (i.e. at least one probe has
+    /// been skipped).
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+}
+
+/// Check if raw sockets are available (i.e. if the process is allowed to
+/// capture and inject packets on all local ethernet devices).
+///
+/// The network scanner falls back to a TCP connect scan on devices where raw
+/// sockets are not available.
+pub fn raw_sockets_available() -> bool {
+    let tc = pcap::new_threading_context();
+
+    EthernetDevice::list()
+        .iter()
+        .all(|dev| raw_sockets_available_on(tc.clone(), dev))
+}
+
+/// Check if raw sockets are available on a given device.
+fn raw_sockets_available_on(
+    pc: pcap::ThreadingContext,
+    device: &EthernetDevice) -> bool {
+    pcap::CaptureBuilder::new(pc, &device.name)
+        .and_then(|builder| builder.activate())
+        .is_ok()
+}
+
 /// Find all RTSP and MJPEG streams and corresponding HTTP services in all
-/// local networks.
-pub fn scan_network(
+/// local networks. Given multicast streams (announced using SAP) are added
+/// as UDP services. A given progress callback is called with the partial
+/// report once RTSP and MJPEG services are found. All probes are taken from
+/// a given budget; the report is marked as incomplete if the budget gets
+/// exhausted.
+pub fn scan_network<F>(
     rtsp_paths_file: &str,
-    mjpeg_paths_file: &str) -> Result<ScanReport> {
+    mjpeg_paths_file: &str,
+    multicast: &[MulticastStream],
+    budget: &ScanBudget,
+    mut progress: F) -> Result<ScanReport>
+    where F: FnMut(&ScanReport) {
     let mut port_set = HashSet::<u16>::new();
 
     port_set.extend(RTSP_PORT_CANDIDATES);
@@ -125,49 +245,145 @@ pub fn scan_network(
     let port_candidates = PortCollection::new()
         .add_all(port_set);
 
-    let mut report = try!(find_all_open_ports(&port_candidates));
+    let mut report = try!(find_all_open_ports(&port_candidates, budget));
 
     // note: we permit only one RTSP service per host (some stupid RTSP servers
     // are accessible from more than one port and they tend to crash when they
     // are accessed from the "incorrect" one)
-    let rtsp_ports = try!(find_rtsp_ports(&report, RTSP_PORT_CANDIDATES));
+    let rtsp_ports = try!(find_rtsp_ports(&report, RTSP_PORT_CANDIDATES, budget));
     let rtsp_port_priorities = get_port_priorities(RTSP_PORT_CANDIDATES);
     let rtsp_ports = filter_duplicit_services(
         &rtsp_ports,
         &rtsp_port_priorities);
 
     // note: we permit only one HTTP service per host
-    let http_ports = try!(find_http_ports(&report, HTTP_PORT_CANDIDATES));
+    let http_ports = try!(find_http_ports(&report, HTTP_PORT_CANDIDATES, budget));
     let http_port_priorities = get_port_priorities(HTTP_PORT_CANDIDATES);
     let http_ports = filter_duplicit_services(
         &http_ports,
         &http_port_priorities);
 
-    let rtsp_services  = try!(find_rtsp_services(rtsp_paths_file, &rtsp_ports));
-    let mjpeg_services = try!(find_mjpeg_services(mjpeg_paths_file, &http_ports));
+    // services are reported after every phase, so that they can get to
+    // Arrow Service before the whole scan finishes
+    let mut rtsp_services = Vec::new();
+
+    for (svc, basic, media) in try!(find_rtsp_services(rtsp_paths_file, &rtsp_ports, budget)) {
+        if let Some(addr) = svc.address().cloned() {
+            if basic {
+                report.add_basic_auth(addr);
+            }
+
+            if let Some(media) = media {
+                report.add_media_info(addr, media);
+            }
+        }
+
+        report.add_service(svc.clone());
+        rtsp_services.push(svc);
+    }
+
+    progress(&report);
+
+    let mut mjpeg_services = Vec::new();
+
+    for (svc, basic) in try!(find_mjpeg_services(mjpeg_paths_file, &http_ports, budget)) {
+        if let Some(addr) = svc.address().cloned() {
+            if basic {
+                report.add_basic_auth(addr);
+            }
+        }
+
+        report.add_service(svc.clone());
+        mjpeg_services.push(svc);
+    }
+
+    progress(&report);
 
     let mut hosts = Vec::new();
 
     hosts.extend(get_hosts(&rtsp_services));
     hosts.extend(get_hosts(&mjpeg_services));
 
-    let http_services = find_http_services(&http_ports, &hosts);
-
-    for svc in rtsp_services {
+    for svc in find_http_services(&http_ports, &hosts) {
         report.add_service(svc);
     }
 
-    for svc in mjpeg_services {
+    for svc in get_multicast_services(&report, multicast) {
         report.add_service(svc);
     }
 
-    for svc in http_services {
-        report.add_service(svc);
+    for stream in multicast {
+        report.add_multicast(stream.clone());
+    }
+
+    if budget.is_exhausted() {
+        report.set_incomplete();
     }
 
     Ok(report)
 }
 
+/// Create UDP services for given multicast streams. The MAC address of the
+/// announcing host is used if the host has been found by the scan.
+fn get_multicast_services(
+    report: &ScanReport,
+    multicast: &[MulticastStream]) -> Vec<Service> {
+    let mut res = Vec::new();
+
+    for stream in multicast {
+        let mac = report.hosts()
+            .find(|host| host.ip_addr == stream.origin)
+            .map(|host| host.mac_addr);
+
+        let mac = match (mac, stream.origin) {
+            (Some(mac), _) => mac,
+            (None, IpAddr::V4(origin)) => get_fake_mac_address(origin),
+            _ => continue
+        };
+
+        res.push(Service::UDP(mac, stream.group));
+    }
+
+    res
+}
+
+/// Probe a given manually added service and return its current form (e.g.
+/// an RTSP service may turn out to require authorization). An error is
+/// returned if the service is not available.
+pub fn probe_service(svc: &Service) -> Result<Service> {
+    match svc {
+        &Service::RTSP(mac, addr, ref path) => {
+            match try!(get_rtsp_describe_status(addr, path)) {
+                DescribeStatus::Ok =>
+                    Ok(svc.clone()),
+                DescribeStatus::Locked | DescribeStatus::LockedBasic =>
+                    Ok(Service::LockedRTSP(mac, addr)),
+                DescribeStatus::Unsupported =>
+                    Ok(Service::UnsupportedRTSP(mac, addr, path.clone())),
+                DescribeStatus::NotFound =>
+                    Err(DiscoveryError::from("RTSP stream not found")),
+                DescribeStatus::Error =>
+                    Err(DiscoveryError::from("RTSP service is not available"))
+            }
+        },
+        &Service::LockedRTSP(_, addr) => {
+            if try!(is_rtsp_service(addr)) {
+                Ok(svc.clone())
+            } else {
+                Err(DiscoveryError::from("RTSP service is not available"))
+            }
+        },
+        &Service::MJPEG(_, addr, _) | &Service::LockedMJPEG(_, addr) => {
+            if try!(is_http_service(addr)) {
+                Ok(svc.clone())
+            } else {
+                Err(DiscoveryError::from("HTTP service is not available"))
+            }
+        },
+        _ => Ok(svc.clone())
+    }
+}
+
 /// Load all path variants from a given file.
 fn load_paths(file: &str) -> Result<Vec<String>> {
     let file      = try!(File::open(file));
@@ -230,6 +446,7 @@ fn is_supported_rtsp_service(sdp: &[u8]) -> bool {
 enum DescribeStatus {
     Ok,
     Locked,
+    LockedBasic,
     Unsupported,
     NotFound,
     Error
@@ -239,6 +456,15 @@ enum DescribeStatus {
 fn get_rtsp_describe_status(
     addr: SocketAddr,
     path: &str) -> Result<DescribeStatus> {
+    describe_rtsp_service(addr, path)
+        .map(|(status, _)| status)
+}
+
+/// Get describe status for a given RTSP service and path together with
+/// media parameters of the stream (if the stream is available).
+fn describe_rtsp_service(
+    addr: SocketAddr,
+    path: &str) -> Result<(DescribeStatus, Option<MediaInfo>)> {
     let host = format!("{}", addr.ip());
     let port = addr.port();
 
@@ -246,7 +472,7 @@ fn get_rtsp_describe_status(
 
     // treat connection errors as DESCRIBE errors
     match RtspClient::new(&host, port) {
-        Err(_) => return Ok(DescribeStatus::Error),
+        Err(_) => return Ok((DescribeStatus::Error, None)),
         Ok(c)  => client = c
     }
 
@@ -261,20 +487,159 @@ fn get_rtsp_describe_status(
         };
 
         if hipcam && path != "/11" && path != "/12" {
-            Ok(DescribeStatus::NotFound)
+            Ok((DescribeStatus::NotFound, None))
         } else {
             match header.code {
-                404 => Ok(DescribeStatus::NotFound),
-                401 => Ok(DescribeStatus::Locked),
+                404 => Ok((DescribeStatus::NotFound, None)),
+                401 if is_basic_challenge(header.get_str("WWW-Authenticate")) =>
+                    Ok((DescribeStatus::LockedBasic, None)),
+                401 => Ok((DescribeStatus::Locked, None)),
                 200 if is_supported_rtsp_service(&response.body) =>
-                    Ok(DescribeStatus::Ok),
-                200 => Ok(DescribeStatus::Unsupported),
-                _   => Ok(DescribeStatus::Error)
+                    Ok((DescribeStatus::Ok, get_media_info(&response.body))),
+                200 => Ok((DescribeStatus::Unsupported, None)),
+                _   => Ok((DescribeStatus::Error, None))
             }
         }
     } else {
-        Ok(DescribeStatus::Error)
+        Ok((DescribeStatus::Error, None))
+    }
+}
+
+/// Get media parameters (video codec, resolution, frame rate and audio
+/// codec) of a stream with a given session description. None is returned
+/// if the session description cannot be parsed or if no parameters are
+/// known.
+fn get_media_info(sdp: &[u8]) -> Option<MediaInfo> {
+    let sdp = match SessionDescription::parse(sdp) {
+        Ok(sdp) => sdp,
+        Err(_)  => return None
+    };
+
+    let mut res = MediaInfo::new();
+
+    let video = sdp.media_descriptions.iter()
+        .find(|md| md.media_type == MediaType::Video);
+    let audio = sdp.media_descriptions.iter()
+        .find(|md| md.media_type == MediaType::Audio);
+
+    if let Some(md) = video {
+        res.codec      = get_media_encoding(md);
+        res.resolution = get_video_resolution(md);
+        res.fps        = get_video_frame_rate(md);
+    }
+
+    res.audio = match audio {
+        Some(md) => get_media_encoding(md),
+        None     => Some("none".to_string())
+    };
+
+    if res.is_empty() {
+        None
+    } else {
+        Some(res)
+    }
+}
+
+/// Get encoding of the first format of a given media description. Static
+/// RTP payload types are used if there is no rtpmap attribute.
+fn get_media_encoding(md: &MediaDescription) -> Option<String> {
+    let format = match md.formats.first() {
+        Some(format) => format,
+        None         => return None
+    };
+
+    for attr in &md.attributes {
+        if let Ok(rtpmap) = RTPMap::parse(attr) {
+            if rtpmap.payload_type.to_string() == *format {
+                return Some(rtpmap.encoding.to_uppercase());
+            }
+        }
+    }
+
+    let encoding = match format as &str {
+        "0"  => "PCMU",
+        "8"  => "PCMA",
+        "14" => "MPA",
+        "26" => "JPEG",
+        "32" => "MPV",
+        "34" => "H263",
+        _    => return None
+    };
+
+    Some(encoding.to_string())
+}
+
+/// Get value of the first attribute with a given name.
+fn get_attribute_value<'a>(md: &'a MediaDescription, name: &str) -> Option<&'a str> {
+    md.attributes.iter()
+        .find(|attr| attr.name.to_lowercase() == name)
+        .and_then(|attr| attr.value.as_ref())
+        .map(|value| value.trim())
+}
+
+/// Get video resolution from the framesize, x-dimensions or cliprect
+/// attribute of a given media description.
+fn get_video_resolution(md: &MediaDescription) -> Option<(u32, u32)> {
+    // a=framesize:<payload type> <width>-<height>
+    let framesize = get_attribute_value(md, "framesize")
+        .and_then(|value| value.split_whitespace().nth(1))
+        .map(|size| size.split('-').collect::<Vec<_>>());
+
+    // a=x-dimensions:<width>,<height>
+    let dimensions = get_attribute_value(md, "x-dimensions")
+        .map(|size| size.split(',').collect::<Vec<_>>());
+
+    let size = framesize.or(dimensions)
+        .and_then(|size| parse_numbers(&size));
+
+    if let Some(size) = size {
+        if size.len() == 2 {
+            return Some((size[0], size[1]));
+        }
+    }
+
+    // a=cliprect:<top>,<left>,<bottom>,<right>
+    let rect = get_attribute_value(md, "cliprect")
+        .and_then(|rect| parse_numbers(&rect.split(',').collect::<Vec<_>>()));
+
+    match rect {
+        Some(ref rect) if rect.len() == 4 && rect[3] > rect[1] && rect[2] > rect[0] =>
+            Some((rect[3] - rect[1], rect[2] - rect[0])),
+        _ => None
+    }
+}
+
+/// Get video frame rate from the framerate or x-framerate attribute of a
+/// given media description.
+fn get_video_frame_rate(md: &MediaDescription) -> Option<f64> {
+    get_attribute_value(md, "framerate")
+        .or(get_attribute_value(md, "x-framerate"))
+        .and_then(|fps| f64::from_str(fps).ok())
+        .and_then(|fps| if fps > 0.0 { Some(fps) } else { None })
+}
+
+/// Parse given decimal numbers.
+fn parse_numbers(items: &[&str]) -> Option<Vec<u32>> {
+    let mut res = Vec::new();
+
+    for item in items {
+        match u32::from_str(item.trim()) {
+            Ok(n)  => res.push(n),
+            Err(_) => return None
+        }
     }
+
+    Some(res)
+}
+
+/// Check if a given WWW-Authenticate header value asks for the Basic
+/// (i.e. plaintext) authentication.
+fn is_basic_challenge(challenge: Option<&str>) -> bool {
+    challenge.map_or(false, |challenge| {
+        challenge.trim()
+            .to_lowercase()
+            .starts_with("basic")
+    })
 }
 
 /// Check if a given service is an HTTP service.
@@ -315,7 +680,9 @@ fn get_http_response_header(
 
 /// Find open ports on all available hosts within all local networks accessible
 /// directly from this host.
-fn find_all_open_ports(ports: &PortCollection) -> Result<ScanReport> {
+fn find_all_open_ports(
+    ports: &PortCollection,
+    budget: &ScanBudget) -> Result<ScanReport> {
     let tc      = pcap::new_threading_context();
     let devices = EthernetDevice::list();
 
@@ -324,8 +691,9 @@ fn find_all_open_ports(ports: &PortCollection) -> Result<ScanReport> {
     for dev in devices {
         let pc     = ports.clone();
         let tc     = tc.clone();
+        let budget = budget.clone();
         let handle = thread::spawn(move || {
-            find_open_ports_in_network(tc, &dev, &pc)
+            find_open_ports_in_network(tc, &dev, &pc, &budget)
         });
 
         threads.push(handle);
@@ -349,15 +717,28 @@ fn find_all_open_ports(ports: &PortCollection) -> Result<ScanReport> {
 fn find_open_ports_in_network(
     pc: pcap::ThreadingContext,
     device: &EthernetDevice,
-    ports: &PortCollection) -> Result<ScanReport> {
+    ports: &PortCollection,
+    budget: &ScanBudget) -> Result<ScanReport> {
+    if !raw_sockets_available_on(pc.clone(), device) {
+        return Ok(find_open_ports_in_network_tcp(device, ports, budget));
+    }
+
     let mut report = ScanReport::new();
 
-    for (mac, ip) in try!(Ipv4ArpScanner::scan_device(pc.clone(), device)) {
-        report.add_host(mac, IpAddr::V4(ip), HINFO_FLAG_ARP);
+    if budget.acquire() {
+        for (mac, ip) in try!(Ipv4ArpScanner::scan_device(pc.clone(), device)) {
+            report.add_host(mac, IpAddr::V4(ip), HINFO_FLAG_ARP);
+        }
+    }
+
+    if budget.acquire() {
+        for (mac, ip) in try!(IcmpScanner::scan_device(pc.clone(), device)) {
+            report.add_host(mac, IpAddr::V4(ip), HINFO_FLAG_ICMP);
+        }
     }
 
-    for (mac, ip) in try!(IcmpScanner::scan_device(pc.clone(), device)) {
-        report.add_host(mac, IpAddr::V4(ip), HINFO_FLAG_ICMP);
+    if !budget.acquire() {
+        return Ok(report);
     }
 
     let open_ports = {
@@ -374,6 +755,118 @@ fn find_open_ports_in_network(
     Ok(report)
 }
 
+/// Find open ports on all available hosts within a given network using TCP
+/// connect scan. This method does not require raw sockets; however, it is
+/// slower and it cannot get MAC addresses of the hosts found.
+fn find_open_ports_in_network_tcp(
+    device: &EthernetDevice,
+    ports: &PortCollection,
+    budget: &ScanBudget) -> ScanReport {
+    let mut report = ScanReport::new();
+
+    let hosts = get_network_hosts(device);
+
+    for batch in hosts.chunks(TCP_CONNECT_BATCH_SIZE) {
+        if !budget.acquire() {
+            break;
+        }
+
+        for (ip, port) in find_open_ports_tcp(batch, ports) {
+            let mac = get_fake_mac_address(ip);
+            let ip  = IpAddr::V4(ip);
+
+            report.add_host(mac, ip, HINFO_FLAG_TCP);
+            report.add_port(mac, ip, port);
+        }
+    }
+
+    report
+}
+
+/// Get all host addresses within the network of a given device (except the
+/// device address itself).
+///
+/// Note: networks larger than /24 are narrowed down to the /24 network
+/// containing the device address in order to keep the TCP connect scan
+/// reasonably fast.
+fn get_network_hosts(device: &EthernetDevice) -> Vec<Ipv4Addr> {
+    let addr    = ipv4_to_u32(&device.ip_addr);
+    let netmask = ipv4_to_u32(&device.netmask) | 0xffffff00;
+    let network = addr & netmask;
+    let bcast   = network | !netmask;
+
+    let mut res = Vec::new();
+
+    // skip the network address and the broadcast address
+    let mut host = network.wrapping_add(1);
+    while host < bcast {
+        if host != addr {
+            res.push(u32_to_ipv4(host));
+        }
+
+        host += 1;
+    }
+
+    res
+}
+
+/// Try to connect to all given ports on all given hosts and return
+/// addresses of those which accepted the connection within the connect
+/// timeout.
+fn find_open_ports_tcp(
+    hosts: &[Ipv4Addr],
+    ports: &PortCollection) -> Vec<(Ipv4Addr, u16)> {
+    let mut streams = Vec::new();
+
+    for ip in hosts {
+        for port in ports.iter() {
+            let addr = SocketAddr::V4(SocketAddrV4::new(*ip, port));
+            // the connect is non-blocking, connection errors are ignored
+            if let Ok(stream) = TcpStream::connect(&addr) {
+                streams.push((*ip, port, stream));
+            }
+        }
+    }
+
+    thread::sleep(Duration::from_millis(TCP_CONNECT_TIMEOUT));
+
+    // only established connections have a peer address
+    streams.into_iter()
+        .filter(|&(_, _, ref stream)| stream.peer_addr().is_ok())
+        .map(|(ip, port, _)| (ip, port))
+        .collect::<_>()
+}
+
+/// Generate a fake MAC address for a given host found by the TCP connect
+/// scanner.
+fn get_fake_mac_address(ip: Ipv4Addr) -> MacAddr {
+    let a = ((TCP_CONNECT_MAC_PREFIX >> 8) & 0xff) as u8;
+    let b = ( TCP_CONNECT_MAC_PREFIX       & 0xff) as u8;
+
+    let octets = ip.octets();
+
+    MacAddr::new(a, b, octets[0], octets[1], octets[2], octets[3])
+}
+
+/// Convert a given IPv4 address into an integer.
+fn ipv4_to_u32(ip: &Ipv4Addr) -> u32 {
+    let octets = ip.octets();
+
+    ((octets[0] as u32) << 24) |
+    ((octets[1] as u32) << 16) |
+    ((octets[2] as u32) <<  8) |
+     (octets[3] as u32)
+}
+
+/// Convert a given integer into an IPv4 address.
+fn u32_to_ipv4(ip: u32) -> Ipv4Addr {
+    Ipv4Addr::new(
+        ((ip >> 24) & 0xff) as u8,
+        ((ip >> 16) & 0xff) as u8,
+        ((ip >>  8) & 0xff) as u8,
+        ( ip        & 0xff) as u8)
+}
+
 /// Check if any of given TCP ports is open on on any host from a given set.
 fn find_open_ports<H: IntoIterator<Item=(MacAddr, IpAddr)>>(
     pc: pcap::ThreadingContext,
@@ -397,7 +890,8 @@ fn find_open_ports<H: IntoIterator<Item=(MacAddr, IpAddr)>>(
 /// Find all RTSP services.
 fn find_rtsp_ports(
     report: &ScanReport,
-    rtsp_ports: &[u16]) -> Result<Vec<(MacAddr, SocketAddr)>> {
+    rtsp_ports: &[u16],
+    budget: &ScanBudget) -> Result<Vec<(MacAddr, SocketAddr)>> {
     let mut ports   = HashSet::<u16>::new();
     let mut threads = Vec::new();
     let mut res     = Vec::new();
@@ -405,7 +899,7 @@ fn find_rtsp_ports(
     ports.extend(rtsp_ports);
 
     for (mac, addr) in report.socket_addrs() {
-        if ports.contains(&addr.port()) {
+        if ports.contains(&addr.port()) && budget.acquire() {
             let handle = thread::spawn(move || {
                 (mac, addr, is_rtsp_service(addr))
             });
@@ -426,37 +920,46 @@ fn find_rtsp_ports(
     Ok(res)
 }
 
-/// Find the first available RTSP path for a given RTSP service.
+/// Find the first available RTSP path for a given RTSP service. The service
+/// is returned together with a flag indicating that the service asks for the
+/// Basic authentication and with media parameters of the stream (if known).
 fn find_rtsp_path(
     mac: MacAddr,
     addr: SocketAddr,
-    paths: &[String]) -> Result<Service> {
+    paths: &[String],
+    budget: &ScanBudget) -> Result<(Service, bool, Option<MediaInfo>)> {
     let mut service = Service::UnknownRTSP(mac, addr);
 
     for path in paths {
-        let status = try!(get_rtsp_describe_status(addr, path));
+        if !budget.acquire() {
+            break;
+        }
+
+        let (status, media) = try!(describe_rtsp_service(addr, path));
         if status == DescribeStatus::Ok {
-            service = Service::RTSP(mac, addr, path.to_string());
+            return Ok((Service::RTSP(mac, addr, path.to_string()), false, media));
         } else if status == DescribeStatus::Unsupported {
             service = Service::UnsupportedRTSP(mac, addr, path.to_string());
         } else if status == DescribeStatus::Locked {
             service = Service::LockedRTSP(mac, addr);
+        } else if status == DescribeStatus::LockedBasic {
+            return Ok((Service::LockedRTSP(mac, addr), true, None));
         }
 
-        match status {
-            DescribeStatus::Ok     => break,
-            DescribeStatus::Locked => break,
-            _ => ()
+        if status == DescribeStatus::Locked {
+            break;
         }
     }
 
-    Ok(service)
+    Ok((service, false, None))
 }
 
-/// Find all RTSP services.
+/// Find all RTSP services (together with the Basic authentication flags and
+/// media parameters).
 fn find_rtsp_services(
     rtsp_paths_file: &str,
-    rtsp_ports: &[(MacAddr, SocketAddr)]) -> Result<Vec<Service>> {
+    rtsp_ports: &[(MacAddr, SocketAddr)],
+    budget: &ScanBudget) -> Result<Vec<(Service, bool, Option<MediaInfo>)>> {
     let paths = Arc::new(try!(load_paths(rtsp_paths_file)));
 
     let mut threads = Vec::new();
@@ -466,8 +969,9 @@ fn find_rtsp_services(
         let mac    = *mac;
         let saddr  = *saddr;
         let paths  = paths.clone();
+        let budget = budget.clone();
         let handle = thread::spawn(move || {
-            find_rtsp_path(mac, saddr, &paths)
+            find_rtsp_path(mac, saddr, &paths, &budget)
         });
         threads.push(handle);
     }
@@ -486,7 +990,8 @@ fn find_rtsp_services(
 /// Find all HTTP services.
 fn find_http_ports(
     report: &ScanReport,
-    http_ports: &[u16]) -> Result<Vec<(MacAddr, SocketAddr)>> {
+    http_ports: &[u16],
+    budget: &ScanBudget) -> Result<Vec<(MacAddr, SocketAddr)>> {
     let mut ports   = HashSet::<u16>::new();
     let mut threads = Vec::new();
     let mut res     = Vec::new();
@@ -494,7 +999,7 @@ fn find_http_ports(
     ports.extend(http_ports);
 
     for (mac, addr) in report.socket_addrs() {
-        if ports.contains(&addr.port()) {
+        if ports.contains(&addr.port()) && budget.acquire() {
             let handle = thread::spawn(move || {
                 (mac, addr, is_http_service(addr))
             });
@@ -515,12 +1020,19 @@ fn find_http_ports(
     Ok(res)
 }
 
-/// Find the first available MJPEG path for a given HTTP service.
+/// Find the first available MJPEG path for a given HTTP service. The service
+/// is returned together with a flag indicating that the service asks for the
+/// Basic authentication.
 fn find_mjpeg_path(
     mac: MacAddr,
     addr: SocketAddr,
-    paths: &[String]) -> Result<Option<Service>> {
+    paths: &[String],
+    budget: &ScanBudget) -> Result<Option<(Service, bool)>> {
     for path in paths {
+        if !budget.acquire() {
+            break;
+        }
+
         if let Some(header) = try!(get_http_response_header(addr, path)) {
             if header.code == 200 {
                 let ctype = header.get_str("content-type")
@@ -529,10 +1041,12 @@ fn find_mjpeg_path(
 
                 if  ctype.starts_with("image/jpeg") ||
                     ctype.starts_with("multipart/x-mixed-replace") {
-                    return Ok(Some(Service::MJPEG(mac, addr, path.to_string())));
+                    return Ok(Some((Service::MJPEG(mac, addr, path.to_string()), false)));
                 }
             } else if header.code == 401 {
-                return Ok(Some(Service::LockedMJPEG(mac, addr)));
+                let basic = is_basic_challenge(header.get_str("WWW-Authenticate"));
+
+                return Ok(Some((Service::LockedMJPEG(mac, addr), basic)));
             }
         }
     }
@@ -540,10 +1054,11 @@ fn find_mjpeg_path(
     Ok(None)
 }
 
-/// Find all MJPEG services.
+/// Find all MJPEG services (together with the Basic authentication flags).
 fn find_mjpeg_services(
     mjpeg_paths_file: &str,
-    mjpeg_ports: &[(MacAddr, SocketAddr)]) -> Result<Vec<Service>> {
+    mjpeg_ports: &[(MacAddr, SocketAddr)],
+    budget: &ScanBudget) -> Result<Vec<(Service, bool)>> {
     let paths = Arc::new(try!(load_paths(mjpeg_paths_file)));
 
     let mut threads = Vec::new();
@@ -553,8 +1068,9 @@ fn find_mjpeg_services(
         let mac    = *mac;
         let saddr  = *saddr;
         let paths  = paths.clone();
+        let budget = budget.clone();
         let handle = thread::spawn(move || {
-            find_mjpeg_path(mac, saddr, &paths)
+            find_mjpeg_path(mac, saddr, &paths, &budget)
         });
         threads.push(handle);
     }
@@ -653,9 +1169,6 @@ fn get_hosts(services: &[Service]) -> Vec<IpAddr> {
         .collect::<_>()
 }
 
-#[cfg(test)]
-use std::net::Ipv4Addr;
-
 #[cfg(test)]
 #[test]
 /// Test the service priority filtering function.
@@ -675,3 +1188,102 @@ fn test_service_filtering() {
     assert_eq!(services.len(), 1);
     assert_eq!(services[0].1.port(), 554);
 }
+
+#[cfg(test)]
+#[test]
+/// Test extraction of media parameters from session descriptions.
+fn test_media_info() {
+    let sdp = "v=0\r\n".to_string()
+        + "m=video 0 RTP/AVP 96\r\n"
+        + "a=rtpmap:96 H264/90000\r\n"
+        + "a=framesize:96 1280-720\r\n"
+        + "a=framerate:25.0\r\n"
+        + "m=audio 0 RTP/AVP 8\r\n";
+
+    let info = get_media_info(sdp.as_bytes())
+        .unwrap();
+
+    assert_eq!(info.codec, Some("H264".to_string()));
+    assert_eq!(info.resolution, Some((1280, 720)));
+    assert_eq!(info.fps, Some(25.0));
+    assert_eq!(info.audio, Some("PCMA".to_string()));
+
+    let sdp = "v=0\r\n".to_string()
+        + "m=video 0 RTP/AVP 97\r\n"
+        + "a=rtpmap:97 MP4V-ES/90000\r\n"
+        + "a=cliprect:0,0,480,640\r\n";
+
+    let info = get_media_info(sdp.as_bytes())
+        .unwrap();
+
+    assert_eq!(info.codec, Some("MP4V-ES".to_string()));
+    assert_eq!(info.resolution, Some((640, 480)));
+    assert_eq!(info.fps, None);
+    assert_eq!(info.audio, Some("none".to_string()));
+}
+
+#[cfg(test)]
+#[test]
+/// Test detection of the Basic authentication challenges.
+fn test_basic_challenge() {
+    assert!(is_basic_challenge(Some("Basic realm=\"camera\"")));
+    assert!(is_basic_challenge(Some(" basic realm=\"camera\"")));
+    assert!(!is_basic_challenge(Some("Digest realm=\"camera\", nonce=\"1234\"")));
+    assert!(!is_basic_challenge(None));
+}
+
+#[cfg(test)]
+#[test]
+/// Test host enumeration used by the TCP connect scanner.
+fn test_network_hosts() {
+    let device = EthernetDevice {
+        name:     "eth0".to_string(),
+        mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
+        ip_addr:  Ipv4Addr::new(192, 168, 1, 10),
+        netmask:  Ipv4Addr::new(255, 255, 255, 248)
+    };
+
+    let hosts = get_network_hosts(&device);
+
+    assert_eq!(hosts, vec![
+        Ipv4Addr::new(192, 168, 1, 9),
+        Ipv4Addr::new(192, 168, 1, 11),
+        Ipv4Addr::new(192, 168, 1, 12),
+        Ipv4Addr::new(192, 168, 1, 13),
+        Ipv4Addr::new(192, 168, 1, 14)]);
+
+    let device = EthernetDevice {
+        netmask: Ipv4Addr::new(255, 255, 0, 0),
+        ..device
+    };
+
+    assert_eq!(get_network_hosts(&device).len(), 253);
+}
+
+#[cfg(test)]
+#[test]
+/// Test the scan budget limits.
+fn test_scan_budget() {
+    let budget = ScanBudget::new(None, Some(2));
+    let clone  = budget.clone();
+
+    assert!(budget.acquire());
+    assert!(clone.acquire());
+    assert!(!budget.is_exhausted());
+
+    // the probe counter is shared by all clones
+    assert!(!clone.acquire());
+    assert!(budget.is_exhausted());
+    assert!(!budget.acquire());
+
+    let budget = ScanBudget::new(Some(0), None);
+
+    assert!(!budget.acquire());
+    assert!(budget.is_exhausted());
+
+    let budget = ScanBudget::unlimited();
+
+    for _ in 0..1000 {
+        assert!(budget.acquire());
+    }
+}
@@ -0,0 +1,1024 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caching DNS resolver for service host names.
+//!
+//! Host names are resolved using the system resolver. If the system resolver
+//! fails, the configured name servers are queried directly using a minimal
+//! DNS client (A records only). Resolved addresses are cached (and
+//! persisted) until their TTL expires and stale cache entries are used if
+//! the host name cannot be resolved at all (e.g. during an outage of the
+//! upstream DNS servers).
+//!
+//! The module also provides host name lookups of discovered devices (reverse
+//! DNS, mDNS and NetBIOS).
+
+use std::io;
+use std::fmt;
+use std::cmp;
+use std::result;
+
+use std::fs::File;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use net;
+use utils;
+
+use rustc_serialize::json;
+
+use openssl::crypto::rand;
+
+use time;
+
+/// DNS resolver error.
+#[derive(Debug, Clone)]
+pub struct DnsError {
+    msg: String,
+}
+
+impl Error for DnsError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl Display for DnsError {
+    fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
+        f.write_str(&self.msg)
+    }
+}
+
+impl From<String> for DnsError {
+    fn from(msg: String) -> DnsError {
+        DnsError { msg: msg }
+    }
+}
+
+impl<'a> From<&'a str> for DnsError {
+    fn from(msg: &'a str) -> DnsError {
+        DnsError::from(msg.to_string())
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(err: io::Error) -> DnsError {
+        DnsError::from(format!("IO error: {}", err))
+    }
+}
+
+/// DNS resolver result type alias.
+pub type Result<T> = result::Result<T, DnsError>;
+
+/// File containing DNS resolver configuration.
+static RESOLV_CONF: &'static str = "/etc/resolv.conf";
+
+/// DNS query timeout (in milliseconds).
+const QUERY_TIMEOUT: u64 = 2000;
+
+/// Timeout of mDNS and NetBIOS queries sent directly to local hosts (in
+/// milliseconds).
+const LOCAL_QUERY_TIMEOUT: u64 = 500;
+
+/// mDNS port.
+const MDNS_PORT: u16 = 5353;
+
+/// NetBIOS name service port.
+const NETBIOS_PORT: u16 = 137;
+
+/// Minimum TTL of cache entries (in seconds).
+const MIN_TTL: u32 = 60;
+
+/// TTL of entries resolved using the system resolver (in seconds).
+const SYSTEM_RESOLVER_TTL: u32 = 300;
+
+const DNS_TYPE_A:     u16 = 1;
+const DNS_TYPE_PTR:   u16 = 12;
+const DNS_TYPE_NBSTAT: u16 = 33;
+const DNS_CLASS_IN:   u16 = 1;
+const DNS_FLAG_QR:    u16 = 0x8000;
+const DNS_FLAG_TC:    u16 = 0x0200;
+const DNS_RCODE_MASK: u16 = 0x000f;
+
+/// DNS cache entry.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
+struct CacheEntry {
+    /// Resolved addresses.
+    addrs:   Vec<String>,
+    /// Expiration time (UNIX timestamp).
+    expires: i64,
+}
+
+impl CacheEntry {
+    /// Create a new cache entry for given addresses and TTL.
+    fn new(addrs: &[IpAddr], ttl: u32) -> CacheEntry {
+        let ttl = cmp::max(ttl, MIN_TTL) as i64;
+
+        CacheEntry {
+            addrs:   addrs.iter()
+                .map(|addr| format!("{}", addr))
+                .collect::<_>(),
+            expires: time::get_time().sec + ttl
+        }
+    }
+
+    /// Check if the entry has expired.
+    fn is_stale(&self) -> bool {
+        self.expires <= time::get_time().sec
+    }
+
+    /// Get the first valid address.
+    fn addr(&self) -> Option<IpAddr> {
+        self.addrs.iter()
+            .filter_map(|addr| IpAddr::from_str(addr).ok())
+            .next()
+    }
+}
+
+/// Caching DNS resolver.
+pub struct DnsCache {
+    /// File for persisting the cache.
+    file:        Option<String>,
+    /// Cached entries.
+    entries:     HashMap<String, CacheEntry>,
+    /// Entries modified since the cache was saved.
+    modified:    bool,
+    /// Name servers used instead of the system resolver (if any).
+    nameservers: Option<Vec<SocketAddr>>,
+}
+
+impl DnsCache {
+    /// Create a new empty in-memory DNS cache.
+    pub fn new() -> DnsCache {
+        DnsCache {
+            file:        None,
+            entries:     HashMap::new(),
+            modified:    false,
+            nameservers: None
+        }
+    }
+
+    /// Create a new empty in-memory DNS cache using only given name servers.
+    #[cfg(test)]
+    fn with_nameservers(nameservers: Vec<SocketAddr>) -> DnsCache {
+        DnsCache {
+            file:        None,
+            entries:     HashMap::new(),
+            modified:    false,
+            nameservers: Some(nameservers)
+        }
+    }
+
+    /// Create a new DNS cache persisted in a given file. Existing entries are
+    /// loaded from the file (if possible).
+    pub fn load(file: &str) -> DnsCache {
+        let entries = utils::load_with_backup(file, load_entries)
+            .map(|(entries, _)| entries)
+            .unwrap_or(HashMap::new());
+
+        DnsCache {
+            file:        Some(file.to_string()),
+            entries:     entries,
+            modified:    false,
+            nameservers: None
+        }
+    }
+
+    /// Save the cache into its file (if any). Nothing is written if there
+    /// are no changes since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        if let Some(ref file) = self.file {
+            let content = try!(json::encode(&self.entries)
+                .or(Err(DnsError::from("unable to encode DNS cache"))));
+
+            try!(utils::write_file_with_backup(file, content.as_bytes()));
+        }
+
+        self.modified = false;
+
+        Ok(())
+    }
+
+    /// Resolve a given socket address in the "host:port" format.
+    pub fn resolve_addr(&mut self, addr: &str) -> Result<SocketAddr> {
+        if let Ok(addr) = SocketAddr::from_str(addr) {
+            return Ok(addr);
+        }
+
+        let mut parts = addr.rsplitn(2, ':');
+
+        let port = parts.next()
+            .and_then(|port| u16::from_str(port).ok());
+        let host = parts.next();
+
+        match (host, port) {
+            (Some(host), Some(port)) => self.resolve(host, port),
+            _ => Err(DnsError::from("invalid socket address"))
+        }
+    }
+
+    /// Resolve a given host name and return a socket address with a given
+    /// port.
+    pub fn resolve(&mut self, host: &str, port: u16) -> Result<SocketAddr> {
+        let host = host.trim_left_matches('[')
+            .trim_right_matches(']');
+
+        if let Ok(ip) = IpAddr::from_str(host) {
+            return Ok(get_socket_address(ip, port));
+        }
+
+        let key = host.to_lowercase();
+
+        if let Some(ip) = self.get(&key, false) {
+            return Ok(get_socket_address(ip, port));
+        }
+
+        let entry = match self.nameservers {
+            Some(ref nameservers) => query(nameservers, &key)
+                .map(|(addrs, ttl)| CacheEntry::new(&addrs, ttl)),
+            None => system_query(&key, port)
+                .map(|ip| CacheEntry::new(&[ip], SYSTEM_RESOLVER_TTL))
+                .or_else(|_| query(&get_nameservers(), &key)
+                    .map(|(addrs, ttl)| CacheEntry::new(&addrs, ttl)))
+        };
+
+        match entry {
+            Ok(entry) => {
+                self.entries.insert(key.clone(), entry);
+                self.modified = true;
+            },
+            Err(err) => {
+                return self.get(&key, true)
+                    .map(|ip| get_socket_address(ip, port))
+                    .ok_or(err);
+            }
+        }
+
+        self.get(&key, true)
+            .map(|ip| get_socket_address(ip, port))
+            .ok_or(DnsError::from("unable to resolve host name"))
+    }
+
+    /// Get cached address for a given host name.
+    fn get(&self, host: &str, allow_stale: bool) -> Option<IpAddr> {
+        self.entries.get(host)
+            .and_then(|entry| if allow_stale || !entry.is_stale() {
+                entry.addr()
+            } else {
+                None
+            })
+    }
+}
+
+/// Load cache entries from a given file.
+fn load_entries(file: &str) -> Result<HashMap<String, CacheEntry>> {
+    let mut content = String::new();
+    let file        = try!(File::open(file));
+    let mut breader = BufReader::new(file);
+
+    try!(breader.read_to_string(&mut content));
+
+    json::decode(&content)
+        .or(Err(DnsError::from("unable to decode DNS cache")))
+}
+
+/// Create a socket address from a given IP address and port.
+fn get_socket_address(ip: IpAddr, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))
+    }
+}
+
+/// Resolve a given host name using the system resolver.
+fn system_query(host: &str, port: u16) -> Result<IpAddr> {
+    net::utils::get_socket_address((host, port))
+        .map(|addr| addr.ip())
+        .or(Err(DnsError::from("unable to resolve host name")))
+}
+
+/// Get addresses of all configured name servers.
+fn get_nameservers() -> Vec<SocketAddr> {
+    let mut res = Vec::new();
+
+    if let Ok(file) = File::open(RESOLV_CONF) {
+        for line in BufReader::new(file).lines() {
+            let line = line.unwrap_or(String::new());
+            let mut words = line.split_whitespace();
+            if words.next() == Some("nameserver") {
+                let addr = words.next()
+                    .and_then(|addr| IpAddr::from_str(addr).ok());
+                if let Some(addr) = addr {
+                    res.push(get_socket_address(addr, 53));
+                }
+            }
+        }
+    }
+
+    res
+}
+
+/// Query given name servers for A records of a given host name. Return the
+/// addresses and their TTL.
+fn query(nameservers: &[SocketAddr], host: &str) -> Result<(Vec<IpAddr>, u32)> {
+    let mut last_error = DnsError::from("no name server available");
+
+    for ns in nameservers {
+        match query_nameserver(ns, host) {
+            Ok(res)  => return Ok(res),
+            Err(err) => last_error = err
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Query a given name server for A records of a given host name.
+fn query_nameserver(ns: &SocketAddr, host: &str) -> Result<(Vec<IpAddr>, u32)> {
+    let id   = new_query_id();
+    let req  = try!(create_query(id, host, DNS_TYPE_A));
+    let resp = try!(exchange(ns, &req, QUERY_TIMEOUT));
+
+    parse_response(id, &resp)
+}
+
+/// Query a given name server (or a host responding to mDNS queries) for the
+/// PTR record of a given IP address.
+fn query_ptr(ns: &SocketAddr, ip: &IpAddr, timeout: u64) -> Result<String> {
+    let id   = new_query_id();
+    let req  = try!(create_query(id, &reverse_name(ip), DNS_TYPE_PTR));
+    let resp = try!(exchange(ns, &req, timeout));
+
+    parse_ptr_response(id, &resp)
+}
+
+/// Get a new random query ID.
+fn new_query_id() -> u16 {
+    let id = rand::rand_bytes(2);
+
+    ((id[0] as u16) << 8) | (id[1] as u16)
+}
+
+/// Send a given request to a given address and wait for the response.
+/// Datagrams from other addresses are dropped by the connected socket and
+/// responses with a different ID are ignored.
+fn exchange(addr: &SocketAddr, req: &[u8], timeout: u64) -> Result<Vec<u8>> {
+    let bind_addr = match addr {
+        &SocketAddr::V4(_) => "0.0.0.0:0",
+        &SocketAddr::V6(_) => "[::]:0"
+    };
+
+    let socket = try!(UdpSocket::bind(bind_addr));
+
+    try!(socket.connect(addr));
+    try!(socket.send(req));
+
+    let deadline = time::precise_time_ns() + timeout * 1000000;
+
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        let now = time::precise_time_ns();
+
+        if now >= deadline {
+            return Err(DnsError::from("DNS query timeout"));
+        }
+
+        let remaining = cmp::max(1, (deadline - now) / 1000000);
+
+        try!(socket.set_read_timeout(Some(Duration::from_millis(remaining))));
+
+        let len = try!(socket.recv(&mut buffer));
+
+        if len >= 2 && buffer[..2] == req[..2] {
+            return Ok(buffer[..len].to_vec());
+        }
+    }
+}
+
+/// Get name of the PTR record of a given IP address.
+fn reverse_name(ip: &IpAddr) -> String {
+    match ip {
+        &IpAddr::V4(ref ip) => {
+            let octets = ip.octets();
+
+            format!("{}.{}.{}.{}.in-addr.arpa",
+                octets[3], octets[2], octets[1], octets[0])
+        },
+        &IpAddr::V6(ref ip) => {
+            let mut res = String::new();
+
+            for segment in ip.segments().iter().rev() {
+                for i in 0..4 {
+                    res.push_str(&format!("{:x}.", (segment >> (i * 4)) & 0xf));
+                }
+            }
+
+            res.push_str("ip6.arpa");
+            res
+        }
+    }
+}
+
+/// Look up host name of a given IP address using reverse DNS, mDNS and
+/// NetBIOS (in this order). None is returned if the host name cannot be
+/// found.
+pub fn lookup_host_name(ip: &IpAddr) -> Option<String> {
+    reverse_lookup(ip)
+        .or_else(|_| mdns_reverse_lookup(ip))
+        .or_else(|_| netbios_lookup(ip))
+        .ok()
+}
+
+/// Look up host name of a given IP address using the configured name
+/// servers.
+pub fn reverse_lookup(ip: &IpAddr) -> Result<String> {
+    let mut last_error = DnsError::from("no name server available");
+
+    for ns in get_nameservers() {
+        match query_ptr(&ns, ip, QUERY_TIMEOUT) {
+            Ok(name) => return Ok(name),
+            Err(err) => last_error = err
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Look up host name of a given IP address by sending a (legacy unicast)
+/// mDNS query directly to the host. The ".local" suffix is removed.
+pub fn mdns_reverse_lookup(ip: &IpAddr) -> Result<String> {
+    let addr = get_socket_address(*ip, MDNS_PORT);
+    let name = try!(query_ptr(&addr, ip, LOCAL_QUERY_TIMEOUT));
+
+    if name.ends_with(".local") {
+        Ok(name[..name.len() - 6].to_string())
+    } else {
+        Ok(name)
+    }
+}
+
+/// Look up NetBIOS name of a given IP address using the NetBIOS node
+/// status request.
+pub fn netbios_lookup(ip: &IpAddr) -> Result<String> {
+    if let &IpAddr::V6(_) = ip {
+        return Err(DnsError::from("NetBIOS is not available over IPv6"));
+    }
+
+    let addr = get_socket_address(*ip, NETBIOS_PORT);
+    let id   = new_query_id();
+    let req  = create_nbstat_query(id);
+    let resp = try!(exchange(&addr, &req, LOCAL_QUERY_TIMEOUT));
+
+    parse_nbstat_response(id, &resp)
+}
+
+/// Create a NetBIOS node status request.
+fn create_nbstat_query(id: u16) -> Vec<u8> {
+    let mut res = Vec::new();
+
+    write_u16(&mut res, id);
+    write_u16(&mut res, 0);         // flags
+    write_u16(&mut res, 1);         // QDCOUNT
+    write_u16(&mut res, 0);         // ANCOUNT
+    write_u16(&mut res, 0);         // NSCOUNT
+    write_u16(&mut res, 0);         // ARCOUNT
+
+    // the "*" wildcard name padded with zeros (first-level encoding)
+    res.push(32);
+    res.extend(b"CK");
+
+    for _ in 0..15 {
+        res.extend(b"AA");
+    }
+
+    res.push(0);
+
+    write_u16(&mut res, DNS_TYPE_NBSTAT);
+    write_u16(&mut res, DNS_CLASS_IN);
+
+    res
+}
+
+/// Parse a NetBIOS node status response and return the unique workstation
+/// name.
+fn parse_nbstat_response(id: u16, data: &[u8]) -> Result<String> {
+    let answers = try!(parse_answers(id, data));
+
+    for (rtype, _, offset, rdlen) in answers {
+        if rtype != DNS_TYPE_NBSTAT || rdlen < 1 {
+            continue;
+        }
+
+        let count = data[offset] as usize;
+
+        for i in 0..count {
+            let start = offset + 1 + i * 18;
+
+            if (start + 18) > (offset + rdlen) {
+                break;
+            }
+
+            let suffix = data[start + 15];
+            let flags  = read_u16(data, start + 16).unwrap();
+
+            // workstation name, not a group name
+            if suffix == 0 && (flags & 0x8000) == 0 {
+                let name = String::from_utf8_lossy(&data[start..start + 15]);
+                let name = name.trim_right_matches(|c: char| c == ' ' || c == '\0');
+
+                if !name.is_empty() {
+                    return Ok(name.to_string());
+                }
+            }
+        }
+    }
+
+    Err(DnsError::from("no NetBIOS name found"))
+}
+
+/// Create a DNS query for records of a given type and host name.
+fn create_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+
+    write_u16(&mut res, id);
+    write_u16(&mut res, 0x0100);    // recursion desired
+    write_u16(&mut res, 1);         // QDCOUNT
+    write_u16(&mut res, 0);         // ANCOUNT
+    write_u16(&mut res, 0);         // NSCOUNT
+    write_u16(&mut res, 0);         // ARCOUNT
+
+    for label in host.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::from("invalid host name"));
+        }
+
+        res.push(label.len() as u8);
+        res.extend(label.as_bytes());
+    }
+
+    res.push(0);
+
+    write_u16(&mut res, qtype);
+    write_u16(&mut res, DNS_CLASS_IN);
+
+    Ok(res)
+}
+
+/// Parse a DNS response with a given ID and return all A records and their
+/// minimum TTL.
+fn parse_response(id: u16, data: &[u8]) -> Result<(Vec<IpAddr>, u32)> {
+    let mut addrs = Vec::new();
+    let mut ttl   = u32::max_value();
+
+    for (rtype, rttl, offset, rdlen) in try!(parse_answers(id, data)) {
+        if rtype == DNS_TYPE_A && rdlen == 4 {
+            let ip = Ipv4Addr::new(
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3]);
+
+            addrs.push(IpAddr::V4(ip));
+
+            ttl = cmp::min(ttl, rttl);
+        }
+    }
+
+    if addrs.is_empty() {
+        Err(DnsError::from("no A record found"))
+    } else {
+        Ok((addrs, ttl))
+    }
+}
+
+/// Parse a DNS response with a given ID and return name from the first PTR
+/// record.
+fn parse_ptr_response(id: u16, data: &[u8]) -> Result<String> {
+    for (rtype, _, offset, _) in try!(parse_answers(id, data)) {
+        if rtype == DNS_TYPE_PTR {
+            let name = try!(read_name(data, offset));
+
+            if !name.is_empty() {
+                return Ok(name);
+            }
+        }
+    }
+
+    Err(DnsError::from("no PTR record found"))
+}
+
+/// Parse a DNS response with a given ID and return type, TTL, data offset
+/// and data length of all answer records.
+fn parse_answers(
+    id: u16,
+    data: &[u8]) -> Result<Vec<(u16, u32, usize, usize)>> {
+    if data.len() < 12 || read_u16(data, 0) != Some(id) {
+        return Err(DnsError::from("invalid DNS response"));
+    }
+
+    let flags   = read_u16(data, 2).unwrap();
+    let qdcount = read_u16(data, 4).unwrap();
+    let ancount = read_u16(data, 6).unwrap();
+
+    if (flags & DNS_FLAG_QR) == 0 {
+        return Err(DnsError::from("invalid DNS response"));
+    } else if (flags & DNS_FLAG_TC) != 0 {
+        return Err(DnsError::from("truncated DNS response"));
+    } else if (flags & DNS_RCODE_MASK) != 0 {
+        return Err(DnsError::from(format!("DNS error (RCODE: {})",
+            flags & DNS_RCODE_MASK)));
+    }
+
+    let mut offset = 12;
+
+    for _ in 0..qdcount {
+        offset = try!(skip_name(data, offset)) + 4;
+    }
+
+    let mut answers = Vec::new();
+
+    for _ in 0..ancount {
+        offset = try!(skip_name(data, offset));
+
+        let rtype  = try!(read_u16(data, offset)
+            .ok_or(DnsError::from("invalid DNS response")));
+        let rttl   = try!(read_u32(data, offset + 4)
+            .ok_or(DnsError::from("invalid DNS response")));
+        let rdlen  = try!(read_u16(data, offset + 8)
+            .ok_or(DnsError::from("invalid DNS response"))) as usize;
+
+        offset += 10;
+
+        if (offset + rdlen) > data.len() {
+            return Err(DnsError::from("invalid DNS response"));
+        }
+
+        answers.push((rtype, rttl, offset, rdlen));
+
+        offset += rdlen;
+    }
+
+    Ok(answers)
+}
+
+/// Read a (possibly compressed) domain name starting at a given offset.
+fn read_name(data: &[u8], mut offset: usize) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut jumps  = 0;
+
+    loop {
+        let len = match data.get(offset) {
+            Some(len) => *len as usize,
+            None => return Err(DnsError::from("invalid DNS response"))
+        };
+
+        if len == 0 {
+            return Ok(labels.join("."));
+        } else if (len & 0xc0) == 0xc0 {
+            let ptr = try!(read_u16(data, offset)
+                .ok_or(DnsError::from("invalid DNS response")));
+
+            // protect against compression loops
+            jumps += 1;
+            if jumps > 16 {
+                return Err(DnsError::from("invalid DNS response"));
+            }
+
+            offset = (ptr & 0x3fff) as usize;
+        } else if (offset + len + 1) > data.len() {
+            return Err(DnsError::from("invalid DNS response"));
+        } else {
+            let label = String::from_utf8_lossy(&data[offset + 1..offset + len + 1]);
+
+            labels.push(label.into_owned());
+
+            offset += len + 1;
+        }
+    }
+}
+
+/// Skip a (possibly compressed) domain name starting at a given offset and
+/// return offset of the next field.
+fn skip_name(data: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = match data.get(offset) {
+            Some(len) => *len as usize,
+            None => return Err(DnsError::from("invalid DNS response"))
+        };
+
+        if len == 0 {
+            return Ok(offset + 1);
+        } else if (len & 0xc0) == 0xc0 {
+            return Ok(offset + 2);
+        } else {
+            offset += len + 1;
+        }
+    }
+}
+
+/// Append a given u16 in the network byte order.
+fn write_u16(buffer: &mut Vec<u8>, v: u16) {
+    buffer.push((v >> 8) as u8);
+    buffer.push((v & 0xff) as u8);
+}
+
+/// Read u16 in the network byte order from a given offset.
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    if (offset + 2) > data.len() {
+        None
+    } else {
+        Some(((data[offset] as u16) << 8) | (data[offset + 1] as u16))
+    }
+}
+
+/// Read u32 in the network byte order from a given offset.
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    match (read_u16(data, offset), read_u16(data, offset + 2)) {
+        (Some(h), Some(l)) => Some(((h as u32) << 16) | (l as u32)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{create_query, exchange, parse_response, write_u16, CacheEntry};
+    use super::{create_nbstat_query, parse_nbstat_response};
+    use super::{parse_ptr_response, reverse_name, DNS_TYPE_A, DNS_TYPE_PTR};
+
+    use std::thread;
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+    use std::thread::JoinHandle;
+
+    /// Start a local name server answering a given number of queries using
+    /// a given function. Responses set to None are not sent.
+    fn stub_nameserver<F>(queries: usize, f: F) -> (SocketAddr, JoinHandle<()>)
+        where F: Fn(&[u8]) -> Vec<Option<Vec<u8>>> + Send + 'static {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .unwrap();
+        let addr = socket.local_addr()
+            .unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+
+            for _ in 0..queries {
+                let (len, src) = socket.recv_from(&mut buffer)
+                    .unwrap();
+
+                for response in f(&buffer[..len]) {
+                    if let Some(response) = response {
+                        socket.send_to(&response, src)
+                            .unwrap();
+                    }
+                }
+            }
+        });
+
+        (addr, handle)
+    }
+
+    /// Create a response to a given A query with a given address and TTL.
+    fn a_response(query: &[u8], ip: [u8; 4], ttl: u16) -> Vec<u8> {
+        let mut response = query.to_vec();
+
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+
+        response.push(0xc0);
+        response.push(12);
+        write_u16(&mut response, DNS_TYPE_A);
+        write_u16(&mut response, 1);
+        write_u16(&mut response, 0);
+        write_u16(&mut response, ttl);
+        write_u16(&mut response, 4);
+        response.extend(&ip);
+
+        response
+    }
+
+    #[test]
+    fn test_response_parsing() {
+        let mut response = create_query(0x1234, "camera.example.com",
+            DNS_TYPE_A)
+            .unwrap();
+
+        // set QR flag and ANCOUNT
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+
+        for i in 1..3 {
+            // pointer to the question name
+            response.push(0xc0);
+            response.push(12);
+            write_u16(&mut response, 1);
+            write_u16(&mut response, 1);
+            write_u16(&mut response, 0);
+            write_u16(&mut response, 100 * i);
+            write_u16(&mut response, 4);
+            response.extend(&[10, 0, 0, i as u8]);
+        }
+
+        let (addrs, ttl) = parse_response(0x1234, &response)
+            .unwrap();
+
+        assert_eq!(addrs, vec![
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+        assert_eq!(ttl, 100);
+
+        assert!(parse_response(0x4321, &response).is_err());
+
+        // truncated response
+        response[2] = 0x83;
+
+        assert!(parse_response(0x1234, &response).is_err());
+
+        // not a response
+        response[2] = 0x01;
+
+        assert!(parse_response(0x1234, &response).is_err());
+
+        // NXDOMAIN
+        response[2] = 0x81;
+        response[3] = 0x83;
+
+        assert!(parse_response(0x1234, &response).is_err());
+    }
+
+    #[test]
+    fn test_name_server_query() {
+        let (ns, handle) = stub_nameserver(1, |query| {
+            vec![Some(a_response(query, [10, 0, 0, 5], 300))]
+        });
+
+        let mut cache = DnsCache::with_nameservers(vec![ns]);
+
+        let addr = cache.resolve("Camera.Example.com", 554)
+            .unwrap();
+
+        handle.join()
+            .unwrap();
+
+        assert_eq!(addr, SocketAddr::from_str("10.0.0.5:554").unwrap());
+
+        let entry = cache.entries.get("camera.example.com")
+            .unwrap();
+
+        assert!(!entry.is_stale());
+
+        // the fresh entry is used without querying the name server
+        let addr = cache.resolve("camera.example.com", 80)
+            .unwrap();
+
+        assert_eq!(addr, SocketAddr::from_str("10.0.0.5:80").unwrap());
+    }
+
+    #[test]
+    fn test_response_id_validation() {
+        let (ns, handle) = stub_nameserver(1, |query| {
+            let mut spoofed = a_response(query, [10, 0, 0, 6], 300);
+
+            spoofed[0] ^= 0xff;
+
+            vec![Some(spoofed), Some(a_response(query, [10, 0, 0, 7], 300))]
+        });
+
+        let req = create_query(0x1234, "camera.example.com", DNS_TYPE_A)
+            .unwrap();
+
+        let resp = exchange(&ns, &req, 1000)
+            .unwrap();
+
+        handle.join()
+            .unwrap();
+
+        let (addrs, _) = parse_response(0x1234, &resp)
+            .unwrap();
+
+        assert_eq!(addrs, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))]);
+    }
+
+    #[test]
+    fn test_host_name_lookup() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+
+        assert_eq!(reverse_name(&ip), "10.1.168.192.in-addr.arpa");
+
+        let mut response = create_query(0x1234, &reverse_name(&ip),
+            DNS_TYPE_PTR)
+            .unwrap();
+
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+
+        response.push(0xc0);
+        response.push(12);
+        write_u16(&mut response, DNS_TYPE_PTR);
+        write_u16(&mut response, 1);
+        write_u16(&mut response, 0);
+        write_u16(&mut response, 100);
+        write_u16(&mut response, 15);
+        response.push(13);
+        response.extend(b"frontdoor-cam");
+        response.push(0);
+
+        assert_eq!(parse_ptr_response(0x1234, &response).unwrap(),
+            "frontdoor-cam");
+
+        let mut response = create_nbstat_query(0x4321);
+
+        response[2] = 0x84;
+        response[7] = 1;
+
+        response.push(0xc0);
+        response.push(12);
+        write_u16(&mut response, 33);
+        write_u16(&mut response, 1);
+        write_u16(&mut response, 0);
+        write_u16(&mut response, 0);
+        write_u16(&mut response, 1 + 2 * 18);
+        response.push(2);
+        response.extend(b"WORKGROUP      ");
+        response.push(0);
+        write_u16(&mut response, 0x8400);
+        response.extend(b"BACKYARD-CAM   ");
+        response.push(0);
+        write_u16(&mut response, 0x0400);
+
+        assert_eq!(parse_nbstat_response(0x4321, &response).unwrap(),
+            "BACKYARD-CAM");
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        // SERVFAIL
+        let (ns, handle) = stub_nameserver(1, |query| {
+            let mut response = query.to_vec();
+
+            response[2] = 0x81;
+            response[3] = 0x82;
+
+            vec![Some(response)]
+        });
+
+        let mut cache = DnsCache::with_nameservers(vec![ns]);
+
+        let ip    = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut entry = CacheEntry::new(&[ip], 0);
+
+        entry.expires = 0;
+
+        cache.entries.insert("camera.invalid".to_string(), entry);
+
+        assert_eq!(cache.get("camera.invalid", false), None);
+        assert_eq!(cache.get("camera.invalid", true), Some(ip));
+
+        // the name server fails, so the stale entry is used
+        let addr = cache.resolve("camera.invalid", 554)
+            .unwrap();
+
+        handle.join()
+            .unwrap();
+
+        assert_eq!(addr.ip(), ip);
+        assert_eq!(addr.port(), 554);
+    }
+
+    #[test]
+    fn test_ip_literals() {
+        let mut cache = DnsCache::new();
+
+        let addr = cache.resolve_addr("127.0.0.1:80")
+            .unwrap();
+
+        assert_eq!(addr.port(), 80);
+        assert!(cache.resolve("[::1]", 80).is_ok());
+    }
+}
@@ -21,6 +21,84 @@ pub mod http;
 #[cfg(feature = "discovery")]
 pub mod discovery;
 
+#[cfg(feature = "control-socket")]
+pub mod control_socket;
+
+#[cfg(feature = "metrics")]
+pub mod status_page;
+
+#[cfg(feature = "onvif")]
+pub mod stream_uri;
+
+#[cfg(feature = "discovery")]
+pub mod oui;
+
+#[cfg(feature = "discovery")]
+pub mod sap;
+
+#[cfg(feature = "discovery")]
+pub mod monitor;
+
+#[cfg(feature = "dns")]
+pub mod dns;
+
+/// Host name resolution using the system resolver (dummy caching DNS
+/// resolver).
+#[cfg(not(feature = "dns"))]
+pub mod dns {
+    use std::net::SocketAddr;
+
+    use net;
+
+    use utils::RuntimeError;
+
+    /// System resolver with the interface of the caching DNS resolver
+    /// (dummy).
+    pub struct DnsCache;
+
+    impl DnsCache {
+        /// Create a new resolver (dummy).
+        pub fn new() -> DnsCache {
+            DnsCache
+        }
+
+        /// Create a new resolver, nothing is loaded (dummy).
+        pub fn load(_: &str) -> DnsCache {
+            DnsCache
+        }
+
+        /// Nothing is saved (dummy).
+        pub fn save(&self) -> Result<(), RuntimeError> {
+            Ok(())
+        }
+
+        /// Resolve a given socket address in the "host:port" format.
+        pub fn resolve_addr(&mut self, addr: &str) -> Result<SocketAddr, RuntimeError> {
+            net::utils::get_socket_address(addr)
+        }
+
+        /// Resolve a given host name and return a socket address with a
+        /// given port.
+        pub fn resolve(&mut self, host: &str, port: u16) -> Result<SocketAddr, RuntimeError> {
+            let host = host.trim_left_matches('[')
+                .trim_right_matches(']');
+
+            net::utils::get_socket_address((host, port))
+        }
+    }
+}
+
+#[cfg(feature = "socks")]
+pub mod socks;
+
+#[cfg(feature = "conntrack")]
+pub mod conntrack;
+
+#[cfg(feature = "sntp")]
+pub mod sntp;
+
 pub mod raw;
+pub mod traffic;
 pub mod arrow;
+pub mod service_url;
 pub mod utils;
@@ -0,0 +1,173 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network change detection.
+//!
+//! The monitor compares results of consecutive network scans and generates
+//! events when a previously unknown MAC address appears on the local network
+//! or when the set of open ports (fingerprint) of a known host changes. The
+//! first scan only establishes the baseline. Hosts which disappear are not
+//! reported (cameras are often turned off) and hosts without any open ports
+//! found are not considered in the fingerprint comparison (a busy or
+//! rebooting device may not respond to the port scan).
+
+use std::collections::{HashMap, VecDeque};
+
+use net::raw::ether::MacAddr;
+use net::arrow::protocol::ScanReport;
+use net::arrow::protocol::event::*;
+
+/// Maximum number of events waiting to be sent to Arrow Service.
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// Network change monitor.
+#[derive(Debug, Clone)]
+pub struct NetworkMonitor {
+    /// Known hosts and their fingerprints (sorted port lists).
+    hosts:    HashMap<MacAddr, Vec<u16>>,
+    /// The baseline has been established.
+    baseline: bool,
+    /// Events waiting to be sent to Arrow Service.
+    pending:  VecDeque<NetworkEvent>,
+}
+
+impl NetworkMonitor {
+    /// Create a new network monitor without any baseline.
+    pub fn new() -> NetworkMonitor {
+        NetworkMonitor {
+            hosts:    HashMap::new(),
+            baseline: false,
+            pending:  VecDeque::new()
+        }
+    }
+
+    /// Compare a given scan report (of a scan started at a given time)
+    /// with the known hosts and return the detected events. The events are
+    /// also queued for Arrow Service (the oldest ones are dropped if there
+    /// are too many of them).
+    pub fn update(
+        &mut self,
+        report: &ScanReport,
+        timestamp: i64) -> Vec<NetworkEvent> {
+        let mut current = HashMap::new();
+
+        // a single MAC address can appear with multiple IP addresses
+        for host in report.hosts() {
+            let entry = current.entry(host.mac_addr)
+                .or_insert((host.ip_addr, Vec::new()));
+
+            entry.1.extend(host.ports());
+        }
+
+        let mut events = Vec::new();
+
+        for (mac, (ip, mut ports)) in current {
+            ports.sort();
+            ports.dedup();
+
+            if let Some(known) = self.hosts.get(&mac) {
+                if ports.is_empty() || *known == ports {
+                    continue;
+                }
+            }
+
+            let event_type = if self.hosts.contains_key(&mac) {
+                EVENT_FINGERPRINT_CHANGED
+            } else {
+                EVENT_NEW_HOST
+            };
+
+            if self.baseline {
+                events.push(NetworkEvent::new(event_type, timestamp,
+                    mac, ip, ports.clone()));
+            }
+
+            self.hosts.insert(mac, ports);
+        }
+
+        self.baseline = true;
+
+        for event in &events {
+            if self.pending.len() >= MAX_PENDING_EVENTS {
+                self.pending.pop_front();
+            }
+
+            self.pending.push_back(event.clone());
+        }
+
+        events
+    }
+
+    /// Take all events waiting to be sent to Arrow Service.
+    pub fn take_pending(&mut self) -> Vec<NetworkEvent> {
+        self.pending.drain(..)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::ScanReport;
+
+    fn host(n: u8) -> (MacAddr, IpAddr) {
+        (MacAddr::new(0, 1, 2, 3, 4, n),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, n)))
+    }
+
+    #[test]
+    fn test_network_monitor() {
+        let mut monitor = NetworkMonitor::new();
+        let mut report  = ScanReport::new();
+
+        let (mac1, ip1) = host(1);
+        let (mac2, ip2) = host(2);
+
+        report.add_port(mac1, ip1, 554);
+        report.add_port(mac1, ip1, 80);
+
+        // the first scan establishes the baseline
+        assert!(monitor.update(&report, 10).is_empty());
+        assert!(monitor.update(&report, 20).is_empty());
+
+        report.add_host(mac2, ip2, 0);
+
+        let events = monitor.update(&report, 30);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, EVENT_NEW_HOST);
+        assert_eq!(events[0].mac_addr, mac2);
+        assert_eq!(events[0].timestamp, 30);
+
+        // hosts without open ports do not change the fingerprint
+        let mut report = ScanReport::new();
+
+        report.add_host(mac1, ip1, 0);
+        report.add_port(mac2, ip2, 22);
+
+        let events = monitor.update(&report, 40);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, EVENT_FINGERPRINT_CHANGED);
+        assert_eq!(events[0].mac_addr, mac2);
+        assert_eq!(events[0].ports, vec![22]);
+
+        assert_eq!(monitor.take_pending().len(), 2);
+        assert!(monitor.take_pending().is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OUI vendor database.
+//!
+//! The database maps the first three octets of MAC addresses (OUIs) to
+//! vendor names. A compact database of camera vendors is built into the
+//! client. A newer database can be downloaded at runtime (using the
+//! `update-oui` command); the downloaded file is verified against a given
+//! SHA-256 checksum and its entries extend (and override) the built-in ones.
+//!
+//! The database is a text file. Every line contains six hex digits of the
+//! OUI followed by the vendor name. Empty lines and lines starting with "#"
+//! are ignored.
+
+use std::str;
+
+use std::collections::HashMap;
+
+use net::raw::ether::MacAddr;
+
+use utils::RuntimeError;
+
+use openssl::crypto::hash;
+
+use openssl::crypto::hash::Type as HashType;
+
+/// Built-in database.
+static EMBEDDED_DATABASE: &'static str = include_str!("oui.txt");
+
+/// Minimum number of entries of a downloaded database.
+const MIN_ENTRIES: usize = 10;
+
+/// OUI vendor database.
+#[derive(Debug, Clone)]
+pub struct OuiDatabase {
+    vendors: HashMap<[u8; 3], String>,
+}
+
+impl OuiDatabase {
+    /// Get the built-in database.
+    pub fn embedded() -> OuiDatabase {
+        OuiDatabase::parse(EMBEDDED_DATABASE)
+            .unwrap()
+    }
+
+    /// Parse a given database.
+    pub fn parse(content: &str) -> Result<OuiDatabase, RuntimeError> {
+        let mut vendors = HashMap::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let oui = try!(parse_oui(line)
+                .ok_or(RuntimeError::from(format!(
+                    "invalid OUI database entry on line {}", index + 1))));
+
+            let vendor = line[6..].trim();
+
+            if vendor.is_empty() {
+                return Err(RuntimeError::from(format!(
+                    "missing vendor name on line {}", index + 1)));
+            }
+
+            vendors.insert(oui, vendor.to_string());
+        }
+
+        let res = OuiDatabase {
+            vendors: vendors
+        };
+
+        Ok(res)
+    }
+
+    /// Load a database from a given file. Entries of the file extend the
+    /// built-in database.
+    pub fn load(file: &str) -> Result<OuiDatabase, RuntimeError> {
+        let content = try!(::utils::config::read_file(file)
+            .map_err(|err| RuntimeError::from(format!(
+                "unable to read OUI database \"{}\": {}", file, err))));
+
+        let mut res = OuiDatabase::embedded();
+
+        res.extend(try!(OuiDatabase::parse(&content)));
+
+        Ok(res)
+    }
+
+    /// Add all entries from a given database.
+    pub fn extend(&mut self, other: OuiDatabase) {
+        self.vendors.extend(other.vendors);
+    }
+
+    /// Get number of entries.
+    pub fn len(&self) -> usize {
+        self.vendors.len()
+    }
+
+    /// Get vendor of a device with a given MAC address (None is returned for
+    /// unknown and locally administered addresses).
+    pub fn vendor(&self, mac: &MacAddr) -> Option<&str> {
+        let octets = mac.octets();
+
+        if (octets[0] & 0x02) != 0 {
+            return None;
+        }
+
+        self.vendors.get(&[octets[0], octets[1], octets[2]])
+            .map(|vendor| vendor as &str)
+    }
+}
+
+/// Parse the OUI at the beginning of a given line.
+fn parse_oui(line: &str) -> Option<[u8; 3]> {
+    if line.len() < 6 || !line.is_char_boundary(6) {
+        return None;
+    }
+
+    let mut res = [0u8; 3];
+
+    for i in 0..3 {
+        match u8::from_str_radix(&line[i * 2..i * 2 + 2], 16) {
+            Ok(octet) => res[i] = octet,
+            Err(_)    => return None
+        }
+    }
+
+    Some(res)
+}
+
+/// Verify a given database file content against a given SHA-256 checksum
+/// (hex encoded) and parse it.
+pub fn verify(content: &[u8], checksum: &str) -> Result<OuiDatabase, RuntimeError> {
+    let digest = hash::hash(HashType::SHA256, content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if digest != checksum.trim().to_lowercase() {
+        return Err(RuntimeError::from("OUI database checksum mismatch"));
+    }
+
+    let content = try!(str::from_utf8(content)
+        .or(Err(RuntimeError::from("OUI database is not a valid UTF-8 file"))));
+
+    let res = try!(OuiDatabase::parse(content));
+
+    if res.len() < MIN_ENTRIES {
+        return Err(RuntimeError::from("OUI database is too small"));
+    }
+
+    Ok(res)
+}
+
+/// Download a database from a given HTTP URL and verify it against a given
+/// SHA-256 checksum. The checksum ensures integrity, so plain HTTP is
+/// sufficient.
+#[cfg(feature = "discovery")]
+pub fn fetch(url: &str, checksum: &str) -> Result<Vec<u8>, RuntimeError> {
+    use std::str::FromStr;
+
+    use net::http::Client;
+
+    use regex::Regex;
+
+    let re = Regex::new(r"^http://([^/:]+)(:(\d+))?(/.*)?$")
+        .unwrap();
+
+    let caps = try!(re.captures(url)
+        .ok_or(RuntimeError::from("invalid URL (http://host[:port]/path expected)")));
+
+    let host = caps.at(1).unwrap();
+    let port = caps.at(3)
+        .map(|port| u16::from_str(port))
+        .unwrap_or(Ok(80));
+    let port = try!(port
+        .or(Err(RuntimeError::from("invalid port"))));
+    let path = caps.at(4)
+        .unwrap_or("/");
+
+    let response = Client::new(host, port)
+        .and_then(|mut client| {
+            try!(client.set_timeout(Some(20000)));
+            client.get(path, &[])
+        });
+
+    let response = try!(response
+        .map_err(|err| RuntimeError::from(format!(
+            "unable to download OUI database: {}", err))));
+
+    if response.header.code != 200 {
+        return Err(RuntimeError::from(format!(
+            "unable to download OUI database: {}", response.header.line)));
+    }
+
+    try!(verify(&response.body, checksum));
+
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use net::raw::ether::MacAddr;
+
+    use openssl::crypto::hash;
+
+    use openssl::crypto::hash::Type as HashType;
+
+    #[test]
+    fn test_oui_database() {
+        let db = OuiDatabase::embedded();
+
+        assert!(db.len() >= 10);
+
+        assert_eq!(db.vendor(&MacAddr::new(0x00, 0x40, 0x8c, 1, 2, 3)),
+            Some("Axis Communications"));
+        assert_eq!(db.vendor(&MacAddr::new(0x02, 0x40, 0x8c, 1, 2, 3)),
+            None);
+
+        let mut db = OuiDatabase::parse("# comment\n\n0a0b0c Foo\n00408c Bar\n")
+            .unwrap();
+
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.vendor(&MacAddr::new(0x0a, 0x0b, 0x0c, 0, 0, 0)),
+            None);
+
+        let mut embedded = OuiDatabase::embedded();
+
+        embedded.extend(db.clone());
+
+        assert_eq!(embedded.vendor(&MacAddr::new(0x00, 0x40, 0x8c, 0, 0, 0)),
+            Some("Bar"));
+
+        db = OuiDatabase::parse("0c0b0a Foo\n").unwrap();
+
+        assert_eq!(db.vendor(&MacAddr::new(0x0c, 0x0b, 0x0a, 0, 0, 0)),
+            Some("Foo"));
+
+        assert!(OuiDatabase::parse("00408 Foo\n").is_err());
+        assert!(OuiDatabase::parse("00408c\n").is_err());
+    }
+
+    #[test]
+    fn test_oui_verification() {
+        let content = (0..16)
+            .map(|i| format!("0000{:02x} Vendor {}\n", i * 4, i))
+            .collect::<String>();
+
+        let checksum = hash::hash(HashType::SHA256, content.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        let db = verify(content.as_bytes(), &checksum.to_uppercase())
+            .unwrap();
+
+        assert_eq!(db.len(), 16);
+
+        assert!(verify(content.as_bytes(), "00").is_err());
+        assert!(verify(b"000000 Foo\n", &checksum).is_err());
+    }
+}
@@ -92,16 +92,12 @@ impl Ipv4PacketHeader {
             let flags_foffset = u16::from_be(rh.flags_foffset);
             let ihl           = rh.vihl & 0x0f;
             let options_len   = ihl as usize - (size >> 2);
-            let offset_1      = size as isize;
 
             if data.len() < (size + (options_len << 2)) {
                 Err(PacketParseError::from("unable to parse IPv4 packet, not enough data"))
             } else {
-                let options = unsafe {
-                    utils::vec_from_raw_parts(
-                        ptr.offset(offset_1) as *const u32,
-                        options_len)
-                };
+                let options = raw::utils::slice_to_raw_words(
+                    &data[size..size + (options_len << 2)]);
 
                 let res = Ipv4PacketHeader {
                     version:  rh.vihl >> 4,
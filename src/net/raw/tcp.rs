@@ -88,11 +88,8 @@ impl Ipv4PacketBody for TcpPacket {
             if offset_2 > data.len() {
                 Err(PacketParseError::from("unable to parse TCP packet, not enough data"))
             } else {
-                let options = unsafe {
-                    utils::vec_from_raw_parts(
-                        ptr.offset(offset_1 as isize) as *const u32, 
-                        options_len)
-                };
+                let options = raw::utils::slice_to_raw_words(
+                    &data[offset_1..offset_2]);
                 
                 let res = TcpPacket {
                     sport:   u16::from_be(rh.sport),
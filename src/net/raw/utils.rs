@@ -19,6 +19,8 @@ use std::slice;
 
 use std::net::Ipv4Addr;
 
+use utils::endian;
+
 /// Sum a given Sized type instance as 16-bit unsigned big endian numbers.
 pub fn sum_type<T: Sized>(data: &T) -> u32 {
     let size = mem::size_of::<T>();
@@ -38,20 +40,20 @@ pub fn sum_slice<T: Sized>(data: &[T]) -> u32 {
     }
 }
 
-/// Sum given raw data as 16-bit unsigned big endian numbers.
+/// Sum given raw data as 16-bit unsigned big endian numbers (the data do
+/// not need to be aligned).
 pub unsafe fn sum_raw_be(data: *const u8, size: usize) -> u32 {
-    let sdata        = slice::from_raw_parts(data as *const u16, size >> 1);
     let slice        = slice::from_raw_parts(data, size);
     let mut sum: u32 = 0;
-    for w in sdata {
-        sum = sum.wrapping_add(u16::from_be(*w) as u32);
+    for w in slice.chunks(2) {
+        if w.len() == 2 {
+            sum = sum.wrapping_add(endian::get_u16(w) as u32);
+        } else {
+            sum = sum.wrapping_add((w[0] as u32) << 8);
+        }
     }
     
-    if (size & 0x01) != 0 {
-        sum.wrapping_add((slice[size - 1] as u32) << 8)
-    } else {
-        sum
-    }
+    sum
 }
 
 /// Convert given 32-bit unsigned sum into 16-bit unsigned checksum.
@@ -71,17 +73,23 @@ pub fn slice_to_ipv4addr(slice: &[u8]) -> Ipv4Addr {
     if slice.len() < 4 {
         panic!("slice is too short");
     } else {
-        let ptr  = slice.as_ptr() as *const u32;
-        let addr = unsafe { u32::from_be(*ptr) };
-        Ipv4Addr::from(addr)
+        Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3])
     }
 }
 
 /// Convert a given IPv4 address into big endian 32-bit unsigned number.
 pub fn ipv4addr_to_u32(addr: &Ipv4Addr) -> u32 {
-    let octets  = addr.octets();
-    let nr: u32 = unsafe { mem::transmute(octets) };
-    nr.to_be()
+    endian::get_u32(&addr.octets())
+}
+
+/// Copy given raw data into a vector of 32-bit words. The words keep the
+/// byte order of the data (i.e. they can be serialized back as they are)
+/// and the data do not need to be aligned.
+pub fn slice_to_raw_words(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .filter(|w| w.len() == 4)
+        .map(|w| endian::get_u32(w).to_be())
+        .collect()
 }
 
 #[cfg(test)]
@@ -130,6 +138,24 @@ mod tests {
         assert_eq!(buffer, addr.octets());
     }
     
+    #[test]
+    fn test_sum_unaligned() {
+        let data = [0u8, 1, 2, 3, 4, 5];
+        
+        let sum = unsafe { sum_raw_be(data[1..].as_ptr(), 5) };
+        
+        assert_eq!(0x0102 + 0x0304 + 0x0500, sum);
+    }
+    
+    #[test]
+    fn test_slice_to_raw_words() {
+        let data  = [0u8, 1, 2, 3, 4, 5, 6, 7, 8];
+        let words = slice_to_raw_words(&data[1..]);
+        
+        assert_eq!(words.len(), 2);
+        assert_eq!(sum_slice(&words), 0x0102 + 0x0304 + 0x0506 + 0x0708);
+    }
+    
     #[test]
     fn test_ipv4addr_to_u32() {
         let addr = Ipv4Addr::new(192, 168, 2, 5);
@@ -94,9 +94,11 @@ type Header = (String, String);
 
 /// RTSP method.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
 enum Method {
     OPTIONS,
     DESCRIBE,
+    SET_PARAMETER,
 }
 
 impl Method {
@@ -105,6 +107,7 @@ impl Method {
         match self {
             Method::OPTIONS  => "OPTIONS",
             Method::DESCRIBE => "DESCRIBE",
+            Method::SET_PARAMETER => "SET_PARAMETER",
         }
     }
 }
@@ -114,6 +117,7 @@ struct Request {
     method:   Method,
     endpoint: String,
     headers:  Vec<Header>,
+    body:     String,
 }
 
 impl Request {
@@ -122,7 +126,8 @@ impl Request {
         Request {
             method:   method,
             endpoint: endpoint.to_string(),
-            headers:  Vec::new()
+            headers:  Vec::new(),
+            body:     String::new()
         }
     }
     
@@ -132,6 +137,16 @@ impl Request {
         self.headers.push((name.to_string(), value.to_string()));
         self
     }
+    
+    /// Set request body of a given content type.
+    fn set_body(self, content_type: &str, body: &str) -> Request {
+        let mut res = self.add_header("Content-Type", content_type)
+            .add_header("Content-Length", body.len());
+        
+        res.body = body.to_string();
+        
+        res
+    }
 }
 
 impl Display for Request {
@@ -141,7 +156,8 @@ impl Display for Request {
         for &(ref name, ref val) in &self.headers {
             try!(f.write_str(&format!("{}: {}\r\n", name, val)));
         }
-        f.write_str("\r\n")
+        try!(f.write_str("\r\n"));
+        f.write_str(&self.body)
     }
 }
 
@@ -513,6 +529,15 @@ impl Client {
         self.perform_request(&request)
     }
     
+    /// Send SET_PARAMETER command with given parameters (one "name: value"
+    /// pair per line).
+    pub fn set_parameter(&mut self, path: &str, params: &str) -> Result<Response> {
+        let request = self.create_request(Method::SET_PARAMETER, path, 1)
+            .set_body("text/parameters", params);
+        
+        self.perform_request(&request)
+    }
+    
     /// Create an RTSP request for a given method, path and sequence number.
     fn create_request(
         &self, 
@@ -593,6 +618,25 @@ fn test_rtsp_request() {
     assert_eq!(expected, msg);
 }
 
+#[cfg(test)]
+#[test]
+fn test_rtsp_request_body() {
+    let request = Request::new(Method::SET_PARAMETER, "rtsp://127.0.0.1:554/foo")
+        .add_header("CSeq", 1)
+        .set_body("text/parameters", "max-bitrate: 2000\r\n");
+    
+    let expected = "SET_PARAMETER rtsp://127.0.0.1:554/foo RTSP/1.0\r\n".to_string()
+        + "CSeq: 1\r\n"
+        + "Content-Type: text/parameters\r\n"
+        + "Content-Length: 19\r\n"
+        + "\r\n"
+        + "max-bitrate: 2000\r\n";
+    
+    let msg = format!("{}", request);
+    
+    assert_eq!(expected, msg);
+}
+
 #[cfg(test)]
 #[test]
 fn test_rtsp_response() {
@@ -0,0 +1,540 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SAP/SDP multicast session announcements (RFC 2974).
+//!
+//! Some encoders do not run an RTSP server, they only send their streams
+//! into a multicast group and announce them periodically using SAP. The
+//! listener below collects the announcements, so that the network scanner
+//! can turn the announced streams into service table entries. Multicast
+//! details of the streams (group, TTL, origin) are kept for UDP relaying.
+
+use std::str;
+use std::thread;
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use utils::{Shared, RuntimeError};
+use utils::clock;
+use utils::config::AppContext;
+use utils::logger::Logger;
+
+use utils::endian::ByteReader;
+
+/// SAP multicast group (global scope IPv4 announcements).
+const SAP_GROUP: [u8; 4] = [224, 2, 127, 254];
+
+/// SAP port.
+const SAP_PORT: u16 = 9875;
+
+/// Announcements which have not been repeated for this time are considered
+/// to be deleted (in seconds; RFC 2974 recommends an hour at least).
+const ANNOUNCEMENT_TIMEOUT: i64 = 3600;
+
+/// Maximum size of a SAP packet.
+const MAX_PACKET_SIZE: usize = 65535;
+
+/// Maximum number of tracked announcements.
+const MAX_ANNOUNCEMENTS: usize = 256;
+
+/// Maximum number of streams taken from a single announcement.
+const MAX_STREAMS: usize = 16;
+
+/// Size of the fixed part of the SAP header.
+const SAP_HEADER_SIZE: usize = 4;
+
+const SAP_FLAG_IPV6:       u8 = 0x10;
+const SAP_FLAG_DELETION:   u8 = 0x04;
+const SAP_FLAG_ENCRYPTED:  u8 = 0x02;
+const SAP_FLAG_COMPRESSED: u8 = 0x01;
+
+/// Multicast stream announced using SAP.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct MulticastStream {
+    /// Address of the announcing host.
+    pub origin:   IpAddr,
+    /// Multicast group and port of the stream.
+    pub group:    SocketAddr,
+    /// Multicast TTL (IPv4 only).
+    pub ttl:      Option<u8>,
+    /// Media type (e.g. "video").
+    pub media:    String,
+    /// Transport protocol (e.g. "RTP/AVP").
+    pub protocol: String,
+    /// Session name.
+    pub name:     String,
+}
+
+/// Single SAP announcement.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    /// Message ID hash.
+    pub hash:     u16,
+    /// Source address of the announcement.
+    pub origin:   IpAddr,
+    /// The announcement deletes a previously announced session.
+    pub deletion: bool,
+    /// Session description.
+    pub sdp:      String,
+}
+
+impl Announcement {
+    /// Parse a given SAP packet received from a given source. Encrypted and
+    /// compressed announcements are not supported. The originating source
+    /// field of the SAP header is ignored as it can be set to anything, the
+    /// packet source address is used instead.
+    pub fn parse(
+        packet: &[u8],
+        source: IpAddr) -> Result<Announcement, RuntimeError> {
+        if packet.len() < SAP_HEADER_SIZE {
+            return Err(RuntimeError::from("SAP packet too short"));
+        }
+
+        let mut reader = ByteReader::new(packet);
+
+        let flags    = reader.read_u8();
+        let auth_len = reader.read_u8() as usize * 4;
+        let hash     = reader.read_u16();
+
+        if (flags >> 5) != 1 {
+            return Err(RuntimeError::from("unsupported SAP version"));
+        } else if (flags & (SAP_FLAG_ENCRYPTED | SAP_FLAG_COMPRESSED)) != 0 {
+            return Err(RuntimeError::from("encrypted or compressed SAP announcement"));
+        }
+
+        let addr_len = if (flags & SAP_FLAG_IPV6) == 0 { 4 } else { 16 };
+
+        if packet.len() < (SAP_HEADER_SIZE + addr_len + auth_len) {
+            return Err(RuntimeError::from("SAP packet too short"));
+        }
+
+        reader.read_bytes(addr_len + auth_len);
+
+        let mut payload = &packet[reader.position()..];
+
+        // the payload type is optional, SDP is assumed if it is missing
+        if !payload.starts_with(b"v=") {
+            let end = match payload.iter().position(|&b| b == 0) {
+                Some(end) => end,
+                None      => return Err(RuntimeError::from("invalid SAP payload type"))
+            };
+
+            if &payload[..end] != b"application/sdp" {
+                return Err(RuntimeError::from("unsupported SAP payload type"));
+            }
+
+            payload = &payload[end + 1..];
+        }
+
+        let sdp = match str::from_utf8(payload) {
+            Ok(sdp) => sdp.to_string(),
+            Err(_)  => return Err(RuntimeError::from("invalid SDP encoding"))
+        };
+
+        let res = Announcement {
+            hash:     hash,
+            origin:   source,
+            deletion: (flags & SAP_FLAG_DELETION) != 0,
+            sdp:      sdp
+        };
+
+        Ok(res)
+    }
+
+    /// Get multicast streams described by the session description. Media
+    /// without a multicast connection address are skipped and at most
+    /// MAX_STREAMS streams are returned.
+    pub fn streams(&self) -> Vec<MulticastStream> {
+        let mut res = Vec::new();
+
+        let mut name       = String::new();
+        let mut session_c  = None;
+        let mut media: Option<(String, u16, String)> = None;
+        let mut media_c    = None;
+
+        for line in self.sdp.lines() {
+            let line = line.trim();
+
+            if line.starts_with("s=") {
+                name = line[2..].to_string();
+            } else if line.starts_with("c=") {
+                let connection = parse_connection(&line[2..]);
+
+                if media.is_some() {
+                    media_c = connection;
+                } else {
+                    session_c = connection;
+                }
+            } else if line.starts_with("m=") {
+                if let Some(stream) = self.stream(&name, media.take(), media_c.take().or(session_c)) {
+                    res.push(stream);
+                }
+
+                media = parse_media(&line[2..]);
+            }
+        }
+
+        if let Some(stream) = self.stream(&name, media, media_c.or(session_c)) {
+            res.push(stream);
+        }
+
+        res.truncate(MAX_STREAMS);
+
+        res
+    }
+
+    /// Create a multicast stream from a given media description and a given
+    /// connection address.
+    fn stream(
+        &self,
+        name: &str,
+        media: Option<(String, u16, String)>,
+        connection: Option<(IpAddr, Option<u8>)>) -> Option<MulticastStream> {
+        let (media, port, protocol) = match media {
+            Some(media) => media,
+            None        => return None
+        };
+
+        let (addr, ttl) = match connection {
+            Some(connection) => connection,
+            None             => return None
+        };
+
+        let multicast = match addr {
+            IpAddr::V4(ref addr) => addr.is_multicast(),
+            IpAddr::V6(ref addr) => addr.is_multicast()
+        };
+
+        if !multicast || port == 0 {
+            return None;
+        }
+
+        let res = MulticastStream {
+            origin:   self.origin,
+            group:    SocketAddr::new(addr, port),
+            ttl:      ttl,
+            media:    media,
+            protocol: protocol,
+            name:     name.to_string()
+        };
+
+        Some(res)
+    }
+}
+
+/// Parse a given SDP connection line value (e.g. "IN IP4 239.1.1.1/16").
+fn parse_connection(value: &str) -> Option<(IpAddr, Option<u8>)> {
+    let fields = value.split_whitespace()
+        .collect::<Vec<_>>();
+
+    if fields.len() < 3 || fields[0] != "IN" {
+        return None;
+    }
+
+    let mut parts = fields[2].split('/');
+
+    let addr = parts.next()
+        .unwrap_or("");
+
+    match fields[1] {
+        "IP4" => {
+            let ttl = parts.next()
+                .and_then(|ttl| ttl.parse().ok());
+
+            addr.parse::<Ipv4Addr>()
+                .ok()
+                .map(|addr| (IpAddr::V4(addr), ttl))
+        },
+        "IP6" => addr.parse::<Ipv6Addr>()
+            .ok()
+            .map(|addr| (IpAddr::V6(addr), None)),
+        _ => None
+    }
+}
+
+/// Parse a given SDP media line value (e.g. "video 5004 RTP/AVP 96").
+fn parse_media(value: &str) -> Option<(String, u16, String)> {
+    let fields = value.split_whitespace()
+        .collect::<Vec<_>>();
+
+    if fields.len() < 3 {
+        return None;
+    }
+
+    let port = fields[1].split('/')
+        .next()
+        .and_then(|port| port.parse().ok());
+
+    port.map(|port| (fields[0].to_string(), port, fields[2].to_string()))
+}
+
+/// Currently announced multicast streams.
+#[derive(Debug, Clone)]
+pub struct Announcements {
+    /// Streams by their announcement (origin and message ID hash) together
+    /// with the time of the last announcement.
+    sessions: HashMap<(IpAddr, u16), (i64, Vec<MulticastStream>)>,
+}
+
+impl Announcements {
+    /// Create an empty set of announcements.
+    pub fn new() -> Announcements {
+        Announcements {
+            sessions: HashMap::new()
+        }
+    }
+
+    /// Process a given announcement received at a given time (UNIX
+    /// timestamp). New sessions are ignored if there are already
+    /// MAX_ANNOUNCEMENTS sessions (false is returned in such case).
+    pub fn update(&mut self, announcement: &Announcement, timestamp: i64) -> bool {
+        let key = (announcement.origin, announcement.hash);
+
+        if announcement.deletion {
+            self.sessions.remove(&key);
+        } else {
+            if !self.sessions.contains_key(&key) {
+                self.remove_expired(timestamp);
+
+                if self.sessions.len() >= MAX_ANNOUNCEMENTS {
+                    return false;
+                }
+            }
+
+            self.sessions.insert(key, (timestamp, announcement.streams()));
+        }
+
+        true
+    }
+
+    /// Get streams announced within the announcement timeout before a given
+    /// time (UNIX timestamp). Expired announcements are dropped.
+    pub fn streams(&mut self, timestamp: i64) -> Vec<MulticastStream> {
+        self.remove_expired(timestamp);
+
+        // the same stream can be announced more than once
+        self.sessions.values()
+            .flat_map(|&(_, ref streams)| streams.iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Remove announcements which have not been repeated within the
+    /// announcement timeout before a given time (UNIX timestamp).
+    fn remove_expired(&mut self, timestamp: i64) {
+        let expired = self.sessions.iter()
+            .filter(|&(_, &(last_seen, _))| (last_seen + ANNOUNCEMENT_TIMEOUT) < timestamp)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            self.sessions.remove(&key);
+        }
+    }
+}
+
+/// SAP listener.
+pub struct SapListener<L: Logger> {
+    logger:      L,
+    app_context: Shared<AppContext>,
+}
+
+impl<L: 'static + Logger + Send> SapListener<L> {
+    /// Create a new SAP listener storing the announcements into a given
+    /// application context.
+    pub fn new(logger: L, app_context: Shared<AppContext>) -> SapListener<L> {
+        SapListener {
+            logger:      logger,
+            app_context: app_context
+        }
+    }
+
+    /// Join the SAP multicast group and process announcements in a
+    /// background thread.
+    pub fn start(mut self) -> Result<(), RuntimeError> {
+        let addr   = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), SAP_PORT);
+        let socket = try!(UdpSocket::bind(addr)
+            .map_err(|err| RuntimeError::from(format!("unable to bind SAP socket: {}", err))));
+
+        let group = Ipv4Addr::new(SAP_GROUP[0], SAP_GROUP[1], SAP_GROUP[2], SAP_GROUP[3]);
+
+        try!(socket.join_multicast_v4(&group, &Ipv4Addr::new(0, 0, 0, 0))
+            .map_err(|err| RuntimeError::from(format!("unable to join SAP group: {}", err))));
+
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+
+            loop {
+                let (len, source) = match socket.recv_from(&mut buffer) {
+                    Ok(res)  => res,
+                    Err(err) => {
+                        log_warn!(self.logger, "SAP listener error: {}", err);
+                        break;
+                    }
+                };
+
+                match Announcement::parse(&buffer[..len], source.ip()) {
+                    Ok(announcement) => self.process(&announcement),
+                    Err(err) => log_debug!(self.logger, "ignoring SAP announcement: {}", err)
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Process a given announcement.
+    fn process(&mut self, announcement: &Announcement) {
+        if announcement.deletion {
+            log_debug!(self.logger, "SAP session deleted (origin: {}, hash: {:04x})",
+                announcement.origin, announcement.hash);
+        } else {
+            log_debug!(self.logger, "SAP session announced (origin: {}, hash: {:04x})",
+                announcement.origin, announcement.hash);
+        }
+
+        let accepted = self.app_context.lock()
+            .unwrap()
+            .sap_announcements
+            .update(announcement, clock::timestamp());
+
+        if !accepted {
+            log_debug!(self.logger, "too many SAP sessions, ignoring announcement (origin: {}, hash: {:04x})",
+                announcement.origin, announcement.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    const SDP: &'static str = "v=0\r\n\
+        o=- 1 1 IN IP4 192.168.1.20\r\n\
+        s=Encoder 1\r\n\
+        c=IN IP4 239.1.1.1/16\r\n\
+        t=0 0\r\n\
+        m=video 5004 RTP/AVP 96\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        m=audio 5006 RTP/AVP 0\r\n\
+        c=IN IP4 192.168.1.20\r\n";
+
+    fn packet(flags: u8, payload_type: bool) -> Vec<u8> {
+        let mut res = vec![flags, 0, 0x12, 0x34, 10, 0, 0, 1];
+
+        if payload_type {
+            res.extend_from_slice(b"application/sdp\0");
+        }
+
+        res.extend_from_slice(SDP.as_bytes());
+        res
+    }
+
+    fn origin() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))
+    }
+
+    #[test]
+    fn test_announcement_parsing() {
+        let announcement = Announcement::parse(&packet(0x20, true), origin())
+            .unwrap();
+
+        // the origin field of the SAP header is ignored
+        assert_eq!(announcement.hash, 0x1234);
+        assert_eq!(announcement.origin, origin());
+        assert!(!announcement.deletion);
+
+        // the audio stream is unicast
+        let streams = announcement.streams();
+
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].group, "239.1.1.1:5004".parse::<SocketAddr>().unwrap());
+        assert_eq!(streams[0].ttl, Some(16));
+        assert_eq!(streams[0].media, "video");
+        assert_eq!(streams[0].name, "Encoder 1");
+
+        assert!(Announcement::parse(&packet(0x20, false), origin()).is_ok());
+        assert!(Announcement::parse(&packet(0x22, true), origin()).is_err());
+        assert!(Announcement::parse(&packet(0x40, true), origin()).is_err());
+        assert!(Announcement::parse(&packet(0x20, true)[..6], origin()).is_err());
+    }
+
+    #[test]
+    fn test_announcements() {
+        let mut announcements = Announcements::new();
+
+        let announcement = Announcement::parse(&packet(0x20, true), origin())
+            .unwrap();
+
+        announcements.update(&announcement, 1000);
+
+        assert_eq!(announcements.streams(2000).len(), 1);
+
+        let deletion = Announcement::parse(&packet(0x24, true), origin())
+            .unwrap();
+
+        announcements.update(&deletion, 2000);
+
+        assert!(announcements.streams(2000).is_empty());
+
+        announcements.update(&announcement, 1000);
+
+        assert!(announcements.streams(1000 + ANNOUNCEMENT_TIMEOUT + 1).is_empty());
+    }
+
+    #[test]
+    fn test_announcement_limits() {
+        let mut sdp = String::from("v=0\r\nc=IN IP4 239.1.1.1/16\r\n");
+
+        for port in 0..(2 * MAX_STREAMS) {
+            sdp.push_str(&format!("m=video {} RTP/AVP 96\r\n", 5000 + port));
+        }
+
+        let mut announcement = Announcement {
+            hash:     0,
+            origin:   origin(),
+            deletion: false,
+            sdp:      sdp
+        };
+
+        assert_eq!(announcement.streams().len(), MAX_STREAMS);
+
+        let mut announcements = Announcements::new();
+
+        for hash in 0..MAX_ANNOUNCEMENTS {
+            announcement.hash = hash as u16;
+
+            assert!(announcements.update(&announcement, 1000));
+        }
+
+        announcement.hash = MAX_ANNOUNCEMENTS as u16;
+
+        assert!(!announcements.update(&announcement, 1000));
+
+        // known sessions can be still refreshed
+        announcement.hash = 0;
+
+        assert!(announcements.update(&announcement, 2000));
+
+        // expired sessions make room for new ones
+        announcement.hash = MAX_ANNOUNCEMENTS as u16;
+
+        assert!(announcements.update(&announcement, 1000 + ANNOUNCEMENT_TIMEOUT + 1));
+        assert_eq!(announcements.sessions.len(), 2);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal SNTP client (RFC 4330).
+//!
+//! The client is intended for gateways without a system NTP daemon. It
+//! measures offset of the system clock using a given list of NTP servers and
+//! it sets the process clock offset accordingly (see `utils::clock`). The
+//! system clock is not modified.
+
+use std::thread;
+
+use std::time::Duration;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use utils::clock;
+use utils::RuntimeError;
+use utils::logger::Logger;
+
+use time;
+
+/// Default NTP port.
+const NTP_PORT: u16 = 123;
+
+/// NTP query timeout (in milliseconds).
+const QUERY_TIMEOUT: u64 = 2000;
+
+/// Synchronization period (in seconds).
+const SYNC_PERIOD: u64 = 3600;
+
+/// Synchronization retry period (in seconds).
+const RETRY_PERIOD: u64 = 60;
+
+/// Number of seconds between the NTP epoch (1900) and the UNIX epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2208988800;
+
+/// NTP packet size (without any extensions).
+const NTP_PACKET_SIZE: usize = 48;
+
+const NTP_VERSION:     u8 = 4;
+const NTP_MODE_CLIENT: u8 = 3;
+const NTP_MODE_SERVER: u8 = 4;
+const NTP_LI_ALARM:    u8 = 3;
+
+/// SNTP client.
+pub struct SntpClient<L: Logger> {
+    logger:  L,
+    servers: Vec<String>,
+}
+
+impl<L: 'static + Logger + Send> SntpClient<L> {
+    /// Create a new SNTP client using given servers ("host[:port]" format).
+    pub fn new(logger: L, servers: Vec<String>) -> SntpClient<L> {
+        SntpClient {
+            logger:  logger,
+            servers: servers
+        }
+    }
+
+    /// Synchronize the process clock and keep it synchronized in a
+    /// background thread. The first synchronization is done immediately.
+    pub fn start(mut self) {
+        let synchronized = self.sync();
+
+        thread::spawn(move || {
+            let mut synchronized = synchronized;
+
+            loop {
+                let period = if synchronized {
+                    SYNC_PERIOD
+                } else {
+                    RETRY_PERIOD
+                };
+
+                thread::sleep(Duration::from_secs(period));
+
+                synchronized = self.sync();
+            }
+        });
+    }
+
+    /// Measure the system clock offset and set the process clock offset.
+    fn sync(&mut self) -> bool {
+        match measure_offset(&self.servers) {
+            Ok(offset) => {
+                let offset = offset.round() as i64;
+
+                if offset != clock::offset() || !clock::is_synchronized() {
+                    log_info!(self.logger, "process clock offset set to {} seconds", offset);
+                }
+
+                clock::set_offset(offset);
+
+                true
+            },
+            Err(err) => {
+                log_warn!(self.logger, "unable to synchronize process clock: {}", err);
+                false
+            }
+        }
+    }
+}
+
+/// Measure the system clock offset (in seconds) using given servers. The
+/// median of all successful measurements is returned.
+pub fn measure_offset(servers: &[String]) -> Result<f64, RuntimeError> {
+    let mut offsets    = Vec::new();
+    let mut last_error = RuntimeError::from("no NTP server available");
+
+    for server in servers {
+        match query(server) {
+            Ok(offset) => offsets.push(offset),
+            Err(err)   => last_error = err
+        }
+    }
+
+    if offsets.is_empty() {
+        return Err(last_error);
+    }
+
+    offsets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    Ok(offsets[offsets.len() / 2])
+}
+
+/// Measure the system clock offset (in seconds) using a given server.
+fn query(server: &str) -> Result<f64, RuntimeError> {
+    let addr = try!(resolve(server));
+
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    };
+
+    let timeout = Duration::from_millis(QUERY_TIMEOUT);
+    let socket  = try!(UdpSocket::bind(bind_addr)
+        .map_err(|err| RuntimeError::from(format!("{}", err))));
+
+    try!(socket.set_read_timeout(Some(timeout))
+        .map_err(|err| RuntimeError::from(format!("{}", err))));
+
+    let t1  = system_time();
+    let req = create_request(to_ntp_timestamp(t1));
+
+    try!(socket.send_to(&req, addr)
+        .map_err(|err| RuntimeError::from(format!("{}: {}", server, err))));
+
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        let (len, peer) = try!(socket.recv_from(&mut buffer)
+            .map_err(|err| RuntimeError::from(format!("{}: {}", server, err))));
+
+        if peer == addr {
+            let t4 = system_time();
+
+            return parse_response(&buffer[..len], t1, t4)
+                .map_err(|err| RuntimeError::from(format!("{}: {}", server, err)));
+        }
+    }
+}
+
+/// Resolve a given server address ("host[:port]" format).
+fn resolve(server: &str) -> Result<SocketAddr, RuntimeError> {
+    let res = if server.contains(':') {
+        server.to_socket_addrs()
+    } else {
+        (server, NTP_PORT).to_socket_addrs()
+    };
+
+    res.ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(RuntimeError::from(format!("unable to resolve NTP server \"{}\"", server)))
+}
+
+/// Get the current system time (UNIX timestamp in seconds).
+fn system_time() -> f64 {
+    let t = time::get_time();
+
+    t.sec as f64 + (t.nsec as f64 / 1000000000.0)
+}
+
+/// Convert a given UNIX timestamp into the NTP timestamp format.
+fn to_ntp_timestamp(t: f64) -> u64 {
+    let t    = t + NTP_UNIX_OFFSET as f64;
+    let sec  = t.floor();
+    let frac = ((t - sec) * 4294967296.0) as u64;
+
+    ((sec as u64) << 32) | (frac & 0xffffffff)
+}
+
+/// Convert a given NTP timestamp into a UNIX timestamp.
+fn from_ntp_timestamp(t: u64) -> f64 {
+    let sec  = (t >> 32) as f64;
+    let frac = (t & 0xffffffff) as f64 / 4294967296.0;
+
+    sec + frac - NTP_UNIX_OFFSET as f64
+}
+
+/// Create an SNTP request with a given transmit timestamp.
+fn create_request(transmit: u64) -> [u8; NTP_PACKET_SIZE] {
+    let mut res = [0u8; NTP_PACKET_SIZE];
+
+    res[0] = (NTP_VERSION << 3) | NTP_MODE_CLIENT;
+
+    write_u64(&mut res[40..], transmit);
+
+    res
+}
+
+/// Parse a given SNTP response to a request sent at t1 and received at t4
+/// and return the clock offset (in seconds).
+fn parse_response(data: &[u8], t1: f64, t4: f64) -> Result<f64, RuntimeError> {
+    if data.len() < NTP_PACKET_SIZE {
+        return Err(RuntimeError::from("NTP response too short"));
+    }
+
+    let li      = data[0] >> 6;
+    let mode    = data[0] & 7;
+    let stratum = data[1];
+
+    if mode != NTP_MODE_SERVER {
+        return Err(RuntimeError::from("unexpected NTP response mode"));
+    } else if stratum == 0 || stratum > 15 || li == NTP_LI_ALARM {
+        return Err(RuntimeError::from("NTP server is not synchronized"));
+    }
+
+    let origin   = read_u64(&data[24..]);
+    let receive  = read_u64(&data[32..]);
+    let transmit = read_u64(&data[40..]);
+
+    if origin != to_ntp_timestamp(t1) {
+        return Err(RuntimeError::from("NTP response does not match the request"));
+    } else if transmit == 0 {
+        return Err(RuntimeError::from("invalid NTP transmit timestamp"));
+    }
+
+    let t2 = from_ntp_timestamp(receive);
+    let t3 = from_ntp_timestamp(transmit);
+
+    Ok(((t2 - t1) + (t3 - t4)) / 2.0)
+}
+
+/// Write a given u64 in the network byte order.
+fn write_u64(buffer: &mut [u8], v: u64) {
+    for i in 0..8 {
+        buffer[i] = (v >> (56 - 8 * i)) as u8;
+    }
+}
+
+/// Read u64 in the network byte order.
+fn read_u64(data: &[u8]) -> u64 {
+    data[..8].iter()
+        .fold(0, |acc, b| (acc << 8) | (*b as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{create_request, parse_response, read_u64, write_u64};
+    use super::{to_ntp_timestamp, from_ntp_timestamp};
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(to_ntp_timestamp(0.0), 2208988800 << 32);
+        assert_eq!(to_ntp_timestamp(1.5), (2208988801 << 32) | 0x80000000);
+        assert_eq!(from_ntp_timestamp(to_ntp_timestamp(1000.25)), 1000.25);
+    }
+
+    #[test]
+    fn test_response_parsing() {
+        let t1 = 1000.0;
+        let t4 = 1001.0;
+
+        let mut response = create_request(to_ntp_timestamp(t1));
+
+        // LI = 0, VN = 4, mode = server, stratum = 2
+        response[0] = 0x24;
+        response[1] = 2;
+
+        // the server clock is 100 seconds ahead
+        let origin = read_u64(&response[40..]);
+
+        write_u64(&mut response[24..], origin);
+        write_u64(&mut response[32..], to_ntp_timestamp(1100.25));
+        write_u64(&mut response[40..], to_ntp_timestamp(1100.75));
+
+        assert_eq!(parse_response(&response, t1, t4).unwrap(), 100.0);
+
+        // unexpected origin timestamp
+        assert!(parse_response(&response, 999.0, t4).is_err());
+
+        // unsynchronized server
+        response[1] = 0;
+
+        assert!(parse_response(&response, t1, t4).is_err());
+        assert!(parse_response(&response[..40], t1, t4).is_err());
+        assert!(measure_offset(&[]).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal SOCKS5 client (RFC 1928).
+//!
+//! Only the CONNECT command is supported. The client can authenticate using
+//! username and password (RFC 1929). Host names of the targets are resolved
+//! by the proxy. The handshake is blocking, the connected stream can be
+//! switched into the non-blocking mode afterwards.
+
+use std::io;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::time::Duration;
+
+use utils::RuntimeError;
+use utils::secret::{Secret, Zeroize};
+
+use regex::Regex;
+
+const SOCKS_VERSION: u8 = 0x05;
+
+const AUTH_NONE:          u8 = 0x00;
+const AUTH_PASSWORD:      u8 = 0x02;
+const AUTH_NOT_ACCEPTED:  u8 = 0xff;
+const AUTH_PASSWORD_VERSION: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4:   u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6:   u8 = 0x04;
+
+/// SOCKS5 proxy.
+#[derive(Debug, Clone)]
+pub struct SocksProxy {
+    /// Proxy address ("host:port" format).
+    addr:        String,
+    /// Username and password.
+    credentials: Option<(String, Secret<String>)>,
+}
+
+impl SocksProxy {
+    /// Parse a given proxy specification ("[user:password@]host:port"
+    /// format).
+    pub fn parse(s: &str) -> Result<SocksProxy, RuntimeError> {
+        let re = Regex::new(r"^(?:([^:@]*):(.*)@)?([^@]+:[0-9]+)$")
+            .unwrap();
+
+        let caps = match re.captures(s) {
+            Some(caps) => caps,
+            None => return Err(RuntimeError::from(
+                "invalid SOCKS proxy, \"[user:password@]host:port\" expected"))
+        };
+
+        let credentials = caps.at(1)
+            .map(|user| {
+                let passwd = caps.at(2).unwrap();
+                (user.to_string(), Secret::new(passwd.to_string()))
+            });
+
+        if let Some((ref user, ref passwd)) = credentials {
+            if user.len() > 255 || passwd.expose().len() > 255 {
+                return Err(RuntimeError::from(
+                    "SOCKS proxy username and password must not be longer than 255 bytes"));
+            }
+        }
+
+        let res = SocksProxy {
+            addr:        caps.at(3).unwrap().to_string(),
+            credentials: credentials
+        };
+
+        Ok(res)
+    }
+
+    /// Get proxy address ("host:port" format).
+    pub fn address(&self) -> &str {
+        &self.addr
+    }
+
+    /// Connect to a given target ("host:port" format) through the proxy.
+    /// The proxy connection has to be established within a given time (in
+    /// milliseconds).
+    pub fn connect(&self, target: &str, timeout: u64) -> io::Result<TcpStream> {
+        let mut stream = try!(TcpStream::connect(&self.addr as &str));
+
+        let timeout = Duration::from_millis(timeout);
+
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+
+        try!(handshake(&mut stream, target, self.credentials.as_ref()));
+
+        try!(stream.set_read_timeout(None));
+        try!(stream.set_write_timeout(None));
+
+        Ok(stream)
+    }
+}
+
+/// Create a new protocol error.
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::Other, format!("SOCKS proxy error: {}", msg))
+}
+
+/// Perform the SOCKS5 handshake on a given stream.
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    target: &str,
+    credentials: Option<&(String, Secret<String>)>) -> io::Result<()> {
+    let request = try!(connect_request(target));
+
+    let method = if credentials.is_some() {
+        AUTH_PASSWORD
+    } else {
+        AUTH_NONE
+    };
+
+    try!(stream.write_all(&[SOCKS_VERSION, 1, method]));
+
+    let mut response = [0u8; 2];
+
+    try!(stream.read_exact(&mut response));
+
+    if response[0] != SOCKS_VERSION {
+        return Err(protocol_error("unsupported protocol version"));
+    } else if response[1] == AUTH_NOT_ACCEPTED || response[1] != method {
+        return Err(protocol_error("no acceptable authentication method"));
+    }
+
+    if let Some(&(ref user, ref passwd)) = credentials {
+        try!(authenticate(stream, user, passwd));
+    }
+
+    try!(stream.write_all(&request));
+
+    let mut response = [0u8; 4];
+
+    try!(stream.read_exact(&mut response));
+
+    if response[0] != SOCKS_VERSION {
+        return Err(protocol_error("unsupported protocol version"));
+    } else if response[1] != 0 {
+        return Err(protocol_error(reply_description(response[1])));
+    }
+
+    // skip the bound address
+    let len = match response[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            try!(stream.read_exact(&mut len));
+            len[0] as usize
+        },
+        _ => return Err(protocol_error("unknown address type"))
+    };
+
+    let mut bound = vec![0u8; len + 2];
+
+    stream.read_exact(&mut bound)
+}
+
+/// Authenticate using a given username and password (RFC 1929).
+fn authenticate<S: Read + Write>(
+    stream: &mut S,
+    user: &str,
+    passwd: &Secret<String>) -> io::Result<()> {
+    let passwd = passwd.expose();
+
+    // the capacity is final, so the request is never reallocated and it can
+    // be zeroed completely
+    let mut request = Vec::with_capacity(3 + user.len() + passwd.len());
+
+    request.push(AUTH_PASSWORD_VERSION);
+    request.push(user.len() as u8);
+    request.extend_from_slice(user.as_bytes());
+    request.push(passwd.len() as u8);
+    request.extend_from_slice(passwd.as_bytes());
+
+    let res = stream.write_all(&request);
+
+    request.zeroize();
+
+    try!(res);
+
+    let mut response = [0u8; 2];
+
+    try!(stream.read_exact(&mut response));
+
+    if response[1] != 0 {
+        Err(protocol_error("authentication failed"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Create a CONNECT request for a given target ("host:port" format).
+fn connect_request(target: &str) -> io::Result<Vec<u8>> {
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+
+    let port = if let Ok(addr) = SocketAddr::from_str(target) {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                request.push(ATYP_IPV6);
+                for segment in &ip.segments() {
+                    request.push((*segment >> 8) as u8);
+                    request.push(*segment as u8);
+                }
+            }
+        }
+
+        addr.port()
+    } else {
+        let mut parts = target.rsplitn(2, ':');
+
+        let port = parts.next()
+            .and_then(|port| u16::from_str(port).ok());
+        let host = parts.next()
+            .unwrap_or("");
+
+        let port = match port {
+            Some(port) if !host.is_empty() && host.len() < 256 => port,
+            _ => return Err(io::Error::new(ErrorKind::InvalidInput,
+                format!("invalid SOCKS target: {}", target)))
+        };
+
+        request.push(ATYP_DOMAIN);
+        request.push(host.len() as u8);
+        request.extend_from_slice(host.as_bytes());
+
+        port
+    };
+
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+
+    Ok(request)
+}
+
+/// Get description of a given reply code.
+fn reply_description(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _    => "unknown error"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{connect_request, handshake};
+
+    use std::io;
+
+    use std::io::{Read, Write};
+
+    use utils::secret::Secret;
+
+    /// Fake proxy stream with a given response.
+    struct FakeStream {
+        input:  io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_proxy_parsing() {
+        let proxy = SocksProxy::parse("proxy.local:1080").unwrap();
+
+        assert_eq!(proxy.address(), "proxy.local:1080");
+        assert!(proxy.credentials.is_none());
+
+        let proxy = SocksProxy::parse("user:p@ss:w0rd@10.0.0.1:1080").unwrap();
+        let (ref user, ref passwd) = *proxy.credentials.as_ref().unwrap();
+
+        assert_eq!(proxy.address(), "10.0.0.1:1080");
+        assert_eq!(user, "user");
+        assert_eq!(passwd.expose(), "p@ss:w0rd");
+
+        assert!(SocksProxy::parse("proxy.local").is_err());
+        assert!(SocksProxy::parse("user@proxy.local:1080").is_err());
+    }
+
+    #[test]
+    fn test_connect_request() {
+        assert_eq!(connect_request("10.0.0.1:554").unwrap(),
+            vec![5, 1, 0, 1, 10, 0, 0, 1, 0x02, 0x2a]);
+        assert_eq!(connect_request("[::1]:80").unwrap(),
+            vec![5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80]);
+        assert_eq!(connect_request("ab.cd:8900").unwrap(),
+            vec![5, 1, 0, 3, 5, b'a', b'b', b'.', b'c', b'd', 0x22, 0xc4]);
+
+        assert!(connect_request("ab.cd").is_err());
+        assert!(connect_request(":80").is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let credentials = ("user".to_string(), Secret::new("pass".to_string()));
+
+        let mut stream = FakeStream {
+            input:  io::Cursor::new(vec![5, 2, 1, 0, 5, 0, 0, 1, 1, 2, 3, 4, 0, 80]),
+            output: Vec::new()
+        };
+
+        handshake(&mut stream, "10.0.0.1:554", Some(&credentials))
+            .unwrap();
+
+        assert_eq!(stream.output, vec![
+            5, 1, 2,
+            1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's',
+            5, 1, 0, 1, 10, 0, 0, 1, 0x02, 0x2a]);
+
+        // rejected authentication
+        let mut stream = FakeStream {
+            input:  io::Cursor::new(vec![5, 2, 1, 1]),
+            output: Vec::new()
+        };
+
+        assert!(handshake(&mut stream, "10.0.0.1:554", Some(&credentials)).is_err());
+
+        // connection refused
+        let mut stream = FakeStream {
+            input:  io::Cursor::new(vec![5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+            output: Vec::new()
+        };
+
+        assert!(handshake(&mut stream, "10.0.0.1:554", None).is_err());
+
+        // unacceptable authentication method
+        let mut stream = FakeStream {
+            input:  io::Cursor::new(vec![5, 0xff]),
+            output: Vec::new()
+        };
+
+        assert!(handshake(&mut stream, "10.0.0.1:554", None).is_err());
+    }
+}
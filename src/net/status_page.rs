@@ -0,0 +1,389 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Embedded status page.
+//!
+//! A tiny read-only HTTP server showing the connection state, devices found
+//! by the network scanner and active sessions. It is meant for installers
+//! verifying a site without access to the cloud. The `/health` path returns
+//! a plain-text connection state which can be used by health checks, the
+//! `/status` path returns the connection state, the current service table,
+//! active sessions and the last connection error as JSON and the `/metrics`
+//! path returns connection metrics in the Prometheus text format.
+//! Every connection is handled in a separate thread. The number of concurrent
+//! connections, the request size and the time for receiving a request are
+//! limited.
+
+use std::cmp;
+use std::io;
+use std::thread;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use utils::Shared;
+use utils::config::AppContext;
+use utils::logger::Logger;
+use utils::status_led::LedState;
+
+use rustc_serialize::json;
+
+/// Time limit for receiving a whole request and for sending the response
+/// (in milliseconds).
+const REQUEST_TIMEOUT: u64 = 5000;
+
+/// Maximum size of a request (the request line and all headers).
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Maximum number of concurrently handled connections.
+const MAX_CONNECTIONS: usize = 8;
+
+/// JSON mapping of a service table entry.
+#[derive(RustcEncodable)]
+struct JsonService {
+    id:      u16,
+    service: String,
+}
+
+/// JSON mapping of the last connection error.
+#[derive(RustcEncodable)]
+struct JsonError {
+    timestamp: i64,
+    message:   String,
+}
+
+/// JSON mapping of the client status.
+#[derive(RustcEncodable)]
+struct JsonStatus {
+    uuid:       String,
+    state:      &'static str,
+    scanning:   bool,
+    services:   Vec<JsonService>,
+    sessions:   Vec<String>,
+    last_error: Option<JsonError>,
+}
+
+/// Reader of a single request. It enforces the request size limit and the
+/// deadline for receiving the whole request (a read timeout alone would allow
+/// a client to keep the connection open by sending a byte at a time).
+struct RequestReader<'a> {
+    stream:    &'a TcpStream,
+    deadline:  Instant,
+    remaining: usize,
+}
+
+impl<'a> RequestReader<'a> {
+    /// Create a new request reader for a given stream.
+    fn new(stream: &'a TcpStream) -> RequestReader<'a> {
+        RequestReader {
+            stream:    stream,
+            deadline:  Instant::now() + Duration::from_millis(REQUEST_TIMEOUT),
+            remaining: MAX_REQUEST_SIZE
+        }
+    }
+}
+
+impl<'a> Read for RequestReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                "request timeout"));
+        } else if self.remaining == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "request too large"));
+        }
+
+        try!(self.stream.set_read_timeout(Some(self.deadline - now)));
+
+        let len = cmp::min(buf.len(), self.remaining);
+        let len = try!(self.stream.read(&mut buf[..len]));
+
+        self.remaining -= len;
+
+        Ok(len)
+    }
+}
+
+/// Status page server.
+#[derive(Clone)]
+pub struct StatusPage<L: Logger> {
+    logger:      L,
+    app_context: Shared<AppContext>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl<L: 'static + Logger + Clone + Send> StatusPage<L> {
+    /// Create a new status page server.
+    pub fn new(logger: L, app_context: Shared<AppContext>) -> StatusPage<L> {
+        StatusPage {
+            logger:      logger,
+            app_context: app_context,
+            connections: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// Start listening on a given address. Incoming connections are accepted
+    /// in a background thread.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> io::Result<()> {
+        let listener = try!(TcpListener::bind(addr));
+
+        thread::spawn(move || self.accept_connections(listener));
+
+        Ok(())
+    }
+
+    /// Accept incoming connections and handle each of them in a separate
+    /// thread. Connections over the limit are closed immediately.
+    fn accept_connections(mut self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err)   => {
+                    log_debug!(self.logger, "status page error: {}", err);
+                    continue;
+                }
+            };
+
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+
+                log_debug!(self.logger, "status page connection limit reached, closing the connection");
+
+                continue;
+            }
+
+            let mut page = self.clone();
+
+            thread::spawn(move || {
+                if let Err(err) = page.handle_connection(stream) {
+                    log_debug!(page.logger, "status page error: {}", err);
+                }
+
+                page.connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    /// Read a single request from a given connection and send the response.
+    fn handle_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        try!(stream.set_write_timeout(
+            Some(Duration::from_millis(REQUEST_TIMEOUT))));
+
+        let mut request = String::new();
+
+        {
+            let mut reader = BufReader::new(RequestReader::new(&stream));
+
+            try!(reader.read_line(&mut request));
+
+            // skip the request headers
+            let mut line = String::new();
+
+            while try!(reader.read_line(&mut line)) > 0 {
+                if line.trim().is_empty() {
+                    break;
+                }
+
+                line.clear();
+            }
+        }
+
+        let mut parts = request.split_whitespace();
+
+        let method = parts.next().unwrap_or("");
+        let path   = parts.next().unwrap_or("");
+
+        let (status, content_type, body) = if method != "GET" {
+            ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string())
+        } else if path == "/" {
+            ("200 OK", "text/html; charset=utf-8", self.page())
+        } else if path == "/health" {
+            ("200 OK", "text/plain", self.health())
+        } else if path == "/status" {
+            ("200 OK", "application/json", self.status())
+        } else if path == "/metrics" {
+            ("200 OK", "text/plain; version=0.0.4", self.metrics())
+        } else {
+            ("404 Not Found", "text/plain", "not found\n".to_string())
+        };
+
+        write!(stream,
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body)
+    }
+
+    /// Get the connection state for health checks.
+    fn health(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        format!("{}\n", state_name(app_context.connection_state))
+    }
+
+    /// Get the client status as JSON.
+    fn status(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        let services = app_context.config.service_table()
+            .active_entries()
+            .into_iter()
+            .map(|(id, svc)| JsonService {
+                id:      id,
+                service: svc.to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let sessions = app_context.active_sessions.iter()
+            .map(|session_id| format!("{:08x}", session_id))
+            .collect::<Vec<_>>();
+
+        let last_error = app_context.last_error.as_ref()
+            .map(|&(timestamp, ref message)| JsonError {
+                timestamp: timestamp,
+                message:   message.clone()
+            });
+
+        let status = JsonStatus {
+            uuid:       app_context.config.uuid_string(),
+            state:      state_name(app_context.connection_state),
+            scanning:   app_context.scanning,
+            services:   services,
+            sessions:   sessions,
+            last_error: last_error
+        };
+
+        let mut res = json::encode(&status)
+            .unwrap();
+
+        res.push('\n');
+
+        res
+    }
+
+    /// Get connection metrics in the Prometheus text format.
+    fn metrics(&self) -> String {
+        self.app_context.lock()
+            .unwrap()
+            .metrics
+            .render()
+    }
+
+    /// Render the status page.
+    fn page(&self) -> String {
+        let app_context = self.app_context.lock()
+            .unwrap();
+
+        let mut res = String::new();
+
+        res.push_str("<!DOCTYPE html>\n<html>\n<head>\n");
+        res.push_str("<meta charset=\"utf-8\">\n");
+        res.push_str("<meta http-equiv=\"refresh\" content=\"10\">\n");
+        res.push_str("<title>Arrow Client</title>\n");
+        res.push_str("<style>body{font-family:sans-serif}td,th{padding:2px 8px;text-align:left}</style>\n");
+        res.push_str("</head>\n<body>\n");
+
+        res.push_str("<h1>Arrow Client</h1>\n");
+        res.push_str(&format!("<p>UUID: {}<br>\nConnection: {}<br>\nScanning: {}</p>\n",
+            app_context.config.uuid_string(),
+            state_name(app_context.connection_state),
+            if app_context.scanning { "yes" } else { "no" }));
+
+        res.push_str("<h2>Discovered devices</h2>\n");
+
+        let mut hosts = app_context.scan_report.hosts()
+            .collect::<Vec<_>>();
+
+        hosts.sort_by_key(|host| host.ip_addr);
+
+        if hosts.is_empty() {
+            res.push_str("<p>no devices found</p>\n");
+        } else {
+            res.push_str("<table>\n<tr><th>IP address</th><th>MAC address</th><th>Vendor</th><th>Host name</th><th>Open ports</th></tr>\n");
+
+            for host in hosts {
+                let name = app_context.host_names.get(&host.mac_addr)
+                    .and_then(|name| name.as_ref())
+                    .map(|name| escape(name))
+                    .unwrap_or(String::new());
+
+                let vendor = app_context.vendor(&host.mac_addr)
+                    .map(|vendor| escape(vendor))
+                    .unwrap_or(String::new());
+
+                let ports = host.ports()
+                    .map(|port| port.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                res.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                    host.ip_addr, host.mac_addr, vendor, name, ports));
+            }
+
+            res.push_str("</table>\n");
+        }
+
+        res.push_str("<h2>Active sessions</h2>\n");
+
+        let sessions = &app_context.active_sessions;
+
+        if sessions.is_empty() {
+            res.push_str("<p>no active sessions</p>\n");
+        } else {
+            res.push_str("<ul>\n");
+
+            for session_id in sessions {
+                res.push_str(&format!("<li>{:08x}</li>\n", session_id));
+            }
+
+            res.push_str("</ul>\n");
+        }
+
+        res.push_str("</body>\n</html>\n");
+
+        res
+    }
+}
+
+/// Get name of a given connection state.
+fn state_name(state: LedState) -> &'static str {
+    match state {
+        LedState::Connecting => "connecting",
+        LedState::Registered => "registered",
+        LedState::Error      => "error"
+    }
+}
+
+/// Escape a given string for use in HTML.
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&'  => res.push_str("&amp;"),
+            '<'  => res.push_str("&lt;"),
+            '>'  => res.push_str("&gt;"),
+            '"'  => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c    => res.push(c)
+        }
+    }
+
+    res
+}
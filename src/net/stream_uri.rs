@@ -0,0 +1,264 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ONVIF stream URI cache.
+//!
+//! ONVIF Profile S cameras report their RTSP stream URIs on request
+//! (GetStreamUri). Resolving a URI takes several SOAP round trips, so the
+//! resolved services are cached per camera (i.e. per device MAC address and
+//! ONVIF endpoint). The URIs may change (e.g. after a firmware update), so
+//! the cached entries are periodically revalidated. A changed URI replaces
+//! the corresponding service in the service table (keeping its service ID)
+//! instead of letting sessions fail at open time.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use net::raw::ether::MacAddr;
+use net::arrow::protocol::Service;
+
+use utils::RuntimeError;
+use utils::config::ArrowConfig;
+
+/// Default revalidation period (in seconds).
+pub const REVALIDATION_PERIOD: i64 = 6 * 3600;
+
+/// Common trait for stream URI resolvers.
+pub trait StreamUriResolver {
+    /// Resolve the RTSP service of a camera with a given MAC address and
+    /// ONVIF endpoint.
+    fn resolve(
+        &mut self,
+        mac: &MacAddr,
+        endpoint: &SocketAddr) -> Result<Service, RuntimeError>;
+}
+
+/// Change of a resolved service.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ServiceChange {
+    /// Previously resolved service.
+    pub old: Service,
+    /// Newly resolved service.
+    pub new: Service,
+}
+
+/// Cache entry.
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    /// Resolved service.
+    service:   Service,
+    /// Time of the last successful validation (UNIX timestamp).
+    validated: i64,
+}
+
+/// Stream URI cache.
+#[derive(Debug, Clone)]
+pub struct StreamUriCache {
+    entries: HashMap<(MacAddr, SocketAddr), CacheEntry>,
+    period:  i64,
+}
+
+impl StreamUriCache {
+    /// Create a new empty cache using the default revalidation period.
+    pub fn new() -> StreamUriCache {
+        StreamUriCache::with_period(REVALIDATION_PERIOD)
+    }
+
+    /// Create a new empty cache using a given revalidation period (in
+    /// seconds).
+    pub fn with_period(period: i64) -> StreamUriCache {
+        StreamUriCache {
+            entries: HashMap::new(),
+            period:  period
+        }
+    }
+
+    /// Get the cached service of a given camera (if any).
+    pub fn get(&self, mac: &MacAddr, endpoint: &SocketAddr) -> Option<&Service> {
+        self.entries.get(&(*mac, *endpoint))
+            .map(|entry| &entry.service)
+    }
+
+    /// Get service of a given camera. The cached service is used if it does
+    /// not need to be revalidated yet, otherwise the service is resolved
+    /// using a given resolver.
+    pub fn resolve<R: StreamUriResolver>(
+        &mut self,
+        resolver: &mut R,
+        mac: &MacAddr,
+        endpoint: &SocketAddr,
+        now: i64) -> Result<Service, RuntimeError> {
+        let key = (*mac, *endpoint);
+
+        if let Some(entry) = self.entries.get(&key) {
+            if (entry.validated + self.period) > now {
+                return Ok(entry.service.clone());
+            }
+        }
+
+        let service = try!(resolver.resolve(mac, endpoint));
+
+        self.entries.insert(key, CacheEntry {
+            service:   service.clone(),
+            validated: now
+        });
+
+        Ok(service)
+    }
+
+    /// Revalidate all entries older than the revalidation period and return
+    /// the list of changed services. Entries which cannot be resolved are
+    /// kept (they will be revalidated again next time).
+    pub fn revalidate<R: StreamUriResolver>(
+        &mut self,
+        resolver: &mut R,
+        now: i64) -> Vec<ServiceChange> {
+        let period  = self.period;
+        let mut res = Vec::new();
+
+        for (&(ref mac, ref endpoint), entry) in &mut self.entries {
+            if (entry.validated + period) > now {
+                continue;
+            }
+
+            if let Ok(service) = resolver.resolve(mac, endpoint) {
+                if service != entry.service {
+                    res.push(ServiceChange {
+                        old: entry.service.clone(),
+                        new: service.clone()
+                    });
+                }
+
+                entry.service   = service;
+                entry.validated = now;
+            }
+        }
+
+        res
+    }
+
+    /// Remove a given camera from the cache.
+    pub fn remove(&mut self, mac: &MacAddr, endpoint: &SocketAddr) {
+        self.entries.remove(&(*mac, *endpoint));
+    }
+}
+
+/// Apply given service changes to the service table of a given config.
+/// The config version is bumped if the table has been changed. Return true
+/// in such case.
+pub fn apply_changes(config: &mut ArrowConfig, changes: &[ServiceChange]) -> bool {
+    let mut changed = false;
+
+    for change in changes {
+        if config.replace(&change.old, change.new.clone()).is_some() {
+            changed = true;
+        }
+    }
+
+    if changed {
+        config.bump_version();
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::Service;
+
+    use utils::RuntimeError;
+
+    /// Resolver returning a given path.
+    struct TestResolver {
+        path:  Option<String>,
+        calls: usize,
+    }
+
+    impl StreamUriResolver for TestResolver {
+        fn resolve(
+            &mut self,
+            mac: &MacAddr,
+            endpoint: &SocketAddr) -> Result<Service, RuntimeError> {
+            self.calls += 1;
+
+            let addr = SocketAddr::new(endpoint.ip(), 554);
+
+            match self.path {
+                Some(ref path) => Ok(Service::RTSP(*mac, addr, path.clone())),
+                None => Err(RuntimeError::from("unable to resolve"))
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_uri_cache() {
+        let mac      = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let endpoint = SocketAddr::from_str("10.0.0.1:80").unwrap();
+        let addr     = SocketAddr::from_str("10.0.0.1:554").unwrap();
+
+        let mut cache    = StreamUriCache::with_period(100);
+        let mut resolver = TestResolver {
+            path:  Some("/stream1".to_string()),
+            calls: 0
+        };
+
+        let svc = cache.resolve(&mut resolver, &mac, &endpoint, 1000)
+            .unwrap();
+
+        assert_eq!(svc, Service::RTSP(mac, addr, "/stream1".to_string()));
+
+        // cached
+        cache.resolve(&mut resolver, &mac, &endpoint, 1050)
+            .unwrap();
+
+        assert_eq!(resolver.calls, 1);
+
+        // not expired yet
+        assert!(cache.revalidate(&mut resolver, 1050).is_empty());
+        assert_eq!(resolver.calls, 1);
+
+        // expired but the same
+        assert!(cache.revalidate(&mut resolver, 1100).is_empty());
+        assert_eq!(resolver.calls, 2);
+
+        // failed revalidation keeps the entry
+        resolver.path = None;
+
+        assert!(cache.revalidate(&mut resolver, 1200).is_empty());
+        assert!(cache.get(&mac, &endpoint).is_some());
+
+        // changed URI
+        resolver.path = Some("/stream2".to_string());
+
+        let changes = cache.revalidate(&mut resolver, 1300);
+
+        assert_eq!(changes, vec![ServiceChange {
+            old: Service::RTSP(mac, addr, "/stream1".to_string()),
+            new: Service::RTSP(mac, addr, "/stream2".to_string())
+        }]);
+
+        assert_eq!(cache.get(&mac, &endpoint),
+            Some(&Service::RTSP(mac, addr, "/stream2".to_string())));
+
+        cache.remove(&mac, &endpoint);
+
+        assert!(cache.get(&mac, &endpoint).is_none());
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network interface traffic statistics.
+//!
+//! RX/TX byte counters of all network interfaces are sampled periodically
+//! from `/proc/net/dev` and the current transfer rates are computed from the
+//! differences between consecutive samples. Utilization of the uplink
+//! interface (the one with the default route unless configured otherwise) is
+//! computed from its capacity, which is either configured or taken from the
+//! interface link speed. The monitor is available only with the `metrics`
+//! feature, otherwise the traffic is always reported as unknown.
+
+#[cfg(feature = "metrics")]
+use std::io;
+#[cfg(feature = "metrics")]
+use std::cmp;
+
+#[cfg(feature = "metrics")]
+use std::fs::File;
+#[cfg(feature = "metrics")]
+use std::io::Read;
+#[cfg(feature = "metrics")]
+use std::str::FromStr;
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+
+#[cfg(feature = "metrics")]
+use time;
+
+/// Traffic sampling period (in milliseconds).
+#[cfg(feature = "metrics")]
+pub const TRAFFIC_SAMPLE_PERIOD: u64 = 10000;
+
+/// Utilization value used if the uplink capacity is not known.
+pub const UTILIZATION_UNKNOWN: u8 = 0xff;
+
+/// Interface statistics file.
+#[cfg(feature = "metrics")]
+static PROC_NET_DEV: &'static str = "/proc/net/dev";
+
+/// Kernel routing table.
+#[cfg(feature = "metrics")]
+static PROC_NET_ROUTE: &'static str = "/proc/net/route";
+
+/// RX/TX byte counters of a network interface.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterfaceCounters {
+    /// Number of received bytes.
+    pub rx_bytes: u64,
+    /// Number of transmitted bytes.
+    pub tx_bytes: u64,
+}
+
+/// Current traffic of a network interface.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TrafficStats {
+    /// Receive rate (in bytes per second).
+    pub rx_rate:     u32,
+    /// Transmit rate (in bytes per second).
+    pub tx_rate:     u32,
+    /// Utilization of the interface capacity in percent (computed from the
+    /// higher of the two rates) or UTILIZATION_UNKNOWN.
+    pub utilization: u8,
+}
+
+impl TrafficStats {
+    /// Create empty statistics (i.e. nothing has been sampled yet).
+    pub fn new() -> TrafficStats {
+        TrafficStats {
+            rx_rate:     0,
+            tx_rate:     0,
+            utilization: UTILIZATION_UNKNOWN
+        }
+    }
+}
+
+/// Traffic monitor of all network interfaces.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct TrafficMonitor {
+    /// Uplink interface (None for the default route interface).
+    uplink:   Option<String>,
+    /// Uplink capacity in bits per second (None for the link speed).
+    capacity: Option<u64>,
+    /// Time and counters of the last sample.
+    last:     Option<(f64, HashMap<String, InterfaceCounters>)>,
+    /// Current traffic of all interfaces.
+    current:  HashMap<String, TrafficStats>,
+    /// Name of the uplink interface used for the last sample.
+    uplink_name: Option<String>,
+}
+
+#[cfg(feature = "metrics")]
+impl TrafficMonitor {
+    /// Create a new traffic monitor for a given uplink interface and
+    /// capacity (in bits per second).
+    pub fn new(uplink: Option<String>, capacity: Option<u64>) -> TrafficMonitor {
+        TrafficMonitor {
+            uplink:   uplink,
+            capacity: capacity,
+            last:     None,
+            current:  HashMap::new(),
+            uplink_name: None
+        }
+    }
+
+    /// Sample counters of all interfaces.
+    pub fn sample(&mut self) -> io::Result<()> {
+        let counters = parse_net_dev(&try!(read_file(PROC_NET_DEV)));
+
+        let uplink = match self.uplink {
+            Some(ref uplink) => Some(uplink.clone()),
+            None => read_file(PROC_NET_ROUTE)
+                .ok()
+                .and_then(|routes| parse_default_route(&routes))
+        };
+
+        let capacity = self.capacity.or_else(|| {
+            uplink.as_ref()
+                .and_then(|uplink| link_speed(uplink))
+        });
+
+        self.update(time::precise_time_s(), counters, uplink, capacity);
+
+        Ok(())
+    }
+
+    /// Get current traffic of the uplink interface.
+    pub fn uplink(&self) -> TrafficStats {
+        self.uplink_name.as_ref()
+            .and_then(|uplink| self.current.get(uplink))
+            .cloned()
+            .unwrap_or(TrafficStats::new())
+    }
+
+    /// Get current traffic of all interfaces.
+    pub fn interfaces(&self) -> &HashMap<String, TrafficStats> {
+        &self.current
+    }
+
+    /// Update the current traffic using given counters sampled at a given
+    /// time.
+    fn update(
+        &mut self,
+        now: f64,
+        counters: HashMap<String, InterfaceCounters>,
+        uplink: Option<String>,
+        capacity: Option<u64>) {
+        if let Some((t, ref last)) = self.last {
+            let elapsed = now - t;
+
+            if elapsed > 0.0 {
+                self.current.clear();
+
+                for (name, cur) in &counters {
+                    if let Some(prev) = last.get(name) {
+                        let rx_rate = rate(prev.rx_bytes, cur.rx_bytes, elapsed);
+                        let tx_rate = rate(prev.tx_bytes, cur.tx_bytes, elapsed);
+
+                        let utilization = if Some(name) == uplink.as_ref() {
+                            utilization(cmp::max(rx_rate, tx_rate), capacity)
+                        } else {
+                            UTILIZATION_UNKNOWN
+                        };
+
+                        self.current.insert(name.clone(), TrafficStats {
+                            rx_rate:     rx_rate,
+                            tx_rate:     tx_rate,
+                            utilization: utilization
+                        });
+                    }
+                }
+            }
+        }
+
+        self.last        = Some((now, counters));
+        self.uplink_name = uplink;
+    }
+}
+
+/// Get transfer rate (in bytes per second) from given counter values. Zero
+/// is returned if the counter has been reset.
+#[cfg(feature = "metrics")]
+fn rate(prev: u64, cur: u64, elapsed: f64) -> u32 {
+    if cur < prev {
+        0
+    } else {
+        let rate = ((cur - prev) as f64 / elapsed) as u64;
+
+        cmp::min(rate, u32::max_value() as u64) as u32
+    }
+}
+
+/// Get utilization (in percent) of a given capacity (in bits per second) by
+/// a given rate (in bytes per second).
+#[cfg(feature = "metrics")]
+fn utilization(rate: u32, capacity: Option<u64>) -> u8 {
+    match capacity {
+        Some(capacity) if capacity > 0 => {
+            let res = (rate as u64) * 8 * 100 / capacity;
+
+            cmp::min(res, 100) as u8
+        },
+        _ => UTILIZATION_UNKNOWN
+    }
+}
+
+/// Get link speed (in bits per second) of a given interface.
+#[cfg(feature = "metrics")]
+fn link_speed(interface: &str) -> Option<u64> {
+    read_file(&format!("/sys/class/net/{}/speed", interface))
+        .ok()
+        .and_then(|speed| i64::from_str(speed.trim()).ok())
+        .and_then(|speed| if speed > 0 {
+            Some(speed as u64 * 1000000)
+        } else {
+            None
+        })
+}
+
+/// Read content of a given file.
+#[cfg(feature = "metrics")]
+fn read_file(file: &str) -> io::Result<String> {
+    let mut content = String::new();
+
+    try!(File::open(file)
+        .and_then(|mut file| file.read_to_string(&mut content)));
+
+    Ok(content)
+}
+
+/// Parse content of /proc/net/dev.
+#[cfg(feature = "metrics")]
+fn parse_net_dev(content: &str) -> HashMap<String, InterfaceCounters> {
+    let mut res = HashMap::new();
+
+    // the first two lines are headers
+    for line in content.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+
+        let name   = parts.next().map(|name| name.trim());
+        let fields = parts.next()
+            .map(|fields| fields.split_whitespace()
+                .map(|field| u64::from_str(field).unwrap_or(0))
+                .collect::<Vec<_>>());
+
+        if let (Some(name), Some(fields)) = (name, fields) {
+            // RX bytes is the first field, TX bytes is the ninth field
+            if fields.len() >= 9 && !name.is_empty() {
+                res.insert(name.to_string(), InterfaceCounters {
+                    rx_bytes: fields[0],
+                    tx_bytes: fields[8]
+                });
+            }
+        }
+    }
+
+    res
+}
+
+/// Parse content of /proc/net/route and return the interface of the default
+/// route (if any).
+#[cfg(feature = "metrics")]
+fn parse_default_route(content: &str) -> Option<String> {
+    for line in content.lines().skip(1) {
+        let fields = line.split_whitespace()
+            .collect::<Vec<_>>();
+
+        // interface, destination, gateway, flags, ..., metric, mask
+        if fields.len() >= 8 && fields[1] == "00000000" && fields[7] == "00000000" {
+            return Some(fields[0].to_string());
+        }
+    }
+
+    None
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use super::{parse_net_dev, parse_default_route};
+
+    static NET_DEV: &'static str = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0
+  eth0: 5000000    4000    0    0    0     0          0         0  2000000    3000    0    0    0     0       0          0
+";
+
+    static ROUTE: &'static str = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+";
+
+    #[test]
+    fn test_traffic_monitor() {
+        let counters = parse_net_dev(NET_DEV);
+
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters["eth0"], InterfaceCounters {
+            rx_bytes: 5000000,
+            tx_bytes: 2000000
+        });
+
+        assert_eq!(parse_default_route(ROUTE), Some("eth0".to_string()));
+
+        let mut monitor = TrafficMonitor::new(None, None);
+
+        let uplink   = Some("eth0".to_string());
+        let capacity = Some(10000000);
+
+        monitor.update(0.0, counters.clone(), uplink.clone(), capacity);
+
+        assert_eq!(monitor.uplink(), TrafficStats::new());
+
+        let mut counters = counters;
+
+        counters.get_mut("eth0").unwrap().rx_bytes += 5000000;
+        counters.get_mut("eth0").unwrap().tx_bytes += 1000000;
+
+        monitor.update(10.0, counters, uplink, capacity);
+
+        // 500 kB/s out of 10 Mbit/s
+        assert_eq!(monitor.uplink(), TrafficStats {
+            rx_rate:     500000,
+            tx_rate:     100000,
+            utilization: 40
+        });
+
+        assert_eq!(monitor.interfaces()["lo"].utilization,
+            UTILIZATION_UNKNOWN);
+    }
+}
@@ -18,10 +18,15 @@ use std::io;
 use std::ptr;
 
 use std::io::Write;
+use std::net;
+
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::os::unix::io::{IntoRawFd, FromRawFd};
 
 use utils::RuntimeError;
 
+use mio::tcp::TcpStream;
+
 use time;
 
 /// Get socket address from a given argument.
@@ -36,6 +41,18 @@ pub fn get_socket_address<T>(s: T) -> Result<SocketAddr, RuntimeError>
     }
 }
 
+/// Switch a given blocking TCP stream into the non-blocking mode and convert
+/// it into a mio TCP stream.
+pub fn into_mio_stream(stream: net::TcpStream) -> io::Result<TcpStream> {
+    try!(stream.set_nonblocking(true));
+
+    let res = unsafe {
+        TcpStream::from_raw_fd(stream.into_raw_fd())
+    };
+
+    Ok(res)
+}
+
 /// Timeout provider for various network protocols.
 #[derive(Debug)]
 pub struct Timeout {
@@ -79,10 +96,11 @@ impl Timeout {
 
 /// Writer that can be used for buffering data.
 pub struct WriteBuffer {
-    buffer:   Vec<u8>,
-    capacity: usize,
-    offset:   usize,
-    used:     usize,
+    buffer:     Vec<u8>,
+    capacity:   usize,
+    offset:     usize,
+    used:       usize,
+    high_water: usize,
 }
 
 impl WriteBuffer {
@@ -91,10 +109,11 @@ impl WriteBuffer {
     /// its capacity.
     pub fn new(capacity: usize) -> WriteBuffer {
         let mut res = WriteBuffer {
-            buffer:   Vec::with_capacity(capacity),
-            capacity: capacity,
-            offset:   0,
-            used:     0
+            buffer:     Vec::with_capacity(capacity),
+            capacity:   capacity,
+            offset:     0,
+            used:       0,
+            high_water: 0
         };
         
         // TODO: replace this with resize (after it's stabilized)
@@ -130,6 +149,22 @@ impl WriteBuffer {
         self.used
     }
     
+    /// Get the buffer capacity (the soft limit).
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    
+    /// Get the maximum number of buffered bytes since the buffer has been
+    /// created or since the last reset_high_water() call.
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+    
+    /// Reset the high-water mark to the current number of buffered bytes.
+    pub fn reset_high_water(&mut self) {
+        self.high_water = self.used;
+    }
+    
     /// Get slice of bytes of the currently buffered data.
     pub fn as_bytes(&self) -> &[u8] {
         let start = self.offset;
@@ -148,6 +183,36 @@ impl WriteBuffer {
         }
     }
     
+    /// Remove given ranges (offset, length) of the buffered data. The offsets
+    /// are relative to the start of the buffered data and the ranges must be
+    /// in ascending order. The remaining data are moved within the buffer.
+    pub fn remove(&mut self, ranges: &[(usize, usize)]) {
+        let base    = self.buffer.as_mut_ptr();
+        let mut src = 0;
+        let mut dst = 0;
+        
+        for &(start, len) in ranges {
+            assert!(src <= start && (start + len) <= self.used);
+            
+            unsafe {
+                let from = base.offset((self.offset + src) as isize);
+                let to   = base.offset((self.offset + dst) as isize);
+                ptr::copy(from, to, start - src);
+            }
+            
+            dst += start - src;
+            src  = start + len;
+        }
+        
+        unsafe {
+            let from = base.offset((self.offset + src) as isize);
+            let to   = base.offset((self.offset + dst) as isize);
+            ptr::copy(from, to, self.used - src);
+        }
+        
+        self.used = dst + self.used - src;
+    }
+    
     /// Drop all buffered data.
     pub fn clear(&mut self) {
         self.offset += self.used;
@@ -188,6 +253,10 @@ impl Write for WriteBuffer {
         
         self.used += data.len();
         
+        if self.used > self.high_water {
+            self.high_water = self.used;
+        }
+        
         Ok(data.len())
     }
     
@@ -0,0 +1,79 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Build information.
+//!
+//! The git hash and the build date are provided by the build script.
+
+use std::env;
+
+use openssl;
+
+/// Client version.
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Git hash of the source tree ("unknown" if not available).
+pub const GIT_HASH: &'static str = env!("ARROW_GIT_HASH");
+
+/// Build date (UTC, "unknown" if not available).
+pub const BUILD_DATE: &'static str = env!("ARROW_BUILD_DATE");
+
+/// Get names of all enabled optional features.
+pub fn features() -> Vec<&'static str> {
+    let features = [
+        ("discovery",      cfg!(feature = "discovery")),
+        ("onvif",          cfg!(feature = "onvif")),
+        ("metrics",        cfg!(feature = "metrics")),
+        ("control-socket", cfg!(feature = "control-socket")),
+        ("recording",      cfg!(feature = "recording")),
+        ("dns",            cfg!(feature = "dns")),
+        ("sntp",           cfg!(feature = "sntp")),
+        ("socks",          cfg!(feature = "socks")),
+        ("websocket",      cfg!(feature = "websocket")),
+        ("e2e",            cfg!(feature = "e2e")),
+        ("sandbox",        cfg!(feature = "sandbox")),
+        ("datagram",       cfg!(feature = "datagram")),
+        ("conntrack",      cfg!(feature = "conntrack")),
+    ];
+
+    features.iter()
+        .filter(|&&(_, enabled)| enabled)
+        .map(|&(name, _)| name)
+        .collect()
+}
+
+/// Get version of the linked OpenSSL library.
+pub fn openssl_version() -> &'static str {
+    openssl::version::version()
+}
+
+/// Get a multi-line build description.
+pub fn describe() -> String {
+    let features = features();
+
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(",")
+    };
+
+    format!("arrow-client {}\n\
+        git hash:   {}\n\
+        build date: {}\n\
+        platform:   {}-{}\n\
+        features:   {}\n\
+        openssl:    {}",
+        VERSION, GIT_HASH, BUILD_DATE, env::consts::OS, env::consts::ARCH,
+        features, openssl_version())
+}
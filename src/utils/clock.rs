@@ -0,0 +1,74 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Process clock.
+//!
+//! The process clock is the system clock corrected by an offset (e.g.
+//! measured using the built-in SNTP client). The system clock itself is
+//! never modified. The offset is zero until it is set.
+//!
+//! Apart from the reload request flag set by the SIGHUP handler (see
+//! `utils::reload`), the offset is the only process-wide state of the
+//! client. It describes the error of the system clock, so it is shared by
+//! all client instances running within the same process.
+
+use std::sync::atomic::{AtomicIsize, AtomicBool, Ordering};
+use std::sync::atomic::{ATOMIC_ISIZE_INIT, ATOMIC_BOOL_INIT};
+
+use time;
+
+use time::Timespec;
+
+/// Offset of the process clock from the system clock (in seconds).
+static OFFSET: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+/// The offset has been set.
+static SYNCHRONIZED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set offset of the process clock from the system clock (in seconds).
+pub fn set_offset(offset: i64) {
+    OFFSET.store(offset as isize, Ordering::SeqCst);
+    SYNCHRONIZED.store(true, Ordering::SeqCst);
+}
+
+/// Get offset of the process clock from the system clock (in seconds).
+pub fn offset() -> i64 {
+    OFFSET.load(Ordering::SeqCst) as i64
+}
+
+/// Check if the process clock offset has been set.
+pub fn is_synchronized() -> bool {
+    SYNCHRONIZED.load(Ordering::SeqCst)
+}
+
+/// Get the current process time.
+pub fn now() -> Timespec {
+    let mut res = time::get_time();
+
+    res.sec += offset();
+
+    res
+}
+
+/// Get the current process time as a UNIX timestamp (in seconds).
+pub fn timestamp() -> i64 {
+    now().sec
+}
+
+/// Get the current process time as a UNIX timestamp (in milliseconds).
+pub fn timestamp_ms() -> i64 {
+    let now = now();
+
+    now.sec * 1000 + (now.nsec / 1000000) as i64
+}
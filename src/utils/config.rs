@@ -16,18 +16,64 @@
 
 use std::io;
 use std::fmt;
+use std::mem;
 use std::result;
 
 use std::fs::File;
+use std::sync::Arc;
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, Read};
 use std::fmt::{Display, Formatter};
 
 use utils;
+use utils::clock;
 use net::raw::ether;
+use net::raw::ether::MacAddr;
+#[cfg(feature = "discovery")]
+use net::oui::OuiDatabase;
 
-use net::arrow::protocol::ScanReport;
+use net::arrow::protocol::{ScanReport, ScanSummary, Metadata};
+use net::arrow::protocol::ServiceStatsTable;
+use net::arrow::overload::OverloadPolicies;
+use net::arrow::any_tcp::TcpAllowlist;
+use net::arrow::access::AccessPolicy;
+use net::arrow::flap::{FlapDamping, FLAP_THRESHOLD};
+#[cfg(feature = "e2e")]
+use net::arrow::e2e::E2eKeys;
+use net::arrow::latency::{LatencyHistogram, SLOW_ACK_THRESHOLD};
+use net::arrow::sequence::SequenceStats;
+use net::arrow::auth::{AuthProvider, StaticSecret};
+use net::arrow::authorizer::SessionAuthorizer;
+#[cfg(feature = "websocket")]
+use net::arrow::websocket::{Transport, WssEndpoint};
+use net::arrow::register_policy::RegisterErrorPolicy;
+use net::arrow::version::RegressionPolicy;
+#[cfg(feature = "recording")]
+use net::arrow::tap::{SessionTaps, TAP_DIR};
+use net::arrow::keepalive::PingTuner;
+use net::arrow::occupancy::BUFFER_ALERT_THRESHOLD;
+use net::arrow::{RegisterCache, SessionOptions};
+#[cfg(feature = "metrics")]
+use net::arrow::metrics::Metrics;
+use net::arrow::untrusted::UntrustedInterfaces;
+#[cfg(feature = "discovery")]
+use net::sap::Announcements;
+#[cfg(feature = "discovery")]
+use net::monitor::NetworkMonitor;
+#[cfg(feature = "metrics")]
+use net::traffic::TrafficMonitor;
+use net::traffic::TrafficStats;
+#[cfg(feature = "socks")]
+use net::socks::SocksProxy;
+use utils::logger::level::LevelController;
+
+use utils::hooks::Hooks;
+use utils::secret::Secret;
+use utils::status_led::{StatusLed, LedState};
+use utils::stats_export::{StatsExport, ConnectionRecord};
 
 use net::arrow::protocol::{Service, ServiceTable};
 
@@ -37,6 +83,10 @@ use uuid::Uuid;
 
 use rustc_serialize::json;
 
+use openssl::crypto::hash;
+
+use openssl::crypto::hash::Type as HashType;
+
 /// Arrow configuration loading/parsing/saving error.
 #[derive(Debug, Clone)]
 pub struct ConfigError {
@@ -100,13 +150,19 @@ impl From<ether::AddrParseError> for ConfigError {
 /// Type alias for Arrow configuration results.
 pub type Result<T> = result::Result<T, ConfigError>;
 
+/// Current version of the configuration file schema.
+pub const CONFIG_SCHEMA_VERSION: u64 = 1;
+
 /// JSON mapping for the Arrow client configuration.
 #[derive(Debug, Clone, RustcDecodable, RustcEncodable)]
 struct JsonConfig<'a> {
+    schema:    Option<u64>,
     uuid:      String,
     passwd:    String,
+    pending_passwd: Option<String>,
     version:   usize,
     svc_table: Cow<'a, ServiceTable>,
+    prev_svc_table: Option<Cow<'a, ServiceTable>>,
 }
 
 impl<'a> JsonConfig<'a> {
@@ -114,36 +170,39 @@ impl<'a> JsonConfig<'a> {
     fn new(
         uuid: String, 
         passwd: String, 
+        pending_passwd: Option<String>,
         version: usize, 
-        svc_table: &'a ServiceTable) -> JsonConfig<'a> {
+        svc_table: &'a ServiceTable,
+        prev_svc_table: Option<&'a ServiceTable>) -> JsonConfig<'a> {
         JsonConfig {
+            schema:    Some(CONFIG_SCHEMA_VERSION),
             uuid:      uuid,
             passwd:    passwd,
+            pending_passwd: pending_passwd,
             version:   version,
-            svc_table: Cow::Borrowed(svc_table)
+            svc_table: Cow::Borrowed(svc_table),
+            prev_svc_table: prev_svc_table.map(Cow::Borrowed)
         }
     }
     
     /// Load configuration from a given file.
     fn load(file: &str) -> Result<JsonConfig<'a>> {
-        let mut content = String::new();
-        let file        = try!(File::open(file));
-        let mut breader = BufReader::new(file);
-        
-        try!(breader.read_to_string(&mut content));
-        
-        Ok(try!(json::decode(&content)))
+        JsonConfig::parse(&try!(read_file(file)))
     }
     
-    /// Save configuration into a given file.
-    fn save(&self, file: &str) -> Result<()> {
-        let content     = try!(json::encode(self));
-        let file        = try!(File::create(file));
-        let mut bwriter = BufWriter::new(file);
+    /// Parse configuration from a given JSON string.
+    fn parse(content: &str) -> Result<JsonConfig<'a>> {
+        Ok(try!(json::decode(content)))
+    }
+    
+    /// Save configuration into a given file. The previous version of the
+    /// file is kept as a backup. Digest of the written content is returned.
+    fn save(&self, file: &str) -> Result<[u8; 32]> {
+        let content = try!(json::encode(self));
         
-        try!(bwriter.write(content.as_bytes()));
+        try!(utils::write_file_with_backup(file, content.as_bytes()));
         
-        Ok(())
+        Ok(content_digest(content.as_bytes()))
     }
 }
 
@@ -159,9 +218,12 @@ impl<'a> Display for JsonConfig<'a> {
 #[derive(Debug, Clone)]
 pub struct ArrowConfig {
     uuid:      Uuid,
-    passwd:    Uuid,
+    passwd:    Secret<[u8; 16]>,
+    pending_passwd: Option<Secret<[u8; 16]>>,
     version:   usize,
     svc_table: ServiceTable,
+    prev_svc_table: Option<ServiceTable>,
+    saved:     Cell<Option<[u8; 32]>>,
 }
 
 impl ArrowConfig {
@@ -169,9 +231,12 @@ impl ArrowConfig {
     pub fn new() -> ArrowConfig {
         ArrowConfig {
             uuid:      Uuid::new_v4(),
-            passwd:    Uuid::new_v4(),
+            passwd:    Secret::new(uuid_to_bytes(&Uuid::new_v4())),
+            pending_passwd: None,
             version:   0,
-            svc_table: ServiceTable::new()
+            svc_table: ServiceTable::new(),
+            prev_svc_table: None,
+            saved:     Cell::new(None)
         }
     }
     
@@ -186,8 +251,47 @@ impl ArrowConfig {
     }
     
     /// Get Arrow Client password.
-    pub fn password(&self) -> [u8; 16] {
-        uuid_to_bytes(&self.passwd)
+    pub fn password(&self) -> Secret<[u8; 16]> {
+        self.passwd.clone()
+    }
+    
+    /// Get password which should be used for the next registration (i.e.
+    /// the pending password if there is a secret rotation in progress).
+    pub fn register_password(&self) -> Secret<[u8; 16]> {
+        self.pending_passwd.as_ref()
+            .unwrap_or(&self.passwd)
+            .clone()
+    }
+    
+    /// Check if there is a secret rotation in progress.
+    pub fn has_pending_password(&self) -> bool {
+        self.pending_passwd.is_some()
+    }
+    
+    /// Install a given password as pending. It will be used for the next
+    /// registration and it will replace the current password once the
+    /// registration succeeds.
+    pub fn set_pending_password(&mut self, passwd: &[u8; 16]) {
+        self.pending_passwd = Some(Secret::new(*passwd));
+    }
+    
+    /// Replace the current password with the pending one. The method returns
+    /// false if there is no pending password.
+    pub fn commit_password(&mut self) -> bool {
+        match self.pending_passwd.take() {
+            Some(passwd) => {
+                self.passwd = passwd;
+                true
+            },
+            None => false
+        }
+    }
+    
+    /// Drop the pending password. The method returns false if there is no
+    /// pending password.
+    pub fn rollback_password(&mut self) -> bool {
+        self.pending_passwd.take()
+            .is_some()
     }
     
     /// Get current configuration version.
@@ -210,6 +314,17 @@ impl ArrowConfig {
         self.svc_table.add_static(svc)
     }
     
+    /// Replace a given service with a new one keeping its service ID.
+    pub fn replace(&mut self, old: &Service, new: Service) -> Option<u16> {
+        self.svc_table.replace(old, new)
+    }
+    
+    /// Remove a given (non-static) service from the underlaying service
+    /// table.
+    pub fn remove(&mut self, id: u16) -> bool {
+        self.svc_table.remove(id)
+    }
+    
     /// Update active flags of all services.
     pub fn update_active_services(&mut self) -> bool {
         self.svc_table.update_active_services()
@@ -220,9 +335,10 @@ impl ArrowConfig {
         self.svc_table.active_services()
     }
     
-    /// Increment version of this config.
+    /// Increment version of this config (the version wraps around on
+    /// overflow).
     pub fn bump_version(&mut self) {
-        self.version += 1;
+        self.version = self.version.wrapping_add(1);
     }
     
     /// Get the underlaying service table.
@@ -230,23 +346,72 @@ impl ArrowConfig {
         &self.svc_table
     }
     
-    /// Set contents of the service table to a given value.
+    /// Set contents of the service table to a given value. The current
+    /// table is kept as an inactive generation, so that it can be restored
+    /// later (any older inactive generation is dropped).
     pub fn reinit(&mut self, svc_table: ServiceTable) {
-        self.svc_table = svc_table
+        let prev = mem::replace(&mut self.svc_table, svc_table);
+
+        self.prev_svc_table = Some(prev);
+    }
+    
+    /// Replace the service table with a given one (e.g. a table edited
+    /// externally). The inactive generation is not affected.
+    pub fn set_service_table(&mut self, svc_table: ServiceTable) {
+        self.svc_table = svc_table;
+    }
+    
+    /// Get the inactive generation of the service table (i.e. the table
+    /// replaced by the last reinit), if any.
+    pub fn prev_service_table(&self) -> Option<&ServiceTable> {
+        self.prev_svc_table.as_ref()
+    }
+    
+    /// Swap the service table with its inactive generation. The method
+    /// returns false if there is no inactive generation.
+    pub fn restore_service_table(&mut self) -> bool {
+        match self.prev_svc_table {
+            Some(ref mut prev) => {
+                mem::swap(&mut self.svc_table, prev);
+                true
+            },
+            None => false
+        }
     }
     
     /// Load configuration from a given file.
     pub fn load(file: &str) -> Result<ArrowConfig> {
-        let json      = try!(JsonConfig::load(file));
+        ArrowConfig::from_json(try!(JsonConfig::load(file)))
+    }
+    
+    /// Parse configuration from a given JSON string.
+    pub fn parse(content: &str) -> Result<ArrowConfig> {
+        ArrowConfig::from_json(try!(JsonConfig::parse(content)))
+    }
+    
+    /// Create configuration from its JSON mapping.
+    fn from_json(json: JsonConfig) -> Result<ArrowConfig> {
         let uuid      = try!(Uuid::parse_str(&json.uuid));
         let passwd    = try!(Uuid::parse_str(&json.passwd));
+        let passwd    = Secret::new(uuid_to_bytes(&passwd));
         let svc_table = json.svc_table.into_owned();
+        let prev_svc_table = json.prev_svc_table
+            .map(|table| table.into_owned());
+        
+        let pending_passwd = match json.pending_passwd {
+            Some(ref passwd) => Some(Secret::new(uuid_to_bytes(
+                &try!(Uuid::parse_str(passwd))))),
+            None => None
+        };
         
         let res = ArrowConfig {
             uuid:      uuid,
             passwd:    passwd,
+            pending_passwd: pending_passwd,
             version:   json.version,
-            svc_table: svc_table
+            svc_table: svc_table,
+            prev_svc_table: prev_svc_table,
+            saved:     Cell::new(None)
         };
         
         Ok(res)
@@ -256,11 +421,25 @@ impl ArrowConfig {
     pub fn save(&self, file: &str) -> Result<()> {
         let json = JsonConfig::new(
             self.uuid.to_hyphenated_string(),
-            self.passwd.to_hyphenated_string(),
+            password_string(&self.passwd),
+            self.pending_passwd.as_ref()
+                .map(password_string),
             self.version,
-            &self.svc_table);
+            &self.svc_table,
+            self.prev_svc_table.as_ref());
+        
+        let digest = try!(json.save(file));
         
-        json.save(file)
+        self.saved.set(Some(digest));
+        
+        Ok(())
+    }
+    
+    /// Check if a given content digest belongs to the last configuration
+    /// saved by this instance.
+    pub fn is_last_saved(&self, digest: &[u8; 32]) -> bool {
+        self.saved.get()
+            .map_or(false, |saved| saved == *digest)
     }
 }
 
@@ -268,14 +447,51 @@ impl Display for ArrowConfig {
     fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
         let json = JsonConfig::new(
             self.uuid.to_hyphenated_string(),
-            self.passwd.to_hyphenated_string(),
+            password_string(&self.passwd),
+            self.pending_passwd.as_ref()
+                .map(password_string),
             self.version,
-            &self.svc_table);
+            &self.svc_table,
+            self.prev_svc_table.as_ref());
         
         json.fmt(f)
     }
 }
 
+/// Default time limit for establishing service connections (in
+/// milliseconds).
+pub const SERVICE_CONNECT_TIMEOUT: u64 = 5000;
+
+/// Default time limit for writing pending data into the Arrow Service
+/// connection (in milliseconds).
+pub const UPLINK_WRITE_TIMEOUT: u64 = 20000;
+
+/// Default time limit for receiving ACKs of Control Protocol messages (in
+/// milliseconds).
+pub const ACK_TIMEOUT: u64 = 20000;
+
+/// Default time limit for writing pending data into service connections (in
+/// milliseconds).
+pub const SESSION_WRITE_TIMEOUT: u64 = 20000;
+
+/// Default capacity of the Arrow Service connection output buffer (in
+/// bytes).
+pub const UPLINK_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Default capacity of session input buffers (in bytes).
+pub const SESSION_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Maximum time given to the current sessions to finish after the control
+/// socket has been handed over to a new process (in seconds).
+pub const DRAIN_TIMEOUT: i64 = 900;
+
+/// Default time limit of a single network scan (in seconds).
+pub const SCAN_TIME_LIMIT: u64 = 1800;
+
+/// Default tolerance of Arrow Service certificates which are not valid yet
+/// (in seconds).
+pub const CLOCK_SKEW_TOLERANCE: u32 = 600;
+
 /// Application context.
 #[derive(Debug, Clone)]
 pub struct AppContext {
@@ -285,10 +501,195 @@ pub struct AppContext {
     pub scanning:        bool,
     /// Diagnostic mode indicator.
     pub diagnostic_mode: bool,
+    /// Accept Arrow Service certificates which are not valid yet (i.e. the
+    /// system clock is behind).
+    pub clock_grace_mode: bool,
+    /// Accept Arrow Service certificates which become valid within this
+    /// number of seconds (small clock skews).
+    pub clock_skew_tolerance: u32,
+    /// The current Arrow Service certificate has been accepted in the clock
+    /// grace mode.
+    pub clock_behind:    bool,
     /// Service discovery enabler.
     pub discovery:       bool,
+    /// Time limit of a single network scan (in seconds).
+    pub scan_time_limit: Option<u64>,
+    /// Maximum number of probes sent during a single network scan.
+    pub scan_probe_limit: Option<usize>,
     /// Last report from the network scanner.
     pub scan_report:     ScanReport,
+    /// Summary of the last network scan.
+    pub scan_summary:    ScanSummary,
+    /// Client metadata.
+    pub metadata:        Metadata,
+    /// Time limit for establishing service connections (in milliseconds).
+    pub service_connect_timeout: u64,
+    /// Time limit for writing pending data into the Arrow Service connection
+    /// (in milliseconds).
+    pub uplink_write_timeout: u64,
+    /// Time limit for receiving ACKs of Control Protocol messages (in
+    /// milliseconds).
+    pub ack_timeout:     u64,
+    /// Time limit for writing pending data into service connections (in
+    /// milliseconds).
+    pub session_write_timeout: u64,
+    /// Capacity of the Arrow Service connection output buffer (in bytes).
+    pub uplink_buffer_size: usize,
+    /// Capacity of session input buffers (in bytes).
+    pub session_buffer_size: usize,
+    /// Service connection statistics.
+    pub svc_stats:       ServiceStatsTable,
+    /// Send service statistics summary within client metadata.
+    pub report_svc_stats: bool,
+    /// Control Protocol ACK latencies.
+    pub ack_latency:     LatencyHistogram,
+    /// ACK latency (in milliseconds) reported as slow (zero disables the
+    /// warnings).
+    pub slow_ack_threshold: u64,
+    /// Number of received Control Protocol messages which could not be 
+    /// handled.
+    pub unsupported_messages: usize,
+    /// Number of duplicated or replayed Control Protocol messages which were
+    /// dropped.
+    pub replayed_messages: usize,
+    /// Check received messages for protocol violations.
+    pub strict_parsing:  bool,
+    /// Number of protocol violations found in the strict parsing mode.
+    pub protocol_violations: usize,
+    /// Loss and reordering statistics of sequence-numbered data frames
+    /// received in all finished sessions.
+    pub data_sequence:   SequenceStats,
+    /// Session overload policies.
+    pub overload_policies: OverloadPolicies,
+    /// Targets allowed for the any-TCP service.
+    pub tcp_allowlist:   TcpAllowlist,
+    /// Session access control policy.
+    pub access_policy:   AccessPolicy,
+    /// Flap detector of unstable services.
+    pub flap_damping:    FlapDamping,
+    /// Per-service bitrate cap (in kbit/s).
+    pub max_bitrate:     Option<u32>,
+    /// Name of the vendor-specific RTSP parameter used for asking services
+    /// exceeding the bitrate cap for a lower bitrate.
+    pub bitrate_hint:    Option<String>,
+    /// End-to-end encryption keys.
+    #[cfg(feature = "e2e")]
+    pub e2e_keys:        E2eKeys,
+    /// Client certificate and private key files presented to Arrow Service.
+    pub client_cert:     Option<(String, String)>,
+    /// Lifecycle event hooks.
+    pub hooks:           Hooks,
+    /// Connection status LED.
+    pub status_led:      StatusLed,
+    /// Current state of the Arrow Service connection.
+    pub connection_state: LedState,
+    /// Last error of the Arrow Service connection (UNIX timestamp and error
+    /// message).
+    pub last_error:      Option<(i64, String)>,
+    /// Log trace IDs of Control Protocol messages and send the connection
+    /// trace ID within client metadata.
+    pub trace_ids:       bool,
+    /// Actions taken on REGISTER errors.
+    pub register_errors: RegisterErrorPolicy,
+    /// Configuration file (used for persisting rotated secrets).
+    pub config_file:     Option<String>,
+    /// Provider of the REGISTER credential.
+    pub auth_provider:   Arc<AuthProvider>,
+    /// Cached REGISTER message body.
+    pub register_cache:  RegisterCache,
+    /// Arrow Service accepted paged service tables during the last
+    /// handshake.
+    pub table_pages:     bool,
+    /// Send REGISTER messages without the client protocol info (Arrow
+    /// Service responded with a legacy or garbled ACK last time).
+    pub legacy_register: bool,
+    /// Service table last sent to Arrow Service (if any).
+    pub sent_svc_table:  Option<ServiceTable>,
+    /// Action taken when the service table version regresses.
+    pub version_regression: RegressionPolicy,
+    /// Periodical statistics export (if enabled).
+    pub stats_export:    Option<StatsExport>,
+    /// Network change monitor (if enabled).
+    #[cfg(feature = "discovery")]
+    pub network_monitor: Option<NetworkMonitor>,
+    /// Look up host names of discovered devices.
+    pub resolve_host_names: bool,
+    /// Host names of discovered devices (None if the name could not be
+    /// found).
+    pub host_names:      HashMap<MacAddr, Option<String>>,
+    /// OUI vendor database.
+    #[cfg(feature = "discovery")]
+    pub oui_database:    Arc<OuiDatabase>,
+    /// Downloaded OUI vendor database (if any, it is reloaded before every
+    /// network scan).
+    #[cfg(feature = "discovery")]
+    pub oui_file:        Option<String>,
+    /// Network interface traffic.
+    #[cfg(feature = "metrics")]
+    pub traffic:         TrafficMonitor,
+    /// IDs of active service sessions.
+    pub active_sessions: BTreeSet<u32>,
+    /// Session payload taps.
+    #[cfg(feature = "recording")]
+    pub session_taps:    SessionTaps,
+    /// PING period tuner.
+    pub ping_tuner:      PingTuner,
+    /// Handle any-TCP sessions in sandbox helper processes.
+    #[cfg(feature = "sandbox")]
+    pub sandbox_any_tcp: bool,
+    /// Remove connection tracking entries of finished sessions.
+    #[cfg(feature = "conntrack")]
+    pub flush_conntrack: bool,
+    /// Secret shared by clients and relays of the federation (used for
+    /// authenticating relay HELLO messages).
+    pub relay_secret:    Option<Secret<Vec<u8>>>,
+    /// SOCKS5 proxy for the connection to Arrow Service.
+    #[cfg(feature = "socks")]
+    pub socks_proxy:     Option<SocksProxy>,
+    /// SOCKS5 proxy for service sessions.
+    #[cfg(feature = "socks")]
+    pub service_socks_proxy: Option<SocksProxy>,
+    /// Session authorizer asked before opening service sessions.
+    pub session_authorizer: Option<Arc<SessionAuthorizer>>,
+    /// Time (in milliseconds) a buffer has to stay almost full before a
+    /// warning is logged.
+    pub buffer_alert_threshold: u64,
+    /// High-water mark of the Arrow output buffer since the last statistics
+    /// export (in bytes).
+    pub uplink_high_water: usize,
+    /// Transport of the connection to Arrow Service.
+    #[cfg(feature = "websocket")]
+    pub transport:       Transport,
+    /// WebSocket endpoint of Arrow Service (None for the default endpoint
+    /// derived from the Arrow Service address).
+    #[cfg(feature = "websocket")]
+    pub wss_endpoint:    Option<WssEndpoint>,
+    /// ID of the first Control Protocol message of the next connection
+    /// (random on startup, then continuing where the previous connection
+    /// stopped, so that message IDs are not reused after fast reconnects).
+    pub next_msg_id:     u16,
+    /// Time (UNIX timestamp) until which the current sessions may finish
+    /// after the control socket has been handed over to a new process (None
+    /// if not draining).
+    pub drain_deadline:  Option<i64>,
+    /// Connection metrics.
+    #[cfg(feature = "metrics")]
+    pub metrics:         Metrics,
+    /// Network interfaces where services asking for plaintext credentials
+    /// are refused.
+    pub untrusted_interfaces: UntrustedInterfaces,
+    /// Multicast streams announced using SAP.
+    #[cfg(feature = "discovery")]
+    pub sap_announcements: Announcements,
+    /// Runtime control of the application log level (None if the log level
+    /// cannot be changed remotely).
+    pub log_level:       Option<LevelController>,
+    /// CA certificate paths (files or directories).
+    pub ca_certificates: Vec<String>,
+    /// Generation of the TLS settings; it is incremented whenever the CA
+    /// certificates or the client certificate change, so that the SSL
+    /// context is rebuilt before the next connection.
+    pub tls_generation:  u64,
 }
 
 impl AppContext {
@@ -298,10 +699,369 @@ impl AppContext {
             config:          config,
             scanning:        false,
             diagnostic_mode: false,
+            clock_grace_mode: false,
+            clock_skew_tolerance: CLOCK_SKEW_TOLERANCE,
+            clock_behind:    false,
             discovery:       false,
-            scan_report:     ScanReport::new()
+            scan_time_limit: Some(SCAN_TIME_LIMIT),
+            scan_probe_limit: None,
+            scan_report:     ScanReport::new(),
+            scan_summary:    ScanSummary::new(),
+            metadata:        Metadata::new(),
+            service_connect_timeout: SERVICE_CONNECT_TIMEOUT,
+            uplink_write_timeout: UPLINK_WRITE_TIMEOUT,
+            ack_timeout:     ACK_TIMEOUT,
+            session_write_timeout: SESSION_WRITE_TIMEOUT,
+            uplink_buffer_size: UPLINK_BUFFER_SIZE,
+            session_buffer_size: SESSION_BUFFER_SIZE,
+            svc_stats:       ServiceStatsTable::new(),
+            report_svc_stats: false,
+            ack_latency:     LatencyHistogram::new(),
+            slow_ack_threshold: SLOW_ACK_THRESHOLD,
+            unsupported_messages: 0,
+            replayed_messages: 0,
+            strict_parsing:  false,
+            protocol_violations: 0,
+            data_sequence:   SequenceStats::new(),
+            overload_policies: OverloadPolicies::new(),
+            tcp_allowlist:   TcpAllowlist::new(),
+            access_policy:   AccessPolicy::new(),
+            flap_damping:    FlapDamping::new(FLAP_THRESHOLD),
+            max_bitrate:     None,
+            bitrate_hint:    None,
+            #[cfg(feature = "e2e")]
+            e2e_keys:        E2eKeys::new(),
+            client_cert:     None,
+            hooks:           Hooks::new(),
+            status_led:      StatusLed::disabled(),
+            connection_state: LedState::Connecting,
+            last_error:      None,
+            trace_ids:       false,
+            register_errors: RegisterErrorPolicy::new(),
+            config_file:     None,
+            auth_provider:   Arc::new(StaticSecret),
+            register_cache:  RegisterCache::new(),
+            table_pages:     false,
+            legacy_register: false,
+            sent_svc_table:  None,
+            version_regression: RegressionPolicy::Update,
+            stats_export:    None,
+            #[cfg(feature = "discovery")]
+            network_monitor: None,
+            resolve_host_names: false,
+            host_names:      HashMap::new(),
+            #[cfg(feature = "discovery")]
+            oui_database:    Arc::new(OuiDatabase::embedded()),
+            #[cfg(feature = "discovery")]
+            oui_file:        None,
+            #[cfg(feature = "metrics")]
+            traffic:         TrafficMonitor::new(None, None),
+            active_sessions: BTreeSet::new(),
+            #[cfg(feature = "recording")]
+            session_taps:    SessionTaps::new(TAP_DIR),
+            ping_tuner:      PingTuner::new(),
+            #[cfg(feature = "sandbox")]
+            sandbox_any_tcp: false,
+            #[cfg(feature = "conntrack")]
+            flush_conntrack: false,
+            relay_secret:    None,
+            #[cfg(feature = "socks")]
+            socks_proxy:     None,
+            #[cfg(feature = "socks")]
+            service_socks_proxy: None,
+            session_authorizer: None,
+            buffer_alert_threshold: BUFFER_ALERT_THRESHOLD,
+            uplink_high_water: 0,
+            #[cfg(feature = "websocket")]
+            transport:       Transport::Tls,
+            #[cfg(feature = "websocket")]
+            wss_endpoint:    None,
+            next_msg_id:     random_msg_id(),
+            drain_deadline:  None,
+            #[cfg(feature = "metrics")]
+            metrics:         Metrics::new(),
+            untrusted_interfaces: UntrustedInterfaces::new(),
+            #[cfg(feature = "discovery")]
+            sap_announcements: Announcements::new(),
+            log_level:       None,
+            ca_certificates: Vec::new(),
+            tls_generation:  0
+        }
+    }
+    
+    /// Create a context for another logical client (tenant) using a given
+    /// configuration saved in a given file. Settings are copied from this
+    /// context, while the state bound to this client (network scanning,
+    /// statistics files, status LED, etc.) is not shared.
+    pub fn tenant(&self, config: ArrowConfig, config_file: &str) -> AppContext {
+        let mut res = self.clone();
+
+        res.config          = config;
+        res.config_file     = Some(config_file.to_string());
+        res.scanning        = false;
+        res.discovery       = false;
+        res.scan_report     = ScanReport::new();
+        res.scan_summary    = ScanSummary::new();
+        res.svc_stats       = ServiceStatsTable::new();
+        res.ack_latency     = LatencyHistogram::new();
+        res.unsupported_messages = 0;
+        res.replayed_messages = 0;
+        res.protocol_violations = 0;
+        res.next_msg_id     = random_msg_id();
+        res.data_sequence   = SequenceStats::new();
+        res.status_led      = StatusLed::disabled();
+        res.connection_state = LedState::Connecting;
+        res.last_error      = None;
+        res.register_cache  = RegisterCache::new();
+        res.sent_svc_table  = None;
+        res.stats_export    = None;
+        res.resolve_host_names = false;
+        res.client_cert     = None;
+        res.host_names      = HashMap::new();
+        res.uplink_high_water = 0;
+
+        #[cfg(feature = "discovery")]
+        {
+            res.network_monitor   = None;
+            res.sap_announcements = Announcements::new();
+        }
+
+        #[cfg(feature = "recording")]
+        {
+            res.session_taps = SessionTaps::new(self.session_taps.dir());
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            res.wss_endpoint = None;
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            res.metrics = Metrics::new();
+        }
+
+        res
+    }
+
+    /// Check if sessions of a given service have to be end-to-end
+    /// encrypted.
+    #[cfg(feature = "e2e")]
+    pub fn requires_e2e(&self, svc: &Service) -> bool {
+        self.e2e_keys.get(svc).is_some()
+    }
+
+    /// Check if sessions of a given service have to be end-to-end encrypted
+    /// (dummy).
+    #[cfg(not(feature = "e2e"))]
+    pub fn requires_e2e(&self, _: &Service) -> bool {
+        false
+    }
+
+    /// Get connection options of a new session of a given service.
+    pub fn session_options(&self, svc: &Service) -> SessionOptions {
+        SessionOptions {
+            sandboxed: self.is_sandboxed(svc),
+            datagram:  svc.is_datagram(),
+            #[cfg(feature = "socks")]
+            proxy:     self.service_socks_proxy.clone(),
+            #[cfg(feature = "e2e")]
+            e2e_key:   self.e2e_keys.get(svc).cloned()
+        }
+    }
+
+    /// Check if sessions of a given service are handled by sandbox helper
+    /// processes.
+    #[cfg(feature = "sandbox")]
+    fn is_sandboxed(&self, svc: &Service) -> bool {
+        self.sandbox_any_tcp && *svc == Service::AnyTCP
+    }
+
+    /// Check if sessions of a given service are handled by sandbox helper
+    /// processes (dummy).
+    #[cfg(not(feature = "sandbox"))]
+    fn is_sandboxed(&self, _: &Service) -> bool {
+        false
+    }
+
+    /// Record a new session of a given service into the session taps and
+    /// the connection metrics (if enabled).
+    pub fn session_opened(&mut self, session_id: u32, service_id: u16) {
+        self.active_sessions.insert(session_id);
+
+        #[cfg(feature = "recording")]
+        {
+            self.session_taps.session_opened(session_id);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.session_opened(session_id, service_id);
+        }
+
+        let _ = service_id;
+    }
+
+    /// Record a closed session into the session taps and the connection
+    /// metrics (if enabled).
+    pub fn session_closed(&mut self, session_id: u32, failed: bool) {
+        self.active_sessions.remove(&session_id);
+
+        #[cfg(feature = "recording")]
+        {
+            self.session_taps.session_closed(session_id);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.session_closed(session_id, failed);
+        }
+
+        let _ = failed;
+    }
+
+    /// Record bytes received from and sent to the service by a given session
+    /// into the connection metrics.
+    #[cfg(feature = "metrics")]
+    pub fn session_transferred(
+        &mut self,
+        session_id: u32,
+        bytes_in: u64,
+        bytes_out: u64) {
+        self.metrics.session_transferred(session_id, bytes_in, bytes_out);
+    }
+
+    /// Record bytes received from and sent to the service by a given session
+    /// into the connection metrics (dummy).
+    #[cfg(not(feature = "metrics"))]
+    pub fn session_transferred(&mut self, _: u32, _: u64, _: u64) {
+    }
+
+    /// Get vendor of a device with a given MAC address.
+    #[cfg(feature = "discovery")]
+    pub fn vendor(&self, mac: &MacAddr) -> Option<&str> {
+        self.oui_database.vendor(mac)
+    }
+
+    /// Get vendor of a device with a given MAC address (dummy).
+    #[cfg(not(feature = "discovery"))]
+    pub fn vendor(&self, _: &MacAddr) -> Option<&str> {
+        None
+    }
+
+    /// Forget all sessions of the previous Arrow Service connection and
+    /// start new connection metrics (if enabled).
+    pub fn connection_started(&mut self) {
+        self.active_sessions.clear();
+
+        #[cfg(feature = "recording")]
+        {
+            self.session_taps.clear();
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.connection_started();
+        }
+    }
+
+    /// Check if a given session is being recorded.
+    #[cfg(feature = "recording")]
+    pub fn is_tapped(&self, session_id: u32) -> bool {
+        self.session_taps.is_tapped(session_id)
+    }
+
+    /// Check if a given session is being recorded (dummy).
+    #[cfg(not(feature = "recording"))]
+    pub fn is_tapped(&self, _: u32) -> bool {
+        false
+    }
+
+    /// Get current traffic of the uplink interface.
+    #[cfg(feature = "metrics")]
+    pub fn uplink_traffic(&self) -> TrafficStats {
+        self.traffic.uplink()
+    }
+
+    /// Get current traffic of the uplink interface (dummy).
+    #[cfg(not(feature = "metrics"))]
+    pub fn uplink_traffic(&self) -> TrafficStats {
+        TrafficStats::new()
+    }
+
+    /// Get a snapshot of the connection statistics.
+    pub fn connection_stats(&self) -> ConnectionRecord {
+        ConnectionRecord {
+            timestamp:   clock::timestamp(),
+            ack_count:   self.ack_latency.count(),
+            ack_avg:     self.ack_latency.avg(),
+            ack_max:     self.ack_latency.max(),
+            unsupported: self.unsupported_messages,
+            replayed:    self.replayed_messages,
+            frames:      self.data_sequence.received,
+            lost:        self.data_sequence.lost,
+            reordered:   self.data_sequence.reordered,
+            uplink_hwm:  self.uplink_high_water
         }
     }
+    
+    /// Get client metadata including the service statistics summary (if
+    /// enabled) and the system clock alert (if any).
+    pub fn client_metadata(&self) -> Metadata {
+        let mut metadata = self.metadata.clone();
+        
+        if self.report_svc_stats {
+            metadata.set_svc_stats(&self.svc_stats.summary());
+            
+            if self.ack_latency.count() > 0 {
+                metadata.set_ack_latency(&self.ack_latency.summary());
+            }
+        }
+        
+        let mut host_names = self.host_names.iter()
+            .filter_map(|(mac, name)| name.as_ref().map(|name| (mac, name)))
+            .collect::<Vec<_>>();
+        
+        host_names.sort_by_key(|&(mac, _)| mac.octets());
+        
+        for (mac, name) in host_names {
+            metadata.add_host_name(mac, name);
+        }
+        
+        if self.clock_behind {
+            metadata.set_clock_alert("system clock is behind, Arrow Service certificate is not valid yet");
+        }
+        
+        metadata
+    }
+}
+
+/// Read content of a given file.
+pub fn read_file(file: &str) -> Result<String> {
+    let mut content = String::new();
+    let file        = try!(File::open(file));
+    let mut breader = BufReader::new(file);
+    
+    try!(breader.read_to_string(&mut content));
+    
+    Ok(content)
+}
+
+/// Get SHA-256 digest of a given configuration file content.
+pub fn content_digest(content: &[u8]) -> [u8; 32] {
+    let digest  = hash::hash(HashType::SHA256, content);
+    let mut res = [0u8; 32];
+    
+    utils::memcpy(&mut res, &digest);
+    
+    res
+}
+
+/// Format a given password as a hyphenated UUID string.
+fn password_string(passwd: &Secret<[u8; 16]>) -> String {
+    Uuid::from_bytes(passwd.expose())
+        .unwrap()
+        .to_hyphenated_string()
 }
 
 /// Transform a given UUID into an array of 16 bytes.
@@ -315,3 +1075,11 @@ fn uuid_to_bytes(uuid: &Uuid) -> [u8; 16] {
     
     res
 }
+
+/// Get a random Control Protocol message ID.
+fn random_msg_id() -> u16 {
+    let uuid  = Uuid::new_v4();
+    let bytes = uuid.as_bytes();
+    
+    ((bytes[0] as u16) << 8) | (bytes[1] as u16)
+}
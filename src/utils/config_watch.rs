@@ -0,0 +1,185 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watching the configuration file for external modifications.
+//!
+//! A provisioning agent may edit the service table in the configuration file
+//! while the client is running. The file is polled periodically and its
+//! content is compared with the content written by the client itself. If the
+//! file has been modified externally, the service table is taken from the
+//! file. The client identity (UUID and passwords) is never taken from the
+//! modified file.
+
+use std::path::Path;
+
+use utils::config::{self, ArrowConfig, ConfigError};
+
+use net::arrow::protocol::ServiceTableDiff;
+
+/// Watcher of a configuration file.
+#[derive(Debug, Clone)]
+pub struct ConfigWatcher {
+    /// Configuration file.
+    file:   String,
+    /// Digest of the last checked content.
+    digest: Option<[u8; 32]>,
+}
+
+impl ConfigWatcher {
+    /// Create a new watcher of a given configuration file.
+    pub fn new(file: &str) -> ConfigWatcher {
+        ConfigWatcher {
+            file:   file.to_string(),
+            digest: None
+        }
+    }
+
+    /// Check the configuration file and reload the service table of a given
+    /// configuration if the file has been modified externally. The
+    /// configuration version is bumped if there are any changes in active
+    /// services. Differences between the tables are returned if the table
+    /// has been reloaded.
+    ///
+    /// Every modification is processed only once, so an invalid file is
+    /// reported only once as well. The file is expected to be replaced
+    /// atomically.
+    pub fn check(
+        &mut self,
+        config: &mut ArrowConfig) -> Result<Option<ServiceTableDiff>, ConfigError> {
+        // the client will create the file again on the next save
+        if !Path::new(&self.file).exists() {
+            return Ok(None);
+        }
+
+        let content = try!(config::read_file(&self.file));
+        let digest  = config::content_digest(content.as_bytes());
+
+        if self.digest == Some(digest) || config.is_last_saved(&digest) {
+            self.digest = Some(digest);
+            return Ok(None);
+        }
+
+        self.digest = Some(digest);
+
+        apply(config, &content)
+            .map(|diff| Some(diff))
+    }
+
+    /// Reload the service table of a given configuration from the
+    /// configuration file even if the file has not been modified externally
+    /// (e.g. on SIGHUP). The configuration version is bumped if there are
+    /// any changes in active services.
+    pub fn reload(
+        &mut self,
+        config: &mut ArrowConfig) -> Result<ServiceTableDiff, ConfigError> {
+        let content = try!(config::read_file(&self.file));
+
+        self.digest = Some(config::content_digest(content.as_bytes()));
+
+        apply(config, &content)
+    }
+}
+
+/// Take the service table from a given configuration file content and
+/// return differences between the tables.
+fn apply(
+    config: &mut ArrowConfig,
+    content: &str) -> Result<ServiceTableDiff, ConfigError> {
+    let external = try!(ArrowConfig::parse(content));
+
+    let diff = config.service_table()
+        .diff(external.service_table());
+
+    config.set_service_table(external.service_table().clone());
+
+    if !diff.is_empty() {
+        config.bump_version();
+    }
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use utils;
+    use utils::config::ArrowConfig;
+
+    use net::raw::ether::MacAddr;
+    use net::arrow::protocol::Service;
+
+    #[test]
+    fn test_config_watcher() {
+        let file = env::temp_dir()
+            .join("arrow-client-test-config-watch")
+            .to_string_lossy()
+            .into_owned();
+
+        fs::remove_file(&file)
+            .ok();
+
+        let mut config  = ArrowConfig::new();
+        let mut watcher = ConfigWatcher::new(&file);
+
+        // missing file
+        assert!(watcher.check(&mut config).unwrap().is_none());
+
+        config.save(&file)
+            .unwrap();
+
+        // own modification
+        assert!(watcher.check(&mut config).unwrap().is_none());
+        assert_eq!(config.version(), 0);
+
+        let mut external = config.clone();
+
+        let mac  = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let addr = SocketAddr::from_str("10.0.0.1:554").unwrap();
+
+        external.add_static(Service::RTSP(mac, addr, "/stream".to_string()));
+
+        utils::write_file_atomic(&file, format!("{}", external).as_bytes())
+            .unwrap();
+
+        // external modification
+        let diff = watcher.check(&mut config)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(config.version(), 1);
+        assert_eq!(config.active_services().len(), 2);
+
+        // the modification is processed only once
+        assert!(watcher.check(&mut config).unwrap().is_none());
+
+        utils::write_file_atomic(&file, b"{")
+            .unwrap();
+
+        assert!(watcher.check(&mut config).is_err());
+        assert!(watcher.check(&mut config).unwrap().is_none());
+
+        fs::remove_file(&file)
+            .ok();
+        fs::remove_file(utils::backup_file(&file))
+            .ok();
+    }
+}
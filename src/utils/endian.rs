@@ -0,0 +1,230 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Byte order and alignment safe encoding of integers.
+//!
+//! All multi-byte integers of the Arrow Protocol are big endian. Protocol
+//! structures are encoded and decoded field by field using the functions
+//! below (i.e. there are no casts between byte slices and structs or wider
+//! integer types), so the wire format does not depend on the byte order,
+//! alignment rules or struct layout of the host (e.g. big endian MIPS
+//! cameras which trap on unaligned loads).
+
+/// Encode a given 16-bit unsigned integer as big endian.
+pub fn u16_to_bytes(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+/// Encode a given 32-bit unsigned integer as big endian.
+pub fn u32_to_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+/// Encode a given 64-bit unsigned integer as big endian.
+pub fn u64_to_bytes(n: u64) -> [u8; 8] {
+    let hi = u32_to_bytes((n >> 32) as u32);
+    let lo = u32_to_bytes(n as u32);
+
+    [hi[0], hi[1], hi[2], hi[3], lo[0], lo[1], lo[2], lo[3]]
+}
+
+/// Decode a big endian 16-bit unsigned integer from the beginning of a given
+/// slice.
+///
+/// # Panics
+/// The function panics if the slice is shorter than 2 bytes.
+pub fn get_u16(data: &[u8]) -> u16 {
+    ((data[0] as u16) << 8) | (data[1] as u16)
+}
+
+/// Decode a big endian 32-bit unsigned integer from the beginning of a given
+/// slice.
+///
+/// # Panics
+/// The function panics if the slice is shorter than 4 bytes.
+pub fn get_u32(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 24)
+        | ((data[1] as u32) << 16)
+        | ((data[2] as u32) << 8)
+        | (data[3] as u32)
+}
+
+/// Decode a big endian 64-bit unsigned integer from the beginning of a given
+/// slice.
+///
+/// # Panics
+/// The function panics if the slice is shorter than 8 bytes.
+pub fn get_u64(data: &[u8]) -> u64 {
+    ((get_u32(data) as u64) << 32) | (get_u32(&data[4..]) as u64)
+}
+
+/// Encode a given 16-bit unsigned integer using the byte order of the host
+/// (needed only for kernel interfaces like netlink).
+pub fn u16_to_native_bytes(n: u16) -> [u8; 2] {
+    let b = u16_to_bytes(n);
+
+    if cfg!(target_endian = "big") {
+        b
+    } else {
+        [b[1], b[0]]
+    }
+}
+
+/// Encode a given 32-bit unsigned integer using the byte order of the host
+/// (needed only for kernel interfaces like netlink).
+pub fn u32_to_native_bytes(n: u32) -> [u8; 4] {
+    let b = u32_to_bytes(n);
+
+    if cfg!(target_endian = "big") {
+        b
+    } else {
+        [b[3], b[2], b[1], b[0]]
+    }
+}
+
+/// Decode a 16-bit unsigned integer in the byte order of the host from the
+/// beginning of a given slice.
+///
+/// # Panics
+/// The function panics if the slice is shorter than 2 bytes.
+pub fn get_native_u16(data: &[u8]) -> u16 {
+    if cfg!(target_endian = "big") {
+        get_u16(data)
+    } else {
+        get_u16(&[data[1], data[0]])
+    }
+}
+
+/// Decode a 32-bit unsigned integer in the byte order of the host from the
+/// beginning of a given slice.
+///
+/// # Panics
+/// The function panics if the slice is shorter than 4 bytes.
+pub fn get_native_u32(data: &[u8]) -> u32 {
+    if cfg!(target_endian = "big") {
+        get_u32(data)
+    } else {
+        get_u32(&[data[3], data[2], data[1], data[0]])
+    }
+}
+
+/// Sequential reader of big endian integers from a byte slice.
+///
+/// The reader does not check the remaining length, the caller is expected
+/// to check the size of the whole structure before reading it.
+pub struct ByteReader<'a> {
+    data:   &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    /// Create a new reader for a given slice.
+    pub fn new(data: &'a [u8]) -> ByteReader<'a> {
+        ByteReader {
+            data:   data,
+            offset: 0
+        }
+    }
+
+    /// Read a given number of bytes.
+    pub fn read_bytes(&mut self, len: usize) -> &'a [u8] {
+        let res = &self.data[self.offset..self.offset + len];
+
+        self.offset += len;
+
+        res
+    }
+
+    /// Read a single byte.
+    pub fn read_u8(&mut self) -> u8 {
+        self.read_bytes(1)[0]
+    }
+
+    /// Read a 16-bit unsigned integer.
+    pub fn read_u16(&mut self) -> u16 {
+        get_u16(self.read_bytes(2))
+    }
+
+    /// Read a 32-bit unsigned integer.
+    pub fn read_u32(&mut self) -> u32 {
+        get_u32(self.read_bytes(4))
+    }
+
+    /// Read a 64-bit unsigned integer.
+    pub fn read_u64(&mut self) -> u64 {
+        get_u64(self.read_bytes(8))
+    }
+
+    /// Get the number of bytes read so far.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simple xorshift generator for pseudo-random test values.
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(u16_to_bytes(0x0102), [1, 2]);
+        assert_eq!(u32_to_bytes(0x01020304), [1, 2, 3, 4]);
+        assert_eq!(u64_to_bytes(0x0102030405060708), [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // unaligned reads
+        let data = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert_eq!(get_u16(&data[1..]), 0x0102);
+        assert_eq!(get_u32(&data[1..]), 0x01020304);
+        assert_eq!(get_u64(&data[1..]), 0x0102030405060708);
+
+        let mut reader = ByteReader::new(&data);
+
+        assert_eq!(reader.read_u8(), 0);
+        assert_eq!(reader.read_u16(), 0x0102);
+        assert_eq!(reader.read_u32(), 0x03040506);
+        assert_eq!(reader.read_bytes(2), &[7, 8]);
+        assert_eq!(reader.position(), 9);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut state = 0x2545f4914f6cdd1d;
+
+        for _ in 0..10000 {
+            let n = next_random(&mut state);
+
+            assert_eq!(get_u16(&u16_to_bytes(n as u16)), n as u16);
+            assert_eq!(get_u32(&u32_to_bytes(n as u32)), n as u32);
+            assert_eq!(get_u64(&u64_to_bytes(n)), n);
+            assert_eq!(get_native_u16(&u16_to_native_bytes(n as u16)), n as u16);
+            assert_eq!(get_native_u32(&u32_to_native_bytes(n as u32)), n as u32);
+
+            // the encoding must match the host-independent definition
+            let bytes = u64_to_bytes(n);
+
+            for i in 0..8 {
+                assert_eq!(bytes[i], (n >> (56 - 8 * i)) as u8);
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration using environment variables.
+//!
+//! Containers are usually configured using environment variables rather
+//! than command line arguments. The variables are translated into command
+//! line arguments as follows:
+//!
+//! * `ARROW_SERVICE` contains the Arrow Service address (mandatory),
+//! * variables from the `SHORT_OPTIONS` table are translated into the
+//!   corresponding short options (e.g. `ARROW_DISCOVERY=1` into `-d`,
+//!   `ARROW_RTSP_SERVICES="url1 url2"` into `-r url1 -r url2`),
+//! * `ARROW_ARGS` contains additional whitespace-separated arguments,
+//! * any other `ARROW_FOO_BAR=value` variable is translated into the
+//!   `--foo-bar=value` option; boolean values ("1", "true", "yes") are
+//!   translated into `--foo-bar` and false values ("0", "false", "no" or an
+//!   empty string) are skipped.
+
+/// Prefix of the configuration variables.
+const PREFIX: &'static str = "ARROW_";
+
+/// Name of the variable containing Arrow Service address.
+pub const SERVICE_VARIABLE: &'static str = "ARROW_SERVICE";
+
+/// Name of the variable containing additional arguments.
+const ARGS_VARIABLE: &'static str = "ARROW_ARGS";
+
+/// Variables translated into short options (variable name without the
+/// prefix, option, the option takes a list of values).
+static SHORT_OPTIONS: &'static [(&'static str, &'static str, bool)] = &[
+    ("CA_CERTIFICATES", "-c", true),
+    ("DISCOVERY",       "-d", false),
+    ("INTERFACE",       "-i", true),
+    ("RTSP_SERVICES",   "-r", true),
+    ("MJPEG_SERVICES",  "-m", true),
+    ("HTTP_SERVICES",   "-h", true),
+    ("TCP_SERVICES",    "-t", true),
+    ("UDP_SERVICES",    "-u", true),
+    ("VERBOSE",         "-v", false),
+];
+
+/// Translate given environment variables into command line arguments
+/// (without the application name). None is returned if the Arrow Service
+/// address is not set.
+pub fn args<I>(vars: I) -> Option<Vec<String>>
+    where I: IntoIterator<Item=(String, String)> {
+    let mut vars = vars.into_iter()
+        .filter(|&(ref name, _)| name.starts_with(PREFIX))
+        .collect::<Vec<_>>();
+
+    // make the result independent of the environment ordering
+    vars.sort();
+
+    let service = vars.iter()
+        .find(|&&(ref name, _)| name == SERVICE_VARIABLE)
+        .map(|&(_, ref value)| value.clone());
+
+    let mut res = match service {
+        Some(service) => vec![service],
+        None => return None
+    };
+
+    for &(ref name, ref value) in &vars {
+        if name == SERVICE_VARIABLE {
+            continue;
+        } else if name == ARGS_VARIABLE {
+            res.extend(value.split_whitespace()
+                .map(|arg| arg.to_string()));
+        } else {
+            append_option(&mut res, &name[PREFIX.len()..], value);
+        }
+    }
+
+    Some(res)
+}
+
+/// Append option corresponding to a given variable (without the prefix).
+fn append_option(args: &mut Vec<String>, name: &str, value: &str) {
+    let short = SHORT_OPTIONS.iter()
+        .find(|&&(var, _, _)| var == name);
+
+    if let Some(&(_, option, list)) = short {
+        if list {
+            for item in value.split_whitespace() {
+                args.push(option.to_string());
+                args.push(item.to_string());
+            }
+        } else if is_true(value) {
+            args.push(option.to_string());
+        }
+    } else {
+        let option = format!("--{}", name.to_lowercase().replace("_", "-"));
+
+        if is_true(value) {
+            args.push(option);
+        } else if !is_false(value) {
+            args.push(format!("{}={}", option, value));
+        }
+    }
+}
+
+/// Check if a given value represents boolean true.
+fn is_true(value: &str) -> bool {
+    match &value.to_lowercase() as &str {
+        "1" | "true" | "yes" => true,
+        _ => false
+    }
+}
+
+/// Check if a given value represents boolean false.
+fn is_false(value: &str) -> bool {
+    match &value.to_lowercase() as &str {
+        "" | "0" | "false" | "no" => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_args() {
+        assert_eq!(args(vars(&[("ARROW_DISCOVERY", "1")])), None);
+
+        let res = args(vars(&[
+            ("HOME", "/root"),
+            ("ARROW_SERVICE", "arrow.example.com:8900"),
+            ("ARROW_DISCOVERY", "true"),
+            ("ARROW_VERBOSE", "0"),
+            ("ARROW_RTSP_SERVICES", "rtsp://a/1 rtsp://b/2"),
+            ("ARROW_CONFIG_FILE", "/data/config.json"),
+            ("ARROW_LOG_JSON", "yes"),
+            ("ARROW_DIAGNOSTIC_MODE", "false"),
+            ("ARROW_ARGS", "-h 10.0.0.1:80")
+        ])).unwrap();
+
+        assert_eq!(res, vec![
+            "arrow.example.com:8900",
+            "-h", "10.0.0.1:80",
+            "--config-file=/data/config.json",
+            "-d",
+            "--log-json",
+            "-r", "rtsp://a/1",
+            "-r", "rtsp://b/2"
+        ]);
+    }
+}
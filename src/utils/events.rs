@@ -0,0 +1,295 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Live client events.
+//!
+//! Lifecycle events (connection state changes, sessions, network scans and
+//! alerts) and logged warnings are published into an event feed. The feed
+//! passes them to its current subscribers (e.g. the `monitor` control socket
+//! command). Events are not buffered, a subscriber receives only events
+//! published after it has subscribed.
+
+use std::fmt;
+
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+
+use time;
+
+use utils::RuntimeError;
+use utils::logger::{Logger, Severity};
+
+/// Event categories.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventCategory {
+    /// Connection to Arrow Service.
+    Connection,
+    /// Service sessions.
+    Session,
+    /// Network scans.
+    Scan,
+    /// Network and bitrate alerts.
+    Alert,
+    /// Logged warnings and errors.
+    Warning,
+}
+
+impl EventCategory {
+    /// Parse a given category name.
+    pub fn from_name(name: &str) -> Result<EventCategory, RuntimeError> {
+        match name {
+            "connection" => Ok(EventCategory::Connection),
+            "session"    => Ok(EventCategory::Session),
+            "scan"       => Ok(EventCategory::Scan),
+            "alert"      => Ok(EventCategory::Alert),
+            "warning"    => Ok(EventCategory::Warning),
+            _ => Err(RuntimeError::from(format!("unknown event category: {}", name)))
+        }
+    }
+
+    /// Get category name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &EventCategory::Connection => "connection",
+            &EventCategory::Session    => "session",
+            &EventCategory::Scan       => "scan",
+            &EventCategory::Alert      => "alert",
+            &EventCategory::Warning    => "warning"
+        }
+    }
+}
+
+/// A single event.
+#[derive(Debug, Clone)]
+pub struct Event {
+    time:     String,
+    category: EventCategory,
+    name:     String,
+    fields:   Vec<(String, String)>,
+}
+
+impl Event {
+    /// Create a new event of a given category and name with given fields
+    /// (name-value pairs). The current local time is used as the event
+    /// time.
+    pub fn new(
+        category: EventCategory,
+        name: &str,
+        fields: Vec<(String, String)>) -> Event {
+        let time = time::strftime("%F %T", &time::now())
+            .unwrap();
+
+        Event {
+            time:     time,
+            category: category,
+            name:     name.to_string(),
+            fields:   fields
+        }
+    }
+
+    /// Get event category.
+    pub fn category(&self) -> EventCategory {
+        self.category
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "{} {:<10} {}", self.time, self.category.name(), self.name));
+
+        for &(ref name, ref value) in &self.fields {
+            if value.is_empty() || value.contains(' ') || value.contains('"') {
+                try!(write!(f, " {}={:?}", name, value));
+            } else {
+                try!(write!(f, " {}={}", name, value));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A subscriber (with its category filter; an empty filter matches all
+/// categories).
+type Subscriber = (Vec<EventCategory>, Sender<Event>);
+
+/// Event feed. All clones of the feed share the same subscribers.
+#[derive(Clone)]
+pub struct EventFeed {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventFeed {
+    /// Create a new event feed with no subscribers.
+    pub fn new() -> EventFeed {
+        EventFeed {
+            subscribers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// Subscribe to events of given categories (all events are received if
+    /// the list is empty). The subscription is cancelled once the receiver
+    /// is dropped.
+    pub fn subscribe(&self, categories: &[EventCategory]) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+
+        self.subscribers.lock()
+            .unwrap()
+            .push((categories.to_vec(), tx));
+
+        rx
+    }
+
+    /// Publish a given event. Cancelled subscriptions are removed.
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock()
+            .unwrap();
+
+        subscribers.retain(|&(ref categories, ref tx)| {
+            if categories.is_empty() || categories.contains(&event.category) {
+                tx.send(event.clone()).is_ok()
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Get number of subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock()
+            .unwrap()
+            .len()
+    }
+}
+
+impl Debug for EventFeed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "EventFeed {{ subscribers: {} }}", self.subscribers())
+    }
+}
+
+/// Logger publishing warnings and errors into a given event feed before
+/// passing all messages to an underlying logger.
+#[derive(Clone)]
+pub struct EventLogger<L: Logger> {
+    logger: L,
+    feed:   EventFeed,
+}
+
+impl<L: Logger> EventLogger<L> {
+    /// Create a new event logger.
+    pub fn new(logger: L, feed: EventFeed) -> EventLogger<L> {
+        EventLogger {
+            logger: logger,
+            feed:   feed
+        }
+    }
+}
+
+impl<L: Logger> EventLogger<L> {
+    /// Publish a given message if it is a warning or an error.
+    fn publish_warning(&self, s: Severity, msg: &str) {
+        let name = match s {
+            Severity::WARN  => Some("warning"),
+            Severity::ERROR => Some("error"),
+            _ => None
+        };
+
+        if let Some(name) = name {
+            self.feed.publish(Event::new(EventCategory::Warning, name,
+                vec![("message".to_string(), msg.to_string())]));
+        }
+    }
+}
+
+impl<L: Logger> Logger for EventLogger<L> {
+    fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
+        self.publish_warning(s, msg);
+        self.logger.log(file, line, s, msg)
+    }
+
+    fn log_fields(
+        &mut self,
+        file: &str,
+        line: u32,
+        s: Severity,
+        fields: &[(&str, &str)],
+        msg: &str) {
+        self.publish_warning(s, msg);
+        self.logger.log_fields(file, line, s, fields, msg)
+    }
+
+    fn set_level(&mut self, s: Severity) {
+        self.logger.set_level(s)
+    }
+
+    fn get_level(&self) -> Severity {
+        self.logger.get_level()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::logger::{DummyLogger, Logger};
+
+    #[test]
+    fn test_event_feed() {
+        let feed = EventFeed::new();
+
+        let all      = feed.subscribe(&[]);
+        let sessions = feed.subscribe(&[EventCategory::Session]);
+
+        feed.publish(Event::new(EventCategory::Scan, "scan-complete",
+            vec![("hosts".to_string(), "3".to_string())]));
+        feed.publish(Event::new(EventCategory::Session, "session-open",
+            vec![("error".to_string(), "foo bar".to_string())]));
+
+        let event = all.try_recv().unwrap();
+
+        assert_eq!(event.category(), EventCategory::Scan);
+        assert!(format!("{}", event).ends_with(" scan-complete hosts=3"));
+
+        let event = all.try_recv().unwrap();
+
+        assert_eq!(event.category(), EventCategory::Session);
+        assert!(format!("{}", event).ends_with(" session-open error=\"foo bar\""));
+
+        assert!(all.try_recv().is_err());
+
+        assert_eq!(sessions.try_recv().unwrap().category(), EventCategory::Session);
+        assert!(sessions.try_recv().is_err());
+
+        drop(all);
+
+        feed.publish(Event::new(EventCategory::Connection, "connect", Vec::new()));
+
+        assert_eq!(feed.subscribers(), 1);
+
+        let warnings   = feed.subscribe(&[EventCategory::Warning]);
+        let mut logger = EventLogger::new(DummyLogger::new(), feed.clone());
+
+        log_info!(logger, "foo");
+        log_warn!(logger, "bar");
+
+        assert!(format!("{}", warnings.try_recv().unwrap()).ends_with(" warning message=bar"));
+        assert!(warnings.try_recv().is_err());
+
+        assert!(EventCategory::from_name("foo").is_err());
+        assert_eq!(EventCategory::from_name("alert").unwrap(), EventCategory::Alert);
+    }
+}
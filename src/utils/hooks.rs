@@ -0,0 +1,197 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lifecycle event hooks.
+//!
+//! Hooks are external commands executed (using `/bin/sh -c`) on client
+//! lifecycle events. The event name is passed in the `ARROW_EVENT`
+//! environment variable together with event-specific variables. Hooks are
+//! executed asynchronously and their exit status is ignored.
+//!
+//! All events are also published into the live event feed (together with
+//! their variables), regardless of whether there is any hook configured.
+
+use std::io;
+use std::thread;
+
+use std::process::{Command, Stdio};
+
+use utils::RuntimeError;
+use utils::events::{Event, EventCategory, EventFeed};
+
+/// Client lifecycle events.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HookEvent {
+    /// Connection to Arrow Service has been established.
+    Connected,
+    /// Connection to Arrow Service has been lost (`ARROW_ERROR`).
+    Disconnected,
+    /// Network scan has been completed (`ARROW_SCAN_HOSTS`,
+    /// `ARROW_SCAN_NEW_SERVICES`, `ARROW_SCAN_REMOVED_SERVICES`).
+    ScanCompleted,
+    /// A service session has been opened (`ARROW_SERVICE_ID`,
+    /// `ARROW_SESSION_ID`).
+    SessionOpened,
+    /// A service session has been closed (`ARROW_SERVICE_ID`,
+    /// `ARROW_SESSION_ID` and optionally `ARROW_ERROR`).
+    SessionClosed,
+    /// The network monitor detected a new host or a changed host
+    /// fingerprint (`ARROW_ALERT_TYPE`, `ARROW_ALERT_MAC`,
+    /// `ARROW_ALERT_VENDOR`, `ARROW_ALERT_IP`, `ARROW_ALERT_PORTS`).
+    NetworkAlert,
+    /// A service exceeded the configured bitrate cap (`ARROW_SERVICE_ID`,
+    /// `ARROW_BITRATE`, `ARROW_MAX_BITRATE`).
+    BitrateExceeded,
+}
+
+impl HookEvent {
+    /// Parse a given event name.
+    pub fn from_name(name: &str) -> Result<HookEvent, RuntimeError> {
+        match name {
+            "connect"       => Ok(HookEvent::Connected),
+            "disconnect"    => Ok(HookEvent::Disconnected),
+            "scan-complete" => Ok(HookEvent::ScanCompleted),
+            "session-open"  => Ok(HookEvent::SessionOpened),
+            "session-close" => Ok(HookEvent::SessionClosed),
+            "network-alert" => Ok(HookEvent::NetworkAlert),
+            "bitrate-exceeded" => Ok(HookEvent::BitrateExceeded),
+            _ => Err(RuntimeError::from(format!("unknown hook event: {}", name)))
+        }
+    }
+
+    /// Get event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &HookEvent::Connected     => "connect",
+            &HookEvent::Disconnected  => "disconnect",
+            &HookEvent::ScanCompleted => "scan-complete",
+            &HookEvent::SessionOpened => "session-open",
+            &HookEvent::SessionClosed => "session-close",
+            &HookEvent::NetworkAlert  => "network-alert",
+            &HookEvent::BitrateExceeded => "bitrate-exceeded"
+        }
+    }
+
+    /// Get category of the event in the live event feed.
+    pub fn category(&self) -> EventCategory {
+        match self {
+            &HookEvent::Connected     => EventCategory::Connection,
+            &HookEvent::Disconnected  => EventCategory::Connection,
+            &HookEvent::ScanCompleted => EventCategory::Scan,
+            &HookEvent::SessionOpened => EventCategory::Session,
+            &HookEvent::SessionClosed => EventCategory::Session,
+            &HookEvent::NetworkAlert  => EventCategory::Alert,
+            &HookEvent::BitrateExceeded => EventCategory::Alert
+        }
+    }
+}
+
+/// Configured hooks.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    hooks:  Vec<(HookEvent, String)>,
+    events: EventFeed,
+}
+
+impl Hooks {
+    /// Create a new empty set of hooks.
+    pub fn new() -> Hooks {
+        Hooks {
+            hooks:  Vec::new(),
+            events: EventFeed::new()
+        }
+    }
+
+    /// Add a given command for a given event.
+    pub fn add(&mut self, event: HookEvent, command: &str) {
+        self.hooks.push((event, command.to_string()));
+    }
+
+    /// Get the live event feed.
+    pub fn events(&self) -> &EventFeed {
+        &self.events
+    }
+
+    /// Execute all commands of a given event with given additional
+    /// environment variables and publish the event into the live event
+    /// feed. The last error is returned if some of the commands could not
+    /// be started.
+    pub fn fire(
+        &self,
+        event: HookEvent,
+        env: &[(&str, String)]) -> io::Result<()> {
+        let fields = env.iter()
+            .map(|&(name, ref value)| {
+                let name = name.trim_left_matches("ARROW_")
+                    .to_lowercase();
+                (name, value.clone())
+            })
+            .collect::<Vec<_>>();
+
+        self.events.publish(Event::new(event.category(), event.name(), fields));
+
+        let mut res = Ok(());
+
+        let commands = self.hooks.iter()
+            .filter(|&&(e, _)| e == event)
+            .map(|&(_, ref command)| command);
+
+        for command in commands {
+            let mut cmd = Command::new("/bin/sh");
+
+            cmd.arg("-c")
+                .arg(command)
+                .env("ARROW_EVENT", event.name())
+                .stdin(Stdio::null());
+
+            for &(name, ref value) in env {
+                cmd.env(name, value);
+            }
+
+            match cmd.spawn() {
+                // the child needs to be reaped
+                Ok(mut child) => {
+                    thread::spawn(move || child.wait());
+                },
+                Err(err) => res = Err(err)
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_names() {
+        let events = [
+            HookEvent::Connected,
+            HookEvent::Disconnected,
+            HookEvent::ScanCompleted,
+            HookEvent::SessionOpened,
+            HookEvent::SessionClosed,
+            HookEvent::NetworkAlert,
+            HookEvent::BitrateExceeded,
+        ];
+
+        for event in &events {
+            assert_eq!(HookEvent::from_name(event.name()).unwrap(), *event);
+        }
+
+        assert!(HookEvent::from_name("foo").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client identity definitions.
+//!
+//! The Arrow Client identifies itself using a MAC address. Containers usually
+//! get a new random (locally administered) MAC address on every restart. This
+//! module derives a stable pseudo-MAC address in such cases. The address is
+//! derived from a persisted seed containing a random component, so cloned
+//! images do not share the same identity.
+
+use std::fs::File;
+use std::io::Read;
+
+use net::raw::ether::MacAddr;
+
+use utils;
+
+use utils::RuntimeError;
+
+use uuid::Uuid;
+
+use openssl::crypto::hash;
+
+use openssl::crypto::hash::Type as HashType;
+
+/// Files containing machine ID.
+static MACHINE_ID_FILES: &'static [&'static str] = &[
+    "/etc/machine-id",
+    "/var/lib/dbus/machine-id"
+];
+
+/// Check if a given MAC address is likely to be stable across restarts (i.e.
+/// it is a non-zero universally administered unicast address).
+pub fn is_stable_mac(mac: &MacAddr) -> bool {
+    let octets = mac.octets();
+
+    (octets[0] & 0x03) == 0 && octets != [0u8; 6]
+}
+
+/// Get pseudo-MAC address derived from a seed persisted in a given file (or
+/// its backup). None is returned if there is no persisted seed.
+pub fn load_mac(seed_file: &str) -> Option<MacAddr> {
+    load_seed(seed_file)
+        .or_else(|| load_seed(&utils::backup_file(seed_file)))
+        .map(|seed| seed_to_mac(&seed))
+}
+
+/// Derive a stable pseudo-MAC address from a new seed and persist the seed
+/// in a given file. The seed consists of machine ID (if available) and a
+/// random UUID.
+pub fn derive_mac(seed_file: &str) -> Result<MacAddr, RuntimeError> {
+    let seed = create_seed(load_machine_id());
+
+    try!(utils::write_file_with_backup(seed_file, &seed)
+        .map_err(|err| RuntimeError::from(
+            format!("unable to persist identity seed: {}", err))));
+
+    Ok(seed_to_mac(&seed))
+}
+
+/// Create a new seed from a given machine ID and a random UUID.
+fn create_seed(machine_id: Option<Vec<u8>>) -> Vec<u8> {
+    let mut seed = machine_id.unwrap_or(Vec::new());
+
+    if !seed.is_empty() {
+        seed.push(b':');
+    }
+
+    seed.extend_from_slice(Uuid::new_v4()
+        .to_hyphenated_string()
+        .as_bytes());
+
+    seed
+}
+
+/// Load a non-empty seed from a given file.
+fn load_seed(file: &str) -> Option<Vec<u8>> {
+    let mut content = String::new();
+
+    File::open(file)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok();
+
+    let seed = content.trim();
+
+    if seed.is_empty() {
+        None
+    } else {
+        Some(seed.as_bytes().to_vec())
+    }
+}
+
+/// Load machine ID.
+fn load_machine_id() -> Option<Vec<u8>> {
+    MACHINE_ID_FILES.iter()
+        .filter_map(|file| load_seed(file))
+        .next()
+}
+
+/// Derive a locally administered unicast MAC address from a given seed.
+fn seed_to_mac(seed: &[u8]) -> MacAddr {
+    let digest = hash::hash(HashType::SHA256, seed);
+
+    MacAddr::new(
+        (digest[0] & 0xfc) | 0x02,
+        digest[1],
+        digest[2],
+        digest[3],
+        digest[4],
+        digest[5])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{create_seed, seed_to_mac};
+
+    use std::env;
+    use std::fs;
+
+    use net::raw::ether::MacAddr;
+
+    #[test]
+    fn test_stable_mac() {
+        assert!(is_stable_mac(&MacAddr::new(0x00, 0x1b, 0x21, 1, 2, 3)));
+        assert!(!is_stable_mac(&MacAddr::new(0x02, 0x42, 0xac, 0x11, 0, 2)));
+        assert!(!is_stable_mac(&MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 1)));
+        assert!(!is_stable_mac(&MacAddr::new(0, 0, 0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn test_seed_to_mac() {
+        let mac1 = seed_to_mac(b"seed");
+        let mac2 = seed_to_mac(b"seed");
+        let mac3 = seed_to_mac(b"another seed");
+
+        assert_eq!(mac1, mac2);
+        assert!(mac1 != mac3);
+        assert_eq!(mac1.octets()[0] & 0x03, 0x02);
+    }
+
+    #[test]
+    fn test_seed_creation() {
+        let seed1 = create_seed(Some(b"machine".to_vec()));
+        let seed2 = create_seed(Some(b"machine".to_vec()));
+
+        // the random component makes seeds of cloned machines different
+        assert!(seed1.starts_with(b"machine:"));
+        assert!(seed1 != seed2);
+
+        assert!(!create_seed(None).starts_with(b":"));
+    }
+
+    #[test]
+    fn test_persisted_identity() {
+        let file = env::temp_dir()
+            .join(format!("arrow-client-identity-test-{}", unsafe { ::libc::getpid() }));
+
+        let file = file.to_str()
+            .unwrap();
+
+        assert!(load_mac(file).is_none());
+
+        let mac = derive_mac(file)
+            .unwrap();
+
+        assert_eq!(load_mac(file), Some(mac));
+
+        // the identity is kept even if only the backup is left
+        fs::rename(file, utils::backup_file(file))
+            .unwrap();
+
+        assert_eq!(load_mac(file), Some(mac));
+
+        fs::remove_file(utils::backup_file(file))
+            .ok();
+    }
+}
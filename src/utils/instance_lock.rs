@@ -0,0 +1,208 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single-instance guard.
+//!
+//! Two clients started with the same configuration file would register with
+//! the same UUID and fight over sessions. Every client holds an exclusive
+//! lock of a lock file next to its configuration file (`<config>.lock`)
+//! while it is running. The lock is released by the kernel once the process
+//! exits (even if it crashes), so stale lock files do not block anything.
+
+use std::io;
+use std::thread;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use utils::RuntimeError;
+
+use time;
+
+use libc;
+
+/// Period of lock acquisition attempts (in milliseconds).
+const RETRY_PERIOD: u64 = 100;
+
+/// Exclusive lock of a configuration file.
+///
+/// Clones share the same lock, so that it can be released from any thread.
+#[derive(Debug, Clone)]
+pub struct InstanceLock {
+    /// Lock file path.
+    path: String,
+    /// Locked file (None if the lock is not held).
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl InstanceLock {
+    /// Create a new (not acquired) lock for a given configuration file.
+    pub fn new(config_file: &str) -> InstanceLock {
+        InstanceLock {
+            path: format!("{}.lock", config_file),
+            file: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// Get the lock file path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Try to acquire the lock. An error is returned immediately if the
+    /// lock is held by another process.
+    pub fn acquire(&self) -> Result<(), RuntimeError> {
+        let mut guard = self.file.lock()
+            .unwrap();
+
+        if guard.is_some() {
+            return Ok(());
+        }
+
+        let mut file = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(|err| RuntimeError::from(format!(
+                "unable to open lock file \"{}\": {}", self.path, err))));
+
+        if let Err(err) = lock_file(&file) {
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(RuntimeError::from(format!(
+                    "unable to lock \"{}\": {}", self.path, err)));
+            }
+
+            let mut owner = String::new();
+
+            file.read_to_string(&mut owner)
+                .unwrap_or(0);
+
+            let owner = owner.trim();
+
+            let msg = if owner.is_empty() {
+                "another instance is already running with this \
+                    configuration".to_string()
+            } else {
+                format!("another instance (PID {}) is already running \
+                    with this configuration", owner)
+            };
+
+            return Err(RuntimeError::from(msg));
+        }
+
+        // the PID is informative only
+        let res = file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", unsafe { libc::getpid() }));
+
+        if let Err(err) = res {
+            return Err(RuntimeError::from(format!(
+                "unable to write lock file \"{}\": {}", self.path, err)));
+        }
+
+        *guard = Some(file);
+
+        Ok(())
+    }
+
+    /// Try to acquire the lock repeatedly until a given timeout (in
+    /// milliseconds) expires.
+    pub fn acquire_timeout(&self, timeout: u64) -> Result<(), RuntimeError> {
+        let deadline = time::precise_time_ns() + timeout * 1000000;
+
+        loop {
+            let res = self.acquire();
+
+            if res.is_ok() || time::precise_time_ns() >= deadline {
+                return res;
+            }
+
+            thread::sleep(Duration::from_millis(RETRY_PERIOD));
+        }
+    }
+
+    /// Release the lock (if held).
+    pub fn release(&self) {
+        let mut guard = self.file.lock()
+            .unwrap();
+
+        if let Some(file) = guard.take() {
+            unsafe {
+                libc::flock(file.as_raw_fd(), libc::LOCK_UN);
+            }
+        }
+    }
+
+    /// Check if the lock is held by this process.
+    pub fn is_held(&self) -> bool {
+        self.file.lock()
+            .unwrap()
+            .is_some()
+    }
+}
+
+/// Lock a given file exclusively without blocking.
+fn lock_file(file: &File) -> io::Result<()> {
+    let ret = unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_instance_lock() {
+        let config_file = env::temp_dir()
+            .join(format!("arrow-client-lock-test-{}", unsafe { ::libc::getpid() }));
+
+        let config_file = config_file.to_str()
+            .unwrap();
+
+        let first  = InstanceLock::new(config_file);
+        let second = InstanceLock::new(config_file);
+
+        assert!(first.acquire().is_ok());
+        assert!(first.is_held());
+
+        // flock locks of separate open files conflict even within a single
+        // process
+        assert!(second.acquire().is_err());
+        assert!(second.acquire_timeout(200).is_err());
+
+        first.release();
+
+        assert!(!first.is_held());
+        assert!(second.acquire().is_ok());
+
+        second.release();
+
+        fs::remove_file(second.path())
+            .unwrap();
+    }
+}
@@ -13,17 +13,24 @@
 // limitations under the License.
 
 //! file logger definitions.
+//!
+//! The log file is rotated once it reaches a given size limit or (optionally)
+//! once it gets older than a given age limit. Rotated files can be compressed
+//! using the external `gzip` utility (e.g. the one from BusyBox); they are
+//! kept uncompressed if the utility is not available.
 
 use std::fs;
 use std::io;
 
 use std::io::Write;
 use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
 use std::fs::{File, OpenOptions};
 
 use time;
 
+use utils::clock;
 use utils::logger::{Logger, Severity};
 
 /// Internal logger implementation.
@@ -34,6 +41,9 @@ struct InternalFileLogger {
     written:   usize,
     limit:     usize,
     rotations: usize,
+    max_age:   Option<i64>,
+    opened:    i64,
+    compress:  bool,
 }
 
 impl InternalFileLogger {
@@ -44,7 +54,7 @@ impl InternalFileLogger {
 
     /// Write given data into the underlaying file and rotate as necessary.
     fn write(&mut self, data: &[u8]) -> io::Result<()> {
-        if (self.written + data.len()) > self.limit {
+        if (self.written + data.len()) > self.limit || self.expired() {
             try!(self.rotate());
         }
 
@@ -55,11 +65,29 @@ impl InternalFileLogger {
         self.file.flush()
     }
 
+    /// Check if the current file is older than the age limit (if any).
+    fn expired(&self) -> bool {
+        match self.max_age {
+            Some(max_age) => self.written > 0
+                && (clock::timestamp() - self.opened) >= max_age,
+            None => false
+        }
+    }
+
+    /// Get path of a given backup file.
+    fn backup_path(&self, n: usize) -> String {
+        if self.compress {
+            format!("{}.{}.gz", &self.path, n)
+        } else {
+            format!("{}.{}", &self.path, n)
+        }
+    }
+
     /// Rotate the log files.
     fn rotate(&mut self) -> io::Result<()> {
-        for i in 0..self.rotations - 1 {
-            let from = format!("{}.{}", &self.path, self.rotations - i - 1);
-            let to   = format!("{}.{}", &self.path, self.rotations - i);
+        for i in (1..self.rotations).rev() {
+            let from = self.backup_path(i);
+            let to   = self.backup_path(i + 1);
 
             if Path::new(&from).exists() {
                 try!(fs::rename(&from, &to));
@@ -67,20 +95,41 @@ impl InternalFileLogger {
         }
 
         if self.rotations > 0 {
-            try!(fs::rename(&self.path, &format!("{}.1", &self.path)));
+            let backup = format!("{}.1", &self.path);
+
+            try!(fs::rename(&self.path, &backup));
+
+            if self.compress {
+                compress(&backup);
+            }
         }
 
         self.file = try!(File::create(&self.path));
 
         self.written = 0;
+        self.opened  = clock::timestamp();
 
         Ok(())
     }
 }
 
+/// Compress a given file using gzip (the original file is replaced with
+/// the compressed one). The file is left as it is if gzip is not available.
+fn compress(path: &str) -> bool {
+    Command::new("gzip")
+        .arg("-f")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
 impl Logger for InternalFileLogger {
     fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
-        let t = time::strftime("%F %T", &time::now())
+        let t = time::strftime("%F %T", &time::at(clock::now()))
             .unwrap();
 
         let severity = match s {
@@ -131,7 +180,10 @@ pub fn new(path: &str, limit: usize, rotations: usize) -> io::Result<FileLogger>
         file:      try!(file),
         written:   written as usize,
         limit:     limit,
-        rotations: rotations
+        rotations: rotations,
+        max_age:   None,
+        opened:    clock::timestamp(),
+        compress:  false
     };
 
     let logger = FileLogger {
@@ -141,6 +193,25 @@ pub fn new(path: &str, limit: usize, rotations: usize) -> io::Result<FileLogger>
     Ok(logger)
 }
 
+impl FileLogger {
+    /// Rotate the log file also once it gets older than a given number of
+    /// seconds (None means no age limit). The age is measured from the
+    /// moment the file was opened or rotated.
+    pub fn set_max_age(&mut self, max_age: Option<u64>) {
+        self.shared.lock()
+            .unwrap()
+            .max_age = max_age.map(|age| age as i64);
+    }
+
+    /// Compress rotated files using gzip. Backup files are named
+    /// `<path>.<n>.gz` in that case.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.shared.lock()
+            .unwrap()
+            .compress = compress;
+    }
+}
+
 impl Logger for FileLogger {
     fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
         self.shared.lock()
@@ -238,4 +309,40 @@ mod test {
 
         remove_files();
     }
+
+    fn remove_age_files() {
+        remove_file("agelog");
+        remove_file("agelog.1");
+        remove_file("agelog.2");
+        remove_file("agelog.1.gz");
+        remove_file("agelog.2.gz");
+    }
+
+    #[test]
+    fn test_file_logger_age_limit() {
+        remove_age_files();
+
+        let mut logger = new("agelog", 10000, 2)
+            .unwrap();
+
+        logger.set_max_age(Some(0));
+
+        log_info!(logger, "foo");
+
+        assert!(!file_exists("agelog.1"));
+
+        log_info!(logger, "foo");
+
+        assert!(file_exists("agelog.1"));
+        assert!(!file_exists("agelog.2"));
+
+        logger.set_compression(true);
+
+        log_info!(logger, "foo");
+
+        // the backup is kept uncompressed if gzip is not available
+        assert!(file_exists("agelog.1.gz") || file_exists("agelog.1"));
+
+        remove_age_files();
+    }
 }
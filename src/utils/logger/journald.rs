@@ -0,0 +1,198 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! systemd-journald logger definitions.
+//!
+//! Records are sent into the native journal socket, so that session and
+//! service IDs and the connection state are stored as separate journal
+//! fields (`SESSION_ID`, `SERVICE_ID` and `ARROW_STATE`) and can be used for
+//! filtering (e.g. `journalctl SESSION_ID=0000002a`). Records which cannot
+//! be sent are written into stderr.
+
+use std;
+
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+
+use utils::logger;
+use utils::logger::{Logger, Severity};
+
+/// Native journal socket.
+const JOURNAL_SOCKET: &'static str = "/run/systemd/journal/socket";
+
+/// Syslog identifier of all records.
+const SYSLOG_IDENTIFIER: &'static str = "arrow-client";
+
+/// journald logger structure.
+pub struct JournaldLogger {
+    level:  Severity,
+    socket: Option<UnixDatagram>,
+}
+
+/// Create a new journald logger with log level set to INFO.
+pub fn new() -> JournaldLogger {
+    JournaldLogger {
+        level:  Severity::INFO,
+        socket: UnixDatagram::unbound().ok()
+    }
+}
+
+impl Clone for JournaldLogger {
+    fn clone(&self) -> JournaldLogger {
+        JournaldLogger {
+            level:  self.level,
+            socket: UnixDatagram::unbound().ok()
+        }
+    }
+}
+
+impl JournaldLogger {
+    /// Send a given record into the journal.
+    fn send(&self, record: &[u8]) -> bool {
+        match self.socket {
+            Some(ref socket) => socket.send_to(record, JOURNAL_SOCKET).is_ok(),
+            None => false
+        }
+    }
+}
+
+impl Logger for JournaldLogger {
+    fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
+        self.log_fields(file, line, s, &[], msg)
+    }
+
+    fn log_fields(
+        &mut self,
+        file: &str,
+        line: u32,
+        s: Severity,
+        fields: &[(&str, &str)],
+        msg: &str) {
+        if s < self.level {
+            return;
+        }
+
+        let record = encode_record(file, line, s, fields, msg);
+
+        if !self.send(&record) {
+            let stderr = std::io::stderr();
+            let mut stderr = stderr.lock();
+
+            writeln!(&mut stderr, "[{}:{}] {}", file, line, msg)
+                .unwrap_or(());
+        }
+    }
+
+    fn set_level(&mut self, s: Severity) {
+        self.level = s;
+    }
+
+    fn get_level(&self) -> Severity {
+        self.level
+    }
+}
+
+/// Encode a journal record. Session and service IDs are taken from the
+/// message unless they are given explicitly.
+fn encode_record(
+    file: &str,
+    line: u32,
+    s: Severity,
+    fields: &[(&str, &str)],
+    msg: &str) -> Vec<u8> {
+    let priority = match s {
+        Severity::DEBUG => "7",
+        Severity::INFO  => "6",
+        Severity::WARN  => "4",
+        Severity::ERROR => "3"
+    };
+
+    let mut res = Vec::new();
+
+    push_field(&mut res, "MESSAGE", msg);
+    push_field(&mut res, "PRIORITY", priority);
+    push_field(&mut res, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    push_field(&mut res, "CODE_FILE", file);
+    push_field(&mut res, "CODE_LINE", &line.to_string());
+
+    let implicit = [
+        ("SESSION_ID", logger::message_id(msg, "session ID", 8)),
+        ("SERVICE_ID", logger::message_id(msg, "service ID", 4)),
+    ];
+
+    for &(name, value) in &implicit {
+        if let Some(value) = value {
+            if !fields.iter().any(|&(n, _)| n == name) {
+                push_field(&mut res, name, value);
+            }
+        }
+    }
+
+    for &(name, value) in fields {
+        push_field(&mut res, name, value);
+    }
+
+    res
+}
+
+/// Append a given field into a given journal record. Values containing new
+/// lines are encoded using the binary (length-prefixed) format.
+fn push_field(record: &mut Vec<u8>, name: &str, value: &str) {
+    record.extend_from_slice(name.as_bytes());
+
+    if value.contains('\n') {
+        let len = value.len() as u64;
+
+        record.push(b'\n');
+
+        for i in 0..8 {
+            record.push((len >> (8 * i)) as u8);
+        }
+    } else {
+        record.push(b'=');
+    }
+
+    record.extend_from_slice(value.as_bytes());
+    record.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_record, push_field};
+
+    use utils::logger::Severity;
+
+    #[test]
+    fn test_record_encoding() {
+        let mut field = Vec::new();
+
+        push_field(&mut field, "MESSAGE", "a\nb");
+
+        assert_eq!(field, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec());
+
+        let record = encode_record("src/main.rs", 10, Severity::WARN,
+            &[("ARROW_STATE", "error")],
+            "session closed (service ID: 0001, session ID: 0000002a)");
+
+        let record = String::from_utf8(record)
+            .unwrap();
+
+        assert!(record.starts_with("MESSAGE=session closed"));
+        assert!(record.contains("\nPRIORITY=4\n"));
+        assert!(record.contains("\nCODE_LINE=10\n"));
+        assert!(record.contains("\nSESSION_ID=0000002a\n"));
+        assert!(record.contains("\nSERVICE_ID=0001\n"));
+        assert!(record.ends_with("\nARROW_STATE=error\n"));
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2016 click2stream, Inc.
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     http://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JSON logger definitions.
+//!
+//! The logger writes one JSON object per line into stdout (which is what
+//! container log collectors usually expect). Apart from the message itself,
+//! every record contains the module which emitted the message and the
+//! session ID (if the message refers to a session), so the records can be
+//! filtered without parsing the messages.
+
+use std;
+use std::io::{Write, Stdout};
+
+use time;
+
+use rustc_serialize::json;
+
+use utils::logger;
+use utils::logger::{Logger, Severity};
+
+/// Single log record.
+#[derive(RustcEncodable)]
+struct LogRecord<'a> {
+    timestamp:  String,
+    level:      &'static str,
+    session_id: Option<&'a str>,
+    module:     String,
+    file:       &'a str,
+    line:       u32,
+    msg:        &'a str,
+}
+
+/// JSON logger structure.
+pub struct JsonLogger {
+    level:  Severity,
+    stdout: Stdout,
+}
+
+/// Create a new JSON logger with log level set to INFO.
+pub fn new() -> JsonLogger {
+    JsonLogger {
+        level:  Severity::INFO,
+        stdout: std::io::stdout()
+    }
+}
+
+impl Clone for JsonLogger {
+    fn clone(&self) -> JsonLogger {
+        JsonLogger {
+            level:  self.level,
+            stdout: std::io::stdout()
+        }
+    }
+}
+
+impl Logger for JsonLogger {
+    fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
+        if s < self.level {
+            return;
+        }
+
+        let t = time::strftime("%FT%T%z", &time::now())
+            .unwrap();
+
+        let level = match s {
+            Severity::DEBUG => "debug",
+            Severity::INFO  => "info",
+            Severity::WARN  => "warning",
+            Severity::ERROR => "error"
+        };
+
+        let record = LogRecord {
+            timestamp:  t,
+            level:      level,
+            session_id: session_id(msg),
+            module:     module_name(file),
+            file:       file,
+            line:       line,
+            msg:        msg
+        };
+
+        let record = json::encode(&record)
+            .unwrap();
+
+        let mut stdout = self.stdout.lock();
+
+        writeln!(&mut stdout, "{}", record)
+            .unwrap();
+
+        stdout.flush()
+            .unwrap();
+    }
+
+    fn set_level(&mut self, s: Severity) {
+        self.level = s;
+    }
+
+    fn get_level(&self) -> Severity {
+        self.level
+    }
+}
+
+unsafe impl Send for JsonLogger { }
+
+/// Get name of the module defined in a given source file (e.g.
+/// `net::arrow::session` for `src/net/arrow/session.rs`).
+fn module_name(file: &str) -> String {
+    file.trim_left_matches("src/")
+        .trim_right_matches(".rs")
+        .trim_right_matches("/mod")
+        .replace("/", "::")
+}
+
+/// Get session ID referred to by a given log message (all session related
+/// messages contain `session ID: xxxxxxxx`).
+fn session_id(msg: &str) -> Option<&str> {
+    logger::message_id(msg, "session ID", 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{module_name, session_id};
+
+    #[test]
+    fn test_record_fields() {
+        assert_eq!(module_name("src/net/arrow/session.rs"), "net::arrow::session");
+        assert_eq!(module_name("src/net/arrow/mod.rs"), "net::arrow");
+        assert_eq!(module_name("src/main.rs"), "main");
+
+        assert_eq!(session_id("session closed (session ID: 0000abcd)"),
+            Some("0000abcd"));
+        assert_eq!(session_id("session ID: 12"), None);
+        assert_eq!(session_id("session ID: 0000abcx"), None);
+        assert_eq!(session_id("connection lost"), None);
+    }
+}
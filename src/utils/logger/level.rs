@@ -0,0 +1,210 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime log level control.
+//!
+//! Clones of ordinary loggers have their own log level. The controlled
+//! logger filters messages using a level shared by all its clones, so that
+//! the level can be changed at runtime for the whole process (e.g. raised
+//! to DEBUG by Arrow Service). A temporary level is reverted to the
+//! configured one automatically once it expires.
+
+use std::sync::{Arc, Mutex};
+
+use time;
+
+use utils::logger::{Logger, Severity};
+
+/// Shared log level.
+#[derive(Debug)]
+struct LevelState {
+    /// Configured log level.
+    level:     Severity,
+    /// Temporary log level and its deadline (monotonic time in ns).
+    temporary: Option<(Severity, u64)>,
+}
+
+impl LevelState {
+    /// Get the current log level (and drop the temporary level if it has
+    /// expired).
+    fn current(&mut self) -> Severity {
+        if let Some((level, deadline)) = self.temporary {
+            if time::precise_time_ns() < deadline {
+                return level;
+            }
+
+            self.temporary = None;
+        }
+
+        self.level
+    }
+}
+
+/// Handle for changing log level of a controlled logger (and all its clones).
+#[derive(Debug, Clone)]
+pub struct LevelController {
+    state: Arc<Mutex<LevelState>>,
+}
+
+impl LevelController {
+    /// Use a given log level for a given number of seconds.
+    pub fn set_temporary(&self, level: Severity, duration: u64) {
+        let deadline = time::precise_time_ns() + duration * 1000000000;
+
+        self.state.lock()
+            .unwrap()
+            .temporary = Some((level, deadline));
+    }
+
+    /// Revert to the configured log level.
+    pub fn restore(&self) {
+        self.state.lock()
+            .unwrap()
+            .temporary = None;
+    }
+
+    /// Get the current log level.
+    pub fn current(&self) -> Severity {
+        self.state.lock()
+            .unwrap()
+            .current()
+    }
+}
+
+/// Logger wrapper using a log level shared by all its clones.
+#[derive(Clone)]
+pub struct ControlledLogger<L> {
+    logger: L,
+    state:  Arc<Mutex<LevelState>>,
+}
+
+/// Create a new controlled logger. The configured level is taken from a
+/// given logger, filtering is done by the wrapper since then.
+pub fn new<L: Logger>(mut logger: L) -> ControlledLogger<L> {
+    let state = LevelState {
+        level:     logger.get_level(),
+        temporary: None
+    };
+
+    logger.set_level(Severity::DEBUG);
+
+    ControlledLogger {
+        logger: logger,
+        state:  Arc::new(Mutex::new(state))
+    }
+}
+
+impl<L: Logger> ControlledLogger<L> {
+    /// Get level controller of this logger.
+    pub fn controller(&self) -> LevelController {
+        LevelController {
+            state: self.state.clone()
+        }
+    }
+
+    /// Check if messages with a given severity pass the current level.
+    fn enabled(&self, s: Severity) -> bool {
+        s >= self.state.lock()
+            .unwrap()
+            .current()
+    }
+}
+
+impl<L: Logger> Logger for ControlledLogger<L> {
+    fn log(&mut self, file: &str, line: u32, s: Severity, msg: &str) {
+        if self.enabled(s) {
+            self.logger.log(file, line, s, msg)
+        }
+    }
+
+    fn log_fields(
+        &mut self,
+        file: &str,
+        line: u32,
+        s: Severity,
+        fields: &[(&str, &str)],
+        msg: &str) {
+        if self.enabled(s) {
+            self.logger.log_fields(file, line, s, fields, msg)
+        }
+    }
+
+    fn set_level(&mut self, s: Severity) {
+        self.state.lock()
+            .unwrap()
+            .level = s;
+    }
+
+    fn get_level(&self) -> Severity {
+        self.state.lock()
+            .unwrap()
+            .current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::logger::{Logger, Severity};
+
+    #[derive(Clone)]
+    struct TestLogger {
+        level:    Severity,
+        messages: usize,
+    }
+
+    impl Logger for TestLogger {
+        fn log(&mut self, _: &str, _: u32, s: Severity, _: &str) {
+            if s >= self.level {
+                self.messages += 1;
+            }
+        }
+
+        fn set_level(&mut self, s: Severity) { self.level = s; }
+        fn get_level(&self) -> Severity { self.level }
+    }
+
+    #[test]
+    fn test_controlled_logger() {
+        let inner = TestLogger { level: Severity::INFO, messages: 0 };
+
+        let mut logger     = new(inner);
+        let mut clone      = logger.clone();
+        let     controller = logger.controller();
+
+        log_debug!(logger, "msg");
+        assert_eq!(logger.logger.messages, 0);
+
+        controller.set_temporary(Severity::DEBUG, 60);
+
+        log_debug!(logger, "msg");
+        log_debug!(clone, "msg");
+        assert_eq!(logger.logger.messages, 1);
+        assert_eq!(clone.logger.messages, 1);
+
+        // the configured level is changed for all clones as well
+        clone.set_level(Severity::WARN);
+        controller.restore();
+
+        log_info!(logger, "msg");
+        assert_eq!(logger.logger.messages, 1);
+        assert_eq!(logger.get_level(), Severity::WARN);
+
+        // expired levels are reverted
+        controller.set_temporary(Severity::DEBUG, 0);
+
+        assert_eq!(controller.current(), Severity::WARN);
+    }
+}
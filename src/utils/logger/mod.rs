@@ -20,6 +20,12 @@ macro_rules! log {
     };
 }
 
+macro_rules! log_fields {
+    ($logger:expr, $severity:expr, $fields:expr, $( $arg:tt )*) => {
+        $logger.log_fields(file!(), line!(), $severity, $fields, &format!($($arg)*))
+    };
+}
+
 macro_rules! log_debug {
     ($logger:expr, $( $arg:tt )*) => {
         $logger.debug(file!(), line!(), &format!($($arg)*))
@@ -46,6 +52,9 @@ macro_rules! log_error {
 
 pub mod syslog;
 pub mod stderr;
+pub mod json;
+pub mod journald;
+pub mod level;
 pub mod file;
 
 /// Log message severity.
@@ -75,6 +84,19 @@ pub trait Logger {
     /// Get minimum log level.
     fn get_level(&self) -> Severity;
 
+    /// Log a given message with a given severity and given structured
+    /// fields (name-value pairs, e.g. `ARROW_STATE`). Loggers without
+    /// support for structured fields log only the message.
+    fn log_fields(
+        &mut self,
+        file: &str,
+        line: u32,
+        s: Severity,
+        _fields: &[(&str, &str)],
+        msg: &str) {
+        self.log(file, line, s, msg)
+    }
+
     /// Log a given debug message.
     fn debug(&mut self, file: &str, line: u32, msg: &str) {
         self.log(file, line, DEBUG, msg)
@@ -97,12 +119,12 @@ pub trait Logger {
 }
 
 /// Helper trait for implementing Clone to the LoggerWrapper.
-pub trait CloneableLogger : Logger {
+pub trait CloneableLogger : Logger + Send {
     /// Clone as trait object.
     fn clone(&self) -> Box<CloneableLogger>;
 }
 
-impl<T> CloneableLogger for T where T: 'static + Logger + Clone {
+impl<T> CloneableLogger for T where T: 'static + Logger + Clone + Send {
     fn clone(&self) -> Box<CloneableLogger> {
         Box::new(<T as Clone>::clone(self))
     }
@@ -138,6 +160,16 @@ impl Logger for LoggerWrapper {
         self.logger.log(file, line, s, msg)
     }
 
+    fn log_fields(
+        &mut self,
+        file: &str,
+        line: u32,
+        s: Severity,
+        fields: &[(&str, &str)],
+        msg: &str) {
+        self.logger.log_fields(file, line, s, fields, msg)
+    }
+
     fn set_level(&mut self, s: Severity) {
         self.logger.set_level(s);
     }
@@ -147,7 +179,28 @@ impl Logger for LoggerWrapper {
     }
 }
 
-unsafe impl Send for LoggerWrapper { }
+/// Get a hexadecimal ID with a given number of digits referred to by a given
+/// log message as `name: id` (e.g. `session ID: 0000002a`).
+pub fn message_id<'a>(msg: &'a str, name: &str, digits: usize) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+
+    let rest = match msg.find(&prefix) {
+        Some(pos) => &msg[pos + prefix.len()..],
+        None      => return None
+    };
+
+    if rest.len() < digits || !rest.is_char_boundary(digits) {
+        return None;
+    }
+
+    let id = &rest[..digits];
+
+    if id.chars().all(|c| c.is_digit(16)) {
+        Some(id)
+    } else {
+        None
+    }
+}
 
 /// This logger does nothing but holds the severity level.
 #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,237 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration file migration.
+//!
+//! Configuration files written by older client versions might be missing
+//! some fields or they might contain services which cannot be loaded anymore.
+//! Such files would be replaced by a new configuration (i.e. the client would
+//! lose its registration). The migration upgrades a given file to the current
+//! schema while preserving the client UUID and passphrase.
+
+use std::collections::BTreeMap;
+
+use utils::config::{ArrowConfig, ConfigError, Result, CONFIG_SCHEMA_VERSION};
+
+use net::arrow::protocol::ServiceTable;
+
+use uuid::Uuid;
+
+use rustc_serialize::json;
+
+use rustc_serialize::json::Json;
+
+use time;
+
+/// Result of a configuration migration.
+pub struct ConfigMigration {
+    /// Migrated configuration.
+    pub config:  ArrowConfig,
+    /// Human readable descriptions of all changes made.
+    pub changes: Vec<String>,
+}
+
+/// Migrate a given configuration (JSON string) to the current schema.
+pub fn migrate_config(content: &str) -> Result<ConfigMigration> {
+    let mut root = try!(Json::from_str(content)
+        .map_err(|err| ConfigError::from(format!("{}", err))));
+
+    let mut changes = Vec::new();
+
+    {
+        let root = try!(root.as_object_mut()
+            .ok_or(ConfigError::from("JSON object expected")));
+
+        try!(check_uuid(root, "uuid", "client UUID"));
+        try!(check_uuid(root, "passwd", "client passphrase"));
+
+        let version_ok = root.get("version")
+            .map_or(false, |version| version.is_u64());
+
+        if !version_ok {
+            root.insert("version".to_string(), Json::U64(0));
+            changes.push("missing or invalid configuration version set to 0".to_string());
+        }
+
+        let schema = root.get("schema")
+            .and_then(|schema| schema.as_u64());
+
+        if schema != Some(CONFIG_SCHEMA_VERSION) {
+            root.insert("schema".to_string(), Json::U64(CONFIG_SCHEMA_VERSION));
+            changes.push(format!("schema version set to {}", CONFIG_SCHEMA_VERSION));
+        }
+
+        let services = match root.remove("svc_table") {
+            Some(Json::Object(mut svc_table)) => {
+                match svc_table.remove("services") {
+                    Some(Json::Array(services)) => Some(services),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+
+        let services = match services {
+            Some(services) => migrate_services(services, &mut changes),
+            None => {
+                changes.push("missing service table created".to_string());
+                Vec::new()
+            }
+        };
+
+        let mut svc_table = BTreeMap::new();
+
+        svc_table.insert("services".to_string(), Json::Array(services));
+
+        root.insert("svc_table".to_string(), Json::Object(svc_table));
+    }
+
+    let content = try!(json::encode(&root));
+    let config  = try!(ArrowConfig::parse(&content));
+
+    let res = ConfigMigration {
+        config:  config,
+        changes: changes
+    };
+
+    Ok(res)
+}
+
+/// Check that a given field contains a valid UUID. The field cannot be
+/// fixed because it would break the client registration.
+fn check_uuid(
+    root: &BTreeMap<String, Json>,
+    field: &str,
+    name: &str) -> Result<()> {
+    let valid = root.get(field)
+        .and_then(|value| value.as_string())
+        .map_or(false, |value| Uuid::parse_str(value).is_ok());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::from(format!("missing or invalid {}, the configuration cannot be migrated without losing the client registration", name)))
+    }
+}
+
+/// Migrate a given list of services. Services which cannot be loaded are
+/// dropped.
+fn migrate_services(services: Vec<Json>, changes: &mut Vec<String>) -> Vec<Json> {
+    let mut res = Vec::new();
+
+    for (index, mut svc) in services.into_iter().enumerate() {
+        let mut added = Vec::new();
+
+        if let Some(svc) = svc.as_object_mut() {
+            if !svc.contains_key("static_svc") {
+                svc.insert("static_svc".to_string(), Json::Boolean(false));
+                added.push("static_svc");
+            }
+
+            if !svc.contains_key("last_seen") {
+                let now = time::now_utc()
+                    .to_timespec()
+                    .sec;
+
+                svc.insert("last_seen".to_string(), Json::I64(now));
+                added.push("last_seen");
+            }
+
+            if !svc.contains_key("active") {
+                svc.insert("active".to_string(), Json::Boolean(true));
+                added.push("active");
+            }
+        }
+
+        match check_service(&svc) {
+            Ok(_) => {
+                if !added.is_empty() {
+                    changes.push(format!("service #{}: missing fields added ({})",
+                        index, added.join(", ")));
+                }
+
+                res.push(svc);
+            },
+            Err(err) => changes.push(format!("service #{}: dropped ({})",
+                index, err))
+        }
+    }
+
+    res
+}
+
+/// Check if a given service can be loaded.
+fn check_service(svc: &Json) -> Result<()> {
+    let mut svc_table = BTreeMap::new();
+
+    svc_table.insert("services".to_string(), Json::Array(vec![svc.clone()]));
+
+    let content = try!(json::encode(&Json::Object(svc_table)));
+
+    try!(json::decode::<ServiceTable>(&content));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_migration() {
+        let content = r#"{
+            "uuid": "0b6a5d1c-3f3a-4ab5-9a4f-4c5e1e5bcb3a",
+            "passwd": "7d444840-9dc0-11d1-b245-5ffdce74fad2",
+            "svc_table": {
+                "services": [
+                    {
+                        "svc_type": 1,
+                        "mac": "00:11:22:33:44:55",
+                        "address": "10.0.0.1:554",
+                        "path": "/stream"
+                    },
+                    {
+                        "svc_type": 1,
+                        "mac": "invalid",
+                        "address": "10.0.0.2:554",
+                        "path": "/stream"
+                    }
+                ]
+            }
+        }"#;
+
+        let migration = migrate_config(content)
+            .unwrap();
+
+        assert_eq!(migration.config.uuid_string(),
+            "0b6a5d1c-3f3a-4ab5-9a4f-4c5e1e5bcb3a");
+        assert_eq!(migration.config.version(), 0);
+        assert_eq!(migration.config.active_services().len(), 2);
+        assert_eq!(migration.changes.len(), 4);
+
+        // migrating the result again does not change anything
+        let content   = format!("{}", migration.config);
+        let migration = migrate_config(&content)
+            .unwrap();
+
+        assert!(migration.changes.is_empty());
+    }
+
+    #[test]
+    fn test_missing_uuid() {
+        let content = r#"{"passwd": "7d444840-9dc0-11d1-b245-5ffdce74fad2"}"#;
+
+        assert!(migrate_config(content).is_err());
+    }
+}
@@ -17,16 +17,34 @@
 #[macro_use]
 pub mod logger;
 
+pub mod build_info;
+pub mod clock;
 pub mod config;
+pub mod config_watch;
+pub mod endian;
+pub mod env_config;
+pub mod events;
+pub mod hooks;
+pub mod identity;
+pub mod instance_lock;
+pub mod migration;
+pub mod reload;
+pub mod secret;
+pub mod stats_export;
+pub mod status_led;
+pub mod x509;
 
 use std::io;
+use std::fs;
 use std::ptr;
 use std::mem;
 use std::fmt;
 use std::slice;
 use std::process;
 
+use std::fs::File;
 use std::ffi::CStr;
+use std::path::Path;
 use std::error::Error;
 use std::ops::Deref;
 use std::io::Write;
@@ -66,6 +84,11 @@ impl<'a> From<&'a str> for RuntimeError {
 }
 
 /// Arc<Mutex<T>> shorthand.
+///
+/// The handle is Send and Sync if the shared object is Send, so it can be
+/// passed to (and used from) threads of an embedding application. Every
+/// client instance owns its own shared objects, there is no hidden global
+/// state behind them.
 #[derive(Clone)]
 pub struct Shared<T> {
     object: Arc<Mutex<T>>,
@@ -88,8 +111,6 @@ impl<T> Deref for Shared<T> {
     }
 }
 
-unsafe impl<T> Send for Shared<T> { }
-
 /// Common trait for serializable objects.
 pub trait Serialize {
     /// Serialize this object using a given writer.
@@ -98,61 +119,67 @@ pub trait Serialize {
 
 impl Serialize for u8 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&[*self])
     }
 }
 
 impl Serialize for i8 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&[*self as u8])
     }
 }
 
 impl Serialize for u16 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u16_to_bytes(*self))
     }
 }
 
 impl Serialize for i16 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u16_to_bytes(*self as u16))
     }
 }
 
 impl Serialize for u32 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u32_to_bytes(*self))
     }
 }
 
 impl Serialize for i32 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u32_to_bytes(*self as u32))
     }
 }
 
 impl Serialize for u64 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u64_to_bytes(*self))
     }
 }
 
 impl Serialize for i64 {
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        w.write_all(&endian::u64_to_bytes(*self as u64))
     }
 }
 
 impl Serialize for usize {
+    /// Serialize the value using the native size of usize.
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        if mem::size_of::<usize>() == 8 {
+            (*self as u64).serialize(w)
+        } else {
+            (*self as u32).serialize(w)
+        }
     }
 }
 
 impl Serialize for isize {
+    /// Serialize the value using the native size of isize.
     fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        w.write_all(as_bytes(&self.to_be()))
+        (*self as usize).serialize(w)
     }
 }
 
@@ -250,10 +277,85 @@ pub fn result_or_log<L, T, E, M>(
     }
 }
 
+/// Get path of the backup of a given file.
+pub fn backup_file(file: &str) -> String {
+    format!("{}.bak", file)
+}
+
+/// Replace content of a given file atomically.
+///
+/// The content is written into a temporary file which is synced and renamed
+/// over the original file afterwards, so the file contains either its
+/// previous or its new content even if the process is interrupted.
+pub fn write_file_atomic(file: &str, content: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", file);
+
+    {
+        let mut tmp_file = try!(File::create(&tmp));
+
+        try!(tmp_file.write_all(content));
+        try!(tmp_file.sync_all());
+    }
+
+    try!(fs::rename(&tmp, file));
+
+    sync_parent_dir(file);
+
+    Ok(())
+}
+
+/// Replace content of a given file atomically keeping its previous version
+/// as a backup (see `backup_file()`).
+pub fn write_file_with_backup(file: &str, content: &[u8]) -> io::Result<()> {
+    if Path::new(file).exists() {
+        let backup = backup_file(file);
+
+        fs::remove_file(&backup)
+            .ok();
+
+        // hard links are not supported by all file systems
+        if fs::hard_link(file, &backup).is_err() {
+            try!(fs::copy(file, &backup));
+        }
+    }
+
+    write_file_atomic(file, content)
+}
+
+/// Load a given file using a given function. The backup of the file is used
+/// if the file cannot be loaded. The second item of the result indicates
+/// whether the backup was used.
+pub fn load_with_backup<T, E, F>(file: &str, load: F) -> Result<(T, bool), E>
+    where F: Fn(&str) -> Result<T, E> {
+    match load(file) {
+        Ok(res)  => Ok((res, false)),
+        Err(err) => load(&backup_file(file))
+            .map(|res| (res, true))
+            .or(Err(err))
+    }
+}
+
+/// Sync the directory containing a given file in order to persist renames.
+/// Errors are ignored as this is not supported on all platforms.
+fn sync_parent_dir(file: &str) {
+    let dir = match Path::new(file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .ok();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use std::env;
     use std::ffi::CString;
+    use std::io::Read;
+    use std::fs::File;
     use utils::logger::*;
     
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -282,6 +384,24 @@ mod tests {
         }
     }
     
+    fn assert_send_sync<T: Send + Sync>() { }
+
+    #[test]
+    fn test_shared_handles() {
+        assert_send_sync::<Shared<config::AppContext>>();
+        assert_send_sync::<Shared<Vec<u8>>>();
+
+        let first  = Shared::new(config::AppContext::new(config::ArrowConfig::new()));
+        let second = Shared::new(config::AppContext::new(config::ArrowConfig::new()));
+
+        first.lock()
+            .unwrap()
+            .scanning = true;
+
+        // instances do not share any state
+        assert!(!second.lock().unwrap().scanning);
+    }
+
     #[test]
     fn test_result_or_error() {
         assert_eq!(1, result_or_error::<i32, RuntimeError, &'static str>(
@@ -301,4 +421,39 @@ mod tests {
             &mut logger, Severity::WARN, "", 
             Err(RuntimeError::from("foo"))));
     }
+    
+    #[test]
+    fn test_write_file_with_backup() {
+        let file = env::temp_dir()
+            .join("arrow-client-test-backup");
+        let file = file.to_str()
+            .unwrap();
+        
+        let read = |file: &str| {
+            let mut content = String::new();
+            File::open(file)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .map(|_| content)
+        };
+        
+        write_file_with_backup(file, b"foo").unwrap();
+        write_file_with_backup(file, b"bar").unwrap();
+        
+        assert_eq!("bar", read(file).unwrap());
+        assert_eq!("foo", read(&backup_file(file)).unwrap());
+        
+        let (content, from_backup) = load_with_backup(file, |file| {
+            read(file).and_then(|content| if content == "foo" {
+                Ok(content)
+            } else {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted"))
+            })
+        }).unwrap();
+        
+        assert_eq!("foo", content);
+        assert!(from_backup);
+        
+        fs::remove_file(file).unwrap();
+        fs::remove_file(backup_file(file)).unwrap();
+    }
 }
@@ -0,0 +1,160 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration reload requests.
+//!
+//! SIGHUP asks the client to reload its configuration file and TLS files
+//! (CA certificates and the client certificate). The signal handler only
+//! sets a process-wide flag, the request itself is processed periodically
+//! by the command handler. Changes of the TLS files are detected using a
+//! digest of their content.
+
+use std::io;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use utils::config;
+
+use libc;
+
+/// A reload has been requested.
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// SIGHUP handler.
+extern "C" fn sighup_handler(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Request a configuration reload on SIGHUP (instead of terminating the
+/// process).
+pub fn install_sighup_handler() -> io::Result<()> {
+    let handler = sighup_handler as extern "C" fn(libc::c_int);
+
+    let ret = unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t)
+    };
+
+    if ret == libc::SIG_ERR {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Request a configuration reload.
+pub fn request() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Check if a reload has been requested and clear the request.
+pub fn take_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Get digest of given TLS files. Directories are processed recursively,
+/// missing and unreadable files are included as well, so that their
+/// (re)appearance is detected.
+pub fn tls_digest(paths: &[String]) -> [u8; 32] {
+    let mut content = Vec::new();
+
+    for path in paths {
+        append_content(&mut content, Path::new(path));
+    }
+
+    config::content_digest(&content)
+}
+
+/// Append path and content of a given file (or of all files within a given
+/// directory) into a given buffer.
+fn append_content(buffer: &mut Vec<u8>, path: &Path) {
+    buffer.extend_from_slice(path.to_string_lossy().as_bytes());
+    buffer.push(0);
+
+    if path.is_dir() {
+        let mut entries = path.read_dir()
+            .map(|dir| {
+                dir.filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or(Vec::new());
+
+        entries.sort();
+
+        for entry in entries {
+            append_content(buffer, &entry);
+        }
+    } else if let Ok(mut file) = File::open(path) {
+        file.read_to_end(buffer)
+            .unwrap_or(0);
+    }
+
+    buffer.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn write_file(path: &Path, content: &[u8]) {
+        File::create(path)
+            .and_then(|mut file| file.write_all(content))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reload_request() {
+        request();
+
+        assert!(take_request());
+        assert!(!take_request());
+    }
+
+    #[test]
+    fn test_tls_digest() {
+        let dir = env::temp_dir()
+            .join(format!("arrow-client-tls-digest-{}", unsafe { ::libc::getpid() }));
+
+        fs::create_dir_all(&dir)
+            .unwrap();
+
+        let paths = vec![dir.to_string_lossy().into_owned()];
+
+        let empty = tls_digest(&paths);
+
+        write_file(&dir.join("ca.pem"), b"foo");
+
+        let first = tls_digest(&paths);
+
+        assert!(empty != first);
+        assert!(first == tls_digest(&paths));
+
+        write_file(&dir.join("ca.pem"), b"bar");
+
+        assert!(first != tls_digest(&paths));
+
+        fs::remove_dir_all(&dir)
+            .unwrap();
+    }
+}
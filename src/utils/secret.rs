@@ -0,0 +1,158 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Secret values (passwords, shared secrets, tokens and keys).
+//!
+//! A secret is overwritten with zeros when it is dropped, it is never
+//! printed by its Debug implementation and secrets are compared in constant
+//! time. Note that this is a best-effort protection only. Temporary copies
+//! (e.g. serialized messages or configuration files) are not covered.
+
+use std::ptr;
+
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use openssl::crypto::memcmp;
+
+/// Common trait for values which can be overwritten with zeros.
+pub trait Zeroize {
+    /// Overwrite the value with zeros.
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for [u8; 16] {
+    fn zeroize(&mut self) {
+        zero_bytes(self);
+    }
+}
+
+impl Zeroize for [u8; 32] {
+    fn zeroize(&mut self) {
+        zero_bytes(self);
+    }
+}
+
+impl Zeroize for Vec<u8> {
+    fn zeroize(&mut self) {
+        zero_bytes(self);
+        self.clear();
+    }
+}
+
+impl Zeroize for String {
+    fn zeroize(&mut self) {
+        // zeros are valid UTF-8, so the string stays valid
+        unsafe {
+            self.as_mut_vec()
+                .zeroize();
+        }
+    }
+}
+
+/// Overwrite a given buffer with zeros. Volatile writes are used, so that
+/// the compiler cannot optimize them out.
+fn zero_bytes(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe {
+            ptr::write_volatile(b, 0);
+        }
+    }
+}
+
+/// Compare two given byte slices in constant time (the time depends only on
+/// their lengths).
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+/// Secret value zeroed on drop.
+pub struct Secret<T: Zeroize> {
+    value: T,
+}
+
+impl<T: Zeroize> Secret<T> {
+    /// Create a new secret.
+    pub fn new(value: T) -> Secret<T> {
+        Secret {
+            value: value
+        }
+    }
+
+    /// Get the secret value. The value must not be logged.
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Secret<T> {
+        Secret::new(self.value.clone())
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+impl<T: Zeroize> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Secret<T>) -> bool {
+        constant_time_eq(self.value.as_ref(), other.value.as_ref())
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Eq for Secret<T> {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let a = Secret::new([1u8; 16]);
+        let b = Secret::new([1u8; 16]);
+        let c = Secret::new([2u8; 16]);
+
+        assert_eq!(a, b);
+        assert!(a != c);
+        assert_eq!(a.clone(), a);
+
+        assert_eq!(format!("{:?}", a), "Secret(<redacted>)");
+
+        let mut value = vec![1u8, 2, 3];
+
+        value.zeroize();
+
+        assert!(value.is_empty());
+
+        let mut value = [3u8; 16];
+
+        value.zeroize();
+
+        assert_eq!(value, [0u8; 16]);
+
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Periodical statistics export.
+//!
+//! Connection statistics and records of finished sessions are buffered and
+//! periodically written into a local CSV or JSON file. Every export creates a
+//! new file and the previous ones are rotated (`file.1`, `file.2`, ...), so
+//! that only a given number of the most recent exports is kept.
+
+use std::io;
+use std::fs;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::collections::VecDeque;
+
+use utils;
+use utils::clock;
+use utils::RuntimeError;
+
+use rustc_serialize::json;
+
+/// Maximum number of buffered session records (the oldest ones are dropped).
+const MAX_SESSION_RECORDS: usize = 10000;
+
+/// Default export period (in seconds).
+pub const EXPORT_PERIOD: u64 = 300;
+
+/// Default number of kept export files.
+pub const EXPORT_FILES: usize = 5;
+
+/// Export file format.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = RuntimeError;
+
+    fn from_str(s: &str) -> Result<ExportFormat, RuntimeError> {
+        match s {
+            "csv"  => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(RuntimeError::from(format!("unknown export format: {}", s)))
+        }
+    }
+}
+
+/// Record of a finished session.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct SessionRecord {
+    /// Time when the session was closed (UNIX timestamp).
+    pub timestamp:  i64,
+    /// Service ID.
+    pub service_id: u16,
+    /// Session ID.
+    pub session_id: u32,
+    /// Session lifetime in milliseconds (None if the service connection
+    /// has not been established).
+    pub lifetime:   Option<u64>,
+    /// Number of bytes received from the service.
+    pub bytes_in:   u64,
+    /// Number of bytes sent to the service.
+    pub bytes_out:  u64,
+    /// High-water mark of the session input buffer (in bytes).
+    pub input_hwm:  usize,
+    /// High-water mark of the session output buffer (in bytes).
+    pub output_hwm: usize,
+    /// Error (if any).
+    pub error:      Option<String>,
+}
+
+/// Snapshot of the connection statistics.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct ConnectionRecord {
+    /// Time of the snapshot (UNIX timestamp).
+    pub timestamp:    i64,
+    /// Number of confirmed Control Protocol messages.
+    pub ack_count:    u64,
+    /// Average ACK latency (in milliseconds).
+    pub ack_avg:      u64,
+    /// Maximum ACK latency (in milliseconds).
+    pub ack_max:      u64,
+    /// Number of rejected unsupported Control Protocol messages.
+    pub unsupported:  usize,
+    /// Number of dropped replayed Control Protocol messages.
+    pub replayed:     usize,
+    /// Number of received sequence-numbered data frames.
+    pub frames:       u64,
+    /// Number of lost data frames.
+    pub lost:         u64,
+    /// Number of reordered data frames.
+    pub reordered:    u64,
+    /// High-water mark of the Arrow output buffer since the last snapshot
+    /// (in bytes).
+    pub uplink_hwm:   usize,
+}
+
+/// JSON mapping of a single export.
+#[derive(RustcEncodable)]
+struct JsonExport<'a> {
+    connection: &'a ConnectionRecord,
+    sessions:   &'a VecDeque<SessionRecord>,
+}
+
+/// Statistics exporter.
+#[derive(Debug, Clone)]
+pub struct StatsExport {
+    /// Export file path.
+    path:      String,
+    /// Export file format.
+    format:    ExportFormat,
+    /// Number of kept export files.
+    files:     usize,
+    /// Export period (in seconds).
+    period:    u64,
+    /// Session records since the last export.
+    sessions:  VecDeque<SessionRecord>,
+}
+
+impl StatsExport {
+    /// Create a new exporter writing into a given file using a given format
+    /// every given number of seconds and keeping a given number of files.
+    pub fn new(
+        path: &str,
+        format: ExportFormat,
+        period: u64,
+        files: usize) -> StatsExport {
+        StatsExport {
+            path:     path.to_string(),
+            format:   format,
+            files:    files,
+            period:   period,
+            sessions: VecDeque::new()
+        }
+    }
+
+    /// Get the export period (in seconds).
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Record a finished session.
+    pub fn record_session(&mut self, record: SessionRecord) {
+        if self.sessions.len() >= MAX_SESSION_RECORDS {
+            self.sessions.pop_front();
+        }
+
+        self.sessions.push_back(record);
+    }
+
+    /// Write a given connection statistics snapshot together with all
+    /// buffered session records into a new export file.
+    pub fn export(&mut self, connection: &ConnectionRecord) -> io::Result<()> {
+        let content = match self.format {
+            ExportFormat::Csv  => self.to_csv(connection),
+            ExportFormat::Json => self.to_json(connection)
+        };
+
+        try!(self.rotate());
+
+        try!(utils::write_file_atomic(&self.path, content.as_bytes()));
+
+        self.sessions.clear();
+
+        Ok(())
+    }
+
+    /// Rotate the export files.
+    fn rotate(&self) -> io::Result<()> {
+        for i in 1..self.files {
+            let from = self.file_path(self.files - i - 1);
+            let to   = self.file_path(self.files - i);
+
+            if Path::new(&from).exists() {
+                try!(fs::rename(&from, &to));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get path of the export file with a given index (zero is the most
+    /// recent one).
+    fn file_path(&self, index: usize) -> String {
+        if index == 0 {
+            self.path.clone()
+        } else {
+            format!("{}.{}", self.path, index)
+        }
+    }
+
+    /// Create CSV output. The first row is the connection record, the
+    /// following rows are session records.
+    fn to_csv(&self, connection: &ConnectionRecord) -> String {
+        let mut res = String::new();
+
+        res.push_str("record,timestamp,service_id,session_id,lifetime,bytes_in,bytes_out,ack_count,ack_avg,ack_max,unsupported,replayed,frames,lost,reordered,input_hwm,output_hwm,uplink_hwm,error\n");
+        res.push_str(&format!("connection,{},,,,,,{},{},{},{},{},{},{},{},,,{},\n",
+            connection.timestamp,
+            connection.ack_count,
+            connection.ack_avg,
+            connection.ack_max,
+            connection.unsupported,
+            connection.replayed,
+            connection.frames,
+            connection.lost,
+            connection.reordered,
+            connection.uplink_hwm));
+
+        for session in &self.sessions {
+            let lifetime = session.lifetime
+                .map(|lifetime| lifetime.to_string())
+                .unwrap_or(String::new());
+            let error = session.error.as_ref()
+                .map(|err| csv_escape(err))
+                .unwrap_or(String::new());
+
+            res.push_str(&format!("session,{},{},{:08x},{},{},{},,,,,,,,,{},{},,{}\n",
+                session.timestamp,
+                session.service_id,
+                session.session_id,
+                lifetime,
+                session.bytes_in,
+                session.bytes_out,
+                session.input_hwm,
+                session.output_hwm,
+                error));
+        }
+
+        res
+    }
+
+    /// Create JSON output.
+    fn to_json(&self, connection: &ConnectionRecord) -> String {
+        let export = JsonExport {
+            connection: connection,
+            sessions:   &self.sessions
+        };
+
+        json::encode(&export)
+            .unwrap()
+    }
+}
+
+impl SessionRecord {
+    /// Create a new session record with the current timestamp.
+    pub fn new(
+        service_id: u16,
+        session_id: u32,
+        lifetime: Option<u64>,
+        transferred: (u64, u64),
+        high_water: (usize, usize),
+        error: Option<&str>) -> SessionRecord {
+        SessionRecord {
+            timestamp:  clock::timestamp(),
+            service_id: service_id,
+            session_id: session_id,
+            lifetime:   lifetime,
+            bytes_in:   transferred.0,
+            bytes_out:  transferred.1,
+            input_hwm:  high_water.0,
+            output_hwm: high_water.1,
+            error:      error.map(|err| err.to_string())
+        }
+    }
+}
+
+/// Escape a given CSV field.
+fn csv_escape(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::csv_escape;
+
+    use std::fs;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// Create a given file with a given content.
+    fn create_file(path: &str, content: &str) {
+        File::create(path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let connection = ConnectionRecord {
+            timestamp:   100,
+            ack_count:   10,
+            ack_avg:     20,
+            ack_max:     30,
+            unsupported: 1,
+            replayed:    2,
+            frames:      100,
+            lost:        3,
+            reordered:   4,
+            uplink_hwm:  5
+        };
+
+        let mut export = StatsExport::new("test-stats.csv", ExportFormat::Csv, 60, 2);
+
+        export.record_session(SessionRecord {
+            timestamp:  101,
+            service_id: 1,
+            session_id: 2,
+            lifetime:   Some(3),
+            bytes_in:   4,
+            bytes_out:  5,
+            input_hwm:  6,
+            output_hwm: 7,
+            error:      Some("foo, bar".to_string())
+        });
+
+        let csv   = export.to_csv(&connection);
+        let lines = csv.lines()
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "connection,100,,,,,,10,20,30,1,2,100,3,4,,,5,");
+        assert_eq!(lines[2], "session,101,1,00000002,3,4,5,,,,,,,,,6,7,,\"foo, bar\"");
+
+        assert_eq!(csv_escape("a\"b"), "\"a\"\"b\"");
+
+        create_file("test-stats.csv", "1");
+        create_file("test-stats.csv.1", "2");
+
+        export.export(&connection)
+            .unwrap();
+
+        assert!(export.sessions.is_empty());
+        assert!(Path::new("test-stats.csv.1").exists());
+        assert!(!Path::new("test-stats.csv.2").exists());
+
+        fs::remove_file("test-stats.csv").unwrap();
+        fs::remove_file("test-stats.csv.1").unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection status indication using an LED.
+//!
+//! The LED is driven using the Linux sysfs interface. It can be either a GPIO
+//! pin (`/sys/class/gpio`) or an LED class device (`/sys/class/leds`). The LED
+//! is driven by a background thread according to a blink pattern of the
+//! current connection state.
+
+use std::io;
+use std::fmt;
+use std::thread;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, Condvar};
+use std::fmt::{Debug, Formatter};
+
+use utils::RuntimeError;
+
+/// Connection states indicated by the LED.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LedState {
+    /// Connecting to Arrow Service.
+    Connecting,
+    /// Registered with Arrow Service.
+    Registered,
+    /// The last connection attempt failed.
+    Error,
+}
+
+impl LedState {
+    /// Parse a given state name.
+    pub fn from_name(name: &str) -> Result<LedState, RuntimeError> {
+        match name {
+            "connecting" => Ok(LedState::Connecting),
+            "registered" => Ok(LedState::Registered),
+            "error"      => Ok(LedState::Error),
+            _ => Err(RuntimeError::from(format!("unknown LED state: {}", name)))
+        }
+    }
+}
+
+/// LED output.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LedOutput {
+    /// GPIO pin number.
+    Gpio(u32),
+    /// LED class device name.
+    Led(String),
+}
+
+impl LedOutput {
+    /// Prepare the output and get path of the file controlling the LED.
+    fn init(&self) -> io::Result<String> {
+        match self {
+            &LedOutput::Gpio(pin) => {
+                let dir = format!("/sys/class/gpio/gpio{}", pin);
+
+                if !Path::new(&dir).exists() {
+                    try!(write_file("/sys/class/gpio/export",
+                        &pin.to_string()));
+                }
+
+                try!(write_file(&format!("{}/direction", dir), "out"));
+
+                Ok(format!("{}/value", dir))
+            },
+            &LedOutput::Led(ref name) => {
+                let dir = format!("/sys/class/leds/{}", name);
+
+                // disable any kernel trigger so that we can control the LED
+                try!(write_file(&format!("{}/trigger", dir), "none"));
+
+                Ok(format!("{}/brightness", dir))
+            }
+        }
+    }
+}
+
+impl FromStr for LedOutput {
+    type Err = RuntimeError;
+
+    /// Parse a given output in the "gpio:pin" or "led:name" format.
+    fn from_str(s: &str) -> Result<LedOutput, RuntimeError> {
+        if s.starts_with("gpio:") {
+            u32::from_str(&s[5..])
+                .map(|pin| LedOutput::Gpio(pin))
+                .or(Err(RuntimeError::from("invalid GPIO pin number")))
+        } else if s.starts_with("led:") && s.len() > 4 && !s.contains('/') {
+            Ok(LedOutput::Led(s[4..].to_string()))
+        } else {
+            Err(RuntimeError::from("gpio:pin or led:name expected"))
+        }
+    }
+}
+
+/// Blink pattern. It is a sequence of durations (in milliseconds) during
+/// which the LED is alternately on and off (starting with on). The pattern
+/// is repeated.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LedPattern {
+    steps: Vec<u32>,
+}
+
+impl LedPattern {
+    /// Create a pattern from given durations.
+    fn new(steps: &[u32]) -> LedPattern {
+        LedPattern {
+            steps: steps.to_vec()
+        }
+    }
+
+    /// Get LED value and duration of a given step. None is returned as the
+    /// duration if the step lasts forever.
+    fn step(&self, index: usize) -> (bool, Option<u32>) {
+        if self.steps.is_empty() {
+            (false, None)
+        } else if self.steps.len() == 1 {
+            (true, None)
+        } else {
+            let index = index % self.steps.len();
+
+            ((index & 1) == 0, Some(self.steps[index]))
+        }
+    }
+}
+
+impl FromStr for LedPattern {
+    type Err = RuntimeError;
+
+    /// Parse a given pattern. It can be either "on", "off" or a comma
+    /// separated list of durations in milliseconds.
+    fn from_str(s: &str) -> Result<LedPattern, RuntimeError> {
+        match s {
+            "on"  => return Ok(LedPattern::new(&[0])),
+            "off" => return Ok(LedPattern::new(&[])),
+            _     => ()
+        }
+
+        let mut steps = Vec::new();
+
+        for step in s.split(',') {
+            match u32::from_str(step) {
+                Ok(step) if step > 0 => steps.push(step),
+                _ => return Err(RuntimeError::from("invalid LED pattern"))
+            }
+        }
+
+        // the on-off sequence must be complete in order to repeat it
+        if (steps.len() & 1) != 0 {
+            return Err(RuntimeError::from("invalid LED pattern"));
+        }
+
+        Ok(LedPattern::new(&steps))
+    }
+}
+
+/// Blink patterns of all connection states.
+#[derive(Debug, Clone)]
+pub struct LedPatterns {
+    connecting: LedPattern,
+    registered: LedPattern,
+    error:      LedPattern,
+}
+
+impl LedPatterns {
+    /// Create default patterns (slow blinking while connecting, solid on
+    /// when registered and fast blinking on error).
+    pub fn new() -> LedPatterns {
+        LedPatterns {
+            connecting: LedPattern::new(&[500, 500]),
+            registered: LedPattern::new(&[0]),
+            error:      LedPattern::new(&[100, 100])
+        }
+    }
+
+    /// Set pattern of a given state.
+    pub fn set(&mut self, state: LedState, pattern: LedPattern) {
+        match state {
+            LedState::Connecting => self.connecting = pattern,
+            LedState::Registered => self.registered = pattern,
+            LedState::Error      => self.error      = pattern
+        }
+    }
+
+    /// Get pattern of a given state.
+    fn get(&self, state: LedState) -> &LedPattern {
+        match state {
+            LedState::Connecting => &self.connecting,
+            LedState::Registered => &self.registered,
+            LedState::Error      => &self.error
+        }
+    }
+}
+
+/// Status LED handle. The handle can be cloned and all clones control the
+/// same LED.
+#[derive(Clone)]
+pub struct StatusLed {
+    state: Option<Arc<(Mutex<LedState>, Condvar)>>,
+}
+
+impl StatusLed {
+    /// Create a handle which does not control any LED.
+    pub fn disabled() -> StatusLed {
+        StatusLed {
+            state: None
+        }
+    }
+
+    /// Initialize a given LED output and start driving it using given
+    /// patterns.
+    pub fn start(
+        output: &LedOutput,
+        patterns: &LedPatterns) -> io::Result<StatusLed> {
+        let file     = try!(output.init());
+        let state    = Arc::new((Mutex::new(LedState::Connecting), Condvar::new()));
+        let patterns = patterns.clone();
+
+        let tstate = state.clone();
+
+        thread::spawn(move || drive_led(&file, &patterns, &tstate));
+
+        let res = StatusLed {
+            state: Some(state)
+        };
+
+        Ok(res)
+    }
+
+    /// Set the current connection state.
+    pub fn set_state(&self, state: LedState) {
+        if let Some(ref shared) = self.state {
+            let &(ref lock, ref cvar) = &**shared;
+
+            let mut current = lock.lock()
+                .unwrap();
+
+            if *current != state {
+                *current = state;
+                cvar.notify_one();
+            }
+        }
+    }
+}
+
+impl Debug for StatusLed {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("StatusLed")
+            .field("enabled", &self.state.is_some())
+            .finish()
+    }
+}
+
+/// Drive the LED controlled by a given file according to the current
+/// connection state.
+fn drive_led(
+    file: &str,
+    patterns: &LedPatterns,
+    shared: &(Mutex<LedState>, Condvar)) {
+    let &(ref lock, ref cvar) = shared;
+
+    let mut state = lock.lock()
+        .unwrap();
+
+    let mut current = *state;
+    let mut index   = 0;
+
+    loop {
+        let (on, duration) = patterns.get(current)
+            .step(index);
+
+        // errors are ignored, there is nowhere to report them
+        write_file(file, if on { "1" } else { "0" })
+            .ok();
+
+        state = match duration {
+            Some(ms) => cvar.wait_timeout(state, Duration::from_millis(ms as u64))
+                .unwrap()
+                .0,
+            None => cvar.wait(state)
+                .unwrap()
+        };
+
+        if *state == current {
+            index += 1;
+        } else {
+            current = *state;
+            index   = 0;
+        }
+    }
+}
+
+/// Write a given value into a given sysfs file.
+fn write_file(file: &str, value: &str) -> io::Result<()> {
+    let mut file = try!(File::create(file));
+
+    file.write_all(value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    #[test]
+    fn test_led_pattern() {
+        let pattern = LedPattern::from_str("200,100")
+            .unwrap();
+
+        assert_eq!(pattern.step(0), (true, Some(200)));
+        assert_eq!(pattern.step(1), (false, Some(100)));
+        assert_eq!(pattern.step(2), (true, Some(200)));
+
+        assert_eq!(LedPattern::from_str("on").unwrap().step(5), (true, None));
+        assert_eq!(LedPattern::from_str("off").unwrap().step(5), (false, None));
+
+        assert!(LedPattern::from_str("200").is_err());
+        assert!(LedPattern::from_str("200,0").is_err());
+        assert!(LedPattern::from_str("foo").is_err());
+    }
+
+    #[test]
+    fn test_led_output() {
+        assert_eq!(LedOutput::from_str("gpio:17").unwrap(), LedOutput::Gpio(17));
+        assert_eq!(LedOutput::from_str("led:status").unwrap(),
+            LedOutput::Led("status".to_string()));
+
+        assert!(LedOutput::from_str("gpio:foo").is_err());
+        assert!(LedOutput::from_str("led:").is_err());
+        assert!(LedOutput::from_str("led:../foo").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Copyright 2016 click2stream, Inc.
+ * 
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ * 
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * 
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <time.h>
+
+#include <openssl/asn1.h>
+#include <openssl/x509.h>
+
+/*
+ * Check if a given certificate becomes valid within a given number of
+ * seconds from now (i.e. its notBefore time is earlier than now + tolerance).
+ */
+int x509_valid_within(X509* cert, long tolerance) {
+    ASN1_TIME* not_before;
+    time_t t;
+    
+    if (!cert)
+        return 0;
+    
+    not_before = X509_get_notBefore(cert);
+    if (!not_before)
+        return 0;
+    
+    t = time(NULL) + tolerance;
+    
+    return X509_cmp_time(not_before, &t) < 0;
+}
+
+/*
+ * Check if a given certificate is valid at a given time.
+ */
+int x509_valid_at(X509* cert, long timestamp) {
+    ASN1_TIME* not_before;
+    ASN1_TIME* not_after;
+    time_t t;
+    
+    if (!cert)
+        return 0;
+    
+    not_before = X509_get_notBefore(cert);
+    not_after = X509_get_notAfter(cert);
+    if (!not_before || !not_after)
+        return 0;
+    
+    t = timestamp;
+    
+    return X509_cmp_time(not_before, &t) < 0
+        && X509_cmp_time(not_after, &t) > 0;
+}
@@ -0,0 +1,42 @@
+// Copyright 2016 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! X509 certificate helpers missing in the openssl bindings.
+
+use libc::{c_int, c_long, c_void};
+
+use openssl::x509::X509;
+
+#[link(name = "x509_utils")]
+extern "C" {
+    fn x509_valid_within(cert: *mut c_void, tolerance: c_long) -> c_int;
+    fn x509_valid_at(cert: *mut c_void, timestamp: c_long) -> c_int;
+}
+
+/// Check if a given certificate becomes valid within a given number of
+/// seconds from now.
+pub fn valid_within(cert: &X509, tolerance: u32) -> bool {
+    unsafe {
+        x509_valid_within(cert.get_handle() as *mut c_void,
+            tolerance as c_long) != 0
+    }
+}
+
+/// Check if a given certificate is valid at a given time (UNIX timestamp).
+pub fn valid_at(cert: &X509, timestamp: i64) -> bool {
+    unsafe {
+        x509_valid_at(cert.get_handle() as *mut c_void,
+            timestamp as c_long) != 0
+    }
+}